@@ -0,0 +1,83 @@
+/*
+ * C interface of the WonderCrab emulator
+ *
+ * Built with `cargo rustc --lib --release --features ffi --crate-type cdylib`.
+ * Kept in sync with src/ffi.rs, a test checks that every function there is declared here.
+ *
+ * An emulator is not thread safe, each one has to be used from a single thread at a time.
+ */
+
+#ifndef WONDERCRAB_H
+#define WONDERCRAB_H
+
+#include <stdbool.h>
+#include <stddef.h>
+#include <stdint.h>
+
+#ifdef __cplusplus
+extern "C" {
+#endif
+
+/* Size of the frames returned by wc_get_framebuffer, 24-bit RGB in landscape orientation */
+#define WC_FRAME_WIDTH 224
+#define WC_FRAME_HEIGHT 144
+
+/* Rate of the samples returned by wc_get_audio */
+#define WC_SAMPLE_RATE 24000
+
+/* Buttons passed to wc_set_input */
+#define WC_KEY_START 0x0002
+#define WC_KEY_A 0x0004
+#define WC_KEY_B 0x0008
+#define WC_KEY_X1 0x0010
+#define WC_KEY_X2 0x0020
+#define WC_KEY_X3 0x0040
+#define WC_KEY_X4 0x0080
+#define WC_KEY_Y1 0x0100
+#define WC_KEY_Y2 0x0200
+#define WC_KEY_Y3 0x0400
+#define WC_KEY_Y4 0x0800
+
+/* Save media read and written by wc_get_save and wc_set_save */
+typedef enum wc_media {
+    WC_MEDIA_SRAM = 0,
+    WC_MEDIA_EEPROM = 1,
+    WC_MEDIA_IEEPROM = 2,
+} wc_media;
+
+typedef struct wc_emulator wc_emulator;
+
+/* Creates an emulator without a ROM, it has to be freed with wc_destroy */
+wc_emulator *wc_create(void);
+/* Frees an emulator, does nothing when given NULL */
+void wc_destroy(wc_emulator *emulator);
+
+/* Returns the last error, empty if nothing failed yet, valid until the next call that fails */
+const char *wc_last_error(const wc_emulator *emulator);
+
+/* Loads a ROM image from memory with blank save media, returns false if it is not a valid ROM */
+bool wc_load_rom(wc_emulator *emulator, const uint8_t *rom, size_t len);
+/* Emulates a single frame, returns false if no ROM is loaded or the emulator panicked */
+bool wc_run_frame(wc_emulator *emulator);
+/* Returns the last finished frame, or NULL if no ROM is loaded, valid until the next wc_run_frame or wc_load_rom */
+const uint8_t *wc_get_framebuffer(const wc_emulator *emulator);
+/* Sets the buttons being held, a combination of WC_KEY_* */
+void wc_set_input(wc_emulator *emulator, uint16_t keys);
+/* Takes up to len unsigned 8-bit mono samples, returns how many were written */
+size_t wc_get_audio(wc_emulator *emulator, uint8_t *buffer, size_t len);
+
+/* Writes a save state if buffer holds at least len bytes, returns its size or 0 if no ROM is loaded, buffer may be NULL */
+size_t wc_save_state(const wc_emulator *emulator, uint8_t *buffer, size_t len);
+/* Loads a save state made for the same ROM, returns false and leaves the emulator untouched if it cannot be loaded */
+bool wc_load_state(wc_emulator *emulator, const uint8_t *state, size_t len);
+
+/* Copies a save medium if buffer holds at least len bytes, returns its size or 0 if the cartridge does not have it */
+size_t wc_get_save(const wc_emulator *emulator, wc_media media, uint8_t *buffer, size_t len);
+/* Replaces the contents of a save medium, returns false if the cartridge does not have it or the size differs */
+bool wc_set_save(wc_emulator *emulator, wc_media media, const uint8_t *data, size_t len);
+
+#ifdef __cplusplus
+}
+#endif
+
+#endif
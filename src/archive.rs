@@ -0,0 +1,131 @@
+use alloc::{format, string::{String, ToString}, vec::Vec};
+
+use crate::png::{crc32, inflate_raw};
+
+/// A file in a ZIP archive, as listed by the archive's central directory
+#[derive(Clone, PartialEq, Eq, Debug)]
+pub struct ZipEntry {
+    /// Path of the file inside the archive
+    pub name: String,
+    /// 0 when stored, 8 when deflated, other methods are not supported
+    method: u16,
+    /// CRC-32 of the uncompressed contents
+    crc: u32,
+    /// Size of the file as stored in the archive
+    compressed_size: usize,
+    /// Size of the file once decompressed
+    pub size: usize,
+    /// Offset of the file's local header
+    offset: usize,
+}
+
+/// Reads a little-endian value of the archive, failing past its end
+fn read<const N: usize>(zip: &[u8], pos: usize) -> Result<[u8; N], String> {
+    zip.get(pos..pos + N).map(|bytes| bytes.try_into().unwrap()).ok_or("Truncated ZIP archive".to_string())
+}
+
+fn read_u16(zip: &[u8], pos: usize) -> Result<u16, String> {
+    read(zip, pos).map(u16::from_le_bytes)
+}
+
+fn read_u32(zip: &[u8], pos: usize) -> Result<u32, String> {
+    read(zip, pos).map(u32::from_le_bytes)
+}
+
+/// Lists the files of a ZIP archive from its central directory
+///
+/// Only single-disk archives without ZIP64 extensions are supported, which covers every ROM set since their files are far below 4 GB.
+pub fn entries(zip: &[u8]) -> Result<Vec<ZipEntry>, String> {
+    // The end of central directory record is last, followed by a comment of up to 64 KB
+    let end = (0..zip.len().saturating_sub(21)).rev().take(0x10000)
+        .find(|pos| zip[*pos..].starts_with(&0x06054B50u32.to_le_bytes()))
+        .ok_or("Not a ZIP archive")?;
+    let count = read_u16(zip, end + 10)?;
+    let mut pos = read_u32(zip, end + 16)? as usize;
+
+    let mut entries = Vec::with_capacity(count as usize);
+    for _ in 0..count {
+        if read_u32(zip, pos)? != 0x02014B50 {return Err("Invalid ZIP central directory".to_string())}
+        let name_len = read_u16(zip, pos + 28)? as usize;
+        let name = zip.get(pos + 46..pos + 46 + name_len).ok_or("Truncated ZIP archive")?;
+        entries.push(ZipEntry {
+            name: String::from_utf8_lossy(name).to_string(),
+            method: read_u16(zip, pos + 10)?,
+            crc: read_u32(zip, pos + 16)?,
+            compressed_size: read_u32(zip, pos + 20)? as usize,
+            size: read_u32(zip, pos + 24)? as usize,
+            offset: read_u32(zip, pos + 42)? as usize,
+        });
+        pos += 46 + name_len + read_u16(zip, pos + 30)? as usize + read_u16(zip, pos + 32)? as usize;
+    }
+    Ok(entries)
+}
+
+/// Extracts a file of a ZIP archive, checking it against its CRC-32
+pub fn extract(zip: &[u8], entry: &ZipEntry) -> Result<Vec<u8>, String> {
+    if read_u32(zip, entry.offset)? != 0x04034B50 {return Err(format!("Invalid ZIP local header for {}", entry.name))}
+    // The local header repeats the name but may have an extra field of a different length than the directory's
+    let start = entry.offset + 30 + read_u16(zip, entry.offset + 26)? as usize + read_u16(zip, entry.offset + 28)? as usize;
+    let data = zip.get(start..start + entry.compressed_size).ok_or("Truncated ZIP archive")?;
+    let contents = match entry.method {
+        0 => data.to_vec(),
+        8 => inflate_raw(data)?,
+        method => return Err(format!("Unsupported compression method {} for {}", method, entry.name)),
+    };
+    if contents.len() != entry.size || crc32(&contents) != entry.crc {return Err(format!("{} is corrupted", entry.name))}
+    Ok(contents)
+}
+
+/// Returns the first WonderSwan ROM of a ZIP archive, the one with a .ws or .wsc extension
+pub fn find_rom(zip: &[u8]) -> Result<ZipEntry, String> {
+    entries(zip)?.into_iter()
+        .find(|entry| [".ws", ".wsc"].iter().any(|extension| entry.name.to_lowercase().ends_with(extension)))
+        .ok_or("No .ws or .wsc file in the archive".to_string())
+}
+
+#[cfg(test)]
+#[cfg_attr(coverage_nightly, coverage(off))]
+mod test {
+    use alloc::vec;
+
+    use crate::soc::diagnostics::zip;
+
+    use super::*;
+
+    #[test]
+    fn test_stored() {
+        let archive = zip("set", &[("readme.txt", b"hello".to_vec()), ("game.wsc", vec![1, 2, 3])]);
+        let entries = entries(&archive).unwrap();
+        assert_eq!(entries.iter().map(|entry| entry.name.as_str()).collect::<Vec<_>>(), ["set/readme.txt", "set/game.wsc"]);
+        let rom = find_rom(&archive).unwrap();
+        assert_eq!(rom, entries[1]);
+        assert_eq!(extract(&archive, &rom).unwrap(), [1, 2, 3]);
+    }
+
+    #[test]
+    fn test_deflated() {
+        // The deflate stream of the fixed Huffman test of png.rs, without its zlib header and checksum
+        let deflate = [0x0B, 0xCF, 0xCF, 0x4B, 0x49, 0x2D, 0x0A, 0x2E, 0x4F, 0xCC, 0x53, 0x08, 0xA7, 0x11, 0xD3, 0x39, 0x3F, 0x27, 0xBF, 0x08, 0x00];
+        let text = [&b"WonderSwan ".repeat(8)[..], b"WonderSwan Color"].concat();
+        let mut archive = zip("set", &[("game.ws", deflate.to_vec())]);
+        // Marks the entry as deflated in both headers and fixes up the sizes and CRC
+        let directory = archive.len() - 22 - 46 - 11;
+        for header in [4, directory + 6] {
+            archive[header + 4..header + 6].copy_from_slice(&8u16.to_le_bytes());
+            archive[header + 10..header + 14].copy_from_slice(&crc32(&text).to_le_bytes());
+            archive[header + 18..header + 22].copy_from_slice(&(text.len() as u32).to_le_bytes());
+        }
+        let rom = find_rom(&archive).unwrap();
+        assert_eq!(rom.size, text.len());
+        assert_eq!(extract(&archive, &rom).unwrap(), text);
+
+        archive[30 + 11] ^= 0xFF;
+        assert!(extract(&archive, &rom).is_err());
+    }
+
+    #[test]
+    fn test_not_zip() {
+        assert!(entries(b"WonderSwan").is_err());
+        assert!(find_rom(&zip("set", &[("readme.txt", Vec::new())])).is_err());
+    }
+}
@@ -0,0 +1,241 @@
+use sdl2::{audio::{AudioCallback, AudioDevice, AudioSpecDesired}, Sdl};
+
+use wonderswan::{options::{Choice, SharedOptions}, sound::buffer::SharedSamples, speed};
+
+/// Rate the SoC produces samples at
+pub const SAMPLE_RATE: u32 = 24000;
+/// Size of the buffer asked of the audio device, in samples
+const BUFFER_SAMPLES: u16 = 1024;
+
+/// Libraries the emulator can play audio through
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum Backend {
+    /// SDL2's audio subsystem, the default
+    Sdl,
+    /// cpal, which talks to WASAPI, CoreAudio or ALSA directly, only available when built with the cpal feature
+    Cpal,
+}
+
+impl Choice for Backend {
+    const ALL: &'static [Self] = &[Backend::Sdl, Backend::Cpal];
+
+    fn name(&self) -> &'static str {
+        match self {
+            Backend::Sdl => "sdl",
+            Backend::Cpal => "cpal",
+        }
+    }
+}
+
+/// Hands the samples produced by the SoC to an audio device
+///
+/// The samples in here are generated by the audio system and the vector is updated at the WonderSwan's samplerate of 24kHz.
+/// Backends call [`SampleStream::fill`] from their audio thread whenever the device needs more.
+/// This is only the playback path, audio captures are taken by the SoC from the same samples before they are stretched or resampled here.
+pub struct SampleStream {
+    /// Vector containing the samples
+    ///
+    /// Samples are unsigned 16-bit values for the left and right side, the speaker's monaural output being the same on both.
+    samples: SharedSamples,
+    /// Options shared with the main loop
+    ///
+    /// When not running at normal speed the samples are stretched so that they still fill the device's buffer at 24kHz
+    options: SharedOptions,
+    /// Rate the device plays at, samples are resampled to it when it is not 24kHz
+    rate: u32,
+}
+
+impl SampleStream {
+    /// Creates a stream for a device playing at the given rate
+    pub fn new(samples: SharedSamples, options: SharedOptions, rate: u32) -> Self {
+        Self {samples, options, rate}
+    }
+
+    /// Fills a device buffer with signed 16-bit stereo samples, the left and right side interleaved
+    pub fn fill(&mut self, out: &mut [i16]) {
+        let settings = self.options.get().speed;
+        let mut buffer = self.samples.lock().unwrap();
+
+        // Amount of 24kHz samples the buffer lasts for at normal speed
+        let ratio = SAMPLE_RATE as f64 / self.rate as f64;
+        let needed = (out.len() / 2) as f64 * ratio;
+        // Unlimited speed consumes everything that has been produced since the last callback
+        let factor = settings.speed.factor().unwrap_or((buffer.len() as f64 / needed).max(1.0));
+        let wanted = ((needed * factor) as usize).min(buffer.len());
+        let input = buffer.take(wanted);
+        drop(buffer);
+
+        // Speed is handled first so that preserving pitch works on the original waveform
+        let normal = speed::stretch(&input, factor, settings.preserve_pitch);
+        let output = if self.rate == SAMPLE_RATE {normal} else {speed::stretch(&normal, ratio, false)};
+        for (frame, sample) in out.chunks_exact_mut(2).zip(output) {
+            frame[0] = (sample.0 ^ 0x8000) as i16;
+            frame[1] = (sample.1 ^ 0x8000) as i16;
+        }
+    }
+}
+
+/// An open audio device playing the SoC's samples
+///
+/// Devices pull samples from a [`SampleStream`] on their own thread, playback lasts for as long as the sink is kept.
+pub trait AudioSink {
+    /// The backend the device was opened with
+    fn backend(&self) -> Backend;
+    /// Rate the device plays at
+    fn rate(&self) -> u32;
+}
+
+/// Opens the default audio device of a backend and starts playing
+pub fn open(backend: Backend, sdl: &Sdl, samples: SharedSamples, options: SharedOptions) -> Result<Box<dyn AudioSink>, String> {
+    match backend {
+        Backend::Sdl => SdlSink::open(sdl, samples, options).map(|sink| Box::new(sink) as Box<dyn AudioSink>),
+        #[cfg(feature = "cpal")]
+        Backend::Cpal => cpal_sink::CpalSink::open(samples, options).map(|sink| Box::new(sink) as Box<dyn AudioSink>),
+        #[cfg(not(feature = "cpal"))]
+        Backend::Cpal => Err("The cpal audio backend needs the emulator to be built with --features cpal".to_string()),
+    }
+}
+
+impl AudioCallback for SampleStream {
+    type Channel = i16;
+
+    fn callback(&mut self, out: &mut [Self::Channel]) {
+        self.fill(out);
+    }
+}
+
+/// Plays audio through SDL, which converts the samples to whatever the device wants
+struct SdlSink {
+    /// The device, stops playing when dropped
+    device: AudioDevice<SampleStream>,
+}
+
+impl SdlSink {
+    fn open(sdl: &Sdl, samples: SharedSamples, options: SharedOptions) -> Result<Self, String> {
+        let desired_spec = AudioSpecDesired {
+            freq: Some(SAMPLE_RATE as i32),
+            channels: Some(2),
+            samples: Some(BUFFER_SAMPLES),
+        };
+        let device = sdl.audio()?.open_playback(None, &desired_spec, |spec| SampleStream::new(samples, options, spec.freq as u32))?;
+        device.resume();
+        Ok(Self {device})
+    }
+}
+
+impl AudioSink for SdlSink {
+    fn backend(&self) -> Backend {
+        Backend::Sdl
+    }
+
+    fn rate(&self) -> u32 {
+        self.device.spec().freq as u32
+    }
+}
+
+/// Playback through cpal
+#[cfg(feature = "cpal")]
+mod cpal_sink {
+    use std::sync::{Arc, Mutex};
+
+    use cpal::{traits::{DeviceTrait, HostTrait, StreamTrait}, Device, FromSample, SampleFormat, SizedSample, Stream, StreamConfig};
+
+    use wonderswan::options::SharedOptions;
+
+    use super::{AudioSink, Backend, SampleStream};
+
+    /// Plays audio through cpal
+    ///
+    /// cpal does not convert formats, so the stream uses the device's default rate, channels and sample format.
+    pub struct CpalSink {
+        /// The stream, stops playing when dropped
+        _stream: Stream,
+        /// Rate the device plays at
+        rate: u32,
+    }
+
+    impl CpalSink {
+        pub fn open(samples: SharedSamples, options: SharedOptions) -> Result<Self, String> {
+            let device = cpal::default_host().default_output_device().ok_or("No audio output device found")?;
+            let supported = device.default_output_config().map_err(|e| e.to_string())?;
+            let config: StreamConfig = supported.config();
+            let rate = config.sample_rate.0;
+            let stream = SampleStream::new(samples, options, rate);
+
+            let stream = match supported.sample_format() {
+                SampleFormat::U8 => build::<u8>(&device, &config, stream),
+                SampleFormat::I16 => build::<i16>(&device, &config, stream),
+                SampleFormat::U16 => build::<u16>(&device, &config, stream),
+                SampleFormat::I32 => build::<i32>(&device, &config, stream),
+                SampleFormat::F32 => build::<f32>(&device, &config, stream),
+                format => return Err(format!("Unsupported audio sample format {}", format)),
+            }?;
+            stream.play().map_err(|e| e.to_string())?;
+            Ok(Self {_stream: stream, rate})
+        }
+    }
+
+    impl AudioSink for CpalSink {
+        fn backend(&self) -> Backend {
+            Backend::Cpal
+        }
+
+        fn rate(&self) -> u32 {
+            self.rate
+        }
+    }
+
+    /// Builds an output stream converting the stereo 16-bit samples to the device's format and channels
+    ///
+    /// Mono devices get both sides mixed, other devices get the left and right side on their first two channels and the mix on the rest.
+    fn build<T: SizedSample + FromSample<i16>>(device: &Device, config: &StreamConfig, mut stream: SampleStream) -> Result<Stream, String> {
+        let channels = config.channels as usize;
+        let mut stereo = Vec::new();
+        device.build_output_stream(config, move |out: &mut [T], _| {
+            stereo.resize(out.len() / channels * 2, 0);
+            stream.fill(&mut stereo);
+            for (frame, sample) in out.chunks_mut(channels).zip(stereo.chunks_exact(2)) {
+                let mix = ((sample[0] as i32 + sample[1] as i32) / 2) as i16;
+                for (channel, out) in frame.iter_mut().enumerate() {
+                    *out = T::from_sample(match (channels, channel) {
+                        (1, _) => mix,
+                        (_, 0) => sample[0],
+                        (_, 1) => sample[1],
+                        _ => mix,
+                    });
+                }
+            }
+        }, |e| println!("Audio stream error: {}", e), None).map_err(|e| e.to_string())
+    }
+}
+
+#[cfg(test)]
+#[cfg_attr(coverage_nightly, coverage(off))]
+mod test {
+    use std::sync::{Arc, Mutex};
+
+    use wonderswan::{options::EmulatorOptions, sound::buffer::SampleBuffer};
+
+    use super::*;
+
+    #[test]
+    fn test_fill_resamples() {
+        let mut buffer = SampleBuffer::new(2048);
+        for i in 0..2048 {buffer.push(((i % 0x100) << 8, 0x8000))}
+        let samples = Arc::new(Mutex::new(buffer));
+        let options = SharedOptions::new(EmulatorOptions::new());
+        // Samples come out signed, the left and right side interleaved
+        let played = |i: usize| [((i << 8) as u16 ^ 0x8000) as i16, 0];
+
+        // At 24kHz samples are played as they are
+        let mut out = [0; 512];
+        SampleStream::new(Arc::clone(&samples), options.clone(), SAMPLE_RATE).fill(&mut out);
+        assert!(out.chunks(2).enumerate().all(|(i, frame)| frame == played(i)));
+        assert_eq!(samples.lock().unwrap().len(), 2048 - 256);
+
+        // A 48kHz device plays each sample twice and consumes half as many
+        SampleStream::new(Arc::clone(&samples), options, 48000).fill(&mut out);
+        assert!(out.chunks(2).enumerate().all(|(i, frame)| frame == played(i / 2)));
+        assert_eq!(samples.lock().unwrap().len(), 2048 - 256 - 128);
+    }
+}
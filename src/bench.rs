@@ -0,0 +1,117 @@
+use std::time::{Duration, Instant};
+
+use crate::{headless::{self, InputSource, NullInput}, options::{Accuracy, Choice, EmulatorOptions, SharedOptions}, parse_rom, soc::{profiler::FrameProfile, SoC}, sound::buffer::SampleBuffer};
+
+/// Frames run when none are given, ten seconds of play
+const DEFAULT_FRAMES: u64 = 600;
+
+/// Results of running frames as fast as the host allows
+#[derive(Clone, Copy, PartialEq, Eq, Debug, Default)]
+pub struct Benchmark {
+    /// Frames run
+    pub frames: u64,
+    /// Instructions and prefixes executed by the CPU
+    pub instructions: u64,
+    /// Wall time spent running the frames
+    pub time: Duration,
+    /// Time spent in each subsystem over every frame, only measured with the profiling feature
+    pub profile: Option<FrameProfile>,
+}
+
+impl Benchmark {
+    /// Returns the frames run per second of wall time
+    pub fn fps(&self) -> f64 {
+        self.frames as f64 / self.time.as_secs_f64().max(f64::EPSILON)
+    }
+
+    /// Describes the run, one measurement per line
+    ///
+    /// The speed is also given relative to the console's 75.47 frames per second, so that runs of different lengths can be compared.
+    pub fn report(&self) -> String {
+        let mut report = format!(
+            "{} frames in {:.3}s\n{} instructions, {:.2} million per second\n{:.1} fps, {:.2}x the console's speed",
+            self.frames, self.time.as_secs_f64(), self.instructions, self.instructions as f64 / self.time.as_secs_f64().max(f64::EPSILON) / 1e6,
+            self.fps(), self.fps() / 75.47,
+        );
+        match self.profile {
+            Some(profile) => report += &format!("\nPer frame: {}", profile.average(self.frames as u32)),
+            None => report += "\nBuild with --features profiling for the time spent in each subsystem",
+        }
+        report
+    }
+}
+
+/// Runs frames back to back without pacing them, holding the buttons an input source asks for, and measures how long they took
+///
+/// Only the frames themselves are timed, the SoC's options are left as they are.
+pub fn benchmark(soc: &mut SoC, input: &mut dyn InputSource, frames: u64) -> Benchmark {
+    let mut benchmark = Benchmark {frames, ..Benchmark::default()};
+    for frame in 0..frames {
+        headless::hold_keys(soc, input.keys(frame));
+        let start = Instant::now();
+        let report = soc.run_frame();
+        benchmark.time += start.elapsed();
+        benchmark.instructions += report.instructions;
+        if let Some(profile) = soc.profile() {
+            benchmark.profile.get_or_insert_default().add(&profile);
+        }
+    }
+    benchmark
+}
+
+/// Entry point of the `--time` flag
+///
+/// Usage: `<rom> --time [--frames N] [accuracy]`
+///
+/// Runs the ROM without a window, sound or pacing for a number of frames and prints how fast it ran,
+/// to compare the emulator's performance across changes independently of the host's display and audio clocks.
+/// Save files are ignored so that every run does the same work.
+pub fn run(args: &[String]) -> Result<(), String> {
+    let game = args.first().filter(|game| !game.starts_with("--")).ok_or("Usage: <rom> --time [--frames N] [accuracy]")?;
+    let frames = match args.iter().position(|arg| arg == "--frames") {
+        Some(idx) => {
+            let frames = args.get(idx + 1).ok_or("--frames needs a frame count")?;
+            frames.parse().ok().filter(|frames| *frames > 0).ok_or(format!("Invalid frame count: {}", frames))?
+        }
+        None => DEFAULT_FRAMES,
+    };
+    let accuracy = args.iter().find_map(|arg| Accuracy::from_name(arg)).unwrap_or(Accuracy::Balanced);
+
+    let (color, ram_content, _, _, rom, mapper, sram, rom_info, _) = parse_rom(game);
+    let ram_content = vec![0; ram_content.len()];
+    let options = SharedOptions::new(EmulatorOptions {color, mute: true, accuracy, ..EmulatorOptions::new()});
+    let mut soc = SoC::new(ram_content, Vec::new(), Vec::new(), rom, mapper, sram, SampleBuffer::shared(), options, rom_info);
+
+    println!("{}", benchmark(&mut soc, &mut NullInput, frames).report());
+    Ok(())
+}
+
+#[cfg(test)]
+#[cfg_attr(coverage_nightly, coverage(off))]
+mod test {
+    use crate::soc::profiler::ENABLED;
+
+    use super::*;
+
+    #[test]
+    fn test_benchmark() {
+        let mut soc = SoC::test_build();
+        // Loops on a single jump, which executes once every few ticks
+        soc.set_wram(vec![0xEB, 0xFE]);
+        let benchmark = benchmark(&mut soc, &mut NullInput, 3);
+        assert_eq!(benchmark.frames, 3);
+        assert_eq!(soc.frames(), 3);
+        assert!(benchmark.instructions > 3 * 1000);
+        assert!(benchmark.time > Duration::ZERO);
+        assert_eq!(benchmark.profile.is_some(), ENABLED);
+    }
+
+    #[test]
+    fn test_report() {
+        let benchmark = Benchmark {frames: 150, instructions: 3_000_000, time: Duration::from_secs(1), profile: None};
+        let report = benchmark.report();
+        assert!(report.contains("150 frames in 1.000s"));
+        assert!(report.contains("3.00 million per second"));
+        assert!(report.contains("150.0 fps, 1.99x"));
+    }
+}
@@ -0,0 +1,248 @@
+//! Internal EEPROM splash and owner tool
+//!
+//! The WonderSwan Color's IPL shows the owner's name on boot and can show a custom splash stored in the internal EEPROM.
+//! This tool converts that splash to and from a PNG, moves the raw splash data between IEEPROM files and edits the owner settings.
+//!
+//! Usage:
+//! - `splash export <ieeprom> <png>` renders the custom splash to a PNG
+//! - `splash import <ieeprom> <png>` converts a PNG into the custom splash
+//! - `splash dump <ieeprom> <file>` writes the raw splash data to a file
+//! - `splash restore <ieeprom> <file>` replaces the splash data with the contents of a file
+//! - `splash owner <ieeprom> [key=value...]` prints the owner settings, or sets them
+//!
+//! The owner keys are `name`, `birthday` (YYYY-MM-DD), `sex` (male/female), `blood` (a/b/o/ab), `color` and `splash` (on/off).
+//! A missing IEEPROM file is treated as a blank color IEEPROM and created when written to.
+
+use std::{env, fs};
+
+use wonderswan::{owner::Owner, png::{decode_png, encode_png}};
+
+/// Size of the color IEEPROM in bytes
+const IEEPROM_SIZE: usize = 0x800;
+
+/// Start of the splash data, everything from here to the end of the first KiB belongs to the splash
+///
+/// The splash header is made up of
+///
+/// | Offset      | Contents                                                          |
+/// |-------------|-------------------------------------------------------------------|
+/// | 0x85        | Palette flags, bit 7 selects 4 bits per pixel tiles instead of 2  |
+/// | 0x86-0x87   | Offset of the palette, 16 RGB444 words                            |
+/// | 0x88-0x89   | Offset of the tiles, in the display's planar format               |
+/// | 0x8A-0x8B   | Offset of the tilemap, one tile index per byte                    |
+/// | 0x8C        | Width of the tilemap in tiles                                     |
+/// | 0x8D        | Height of the tilemap in tiles                                    |
+///
+/// Offsets are from the start of the IEEPROM.
+const SPLASH_START: usize = 0x85;
+/// End of the splash data
+const SPLASH_END: usize = 0x400;
+/// Largest splash in pixels, the size of the screen
+const SPLASH_MAX: (usize, usize) = (224, 144);
+
+/// Renders the custom splash's tilemap as 24-bit RGB, returns the width, height and pixels
+fn render_splash(ieeprom: &[u8]) -> Result<(usize, usize, Vec<u8>), String> {
+    let word = |addr: usize| u16::from_le_bytes([ieeprom[addr], ieeprom[addr + 1]]) as usize;
+    let four_bpp = ieeprom[SPLASH_START] & 0x80 != 0;
+    let (palette, tiles, tilemap) = (word(0x86), word(0x88), word(0x8A));
+    let (width, height) = (ieeprom[0x8C] as usize, ieeprom[0x8D] as usize);
+    if width == 0 || height == 0 {return Err("The IEEPROM contains no custom splash".to_string())}
+
+    let tile_size = if four_bpp {32} else {16};
+    let planes = if four_bpp {4} else {2};
+    let read = |addr: usize| ieeprom.get(addr).copied().ok_or(format!("Splash data at {:04X} is outside the IEEPROM", addr));
+
+    let mut pixels = vec![0; width * 8 * height * 8 * 3];
+    for (ty, tx) in (0..height).flat_map(|ty| (0..width).map(move |tx| (ty, tx))) {
+        let tile = tiles + read(tilemap + ty * width + tx)? as usize * tile_size;
+        for row in 0..8 {
+            let bytes = (0..planes).map(|plane| read(tile + row * planes + plane)).collect::<Result<Vec<_>, _>>()?;
+            for col in 0..8 {
+                let color = bytes.iter().enumerate().fold(0, |color, (plane, byte)| color | (((byte >> (7 - col)) & 1) as usize) << plane);
+                let rgb = read(palette + color * 2)? as u16 | (read(palette + color * 2 + 1)? as u16) << 8;
+                let idx = ((ty * 8 + row) * width * 8 + tx * 8 + col) * 3;
+                for (channel, shift) in [8, 4, 0].into_iter().enumerate() {
+                    pixels[idx + channel] = ((rgb >> shift) & 0x0F) as u8 * 0x11;
+                }
+            }
+        }
+    }
+    Ok((width * 8, height * 8, pixels))
+}
+
+/// Converts a PNG into the custom splash, replacing the previous one
+///
+/// The image must be made of whole tiles, fit on the screen and use at most 16 colors once reduced to RGB444.
+/// Up to 4 colors are stored as 2 bits per pixel tiles and more as 4 bits per pixel ones, identical tiles are stored once.
+/// The palette, tilemap and tiles are laid out in that order after the header and must fit before [`SPLASH_END`].
+fn import_splash(ieeprom: &mut [u8], png: &[u8]) -> Result<(), String> {
+    let (width, height, pixels) = decode_png(png)?;
+    if width == 0 || height == 0 || width % 8 != 0 || height % 8 != 0 || width > SPLASH_MAX.0 || height > SPLASH_MAX.1 {
+        return Err(format!("The splash must be a multiple of 8 pixels in each direction and at most {}x{}, got {}x{}", SPLASH_MAX.0, SPLASH_MAX.1, width, height));
+    }
+
+    // Reduces every pixel to RGB444, the inverse of the expansion done when rendering
+    let mut palette: Vec<u16> = Vec::new();
+    let mut indices = Vec::with_capacity(width * height);
+    for rgb in pixels.chunks(3) {
+        let color = rgb.iter().fold(0, |color, &channel| color << 4 | ((channel as u16 + 8) / 0x11));
+        let index = match palette.iter().position(|&c| c == color) {
+            Some(index) => index,
+            None => {palette.push(color); palette.len() - 1}
+        };
+        indices.push(index);
+    }
+    if palette.len() > 16 {return Err(format!("The splash can use at most 16 colors, the image has {}", palette.len()))}
+    let four_bpp = palette.len() > 4;
+    let planes = if four_bpp {4} else {2};
+
+    let (tiles_wide, tiles_high) = (width / 8, height / 8);
+    let mut tiles: Vec<Vec<u8>> = Vec::new();
+    let mut tilemap = Vec::with_capacity(tiles_wide * tiles_high);
+    for (ty, tx) in (0..tiles_high).flat_map(|ty| (0..tiles_wide).map(move |tx| (ty, tx))) {
+        let mut tile = Vec::with_capacity(8 * planes);
+        for row in 0..8 {
+            let line = &indices[(ty * 8 + row) * width + tx * 8..][..8];
+            for plane in 0..planes {
+                tile.push(line.iter().fold(0, |byte, &color| byte << 1 | ((color >> plane) & 1) as u8));
+            }
+        }
+        let index = match tiles.iter().position(|t| *t == tile) {
+            Some(index) => index,
+            None => {tiles.push(tile); tiles.len() - 1}
+        };
+        if index > 0xFF {return Err("The splash has more than 256 different tiles".to_string())}
+        tilemap.push(index as u8);
+    }
+
+    let palette_start = 0x8E;
+    let tilemap_start = palette_start + if four_bpp {32} else {8};
+    let tiles_start = tilemap_start + tilemap.len();
+    let end = tiles_start + tiles.len() * 8 * planes;
+    if end > SPLASH_END {
+        return Err(format!("The splash needs {} bytes but only {} fit in the IEEPROM, use fewer colors or more repeated tiles", end - SPLASH_START, SPLASH_END - SPLASH_START));
+    }
+
+    ieeprom[SPLASH_START..SPLASH_END].fill(0);
+    ieeprom[SPLASH_START] = if four_bpp {0x80} else {0};
+    for (offset, addr) in [palette_start, tiles_start, tilemap_start].into_iter().enumerate() {
+        ieeprom[0x86 + offset * 2..0x88 + offset * 2].copy_from_slice(&(addr as u16).to_le_bytes());
+    }
+    ieeprom[0x8C] = tiles_wide as u8;
+    ieeprom[0x8D] = tiles_high as u8;
+    for (i, color) in palette.iter().enumerate() {
+        ieeprom[palette_start + i * 2..palette_start + i * 2 + 2].copy_from_slice(&color.to_le_bytes());
+    }
+    ieeprom[tilemap_start..tiles_start].copy_from_slice(&tilemap);
+    ieeprom[tiles_start..end].copy_from_slice(&tiles.concat());
+    Ok(())
+}
+
+/// Reads an IEEPROM file, a missing file becomes a blank IEEPROM
+fn read_ieeprom(path: &str) -> Result<Vec<u8>, String> {
+    match fs::read(path) {
+        Ok(ieeprom) if ieeprom.len() == IEEPROM_SIZE => Ok(ieeprom),
+        Ok(ieeprom) => Err(format!("{} is {} bytes, only color IEEPROMs of {} bytes have owner and splash data", path, ieeprom.len(), IEEPROM_SIZE)),
+        Err(_) => Ok(vec![0; IEEPROM_SIZE]),
+    }
+}
+
+fn main() -> Result<(), String> {
+    let args: Vec<_> = env::args().skip(1).collect();
+    let usage = "Usage: splash <export|import|dump|restore|owner> <ieeprom> [args...]";
+    let (command, path) = match (args.first(), args.get(1)) {
+        (Some(command), Some(path)) => (command.as_str(), path.as_str()),
+        _ => return Err(usage.to_string()),
+    };
+    let mut ieeprom = read_ieeprom(path)?;
+    let file = args.get(2).ok_or(usage);
+
+    match command {
+        "export" => {
+            let (width, height, pixels) = render_splash(&ieeprom)?;
+            fs::write(file?, encode_png(width, height, &pixels)).map_err(|e| e.to_string())?;
+        }
+        "import" => {
+            let png = fs::read(file?).map_err(|e| e.to_string())?;
+            import_splash(&mut ieeprom, &png)?;
+            fs::write(path, &ieeprom).map_err(|e| e.to_string())?;
+        }
+        "dump" => fs::write(file?, &ieeprom[SPLASH_START..SPLASH_END]).map_err(|e| e.to_string())?,
+        "restore" => {
+            let splash = fs::read(file?).map_err(|e| e.to_string())?;
+            if splash.len() != SPLASH_END - SPLASH_START {
+                return Err(format!("Splash data must be {} bytes, got {}", SPLASH_END - SPLASH_START, splash.len()));
+            }
+            ieeprom[SPLASH_START..SPLASH_END].copy_from_slice(&splash);
+            fs::write(path, &ieeprom).map_err(|e| e.to_string())?;
+        }
+        "owner" => {
+            let mut owner = Owner::read(&ieeprom);
+            if args.len() > 2 {
+                for setting in &args[2..] {
+                    owner.set(setting)?;
+                }
+                owner.write(&mut ieeprom);
+                fs::write(path, &ieeprom).map_err(|e| e.to_string())?;
+            }
+            println!("{:#?}", Owner::read(&ieeprom));
+        }
+        _ => return Err(usage.to_string()),
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+#[cfg_attr(coverage_nightly, coverage(off))]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_render_splash() {
+        let mut ieeprom = vec![0; IEEPROM_SIZE];
+        // 2x1 tilemap at 0x100 using tiles 0 and 1, tiles at 0x120 and the palette at 0x200
+        ieeprom[0x86..0x8E].copy_from_slice(&[0x00, 0x02, 0x20, 0x01, 0x00, 0x01, 2, 1]);
+        ieeprom[0x101] = 1;
+        ieeprom[0x130..0x140].fill(0xFF);
+        ieeprom[0x206..0x208].copy_from_slice(&0x0F80u16.to_le_bytes());
+
+        let (width, height, pixels) = render_splash(&ieeprom).unwrap();
+        assert_eq!((width, height), (16, 8));
+        assert_eq!(&pixels[0..3], &[0, 0, 0]);
+        assert_eq!(&pixels[8 * 3..8 * 3 + 3], &[0xFF, 0x88, 0x00]);
+    }
+
+    #[test]
+    fn test_import_splash() {
+        // A 16x8 image whose halves are a gradient of 8 colors and a solid color, so 4 bits per pixel tiles are needed
+        let mut pixels = Vec::new();
+        for _ in 0..8 {
+            for x in 0..16 {
+                pixels.extend(if x < 8 {[x as u8 * 0x11, 0x22, 0xFF]} else {[0xFF, 0x88, 0x00]});
+            }
+        }
+        let mut ieeprom = vec![0; IEEPROM_SIZE];
+        import_splash(&mut ieeprom, &encode_png(16, 8, &pixels)).unwrap();
+        assert_eq!(ieeprom[SPLASH_START], 0x80);
+        assert_eq!(render_splash(&ieeprom).unwrap(), (16, 8, pixels));
+
+        // Identical tiles are only stored once
+        let solid = vec![0x44; 32 * 16 * 3];
+        import_splash(&mut ieeprom, &encode_png(32, 16, &solid)).unwrap();
+        assert_eq!(ieeprom[SPLASH_START], 0x00);
+        assert_eq!(&ieeprom[0x96..0x9E], &[0; 8]);
+        assert_eq!(render_splash(&ieeprom).unwrap(), (32, 16, solid));
+
+        assert!(import_splash(&mut ieeprom, &encode_png(12, 8, &vec![0; 12 * 8 * 3])).unwrap_err().contains("got 12x8"));
+        assert!(import_splash(&mut ieeprom, &encode_png(232, 8, &vec![0; 232 * 8 * 3])).is_err());
+        let colorful: Vec<u8> = (0..136 * 8).map(|i| i % 17).flat_map(|k| [(k % 16) as u8 * 0x11, (k / 16) as u8 * 0x11, 0]).collect();
+        assert!(import_splash(&mut ieeprom, &encode_png(136, 8, &colorful)).unwrap_err().contains("17"));
+        // 224 different 4 bits per pixel tiles do not fit, each row of a tile has its own color and the bits of the tile's index
+        let noisy: Vec<u8> = (0..224 * 64).flat_map(|i| {
+            let (x, y) = (i % 224, i / 224);
+            let tile = y / 8 * 28 + x / 8;
+            [(y % 8 * 2 + (tile >> (x % 8) & 1)) as u8 * 0x11, 0, 0]
+        }).collect();
+        assert!(import_splash(&mut ieeprom, &encode_png(224, 64, &noisy)).unwrap_err().contains("bytes"));
+    }
+}
@@ -1,8 +1,11 @@
-use std::{cell::RefCell, rc::Rc};
+use alloc::{format, rc::Rc, string::String, vec, vec::Vec};
+use core::cell::RefCell;
 
-use eeprom::EEPROM;
+use eeprom::{IeepromProtection, EEPROM};
+use serial::Serial;
+use timers::{Timer, HBLANK_TIMER, VBLANK_TIMER};
 
-use crate::{bus::io_bus::keypad::{Keypad, Keys}, cartridge::Cartridge, display::PaletteFormat};
+use crate::{bus::io_bus::keypad::{Keypad, Keys}, cartridge::{header::SaveType, Cartridge}, display::{timing::DisplayTiming, PaletteFormat}, owner::Owner, state::{Snapshot, StateError, StateReader, StateWriter}};
 
 /// IEEPROM and cartridge EEPROM
 /// 
@@ -14,7 +17,23 @@ mod eeprom;
 /// Module used for inputs
 /// 
 /// The keypad represents all of the system's built-in buttons.
-pub(crate) mod keypad;
+pub mod keypad;
+/// The link port and what can be plugged into it
+pub mod serial;
+/// The HBLANK and VBLANK countdown timers
+pub mod timers;
+
+/// The console models, which differ in how some hardware behaves
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum Model {
+    /// The original WonderSwan, built around the ASWAN SoC
+    MONO,
+    /// The WonderSwan Color, built around the SPHINX SoC
+    COLOR,
+}
+
+/// Mask of the VRAM addresses reachable outside of color mode, which only has 16 KB of it
+const MONO_VRAM_MASK: u16 = 0x3FFF;
 
 /// The WonderSwan's shared I/O bus
 pub struct IOBus {
@@ -30,6 +49,60 @@ pub struct IOBus {
 
     /// The console's built-in keys
     keypad: Keypad,
+    /// The link port
+    pub(crate) serial: Serial,
+
+    /// The console model being emulated
+    pub(crate) model: Model,
+
+    /// Output of the comparator between LCD_LINE and LCD_LINE_CMP, DISPLINE is raised on its rising edge
+    line_match: bool,
+
+    /// Ticks of the sound chip, used to timestamp PCM writes
+    pub(crate) sound_clock: u64,
+    /// Writes to the PCM sample port made while voice mode was enabled, along with when they happened
+    pcm_writes: Vec<(u64, u8)>,
+    /// Set when one of the sound ports 0x80-0x9F is written, cleared once the sound chip latches them
+    sound_dirty: bool,
+
+    /// Set once a WonderSwan Color is switched off through port 0x62, nothing but the power switch brings it back
+    powered_off: bool,
+    /// Whether the battery is reported as running low, set by the front-end to exercise games' power management
+    low_battery: bool,
+    /// Set when the battery runs low while port 0xB7 enables its NMI, cleared once the CPU takes it
+    nmi_pending: bool,
+
+    /// Ports whose writes are reported
+    pub(crate) port_triggers: Vec<PortTrigger>,
+    /// Writes that hit a trigger since the SoC last took them, oldest first
+    pub(crate) port_hits: Vec<PortHit>,
+}
+
+/// A port whose writes are reported, optionally only when some bits of the byte written match a value
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub struct PortTrigger {
+    /// Port watched
+    pub port: u8,
+    /// Bits of the byte written that have to match, none means every write is reported
+    pub mask: u8,
+    /// Value the masked bits have to match
+    pub value: u8,
+}
+
+impl PortTrigger {
+    /// Whether writing the byte to the port is reported
+    pub fn hit(&self, port: u8, byte: u8) -> bool {
+        port == self.port && byte & self.mask == self.value & self.mask
+    }
+}
+
+/// A write to a port that hit a trigger
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub struct PortHit {
+    /// Port written
+    pub port: u8,
+    /// Byte written
+    pub byte: u8,
 }
 
 /// Trait shared by objects which are connected to the I/O bus
@@ -68,15 +141,30 @@ pub trait IOBusConnection {
     }
 }
 
+/// Width of the register behind a port
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum PortWidth {
+    /// The port is a register of its own
+    BYTE,
+    /// The port is half of a 16-bit register, the even port holding the low byte
+    WORD,
+}
+
+/// Word accesses follow the width of the registers
+/// 
+/// Every word access is performed as two byte accesses, word reads always go low byte first.
+/// A word written to the even port of a 16-bit register is split by [`IOBus::split_word`] so the high byte is stored first, and side effects triggered by the low byte's port see the full word.
+/// Any other word write, including every write to an odd address, goes low byte first.
+/// Such accesses straddle two registers, for example a word written to port 0xBD sets the high byte of the EEPROM command before port 0xBE runs it.
 impl IOBusConnection for IOBus {
     fn read_io(&mut self, addr: u16) -> u8 {
-        let Some(port) = Self::check_open_bus(addr) else {return Self::open_bus()};
+        let Some(port) = self.check_open_bus(addr) else {return Self::open_bus()};
         // println!("Reading from {:02X}", port);
 
         match port {
             // SCR_LUT ports have undefined bits
-            0x28 | 0x2A | 0x2C | 0x2E | 0x38 | 0x3A | 0x3C | 0x3E => self.ports[addr as usize] & 0x70,
-            0x20..=0x3F => self.ports[addr as usize] & 0x77,
+            0x28 | 0x2A | 0x2C | 0x2E | 0x38 | 0x3A | 0x3C | 0x3E => self.ports[port as usize] & 0x70,
+            0x20..=0x3F => self.ports[port as usize] & 0x77,
 
             // Lowest bit of GDMA_SOURCE_L is always clear
             0x40 => self.ports[0x40] & 0xFE,
@@ -117,8 +205,11 @@ impl IOBusConnection for IOBus {
             // VBLANK is always enabled in INT_ENABLE
             0xB2 => self.ports[0xB2] | (1 << 6),
 
+            // SERIAL_DATA takes the byte received
+            0xB1 => self.serial.read_data(),
+
             // SERIAL_STATUS
-            0xB3 => 0x84,
+            0xB3 => self.serial.read_status(),
 
             // Reading INT_CAUSE clears edge interrupts
             0xB4 => {
@@ -146,6 +237,7 @@ impl IOBusConnection for IOBus {
             0xC1 => self.cartridge.borrow().read_ram_bank(),
             0xC2 => self.cartridge.borrow().read_rom_bank_0(),
             0xC3 => self.cartridge.borrow().read_rom_bank_1(),
+            0xCA | 0xCB => self.cartridge.borrow_mut().read_rtc(port),
             0xCF => self.cartridge.borrow().read_linear_addr_off_shadow(),
             0xD0 => self.cartridge.borrow().read_ram_bank_l(),
             0xD1 => self.cartridge.borrow().read_ram_bank_h(),
@@ -170,8 +262,16 @@ impl IOBusConnection for IOBus {
         }
     }
 
+    fn write_io_16(&mut self, addr: u16, word: u16) {
+        for (addr, byte) in self.split_word(addr, word) {
+            self.write_io(addr, byte);
+        }
+    }
+
     fn write_io(&mut self, addr: u16, byte: u8) {
-        let Some(port) = Self::check_open_bus(addr) else {return};
+        let Some(port) = self.check_open_bus(addr) else {return};
+        if port & 0xE0 == 0x80 {self.sound_dirty = true}
+        if self.port_triggers.iter().any(|trigger| trigger.hit(port, byte)) {self.port_hits.push(PortHit {port, byte})}
         // println!("{:02X} <- {:02X}", port, byte);
         // if (0xC4..=0xC9).contains(&addr) {println!("Cart EEPROM operation at {:02X}", port)}
         // if (0xBA..=0xBF).contains(&addr) {println!("IEEPROM operation at {:02X}", port)}
@@ -183,9 +283,22 @@ impl IOBusConnection for IOBus {
             }
             // LCD_LINE is read-only
             0x02 => {}
+            // SND_VOL_2 doubles as the PCM sample register in voice mode
+            0x89 => {
+                self.ports[0x89] = byte;
+                if self.ports[0x90] & 0x20 != 0 {
+                    self.pcm_writes.push((self.sound_clock, byte));
+                }
+            }
+
+            // LCD_LINE_CMP, the comparison happens immediately
+            0x03 => {
+                self.ports[0x03] = byte;
+                self.compare_lcd_line();
+            }
 
             // SCR_LUT ports have undefined bits
-            0x20..=0x3E => self.ports[addr as usize] = byte & 0x77,
+            0x20..=0x3E => self.ports[port as usize] = byte & 0x77,
 
             // Lowest bit of GDMA_SOURCE_L is always clear
             0x40 => self.ports[0x40] = byte & 0xFE,
@@ -206,22 +319,10 @@ impl IOBusConnection for IOBus {
             0x50 => self.ports[0x50] = byte & 0x0F,
             0x51 => {},
 
-            // Writing to HBLANK and VBLANK timers also sets the counters
-            0xA4 => {
-                self.ports[0xA4] = byte;
-                self.ports[0xA8] = byte;
-            }
-            0xA5 => {
-                self.ports[0xA5] = byte;
-                self.ports[0xA9] = byte;
-            }
-            0xA6 => {
-                self.ports[0xA6] = byte;
-                self.ports[0xAA] = byte;
-            }
-            0xA7 => {
-                self.ports[0xA7] = byte;
-                self.ports[0xAB] = byte;
+            // Writing either byte of the HBLANK and VBLANK timers reloads the whole counter
+            0xA4..=0xA7 => {
+                self.ports[port as usize] = byte;
+                if let Some(timer) = Timer::for_reload_port(port) {timer.reload(&mut self.ports)}
             }
 
             // Counters are read-only
@@ -230,8 +331,11 @@ impl IOBusConnection for IOBus {
             // VBLANK is always enabled in INT_ENABLE
             0xB2 => self.ports[0xB2] = byte | (1 << 6),
 
-            // SERIAL_STATUS is read-only
-            0xB3 => {}
+            // SERIAL_DATA sends a byte
+            0xB1 => self.serial.write_data(byte),
+
+            // SERIAL_STATUS sets the baud rate and clears overruns, the rest is read-only
+            0xB3 => self.serial.write_status(byte),
 
             // INT_CAUSE is read-only
             0xB4 => {}
@@ -247,6 +351,9 @@ impl IOBusConnection for IOBus {
                 }
             }
 
+            // SYSTEM_CTRL_3 only exists on the WonderSwan Color, setting bit 0 switches the console off
+            0x62 => if self.model == Model::COLOR && byte & 1 != 0 {self.powered_off = true},
+
             // INT_CAUSE_CLEAR clears bits of INT_CAUSE when written to
             0xB6 => {
                 self.ports[0xB6] = byte;
@@ -258,6 +365,7 @@ impl IOBusConnection for IOBus {
             0xC1 => self.cartridge.borrow_mut().write_ram_bank(byte),
             0xC2 => self.cartridge.borrow_mut().write_rom_bank_0(byte),
             0xC3 => self.cartridge.borrow_mut().write_rom_bank_1(byte),
+            0xCA | 0xCB => self.cartridge.borrow_mut().write_rtc(port, byte),
             0xCF => {}
             0xD0 => self.cartridge.borrow_mut().write_ram_bank_l(byte),
             0xD1 => self.cartridge.borrow_mut().write_ram_bank_h(byte),
@@ -267,30 +375,34 @@ impl IOBusConnection for IOBus {
             0xD5 => self.cartridge.borrow_mut().write_rom_bank_1_h(byte),
 
             // EEPROM ports
-            0xC4..=0xC7 => if self.eeprom.is_some() {
+            0xC4..=0xC7 => if self.eeprom.is_some() || self.cartridge.borrow().may_probe() {
                 // println!("[{:02X}] <- {:02X}", port, byte);
                 self.ports[port as usize] = byte;
             }
 
-            0xC8 => if let Some(eeprom) = &mut self.eeprom {
-                self.ports[0xC8] = byte & 0xF0;
-                let operation = byte >> 4;
-                // println!("Cart EEPROM operation: {:04b}", operation);
-                match operation {
-                    0b0001 => {
-                        eeprom.write_comm(u16::from_le_bytes([self.ports[0xC6], self.ports[0xC7]]));
-                        [self.ports[0xC4], self.ports[0xC5]] = eeprom.read_data().to_le_bytes();
-                        // println!("Read data from EEPROM: {:04X}", u16::from_le_bytes([self.ports[0xC4], self.ports[0xC5]]))
-                    }
-                    0b0010 => {
-                        let data = u16::from_le_bytes([self.ports[0xC4], self.ports[0xC5]]);
-                        let comm = u16::from_le_bytes([self.ports[0xC6], self.ports[0xC7]]);
-                        eeprom.write_data(data);
-                        eeprom.write_comm(comm);
-                        // println!("data: {:04X}, comm: {:04X}", data, comm);
+            0xC8 => {
+                // Without EEPROM, the first command is taken as the game reaching for one its footer did not declare
+                if self.eeprom.is_none() && self.cartridge.borrow().may_probe() {self.probe_eeprom()}
+                if let Some(eeprom) = &mut self.eeprom {
+                    self.ports[0xC8] = byte & 0xF0;
+                    let operation = byte >> 4;
+                    // println!("Cart EEPROM operation: {:04b}", operation);
+                    match operation {
+                        0b0001 => {
+                            eeprom.write_comm(u16::from_le_bytes([self.ports[0xC6], self.ports[0xC7]]));
+                            [self.ports[0xC4], self.ports[0xC5]] = eeprom.read_data().to_le_bytes();
+                            // println!("Read data from EEPROM: {:04X}", u16::from_le_bytes([self.ports[0xC4], self.ports[0xC5]]))
+                        }
+                        0b0010 => {
+                            let data = u16::from_le_bytes([self.ports[0xC4], self.ports[0xC5]]);
+                            let comm = u16::from_le_bytes([self.ports[0xC6], self.ports[0xC7]]);
+                            eeprom.write_data(data);
+                            eeprom.write_comm(comm);
+                            // println!("data: {:04X}, comm: {:04X}", data, comm);
+                        }
+                        0b0100 => eeprom.write_comm(u16::from_le_bytes([self.ports[0xC6], self.ports[0xC7]])),
+                        _ => {}
                     }
-                    0b0100 => eeprom.write_comm(u16::from_le_bytes([self.ports[0xC6], self.ports[0xC7]])),
-                    _ => {}
                 }
             }
             0xC9 => {},
@@ -299,12 +411,7 @@ impl IOBusConnection for IOBus {
                 self.ports[0xBE] = byte & 0xF0;
                 let operation = byte >> 4;
                 let comm = u16::from_le_bytes([self.ports[0xBC], self.ports[0xBD]]);
-                if operation != 0b0001 {
-                    let address_bits = if self.color_mode() {10} else {6};
-                    if (comm & ((1 << address_bits) - 1)) * 2 >= 0x60 {
-                        return;
-                    }
-                }
+                if !IeepromProtection::for_model(self.model).allows(comm) {return}
                 match operation {
                     0b0001 => {
                         self.ieeprom.write_comm(comm);
@@ -328,6 +435,53 @@ impl IOBusConnection for IOBus {
 }
 
 impl IOBus {
+    /// Returns the stored value of every port without the side effects of reading them
+    /// 
+    /// Ports computed on read, such as the keypad or the EEPROMs' data, hold whatever was last stored in them.
+    pub fn peek_ports(&self) -> &[u8; 0x100] {
+        &self.ports
+    }
+
+    /// Returns the width of the register a port belongs to
+    /// 
+    /// | Ports     | Registers                                    |
+    /// |-----------|----------------------------------------------|
+    /// | 0x40-0x47 | GDMA source, destination and counter         |
+    /// | 0x4A-0x51 | SDMA source and length                       |
+    /// | 0x80-0x87 | Sound channel frequencies                    |
+    /// | 0xA4-0xAB | HBLANK and VBLANK timer reloads and counters |
+    /// | 0xBA-0xBD | IEEPROM data and command                     |
+    /// | 0xC4-0xC7 | Cartridge EEPROM data and command            |
+    /// | 0xD0-0xD5 | 2003 mapper bank registers                   |
+    pub fn port_width(port: u8) -> PortWidth {
+        match port {
+            0x40..=0x47 | 0x4A..=0x51 | 0x80..=0x87 | 0xA4..=0xAB | 0xBA..=0xBD | 0xC4..=0xC7 | 0xD0..=0xD5 => PortWidth::WORD,
+            _ => PortWidth::BYTE,
+        }
+    }
+
+    /// Splits a word written to an address into the byte writes it is made of, in the order they reach the ports
+    /// 
+    /// The high byte of a 16-bit register is stored first, as side effects are triggered by the low byte's port.
+    pub fn split_word(&self, addr: u16, word: u16) -> [(u16, u8); 2] {
+        let [lo, hi] = word.to_le_bytes();
+        let (lo, hi) = ((addr, lo), (addr.wrapping_add(1), hi));
+        match self.check_open_bus(addr) {
+            Some(port) if addr & 1 == 0 && Self::port_width(port) == PortWidth::WORD => [hi, lo],
+            _ => [lo, hi],
+        }
+    }
+
+    /// Returns the owner settings stored in the internal EEPROM
+    pub fn owner(&self) -> Owner {
+        Owner::read(&self.ieeprom.contents)
+    }
+
+    /// Stores owner settings in the internal EEPROM, games read them back like ones entered in the IPL
+    pub fn set_owner(&mut self, owner: &Owner) {
+        owner.write(&mut self.ieeprom.contents);
+    }
+
     /// Returns a new I/O bus object
     /// 
     /// Requires the IEEPROM, an optional cartridge EEPROM, a boolean indicating whether to run in color mode, info about the ROM and a shared reference to the cartridge.
@@ -351,12 +505,29 @@ impl IOBus {
             Some(EEPROM::new(contents, address_bits))
         } else {None};
         
-        let mut bus = Self {ports: [0; 0x100], cartridge, keypad: Keypad::new(), eeprom, ieeprom};
+        let model = if color {Model::COLOR} else {Model::MONO};
+        let mut bus = Self {ports: [0; 0x100], cartridge, keypad: Keypad::new(), serial: Serial::default(), eeprom, ieeprom, model, line_match: false, sound_clock: 0, pcm_writes: Vec::new(), sound_dirty: true, powered_off: false, low_battery: false, nmi_pending: false, port_triggers: Vec::new(), port_hits: Vec::new()};
         if color {bus.color_setup()};
+        // The IPL leaves the LCD switched on with a frame of 159 lines
+        bus.ports[0x14] = 0x01;
+        bus.ports[0x16] = DisplayTiming::DEFAULT_VTOTAL;
         bus.ports[0xA0] |= rom_info;
         bus
     }
 
+    /// Returns the ports holding bits that writes can never set, with the bits that are wrong
+    ///
+    /// Writes mask these bits off, so a port breaking this was changed without going through [`IOBusConnection::write_io`].
+    pub(crate) fn invariant_violations(&self) -> Vec<String> {
+        // Ports and the bits they always keep clear
+        let mut clear: Vec<(u8, u8)> = (0x20..=0x3E).map(|port| (port, 0x88)).collect();
+        clear.extend([(0x40, 0x01), (0x42, 0xF0), (0x43, 0xFF), (0x44, 0x01), (0x46, 0x01), (0x4C, 0xF0), (0x4D, 0xFF), (0x50, 0xF0), (0x51, 0xFF)]);
+        clear.into_iter()
+            .filter(|(port, mask)| self.ports[*port as usize] & mask != 0)
+            .map(|(port, mask)| format!("port {:02X} = {:02X} has bits {:02X} set", port, self.ports[port as usize], self.ports[port as usize] & mask))
+            .collect()
+    }
+
     /// Returns whether or not the console is in color mode as indicated by port 0x60
     pub fn color_mode(&mut self) -> bool {
         self.ports[0x60] >> 7 != 0
@@ -376,6 +547,23 @@ impl IOBus {
         }
     }
 
+    /// Returns the address of screen 1's map, bits 0-3 of port 0x07 in units of 2 KB
+    ///
+    /// The display latches color mode once per frame, so whether it is enabled is passed in rather than read from port 0x60.
+    pub fn screen_1_base(&self, color: bool) -> u16 {
+        vram_base(((self.ports[0x07] & 0x0F) as u16) << 11, color)
+    }
+
+    /// Returns the address of screen 2's map, bits 4-7 of port 0x07 in units of 2 KB
+    pub fn screen_2_base(&self, color: bool) -> u16 {
+        vram_base(((self.ports[0x07] >> 4) as u16) << 11, color)
+    }
+
+    /// Returns the address of the sprite table, bits 0-5 of port 0x04 in units of 512 bytes
+    pub fn sprite_base(&self, color: bool) -> u16 {
+        vram_base(((self.ports[0x04] & 0x3F) as u16) << 9, color)
+    }
+
     /// Sets the values of ports 0x60 and 0xA0 to what would be expected in a WonderSwan Color model with color mode enabled
     pub fn color_setup(&mut self) {
         self.ports[0x60] = 0x80;
@@ -391,8 +579,12 @@ impl IOBus {
     }
 
     /// Sets the state of a key to be either pressed or unpressed
+    /// 
+    /// # Interrupt
+    /// The keys of the groups selected in port 0xB5 are wired to the KEY interrupt, so pressing one raises it even while the CPU sleeps.
     pub fn set_key(&mut self, key: Keys, pressed: bool) {
         self.keypad.set_key(key, pressed);
+        self.keypad.poll((self.ports[0xB5] & 0x70) >> 4);
         let old_keys = self.ports[0xB5];
         self.ports[0xB5] |= self.keypad.read_keys();
         if !old_keys & self.ports[0xB5] != 0 {
@@ -400,17 +592,84 @@ impl IOBus {
         }
     }
 
+    // Power functions
+
+    /// Returns whether the LCD is switched on, bit 0 of port 0x14, which is always off once the console is powered off
+    pub fn lcd_enabled(&self) -> bool {
+        self.ports[0x14] & 1 != 0 && !self.powered_off
+    }
+
+    /// Returns whether the console was switched off through port 0x62
+    pub fn powered_off(&self) -> bool {
+        self.powered_off
+    }
+
+    /// Reports the battery as running low or not
+    /// 
+    /// # Interrupt
+    /// The battery running low raises the NMI if bit 4 of port 0xB7 enables it, the NMI being edge triggered it is only raised once until the battery recovers.
+    pub fn set_low_battery(&mut self, low: bool) {
+        if low && !self.low_battery && self.ports[0xB7] & 0x10 != 0 {
+            self.nmi_pending = true;
+        }
+        self.low_battery = low;
+    }
+
+    /// Returns whether an NMI is waiting to be taken by the CPU
+    pub(crate) fn nmi_pending(&self) -> bool {
+        self.nmi_pending
+    }
+
+    /// Called by the CPU once it takes the NMI
+    pub(crate) fn acknowledge_nmi(&mut self) {
+        self.nmi_pending = false;
+    }
+
     // Display functions
 
+    /// Returns the frame timing selected by LCD_VTOTAL in port 0x16
+    pub fn display_timing(&self) -> DisplayTiming {
+        DisplayTiming::from_vtotal(self.ports[0x16])
+    }
+
     /// Called by the display controller to announce its current scanline
     /// 
     /// # Interrupt
     /// Can potentially trigger the DISPLINE interrupt if enabled and the scanline matches port 0x03
     pub(crate) fn set_lcd_line(&mut self, line: u8) {
         self.ports[0x02] = line;
-        if self.ports[0x02] == self.ports[0x03] {
+        self.compare_lcd_line();
+    }
+
+    /// Returns the sound ports 0x80-0x9F if any of them was written since they were last returned
+    /// 
+    /// The sound chip keeps its own copy of these ports, so that it only borrows the bus once per tick instead of reading each port.
+    pub(crate) fn take_sound_registers(&mut self) -> Option<[u8; 0x20]> {
+        if !self.sound_dirty {return None}
+        self.sound_dirty = false;
+        Some(self.ports[0x80..0xA0].try_into().unwrap())
+    }
+
+    /// Removes and returns the PCM writes made up to the given tick of the sound chip, oldest first
+    pub(crate) fn take_pcm_writes(&mut self, until: u64) -> Vec<(u64, u8)> {
+        let split = self.pcm_writes.partition_point(|(time, _)| *time <= until);
+        self.pcm_writes.drain(..split).collect()
+    }
+
+    /// Compares LCD_LINE with LCD_LINE_CMP
+    /// 
+    /// # Interrupt
+    /// DISPLINE is edge triggered, it is raised when the lines start matching,
+    /// either because the display moved to the compared line or because the compared line was set to the current one.
+    /// Writing the same value again while the lines match does not raise it again.
+    /// 
+    /// Compare values past the last scanline never match.
+    fn compare_lcd_line(&mut self) {
+        let line_match = self.ports[0x02] == self.ports[0x03];
+        if line_match && !self.line_match {
             self.ports[0xB4] |= (1 << 4) & self.ports[0xB2];
         }
+        self.line_match = line_match;
     }
 
     /// Called by the display controller to announce that it has finished rendering a frame
@@ -419,22 +678,7 @@ impl IOBus {
     /// Can trigger the VBLANK and VBLANK_COUNTER interrupts if enabled and their conditions are met
     pub (crate) fn vblank(&mut self) {
         self.ports[0xB4] |= (1 << 6) & self.ports[0xB2];
-        if self.ports[0xA2] & 4 != 0 {
-            let counter = u16::from_le_bytes([self.ports[0xAA], self.ports[0xAB]]);
-            if counter == 1 {
-                self.ports[0xB4] |= (1 << 5) & self.ports[0xB2];
-                if self.ports[0xA2] & 8 != 0 {
-                    self.ports[0xAA] = self.ports[0xA6];
-                    self.ports[0xAB] = self.ports[0xA7];
-                } else {
-                    self.ports[0xAA] = 0;
-                    self.ports[0xAB] = 0;
-                }
-            } else {
-                let counter = counter - 1;
-                [self.ports[0xAA], self.ports[0xAB]] = counter.to_le_bytes();
-            }
-        }
+        if VBLANK_TIMER.tick(&mut self.ports) {self.ports[0xB4] |= (1 << 5) & self.ports[0xB2]}
     }
 
     /// Called by the display controller to announce it has finished rendering a scanline
@@ -442,22 +686,10 @@ impl IOBus {
     /// # Interrupt
     /// Can trigger the HBLANK_COUNTER interrupt if enabled and is condition is met
     pub (crate) fn hblank(&mut self) {
-        if self.ports[0xA2] & 1 != 0 {
-            let counter = u16::from_le_bytes([self.ports[0xA8], self.ports[0xA9]]);
-            if counter == 1 {
-                self.ports[0xB4] |= (1 << 7) & self.ports[0xB2];
-                if self.ports[0xA2] & 2 != 0 {
-                    self.ports[0xA8] = self.ports[0xA4];
-                    self.ports[0xA9] = self.ports[0xA5];
-                } else {
-                    self.ports[0xA8] = 0;
-                    self.ports[0xA9] = 0;
-                }
-            } else {
-                let counter = counter - 1;
-                [self.ports[0xA8], self.ports[0xA9]] = counter.to_le_bytes();
-            }
-        }
+        // The serial interrupts are level triggered, following the state of the port
+        let serial = self.serial.line();
+        self.ports[0xB4] = (self.ports[0xB4] & !0x09) | (serial & self.ports[0xB2]);
+        if HBLANK_TIMER.tick(&mut self.ports) {self.ports[0xB4] |= (1 << 7) & self.ports[0xB2]}
     }
 
     // Sound functions
@@ -473,28 +705,260 @@ impl IOBus {
 
     /// Transforms the 16-bit address received by the bus into an 8-bit index
     /// 
+    /// Only bits 0-8 of the address are decoded, so the 512 byte window mirrors across the entire 16-bit space.
+    /// Within the window the decoding depends on the model:
+    /// 
+    /// | Address       | Mono          | Color                      |
+    /// |---------------|---------------|----------------------------|
+    /// | 0x000 - 0x0FF | port          | port                       |
+    /// | 0x100 - 0x1FF | open bus      | open bus                   |
+    /// | mirrors       | port          | port, open bus above 0xB8  |
+    /// 
+    /// The color SoC fully decodes its system, interrupt, serial and cartridge ports, so they only appear at their base address.
+    /// 
     /// # Returns
     /// - None, if the address is invalid
     /// - Some(port), the port mirrored into the valid addressing space
-    fn check_open_bus(addr: u16) -> Option<u8> {
+    fn check_open_bus(&self, addr: u16) -> Option<u8> {
         if addr & 0x0100 != 0 {
             return None;
         }
 
         let port = addr as u8;
-        if addr > 0xFF && port > 0xB8 {
+        if addr > 0xFF && self.model == Model::COLOR && port > 0xB8 {
             return None;
         }
 
-        return Some(port);
+        Some(port)
+    }
+
+    /// Allocates a blank cartridge EEPROM for the command in ports 0xC6-0xC7, see [`Cartridge::may_probe`]
+    ///
+    /// Commands start with a set start bit followed by a 2-bit opcode and the address, so the start bit gives away the EEPROM's size.
+    /// Commands that do not match a size cartridges came in are ignored.
+    fn probe_eeprom(&mut self) {
+        let comm = u16::from_le_bytes([self.ports[0xC6], self.ports[0xC7]]);
+        let (size, address_bits) = match comm.checked_ilog2().map(|start_bit| start_bit.saturating_sub(2)) {
+            Some(6) => (0x400, 6),
+            Some(10) => (0x4000, 10),
+            _ => return,
+        };
+        self.eeprom = Some(EEPROM::new(vec![0; size], address_bits));
+        self.cartridge.borrow_mut().probed = Some(SaveType::Eeprom(size));
     }
 
     #[allow(dead_code)]
     #[doc(hidden)]
+    #[cfg(feature = "std")]
     pub(crate) fn debug_eeprom(&self) {
         println!("IEEPROM {:#?}", self.ieeprom.contents);
         if let Some(eeprom) = &self.eeprom {
             println!("CART EEPROM: {:#?}", eeprom.contents);
         }
     }
-}
\ No newline at end of file
+}
+
+/// The I/O bus' state contains every port, including the timer counters, as well as the keypad, both EEPROMs and the cartridge
+impl Snapshot for IOBus {
+    const TAG: [u8; 4] = *b"IOBS";
+    const VERSION: u16 = 5;
+
+    fn save_fields(&self, w: &mut StateWriter) {
+        w.write_bytes(&self.ports);
+        w.write_bool(self.line_match);
+        for flag in [self.powered_off, self.low_battery, self.nmi_pending] {w.write_bool(flag)}
+        self.serial.save_state(w);
+        self.keypad.save_state(w);
+        self.ieeprom.save_state(w);
+        w.write_bool(self.eeprom.is_some());
+        if let Some(eeprom) = &self.eeprom {eeprom.save_state(w)}
+        self.cartridge.borrow().save_state(w);
+    }
+
+    fn load_fields(&mut self, r: &mut StateReader, version: u16) -> Result<(), StateError> {
+        let mut ports = r.read_array::<0x100>()?;
+        // Up to version 4 LCD_VTOTAL was ignored and left at 0 unless the game wrote it
+        if version < 5 {ports[0x16] = DisplayTiming::DEFAULT_VTOTAL}
+        // Version 1 did not save the line comparator
+        let line_match = if version >= 2 {r.read_bool()?} else {ports[0x02] == ports[0x03]};
+        // Version 2 did not save the power state
+        let power = if version >= 3 {[r.read_bool()?, r.read_bool()?, r.read_bool()?]} else {[false; 3]};
+        // Version 3 did not save the serial port
+        let mut serial = Serial::default();
+        if version >= 4 {serial.load_state(r)?}
+        let mut keypad = self.keypad.clone();
+        keypad.load_state(r)?;
+        let mut ieeprom = self.ieeprom.clone();
+        ieeprom.load_state(r)?;
+        let mut eeprom = self.eeprom.clone();
+        match (r.read_bool()?, &mut eeprom) {
+            (true, Some(eeprom)) => eeprom.load_state(r)?,
+            (false, None) => {}
+            _ => return Err(StateError::Mismatch("cartridge save type")),
+        }
+        // Loaded last, as it is shared and cannot be restored if anything after it fails
+        self.cartridge.borrow_mut().load_state(r)?;
+
+        (self.ports, self.line_match, self.keypad, self.ieeprom, self.eeprom) = (ports, line_match, keypad, ieeprom, eeprom);
+        [self.powered_off, self.low_battery, self.nmi_pending] = power;
+        serial.peer = self.serial.peer.take();
+        self.serial = serial;
+        self.sound_dirty = true;
+        Ok(())
+    }
+}
+
+/// Limits a base address set in ports 0x04 or 0x07 to the VRAM the display can reach
+///
+/// Outside of color mode the display only sees the first 16 KB, so the highest bit of each base is ignored
+/// and the maps and sprite table wrap into the mono VRAM, even on a WonderSwan Color.
+fn vram_base(base: u16, color: bool) -> u16 {
+    if color {base} else {base & MONO_VRAM_MASK}
+}
+
+#[cfg(test)]
+#[cfg_attr(coverage_nightly, coverage(off))]
+mod test {
+    use super::*;
+
+    fn test_io_bus() -> IOBus {
+        let cartridge = Rc::new(RefCell::new(Cartridge::test_build()));
+        let mut io_bus = IOBus::new(cartridge, Vec::new(), None, false, 0);
+        io_bus.ports[0xB2] = 1 << 4;
+        io_bus
+    }
+
+    fn displine(io_bus: &mut IOBus) -> bool {
+        let raised = io_bus.ports[0xB4] & (1 << 4) != 0;
+        io_bus.ports[0xB4] = 0;
+        raised
+    }
+
+    #[test]
+    fn test_video_bases() {
+        let mut io_bus = test_io_bus();
+        io_bus.write_io(0x07, 0xF7);
+        io_bus.write_io(0x04, 0x3F);
+        assert_eq!((io_bus.screen_1_base(true), io_bus.screen_2_base(true), io_bus.sprite_base(true)), (0x3800, 0x7800, 0x7E00));
+        assert_eq!((io_bus.screen_1_base(false), io_bus.screen_2_base(false), io_bus.sprite_base(false)), (0x3800, 0x3800, 0x3E00));
+
+        // Unused bits of port 0x04 are ignored
+        io_bus.write_io(0x07, 0x9A);
+        io_bus.write_io(0x04, 0xE1);
+        assert_eq!((io_bus.screen_1_base(true), io_bus.screen_2_base(true), io_bus.sprite_base(true)), (0x5000, 0x4800, 0x4200));
+        assert_eq!((io_bus.screen_1_base(false), io_bus.screen_2_base(false), io_bus.sprite_base(false)), (0x1000, 0x0800, 0x0200));
+    }
+
+    #[test]
+    fn test_displine_on_compare_write() {
+        let mut io_bus = test_io_bus();
+        io_bus.set_lcd_line(10);
+        assert!(!displine(&mut io_bus));
+
+        io_bus.write_io(0x03, 10);
+        assert!(displine(&mut io_bus));
+
+        // Already matching, no new edge
+        io_bus.write_io(0x03, 10);
+        assert!(!displine(&mut io_bus));
+
+        io_bus.write_io(0x03, 11);
+        io_bus.write_io(0x03, 10);
+        assert!(displine(&mut io_bus));
+    }
+
+    #[test]
+    fn test_gdma_word_access() {
+        let mut io_bus = test_io_bus();
+        io_bus.write_io_16(0x40, 0x1235);
+        assert_eq!(io_bus.read_io_16(0x40), (0x34, 0x12));
+        io_bus.write_io_16(0x46, 0xFFFF);
+        assert_eq!(io_bus.read_io_16(0x46), (0xFE, 0xFF));
+
+        // Odd addresses straddle the source's low word and its bank
+        io_bus.write_io_16(0x41, 0xABCD);
+        assert_eq!(io_bus.read_io_16(0x40), (0x34, 0xCD));
+        assert_eq!(io_bus.read_io_16(0x42), (0x0B, 0x00));
+        assert_eq!(io_bus.read_io_16(0x41), (0xCD, 0x0B));
+    }
+
+    #[test]
+    fn test_timer_word_access() {
+        let mut io_bus = test_io_bus();
+        io_bus.write_io(0xA2, 0x01);
+        io_bus.write_io_16(0xA4, 0x0102);
+        assert_eq!(io_bus.read_io_16(0xA8), (0x02, 0x01));
+
+        // Counting down borrows from the high byte
+        for _ in 0..3 {io_bus.hblank()}
+        assert_eq!(io_bus.read_io_16(0xA8), (0xFF, 0x00));
+
+        // Writing a single byte of the reload reloads the whole counter
+        io_bus.write_io(0xA5, 0x03);
+        assert_eq!(io_bus.read_io_16(0xA8), (0x02, 0x03));
+        io_bus.write_io_16(0xA9, 0xFFFF);
+        assert_eq!(io_bus.read_io_16(0xA8), (0x02, 0x03));
+
+        // A word at an odd address sets the high byte of the HBLANK reload and the low byte of the VBLANK one
+        io_bus.write_io_16(0xA5, 0x0605);
+        assert_eq!(io_bus.read_io_16(0xA8), (0x02, 0x05));
+        assert_eq!(io_bus.read_io_16(0xAA), (0x06, 0x00));
+    }
+
+    #[test]
+    fn test_split_word() {
+        let io_bus = test_io_bus();
+        assert_eq!(io_bus.split_word(0xA4, 0x1234), [(0xA5, 0x12), (0xA4, 0x34)]);
+        assert_eq!(io_bus.split_word(0xA5, 0x1234), [(0xA5, 0x34), (0xA6, 0x12)]);
+        assert_eq!(io_bus.split_word(0xB0, 0x1234), [(0xB0, 0x34), (0xB1, 0x12)]);
+        assert_eq!(IOBus::port_width(0x47), PortWidth::WORD);
+        assert_eq!(IOBus::port_width(0x48), PortWidth::BYTE);
+    }
+
+    #[test]
+    fn test_displine_once_per_line() {
+        let mut io_bus = test_io_bus();
+        io_bus.write_io(0x03, 150);
+        let mut raised = Vec::new();
+        for line in 0..=158 {
+            io_bus.set_lcd_line(line);
+            if displine(&mut io_bus) {raised.push(line)}
+        }
+        assert_eq!(raised, vec![150]);
+
+        // Staying on the same line keeps the comparator high
+        io_bus.set_lcd_line(150);
+        io_bus.set_lcd_line(150);
+        assert!(displine(&mut io_bus));
+        io_bus.set_lcd_line(150);
+        assert!(!displine(&mut io_bus));
+    }
+
+    #[test]
+    fn test_probe_eeprom() {
+        let cartridge = Rc::new(RefCell::new(Cartridge::new(crate::cartridge::Mapper::B_2001, Vec::new(), vec![0; 0x10000], true)));
+        let mut io_bus = IOBus::new(Rc::clone(&cartridge), Vec::new(), None, false, 0);
+        // Writes 0x1234 to word 5 of a 16 Kbit EEPROM, whose commands have their start bit at bit 12
+        let write = |io_bus: &mut IOBus| {
+            io_bus.write_io(0xC4, 0x34);
+            io_bus.write_io(0xC5, 0x12);
+            io_bus.write_io(0xC6, 0x05);
+            io_bus.write_io(0xC7, 0x14);
+            io_bus.write_io(0xC8, 0x20);
+        };
+        write(&mut io_bus);
+        assert!(io_bus.eeprom.is_none());
+
+        cartridge.borrow_mut().probe = true;
+        write(&mut io_bus);
+        assert_eq!(cartridge.borrow().probed, Some(SaveType::Eeprom(0x4000)));
+        assert_eq!(io_bus.eeprom.as_ref().unwrap().contents[10..12], [0x34, 0x12]);
+        io_bus.write_io(0xC6, 0x05);
+        io_bus.write_io(0xC7, 0x18);
+        io_bus.write_io(0xC8, 0x10);
+        assert_eq!((io_bus.read_io(0xC4), io_bus.read_io(0xC5)), (0x34, 0x12));
+        // SRAM is no longer looked for once the EEPROM is found
+        cartridge.borrow_mut().write_sram(0x10000, 0x01);
+        assert!(cartridge.borrow().sram.is_empty());
+    }
+}
@@ -1,8 +1,15 @@
+use alloc::vec::Vec;
+
+use crate::state::{Snapshot, StateError, StateReader, StateWriter};
+
+use super::Model;
+
 /// EEPROM struct
 /// 
 /// IEEPROMs differed in size between 1Kbit on mono models to 16 Kbit on color models
 /// 
 /// Cartridge EEPROMs differed between 1, 8 and 16 Kbits
+#[derive(Clone)]
 pub struct EEPROM {
     /// EEPROM contents as a byte vector
     pub contents: Vec<u8>,
@@ -142,4 +149,155 @@ impl EEPROM {
             _ => unreachable!()
         }
     }
-}
\ No newline at end of file
+}
+
+/// Which IEEPROM commands the console lets through port 0xBE
+/// 
+/// The end of the IEEPROM, from byte 0x60 onwards, holds the owner's settings and on color models the boot splash.
+/// Games can only write to the area before it, the console drops any command that would change the protected area.
+/// 
+/// | Model | Size  | Address bits | Writable    | Protected    |
+/// |-------|-------|--------------|-------------|--------------|
+/// | Mono  | 128 B | 6            | 0x000-0x05F | 0x060-0x07F  |
+/// | Color | 2 KB  | 10           | 0x000-0x05F | 0x060-0x7FF  |
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub struct IeepromProtection {
+    /// The size of the IEEPROM's addressing space
+    address_bits: u8,
+}
+
+impl IeepromProtection {
+    /// First byte of the protected area
+    pub const PROTECTED_START: u16 = 0x60;
+
+    /// Returns the protection of the IEEPROM fitted to a model
+    pub fn for_model(model: Model) -> Self {
+        match model {
+            Model::MONO => Self {address_bits: 6},
+            Model::COLOR => Self {address_bits: 10},
+        }
+    }
+
+    /// The size of the IEEPROM in bytes
+    pub fn size(&self) -> u16 {
+        2 << self.address_bits
+    }
+
+    /// Whether a byte of the IEEPROM is protected
+    pub fn is_protected(&self, address: u16) -> bool {
+        (Self::PROTECTED_START..self.size()).contains(&address)
+    }
+
+    /// Whether a command written to the IEEPROM's control port may run
+    /// 
+    /// Reads and the commands enabling or disabling writes always run.
+    /// WRITE and ERASE only run outside the protected area, while WRAL and ERAL never run since they would change all of it.
+    pub fn allows(&self, comm: u16) -> bool {
+        let opcode = (comm >> self.address_bits) & 3;
+        let address = (comm & ((1 << self.address_bits) - 1)) * 2;
+        match opcode {
+            // READ
+            2 => true,
+            // WRITE, ERASE
+            1 | 3 => !self.is_protected(address),
+            // EWDS, WRAL, ERAL, EWEN
+            _ => matches!((comm >> (self.address_bits - 2)) & 3, 0 | 3),
+        }
+    }
+}
+
+/// The EEPROM's state includes the latched command and data, so that states taken in the middle of a command can be resumed
+impl Snapshot for EEPROM {
+    const TAG: [u8; 4] = *b"EEPR";
+    const VERSION: u16 = 1;
+
+    fn save_fields(&self, w: &mut StateWriter) {
+        w.write_u8(self.address_bits);
+        w.write_vec(&self.contents);
+        w.write_u16(self.input);
+        w.write_u16(self.output);
+        w.write_u16(self.comm);
+        w.write_bool(self.write_enabled);
+    }
+
+    fn load_fields(&mut self, r: &mut StateReader, _version: u16) -> Result<(), StateError> {
+        if r.read_u8()? != self.address_bits {return Err(StateError::Mismatch("EEPROM addressing space"))}
+        let contents = r.read_vec()?;
+        if contents.len() != self.contents.len() {return Err(StateError::Mismatch("EEPROM size"))}
+        let (input, output, comm) = (r.read_u16()?, r.read_u16()?, r.read_u16()?);
+        let write_enabled = r.read_bool()?;
+
+        *self = Self {contents, input, output, comm, address_bits: self.address_bits, write_enabled};
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+#[cfg_attr(coverage_nightly, coverage(off))]
+mod test {
+    use super::*;
+
+    /// Builds a command with the start bit set
+    fn comm(protection: IeepromProtection, opcode: u16, address: u16) -> u16 {
+        (1 << (protection.address_bits + 2)) | (opcode << protection.address_bits) | (address / 2)
+    }
+
+    #[test]
+    fn test_protection_boundaries() {
+        for (model, size) in [(Model::MONO, 0x80), (Model::COLOR, 0x800)] {
+            let protection = IeepromProtection::for_model(model);
+            assert_eq!(protection.size(), size);
+
+            assert!(!protection.is_protected(0x00));
+            assert!(!protection.is_protected(0x5E));
+            assert!(!protection.is_protected(0x5F));
+            assert!(protection.is_protected(0x60));
+            assert!(protection.is_protected(size - 1));
+            assert!(!protection.is_protected(size));
+
+            for opcode in [1, 3] {
+                assert!(protection.allows(comm(protection, opcode, 0x00)));
+                assert!(protection.allows(comm(protection, opcode, 0x5E)));
+                assert!(!protection.allows(comm(protection, opcode, 0x60)));
+                assert!(!protection.allows(comm(protection, opcode, size - 2)));
+            }
+            assert!(protection.allows(comm(protection, 2, 0x60)));
+            assert!(protection.allows(comm(protection, 2, size - 2)));
+        }
+    }
+
+    #[test]
+    fn test_protection_sub_ops() {
+        for model in [Model::MONO, Model::COLOR] {
+            let protection = IeepromProtection::for_model(model);
+            let sub_op = |sub_op: u16| comm(protection, 0, 0) | (sub_op << (protection.address_bits - 2));
+            // EWDS and EWEN run, WRAL and ERAL would overwrite the protected area
+            assert!(protection.allows(sub_op(0)));
+            assert!(!protection.allows(sub_op(1)));
+            assert!(!protection.allows(sub_op(2)));
+            assert!(protection.allows(sub_op(3)));
+        }
+    }
+
+    #[test]
+    fn test_protected_write_through_port() {
+        use alloc::rc::Rc;
+        use core::cell::RefCell;
+        use crate::{bus::io_bus::{IOBus, IOBusConnection}, cartridge::Cartridge};
+
+        for color in [false, true] {
+            let cartridge = Rc::new(RefCell::new(Cartridge::test_build()));
+            let mut io_bus = IOBus::new(cartridge, Vec::new(), None, color, 0);
+            let protection = IeepromProtection::for_model(io_bus.model);
+            let mut write = |address: u16| {
+                io_bus.write_io_16(0xBA, 0xBEEF);
+                io_bus.write_io_16(0xBC, comm(protection, 1, address));
+                io_bus.write_io(0xBE, 0x20);
+            };
+            write(0x5E);
+            write(0x60);
+
+            assert_eq!(io_bus.ieeprom.contents[0x5E..0x62], [0xEF, 0xBE, 0x00, 0x00]);
+        }
+    }
+}
@@ -1,5 +1,7 @@
 use bitflags::bitflags;
 
+use crate::state::{Snapshot, StateError, StateReader, StateWriter};
+
 bitflags! {
     /// Bitflags representing each button
     #[derive(Copy, Clone, Debug)]
@@ -21,6 +23,7 @@ bitflags! {
 }
 
 /// Contains the state of the console's built-in buttons
+#[derive(Clone)]
 pub struct Keypad {
     /// Describes which buttons are currently pressed using a `u16` representing bitflags referring to each button
     state: Keys,
@@ -64,4 +67,27 @@ impl Keypad {
     pub(super) fn set_key(&mut self, key: Keys, pressed: bool) {
         self.state.set(key, pressed);
     }
-}
\ No newline at end of file
+}
+
+impl Default for Keypad {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Snapshot for Keypad {
+    const TAG: [u8; 4] = *b"KEYS";
+    const VERSION: u16 = 1;
+
+    fn save_fields(&self, w: &mut StateWriter) {
+        w.write_u16(self.state.bits());
+        w.write_u8(self.keys);
+    }
+
+    fn load_fields(&mut self, r: &mut StateReader, _version: u16) -> Result<(), StateError> {
+        let state = Keys::from_bits_truncate(r.read_u16()?);
+        let keys = r.read_u8()?;
+        (self.state, self.keys) = (state, keys);
+        Ok(())
+    }
+}
@@ -0,0 +1,317 @@
+use alloc::{boxed::Box, collections::VecDeque, rc::Rc, vec::Vec};
+use core::cell::RefCell;
+
+use crate::state::{Snapshot, StateError, StateReader, StateWriter};
+
+/// Lines a byte takes to go through the cable at 9600 baud, 10 bits of 320 ticks each
+const SLOW_BYTE_LINES: u8 = 13;
+/// Lines a byte takes to go through the cable at 38400 baud
+const FAST_BYTE_LINES: u8 = 4;
+
+/// Whatever is plugged into the other end of the link cable
+///
+/// Bytes move one at a time in each direction at the speed the console set in SER_STATUS, the peer being asked for a byte once per byte time.
+pub trait SerialPeer {
+    /// Takes a byte the console finished sending
+    fn send(&mut self, byte: u8);
+    /// Returns the next byte the peer sends the console, if it has one
+    fn receive(&mut self) -> Option<u8>;
+}
+
+/// A plug wiring the console's output back to its input, so that every byte sent is received again
+#[derive(Default)]
+pub struct Loopback {
+    /// Bytes sent but not yet received back
+    pending: VecDeque<u8>,
+}
+
+impl SerialPeer for Loopback {
+    fn send(&mut self, byte: u8) {
+        self.pending.push_back(byte);
+    }
+
+    fn receive(&mut self) -> Option<u8> {
+        self.pending.pop_front()
+    }
+}
+
+/// A peer replying to every byte the console sends with the next byte of a script, for testing a game's handshake without a second console
+///
+/// Bytes in `greeting` are sent before the console sends anything, as by a console that starts the handshake.
+pub struct ScriptedPeer {
+    /// Bytes waiting to be sent to the console
+    outgoing: VecDeque<u8>,
+    /// Replies left, one taken for each byte the console sends
+    replies: VecDeque<u8>,
+    /// Every byte the console sent, shared so that it can be inspected once the peer is attached
+    pub sent: Rc<RefCell<Vec<u8>>>,
+}
+
+impl ScriptedPeer {
+    /// Creates a peer sending `greeting` first, then one of `replies` for each byte it receives
+    pub fn new(greeting: &[u8], replies: &[u8]) -> Self {
+        Self {outgoing: greeting.iter().copied().collect(), replies: replies.iter().copied().collect(), sent: Rc::default()}
+    }
+}
+
+impl SerialPeer for ScriptedPeer {
+    fn send(&mut self, byte: u8) {
+        self.sent.borrow_mut().push(byte);
+        if let Some(reply) = self.replies.pop_front() {self.outgoing.push_back(reply)}
+    }
+
+    fn receive(&mut self) -> Option<u8> {
+        self.outgoing.pop_front()
+    }
+}
+
+/// One end of a link cable between two consoles emulated in the same process
+///
+/// The consoles are run one after the other, for example a frame at a time, so each byte arrives once the other console catches up.
+pub struct CableEnd {
+    /// Bytes this end sent, read by the other end
+    outgoing: Rc<RefCell<VecDeque<u8>>>,
+    /// Bytes the other end sent
+    incoming: Rc<RefCell<VecDeque<u8>>>,
+}
+
+/// Creates a link cable, each end being attached to one of the consoles
+pub fn link_cable() -> (CableEnd, CableEnd) {
+    let (a, b) = (Rc::new(RefCell::new(VecDeque::new())), Rc::new(RefCell::new(VecDeque::new())));
+    (CableEnd {outgoing: Rc::clone(&a), incoming: Rc::clone(&b)}, CableEnd {outgoing: b, incoming: a})
+}
+
+impl SerialPeer for CableEnd {
+    fn send(&mut self, byte: u8) {
+        self.outgoing.borrow_mut().push_back(byte);
+    }
+
+    fn receive(&mut self) -> Option<u8> {
+        self.incoming.borrow_mut().pop_front()
+    }
+}
+
+/// The serial port behind SER_DATA (port 0xB1) and SER_STATUS (port 0xB3)
+///
+/// SER_DATA is a latch holding the last byte received: reading it empties the receive buffer but keeps the byte,
+/// so a game polling it while nothing arrives reads the same byte again, 0 if nothing ever arrived, rather than open bus.
+/// A byte arriving while the previous one is unread replaces it and sets the overrun flag until the game clears it.
+///
+/// | SER_STATUS bit | Read                           | Write                    |
+/// |----------------|--------------------------------|--------------------------|
+/// | 7              | Port enabled                   | Enables the port         |
+/// | 6              | 38400 baud rather than 9600    | Selects the baud rate    |
+/// | 5              |                                | Clears the overrun flag  |
+/// | 2              | Send buffer empty              |                          |
+/// | 1              | Overrun                        |                          |
+/// | 0              | Receive buffer full            |                          |
+///
+/// Without a peer nothing is plugged in, the port then reads as enabled with an empty send buffer as it always did.
+#[derive(Default)]
+pub struct Serial {
+    /// Whatever is plugged into the link port
+    pub(crate) peer: Option<Box<dyn SerialPeer>>,
+    /// Bits 6 and 7 of SER_STATUS, the baud rate and whether the port is enabled
+    control: u8,
+    /// Byte being sent along with the lines left until it is out
+    sending: Option<(u8, u8)>,
+    /// The last byte received, read through SER_DATA
+    data: u8,
+    /// Whether `data` has not been read yet
+    full: bool,
+    /// Set when a byte arrived while the last one was still unread
+    overrun: bool,
+    /// Lines left until the peer is asked for the next byte
+    receive_lines: u8,
+}
+
+impl Serial {
+    /// Whether the port is enabled, which nothing but a peer can make use of
+    fn enabled(&self) -> bool {
+        self.peer.is_some() && self.control & 0x80 != 0
+    }
+
+    /// Returns the lines a byte takes at the baud rate set
+    fn byte_lines(&self) -> u8 {
+        if self.control & 0x40 != 0 {FAST_BYTE_LINES} else {SLOW_BYTE_LINES}
+    }
+
+    /// Reads SER_DATA, emptying the receive buffer
+    pub fn read_data(&mut self) -> u8 {
+        self.full = false;
+        self.data
+    }
+
+    /// Writes SER_DATA, sending a byte unless one is still being sent
+    pub fn write_data(&mut self, byte: u8) {
+        if self.enabled() && self.sending.is_none() {self.sending = Some((byte, self.byte_lines()))}
+    }
+
+    /// Reads SER_STATUS
+    pub fn read_status(&self) -> u8 {
+        if self.peer.is_none() {return 0x84}
+        self.control | (self.sending.is_none() as u8) << 2 | (self.overrun as u8) << 1 | self.full as u8
+    }
+
+    /// Writes SER_STATUS, bit 5 clears the overrun flag
+    pub fn write_status(&mut self, byte: u8) {
+        self.control = byte & 0xC0;
+        if byte & 0x20 != 0 {self.overrun = false}
+    }
+
+    /// Moves bytes through the cable for the length of a line, returns the serial bits of INT_CAUSE, send buffer empty and byte received
+    pub fn line(&mut self) -> u8 {
+        if !self.enabled() {return 0}
+        if let Some((byte, lines)) = self.sending {
+            self.sending = if lines <= 1 {
+                self.peer.as_mut().unwrap().send(byte);
+                None
+            } else {
+                Some((byte, lines - 1))
+            };
+        }
+        self.receive_lines = self.receive_lines.saturating_sub(1);
+        if self.receive_lines == 0 {
+            self.receive_lines = self.byte_lines();
+            if let Some(byte) = self.peer.as_mut().unwrap().receive() {
+                if self.full {self.overrun = true}
+                (self.data, self.full) = (byte, true);
+            }
+        }
+        self.sending.is_none() as u8 | (self.full as u8) << 3
+    }
+}
+
+/// The peer is not part of the state, whatever is plugged in stays plugged in
+impl Snapshot for Serial {
+    const TAG: [u8; 4] = *b"SERL";
+    const VERSION: u16 = 1;
+
+    fn save_fields(&self, w: &mut StateWriter) {
+        w.write_u8(self.control);
+        w.write_bool(self.sending.is_some());
+        let (byte, lines) = self.sending.unwrap_or_default();
+        w.write_bytes(&[byte, lines]);
+        w.write_bool(self.full);
+        w.write_u8(self.data);
+        w.write_bool(self.overrun);
+        w.write_u8(self.receive_lines);
+    }
+
+    fn load_fields(&mut self, r: &mut StateReader, _version: u16) -> Result<(), StateError> {
+        let control = r.read_u8()?;
+        let sending = (r.read_bool()?, r.read_array::<2>()?);
+        let received = (r.read_bool()?, r.read_u8()?);
+        let (overrun, receive_lines) = (r.read_bool()?, r.read_u8()?);
+        self.control = control;
+        self.sending = sending.0.then_some((sending.1[0], sending.1[1]));
+        (self.full, self.data) = received;
+        (self.overrun, self.receive_lines) = (overrun, receive_lines);
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+#[cfg_attr(coverage_nightly, coverage(off))]
+mod test {
+    use crate::{bus::{io_bus::IOBusConnection, mem_bus::MemBusConnection}, soc::SoC};
+
+    use super::*;
+
+    /// Sends 0x5A, then stores the first byte received at 0x1000
+    const SEND_THEN_RECEIVE: [u8; 22] = [
+        0xB0, 0x80,       // MOV AL, 0x80
+        0xE6, 0xB3,       // OUT 0xB3, AL
+        0xB0, 0x5A,       // MOV AL, 0x5A
+        0xE6, 0xB1,       // OUT 0xB1, AL
+        0xE4, 0xB3,       // IN AL, 0xB3
+        0xA8, 0x01,       // TEST AL, 0x01
+        0x74, 0xFA,       // BE -6
+        0xE4, 0xB1,       // IN AL, 0xB1
+        0xA2, 0x00, 0x10, // MOV [0x1000], AL
+        0xEB, 0xFE,       // BR -2
+        0x90,
+    ];
+
+    fn soc_with(program: &[u8], peer: Option<Box<dyn SerialPeer>>) -> SoC {
+        let mut soc = SoC::test_build();
+        soc.set_wram(program.to_vec());
+        soc.set_serial_peer(peer);
+        soc
+    }
+
+    #[test]
+    fn test_loopback() {
+        let mut soc = soc_with(&SEND_THEN_RECEIVE, Some(Box::new(Loopback::default())));
+        for _ in 0..2 {soc.run_frame();}
+        assert_eq!(soc.read_mem(0x1000), 0x5A);
+    }
+
+    #[test]
+    fn test_scripted_peer() {
+        let peer = ScriptedPeer::new(&[], &[0x99]);
+        let sent = Rc::clone(&peer.sent);
+        let mut soc = soc_with(&SEND_THEN_RECEIVE, Some(Box::new(peer)));
+        for _ in 0..2 {soc.run_frame();}
+        assert_eq!(soc.read_mem(0x1000), 0x99);
+        assert_eq!(*sent.borrow(), [0x5A]);
+
+        // Nothing plugged in, the port reads as it always did and nothing arrives
+        let mut soc = soc_with(&SEND_THEN_RECEIVE, None);
+        for _ in 0..2 {soc.run_frame();}
+        assert_eq!(soc.read_io(0xB3), 0x84);
+        assert_eq!(soc.read_mem(0x1000), 0x01);
+    }
+
+    #[test]
+    fn test_receive_buffer() {
+        let mut serial = Serial {peer: Some(Box::new(Loopback::default())), ..Serial::default()};
+        serial.write_status(0x80);
+        // Nothing received yet, the data port reads a defined 0 with the buffer empty
+        assert_eq!((serial.read_data(), serial.read_status()), (0x00, 0x84));
+
+        let send = |serial: &mut Serial, byte| {
+            serial.write_data(byte);
+            for _ in 0..2 * SLOW_BYTE_LINES {serial.line();}
+        };
+        send(&mut serial, 0x12);
+        assert_eq!(serial.read_status(), 0x85);
+        assert_eq!(serial.read_data(), 0x12);
+        // The byte stays latched once read
+        assert_eq!((serial.read_data(), serial.read_status()), (0x12, 0x84));
+
+        // A second byte before the first is read replaces it and flags the overrun, until the game clears it
+        send(&mut serial, 0x34);
+        send(&mut serial, 0x56);
+        assert_eq!(serial.read_status(), 0x87);
+        assert_eq!(serial.read_data(), 0x56);
+        serial.write_status(0xA0);
+        assert_eq!(serial.read_status(), 0x84);
+    }
+
+    #[test]
+    fn test_link_cable() {
+        // Waits for a byte, stores it at 0x1000 and sends it back plus one
+        let reply = [
+            0xB0, 0x80,       // MOV AL, 0x80
+            0xE6, 0xB3,       // OUT 0xB3, AL
+            0xE4, 0xB3,       // IN AL, 0xB3
+            0xA8, 0x01,       // TEST AL, 0x01
+            0x74, 0xFA,       // BE -6
+            0xE4, 0xB1,       // IN AL, 0xB1
+            0xA2, 0x00, 0x10, // MOV [0x1000], AL
+            0xFE, 0xC0,       // INC AL
+            0xE6, 0xB1,       // OUT 0xB1, AL
+            0xEB, 0xFE,       // BR -2
+        ];
+        let (a, b) = link_cable();
+        let mut first = soc_with(&SEND_THEN_RECEIVE, Some(Box::new(a)));
+        let mut second = soc_with(&reply, Some(Box::new(b)));
+        for _ in 0..3 {
+            first.run_frame();
+            second.run_frame();
+        }
+        assert_eq!(second.read_mem(0x1000), 0x5A);
+        assert_eq!(first.read_mem(0x1000), 0x5B);
+    }
+}
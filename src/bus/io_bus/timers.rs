@@ -0,0 +1,124 @@
+/// One of the two countdown timers, described by where it lives in the I/O ports
+///
+/// The timers keep their whole state in the ports, so that savestates and the debugger see them as the CPU does.
+/// TIMER_CTRL (0xA2) enables each of them and chooses whether it repeats, a 16-bit reload port sets the count and a read-only port holds what is left of it.
+///
+/// A counter only counts while it is above 0. When it would reach 0 the timer expires: it interrupts and, if it repeats, starts over from its reload value.
+/// A reload value of 0 therefore stops the timer, and a reload value of 1 with repeat on makes it expire on every line or frame.
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub struct Timer {
+    /// Bit of TIMER_CTRL enabling the timer
+    enable: u8,
+    /// Bit of TIMER_CTRL making the timer start over when it expires instead of stopping
+    repeat: u8,
+    /// Port of the reload value's low byte
+    reload: usize,
+    /// Port of the counter's low byte
+    counter: usize,
+}
+
+/// Counts the lines drawn, its counter is in HTMR_CTR (0xA8) and its reload in HTMR_FREQ (0xA4)
+pub const HBLANK_TIMER: Timer = Timer {enable: 1 << 0, repeat: 1 << 1, reload: 0xA4, counter: 0xA8};
+/// Counts the frames drawn, its counter is in VTMR_CTR (0xAA) and its reload in VTMR_FREQ (0xA6)
+pub const VBLANK_TIMER: Timer = Timer {enable: 1 << 2, repeat: 1 << 3, reload: 0xA6, counter: 0xAA};
+
+impl Timer {
+    /// Returns the timer whose reload value contains the port, if any
+    pub fn for_reload_port(port: u8) -> Option<Timer> {
+        [HBLANK_TIMER, VBLANK_TIMER].into_iter().find(|timer| port as usize & 0xFE == timer.reload)
+    }
+
+    /// Returns what is left of the count
+    pub fn counter(&self, ports: &[u8; 0x100]) -> u16 {
+        u16::from_le_bytes([ports[self.counter], ports[self.counter + 1]])
+    }
+
+    fn set_counter(&self, ports: &mut [u8; 0x100], counter: u16) {
+        [ports[self.counter], ports[self.counter + 1]] = counter.to_le_bytes();
+    }
+
+    /// Starts the count over from the reload value, writing either byte of the reload port does so at any point of the count
+    pub fn reload(&self, ports: &mut [u8; 0x100]) {
+        self.set_counter(ports, u16::from_le_bytes([ports[self.reload], ports[self.reload + 1]]));
+    }
+
+    /// Counts a line or frame, returns whether the timer expired
+    pub fn tick(&self, ports: &mut [u8; 0x100]) -> bool {
+        let control = ports[0xA2];
+        let counter = self.counter(ports);
+        if control & self.enable == 0 || counter == 0 {return false}
+        if counter > 1 {
+            self.set_counter(ports, counter - 1);
+            return false;
+        }
+        if control & self.repeat != 0 {self.reload(ports)} else {self.set_counter(ports, 0)}
+        true
+    }
+}
+
+#[cfg(test)]
+#[cfg_attr(coverage_nightly, coverage(off))]
+mod test {
+    use alloc::vec::Vec;
+
+    use super::*;
+
+    /// Returns ports with the VBLANK timer enabled, repeating or not, and loaded with a reload value
+    fn vblank_ports(repeat: bool, reload: u16) -> [u8; 0x100] {
+        let mut ports = [0; 0x100];
+        ports[0xA2] = VBLANK_TIMER.enable | if repeat {VBLANK_TIMER.repeat} else {0};
+        [ports[0xA6], ports[0xA7]] = reload.to_le_bytes();
+        VBLANK_TIMER.reload(&mut ports);
+        ports
+    }
+
+    #[test]
+    fn test_reload_zero() {
+        let mut ports = vblank_ports(true, 0);
+        for _ in 0..3 {assert!(!VBLANK_TIMER.tick(&mut ports))}
+        assert_eq!(VBLANK_TIMER.counter(&ports), 0);
+    }
+
+    #[test]
+    fn test_reload_one() {
+        let mut ports = vblank_ports(true, 1);
+        for _ in 0..3 {assert!(VBLANK_TIMER.tick(&mut ports))}
+        assert_eq!(VBLANK_TIMER.counter(&ports), 1);
+    }
+
+    #[test]
+    fn test_one_shot() {
+        let mut ports = vblank_ports(false, 3);
+        let expiries: Vec<bool> = (0..5).map(|_| VBLANK_TIMER.tick(&mut ports)).collect();
+        assert_eq!(expiries, [false, false, true, false, false]);
+        assert_eq!(VBLANK_TIMER.counter(&ports), 0);
+
+        // Repeating timers start over from their reload value
+        let mut ports = vblank_ports(true, 3);
+        let expiries: Vec<bool> = (0..6).map(|_| VBLANK_TIMER.tick(&mut ports)).collect();
+        assert_eq!(expiries, [false, false, true, false, false, true]);
+    }
+
+    #[test]
+    fn test_mid_count_write() {
+        let mut ports = vblank_ports(false, 5);
+        VBLANK_TIMER.tick(&mut ports);
+        VBLANK_TIMER.tick(&mut ports);
+        assert_eq!(VBLANK_TIMER.counter(&ports), 3);
+        ports[0xA6] = 2;
+        VBLANK_TIMER.reload(&mut ports);
+        assert!(!VBLANK_TIMER.tick(&mut ports));
+        assert!(VBLANK_TIMER.tick(&mut ports));
+    }
+
+    #[test]
+    fn test_disabled() {
+        let mut ports = vblank_ports(true, 2);
+        ports[0xA2] = HBLANK_TIMER.enable;
+        assert!(!VBLANK_TIMER.tick(&mut ports));
+        assert_eq!(VBLANK_TIMER.counter(&ports), 2);
+        assert_eq!(Timer::for_reload_port(0xA7), Some(VBLANK_TIMER));
+        assert_eq!(Timer::for_reload_port(0xA5), Some(HBLANK_TIMER));
+        assert_eq!(Timer::for_reload_port(0xA8), None);
+    }
+}
@@ -1,6 +1,7 @@
-use std::{cell::RefCell, rc::Rc};
+use alloc::{rc::Rc, vec::Vec};
+use core::cell::RefCell;
 
-use crate::cartridge::Cartridge;
+use crate::{cartridge::Cartridge, state::{Snapshot, StateError, StateReader, StateWriter}};
 
 use super::io_bus::IOBus;
 
@@ -13,6 +14,94 @@ pub enum Owner {
     CPU,
     /// Used when the GDMA is operating
     DMA,
+    /// Used while the display chip is fetching tiles, screen elements or sprites from WRAM
+    DISPLAY,
+}
+
+/// Components that access the memory bus, so that accesses can be told apart
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum Accessor {
+    /// The CPU, including its instruction fetches
+    CPU,
+    /// The general DMA
+    GDMA,
+    /// The sound DMA
+    SDMA,
+    /// The display chip fetching tiles, screen elements and sprites
+    DISPLAY,
+    /// The sound chip fetching waveforms
+    SOUND,
+}
+
+impl Accessor {
+    /// Every component accessing the bus
+    pub const ALL: [Accessor; 5] = [Accessor::CPU, Accessor::GDMA, Accessor::SDMA, Accessor::DISPLAY, Accessor::SOUND];
+
+    /// Whether accesses are made by the display or sound chips on their own, rather than by the game's code
+    ///
+    /// These are left out of the access heat and watchpoints, as they would drown out everything else in WRAM.
+    pub fn is_fetch(&self) -> bool {
+        matches!(self, Accessor::DISPLAY | Accessor::SOUND)
+    }
+}
+
+/// A range of addresses whose accesses are reported
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub struct Watchpoint {
+    /// First watched address
+    pub start: u32,
+    /// Last watched address
+    pub end: u32,
+    /// Whether reads are reported
+    pub reads: bool,
+    /// Whether writes are reported
+    pub writes: bool,
+}
+
+impl Watchpoint {
+    /// Whether an access to the address is reported
+    pub fn hit(&self, addr: u32, write: bool) -> bool {
+        (self.start..=self.end).contains(&addr) && if write {self.writes} else {self.reads}
+    }
+}
+
+/// An access to a watched address
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub struct WatchHit {
+    /// The component that made the access
+    pub accessor: Accessor,
+    /// Address accessed
+    pub addr: u32,
+    /// Whether the access was a write
+    pub write: bool,
+    /// Byte at the address before the access
+    pub before: u8,
+    /// Byte at the address after the access, writes to ROM leave it unchanged
+    pub after: u8,
+}
+
+/// An access to the bus recorded by a [`BusLog`]
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub struct BusTransaction {
+    /// Ticks since power on, each being 4 cycles of the master clock
+    pub tick: u64,
+    /// Component making the access
+    pub accessor: Accessor,
+    /// Whether the access is a write
+    pub write: bool,
+    /// Address accessed
+    pub addr: u32,
+    /// Byte read or written
+    pub value: u8,
+}
+
+/// Records every access to the bus, including the display and sound chips' fetches
+#[derive(Clone, PartialEq, Eq, Debug, Default)]
+pub struct BusLog {
+    /// Tick the accesses being made belong to, kept up to date by the SoC
+    pub tick: u64,
+    /// Accesses made so far, oldest first
+    pub transactions: Vec<BusTransaction>,
 }
 
 /// The WonderSwan's shared memory bus
@@ -28,6 +117,46 @@ pub struct MemBus {
 
     /// A reference to the I/O bus, only used to check if color mode is enabled
     pub io_bus: Rc<RefCell<IOBus>>,
+
+    /// If set, the CPU is stalled for a cycle whenever it accesses WRAM while the display chip owns the bus
+    pub vram_stalls: bool,
+
+    /// Accesses to each page of the address space since the SoC last took them
+    pub heat: AccessHeat,
+
+    /// Ranges of addresses whose accesses are reported
+    pub watchpoints: Vec<Watchpoint>,
+    /// Accesses to watched addresses since the SoC last took them, oldest first
+    pub watch_hits: Vec<WatchHit>,
+    /// Log of every access, only kept while the SoC is logging the bus
+    pub bus_log: Option<BusLog>,
+}
+
+/// Amount of pages the address space is split into when counting accesses, 4 KB each
+pub const HEAT_PAGES: usize = 0x100;
+
+/// Reads and writes to each 4 KB page of the address space
+///
+/// Fetches made by the display and sound chips are not counted, see [`Accessor::is_fetch`].
+#[derive(Clone, PartialEq, Eq, Debug)]
+pub struct AccessHeat {
+    /// Reads from each page
+    pub reads: [u32; HEAT_PAGES],
+    /// Writes to each page
+    pub writes: [u32; HEAT_PAGES],
+}
+
+impl AccessHeat {
+    /// Returns the page an address belongs to
+    pub fn page(addr: u32) -> usize {
+        (addr >> 12) as usize & (HEAT_PAGES - 1)
+    }
+}
+
+impl Default for AccessHeat {
+    fn default() -> Self {
+        Self {reads: [0; HEAT_PAGES], writes: [0; HEAT_PAGES]}
+    }
 }
 
 /// Trait shared by objects containing references to the shared memory bus
@@ -88,8 +217,79 @@ pub trait MemBusConnection {
     }
 }
 
+/// Accesses made through the trait are attributed to the CPU, other components use [`MemBus::read_by`] and [`MemBus::write_by`]
 impl MemBusConnection for MemBus {
     fn read_mem(&mut self, addr: u32) -> u8 {
+        self.read_by(addr, Accessor::CPU)
+    }
+
+    fn write_mem(&mut self, addr: u32, byte: u8) {
+        self.write_by(addr, byte, Accessor::CPU);
+    }
+}
+
+impl MemBus {
+    /// Creates a new I/O bus, requires references to the I/O bus and cartridge
+    pub fn new(io_bus: Rc<RefCell<IOBus>>, cartridge: Rc<RefCell<Cartridge>>) -> Self {
+        Self {owner: Owner::NONE, wram: [0; 0x10000], io_bus, cartridge, vram_stalls: false, heat: AccessHeat::default(), watchpoints: Vec::new(), watch_hits: Vec::new(), bus_log: None}
+    }
+
+    /// A test build used during tests or if the user does not provide a ROM
+    pub fn test_build(io_bus: Rc<RefCell<IOBus>>, cartridge: Rc<RefCell<Cartridge>>) -> Self {
+        Self {owner: Owner::NONE, wram: [0; 0x10000], io_bus, cartridge, vram_stalls: false, heat: AccessHeat::default(), watchpoints: Vec::new(), watch_hits: Vec::new(), bus_log: None}
+    }
+
+    /// Reads the byte at the address on behalf of a component, counting the read towards the access heat, watchpoints and bus log
+    pub fn read_by(&mut self, addr: u32, accessor: Accessor) -> u8 {
+        let byte = self.fetch(addr);
+        self.log(accessor, false, addr, byte);
+        if !accessor.is_fetch() {
+            self.heat.reads[AccessHeat::page(addr)] += 1;
+            if self.watchpoints.iter().any(|watchpoint| watchpoint.hit(addr, false)) {
+                self.watch_hits.push(WatchHit {accessor, addr, write: false, before: byte, after: byte});
+            }
+        }
+        byte
+    }
+
+    /// Writes the byte to the address on behalf of a component, counting the write towards the access heat, watchpoints and bus log
+    pub fn write_by(&mut self, addr: u32, byte: u8, accessor: Accessor) {
+        self.log(accessor, true, addr, byte);
+        if accessor.is_fetch() {return self.store(addr, byte)}
+        self.heat.writes[AccessHeat::page(addr)] += 1;
+        if !self.watchpoints.iter().any(|watchpoint| watchpoint.hit(addr, true)) {return self.store(addr, byte)}
+
+        let before = self.fetch(addr);
+        self.store(addr, byte);
+        let after = self.fetch(addr);
+        self.watch_hits.push(WatchHit {accessor, addr, write: true, before, after});
+    }
+
+    /// Adds an access to the bus log, if the bus is being logged
+    fn log(&mut self, accessor: Accessor, write: bool, addr: u32, value: u8) {
+        if let Some(log) = &mut self.bus_log {
+            log.transactions.push(BusTransaction {tick: log.tick, accessor, write, addr, value});
+        }
+    }
+
+    /// Writes the byte to the address without counting it anywhere
+    fn store(&mut self, addr: u32, byte: u8) {
+        match addr {
+            0x00000..=0x03FFF => {
+                self.wram[addr as usize] = byte;
+                // println!("{:05X} <- {:02X}", addr, byte);
+            }
+            0x04000..=0x0FFFF => if self.io_bus.borrow_mut().color_mode() {self.wram[addr as usize] = byte}
+            0x10000..=0x1FFFF => self.cartridge.borrow_mut().write_sram(addr, byte),
+            0x20000..=0xFFFFF => {
+                // println!("Ignoring attempt to write to ROM {:05X} <- {:02X}", addr, byte),
+            }
+            addr => panic!("Address {:08X} out of range! Attempting to write {:02X}", addr, byte),
+        }
+    }
+
+    /// Returns the byte at the address without counting the read anywhere
+    pub fn fetch(&mut self, addr: u32) -> u8 {
         match addr {
             0x00000..=0x03FFF => self.wram[addr as usize],
             0x04000..=0x0FFFF => {
@@ -107,40 +307,59 @@ impl MemBusConnection for MemBus {
         }
     }
 
-    fn write_mem(&mut self, addr: u32, byte: u8) {
-        // if (0x29C0..=0x29CF).contains(&addr) {println!("[{:04X}] <- {:02X}", addr, byte)}
-        // if addr == 0x01000 {println!("[{:04X}] <- {:02X}", addr, byte)}
-        match addr {
-            0x00000..=0x03FFF => {
-                self.wram[addr as usize] = byte;
-                // println!("{:05X} <- {:02X}", addr, byte);
-            }
-            0x04000..=0x0FFFF => if self.io_bus.borrow_mut().color_mode() {self.wram[addr as usize] = byte}
-            0x10000..=0x1FFFF => self.cartridge.borrow_mut().write_sram(addr, byte),
-            0x20000..=0xFFFFF => {
-                // println!("Ignoring attempt to write to ROM {:05X} <- {:02X}", addr, byte),
-            }
-            addr => panic!("Address {:08X} out of range! Attempting to write {:02X}", addr, byte),
+    /// Called by the display chip to announce whether or not it is inside one of its fetch windows
+    /// 
+    /// The display only takes ownership of the bus if nobody else owns it, and only releases it if it is the owner.
+    pub fn set_display_fetch(&mut self, fetching: bool) {
+        match (fetching, &self.owner) {
+            (true, Owner::NONE) => self.owner = Owner::DISPLAY,
+            (false, Owner::DISPLAY) => self.owner = Owner::NONE,
+            _ => {}
         }
     }
+
+    /// Returns whether or not a CPU access to the address should be stalled
+    /// 
+    /// This happens when stalls are enabled, the display chip owns the bus and the address is in WRAM.
+    pub fn vram_stall(&self, addr: u32) -> bool {
+        self.vram_stalls && self.owner == Owner::DISPLAY && addr <= 0x0FFFF
+    }
 }
 
-impl MemBus {
-    /// Creates a new I/O bus, requires references to the I/O bus and cartridge
-    pub fn new(io_bus: Rc<RefCell<IOBus>>, cartridge: Rc<RefCell<Cartridge>>) -> Self {
-        Self {owner: Owner::NONE, wram: [0; 0x10000], io_bus, cartridge}
+/// The memory bus' state contains WRAM and the bus' owner, the cartridge is saved along with the I/O bus
+impl Snapshot for MemBus {
+    const TAG: [u8; 4] = *b"MEMB";
+    const VERSION: u16 = 1;
+
+    fn save_fields(&self, w: &mut StateWriter) {
+        w.write_u8(match self.owner {
+            Owner::NONE => 0,
+            Owner::CPU => 1,
+            Owner::DMA => 2,
+            Owner::DISPLAY => 3,
+        });
+        w.write_bytes(&self.wram);
     }
 
-    /// A test build used during tests or if the user does not provide a ROM
-    pub fn test_build(io_bus: Rc<RefCell<IOBus>>, cartridge: Rc<RefCell<Cartridge>>) -> Self {
-        Self {owner: Owner::NONE, wram: [0; 0x10000], io_bus, cartridge}
+    fn load_fields(&mut self, r: &mut StateReader, _version: u16) -> Result<(), StateError> {
+        let owner = match r.read_u8()? {
+            0 => Owner::NONE,
+            1 => Owner::CPU,
+            2 => Owner::DMA,
+            3 => Owner::DISPLAY,
+            _ => return Err(StateError::Mismatch("memory bus owner")),
+        };
+        self.wram = r.read_array()?;
+        self.owner = owner;
+        Ok(())
     }
 }
 
 #[cfg(test)]
 pub mod test {
     use super::*;
-    use std::ops::{Index, IndexMut};
+    use core::ops::{Index, IndexMut};
+    use crate::bus::io_bus::IOBusConnection;
 
     #[cfg(test)]
     impl Index<usize> for MemBus {
@@ -157,4 +376,54 @@ pub mod test {
             &mut self.wram[index]
         }
     }
+
+    fn test_bus() -> MemBus {
+        let cartridge = Rc::new(RefCell::new(Cartridge::test_build()));
+        let io_bus = Rc::new(RefCell::new(IOBus::new(Rc::clone(&cartridge), Vec::new(), None, false, 0)));
+        MemBus::test_build(io_bus, cartridge)
+    }
+
+    #[test]
+    fn test_display_fetch_ownership() {
+        let mut bus = test_bus();
+        bus.set_display_fetch(true);
+        assert!(bus.owner == Owner::DISPLAY);
+        bus.set_display_fetch(false);
+        assert!(bus.owner == Owner::NONE);
+
+        bus.owner = Owner::DMA;
+        bus.set_display_fetch(true);
+        assert!(bus.owner == Owner::DMA);
+        bus.set_display_fetch(false);
+        assert!(bus.owner == Owner::DMA);
+    }
+
+    #[test]
+    fn test_palette_ram_mono() {
+        let mut bus = test_bus();
+        // Palette RAM is part of the upper WRAM, which does not exist in mono mode
+        bus.write_mem(0x0FE00, 0x12);
+        assert_eq!(bus.read_mem(0x0FE00), 0x90);
+        assert_eq!(bus.wram[0xFE00], 0x00);
+
+        bus.io_bus.borrow_mut().write_io(0x60, 0x80);
+        bus.write_mem(0x0FE00, 0x12);
+        assert_eq!(bus.read_mem(0x0FE00), 0x12);
+    }
+
+    #[test]
+    fn test_vram_stall() {
+        let mut bus = test_bus();
+        bus.set_display_fetch(true);
+        assert!(!bus.vram_stall(0x02000));
+
+        bus.vram_stalls = true;
+        assert!(bus.vram_stall(0x02000));
+        assert!(bus.vram_stall(0x0FE00));
+        assert!(!bus.vram_stall(0x10000));
+        assert!(!bus.vram_stall(0x20000));
+
+        bus.set_display_fetch(false);
+        assert!(!bus.vram_stall(0x02000));
+    }
 }
\ No newline at end of file
@@ -1,10 +1,29 @@
-use crate::bus::io_bus::IOBus;
+use alloc::{boxed::Box, format, string::String, vec, vec::Vec};
+
+use crate::{bus::io_bus::IOBus, cartridge::header::SaveType, state::{Snapshot, StateError, StateReader, StateWriter}};
+
+use rtc::{FixedTime, Rtc, Y2K};
+
+/// Background thread journaling snapshots of the SRAM
+pub mod autosave;
 
 /// Various getter and setter functions meant to be used by the I/O bus
 pub mod cart_ports;
 
+/// Parser for the footer at the end of every ROM
+pub mod header;
+
+/// Settings read from a file next to the ROM, replacing the footer's and the ROM database's
+pub mod overrides;
+
+/// Append-only journal of SRAM changes, so that saves survive crashes without rewriting the whole SRAM file
+pub mod journal;
+
+/// The real-time clock of 2003 mapper cartridges and the sources it takes the time from
+pub mod rtc;
+
 /// The mapper chips contained within WonderSwan cartridges
-#[derive(PartialEq)]
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
 pub enum Mapper {
     /// Bandai 2001 mapper
     B_2001,
@@ -16,8 +35,12 @@ pub enum Mapper {
 pub struct Cartridge {
     /// The cartridge SRAM, may be empty
     pub(crate) sram: Vec<u8>,
-    /// The contents of the ROM
+    /// The contents of the ROM, always a power of two in size
     rom: Vec<u8>,
+    /// Mask applied to offsets into the ROM, mirrors banks beyond the end of the ROM
+    rom_mask: u32,
+    /// Mask applied to offsets into the SRAM, mirrors banks beyond the end of the SRAM
+    sram_mask: u32,
 
     /// The mapper chip
     mapper: Mapper,
@@ -40,62 +63,108 @@ pub struct Cartridge {
 
     /// Whether or not the cartridge contains SRAM
     rewrittable: bool,
+
+    /// Set for each page of SRAM that has changed since the pages were last taken
+    sram_dirty: Vec<bool>,
+
+    /// The real-time clock, only reachable on the 2003 mapper
+    pub(crate) rtc: Option<Rtc>,
+
+    /// Whether save media are allocated once the game writes to them when the cartridge has none, see [`Cartridge::may_probe`]
+    pub(crate) probe: bool,
+    /// The save media allocated that way, if the game wrote to any
+    pub(crate) probed: Option<SaveType>,
 }
 
 impl Cartridge {
     /// Returns a new cartridge, requires a mapper, SRAM, ROM and the `rewrittable` boolean, all other fields initialized to 0xFF
+    /// 
+    /// Cartridges with the 2003 mapper get a real-time clock starting at 2000-01-01 and following emulated time,
+    /// so that runs are reproducible until a front-end picks another source.
+    /// 
+    /// ROMs whose size is not a power of two are padded at the start with 0xFF bytes,
+    /// so that the last bank, containing the footer and the boot code, is still mapped at bank 0xFF.
     pub fn new(mapper: Mapper, sram: Vec<u8>, rom: Vec<u8>, rewrittable: bool) -> Self {
+        let rom = if rom.len().is_power_of_two() {rom} else {
+            let mut padded = vec![0xFF; rom.len().next_power_of_two() - rom.len()];
+            padded.extend(rom);
+            padded
+        };
+        let rom_mask = (rom.len() - 1) as u32;
+        let sram_mask = (sram.len().max(1).next_power_of_two() - 1) as u32;
+        let sram_dirty = vec![false; sram.len().div_ceil(journal::PAGE_SIZE)];
+        let rtc = (mapper == Mapper::B_2003).then(|| Rtc::new(Box::new(FixedTime(Y2K))));
+
         Self {
-            sram, rom, mapper,
+            sram, rom, rom_mask, sram_mask, mapper,
             RAM_BANK_L: 0xFF, RAM_BANK_H: 0xFF,
             ROM_BANK_0_L: 0xFF, ROM_BANK_0_H: 0xFF,
             ROM_BANK_1_L: 0xFF, ROM_BANK_1_H: 0xFF,
             LINEAR_ADDR_OFF: 0xFF,
-            rewrittable,
+            rewrittable, sram_dirty, rtc,
+            probe: false, probed: None,
         }
     }
 
-    /// Reads the SRAM at the index formed by combining the provided address with the RAM bank
-    pub fn read_sram(&self, addr: u32) -> u8 {
-        if self.sram.len() == 0 {
-            return IOBus::open_bus();
-        }
-        
+    /// Returns the index into the SRAM formed by combining the provided address with the RAM bank, or None if it is past the end of the SRAM
+    /// 
+    /// The bank is masked to the SRAM's size rounded up to a power of two, so that banks beyond the end of the SRAM mirror the ones below them.
+    /// On mapper 2003 the bank is 16 bits wide, combining the shadow registers 0xD0 and 0xD1, while mapper 2001 only has the 8 bits of 0xC1.
+    /// Only SRAM files of an unexpected size leave a gap between the end of the SRAM and the mask.
+    pub(crate) fn sram_offset(&self, addr: u32) -> Option<usize> {
         let hi = match self.mapper {
             Mapper::B_2001 => self.RAM_BANK_L as u32,
             Mapper::B_2003 => u16::from_le_bytes([self.RAM_BANK_L, self.RAM_BANK_H]) as u32,
         };
         let lo = addr & 0xFFFF;
 
-        let offset = ((hi << 16) | lo) % self.sram.len() as u32;
+        let offset = ((hi << 16) | lo) & self.sram_mask;
+        if (offset as usize) < self.sram.len() {Some(offset as usize)} else {None}
+    }
 
-        if offset as usize > self.sram.len() {
-            IOBus::open_bus()
-        } else {
-            self.sram[offset as usize]
+    /// Reads the SRAM at the index formed by combining the provided address with the RAM bank
+    pub fn read_sram(&self, addr: u32) -> u8 {
+        match self.sram_offset(addr) {
+            Some(offset) => self.sram[offset],
+            None => IOBus::open_bus(),
         }
     }
 
     /// Writes a byte to the SRAM at the index formed by combining the provided address with the RAM bank
     pub fn write_sram(&mut self, addr: u32, byte: u8) {
-        if self.sram.len() == 0 {return}
-        if self.rewrittable {
-            let hi = match self.mapper {
-                Mapper::B_2001 => self.RAM_BANK_L as u32,
-                Mapper::B_2003 => u16::from_le_bytes([self.RAM_BANK_L, self.RAM_BANK_H]) as u32,
-            };
-            let lo = addr & 0xFFFF;
-
-            let offset = ((hi << 16) | lo) % self.sram.len() as u32;
-
-        // print!("CART SRAM_OFFSET: {:07X}", offset);
-            
-            if !(offset as usize > self.sram.len()) {
-                self.sram[offset as usize] = byte;
+        if !self.rewrittable {return}
+        if self.may_probe() {self.probe_sram(addr)}
+        if let Some(offset) = self.sram_offset(addr) {
+            if self.sram[offset] != byte {
+                self.sram[offset] = byte;
+                self.sram_dirty[offset / journal::PAGE_SIZE] = true;
             }
         }
     }
 
+    /// Whether save media can still be allocated for a game writing to them
+    ///
+    /// Some bad dumps declare no save memory in their footer although the game saves, which would silently lose its progress.
+    /// While probing is on, cartridges declared without any save memory get the first kind the game writes to:
+    /// SRAM through [`Cartridge::write_sram`], or EEPROM through the I/O bus. Once one is allocated the other is not looked for.
+    pub(crate) fn may_probe(&self) -> bool {
+        self.probe && self.rewrittable && self.sram.is_empty() && self.probed.is_none()
+    }
+
+    /// Allocates SRAM for a write to it, of the smallest size footers declare that reaches the address written through the RAM bank
+    fn probe_sram(&mut self, addr: u32) {
+        let bank = match self.mapper {
+            Mapper::B_2001 => self.RAM_BANK_L as usize,
+            Mapper::B_2003 => u16::from_le_bytes([self.RAM_BANK_L, self.RAM_BANK_H]) as usize,
+        };
+        let offset = (bank << 16) | (addr as usize & 0xFFFF);
+        let size = [0x8000, 0x20000, 0x40000, 0x80000].into_iter().find(|size| offset < *size).unwrap_or(0x80000);
+        self.sram = vec![0; size];
+        self.sram_mask = (size - 1) as u32;
+        self.sram_dirty = vec![false; size.div_ceil(journal::PAGE_SIZE)];
+        self.probed = Some(SaveType::Sram(size));
+    }
+
     /// Reads the ROM at the index formed by combining the provided address with the ROM bank 0
     pub fn read_rom_0(&self, addr: u32) -> u8 {
         let hi = match self.mapper {
@@ -104,7 +173,7 @@ impl Cartridge {
         };
         let lo = addr & 0xFFFF;
 
-        let offset = ((hi << 16) | lo) % self.rom.len() as u32;
+        let offset = ((hi << 16) | lo) & self.rom_mask;
 
         // print!("CART ROM0_OFFSET: {:07X}", offset);
 
@@ -119,7 +188,7 @@ impl Cartridge {
         };
         let lo = addr & 0xFFFF;
 
-        let offset = ((hi << 16) | lo) % self.rom.len() as u32;
+        let offset = ((hi << 16) | lo) & self.rom_mask;
 
         // print!("CART ROM1_OFFSET: {:07X}", offset);
 
@@ -130,15 +199,294 @@ impl Cartridge {
     pub fn read_rom_ex(&self, addr: u32) -> u8 {
         let addr = addr & 0xFFFFF;
         let hi = (self.LINEAR_ADDR_OFF as u32) << 20;
-        let offset = (hi | addr) % self.rom.len() as u32;
+        let offset = (hi | addr) & self.rom_mask;
 
         // print!("CART EX_OFFSET: {:07X}", offset);
 
         self.rom[offset as usize]
     }
 
+    /// Returns the offset into the ROM an address of the ROM banks or the linear window is mapped to with the current banks
+    pub fn rom_offset(&self, addr: u32) -> Option<u32> {
+        let bank = |l: u8, h: u8| match self.mapper {
+            Mapper::B_2001 => l as u32,
+            Mapper::B_2003 => u16::from_le_bytes([l, h]) as u32,
+        };
+        let offset = match addr & 0xFFFFF {
+            addr @ 0x20000..=0x2FFFF => (bank(self.ROM_BANK_0_L, self.ROM_BANK_0_H) << 16) | (addr & 0xFFFF),
+            addr @ 0x30000..=0x3FFFF => (bank(self.ROM_BANK_1_L, self.ROM_BANK_1_H) << 16) | (addr & 0xFFFF),
+            addr @ 0x40000..=0xFFFFF => ((self.LINEAR_ADDR_OFF as u32) << 20) | addr,
+            _ => return None,
+        };
+        Some(offset & self.rom_mask)
+    }
+
+    /// Returns the indices of the SRAM pages that changed since this was last called and clears their flags
+    pub fn take_dirty_sram_pages(&mut self) -> Vec<usize> {
+        let pages = self.sram_dirty.iter().enumerate().filter(|(_, dirty)| **dirty).map(|(page, _)| page).collect();
+        self.sram_dirty.fill(false);
+        pages
+    }
+
+    /// Fits a real-time clock to a cartridge whose mapper does not come with one, for games listed with the rtc quirk
+    pub fn fit_rtc(&mut self) {
+        if self.rtc.is_none() {self.rtc = Some(Rtc::new(Box::new(FixedTime(Y2K))))}
+    }
+
+    /// Reads one of the real-time clock's ports, open bus without a clock
+    pub fn read_rtc(&mut self, port: u8) -> u8 {
+        self.rtc.as_mut().map_or(IOBus::open_bus(), |rtc| rtc.read(port))
+    }
+
+    /// Writes one of the real-time clock's ports, ignored without a clock
+    pub fn write_rtc(&mut self, port: u8, byte: u8) {
+        if let Some(rtc) = &mut self.rtc {rtc.write(port, byte)}
+    }
+
+    /// Returns the mapper chip
+    pub fn mapper(&self) -> Mapper {
+        self.mapper
+    }
+
+    /// Returns the names and values of the bank registers
+    pub fn bank_registers(&self) -> [(&'static str, u8); 7] {
+        [
+            ("RAM_BANK_L", self.RAM_BANK_L), ("RAM_BANK_H", self.RAM_BANK_H),
+            ("ROM_BANK_0_L", self.ROM_BANK_0_L), ("ROM_BANK_0_H", self.ROM_BANK_0_H),
+            ("ROM_BANK_1_L", self.ROM_BANK_1_L), ("ROM_BANK_1_H", self.ROM_BANK_1_H),
+            ("LINEAR_ADDR_OFF", self.LINEAR_ADDR_OFF),
+        ]
+    }
+
+    /// Returns the bank registers and masks that do not hold what the mapper allows
+    ///
+    /// The 2001 mapper ignores writes to the high bytes of the 2003's banks, which stay at their initial 0xFF.
+    pub(crate) fn invariant_violations(&self) -> Vec<String> {
+        let mut violations = Vec::new();
+        if self.mapper == Mapper::B_2001 {
+            for (name, value) in [("RAM_BANK_H", self.RAM_BANK_H), ("ROM_BANK_0_H", self.ROM_BANK_0_H), ("ROM_BANK_1_H", self.ROM_BANK_1_H)] {
+                if value != 0xFF {violations.push(format!("{} = {:02X} on the 2001 mapper", name, value))}
+            }
+        }
+        if self.rom_mask as usize != self.rom.len() - 1 {violations.push(format!("ROM mask {:X} for a ROM of {:X} bytes", self.rom_mask, self.rom.len()))}
+        if !(self.sram_mask as usize + 1).is_power_of_two() || (self.sram_mask as usize) < self.sram.len().saturating_sub(1) {
+            violations.push(format!("SRAM mask {:X} for an SRAM of {:X} bytes", self.sram_mask, self.sram.len()));
+        }
+        violations
+    }
+
     /// A test build used during tests or if the user does not provide a ROM
     pub fn test_build() -> Self {
         Self::new(Mapper::B_2001, vec![0; 0x100000], vec![0; 0x100000], true)
     }
-}
\ No newline at end of file
+}
+
+/// The cartridge's state contains the bank registers, SRAM and real-time clock, the ROM is expected to be loaded separately
+impl Snapshot for Cartridge {
+    const TAG: [u8; 4] = *b"CART";
+    const VERSION: u16 = 2;
+
+    fn save_fields(&self, w: &mut StateWriter) {
+        w.write_bool(self.mapper == Mapper::B_2003);
+        w.write_bytes(&[
+            self.RAM_BANK_L, self.RAM_BANK_H,
+            self.ROM_BANK_0_L, self.ROM_BANK_0_H,
+            self.ROM_BANK_1_L, self.ROM_BANK_1_H,
+            self.LINEAR_ADDR_OFF,
+        ]);
+        w.write_vec(&self.sram);
+        if let Some(rtc) = &self.rtc {rtc.save(w)}
+    }
+
+    fn load_fields(&mut self, r: &mut StateReader, version: u16) -> Result<(), StateError> {
+        if r.read_bool()? != (self.mapper == Mapper::B_2003) {return Err(StateError::Mismatch("cartridge mapper"))}
+        let banks = r.read_array::<7>()?;
+        let sram = r.read_vec()?;
+        if sram.len() != self.sram.len() {return Err(StateError::Mismatch("SRAM size"))}
+        // The clock is loaded first, as it is the only field that can fail once the SRAM's size matches
+        if let (Some(rtc), true) = (&mut self.rtc, version >= 2) {rtc.load(r)?}
+
+        [
+            self.RAM_BANK_L, self.RAM_BANK_H,
+            self.ROM_BANK_0_L, self.ROM_BANK_0_H,
+            self.ROM_BANK_1_L, self.ROM_BANK_1_H,
+            self.LINEAR_ADDR_OFF,
+        ] = banks;
+        self.sram = sram;
+        self.sram_dirty.fill(true);
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+#[cfg_attr(coverage_nightly, coverage(off))]
+mod test {
+    use super::*;
+
+    /// Returns a ROM in which every byte contains the index of its 64KB bank
+    fn banked_rom(size: usize) -> Vec<u8> {
+        (0..size).map(|i| (i >> 16) as u8).collect()
+    }
+
+    #[test]
+    fn test_4mb_linear_bank() {
+        let mut cart = Cartridge::new(Mapper::B_2001, Vec::new(), banked_rom(0x400000), false);
+        cart.write_linear_addr_off(0x03);
+        assert_eq!(cart.read_rom_ex(0x40000), 0x34);
+        assert_eq!(cart.read_rom_ex(0xFFFFF), 0x3F);
+    }
+
+    #[test]
+    fn test_4mb_linear_matches_banked() {
+        let mut cart = Cartridge::new(Mapper::B_2001, Vec::new(), banked_rom(0x400000), false);
+        cart.write_linear_addr_off(0x02);
+        cart.write_rom_bank_0(0x2F);
+        cart.write_rom_bank_1(0x30);
+        assert_eq!(cart.read_rom_ex(0xFFFFF), cart.read_rom_0(0x2FFFF));
+        assert_eq!(cart.read_rom_0(0x2FFFF), 0x2F);
+        assert_eq!(cart.read_rom_1(0x30000), 0x30);
+    }
+
+    #[test]
+    fn test_4mb_bank_mirroring() {
+        let mut cart = Cartridge::new(Mapper::B_2001, Vec::new(), banked_rom(0x400000), false);
+        cart.write_rom_bank_0(0x7F);
+        cart.write_linear_addr_off(0x07);
+        assert_eq!(cart.read_rom_0(0x20000), 0x3F);
+        assert_eq!(cart.read_rom_ex(0x40000), 0x34);
+        assert_eq!(cart.read_rom_bank_0(), 0x7F);
+    }
+
+    #[test]
+    fn test_8mb_2003_banks() {
+        let mut cart = Cartridge::new(Mapper::B_2003, Vec::new(), banked_rom(0x800000), false);
+        cart.write_rom_bank_1_l(0x7F);
+        cart.write_rom_bank_1_h(0x01);
+        assert_eq!(cart.read_rom_1(0x3FFFF), 0x7F);
+        cart.write_linear_addr_off(0x07);
+        assert_eq!(cart.read_rom_ex(0xFFFFF), 0x7F);
+    }
+
+    #[test]
+    fn test_non_power_of_two_rom_padded() {
+        let mut rom = banked_rom(0x300000);
+        *rom.last_mut().unwrap() = 0xAA;
+        let cart = Cartridge::new(Mapper::B_2001, Vec::new(), rom, false);
+        assert_eq!(cart.rom.len(), 0x400000);
+        assert_eq!(cart.read_rom_ex(0xFFFFF), 0xAA);
+        assert_eq!(cart.read_rom_1(0x30000), 0x2F);
+    }
+
+    /// Returns SRAM in which every byte contains the index of its 64KB bank
+    fn banked_sram(size: usize) -> Vec<u8> {
+        (0..size).map(|i| (i >> 16) as u8).collect()
+    }
+
+    #[test]
+    fn test_4mbit_sram_bank_boundaries() {
+        for mapper in [Mapper::B_2001, Mapper::B_2003] {
+            let mut cart = Cartridge::new(mapper, banked_sram(0x80000), Vec::new(), true);
+            for bank in 0..8 {
+                cart.write_ram_bank(bank);
+                assert_eq!(cart.read_sram(0x10000), bank);
+                assert_eq!(cart.read_sram(0x1FFFF), bank);
+            }
+
+            // Writes at the end of a bank do not spill into the next one
+            cart.write_ram_bank(0x03);
+            cart.write_sram(0x1FFFF, 0xAA);
+            cart.write_ram_bank(0x04);
+            cart.write_sram(0x10000, 0xBB);
+            assert_eq!(cart.sram[0x3FFFF..=0x40000], [0xAA, 0xBB]);
+            assert_eq!(cart.take_dirty_sram_pages().len(), 2);
+
+            // Banks past the end of the SRAM mirror the ones below them
+            cart.write_ram_bank(0x0C);
+            assert_eq!(cart.read_sram(0x10000), 0xBB);
+            cart.write_sram(0x10001, 0xCC);
+            assert_eq!(cart.sram[0x40001], 0xCC);
+        }
+    }
+
+    #[test]
+    fn test_2003_sram_shadow_registers() {
+        let mut cart = Cartridge::new(Mapper::B_2003, banked_sram(0x80000), Vec::new(), true);
+        // 0xC1 and 0xD0 are the same register
+        cart.write_ram_bank(0x05);
+        assert_eq!(cart.read_ram_bank_l(), 0x05);
+        cart.write_ram_bank_l(0x06);
+        assert_eq!(cart.read_ram_bank(), 0x06);
+        assert_eq!(cart.read_sram(0x10000), 0x06);
+
+        // The high byte is masked off since the SRAM is smaller than 256 banks
+        cart.write_ram_bank_h(0x01);
+        assert_eq!(cart.read_ram_bank_h(), 0x01);
+        assert_eq!(cart.read_sram(0x10000), 0x06);
+
+        let mut cart = Cartridge::new(Mapper::B_2001, banked_sram(0x80000), Vec::new(), true);
+        cart.write_ram_bank_l(0x06);
+        cart.write_ram_bank_h(0x01);
+        assert_eq!(cart.read_ram_bank_l(), IOBus::open_bus());
+        assert_eq!(cart.read_ram_bank_h(), IOBus::open_bus());
+        assert_eq!(cart.read_ram_bank(), 0xFF);
+    }
+
+    #[test]
+    fn test_small_and_odd_sram() {
+        // 256 Kbit of SRAM is mirrored within each bank
+        let mut cart = Cartridge::new(Mapper::B_2001, vec![0; 0x8000], Vec::new(), true);
+        cart.write_ram_bank(0x00);
+        cart.write_sram(0x18000, 0x12);
+        assert_eq!(cart.read_sram(0x10000), 0x12);
+
+        // A save file of an unexpected size leaves open bus past its end instead of wrapping around
+        let mut cart = Cartridge::new(Mapper::B_2001, vec![0; 0x6000], Vec::new(), true);
+        cart.write_ram_bank(0x00);
+        cart.write_sram(0x17000, 0x34);
+        assert_eq!(cart.read_sram(0x17000), IOBus::open_bus());
+        assert!(cart.sram.iter().all(|byte| *byte == 0));
+
+        let cart = Cartridge::new(Mapper::B_2001, Vec::new(), Vec::new(), true);
+        assert_eq!(cart.read_sram(0x10000), IOBus::open_bus());
+    }
+
+    #[test]
+    fn test_probe_sram() {
+        let mut cart = Cartridge::new(Mapper::B_2001, Vec::new(), Vec::new(), true);
+        cart.write_ram_bank(0x02);
+        cart.write_sram(0x10004, 0x56);
+        assert!(cart.sram.is_empty());
+
+        // The third bank needs 2 Mbit, the write that revealed the SRAM lands in it
+        cart.probe = true;
+        cart.write_sram(0x10004, 0x56);
+        assert_eq!(cart.probed, Some(SaveType::Sram(0x40000)));
+        assert_eq!(cart.read_sram(0x10004), 0x56);
+        assert_eq!(cart.take_dirty_sram_pages().len(), 1);
+        assert!(!cart.may_probe());
+
+        // Cartridges with EEPROM have no SRAM to find
+        let mut cart = Cartridge::new(Mapper::B_2001, Vec::new(), Vec::new(), false);
+        cart.probe = true;
+        cart.write_sram(0x10000, 0x56);
+        assert_eq!(cart.probed, None);
+    }
+
+    #[test]
+    fn test_rtc_ports_and_state() {
+        let mut cart = Cartridge::new(Mapper::B_2001, Vec::new(), Vec::new(), false);
+        assert_eq!(cart.read_rtc(0xCA), IOBus::open_bus());
+
+        let mut cart = Cartridge::new(Mapper::B_2003, Vec::new(), Vec::new(), false);
+        cart.rtc.as_mut().unwrap().time_travel(3600);
+        cart.write_rtc(0xCA, 0x15);
+        // 2000-01-01 01:00:00, a Saturday
+        assert_eq!([(); 7].map(|_| cart.read_rtc(0xCB)), [0x00, 0x01, 0x01, 0x06, 0x01, 0x00, 0x00]);
+
+        let mut w = StateWriter::new();
+        cart.save_state(&mut w);
+        let mut other = Cartridge::new(Mapper::B_2003, Vec::new(), Vec::new(), false);
+        other.load_state(&mut StateReader::new(&w.finish())).unwrap();
+        assert_eq!(other.rtc.unwrap().now(), Y2K + 3600);
+    }
+}
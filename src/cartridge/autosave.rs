@@ -0,0 +1,157 @@
+use alloc::vec::Vec;
+#[cfg(feature = "std")]
+use std::{io, sync::mpsc::{self, Sender}, thread::{self, JoinHandle}};
+
+use super::{journal::PAGE_SIZE, Cartridge};
+#[cfg(feature = "std")]
+use super::journal::SramJournal;
+
+/// Copies of the SRAM pages that changed since the previous snapshot
+///
+/// Taking a snapshot only copies the changed pages, so it is cheap enough to do on the emulation thread every few frames.
+/// The copies can then be sent to another thread, which never touches the cartridge itself.
+#[derive(Clone, PartialEq, Eq, Debug, Default)]
+pub struct SramSnapshot {
+    /// Index and contents of each changed page
+    pub pages: Vec<(usize, Vec<u8>)>,
+}
+
+impl SramSnapshot {
+    /// Whether no page changed
+    pub fn is_empty(&self) -> bool {
+        self.pages.is_empty()
+    }
+
+    /// Writes the pages into a full copy of the SRAM, returns their indices
+    pub fn apply(&self, sram: &mut [u8]) -> Vec<usize> {
+        for (page, data) in &self.pages {
+            let start = page * PAGE_SIZE;
+            sram[start..start + data.len()].copy_from_slice(data);
+        }
+        self.pages.iter().map(|(page, _)| *page).collect()
+    }
+}
+
+impl Cartridge {
+    /// Copies the SRAM pages that changed since this was last called and clears their flags
+    pub fn snapshot_sram(&mut self) -> SramSnapshot {
+        let pages = self.take_dirty_sram_pages().into_iter().map(|page| {
+            let start = page * PAGE_SIZE;
+            let end = (start + PAGE_SIZE).min(self.sram.len());
+            (page, self.sram[start..end].to_vec())
+        }).collect();
+        SramSnapshot {pages}
+    }
+}
+
+/// Journals SRAM snapshots on a background thread
+///
+/// The thread keeps its own copy of the SRAM, which it updates with every snapshot, so that compacting the journal never needs the cartridge.
+/// Writing to disk and waiting for it to sync therefore never stalls emulation, and the cartridge stays owned by the single-threaded SoC.
+///
+/// If writing fails the error is printed and journaling stops, the SRAM is still saved in full when quitting.
+#[cfg(feature = "std")]
+pub struct AutoSaver {
+    /// Snapshots waiting to be journaled
+    snapshots: Sender<SramSnapshot>,
+    /// The background thread, gives back the journal when it finishes unless writing to it failed
+    handle: JoinHandle<Option<SramJournal>>,
+}
+
+#[cfg(feature = "std")]
+impl AutoSaver {
+    /// Opens the journal of an SRAM file and starts journaling on a new thread
+    ///
+    /// The journal is opened on the calling thread so that failing to open it is reported right away.
+    pub fn spawn(sram_path: &str, sram: &[u8]) -> io::Result<Self> {
+        let mut journal = SramJournal::open(sram_path, sram)?;
+        let mut shadow = sram.to_vec();
+        let (snapshots, received) = mpsc::channel::<SramSnapshot>();
+
+        let handle = thread::spawn(move || {
+            for snapshot in received {
+                let pages = snapshot.apply(&mut shadow);
+                if let Err(e) = journal.record(&shadow, &pages) {
+                    println!("Could not write to SRAM journal: {}", e);
+                    return None;
+                }
+            }
+            Some(journal)
+        });
+        Ok(Self {snapshots, handle})
+    }
+
+    /// Queues a snapshot to be journaled, never blocks
+    pub fn submit(&self, snapshot: SramSnapshot) {
+        if snapshot.is_empty() {return}
+        let _ = self.snapshots.send(snapshot);
+    }
+
+    /// Waits for every queued snapshot to be journaled and stops the thread
+    ///
+    /// Returns the journal, so that it can be closed once the SRAM file has been written in full.
+    pub fn finish(self) -> Option<SramJournal> {
+        drop(self.snapshots);
+        self.handle.join().ok().flatten()
+    }
+}
+
+#[cfg(test)]
+#[cfg_attr(coverage_nightly, coverage(off))]
+mod test {
+    use alloc::vec;
+    #[cfg(feature = "std")]
+    use std::fs;
+
+    #[cfg(feature = "std")]
+    use crate::cartridge::journal::{journal_path, replay_file};
+
+    use super::*;
+
+    #[test]
+    fn test_snapshot_dirty_pages() {
+        let mut cartridge = Cartridge::test_build();
+        cartridge.sram = vec![0; 0x400];
+        cartridge.sram_dirty = vec![false; 4];
+        cartridge.sram[0x205] = 0xAA;
+        cartridge.sram_dirty[2] = true;
+
+        let snapshot = cartridge.snapshot_sram();
+        assert_eq!(snapshot.pages.len(), 1);
+        assert_eq!(snapshot.pages[0].0, 2);
+        assert_eq!(snapshot.pages[0].1[5], 0xAA);
+        assert!(cartridge.snapshot_sram().is_empty());
+
+        // The snapshot is independent of later writes
+        cartridge.sram[0x205] = 0xBB;
+        let mut copy = vec![0; 0x400];
+        assert_eq!(snapshot.apply(&mut copy), vec![2]);
+        assert_eq!(copy[0x205], 0xAA);
+    }
+
+    #[test]
+    #[cfg(feature = "std")]
+    fn test_background_journal() {
+        let sram_path = std::env::temp_dir().join(format!("wondercrab_autosave_{}.sram", std::process::id()));
+        let sram_path = sram_path.to_str().unwrap();
+
+        let mut sram = vec![0; 0x400];
+        let saver = AutoSaver::spawn(sram_path, &sram).unwrap();
+        for value in 1..=3 {
+            sram[0x105] = value;
+            sram[0x3FF] = value;
+            saver.submit(SramSnapshot {pages: vec![(1, sram[0x100..0x200].to_vec()), (3, sram[0x300..0x400].to_vec())]});
+        }
+        saver.submit(SramSnapshot::default());
+        let journal = saver.finish().unwrap();
+
+        // Every snapshot reached the journal before the thread stopped
+        let mut restored = fs::read(sram_path).unwrap();
+        replay_file(sram_path, &mut restored);
+        assert_eq!(restored, sram);
+
+        journal.close().unwrap();
+        assert!(!journal_path(sram_path).exists());
+        fs::remove_file(sram_path).unwrap();
+    }
+}
@@ -0,0 +1,312 @@
+use alloc::{format, string::{String, ToString}, vec::Vec};
+use core::fmt::Write;
+
+use super::{overrides::Overrides, Mapper};
+
+/// The kind and size of a cartridge's save memory
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum SaveType {
+    /// The cartridge has no save memory
+    None,
+    /// Battery backed SRAM of the given size in bytes
+    Sram(usize),
+    /// EEPROM of the given size in bytes
+    Eeprom(usize),
+}
+
+impl SaveType {
+    /// Decodes byte 0xB of the footer
+    pub fn from_code(code: u8) -> Option<Self> {
+        Some(match code {
+            0x00 => Self::None,
+            0x01 | 0x02 => Self::Sram(0x08000),
+            0x03 => Self::Sram(0x20000),
+            0x04 => Self::Sram(0x40000),
+            0x05 => Self::Sram(0x80000),
+            0x10 => Self::Eeprom(0x0400),
+            0x20 => Self::Eeprom(0x4000),
+            0x50 => Self::Eeprom(0x2000),
+            _ => return None,
+        })
+    }
+}
+
+/// The footer found in the last 16 bytes of every ROM
+///
+/// | Offset | Contents                                                       |
+/// |--------|----------------------------------------------------------------|
+/// | 0x0    | Far jump to the game's entry point                             |
+/// | 0x5    | Maintenance flags, checked by the IPL                          |
+/// | 0x6    | Publisher                                                      |
+/// | 0x7    | Bit 0 is set for games with color support                      |
+/// | 0x8    | Game ID                                                        |
+/// | 0x9    | Revision                                                       |
+/// | 0xA    | ROM size                                                       |
+/// | 0xB    | Save type                                                      |
+/// | 0xC    | Bit 0 is set for vertical games, bits 2-3 are copied into 0xA0 |
+/// | 0xD    | Mapper, 0 for the 2001 and 1 for the 2003                      |
+/// | 0xE    | Checksum, the 16-bit sum of every other byte of the ROM        |
+#[derive(Clone, PartialEq, Eq, Debug)]
+pub struct RomInfo {
+    /// Publisher ID
+    pub publisher: u8,
+    /// Whether the game supports color
+    pub color: bool,
+    /// Game ID, unique for each publisher
+    pub game_id: u8,
+    /// Revision of the game
+    pub revision: u8,
+    /// The ROM size code
+    pub rom_size: u8,
+    /// The save memory
+    pub save: SaveType,
+    /// The flags byte, containing the orientation and the ROM bus settings
+    pub flags: u8,
+    /// The mapper chip
+    pub mapper: Mapper,
+    /// The checksum stored in the footer
+    pub checksum: u16,
+    /// The checksum of the ROM's contents
+    pub computed_checksum: u16,
+}
+
+impl RomInfo {
+    /// Parses the footer of a ROM image
+    pub fn parse(rom: &[u8]) -> Result<Self, String> {
+        Self::parse_with(rom, &Overrides::default())
+    }
+
+    /// Parses the footer of a ROM image, replacing the fields given by the overrides
+    ///
+    /// Overridden fields are not decoded, so that footers with unknown save types or mappers can be fixed.
+    pub fn parse_with(rom: &[u8], overrides: &Overrides) -> Result<Self, String> {
+        let footer = rom.last_chunk::<16>().ok_or("The ROM is too small to contain a footer")?;
+        let save = match overrides.save {
+            Some(save) => save,
+            None => SaveType::from_code(footer[0xB]).ok_or(format!("Unknown save type {:02X}", footer[0xB]))?,
+        };
+        let mapper = match (overrides.mapper, footer[0xD]) {
+            (Some(mapper), _) => mapper,
+            (None, 0) => Mapper::B_2001,
+            (None, 1) => Mapper::B_2003,
+            (None, mapper) => return Err(format!("Unknown mapper {:02X}", mapper)),
+        };
+        let flags = match overrides.vertical {
+            Some(vertical) => footer[0xC] & !1 | vertical as u8,
+            None => footer[0xC],
+        };
+        let computed_checksum = rom[..rom.len() - 2].iter().fold(0u16, |sum, byte| sum.wrapping_add(*byte as u16));
+
+        Ok(Self {
+            publisher: footer[0x6],
+            color: footer[0x7] & 1 != 0,
+            game_id: footer[0x8],
+            revision: footer[0x9],
+            rom_size: footer[0xA],
+            save,
+            flags,
+            mapper,
+            checksum: u16::from_le_bytes([footer[0xE], footer[0xF]]),
+            computed_checksum,
+        })
+    }
+
+    /// Whether the game is played with the console held vertically
+    pub fn vertical(&self) -> bool {
+        self.flags & 1 != 0
+    }
+
+    /// Whether the stored checksum matches the ROM's contents
+    pub fn checksum_valid(&self) -> bool {
+        self.checksum == self.computed_checksum
+    }
+
+    /// Lists what looks wrong with a ROM image, empty for a good dump
+    ///
+    /// Checks that the footer starts with a far jump, that the image's size matches the footer's and that the checksum is valid.
+    pub fn problems(&self, rom: &[u8]) -> Vec<String> {
+        let mut problems = Vec::new();
+        if rom[rom.len() - 16] != 0xEA {problems.push("The footer does not start with a far jump to the entry point".to_string())}
+        match self.rom_size_bytes() {
+            Some(size) if rom.len() > size => problems.push(format!("Overdump, {} bytes where the footer says {}", rom.len(), size)),
+            Some(size) if rom.len() < size => problems.push(format!("Underdump, {} bytes where the footer says {}", rom.len(), size)),
+            Some(_) => {}
+            None => problems.push(format!("Unknown ROM size {:02X}", self.rom_size)),
+        }
+        if !self.checksum_valid() {
+            problems.push(format!("Checksum {:04X} does not match the contents, which sum to {:04X}", self.checksum, self.computed_checksum));
+        }
+        problems
+    }
+
+    /// Bits 2 and 3 of the system control port 0xA0
+    pub fn port_a0_bits(&self) -> u8 {
+        self.flags & 0x0C
+    }
+
+    /// The ROM size given by the footer in bytes, or None for unknown codes
+    pub fn rom_size_bytes(&self) -> Option<usize> {
+        const MBIT: usize = 0x20000;
+        Some(match self.rom_size {
+            0x00 => MBIT,
+            0x01 => 2 * MBIT,
+            0x02 => 4 * MBIT,
+            0x03 => 8 * MBIT,
+            0x04 => 16 * MBIT,
+            0x05 => 24 * MBIT,
+            0x06 => 32 * MBIT,
+            0x07 => 48 * MBIT,
+            0x08 => 64 * MBIT,
+            0x09 => 128 * MBIT,
+            _ => return None,
+        })
+    }
+
+    /// The publisher's name, if it is known
+    pub fn publisher_name(&self) -> Option<&'static str> {
+        Some(match self.publisher {
+            0x01 => "Bandai",
+            0x02 => "Taito",
+            0x03 => "Tomy",
+            0x04 => "Koei",
+            0x05 => "Data East",
+            0x06 => "Asmik Ace",
+            0x07 => "Media Entertainment",
+            0x08 => "Nichibutsu",
+            0x0A => "Coconuts Japan",
+            0x0B => "Sammy",
+            0x0C => "Sunsoft",
+            0x0D => "Mebius",
+            0x0E => "Banpresto",
+            0x10 => "Jaleco",
+            0x11 => "Imagineer",
+            0x12 => "Konami",
+            0x16 => "Kobunsha",
+            0x17 => "Bottom Up",
+            0x19 => "Sunrise",
+            0x1A => "Cyberfront",
+            0x1B => "Mega House",
+            0x1D => "Interbec",
+            0x1E => "Nihon Application",
+            0x1F => "Bandai Visual",
+            0x20 => "Athena",
+            0x21 => "KID",
+            0x22 => "HAL Corporation",
+            0x23 => "Yuki Enterprise",
+            0x24 => "Omega Micott",
+            0x25 => "Layup",
+            0x26 => "Kadokawa Shoten",
+            0x27 => "Shall Luck",
+            0x28 => "Squaresoft",
+            0x2B => "Tom Create",
+            0x2D => "Namco",
+            0x2E => "Movic",
+            0x2F => "E3 Staff",
+            0x31 => "Vanguard",
+            0x32 => "Megatron",
+            0x33 => "Wiz",
+            0x36 => "Capcom",
+            _ => return None,
+        })
+    }
+
+    /// Describes every field of the footer, one per line
+    pub fn describe(&self) -> String {
+        let mut text = String::new();
+        let publisher = self.publisher_name().unwrap_or("unknown");
+        writeln!(text, "Publisher:   {} ({:02X})", publisher, self.publisher).unwrap();
+        writeln!(text, "Game ID:     {:02X}", self.game_id).unwrap();
+        writeln!(text, "Revision:    {:02X}", self.revision).unwrap();
+        match self.rom_size_bytes() {
+            Some(size) => writeln!(text, "ROM size:    {} KB", size / 1024).unwrap(),
+            None => writeln!(text, "ROM size:    unknown ({:02X})", self.rom_size).unwrap(),
+        }
+        match self.save {
+            SaveType::None => writeln!(text, "Save:        none").unwrap(),
+            SaveType::Sram(size) => writeln!(text, "Save:        {} KB SRAM", size / 1024).unwrap(),
+            SaveType::Eeprom(size) => writeln!(text, "Save:        {} B EEPROM", size).unwrap(),
+        }
+        writeln!(text, "Mapper:      {}", if self.mapper == Mapper::B_2003 {"2003"} else {"2001"}).unwrap();
+        writeln!(text, "Orientation: {}", if self.vertical() {"vertical"} else {"horizontal"}).unwrap();
+        writeln!(text, "Color:       {}", if self.color {"yes"} else {"no"}).unwrap();
+        let validity = if self.checksum_valid() {"valid".to_string()} else {format!("invalid, contents sum to {:04X}", self.computed_checksum)};
+        writeln!(text, "Checksum:    {:04X} ({})", self.checksum, validity).unwrap();
+        text
+    }
+}
+
+#[cfg(test)]
+#[cfg_attr(coverage_nightly, coverage(off))]
+mod test {
+    use alloc::vec;
+
+    use super::*;
+
+    /// Builds a 128 KB ROM with the given footer fields and a correct checksum
+    fn rom(publisher: u8, color: u8, save: u8, flags: u8, mapper: u8) -> Vec<u8> {
+        let mut rom = vec![0xFF; 0x20000];
+        let footer = rom.len() - 16;
+        rom[footer..footer + 14].copy_from_slice(&[0xEA, 0x00, 0x00, 0x00, 0x40, 0x00, publisher, color, 0x12, 0x01, 0x00, save, flags, mapper]);
+        let checksum = rom[..rom.len() - 2].iter().fold(0u16, |sum, byte| sum.wrapping_add(*byte as u16));
+        rom[footer + 14..].copy_from_slice(&checksum.to_le_bytes());
+        rom
+    }
+
+    #[test]
+    fn test_parse_footer() {
+        let info = RomInfo::parse(&rom(0x01, 0x01, 0x03, 0x05, 0x01)).unwrap();
+        assert_eq!(info.publisher_name(), Some("Bandai"));
+        assert!(info.color);
+        assert_eq!(info.game_id, 0x12);
+        assert_eq!(info.revision, 0x01);
+        assert_eq!(info.rom_size_bytes(), Some(0x20000));
+        assert_eq!(info.save, SaveType::Sram(0x20000));
+        assert!(info.vertical());
+        assert_eq!(info.port_a0_bits(), 0x04);
+        assert!(info.mapper == Mapper::B_2003);
+        assert!(info.checksum_valid());
+        assert!(info.describe().contains("Checksum:    ") && info.describe().contains("(valid)"));
+    }
+
+    #[test]
+    fn test_rom_problems() {
+        let good = rom(0x01, 0x00, 0x00, 0x00, 0x00);
+        assert!(RomInfo::parse(&good).unwrap().problems(&good).is_empty());
+
+        // A second copy of the ROM, as dumpers reading past the end of small cartridges produce, which also throws the checksum off
+        let overdump = [good.clone(), good.clone()].concat();
+        let problems = RomInfo::parse(&overdump).unwrap().problems(&overdump);
+        assert_eq!(problems.len(), 2);
+        assert!(problems[0].starts_with("Overdump, 262144 bytes"));
+
+        let mut bad = good.clone();
+        bad[0x1234] = 0x00;
+        let footer = bad.len() - 16;
+        bad[footer] = 0x00;
+        let problems = RomInfo::parse(&bad).unwrap().problems(&bad);
+        assert_eq!(problems.len(), 2);
+        assert!(problems[1].starts_with("Checksum"));
+    }
+
+    #[test]
+    fn test_invalid_footers() {
+        let mut bad_checksum = rom(0x99, 0x00, 0x10, 0x00, 0x00);
+        bad_checksum[0] = 0x00;
+        let info = RomInfo::parse(&bad_checksum).unwrap();
+        assert!(!info.checksum_valid());
+        assert_eq!(info.publisher_name(), None);
+        assert_eq!(info.save, SaveType::Eeprom(0x400));
+        assert!(info.describe().contains("invalid"));
+
+        assert!(RomInfo::parse(&rom(0x01, 0x00, 0x07, 0x00, 0x00)).is_err());
+        assert!(RomInfo::parse(&rom(0x01, 0x00, 0x00, 0x00, 0x02)).is_err());
+        assert!(RomInfo::parse(&[0; 8]).is_err());
+
+        // Overridden fields are never decoded
+        let overrides = Overrides {mapper: Some(Mapper::B_2003), save: Some(SaveType::Sram(0x8000)), vertical: Some(true), ..Overrides::default()};
+        let info = RomInfo::parse_with(&rom(0x01, 0x00, 0x07, 0x04, 0x02), &overrides).unwrap();
+        assert!(info.mapper == Mapper::B_2003);
+        assert_eq!(info.save, SaveType::Sram(0x8000));
+        assert_eq!((info.vertical(), info.port_a0_bits()), (true, 0x04));
+    }
+}
@@ -0,0 +1,196 @@
+use alloc::vec::Vec;
+#[cfg(feature = "std")]
+use std::{fs::{self, File, OpenOptions}, io::{self, Write}, path::PathBuf};
+
+/// Size of the blocks in which SRAM changes are tracked and journaled
+pub const PAGE_SIZE: usize = 0x100;
+
+/// The journal is compacted into the SRAM file once it grows beyond this many times the size of the SRAM
+#[cfg(feature = "std")]
+const COMPACT_FACTOR: u64 = 4;
+
+/// Size of a record's header, a 32-bit offset followed by a 16-bit length
+const HEADER_SIZE: usize = 6;
+/// Size of the checksum following a record's data
+const CHECKSUM_SIZE: usize = 4;
+
+/// Returns the path of the journal belonging to an SRAM file
+#[cfg(feature = "std")]
+pub fn journal_path(sram_path: &str) -> PathBuf {
+    PathBuf::from(format!("{}.journal", sram_path))
+}
+
+/// Simple additive checksum, enough to tell a record that was only partially written apart from a complete one
+fn checksum(bytes: &[u8]) -> u32 {
+    bytes.iter().fold(0x5753_5241u32, |acc, byte| acc.rotate_left(5).wrapping_add(*byte as u32))
+}
+
+/// Encodes a single record containing the new contents of the SRAM starting at `offset`
+pub fn encode_record(offset: u32, data: &[u8]) -> Vec<u8> {
+    let mut record = Vec::with_capacity(HEADER_SIZE + data.len() + CHECKSUM_SIZE);
+    record.extend(offset.to_le_bytes());
+    record.extend((data.len() as u16).to_le_bytes());
+    record.extend_from_slice(data);
+    record.extend(checksum(&record).to_le_bytes());
+    record
+}
+
+/// Applies the records in the journal to the SRAM, returns how many were applied
+///
+/// Replaying stops at the first record that is incomplete, corrupt or out of range.
+/// Such a record can only be the result of the emulator stopping while it was being written,
+/// so everything before it is still valid.
+pub fn replay(journal: &[u8], sram: &mut [u8]) -> usize {
+    let mut pos = 0;
+    let mut applied = 0;
+
+    while let Some(header) = journal.get(pos..pos + HEADER_SIZE) {
+        let offset = u32::from_le_bytes([header[0], header[1], header[2], header[3]]) as usize;
+        let len = u16::from_le_bytes([header[4], header[5]]) as usize;
+        let end = pos + HEADER_SIZE + len;
+
+        let Some(record) = journal.get(pos..end) else {break};
+        let Some(sum) = journal.get(end..end + CHECKSUM_SIZE) else {break};
+        if checksum(record).to_le_bytes() != sum || offset + len > sram.len() {break}
+
+        sram[offset..offset + len].copy_from_slice(&record[HEADER_SIZE..]);
+        pos = end + CHECKSUM_SIZE;
+        applied += 1;
+    }
+    applied
+}
+
+/// Applies the journal belonging to an SRAM file, if there is one
+#[cfg(feature = "std")]
+pub fn replay_file(sram_path: &str, sram: &mut [u8]) {
+    if let Ok(journal) = fs::read(journal_path(sram_path)) {
+        replay(&journal, sram);
+    }
+}
+
+/// An open SRAM journal
+///
+/// Changed pages of SRAM are appended to the journal every few frames instead of rewriting the entire SRAM file,
+/// which can be up to 512KB large. The journal is compacted into the SRAM file when it grows too large and on a clean exit.
+#[cfg(feature = "std")]
+pub struct SramJournal {
+    /// Path of the SRAM file
+    sram_path: String,
+    /// The journal, opened for appending
+    file: File,
+    /// Current size of the journal
+    len: u64,
+    /// Size above which the journal is compacted
+    limit: u64,
+}
+
+#[cfg(feature = "std")]
+impl SramJournal {
+    /// Opens the journal of an SRAM file
+    ///
+    /// The SRAM is expected to already have had any existing journal replayed, it is written to the SRAM file and the journal starts out empty.
+    pub fn open(sram_path: &str, sram: &[u8]) -> io::Result<Self> {
+        let file = OpenOptions::new().create(true).append(true).open(journal_path(sram_path))?;
+        let mut journal = Self {sram_path: sram_path.to_string(), file, len: 0, limit: sram.len() as u64 * COMPACT_FACTOR};
+        journal.compact(sram)?;
+        Ok(journal)
+    }
+
+    /// Appends the given pages of the SRAM to the journal and makes sure they reach the disk
+    pub fn record(&mut self, sram: &[u8], pages: &[usize]) -> io::Result<()> {
+        if pages.is_empty() {return Ok(())}
+
+        let mut records = Vec::new();
+        for page in pages {
+            let start = page * PAGE_SIZE;
+            let end = (start + PAGE_SIZE).min(sram.len());
+            records.extend(encode_record(start as u32, &sram[start..end]));
+        }
+        self.file.write_all(&records)?;
+        self.file.sync_data()?;
+        self.len += records.len() as u64;
+
+        if self.len > self.limit {
+            self.compact(sram)?;
+        }
+        Ok(())
+    }
+
+    /// Rewrites the SRAM file and empties the journal
+    ///
+    /// The SRAM file is replaced atomically, if the emulator stops before the journal is emptied
+    /// replaying it again leads to the same contents.
+    pub fn compact(&mut self, sram: &[u8]) -> io::Result<()> {
+        let temp_path = format!("{}.tmp", self.sram_path);
+        let mut temp = File::create(&temp_path)?;
+        temp.write_all(sram)?;
+        temp.sync_all()?;
+        fs::rename(&temp_path, &self.sram_path)?;
+
+        self.file.set_len(0)?;
+        self.file.sync_all()?;
+        self.len = 0;
+        Ok(())
+    }
+
+    /// Closes the journal on a clean exit, once the SRAM file has been written in full
+    pub fn close(self) -> io::Result<()> {
+        drop(self.file);
+        fs::remove_file(journal_path(&self.sram_path))
+    }
+}
+
+#[cfg(test)]
+#[cfg_attr(coverage_nightly, coverage(off))]
+mod test {
+    use alloc::vec;
+
+    use super::*;
+
+    #[test]
+    fn test_replay_records() {
+        let mut journal = encode_record(0x10, &[1, 2, 3]);
+        journal.extend(encode_record(0x11, &[9]));
+
+        let mut sram = vec![0; 0x20];
+        assert_eq!(replay(&journal, &mut sram), 2);
+        assert_eq!(sram[0x10..0x13], [1, 9, 3]);
+    }
+
+    #[test]
+    fn test_replay_stops_at_torn_record() {
+        let mut journal = encode_record(0x00, &[1, 2]);
+        let torn = encode_record(0x02, &[3, 4]);
+        journal.extend(&torn[..torn.len() - 1]);
+
+        let mut sram = vec![0; 0x10];
+        assert_eq!(replay(&journal, &mut sram), 1);
+        assert_eq!(sram[0..4], [1, 2, 0, 0]);
+
+        let mut corrupt = encode_record(0x00, &[1, 2]);
+        corrupt[HEADER_SIZE] ^= 0xFF;
+        assert_eq!(replay(&corrupt, &mut sram), 0);
+    }
+
+    #[test]
+    #[cfg(feature = "std")]
+    fn test_journal_file() {
+        let sram_path = std::env::temp_dir().join(format!("wondercrab_journal_{}.sram", std::process::id()));
+        let sram_path = sram_path.to_str().unwrap();
+
+        let mut sram = vec![0; 0x400];
+        let mut journal = SramJournal::open(sram_path, &sram).unwrap();
+        sram[0x105] = 0xAA;
+        journal.record(&sram, &[1]).unwrap();
+
+        // Simulate a crash by reading the files without closing the journal
+        let mut restored = fs::read(sram_path).unwrap();
+        assert_eq!(restored[0x105], 0x00);
+        replay_file(sram_path, &mut restored);
+        assert_eq!(restored, sram);
+
+        journal.close().unwrap();
+        assert!(!journal_path(sram_path).exists());
+        fs::remove_file(sram_path).unwrap();
+    }
+}
@@ -0,0 +1,166 @@
+use alloc::{format, string::{String, ToString}};
+
+use crate::{romdb::{GameInfo, Quirks}, toml::{self, Line, Value}};
+
+use super::{header::SaveType, Mapper};
+
+/// Settings from a `[game].wondercrab.toml` file next to the ROM, replacing what the footer and the ROM database say
+///
+/// This lets bad dumps and homebrew with wrong footers run without editing the ROM. Every key is optional:
+///
+/// ```toml
+/// mapper = "2003"            # "2001" or "2003"
+/// save = "eeprom"            # "none", "sram" or "eeprom"
+/// save_size = 0x400          # in bytes, required unless save is "none"
+/// orientation = "vertical"   # "horizontal" or "vertical"
+/// rtc = true                 # the clock is part of the 2003 mapper, which this selects
+/// quirks = "vertical,rtc"    # in the ROM database's format
+/// ```
+///
+/// EEPROMs hold 0x400, 0x2000 or 0x4000 bytes and SRAM a power of two from 0x2000 to 0x80000 bytes, other sizes are refused.
+///
+/// Only the flat subset of TOML read by [`crate::toml`] is understood, without any tables.
+#[derive(Clone, Copy, PartialEq, Eq, Debug, Default)]
+pub struct Overrides {
+    /// The mapper chip
+    pub mapper: Option<Mapper>,
+    /// The save memory
+    pub save: Option<SaveType>,
+    /// Whether the game is played vertically
+    pub vertical: Option<bool>,
+    /// Whether the cartridge contains a real-time clock
+    pub rtc: Option<bool>,
+    /// Quirks replacing the database's, before the keys above are applied to them
+    pub quirks: Option<Quirks>,
+}
+
+impl Overrides {
+    /// Returns the path of a game's overrides
+    pub fn path(game: &str) -> String {
+        format!("{}.wondercrab.toml", game)
+    }
+
+    /// Reads a game's overrides, returns no overrides if the file does not exist
+    #[cfg(feature = "std")]
+    pub fn load(game: &str) -> Result<Self, String> {
+        let path = Self::path(game);
+        match std::fs::read_to_string(&path) {
+            Ok(text) => Self::parse(&text).map_err(|e| format!("{}: {}", path, e)),
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => Ok(Self::default()),
+            Err(e) => Err(format!("{}: {}", path, e)),
+        }
+    }
+
+    /// Parses overrides
+    pub fn parse(text: &str) -> Result<Self, String> {
+        let mut overrides = Self::default();
+        let mut save = None;
+        let mut save_size = None;
+        for line in toml::lines(text) {
+            let (number, line) = line?;
+            let error = |e: String| format!("line {}: {}", number, e);
+            let (key, value) = match line {
+                Line::Pair(key, value) => (key, value),
+                Line::Table(table) => return Err(error(format!("Expected key = value, found the table {}", table))),
+            };
+            match (key.as_str(), value) {
+                ("mapper", Value::String(mapper)) => overrides.mapper = Some(match mapper.as_str() {
+                    "2001" => Mapper::B_2001,
+                    "2003" => Mapper::B_2003,
+                    mapper => return Err(error(format!("Unknown mapper {}, expected 2001 or 2003", mapper))),
+                }),
+                ("save", Value::String(kind)) => save = Some(kind),
+                ("save_size", Value::Integer(size)) => save_size = Some(size),
+                ("orientation", Value::String(orientation)) => overrides.vertical = Some(match orientation.as_str() {
+                    "horizontal" => false,
+                    "vertical" => true,
+                    orientation => return Err(error(format!("Unknown orientation {}, expected horizontal or vertical", orientation))),
+                }),
+                ("rtc", Value::Boolean(rtc)) => overrides.rtc = Some(rtc),
+                ("quirks", Value::String(quirks)) => overrides.quirks = Some(Quirks::parse(&quirks)),
+                (key @ ("mapper" | "save" | "save_size" | "orientation" | "rtc" | "quirks"), _) => return Err(error(format!("Wrong type for {}", key))),
+                (key, _) => return Err(error(format!("Unknown key {}", key))),
+            }
+        }
+
+        overrides.save = match (save.as_deref(), save_size) {
+            (None, None) => None,
+            (Some("none"), None) => Some(SaveType::None),
+            (Some("sram"), Some(size @ 0x2000..=0x80000)) if size.is_power_of_two() => Some(SaveType::Sram(size)),
+            (Some("eeprom"), Some(size @ (0x400 | 0x2000 | 0x4000))) => Some(SaveType::Eeprom(size)),
+            (Some("sram"), Some(size)) => return Err(format!("Unsupported SRAM size {:#X}, expected a power of two from 0x2000 to 0x80000", size)),
+            (Some("eeprom"), Some(size)) => return Err(format!("Unsupported EEPROM size {:#X}, expected 0x400, 0x2000 or 0x4000", size)),
+            (Some("sram" | "eeprom"), None) => return Err("save_size is required along with save".to_string()),
+            (None | Some("none"), Some(_)) => return Err("save_size requires save to be sram or eeprom".to_string()),
+            (Some(kind), _) => return Err(format!("Unknown save type {}, expected none, sram or eeprom", kind)),
+        };
+        if overrides.rtc == Some(true) {
+            if overrides.mapper == Some(Mapper::B_2001) {return Err("The real-time clock requires the 2003 mapper".to_string())}
+            overrides.mapper = Some(Mapper::B_2003);
+        }
+        Ok(overrides)
+    }
+
+    /// Applies the overrides to the game's database entry, creating one named after the file if the game is not in the database
+    pub fn apply_quirks(&self, game: &str, info: &mut Option<GameInfo>) {
+        if self.quirks.is_none() && self.vertical.is_none() && self.rtc.is_none() && self.save.is_none() {return}
+        let title = game.rsplit(['/', '\\']).next().filter(|name| !name.is_empty()).unwrap_or(game).to_string();
+        let info = info.get_or_insert_with(|| GameInfo {title, quirks: Quirks::default(), status: None, notes: String::new()});
+        let quirks = &mut info.quirks;
+        if let Some(replacement) = self.quirks {*quirks = replacement}
+        if let Some(vertical) = self.vertical {quirks.vertical = vertical}
+        if let Some(rtc) = self.rtc {quirks.rtc = rtc}
+        // The save memory given here takes precedence over the database's
+        if self.save.is_some() {quirks.eeprom_size = None}
+    }
+}
+
+#[cfg(test)]
+#[cfg_attr(coverage_nightly, coverage(off))]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_parse_overrides() {
+        let overrides = Overrides::parse("
+            # Homebrew with a blank footer
+            mapper = \"2001\"
+            save = \"eeprom\"  # the footer says none
+            save_size = 0x0400
+            orientation = \"vertical\"
+            quirks = \"rtc\"
+        ").unwrap();
+        assert_eq!(overrides, Overrides {
+            mapper: Some(Mapper::B_2001), save: Some(SaveType::Eeprom(0x400)), vertical: Some(true), rtc: None,
+            quirks: Some(Quirks {rtc: true, ..Quirks::default()}),
+        });
+        assert_eq!(Overrides::parse("rtc = true").unwrap().mapper, Some(Mapper::B_2003));
+        assert_eq!(Overrides::parse("save = \"sram\"\nsave_size = 32_768").unwrap().save, Some(SaveType::Sram(0x8000)));
+        assert_eq!(Overrides::parse("").unwrap(), Overrides::default());
+
+        assert!(Overrides::parse("mapper = \"2002\"").is_err());
+        assert!(Overrides::parse("mapper = 2003").is_err());
+        assert!(Overrides::parse("save = \"sram\"").is_err());
+        assert!(Overrides::parse("save = \"eeprom\"\nsave_size = 0x800").unwrap_err().contains("Unsupported EEPROM size 0x800"));
+        assert!(Overrides::parse("save = \"sram\"\nsave_size = 0x6000").unwrap_err().contains("Unsupported SRAM size 0x6000"));
+        assert!(Overrides::parse("rtc = true\nmapper = \"2001\"").is_err());
+        assert!(Overrides::parse("[cartridge]").unwrap_err().contains("line 1"));
+        assert!(Overrides::parse("color = true").unwrap_err().contains("Unknown key color"));
+    }
+
+    #[test]
+    fn test_apply_quirks() {
+        let mut info = None;
+        Overrides::default().apply_quirks("roms/homebrew", &mut info);
+        assert_eq!(info, None);
+
+        Overrides {vertical: Some(true), ..Overrides::default()}.apply_quirks("roms/homebrew", &mut info);
+        let info = info.unwrap();
+        assert_eq!(info.title, "homebrew");
+        assert!(info.quirks.vertical);
+
+        let mut known = Some(GameInfo {quirks: Quirks {eeprom_size: Some(0x800), rtc: true, ..Quirks::default()}, ..info});
+        Overrides {save: Some(SaveType::Sram(0x8000)), rtc: Some(false), ..Overrides::default()}.apply_quirks("roms/homebrew", &mut known);
+        assert_eq!(known.unwrap().quirks, Quirks::default());
+    }
+}
@@ -0,0 +1,371 @@
+use alloc::{boxed::Box, vec::Vec};
+use core::{sync::atomic::{AtomicI64, Ordering}, time::Duration};
+#[cfg(feature = "std")]
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use crate::{state::{StateError, StateReader, StateWriter}, sync::Arc};
+
+/// Ticks of the SoC per second of emulated time
+const TICKS_PER_SECOND: u64 = 3_072_000;
+/// 2000-01-01 00:00:00, the earliest date the clock can hold and where it starts by default
+pub const Y2K: i64 = 946_684_800;
+
+/// Where the cartridge's real-time clock gets the time from
+///
+/// Times are seconds since 1970-01-01 00:00:00. The clock has no notion of time zones, so the time given is the one games show.
+pub trait TimeSource {
+    /// Returns the current time, given how long the console has been emulated for
+    fn now(&self, emulated: Duration) -> i64;
+}
+
+/// The host's clock, in UTC
+#[cfg(feature = "std")]
+pub struct HostClock;
+
+#[cfg(feature = "std")]
+impl TimeSource for HostClock {
+    fn now(&self, _emulated: Duration) -> i64 {
+        SystemTime::now().duration_since(UNIX_EPOCH).map_or(0, |time| time.as_secs() as i64)
+    }
+}
+
+/// A clock starting at a fixed time and following emulated time, so the same inputs always see the same times
+///
+/// This is what headless runs and tests use, a movie played back at any speed sees the clock exactly as it was recorded.
+pub struct FixedTime(pub i64);
+
+impl TimeSource for FixedTime {
+    fn now(&self, emulated: Duration) -> i64 {
+        self.0 + emulated.as_secs() as i64
+    }
+}
+
+/// Another source moved by an offset that can be changed while the game runs
+///
+/// The offset is shared, so a front-end or a script can make time travel from another thread through [`OffsetClock::handle`].
+pub struct OffsetClock<S: TimeSource> {
+    /// The time the offset is added to
+    source: S,
+    /// Seconds added to the source's time
+    offset: Arc<AtomicI64>,
+}
+
+impl<S: TimeSource> OffsetClock<S> {
+    /// Moves a source by an offset in seconds
+    pub fn new(source: S, offset: i64) -> Self {
+        Self {source, offset: Arc::new(AtomicI64::new(offset))}
+    }
+
+    /// Starts the clock at the given time, from then on it runs along with the source
+    pub fn starting_at(source: S, time: i64) -> Self {
+        let offset = time - source.now(Duration::ZERO);
+        Self::new(source, offset)
+    }
+
+    /// Returns the shared offset, changing it takes effect the next time the game reads the clock
+    pub fn handle(&self) -> Arc<AtomicI64> {
+        Arc::clone(&self.offset)
+    }
+}
+
+impl<S: TimeSource> TimeSource for OffsetClock<S> {
+    fn now(&self, emulated: Duration) -> i64 {
+        self.source.now(emulated) + self.offset.load(Ordering::Relaxed)
+    }
+}
+
+/// The Seiko S-3511A real-time clock found on cartridges with the 2003 mapper, reached through ports 0xCA and 0xCB
+///
+/// Port 0xCA takes a command and reads back the current one with bit 7 set, as the clock is always ready.
+/// Data is transferred a byte at a time through port 0xCB:
+///
+/// | Command | Transfer                                                                       |
+/// |---------|--------------------------------------------------------------------------------|
+/// | 0x10    | Resets the clock to 2000-01-01 00:00:00                                        |
+/// | 0x12    | Writes the status byte, bit 6 selects the 24-hour mode                         |
+/// | 0x13    | Reads the status byte                                                          |
+/// | 0x14    | Writes the year, month, day, day of the week, hour, minute and second in BCD   |
+/// | 0x15    | Reads the same 7 bytes, latched when the command is given                      |
+///
+/// In the 12-hour mode bit 7 of the hour is set in the afternoon. The alarm is not emulated.
+pub struct Rtc {
+    /// Where the time comes from
+    source: Box<dyn TimeSource>,
+    /// Seconds the game's clock is ahead of the source, set when the game writes the time
+    offset: i64,
+    /// Ticks of the SoC emulated so far
+    ticks: u64,
+
+    /// The command being run
+    command: u8,
+    /// The byte of the date and time the next transfer is made with
+    index: usize,
+    /// The status byte
+    status: u8,
+    /// Date and time being read or written
+    buffer: [u8; 7],
+}
+
+impl Rtc {
+    /// Creates a clock taking the time from a source
+    pub fn new(source: Box<dyn TimeSource>) -> Self {
+        Self {source, offset: 0, ticks: 0, command: 0, index: 0, status: 0x40, buffer: [0; 7]}
+    }
+
+    /// Replaces the source, the time the game set is kept relative to the new one
+    pub fn set_source(&mut self, source: Box<dyn TimeSource>) {
+        self.source = source;
+    }
+
+    /// Moves the clock by an amount of seconds, kept like a time set by the game
+    pub fn time_travel(&mut self, seconds: i64) {
+        self.offset += seconds;
+    }
+
+    /// Advances emulated time
+    pub fn advance(&mut self, ticks: u64) {
+        self.ticks += ticks;
+    }
+
+    /// Returns the time the game sees
+    pub fn now(&self) -> i64 {
+        let emulated = Duration::from_secs(self.ticks / TICKS_PER_SECOND) + Duration::from_nanos(self.ticks % TICKS_PER_SECOND * 1_000_000_000 / TICKS_PER_SECOND);
+        self.source.now(emulated) + self.offset
+    }
+
+    /// Reads port 0xCA or 0xCB
+    pub fn read(&mut self, port: u8) -> u8 {
+        if port == 0xCA {return self.command | 0x80}
+        match self.command {
+            0x13 => self.status,
+            0x15 => {
+                let byte = self.buffer[self.index];
+                self.next_byte();
+                byte
+            }
+            _ => 0,
+        }
+    }
+
+    /// Writes port 0xCA or 0xCB
+    pub fn write(&mut self, port: u8, byte: u8) {
+        if port == 0xCA {
+            (self.command, self.index) = (byte & 0x1F, 0);
+            match self.command {
+                0x10 => {
+                    self.offset = Y2K - (self.now() - self.offset);
+                    self.status = 0;
+                }
+                0x15 => self.buffer = self.encode(self.now()),
+                _ => {}
+            }
+            return;
+        }
+        match self.command {
+            0x12 => self.status = byte,
+            0x14 => {
+                self.buffer[self.index] = byte;
+                if self.index == 6 {
+                    if let Some(time) = self.decode() {self.offset = time - (self.now() - self.offset)}
+                }
+                self.next_byte();
+            }
+            _ => {}
+        }
+    }
+
+    /// Moves on to the next byte of the date and time, the command finishes after the last one
+    fn next_byte(&mut self) {
+        self.index += 1;
+        if self.index == self.buffer.len() {(self.command, self.index) = (0, 0)}
+    }
+
+    /// Whether the hours are counted from 0 to 23 rather than in two halves of the day
+    fn hours_24(&self) -> bool {
+        self.status & 0x40 != 0
+    }
+
+    /// Splits a time into the clock's BCD registers, times before 2000 show as 2000-01-01 00:00:00
+    fn encode(&self, time: i64) -> [u8; 7] {
+        let time = time.max(Y2K);
+        let (days, seconds) = (time.div_euclid(86400), time.rem_euclid(86400));
+        let (year, month, day) = civil_from_days(days);
+        let hour = seconds / 3600;
+        let hour = if self.hours_24() {bcd(hour)} else {bcd(hour % 12) | if hour >= 12 {0x80} else {0}};
+        // 1970-01-01 was a Thursday, days of the week start on Sunday
+        let weekday = (days + 4).rem_euclid(7);
+        [bcd(year % 100), bcd(month), bcd(day), bcd(weekday), hour, bcd(seconds / 60 % 60), bcd(seconds % 60)]
+    }
+
+    /// Reads back the time written by the game, or `None` if it is not a valid date
+    fn decode(&self) -> Option<i64> {
+        let [year, month, day, _, hour, minute, second] = self.buffer;
+        let hour = if self.hours_24() {from_bcd(hour)?} else {from_bcd(hour & 0x7F)? % 12 + if hour & 0x80 != 0 {12} else {0}};
+        let (year, month, day, minute, second) = (2000 + from_bcd(year)?, from_bcd(month)?, from_bcd(day)?, from_bcd(minute)?, from_bcd(second)?);
+        if !(1..=12).contains(&month) || !(1..=31).contains(&day) || hour > 23 || minute > 59 || second > 59 {return None}
+        Some(days_from_civil(year, month, day) * 86400 + hour * 3600 + minute * 60 + second)
+    }
+
+    /// Returns what the cartridge's battery keeps while the console is off, to be stored alongside the save file
+    ///
+    /// This is the time the game set, as the offset in seconds from the source in 8 little-endian bytes, followed by the status byte.
+    /// As the offset is relative to the source, a clock the game set 5 minutes fast stays 5 minutes fast on the next run.
+    pub fn battery(&self) -> Vec<u8> {
+        let mut data = self.offset.to_le_bytes().to_vec();
+        data.push(self.status);
+        data
+    }
+
+    /// Restores what [`Rtc::battery`] returned, returns whether it could, data of another size leaves the clock untouched
+    pub fn restore_battery(&mut self, data: &[u8]) -> bool {
+        let Ok(data) = <[u8; 9]>::try_from(data) else {return false};
+        self.offset = i64::from_le_bytes(data[..8].try_into().unwrap());
+        self.status = data[8];
+        true
+    }
+
+    /// Saves the registers and the time the game set, relative to the source
+    pub fn save(&self, w: &mut StateWriter) {
+        w.write_u64(self.offset as u64);
+        w.write_u64(self.ticks);
+        w.write_u8(self.command);
+        w.write_u8(self.index as u8);
+        w.write_u8(self.status);
+        w.write_bytes(&self.buffer);
+    }
+
+    /// Loads what [`Rtc::save`] wrote, the source stays the same
+    pub fn load(&mut self, r: &mut StateReader) -> Result<(), StateError> {
+        let (offset, ticks, command, index, status, buffer) = (r.read_u64()? as i64, r.read_u64()?, r.read_u8()?, r.read_u8()? as usize, r.read_u8()?, r.read_array::<7>()?);
+        if index >= buffer.len() {return Err(StateError::Mismatch("RTC transfer"))}
+        (self.offset, self.ticks, self.command, self.index, self.status, self.buffer) = (offset, ticks, command, index, status, buffer);
+        Ok(())
+    }
+}
+
+/// Parses a time given as `YYYY-MM-DD`, `YYYY-MM-DDTHH:MM` or `YYYY-MM-DDTHH:MM:SS`
+pub fn parse_time(text: &str) -> Option<i64> {
+    let (date, time) = text.trim().split_once('T').unwrap_or((text.trim(), "00:00"));
+    let date: Vec<i64> = date.split('-').map(|part| part.parse().ok()).collect::<Option<_>>()?;
+    let time: Vec<i64> = time.split(':').map(|part| part.parse().ok()).collect::<Option<_>>()?;
+    let ([year, month, day], [hour, minute, rest @ ..]) = (&date[..], &time[..]) else {return None};
+    let second = match rest {
+        [] => 0,
+        [second] => *second,
+        _ => return None,
+    };
+    let (year, month, day, hour, minute) = (*year, *month, *day, *hour, *minute);
+    if !(1..=12).contains(&month) || !(1..=31).contains(&day) || hour > 23 || minute > 59 || second > 59 {return None}
+    Some(days_from_civil(year, month, day) * 86400 + hour * 3600 + minute * 60 + second)
+}
+
+/// Encodes a value from 0 to 99 in BCD
+fn bcd(value: i64) -> u8 {
+    (((value / 10) << 4) | (value % 10)) as u8
+}
+
+/// Decodes a BCD byte, or returns `None` if one of its digits is not decimal
+fn from_bcd(byte: u8) -> Option<i64> {
+    let (hi, lo) = (byte >> 4, byte & 0xF);
+    (hi < 10 && lo < 10).then_some((hi * 10 + lo) as i64)
+}
+
+/// Days since 1970-01-01 of a date in the proleptic Gregorian calendar
+fn days_from_civil(year: i64, month: i64, day: i64) -> i64 {
+    let year = if month <= 2 {year - 1} else {year};
+    let era = year.div_euclid(400);
+    let year_of_era = year - era * 400;
+    let day_of_year = (153 * (month + if month > 2 {-3} else {9}) + 2) / 5 + day - 1;
+    let day_of_era = year_of_era * 365 + year_of_era / 4 - year_of_era / 100 + day_of_year;
+    era * 146_097 + day_of_era - 719_468
+}
+
+/// Date of a day counted from 1970-01-01, the inverse of [`days_from_civil`]
+fn civil_from_days(days: i64) -> (i64, i64, i64) {
+    let days = days + 719_468;
+    let era = days.div_euclid(146_097);
+    let day_of_era = days - era * 146_097;
+    let year_of_era = (day_of_era - day_of_era / 1460 + day_of_era / 36524 - day_of_era / 146_096) / 365;
+    let day_of_year = day_of_era - (365 * year_of_era + year_of_era / 4 - year_of_era / 100);
+    let month_index = (5 * day_of_year + 2) / 153;
+    let day = day_of_year - (153 * month_index + 2) / 5 + 1;
+    let month = if month_index < 10 {month_index + 3} else {month_index - 9};
+    let year = year_of_era + era * 400 + if month <= 2 {1} else {0};
+    (year, month, day)
+}
+
+#[cfg(test)]
+#[cfg_attr(coverage_nightly, coverage(off))]
+mod test {
+    use super::*;
+
+    /// Reads the date and time through the ports
+    fn read_time(rtc: &mut Rtc) -> [u8; 7] {
+        rtc.write(0xCA, 0x15);
+        core::array::from_fn(|_| rtc.read(0xCB))
+    }
+
+    #[test]
+    fn test_dates() {
+        assert_eq!(days_from_civil(1970, 1, 1), 0);
+        assert_eq!(days_from_civil(2000, 1, 1) * 86400, Y2K);
+        assert_eq!(civil_from_days(days_from_civil(2024, 2, 29)), (2024, 2, 29));
+        assert_eq!(parse_time("2001-03-04T05:06:07"), Some(Y2K + (days_from_civil(2001, 3, 4) - days_from_civil(2000, 1, 1)) * 86400 + 5 * 3600 + 6 * 60 + 7));
+        assert_eq!(parse_time("2000-01-01"), Some(Y2K));
+        assert_eq!(parse_time("2000-13-01"), None);
+        assert_eq!(parse_time("yesterday"), None);
+    }
+
+    #[test]
+    fn test_fixed_time_follows_emulation() {
+        let mut rtc = Rtc::new(Box::new(FixedTime(parse_time("2001-12-31T23:59:58").unwrap())));
+        // Monday the 31st of December 2001
+        assert_eq!(read_time(&mut rtc), [0x01, 0x12, 0x31, 0x01, 0x23, 0x59, 0x58]);
+        assert_eq!(rtc.read(0xCA), 0x80);
+
+        rtc.advance(3 * TICKS_PER_SECOND);
+        assert_eq!(read_time(&mut rtc), [0x02, 0x01, 0x01, 0x02, 0x00, 0x00, 0x01]);
+    }
+
+    #[test]
+    fn test_game_sets_time() {
+        let mut rtc = Rtc::new(Box::new(FixedTime(Y2K)));
+        rtc.write(0xCA, 0x14);
+        for byte in [0x05, 0x06, 0x07, 0x02, 0x08, 0x09, 0x10] {rtc.write(0xCB, byte)}
+        rtc.advance(TICKS_PER_SECOND);
+        assert_eq!(read_time(&mut rtc), [0x05, 0x06, 0x07, 0x02, 0x08, 0x09, 0x11]);
+
+        // The 12-hour mode marks the afternoon in bit 7, a reset goes back to 2000
+        rtc.write(0xCA, 0x12);
+        rtc.write(0xCB, 0x00);
+        rtc.write(0xCA, 0x15);
+        assert_eq!(rtc.read(0xCB), 0x05);
+        rtc.write(0xCA, 0x10);
+        assert_eq!(read_time(&mut rtc), [0x00, 0x01, 0x01, 0x06, 0x00, 0x00, 0x00]);
+    }
+
+    #[test]
+    fn test_battery() {
+        let mut rtc = Rtc::new(Box::new(FixedTime(Y2K)));
+        rtc.write(0xCA, 0x14);
+        for byte in [0x05, 0x06, 0x07, 0x02, 0x08, 0x09, 0x10] {rtc.write(0xCB, byte)}
+        rtc.write(0xCA, 0x12);
+        rtc.write(0xCB, 0x00);
+
+        // The next run starts later, with the game's time and mode kept
+        let mut next = Rtc::new(Box::new(FixedTime(Y2K + 60)));
+        assert!(!next.restore_battery(&[0; 4]));
+        assert!(next.restore_battery(&rtc.battery()));
+        assert_eq!(read_time(&mut next), [0x05, 0x06, 0x07, 0x02, 0x08, 0x10, 0x10]);
+        next.write(0xCA, 0x13);
+        assert_eq!(next.read(0xCB), 0x00);
+    }
+
+    #[test]
+    fn test_offset_clock() {
+        let clock = OffsetClock::starting_at(FixedTime(0), Y2K);
+        assert_eq!(clock.now(Duration::ZERO), Y2K);
+        clock.handle().fetch_add(3600, Ordering::Relaxed);
+        assert_eq!(clock.now(Duration::from_secs(1)), Y2K + 3601);
+    }
+}
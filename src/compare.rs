@@ -0,0 +1,184 @@
+use std::fs;
+
+use crate::{headless::{self, InputSource, NullInput, ScriptedInput}, options::{EmulatorOptions, SharedOptions}, parse_rom, png::{decode_png, encode_png}, soc::SoC, sound::buffer::SampleBuffer};
+
+/// Width of the LCD
+const WIDTH: usize = 224;
+/// Height of the LCD
+const HEIGHT: usize = 144;
+
+/// Scales a reference screenshot down to the LCD's size
+///
+/// Screenshots may be any whole multiple of the LCD's size, and rotated a quarter turn either way as emulators show vertical games.
+/// Returns every orientation that fits, as which way a screenshot was rotated cannot be told from its size.
+pub fn fit_reference(width: usize, height: usize, rgb: &[u8]) -> Result<Vec<Vec<u8>>, String> {
+    let pixel = |x: usize, y: usize| &rgb[(y * width + x) * 3..(y * width + x) * 3 + 3];
+    let fit = |scale: usize, map: &dyn Fn(usize, usize) -> (usize, usize)| -> Vec<u8> {
+        (0..HEIGHT).flat_map(|y| (0..WIDTH).map(move |x| (x, y)))
+            .flat_map(|(x, y)| {
+                let (rx, ry) = map(x, y);
+                pixel(rx * scale + scale / 2, ry * scale + scale / 2).to_vec()
+            }).collect()
+    };
+    if width.is_multiple_of(WIDTH) && height.is_multiple_of(HEIGHT) && width / WIDTH == height / HEIGHT {
+        Ok(vec![fit(width / WIDTH, &|x, y| (x, y))])
+    } else if width.is_multiple_of(HEIGHT) && height.is_multiple_of(WIDTH) && width / HEIGHT == height / WIDTH {
+        let scale = width / HEIGHT;
+        Ok(vec![fit(scale, &|x, y| (y, WIDTH - 1 - x)), fit(scale, &|x, y| (HEIGHT - 1 - y, x))])
+    } else {
+        Err(format!("The reference is {}x{}, which is not a multiple of the {}x{} screen", width, height, WIDTH, HEIGHT))
+    }
+}
+
+/// Differences between a frame and a reference screenshot of it
+pub struct ScreenDiff {
+    /// Whether each pixel differs by more than the tolerance
+    pub mismatches: Vec<bool>,
+}
+
+impl ScreenDiff {
+    /// Compares two frames, pixels matching when none of their channels differ by more than `tolerance`
+    pub fn between(frame: &[u8], reference: &[u8], tolerance: u8) -> Self {
+        let mismatches = frame.chunks(3).zip(reference.chunks(3))
+            .map(|(a, b)| a.iter().zip(b).any(|(a, b)| a.abs_diff(*b) > tolerance))
+            .collect();
+        Self {mismatches}
+    }
+
+    /// Returns how many pixels differ
+    pub fn count(&self) -> usize {
+        self.mismatches.iter().filter(|mismatch| **mismatch).count()
+    }
+
+    /// Returns the smallest rectangle holding every differing pixel as its left, top, right and bottom edges, inclusive
+    pub fn bounds(&self) -> Option<(usize, usize, usize, usize)> {
+        let positions = || self.mismatches.iter().enumerate().filter(|(_, mismatch)| **mismatch).map(|(i, _)| (i % WIDTH, i / WIDTH));
+        Some((positions().map(|(x, _)| x).min()?, positions().map(|(_, y)| y).min()?, positions().map(|(x, _)| x).max()?, positions().map(|(_, y)| y).max()?))
+    }
+
+    /// Returns the scanlines holding a differing pixel, as ranges of consecutive lines
+    pub fn lines(&self) -> Vec<(usize, usize)> {
+        let mut ranges: Vec<(usize, usize)> = Vec::new();
+        for y in (0..HEIGHT).filter(|y| self.mismatches[y * WIDTH..(y + 1) * WIDTH].contains(&true)) {
+            match ranges.last_mut() {
+                Some((_, end)) if *end + 1 == y => *end = y,
+                _ => ranges.push((y, y)),
+            }
+        }
+        ranges
+    }
+
+    /// Draws the frame, the reference and the frame dimmed with differing pixels in red side by side
+    pub fn png(&self, frame: &[u8], reference: &[u8]) -> Vec<u8> {
+        let mut pixels = Vec::with_capacity(WIDTH * 3 * HEIGHT * 3);
+        for y in 0..HEIGHT {
+            let row = y * WIDTH * 3..(y + 1) * WIDTH * 3;
+            pixels.extend_from_slice(&frame[row.clone()]);
+            pixels.extend_from_slice(&reference[row.clone()]);
+            for (x, pixel) in frame[row].chunks(3).enumerate() {
+                match self.mismatches[y * WIDTH + x] {
+                    true => pixels.extend([0xFF, 0x00, 0x00]),
+                    false => pixels.extend(pixel.iter().map(|channel| channel / 4 + 0x60)),
+                }
+            }
+        }
+        encode_png(WIDTH * 3, HEIGHT, &pixels)
+    }
+
+    /// Describes how many pixels differ and where
+    pub fn report(&self) -> String {
+        let count = self.count();
+        let mut report = format!("{} of {} pixels differ ({:.2}%)", count, WIDTH * HEIGHT, 100.0 * count as f64 / (WIDTH * HEIGHT) as f64);
+        if let Some((left, top, right, bottom)) = self.bounds() {
+            let lines: Vec<_> = self.lines().iter()
+                .map(|(start, end)| if start == end {start.to_string()} else {format!("{}-{}", start, end)})
+                .collect();
+            report += &format!("\nwithin x {}-{}, y {}-{}\non lines {}", left, right, top, bottom, lines.join(", "));
+        }
+        report
+    }
+}
+
+/// Entry point of the `compare` command
+///
+/// Usage: `compare <rom> <reference.png> <frame> [script] [tolerance=N]`
+///
+/// Plays the ROM without a window up to the given frame, holding the buttons of a [`ScriptedInput`] script if one is given,
+/// then compares the frame pixel by pixel against a screenshot another emulator took at the same point.
+/// Save files are ignored so that the frame only depends on the ROM and the script.
+pub fn run(args: &[String]) -> Result<(), String> {
+    let usage = "Usage: compare <rom> <reference.png> <frame> [script] [tolerance=N]";
+    let (game, reference, frame) = match args {
+        [game, reference, frame, ..] => (game, reference, frame.parse::<u64>().map_err(|_| format!("Invalid frame: {}", frame))?),
+        _ => return Err(usage.to_string()),
+    };
+    let tolerance = match args.iter().find_map(|arg| arg.strip_prefix("tolerance=")) {
+        Some(tolerance) => tolerance.parse().map_err(|_| format!("Invalid tolerance: {}", tolerance))?,
+        None => 0,
+    };
+    let mut input: Box<dyn InputSource> = match args.get(3).filter(|arg| !arg.starts_with("tolerance=")) {
+        Some(script) => Box::new(ScriptedInput::load(script)?),
+        None => Box::new(NullInput),
+    };
+
+    let png = fs::read(reference).map_err(|e| format!("{}: {}", reference, e))?;
+    let (width, height, rgb) = decode_png(&png).map_err(|e| format!("{}: {}", reference, e))?;
+    let references = fit_reference(width, height, &rgb)?;
+
+    let (color, ram_content, _, _, rom, mapper, sram, rom_info, _) = parse_rom(game);
+    let ram_content = vec![0; ram_content.len()];
+    let options = SharedOptions::new(EmulatorOptions {color, mute: true, ..EmulatorOptions::new()});
+    let mut soc = SoC::new(ram_content, Vec::new(), Vec::new(), rom, mapper, sram, SampleBuffer::shared(), options, rom_info);
+    headless::run(&mut soc, input.as_mut(), frame, |_, _| {});
+    let lcd = soc.get_lcd().borrow().to_vec();
+
+    let (diff, reference) = references.into_iter()
+        .map(|reference| (ScreenDiff::between(&lcd, &reference, tolerance), reference))
+        .min_by_key(|(diff, _)| diff.count()).unwrap();
+    println!("{}", diff.report());
+    let path = format!("{}.compare-{}.png", game, frame);
+    fs::write(&path, diff.png(&lcd, &reference)).map_err(|e| format!("{}: {}", path, e))?;
+    println!("Wrote the comparison to {}", path);
+    Ok(())
+}
+
+#[cfg(test)]
+#[cfg_attr(coverage_nightly, coverage(off))]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_fit_reference() {
+        let frame: Vec<u8> = (0..WIDTH * HEIGHT).flat_map(|i| [(i % WIDTH) as u8, (i / WIDTH) as u8, 0]).collect();
+        assert_eq!(fit_reference(WIDTH, HEIGHT, &frame).unwrap(), std::slice::from_ref(&frame));
+
+        // Twice the size
+        let doubled: Vec<u8> = (0..HEIGHT * 2).flat_map(|y| (0..WIDTH * 2).flat_map(move |x| [(x / 2) as u8, (y / 2) as u8, 0])).collect();
+        assert_eq!(fit_reference(WIDTH * 2, HEIGHT * 2, &doubled).unwrap(), std::slice::from_ref(&frame));
+
+        // Rotated a quarter turn clockwise, the frame's left column being the top row
+        let rotated: Vec<u8> = (0..WIDTH).flat_map(|y| (0..HEIGHT).flat_map(move |x| [y as u8, (HEIGHT - 1 - x) as u8, 0])).collect();
+        assert!(fit_reference(HEIGHT, WIDTH, &rotated).unwrap().contains(&frame));
+
+        assert!(fit_reference(WIDTH + 1, HEIGHT, &vec![0; (WIDTH + 1) * HEIGHT * 3]).is_err());
+    }
+
+    #[test]
+    fn test_screen_diff() {
+        let frame = vec![0x80; WIDTH * HEIGHT * 3];
+        let mut reference = frame.clone();
+        reference[(10 * WIDTH + 20) * 3] = 0x84;
+        for x in 30..40 {reference[(12 * WIDTH + x) * 3 + 2] = 0x00}
+
+        let diff = ScreenDiff::between(&frame, &reference, 0);
+        assert_eq!(diff.count(), 11);
+        assert_eq!(diff.bounds(), Some((20, 10, 39, 12)));
+        assert_eq!(diff.lines(), [(10, 10), (12, 12)]);
+        assert!(diff.report().contains("on lines 10, 12"));
+        assert_eq!(ScreenDiff::between(&frame, &reference, 4).count(), 10);
+        assert_eq!(ScreenDiff::between(&frame, &frame, 0).bounds(), None);
+
+        let png = diff.png(&frame, &reference);
+        assert_eq!(u32::from_be_bytes(png[16..20].try_into().unwrap()), WIDTH as u32 * 3);
+    }
+}
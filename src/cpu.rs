@@ -5,6 +5,16 @@ pub mod v30mz;
 #[allow(unused)]
 mod opcode;
 
+/// Counts of the instructions executed over a session
+pub mod stats;
+
+/// Decoding of instructions into text without executing them, for debugging
+pub mod disasm;
+
+/// Compact binary CPU traces for long sessions, and their conversion to text
+#[cfg(feature = "std")]
+pub mod trace;
+
 /// Operands that the instruction uses
 #[derive(Debug)]
 #[derive(Clone, Copy, PartialEq, Eq)]
@@ -0,0 +1,244 @@
+use alloc::{format, string::{String, ToString}, vec, vec::Vec};
+
+use super::{opcode::{OpCode, SubOpCode, CPU_OP_CODES, GROUP_1, GROUP_2, IMMEDIATE_GROUP, SHIFT_GROUP}, Mode, Operand};
+
+/// Word registers, as selected by the reg field of a mod/rm byte or the lowest 3 bits of an opcode
+const WORD_REGISTERS: [&str; 8] = ["AW", "CW", "DW", "BW", "SP", "BP", "IX", "IY"];
+/// Byte registers, as selected by the reg field of a mod/rm byte or the lowest 3 bits of an opcode
+const BYTE_REGISTERS: [&str; 8] = ["AL", "CL", "DL", "BL", "AH", "CH", "DH", "BH"];
+/// Segment registers, as selected by bits 3-4 of a mod/rm byte or of an opcode
+const SEGMENT_REGISTERS: [&str; 4] = ["DS1", "PS", "SS", "DS0"];
+
+/// What an instruction does, for telling instructions apart at a glance
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum InstructionKind {
+    /// Anything not listed below
+    Normal,
+    /// Branches, calls, returns and software interrupts
+    Branch,
+    /// Accesses to I/O ports
+    Io,
+    /// Instructions the V30MZ does not define
+    Invalid,
+}
+
+/// An instruction decoded without executing it
+#[derive(Clone, PartialEq, Eq, Debug)]
+pub struct Disassembly {
+    /// Length of the instruction in bytes
+    pub len: u8,
+    /// Mnemonic followed by the operands
+    pub text: String,
+    /// What the instruction does
+    pub kind: InstructionKind,
+    /// Offset a near branch goes to, within the instruction's segment
+    pub target: Option<u16>,
+}
+
+/// Decodes the instruction at `offset` of a segment, reading its bytes through `fetch`
+///
+/// Offsets wrap around the end of the segment, like the CPU fetching them.
+/// Lengths follow the same rules as the CPU, prefixes being instructions of their own.
+pub fn disassemble(fetch: impl Fn(u16) -> u8, offset: u16) -> Disassembly {
+    let byte = |i: u16| fetch(offset.wrapping_add(i));
+    let code = byte(0);
+    let op = &CPU_OP_CODES[code as usize];
+    let mut len: u16 = 1;
+
+    // Far pointers and PREPARE's two immediates are laid out unlike anything else
+    match code {
+        0x9A | 0xEA => {
+            let word = |i| u16::from_le_bytes([byte(i), byte(i + 1)]);
+            return Disassembly {len: 5, text: format!("{:<7} {:04X}:{:04X}", op.name, word(3), word(1)), kind: InstructionKind::Branch, target: None};
+        }
+        0xC8 => {
+            let text = format!("{:<7} {:04X}, {:02X}", op.name, u16::from_le_bytes([byte(1), byte(2)]), byte(3));
+            return Disassembly {len: 4, text, kind: InstructionKind::Normal, target: None};
+        }
+        _ => {}
+    }
+
+    let has = |operand| op.op1 == operand || op.op2 == operand || op.op3 == Some(operand);
+    let mod_rm = has(Operand::MEMORY).then(|| {
+        let mod_rm = byte(1);
+        len += 1;
+        let displacement = match (mod_rm >> 6, mod_rm & 7) {
+            (0b00, 0b110) | (0b10, _) => {len += 2; u16::from_le_bytes([byte(2), byte(3)])}
+            (0b01, _) => {len += 1; byte(2) as i8 as u16}
+            _ => 0,
+        };
+        (mod_rm, displacement)
+    });
+    let sub = mod_rm.map_or(0, |(mod_rm, _)| (mod_rm >> 3) & 7);
+    let group = group(code).map(|group| &group[sub as usize]);
+    let name = group.map_or(op.name.as_str(), |sub_op| sub_op.name.as_str());
+
+    // Only TEST takes an immediate in group 1
+    let immediate_skipped = matches!(code, 0xF6 | 0xF7) && sub != 0;
+    let imm = has(Operand::IMMEDIATE) && !immediate_skipped;
+    let immediate = if (((imm && op.mode == Mode::M8) || has(Operand::IMMEDIATE_S)) && code != 0xE8 && code != 0xE9) || matches!(code, 0xC1 | 0xE5 | 0xE7) {
+        let value = byte(len);
+        len += 1;
+        Some(value as u16)
+    } else if imm || has(Operand::DIRECT) || code == 0xE8 || code == 0xE9 {
+        let value = u16::from_le_bytes([byte(len), byte(len + 1)]);
+        len += 2;
+        Some(value)
+    } else {
+        None
+    };
+
+    let kind = match (code, sub) {
+        (0x6C..=0x6F | 0xE4..=0xE7 | 0xEC..=0xEF, _) => InstructionKind::Io,
+        (0x70..=0x7F | 0xC2 | 0xC3 | 0xCA..=0xCF | 0xE0..=0xE3 | 0xE8..=0xEB, _) | (0xFE | 0xFF, 2..=5) => InstructionKind::Branch,
+        (0xF1, _) | (0xFE, 7) | (0xFF, 7) => InstructionKind::Invalid,
+        _ => InstructionKind::Normal,
+    };
+    // MUL with a sign extended immediate marks its immediate's size through its mode, its registers are words
+    let word_mode = op.mode != Mode::M8 || code == 0x6B;
+    let target = match code {
+        0x70..=0x7F | 0xE0..=0xE3 | 0xEB => immediate.map(|disp| offset.wrapping_add(len).wrapping_add(disp as u8 as i8 as u16)),
+        0xE8 | 0xE9 => immediate.map(|disp| offset.wrapping_add(len).wrapping_add(disp)),
+        _ => None,
+    };
+
+    let operands = operands(code, op, group, mod_rm, immediate, target, word_mode);
+    let text = if operands.is_empty() {name.to_string()} else {format!("{:<7} {}", name, operands.join(", "))};
+    Disassembly {len: len as u8, text, kind, target}
+}
+
+/// Returns the group an opcode takes its mnemonic from, selected by bits 3-5 of its mod/rm byte
+fn group(code: u8) -> Option<&'static [SubOpCode]> {
+    match code {
+        0x80..=0x83 => Some(&IMMEDIATE_GROUP),
+        0xC0 | 0xC1 | 0xD0..=0xD3 => Some(&SHIFT_GROUP),
+        0xF6 | 0xF7 => Some(&GROUP_1),
+        0xFE | 0xFF => Some(&GROUP_2),
+        _ => None,
+    }
+}
+
+/// Formats the memory operand of a mod/rm byte, or the register it selects when its mode is 0b11
+fn mod_rm_operand(mod_rm: u8, displacement: u16, word: bool) -> String {
+    let rm = (mod_rm & 7) as usize;
+    let base = ["BW+IX", "BW+IY", "BP+IX", "BP+IY", "IX", "IY", "BP", "BW"][rm];
+    match mod_rm >> 6 {
+        0b11 => if word {WORD_REGISTERS[rm]} else {BYTE_REGISTERS[rm]}.to_string(),
+        0b00 if rm == 6 => format!("[{:04X}]", displacement),
+        0b00 => format!("[{}]", base),
+        0b01 if (displacement as i16) < 0 => format!("[{}-{:02X}]", base, (displacement as i16).unsigned_abs()),
+        0b01 => format!("[{}+{:02X}]", base, displacement),
+        _ => format!("[{}+{:04X}]", base, displacement),
+    }
+}
+
+/// Lists an instruction's operands in order, destination first
+fn operands(code: u8, op: &OpCode, group: Option<&SubOpCode>, mod_rm: Option<(u8, u16)>, immediate: Option<u16>, target: Option<u16>, word: bool) -> Vec<String> {
+    let register = |index: u8| if word {WORD_REGISTERS} else {BYTE_REGISTERS}[index as usize & 7].to_string();
+    let memory = || {
+        let (mod_rm, displacement) = mod_rm.unwrap();
+        let operand = mod_rm_operand(mod_rm, displacement, word);
+        // Sizes are only ambiguous when no register gives them
+        let sized = mod_rm >> 6 != 0b11 && (group.is_some() || matches!(code, 0x8F | 0xC6 | 0xC7));
+        match (sized, word) {
+            (true, true) => format!("WORD {}", operand),
+            (true, false) => format!("BYTE {}", operand),
+            (false, _) => operand,
+        }
+    };
+
+    match code {
+        0x9E => return vec!["PSW".to_string(), "AH".to_string()],
+        0x9F => return vec!["AH".to_string(), "PSW".to_string()],
+        0x60 | 0x61 => return vec!["R".to_string()],
+        0x9C | 0x9D => return vec!["PSW".to_string()],
+        0xCC => return vec!["3".to_string()],
+        0xC4 | 0xC5 => return vec![SEGMENT_REGISTERS[if code == 0xC4 {0} else {3}].to_string(), register(mod_rm.unwrap().0 >> 3), memory()],
+        0xE4..=0xE7 => {
+            let (accumulator, port) = (register(0), format!("{:02X}", immediate.unwrap() as u8));
+            return if code < 0xE6 {vec![accumulator, port]} else {vec![port, accumulator]};
+        }
+        0xEC..=0xEF => return if code < 0xEE {vec![register(0), "DW".to_string()]} else {vec!["DW".to_string(), register(0)]},
+        0xD0 | 0xD1 => return vec![memory(), "1".to_string()],
+        0xD2 | 0xD3 => return vec![memory(), "CL".to_string()],
+        0xFE | 0xFF if group.is_some_and(|sub_op| sub_op.mode == Some(Mode::M32)) => return vec![format!("FAR {}", mod_rm_operand(mod_rm.unwrap().0, mod_rm.unwrap().1, true))],
+        _ => {}
+    }
+
+    let mut operands = Vec::new();
+    for operand in [Some(op.op1), Some(op.op2), op.op3].into_iter().flatten() {
+        let text = match operand {
+            Operand::MEMORY => memory(),
+            Operand::REGISTER => match mod_rm {
+                Some((mod_rm, _)) => register(mod_rm >> 3),
+                None => register(code),
+            },
+            Operand::ACCUMULATOR => register(0),
+            Operand::IMMEDIATE | Operand::IMMEDIATE_S => match (target, immediate) {
+                (Some(target), _) => format!("{:04X}", target),
+                (None, Some(value)) if operand == Operand::IMMEDIATE_S && !word => format!("{:02X}", value as u8),
+                (None, Some(value)) if operand == Operand::IMMEDIATE_S => format!("{:04X}", value as u8 as i8 as u16),
+                (None, Some(value)) if op.mode == Mode::M8 || code == 0xC1 => format!("{:02X}", value as u8),
+                (None, Some(value)) => format!("{:04X}", value),
+                (None, None) => continue,
+            },
+            Operand::SEGMENT => match mod_rm {
+                Some((mod_rm, _)) => SEGMENT_REGISTERS[(mod_rm >> 3) as usize & 3].to_string(),
+                None => SEGMENT_REGISTERS[(code >> 3) as usize & 3].to_string(),
+            },
+            Operand::DIRECT => format!("[{:04X}]", immediate.unwrap()),
+            Operand::NONE => continue,
+        };
+        operands.push(text);
+    }
+    operands
+}
+
+#[cfg(test)]
+#[cfg_attr(coverage_nightly, coverage(off))]
+mod test {
+    use super::*;
+
+    fn disassemble_bytes(bytes: &[u8]) -> Disassembly {
+        disassemble(|offset| bytes.get(offset as usize).copied().unwrap_or(0), 0)
+    }
+
+    #[test]
+    fn test_disassemble() {
+        let cases: [(&[u8], u8, &str); 16] = [
+            (&[0x90], 1, "NOP"),
+            (&[0xB0, 0x5A], 2, "MOV     AL, 5A"),
+            (&[0xB9, 0x34, 0x12], 3, "MOV     CW, 1234"),
+            (&[0xE6, 0xB1], 2, "OUT     B1, AL"),
+            (&[0xEC], 1, "IN      AL, DW"),
+            (&[0xA2, 0x00, 0x10], 3, "MOV     [1000], AL"),
+            (&[0x8B, 0x46, 0xFE], 3, "MOV     AW, [BP-02]"),
+            (&[0x03, 0x87, 0x34, 0x12], 4, "ADD     AW, [BW+1234]"),
+            (&[0x80, 0x3E, 0x00, 0x20, 0x07], 5, "CMP     BYTE [2000], 07"),
+            (&[0x83, 0xC4, 0xFE], 3, "ADD     SP, FFFE"),
+            (&[0xF6, 0xC3, 0x01], 3, "TEST    BL, 01"),
+            (&[0xF7, 0xE1], 2, "MULU    CW"),
+            (&[0xD1, 0xE0], 2, "SHL     AW, 1"),
+            (&[0xFF, 0x1E, 0x00, 0x30], 4, "CALL    FAR [3000]"),
+            (&[0x1E], 1, "PUSH    DS0"),
+            (&[0xC8, 0x10, 0x00, 0x01], 4, "PREPARE 0010, 01"),
+        ];
+        for (bytes, len, text) in cases {
+            let disassembly = disassemble_bytes(bytes);
+            assert_eq!((disassembly.len, disassembly.text.as_str()), (len, text), "{:02X?}", bytes);
+        }
+        assert_eq!(disassemble_bytes(&[0xEA, 0x00, 0x00, 0x00, 0xF0]).text, "BR      F000:0000");
+        assert_eq!(disassemble_bytes(&[0xF1]).kind, InstructionKind::Invalid);
+        assert_eq!(disassemble_bytes(&[0xE4, 0xB3]).kind, InstructionKind::Io);
+    }
+
+    #[test]
+    fn test_branch_targets() {
+        // BE -6 at 0x100 goes back to 0xFC, CALL near at 0xFFFE wraps around the segment
+        let program = |offset: u16| match offset {0x100 => 0x74, 0x101 => 0xFA, 0xFFFE => 0xE8, 0xFFFF => 0x10, 0x0000 => 0x00, _ => 0x90};
+        let branch = disassemble(program, 0x100);
+        assert_eq!((branch.target, branch.text.as_str(), branch.kind), (Some(0xFC), "BE      00FC", InstructionKind::Branch));
+        let call = disassemble(program, 0xFFFE);
+        assert_eq!((call.len, call.target), (3, Some(0x0011)));
+    }
+}
@@ -1,7 +1,36 @@
-use once_cell::sync::Lazy;
+use alloc::{boxed::Box, string::{String, ToString}, vec, vec::Vec};
+use core::ops::Deref;
+
+use once_cell::race::OnceBox;
 
 use super::*;
 
+/// A table of opcodes built the first time it is read
+///
+/// Unlike `once_cell::sync::Lazy`, this does not need the standard library to make other threads wait while the table is built,
+/// threads that race to read it first each build it and all but the first to finish drop theirs.
+pub struct Table<T: 'static> {
+    /// The table once built
+    cell: OnceBox<Vec<T>>,
+    /// Builds the table
+    build: fn() -> Vec<T>,
+}
+
+impl<T> Table<T> {
+    /// Creates a table that is built by `build` when first read
+    const fn new(build: fn() -> Vec<T>) -> Self {
+        Self {cell: OnceBox::new(), build}
+    }
+}
+
+impl<T> Deref for Table<T> {
+    type Target = Vec<T>;
+
+    fn deref(&self) -> &Vec<T> {
+        self.cell.get_or_init(|| Box::new((self.build)()))
+    }
+}
+
 /// An opcode as identified by its first byte
 #[derive(Debug, Clone)]
 pub struct OpCode {
@@ -86,7 +115,7 @@ impl SubOpCode {
 }
 
 /// A full list of instructions as identified by their first byte
-pub static CPU_OP_CODES: Lazy<Vec<OpCode>> = Lazy::new(|| {
+pub static CPU_OP_CODES: Table<OpCode> = Table::new(|| {
     vec![
         OpCode::one_byte(0x00, "ADD",     Operand::MEMORY,      Operand::REGISTER,    Mode::M8,  1,  2),
         OpCode::one_byte(0x01, "ADD",     Operand::MEMORY,      Operand::REGISTER,    Mode::M16, 1,  2),
@@ -248,7 +277,7 @@ pub static CPU_OP_CODES: Lazy<Vec<OpCode>> = Lazy::new(|| {
         OpCode::one_byte(0x8C, "MOV",     Operand::MEMORY,      Operand::SEGMENT,     Mode::M16, 1,  2),
         OpCode::one_byte(0x8D, "LDEA",    Operand::REGISTER,    Operand::MEMORY,      Mode::M16, 1,  0),
         OpCode::one_byte(0x8E, "MOV",     Operand::SEGMENT,     Operand::MEMORY,      Mode::M16, 2,  1),
-        OpCode::one_byte(0x8F, "POP",     Operand::MEMORY,      Operand::NONE,        Mode::M16, 1,  2),
+        OpCode::one_byte(0x8F, "POP",     Operand::NONE,        Operand::MEMORY,      Mode::M16, 1,  2),
         
         OpCode::one_byte(0x90, "NOP",     Operand::NONE,        Operand::NONE,        Mode::M16, 3,  0),
         OpCode::one_byte(0x91, "XCH",     Operand::ACCUMULATOR, Operand::REGISTER,    Mode::M16, 3,  0),
@@ -379,7 +408,7 @@ pub static CPU_OP_CODES: Lazy<Vec<OpCode>> = Lazy::new(|| {
 });
 
 /// The immediate group, contains instructions whose first byte is 0x80 - 0x83
-pub static IMMEDIATE_GROUP: Lazy<Vec<SubOpCode>> = Lazy::new(|| {
+pub static IMMEDIATE_GROUP: Table<SubOpCode> = Table::new(|| {
     vec![
         SubOpCode::normal(0b000, "ADD",  1,  2),
         SubOpCode::normal(0b001, "OR",   1,  2),
@@ -393,7 +422,7 @@ pub static IMMEDIATE_GROUP: Lazy<Vec<SubOpCode>> = Lazy::new(|| {
 });
 
 /// The shift group, contains instructions whose first byte is 0xC0, 0xC1, 0xD0 - 0xD3
-pub static SHIFT_GROUP: Lazy<Vec<SubOpCode>> = Lazy::new(|| {
+pub static SHIFT_GROUP: Table<SubOpCode> = Table::new(|| {
     vec![
         SubOpCode::normal(0b000, "ROL",  0, 0), // These operations base
         SubOpCode::normal(0b001, "ROR",  0, 0), // their timing on their
@@ -407,7 +436,7 @@ pub static SHIFT_GROUP: Lazy<Vec<SubOpCode>> = Lazy::new(|| {
 });
 
 /// Group one, contains instructions whose first byte is 0xF6, 0xF7
-pub static GROUP_1: Lazy<Vec<SubOpCode>> = Lazy::new(|| {
+pub static GROUP_1: Table<SubOpCode> = Table::new(|| {
     vec![
         SubOpCode::normal(0b000, "TEST", 1,  1),
         SubOpCode::normal(0b001, "NOP",  1,  0),
@@ -421,7 +450,7 @@ pub static GROUP_1: Lazy<Vec<SubOpCode>> = Lazy::new(|| {
 });
 
 /// Group two, contains instructions whose first byte is 0xFE, 0xFF
-pub static GROUP_2: Lazy<Vec<SubOpCode>> = Lazy::new(|| {
+pub static GROUP_2: Table<SubOpCode> = Table::new(|| {
     vec![
         SubOpCode::normal(0b000, "INC", 1,  2),
         SubOpCode::normal(0b001, "DEC", 1,  2),
@@ -0,0 +1,102 @@
+use alloc::{boxed::Box, format, string::String, vec, vec::Vec};
+
+use super::opcode::{SubOpCode, CPU_OP_CODES, GROUP_1, GROUP_2, IMMEDIATE_GROUP, SHIFT_GROUP};
+
+/// Executions of each instruction over a session, to find out what games spend their time on
+///
+/// Instructions are counted by their first byte, group instructions are further split by the sub-opcode in bits 3-5 of their second byte.
+/// Prefixes are counted as instructions of their own, like the CPU executes them.
+pub struct OpcodeStats {
+    /// Executions of each first byte and sub-opcode, only the first column is used by instructions outside of groups
+    counts: Box<[[u64; 8]; 256]>,
+}
+
+impl OpcodeStats {
+    /// Creates an empty table
+    pub fn new() -> Self {
+        Self {counts: Box::new([[0; 8]; 256])}
+    }
+
+    /// Counts an execution of the instruction starting with these two bytes, the second one is ignored outside of groups
+    pub fn record(&mut self, code: u8, second: u8) {
+        let sub = if group(code).is_some() {(second >> 3) & 7} else {0};
+        self.counts[code as usize][sub as usize] += 1;
+    }
+
+    /// Returns how often the instruction was executed, with the sub-opcode ignored outside of groups
+    pub fn count(&self, code: u8, sub: u8) -> u64 {
+        if group(code).is_some() {self.counts[code as usize][sub as usize & 7]} else {self.counts[code as usize][0]}
+    }
+
+    /// Returns the amount of instructions executed
+    pub fn total(&self) -> u64 {
+        self.counts.iter().flatten().sum()
+    }
+
+    /// Lists the executed instructions from the most to the least executed, with their share of the total
+    ///
+    /// Each line holds the count, the share, the first byte, followed by the sub-opcode for group instructions, and the mnemonic.
+    pub fn report(&self) -> String {
+        let mut entries: Vec<(u64, u8, Option<u8>)> = (0..=255u8).flat_map(|code| match group(code) {
+            Some(_) => (0..8).map(|sub| (self.counts[code as usize][sub as usize], code, Some(sub))).collect(),
+            None => vec![(self.counts[code as usize][0], code, None)],
+        }).filter(|(count, _, _)| *count > 0).collect();
+        entries.sort_by(|a, b| b.0.cmp(&a.0).then((a.1, a.2).cmp(&(b.1, b.2))));
+
+        let total = self.total().max(1) as f64;
+        let mut report = format!("{} instructions executed\n", self.total());
+        for (count, code, sub) in entries {
+            let (id, name) = match sub {
+                Some(sub) => (format!("{:02X}/{}", code, sub), group(code).unwrap()[sub as usize].name.as_str()),
+                None => (format!("{:02X}", code), CPU_OP_CODES[code as usize].name.as_str()),
+            };
+            report += &format!("{:>12} {:>6.2}% {:<5} {}\n", count, count as f64 * 100.0 / total, id, name);
+        }
+        report
+    }
+}
+
+impl Default for OpcodeStats {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+/// Returns the sub-opcodes of a group instruction, or `None` if the first byte is not one
+fn group(code: u8) -> Option<&'static [SubOpCode]> {
+    match code {
+        0x80..=0x83 => Some(&IMMEDIATE_GROUP),
+        0xC0 | 0xC1 | 0xD0..=0xD3 => Some(&SHIFT_GROUP),
+        0xF6 | 0xF7 => Some(&GROUP_1),
+        0xFE | 0xFF => Some(&GROUP_2),
+        _ => None,
+    }
+}
+
+#[cfg(test)]
+#[cfg_attr(coverage_nightly, coverage(off))]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_report() {
+        let mut stats = OpcodeStats::new();
+        for _ in 0..3 {stats.record(0x40, 0xFF)}
+        // SUB and CMP with an immediate, told apart by the second byte
+        stats.record(0x81, 0b0010_1000);
+        for _ in 0..2 {stats.record(0x81, 0b0011_1000)}
+
+        assert_eq!(stats.count(0x40, 7), 3);
+        assert_eq!(stats.count(0x81, 5), 1);
+        assert_eq!(stats.count(0x81, 7), 2);
+        assert_eq!(stats.total(), 6);
+
+        let report = stats.report();
+        let lines: Vec<_> = report.lines().collect();
+        assert_eq!(lines[0], "6 instructions executed");
+        assert_eq!(lines[1], "           3  50.00% 40    INC");
+        assert_eq!(lines[2], "           2  33.33% 81/7  CMP");
+        assert_eq!(lines[3], "           1  16.67% 81/5  SUB");
+        assert_eq!(lines.len(), 4);
+    }
+}
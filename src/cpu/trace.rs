@@ -0,0 +1,283 @@
+use std::{fmt::Write as _, io::{self, Read, Write}};
+
+use super::{opcode::CPU_OP_CODES, v30mz::V30MZ};
+
+/// Bytes every binary trace starts with, followed by [`VERSION`]
+pub const MAGIC: [u8; 4] = *b"WCBT";
+/// Version of the format, increased whenever the encoding of steps changes
+pub const VERSION: u8 = 1;
+/// Bit of the change mask for the PSW, the bits below it standing for the registers in the order of [`V30MZ::REGISTER_NAMES`]
+const PSW_BIT: u16 = 1 << 13;
+
+/// An instruction of a binary trace, along with the registers before it executed
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub struct Step {
+    /// First byte of the instruction
+    pub code: u8,
+    /// Registers in the order of [`V30MZ::REGISTER_NAMES`]
+    pub registers: [u16; 13],
+    /// Value of the PSW
+    pub psw: u16,
+}
+
+impl Step {
+    /// Physical address of the instruction, from PS and PC
+    pub fn address(&self) -> u32 {
+        (((self.registers[6] as u32) << 4) + self.registers[12] as u32) & 0xFFFFF
+    }
+
+    /// Mnemonic of the instruction, or of its group for instructions that belong to one, as the CPU's text trace prints it
+    pub fn name(&self) -> &'static str {
+        &CPU_OP_CODES[self.code as usize].name
+    }
+
+    /// Formats the step as the block of lines the CPU prints with the trace option, which [`crate::tracediff::parse_trace`] reads back
+    pub fn to_text(&self) -> String {
+        let [aw, bw, cw, dw, ds0, ds1, ps, ss, ix, iy, sp, bp, pc] = self.registers;
+        format!(
+            "{:05X} {:02X} {}\nIY {:04X} IX {:04X} BP {:04X} SP {:04X}\nBW {:04X} DW {:04X} CW {:04X} AW {:04X}\nPC {:04X} PS {:04X} PSW: {:04X}\nDS0: {:04X} DS1: {:04X} SS {:04X} PS {:04X}\n\n",
+            self.address(), self.code, self.name(), iy, ix, bp, sp, bw, dw, cw, aw, pc, ps, self.psw, ds0, ds1, ss, ps,
+        )
+    }
+
+    /// Formats the step as a single line of JSON, with the address, code, mnemonic and every register as numbers
+    pub fn to_json(&self) -> String {
+        let mut json = format!("{{\"address\":{},\"code\":{},\"name\":\"{}\"", self.address(), self.code, self.name());
+        for (name, value) in V30MZ::REGISTER_NAMES.iter().zip(self.registers) {write!(json, ",\"{}\":{}", name, value).unwrap()}
+        write!(json, ",\"PSW\":{}}}", self.psw).unwrap();
+        json
+    }
+}
+
+/// Writes the steps of a CPU trace in a compact binary format
+///
+/// After [`MAGIC`] and [`VERSION`] each step is encoded against the one before it, the first one against registers all 0:
+///
+/// | Field   | Encoding                                                                                   |
+/// |---------|--------------------------------------------------------------------------------------------|
+/// | Changes | LEB128 mask of the values that changed, bits 0-12 for the registers and bit 13 for the PSW |
+/// | Code    | First byte of the instruction                                                              |
+/// | Deltas  | For each bit of the mask from the lowest, the wrapping difference zigzag encoded in LEB128 |
+///
+/// Most instructions only move PC forward by a few bytes and change a register or two, which takes around 5 bytes instead of the 170 of the text trace.
+/// The address and mnemonic are not stored since PS, PC and the code give them back.
+///
+/// The first error writing is kept and returned by [`TraceWriter::finish`], steps are dropped from then on.
+pub struct TraceWriter<W: Write> {
+    /// Where the trace is written
+    out: W,
+    /// The step before, which the next one is encoded against
+    previous: Step,
+    /// Amount of steps written
+    steps: u64,
+    /// The first error writing, if any
+    error: Option<io::Error>,
+}
+
+impl<W: Write> TraceWriter<W> {
+    /// Starts a trace, writing its header
+    pub fn new(mut out: W) -> io::Result<Self> {
+        out.write_all(&MAGIC)?;
+        out.write_all(&[VERSION])?;
+        Ok(Self {out, previous: Step {code: 0, registers: [0; 13], psw: 0}, steps: 0, error: None})
+    }
+
+    /// Appends a step
+    pub fn record(&mut self, step: Step) {
+        if self.error.is_some() {return}
+        let values = step.registers.into_iter().chain([step.psw]);
+        let previous = self.previous.registers.into_iter().chain([self.previous.psw]);
+        let deltas: Vec<(usize, u16)> = values.zip(previous).enumerate()
+            .filter(|(_, (value, previous))| value != previous)
+            .map(|(idx, (value, previous))| (idx, value.wrapping_sub(previous)))
+            .collect();
+
+        let mut bytes = Vec::with_capacity(16);
+        write_varint(&mut bytes, deltas.iter().fold(0, |mask, (idx, _)| mask | 1 << idx));
+        bytes.push(step.code);
+        for (_, delta) in deltas {write_varint(&mut bytes, zigzag(delta))}
+
+        match self.out.write_all(&bytes) {
+            Ok(()) => (self.previous, self.steps) = (step, self.steps + 1),
+            Err(e) => self.error = Some(e),
+        }
+    }
+
+    /// Amount of steps written so far
+    pub fn steps(&self) -> u64 {
+        self.steps
+    }
+
+    /// Flushes the trace, returns where it was written along with the amount of steps or the first error
+    pub fn finish(mut self) -> io::Result<(W, u64)> {
+        if let Some(e) = self.error {return Err(e)}
+        self.out.flush()?;
+        Ok((self.out, self.steps))
+    }
+}
+
+/// Reads the steps of a trace written by a [`TraceWriter`] one at a time, so that traces larger than memory can be converted
+pub struct TraceReader<R: Read> {
+    /// Where the trace is read from
+    input: R,
+    /// The step before, which the next one is decoded against
+    previous: Step,
+}
+
+impl<R: Read> TraceReader<R> {
+    /// Starts reading a trace, checking its header
+    pub fn new(mut input: R) -> io::Result<Self> {
+        let mut header = [0; 5];
+        input.read_exact(&mut header)?;
+        if header[..4] != MAGIC {return Err(io::Error::new(io::ErrorKind::InvalidData, "Not a binary trace"))}
+        if header[4] != VERSION {return Err(io::Error::new(io::ErrorKind::InvalidData, format!("Unsupported binary trace version {}", header[4])))}
+        Ok(Self {input, previous: Step {code: 0, registers: [0; 13], psw: 0}})
+    }
+
+    /// Reads the next step, `None` at the end of the trace
+    fn read_step(&mut self) -> io::Result<Option<Step>> {
+        let mut first = [0];
+        if self.input.read(&mut first)? == 0 {return Ok(None)}
+        let mask = read_varint(&mut self.input, first[0])?;
+        if mask >= PSW_BIT << 1 {return Err(io::Error::new(io::ErrorKind::InvalidData, "Invalid change mask"))}
+
+        let mut code = [0];
+        self.input.read_exact(&mut code)?;
+        let mut step = Step {code: code[0], ..self.previous};
+        for idx in (0..14).filter(|idx| mask & 1 << idx != 0) {
+            let mut byte = [0];
+            self.input.read_exact(&mut byte)?;
+            let delta = unzigzag(read_varint(&mut self.input, byte[0])?);
+            let value = if idx == 13 {&mut step.psw} else {&mut step.registers[idx]};
+            *value = value.wrapping_add(delta);
+        }
+        self.previous = step;
+        Ok(Some(step))
+    }
+}
+
+impl<R: Read> Iterator for TraceReader<R> {
+    type Item = io::Result<Step>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        self.read_step().transpose()
+    }
+}
+
+/// Maps a signed difference to an unsigned one, small differences either way staying small
+fn zigzag(delta: u16) -> u16 {
+    let delta = delta as i16;
+    ((delta << 1) ^ (delta >> 15)) as u16
+}
+
+/// Reverses [`zigzag`]
+fn unzigzag(value: u16) -> u16 {
+    (value >> 1) ^ (value & 1).wrapping_neg()
+}
+
+/// Appends a value in LEB128, 7 bits per byte with the top bit set on every byte but the last
+fn write_varint(bytes: &mut Vec<u8>, mut value: u16) {
+    while value >= 0x80 {
+        bytes.push(value as u8 | 0x80);
+        value >>= 7;
+    }
+    bytes.push(value as u8);
+}
+
+/// Reads the rest of a LEB128 value whose first byte has already been read, at most 3 bytes for a 16-bit value
+fn read_varint(input: &mut impl Read, first: u8) -> io::Result<u16> {
+    let (mut value, mut byte, mut shift) = ((first & 0x7F) as u32, first, 7);
+    while byte & 0x80 != 0 {
+        if shift > 14 {return Err(io::Error::new(io::ErrorKind::InvalidData, "Value too long"))}
+        let mut next = [0];
+        input.read_exact(&mut next)?;
+        byte = next[0];
+        value |= ((byte & 0x7F) as u32) << shift;
+        shift += 7;
+    }
+    u16::try_from(value).map_err(|_| io::Error::new(io::ErrorKind::InvalidData, "Value out of range"))
+}
+
+/// Returns whether a file starts like a binary trace, so that commands can take either kind of trace
+pub fn is_binary_trace(bytes: &[u8]) -> bool {
+    bytes.starts_with(&MAGIC)
+}
+
+/// Entry point of the `btrace` command
+///
+/// Usage: `btrace record <rom> [frames] [script]`, `btrace text <trace>` or `btrace jsonl <trace>`
+///
+/// Records a binary trace of a headless run, see [`crate::headless::record_trace`], or converts one on the standard output
+/// to the CPU's text trace or to a line of JSON per step. `tracediff` also reads binary traces as they are.
+#[cfg(feature = "std")]
+pub fn run(args: &[String]) -> Result<(), String> {
+    const USAGE: &str = "Usage: btrace record <rom> [frames] [script] or btrace text|jsonl <trace>";
+    if args.first().map(String::as_str) == Some("record") {return crate::headless::record_trace(&args[1..])}
+    let [format, path, ..] = args else {return Err(USAGE.to_string())};
+    let convert: fn(&Step) -> String = match format.as_str() {
+        "text" => Step::to_text,
+        "jsonl" => |step| step.to_json() + "\n",
+        _ => return Err(USAGE.to_string()),
+    };
+    let file = std::fs::File::open(path).map_err(|e| format!("Could not read {}: {}", path, e))?;
+    let reader = TraceReader::new(io::BufReader::new(file)).map_err(|e| format!("{}: {}", path, e))?;
+    let mut out = io::BufWriter::new(io::stdout().lock());
+    for step in reader {
+        let step = step.map_err(|e| format!("{}: {}", path, e))?;
+        out.write_all(convert(&step).as_bytes()).map_err(|e| e.to_string())?;
+    }
+    out.flush().map_err(|e| e.to_string())
+}
+
+#[cfg(test)]
+#[cfg_attr(coverage_nightly, coverage(off))]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_round_trip() {
+        let mut registers = [0u16; 13];
+        registers[6] = 0xF000;
+        let mut steps = Vec::new();
+        for idx in 0..100u16 {
+            registers[12] = registers[12].wrapping_add(idx % 5);
+            registers[0] = registers[0].wrapping_sub(idx * 300);
+            steps.push(Step {code: idx as u8, registers, psw: 0xF002 | (idx & 1)});
+        }
+        let mut writer = TraceWriter::new(Vec::new()).unwrap();
+        for step in &steps {writer.record(*step)}
+        let (bytes, count) = writer.finish().unwrap();
+        assert_eq!(count, 100);
+        // A PC step, a register going down and a flag take far less than the text trace
+        assert!(bytes.len() < 100 * 8);
+
+        let read: Vec<Step> = TraceReader::new(&bytes[..]).unwrap().collect::<io::Result<_>>().unwrap();
+        assert_eq!(read, steps);
+        // A trace cut in the middle of a step is an error rather than a shorter trace
+        let errors = TraceReader::new(&bytes[..bytes.len() - 1]).unwrap().filter(Result::is_err).count();
+        assert_eq!(errors, 1);
+        assert!(TraceReader::new(&b"WCTX\x01"[..]).is_err());
+    }
+
+    #[test]
+    fn test_zigzag_varint() {
+        for delta in [0, 1, 0xFFFF, 0x7FFF, 0x8000, 0x40] {
+            let mut bytes = Vec::new();
+            write_varint(&mut bytes, zigzag(delta));
+            assert_eq!(unzigzag(read_varint(&mut &bytes[1..], bytes[0]).unwrap()), delta);
+        }
+        // Small steps backwards fit in a byte too
+        assert_eq!(zigzag(0xFFFE), 3);
+    }
+
+    #[test]
+    #[cfg(feature = "std")]
+    fn test_conversions() {
+        let step = Step {code: 0xEA, registers: [1, 2, 3, 4, 5, 6, 0xF000, 8, 9, 10, 11, 12, 0xFFF0], psw: 0xF002};
+        let steps = crate::tracediff::parse_trace(&step.to_text()).unwrap();
+        assert_eq!((steps[0].address, steps[0].registers, steps[0].psw), (0xFFFF0, step.registers, step.psw));
+        assert_eq!(steps[0].name, step.name());
+        assert!(step.to_json().starts_with(&format!("{{\"address\":1048560,\"code\":234,\"name\":\"{}\",\"AW\":1,", step.name())));
+        assert!(step.to_json().ends_with(",\"PC\":65520,\"PSW\":61442}"));
+    }
+}
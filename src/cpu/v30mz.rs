@@ -1,10 +1,15 @@
-use std::{cell::RefCell, collections::HashMap, rc::Rc};
+use alloc::{collections::{BTreeMap, VecDeque}, rc::Rc, vec::Vec};
+use core::cell::RefCell;
+#[cfg(feature = "std")]
+use std::io::Write;
 
 use bitflags::bitflags;
 
-use crate::bus::{io_bus::{IOBus, IOBusConnection}, mem_bus::{MemBus, MemBusConnection, Owner}};
+use crate::{bus::{io_bus::{IOBus, IOBusConnection}, mem_bus::{MemBus, MemBusConnection, Owner}}, state::{Snapshot, StateError, StateReader, StateWriter}};
 
-use super::{opcode::{OpCode, CPU_OP_CODES, GROUP_1, GROUP_2, IMMEDIATE_GROUP, SHIFT_GROUP}, swap_h, swap_l, MemOperand, Mode, Operand, RegisterType};
+use super::{opcode::{OpCode, CPU_OP_CODES, GROUP_1, GROUP_2, IMMEDIATE_GROUP, SHIFT_GROUP}, stats::OpcodeStats, swap_h, swap_l, MemOperand, Mode, Operand, RegisterType};
+#[cfg(feature = "std")]
+use super::trace::{Step, TraceWriter};
 
 /// Utility module for the CPU
 mod util;
@@ -62,6 +67,88 @@ bitflags! {
     }
 }
 
+/// What the CPU does when it decodes an instruction that does not exist
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum InvalidOpcodeBehavior {
+    /// Behave like the V30MZ, which executes undefined instructions as NOPs
+    Nop,
+    /// Stop the CPU at the invalid instruction and record a fault so that the front-end can pause
+    Fault,
+}
+
+/// An invalid instruction that the CPU has stopped at
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub struct InvalidOpcode {
+    /// Physical address of the instruction
+    pub address: u32,
+    /// First byte of the instruction
+    pub code: u8,
+    /// Bits 3-5 of the second byte, if the instruction belongs to a group
+    pub sub_code: Option<u8>,
+}
+
+/// Amount of executed instructions the CPU remembers for diagnostics
+pub const HISTORY_LEN: usize = 64;
+/// Cycles taken to acknowledge a hardware interrupt, push PSW, PS and PC and fetch the vector, before the handler's first instruction
+pub const INTERRUPT_CYCLES: u8 = 32;
+
+/// An executed instruction, as remembered for diagnostics
+#[derive(Clone, Copy, Debug)]
+pub struct HistoryEntry {
+    /// Physical address of the instruction
+    pub address: u32,
+    /// Bytes of the instruction, only the first `len` are valid
+    pub bytes: [u8; 8],
+    /// Length of the instruction, at most 8 bytes are kept
+    pub len: u8,
+    /// Registers before the instruction executed, in the order of [`V30MZ::REGISTER_NAMES`]
+    pub registers: [u16; 13],
+}
+
+impl HistoryEntry {
+    /// Returns the mnemonic of the instruction, or of its group for instructions that belong to one
+    pub fn name(&self) -> &'static str {
+        &CPU_OP_CODES[self.bytes[0] as usize].name
+    }
+}
+
+/// Address of an instruction's memory operand, remembered for watchpoint reports
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub struct OperandAddress {
+    /// Value of the segment register the offset was applied to, after any segment override
+    pub segment: u16,
+    /// Offset within the segment
+    pub offset: u16,
+    /// The mod/rm byte the offset was computed from, none for operands with implied addresses such as those of block instructions
+    pub mod_rm: Option<u8>,
+}
+
+impl core::fmt::Display for OperandAddress {
+    fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
+        write!(f, "{:04X}:{:04X}", self.segment, self.offset)?;
+        let Some(mod_rm) = self.mod_rm else {return Ok(())};
+        // Expressions selected by the lowest 3 bits, with the displacement selected by the highest 2
+        let base = ["BW+IX", "BW+IY", "BP+IX", "BP+IY", "IX", "IY", "BP", "BW"][(mod_rm & 7) as usize];
+        match mod_rm >> 6 {
+            0 if mod_rm & 7 == 6 => write!(f, " mod/rm={:02X} [disp16]", mod_rm),
+            0 => write!(f, " mod/rm={:02X} [{}]", mod_rm, base),
+            1 => write!(f, " mod/rm={:02X} [{}+disp8]", mod_rm, base),
+            _ => write!(f, " mod/rm={:02X} [{}+disp16]", mod_rm, base),
+        }
+    }
+}
+
+/// What the CPU was doing when it accessed memory, for watchpoint reports
+#[derive(Clone, Copy, Debug)]
+pub struct CpuAccess {
+    /// The last instruction the CPU started
+    pub instruction: Option<HistoryEntry>,
+    /// The instruction's memory operand containing the accessed address, none for accesses such as pushes and instruction fetches
+    pub operand: Option<OperandAddress>,
+    /// Vector of the interrupt or exception raised since the instruction started, whose pushes and vector reads belong to no instruction
+    pub exception: Option<u8>,
+}
+
 /// The WonderSwan's CPU
 /// 
 /// The NEC V30MZ processor used by the WonderSwan is a clone of the Intel 80186 CPU with some quirks preserved and some functionality removed
@@ -156,6 +243,10 @@ pub struct V30MZ {
     rep_z: bool,
     /// Indicates that certain situations have happened where interrupts cannot be processed
     no_interrupt: bool,
+    /// What to do when an invalid instruction is decoded
+    pub invalid_opcode: InvalidOpcodeBehavior,
+    /// The invalid instruction the CPU has stopped at, the CPU will not execute anything while this is set
+    pub fault: Option<InvalidOpcode>,
 
     // MEMORY
 
@@ -167,9 +258,11 @@ pub struct V30MZ {
     // MEMORY BUFFER
 
     /// Buffer to which memory writes are written before being committed to the shared bus
-    mem_buffer: HashMap<u32, u8>,
+    mem_buffer: BTreeMap<u32, u8>,
     /// Buffer to which I/O port writes are written before being committed to the shared bus
-    io_buffer: HashMap<u16, u8>,
+    /// 
+    /// Kept in order, as writing a port can have side effects that depend on the ports written before it.
+    io_buffer: Vec<(u16, u8)>,
 
     // TIMING
 
@@ -177,21 +270,48 @@ pub struct V30MZ {
     cycles: u8,
     /// The base amount to be added to cycles at the end of the current op, may be increased by extra cycles
     base: u8,
+    /// Cycles the current op has been stalled for by accessing WRAM while the display owns the bus
+    stall: u8,
+
+    /// The last executed instructions, oldest first
+    history: VecDeque<HistoryEntry>,
+    /// Addresses of the memory operands of the current instruction
+    operands: Vec<OperandAddress>,
+    /// The mod/rm byte of the memory operand being resolved, taken once its address is known
+    mod_rm: Option<u8>,
+    /// Vector of the interrupt or exception raised since the current instruction started
+    exception: Option<u8>,
+    /// Amount of hardware interrupts dispatched so far, used by the debugger to stop at the next one
+    pub interrupts: u64,
+    /// Vector of the last hardware interrupt dispatched
+    pub last_interrupt: Option<u8>,
+    /// Amount of instructions and prefixes executed so far, used to benchmark the emulator
+    pub instructions: u64,
 
     /// Enable trace
     /// 
     /// # WARNING
     /// 
     /// This will absolutely destroy framerates when enabled, only meant for debugging purposes
+    ///
+    /// The trace is printed on the standard output, so it is only available with the std feature.
     pub trace: bool,
+    /// Executions of each instruction, only counted when enabled in the options
+    pub opcode_stats: Option<OpcodeStats>,
+    /// Binary trace being recorded, see [`SoC::start_binary_trace`](crate::soc::SoC::start_binary_trace)
+    #[cfg(feature = "std")]
+    pub binary_trace: Option<TraceWriter<Box<dyn Write>>>,
 }
 
 impl MemBusConnection for V30MZ {
     fn read_mem(&mut self, addr: u32) -> u8 {
-        self.mem_bus.borrow_mut().read_mem(addr)
+        let mut mem_bus = self.mem_bus.borrow_mut();
+        if mem_bus.vram_stall(addr) {self.stall = self.stall.saturating_add(1)}
+        mem_bus.read_mem(addr)
     }
 
     fn write_mem(&mut self, addr: u32, byte: u8) {
+        if self.mem_bus.borrow().vram_stall(addr) {self.stall = self.stall.saturating_add(1)}
         self.mem_buffer.insert(addr, byte);
     }
 }
@@ -202,7 +322,12 @@ impl IOBusConnection for V30MZ {
     }
 
     fn write_io(&mut self, addr: u16, byte: u8) {
-        self.io_buffer.insert(addr, byte);
+        self.io_buffer.push((addr, byte));
+    }
+
+    fn write_io_16(&mut self, addr: u16, word: u16) {
+        let writes = self.io_bus.borrow().split_word(addr, word);
+        self.io_buffer.extend(writes);
     }
 }
 
@@ -222,22 +347,66 @@ impl V30MZ {
             segment_override: None,
             halt: false, rep: false, rep_z: false,
             no_interrupt: false,
+            invalid_opcode: InvalidOpcodeBehavior::Nop, fault: None,
 
             mem_bus, io_bus,
-            mem_buffer: HashMap::new(),
-            io_buffer: HashMap::new(),
-
-            cycles: 0, base: 0,
+            mem_buffer: BTreeMap::new(),
+            io_buffer: Vec::new(),
+
+            cycles: 0, base: 0, stall: 0,
+            history: VecDeque::with_capacity(HISTORY_LEN),
+            operands: Vec::new(), mod_rm: None, exception: None,
+            interrupts: 0,
+            last_interrupt: None,
+            instructions: 0,
             trace,
+            opcode_stats: None,
+            #[cfg(feature = "std")]
+            binary_trace: None,
         }
     }
 
+    /// Names of the registers returned by [`V30MZ::registers`]
+    pub const REGISTER_NAMES: [&'static str; 13] = ["AW", "BW", "CW", "DW", "DS0", "DS1", "PS", "SS", "IX", "IY", "SP", "BP", "PC"];
+
+    /// Returns the values of the registers, see [`V30MZ::REGISTER_NAMES`] for their order
+    pub fn registers(&self) -> [u16; 13] {
+        [self.AW, self.BW, self.CW, self.DW, self.DS0, self.DS1, self.PS, self.SS, self.IX, self.IY, self.SP, self.BP, self.PC]
+    }
+
+    /// Returns the program status word
+    pub fn psw(&self) -> u16 {
+        self.PSW.bits()
+    }
+
+    /// Whether the CPU is halted, waiting for an interrupt request
+    pub fn halted(&self) -> bool {
+        self.halt
+    }
+
+    /// Returns the last executed instructions, oldest first
+    pub fn history(&self) -> impl Iterator<Item = &HistoryEntry> {
+        self.history.iter()
+    }
+
+    /// Describes what the CPU was doing when it accessed the address during the current tick
+    ///
+    /// Writes are committed once the instruction's cycles have elapsed, which is still before the next instruction starts.
+    pub fn access(&self, addr: u32) -> CpuAccess {
+        // Operands are at most 4 bytes long, for far pointers
+        let operand = self.operands.iter().rev()
+            .find(|operand| (0..4).any(|byte| self.apply_segment(operand.offset.wrapping_add(byte), operand.segment) == addr))
+            .copied();
+        CpuAccess {instruction: self.history.back().copied(), operand, exception: self.exception}
+    }
+
     /// Ticks the CPU
     /// 
     /// When the `cycles` field reaches 0 it can potentially execute an instruction or poll interrupts.
     /// Otherwise it decreases the `cycles` field, if this sets `cycles` to 0 it commits the writes scheduled by the previous instruction.
     pub fn tick(&mut self) {
         // println!("Tick: halt={}, cycles={}", self.halt, self.cycles);
+        if self.fault.is_some() {return}
         self.PSW = self.PSW.union(CpuStatus::from_bits_truncate(0xF002));
         self.PSW.remove(CpuStatus::FIXED_OFF_1);
         self.PSW.remove(CpuStatus::FIXED_OFF_2);
@@ -254,19 +423,29 @@ impl V30MZ {
     /// 
     /// If trace is enabled this will also print the currently executing instruction's first byte, address and mnemonic, along with the state of the CPU's registers
     /// 
-    /// # Panics
-    /// 
-    /// This will panic when running the opcode 0xFF,0xFF this is because it is an undocumented instruction rarely seen in actual software,
-    /// many ROMs also use 0xFF bytes for padding, so crashing when reaching this value is a way to ensure the program stops execution when
-    /// something has gone wrong during execution
+    /// Invalid instructions, such as 0xF1 or 0xFF,0xFF, are handled according to the `invalid_opcode` field.
+    /// Many ROMs use 0xFF bytes for padding, so running into them usually means something has gone wrong during execution.
     /// 
     /// # TODO
     /// 
     /// Implement undocumented instructions
     pub fn execute(&mut self) {
+        self.stall = 0;
+        self.operands.clear();
+        (self.mod_rm, self.exception) = (None, None);
         let op = self.allocate_instruction().clone();
         self.no_interrupt = false;
 
+        let mut entry = HistoryEntry {address: self.get_pc_address(), bytes: [0; 8], len: self.current_op.len().min(8) as u8, registers: self.registers()};
+        entry.bytes[..entry.len as usize].copy_from_slice(&self.current_op[..entry.len as usize]);
+        if self.history.len() == HISTORY_LEN {self.history.pop_front();}
+        self.history.push_back(entry);
+        self.instructions += 1;
+        if let Some(stats) = &mut self.opcode_stats {stats.record(op.code, self.current_op.get(1).copied().unwrap_or(0))}
+        #[cfg(feature = "std")]
+        if let Some(trace) = &mut self.binary_trace {trace.record(Step {code: op.code, registers: entry.registers, psw: self.PSW.bits()})}
+
+        #[cfg(feature = "std")]
         if self.trace {
             println!("{:05X} {:02X} {}", self.get_pc_address(), op.code, op.name);
             println!("IY {:04X} IX {:04X} BP {:04X} SP {:04X}", self.IY, self.IX, self.BP, self.SP);
@@ -656,7 +835,7 @@ impl V30MZ {
                     4 => self.branch_op(op.op1, Mode::M16, sub_op.extra),
                     5 => self.branch_op(op.op1, Mode::M32, sub_op.extra),
                     6 => self.push_op(Operand::MEMORY, sub_op.extra),
-                    7 => panic!("INVALID {:05X} {:02X} {}", self.get_pc_address(), op.code, op.name),
+                    7 => self.invalid(op.code, Some(sub_op.code)),
                     _ => unreachable!()
                 }
             }
@@ -664,7 +843,7 @@ impl V30MZ {
             // NOP
             0x0F | 0x63..=0x67 => {}
                 
-            code => println!("Not yet implemented! Code: {:02X}", code),
+            code => self.invalid(code, None),
         };
 
         // if self.PSW.contains(CpuStatus::BREAK) {println!("BREAK set!")}
@@ -719,6 +898,7 @@ impl V30MZ {
 
         self.current_op.clear();
         self.pc_displacement = 0;
+        self.cycles = self.cycles.saturating_add(self.stall);
         self.cycles -= 1;
         if self.cycles == 0 {
             self.commit_writes();
@@ -733,6 +913,7 @@ impl V30MZ {
         self.PC = self.PC.wrapping_add(1);
         self.current_op.clear();
         self.pc_displacement = 0;
+        self.cycles = self.cycles.saturating_add(self.stall);
         self.cycles -= 1;
         if self.cycles == 0 {
             self.commit_writes();
@@ -740,11 +921,24 @@ impl V30MZ {
         self.no_interrupt = true;
     }
 
+    /// Called when an invalid instruction is decoded
+    /// 
+    /// Depending on the `invalid_opcode` field this will either do nothing, treating the instruction as a NOP,
+    /// or record a fault and leave the program counter pointing at the instruction.
+    fn invalid(&mut self, code: u8, sub_code: Option<u8>) {
+        if self.invalid_opcode == InvalidOpcodeBehavior::Fault {
+            self.fault = Some(InvalidOpcode {address: self.get_pc_address(), code, sub_code});
+            self.pc_displacement = 0;
+        }
+    }
+
     /// Raises exception with the given vector
     /// 
     /// This will read two words from memory at the address given by the vector * 4 and assign the first two `PC` and the second to `PS`
     fn raise_exception(&mut self, vector: u8) {
+        self.exception = Some(vector);
         self.PC = self.PC.wrapping_add(self.pc_displacement);
+        #[cfg(feature = "std")]
         if self.trace {println!("Exception raised: vector={:02X}. Pushing PSW={:016b} PS={:04X}, PC={:04X}", vector, self.PSW.bits(), self.PS, self.PC)}
         self.pc_displacement = 0;
 
@@ -757,31 +951,51 @@ impl V30MZ {
         let vec_addr = (vector as u32) * 4;
 
         (self.PC, self.PS) = self.read_mem_32(vec_addr);
+        #[cfg(feature = "std")]
         if self.trace {println!("New values: PSW={:016b} PS={:04X}, PC={:04X}", self.PSW.bits(), self.PS, self.PC)}
     }
 
     /// Polls the I/O bus to see if other components have requested interrupts
+    /// 
+    /// The NMI, raised by the battery running low, goes through vector 2 regardless of the interrupt flag and is taken before any other interrupt.
     fn poll_interrupts(&mut self) -> bool {
-        let nmi = self.read_io(0xB7) != 0;
+        let nmi = self.io_bus.borrow().nmi_pending();
         let cause = self.read_io(0xB4);
         // if cause != 0 {println!("Polling interrupts: NMI={}, cause={:02X}", nmi, cause)}
 
         if (cause != 0 || nmi) && self.mem_bus.borrow().owner != Owner::CPU {
             // if self.halt {println!("Returning from halt!")}
             self.halt = false;
-            if self.PSW.contains(CpuStatus::INTERRUPT) || nmi {
+            if nmi {
+                self.io_bus.borrow_mut().acknowledge_nmi();
+                self.acknowledge_interrupt(2);
+                return true;
+            }
+            if self.PSW.contains(CpuStatus::INTERRUPT) {
                 let source = cause.trailing_zeros() as u8;
                 if source == 1 {self.trace = true}
                 // if source == 0x01 {println!("KEY interrupt")}
                 let vector = (self.read_io(0xB0) & 0xF8).wrapping_add(source);
                 // println!("Interrupt triggered: vector={:02X}", vector);
-                self.raise_exception(vector);
+                self.acknowledge_interrupt(vector);
                 return true;
             }
         }
         false
     }
 
+    /// Enters the handler of a hardware interrupt
+    ///
+    /// Interrupts are only taken between instructions. The exception sequence then keeps the CPU busy for [`INTERRUPT_CYCLES`],
+    /// counting the cycle it is acknowledged on, and the pushed registers reach memory at its end like an instruction's writes.
+    fn acknowledge_interrupt(&mut self, vector: u8) {
+        self.raise_exception(vector);
+        self.interrupts += 1;
+        self.last_interrupt = Some(vector);
+        self.base = INTERRUPT_CYCLES;
+        self.cycles = INTERRUPT_CYCLES - 1;
+    }
+
     /// Commits writes at the end of an instruction
     fn commit_writes(&mut self) {
         for (addr, byte) in &self.mem_buffer {
@@ -801,4 +1015,203 @@ impl V30MZ {
         if !self.halt {self.execute()};
         self.commit_writes();
     }
-}
\ No newline at end of file
+
+    #[doc(hidden)]
+    #[cfg(test)]
+    pub fn set_psw(&mut self, psw: u16) {
+        self.PSW = CpuStatus::from_bits_retain(psw);
+    }
+}
+
+/// The CPU's state contains its registers, the prefixes in effect and the writes of the current instruction that are yet to be committed
+///
+/// The history and operands are only kept for diagnostics and are not part of it.
+impl Snapshot for V30MZ {
+    const TAG: [u8; 4] = *b"V30M";
+    const VERSION: u16 = 1;
+
+    fn save_fields(&self, w: &mut StateWriter) {
+        for register in self.registers() {w.write_u16(register)}
+        w.write_u16(self.PSW.bits());
+        w.write_bool(self.segment_override.is_some());
+        w.write_u16(self.segment_override.unwrap_or(0));
+        for flag in [self.halt, self.rep, self.rep_z, self.no_interrupt] {w.write_bool(flag)}
+        w.write_bytes(&[self.cycles, self.base, self.stall]);
+
+        // Kept sorted by address, so that the same state always produces the same bytes
+        w.write_u32(self.mem_buffer.len() as u32);
+        for (addr, byte) in &self.mem_buffer {
+            w.write_u32(*addr);
+            w.write_u8(*byte);
+        }
+        w.write_u32(self.io_buffer.len() as u32);
+        for (addr, byte) in &self.io_buffer {
+            w.write_u16(*addr);
+            w.write_u8(*byte);
+        }
+
+        w.write_bool(self.fault.is_some());
+        if let Some(fault) = self.fault {
+            w.write_u32(fault.address);
+            w.write_u8(fault.code);
+            w.write_bool(fault.sub_code.is_some());
+            w.write_u8(fault.sub_code.unwrap_or(0));
+        }
+        w.write_u64(self.interrupts);
+        w.write_bool(self.last_interrupt.is_some());
+        w.write_u8(self.last_interrupt.unwrap_or(0));
+    }
+
+    fn load_fields(&mut self, r: &mut StateReader, _version: u16) -> Result<(), StateError> {
+        let mut registers = [0; 13];
+        for register in &mut registers {*register = r.read_u16()?}
+        let psw = r.read_u16()?;
+        let segment_override = (r.read_bool()?, r.read_u16()?);
+        let flags = [r.read_bool()?, r.read_bool()?, r.read_bool()?, r.read_bool()?];
+        let [cycles, base, stall] = r.read_array::<3>()?;
+
+        let mut mem_buffer = BTreeMap::new();
+        for _ in 0..r.read_u32()? {
+            mem_buffer.insert(r.read_u32()?, r.read_u8()?);
+        }
+        let mut io_buffer = Vec::new();
+        for _ in 0..r.read_u32()? {
+            io_buffer.push((r.read_u16()?, r.read_u8()?));
+        }
+
+        let fault = if r.read_bool()? {
+            let (address, code) = (r.read_u32()?, r.read_u8()?);
+            let sub_code = (r.read_bool()?, r.read_u8()?);
+            Some(InvalidOpcode {address, code, sub_code: sub_code.0.then_some(sub_code.1)})
+        } else {None};
+        let interrupts = r.read_u64()?;
+        let last_interrupt = (r.read_bool()?, r.read_u8()?);
+
+        [self.AW, self.BW, self.CW, self.DW, self.DS0, self.DS1, self.PS, self.SS, self.IX, self.IY, self.SP, self.BP, self.PC] = registers;
+        self.PSW = CpuStatus::from_bits_truncate(psw);
+        self.segment_override = segment_override.0.then_some(segment_override.1);
+        [self.halt, self.rep, self.rep_z, self.no_interrupt] = flags;
+        (self.cycles, self.base, self.stall) = (cycles, base, stall);
+        (self.mem_buffer, self.io_buffer) = (mem_buffer, io_buffer);
+        (self.fault, self.interrupts) = (fault, interrupts);
+        self.last_interrupt = last_interrupt.0.then_some(last_interrupt.1);
+        self.current_op.clear();
+        self.pc_displacement = 0;
+        self.history.clear();
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+#[cfg_attr(coverage_nightly, coverage(off))]
+mod test {
+    use alloc::vec;
+
+    use crate::soc::SoC;
+    use crate::assert_eq_hex;
+
+    use super::*;
+
+    #[test]
+    fn test_invalid_opcode_nop() {
+        let mut soc = SoC::test_build();
+        soc.set_wram(vec![
+            0xF1,       // INV
+            0xFF, 0xFF, // INV
+            0x40,       // INC AW
+        ]);
+        soc.get_cpu().AW = 0;
+
+        soc.tick_cpu_no_cycles();
+        assert_eq_hex!(soc.get_cpu().PC, 0x0001);
+        soc.tick_cpu_no_cycles();
+        assert_eq_hex!(soc.get_cpu().PC, 0x0003);
+        soc.tick_cpu_no_cycles();
+        assert_eq_hex!(soc.get_cpu().AW, 0x0001);
+        assert!(soc.get_cpu().fault.is_none());
+    }
+
+    #[test]
+    fn test_invalid_opcode_fault() {
+        let mut soc = SoC::test_build();
+        soc.set_wram(vec![
+            0x90,       // NOP
+            0xFF, 0xFF, // INV
+            0x40,       // INC AW
+        ]);
+        soc.get_cpu().AW = 0;
+        soc.get_cpu().invalid_opcode = InvalidOpcodeBehavior::Fault;
+
+        soc.tick_cpu_no_cycles();
+        soc.tick_cpu_no_cycles();
+        assert_eq_hex!(soc.get_cpu().PC, 0x0001);
+        assert_eq!(soc.get_cpu().fault, Some(InvalidOpcode {address: 0x00001, code: 0xFF, sub_code: Some(7)}));
+
+        for _ in 0..8 {soc.tick();}
+        assert_eq_hex!(soc.get_cpu().PC, 0x0001);
+        assert_eq_hex!(soc.get_cpu().AW, 0x0000);
+    }
+
+    #[test]
+    fn test_vram_stall_cycles() {
+        let mut soc = SoC::test_build();
+        soc.set_wram(vec![
+            0x88, 0x06, 0xFE, 0x00, // [0x00FE] <- AL
+            0x88, 0x06, 0xFE, 0x00, // [0x00FE] <- AL
+        ]);
+
+        soc.get_cpu().tick();
+        let unstalled = soc.get_cpu().cycles;
+        while soc.get_cpu().cycles > 0 {soc.get_cpu().tick()}
+
+        soc.get_wram().borrow_mut().vram_stalls = true;
+        soc.get_wram().borrow_mut().set_display_fetch(true);
+        soc.get_cpu().tick();
+
+        // Four bytes fetched and one byte written
+        assert_eq!(soc.get_cpu().cycles, unstalled + 5);
+    }
+
+    #[test]
+    fn test_interrupt_latency() {
+        let mut soc = SoC::test_build();
+        let mut program = vec![
+            0xFB,       // EI
+            0xEB, 0x7E, // BR 0x0081
+        ];
+        program.resize(0x3C, 0x01);
+        program.extend([0x00, 0x30, 0x00, 0x00]); // Vector 0x0F at 0000:3000
+        program.resize(0x81, 0x01);
+        for _ in 0..2000 {program.extend([0xBC, 0x00, 0x20])} // MOV SP, 0x2000, one cycle each
+        program.resize(0x3000, 0x01);
+        program.push(0xF4); // HLT
+        soc.set_wram(program);
+
+        // The HBLANK timer expires at the end of the second line, and every other line after that
+        soc.io_bus.borrow_mut().write_io(0xB0, 0x08);
+        soc.io_bus.borrow_mut().write_io_16(0xA4, 2);
+        soc.io_bus.borrow_mut().write_io(0xA2, 0x03);
+        soc.io_bus.borrow_mut().write_io(0xB2, 0x80);
+
+        let mut expired = None;
+        let mut entered = None;
+        for tick in 0..2000u64 {
+            soc.tick();
+            // Reading INT_CAUSE would acknowledge the interrupt, so the expiry is found from LCD_LINE instead
+            if expired.is_none() && soc.io_bus.borrow_mut().read_io(0x02) == 2 {expired = Some(tick)}
+            if let (Some(expired), None) = (expired, entered) {
+                // The registers are pushed once the exception sequence is over
+                if tick == expired + INTERRUPT_CYCLES as u64 - 1 {assert_eq!(soc.read_mem(0x1FFA), 0x01)}
+                if soc.get_cpu().history().last().is_some_and(|entry| entry.address == 0x03000) {entered = Some(tick)}
+            }
+        }
+        let (expired, entered) = (expired.unwrap(), entered.unwrap());
+        assert_eq!(soc.get_cpu().last_interrupt, Some(0x0F));
+
+        // Acknowledged after the running one cycle instruction, then the exception sequence
+        assert_eq!(entered - expired, 1 + INTERRUPT_CYCLES as u64);
+        let (pc, ps) = (u16::from_le_bytes([soc.read_mem(0x1FFA), soc.read_mem(0x1FFB)]), u16::from_le_bytes([soc.read_mem(0x1FFC), soc.read_mem(0x1FFD)]));
+        assert_eq!(ps, 0x0000);
+        assert!(pc > 0x0081 && (pc - 0x0081) % 3 == 0);
+    }
+}
@@ -728,6 +728,8 @@ impl V30MZ {
 #[cfg(test)]
 #[cfg_attr(coverage_nightly, coverage(off))]
 mod test {
+    use alloc::vec;
+
     use crate::soc::SoC;
     use crate::assert_eq_hex;
 
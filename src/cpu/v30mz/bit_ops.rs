@@ -454,6 +454,8 @@ impl V30MZ {
 
 #[cfg(test)]
 mod test {
+    use alloc::vec;
+
     use crate::cpu::v30mz::CpuStatus;
     use crate::soc::SoC;
     use crate::assert_eq_hex;
@@ -9,8 +9,9 @@ impl V30MZ {
     /// 
     /// Intel name: CMPS
     pub fn cmpbk(&mut self, mode: Mode, cycles: u8, rep_cycles: u8) {
+        if self.skip_empty_rep(cycles) {return}
         let addr_x = self.get_physical_address(self.IX, self.DS0);
-        let addr_y = self.apply_segment(self.IY, self.DS1);
+        let addr_y = self.get_block_destination();
         match mode {
             Mode::M8 => {
                 let x = self.read_mem(addr_x);
@@ -29,12 +30,10 @@ impl V30MZ {
         self.IX = self.update_block_index(mode, self.IX);
         self.IY = self.update_block_index(mode, self.IY);
 
-        if self.rep {
+        // The iteration that ends the repetition still costs as much as the others
+        self.base = if self.rep {
             self.CW -= 1;
             self.rep = self.PSW.contains(CpuStatus::ZERO) == self.rep_z;
-        }
-
-        self.base = if self.rep {
             if self.rep_z {
                 rep_cycles + 1
             } else {
@@ -52,7 +51,8 @@ impl V30MZ {
     /// 
     /// Intel name: SCAS
     pub fn cmpm(&mut self, mode: Mode, cycles: u8, rep_cycles: u8) {
-        let addr = self.apply_segment(self.IY, self.DS1);
+        if self.skip_empty_rep(cycles) {return}
+        let addr = self.get_block_destination();
         match mode {
             Mode::M8 => {
                 let a = self.AW as u8;
@@ -69,12 +69,12 @@ impl V30MZ {
         }
         self.IY = self.update_block_index(mode, self.IY);
 
-        if self.rep {
+        // The iteration that ends the repetition still costs as much as the others
+        self.base = if self.rep {
             self.CW -= 1;
             self.rep = self.PSW.contains(CpuStatus::ZERO) == self.rep_z;
-        }
-
-        self.base = if self.rep {rep_cycles} else {cycles};
+            rep_cycles
+        } else {cycles};
         self.cycles = self.base;
     }
 
@@ -84,10 +84,11 @@ impl V30MZ {
     /// 
     /// Intel name: INS
     pub fn inm(&mut self, mode: Mode, cycles: u8, rep_cycles: u8) {
-        let addr = self.apply_segment(self.IY, self.DS1);
+        if self.skip_empty_rep(cycles) {return}
+        let addr = self.get_block_destination();
         match mode {
             Mode::M8 => {
-                let byte = self.read_io(self.DW as u8 as u16);
+                let byte = self.read_io(self.DW);
                 self.write_mem(addr, byte);
             }
             Mode::M16 => {
@@ -112,6 +113,7 @@ impl V30MZ {
     /// 
     /// Intel name: LODS
     pub fn ldm(&mut self, mode: Mode, cycles: u8, rep_cycles: u8) {
+        if self.skip_empty_rep(cycles) {return}
         let addr = self.get_physical_address(self.IX, self.DS0);
         match mode {
             Mode::M8 => {
@@ -136,8 +138,9 @@ impl V30MZ {
     /// 
     /// Intel name: MOVS
     pub fn movbk(&mut self, mode: Mode, cycles: u8, rep_cycles: u8) {
+        if self.skip_empty_rep(cycles) {return}
         let addr_x = self.get_physical_address(self.IX, self.DS0);
-        let addr_y = self.apply_segment(self.IY, self.DS1);
+        let addr_y = self.get_block_destination();
         match mode {
             Mode::M8 => {
                 let byte = self.read_mem(addr_x);
@@ -165,11 +168,12 @@ impl V30MZ {
     /// 
     /// Intel name: OUTS
     pub fn outm(&mut self, mode: Mode, cycles: u8, rep_cycles: u8) {
+        if self.skip_empty_rep(cycles) {return}
         let addr = self.get_physical_address(self.IX, self.DS0);
         match mode {
             Mode::M8 => {
                 let byte = self.read_mem(addr);
-                self.write_io(self.DW as u8 as u16, byte);
+                self.write_io(self.DW, byte);
             }
             Mode::M16 => {
                 let word = self.read_mem_16(addr);
@@ -192,7 +196,8 @@ impl V30MZ {
     /// 
     /// Intel name: STOS
     pub fn stm(&mut self, mode: Mode, cycles: u8, rep_cycles: u8) {
-        let addr = self.apply_segment(self.IY, self.DS1);
+        if self.skip_empty_rep(cycles) {return}
+        let addr = self.get_block_destination();
         match mode {
             Mode::M8 => self.write_mem(addr, self.AW as u8),
             Mode::M16 => self.write_mem_16(addr, self.AW),
@@ -207,6 +212,18 @@ impl V30MZ {
         self.cycles = self.base;
     }
 
+    /// Checks CW before a block operation under REP or REPNE
+    /// 
+    /// When CW is already 0 not even a single iteration is executed, the prefix is dropped and the instruction only costs its base cycles.
+    /// Returns whether the instruction should be skipped.
+    fn skip_empty_rep(&mut self, cycles: u8) -> bool {
+        if !self.rep || self.CW != 0 {return false}
+        self.rep = false;
+        self.base = cycles;
+        self.cycles = self.base;
+        true
+    }
+
     /// Updates the provided in the index parameter based on the mode parameter and the direction flag
     fn update_block_index(&mut self, mode: Mode, index: u16) -> u16 {
         match (self.PSW.contains(CpuStatus::DIRECTION), mode) {
@@ -217,4 +234,128 @@ impl V30MZ {
             _ => unreachable!()
         }
     }
-}
\ No newline at end of file
+}
+
+#[cfg(test)]
+#[cfg_attr(coverage_nightly, coverage(off))]
+mod test {
+    use alloc::vec;
+
+    use crate::soc::SoC;
+    use crate::bus::io_bus::{IOBusConnection, Model};
+    use crate::assert_eq_hex;
+
+    use super::*;
+
+    /// Opcodes of every block operation
+    const BLOCK_OPS: [u8; 14] = [0x6C, 0x6D, 0x6E, 0x6F, 0xA4, 0xA5, 0xA6, 0xA7, 0xAA, 0xAB, 0xAC, 0xAD, 0xAE, 0xAF];
+
+    #[test]
+    fn test_rep_cw_0() {
+        for op in BLOCK_OPS {
+            for prefix in [0xF2, 0xF3] {
+                let mut soc = SoC::test_build();
+                soc.set_wram(vec![prefix, op, 0x90]);
+                soc.get_cpu().IX = 0x1000;
+                soc.get_cpu().IY = 0x2000;
+                soc.get_cpu().AW = 0x1234;
+
+                soc.tick_cpu_no_cycles();
+                soc.tick_cpu_no_cycles();
+                assert_eq_hex!(soc.get_cpu().CW, 0x0000);
+                assert_eq_hex!(soc.get_cpu().IX, 0x1000);
+                assert_eq_hex!(soc.get_cpu().IY, 0x2000);
+                assert_eq_hex!(soc.get_cpu().AW, 0x1234);
+                assert_eq_hex!(soc.read_mem(0x2000), 0x01);
+                assert_eq_hex!(soc.get_cpu().PC, 0x0002);
+                assert!(!soc.get_cpu().rep);
+            }
+        }
+    }
+
+    #[test]
+    fn test_rep_cw_1() {
+        for op in BLOCK_OPS {
+            let mut soc = SoC::test_build();
+            soc.set_wram(vec![0xF3, op, 0x90]);
+            soc.get_cpu().CW = 1;
+            soc.get_cpu().IX = 0x1000;
+            soc.get_cpu().IY = 0x2000;
+
+            soc.tick_cpu_no_cycles();
+            soc.tick_cpu_no_cycles();
+            assert_eq_hex!(soc.get_cpu().CW, 0x0000);
+            assert_eq_hex!(soc.get_cpu().PC, 0x0002);
+            assert!(soc.get_cpu().IX != 0x1000 || soc.get_cpu().IY != 0x2000);
+        }
+    }
+
+    #[test]
+    fn test_cmpbk_rep_termination_cycles() {
+        let mut soc = SoC::test_build();
+        soc.set_wram(vec![0xF3, 0xA6, 0x90]);
+        soc.get_cpu().CW = 3;
+        soc.get_cpu().IX = 0x1000;
+        soc.get_cpu().IY = 0x2000;
+        soc.get_wram().borrow_mut()[0x1000] = 0x02;
+
+        // REP stops on the first mismatch, which is still charged as a repeated iteration
+        soc.tick_cpu_no_cycles();
+        soc.tick_cpu_no_cycles();
+        assert_eq_hex!(soc.get_cpu().CW, 0x0002);
+        assert_eq_hex!(soc.get_cpu().PC, 0x0002);
+        assert_eq!(soc.get_cpu().base, 10);
+    }
+
+    #[test]
+    fn test_cmpm_repne_termination_cycles() {
+        let mut soc = SoC::test_build();
+        soc.set_wram(vec![0xF2, 0xAE, 0x90]);
+        soc.get_cpu().CW = 3;
+        soc.get_cpu().IY = 0x2000;
+        soc.get_cpu().AW = 0x0001;
+
+        // REPNE stops on the first match
+        soc.tick_cpu_no_cycles();
+        soc.tick_cpu_no_cycles();
+        assert_eq_hex!(soc.get_cpu().CW, 0x0002);
+        assert_eq_hex!(soc.get_cpu().PC, 0x0002);
+        assert_eq!(soc.get_cpu().base, 9);
+    }
+
+    #[test]
+    fn test_byte_io_above_port_ff() {
+        for model in [Model::MONO, Model::COLOR] {
+            // Ports 0x100-0x1FF are open bus and 0x200-0x2FF mirror the first 256, except for those past 0xB8 on the color model
+            let mut soc = SoC::test_build();
+            soc.set_model(model);
+            soc.write_io(0xC0, 0x0A);
+            let mirrored = if model == Model::COLOR {0x90} else {soc.read_io(0xC0)};
+            for (port, expected) in [(0x1C0, 0x90), (0x2C0, mirrored)] {
+                soc.set_wram(vec![0xF3, 0x6C, 0x90]);
+                soc.get_cpu().PC = 0;
+                soc.get_cpu().CW = 2;
+                soc.get_cpu().DW = port;
+                soc.get_cpu().IY = 0x2000;
+                for _ in 0..3 {soc.tick_cpu_no_cycles();}
+                assert_eq_hex!(soc.get_cpu().CW, 0x0000);
+                assert_eq_hex!(soc.read_mem(0x2000), expected);
+                assert_eq_hex!(soc.read_mem(0x2001), expected);
+            }
+
+            for (port, expected) in [(0x104, 0x00), (0x204, 0x05)] {
+                let mut soc = SoC::test_build();
+                soc.set_model(model);
+                soc.set_wram(vec![0xF3, 0x6E, 0x90]);
+                soc.get_wram().borrow_mut()[0x1000] = 0x05;
+                soc.get_wram().borrow_mut()[0x1001] = 0x05;
+                soc.get_cpu().CW = 2;
+                soc.get_cpu().DW = port;
+                soc.get_cpu().IX = 0x1000;
+                for _ in 0..3 {soc.tick_cpu_no_cycles();}
+                assert_eq_hex!(soc.get_cpu().CW, 0x0000);
+                assert_eq_hex!(soc.read_io(0x04), expected);
+            }
+        }
+    }
+}
@@ -121,6 +121,8 @@ impl V30MZ {
     /// - BP <- temp
     /// - SP -= imm16
     /// 
+    /// Every access, including reading the outer frames' pointers, uses SS regardless of segment override prefixes.
+    /// 
     /// Intel name: ENTER
     pub fn prepare(&mut self) {
         let imm16 = u16::from_le_bytes(self.current_op[1..=2].try_into().unwrap());
@@ -130,7 +132,8 @@ impl V30MZ {
         if imm5 > 0 {
             for _ in 0..(imm5 - 1) {
                 self.BP = self.BP.wrapping_sub(2);
-                let addr = self.get_physical_address(self.BP, self.SS);
+                // The display is always on the stack, so segment overrides do not apply
+                let addr = self.apply_segment(self.BP, self.SS);
                 let word = self.read_mem_16(addr);
                 self.push(word);
             }
@@ -143,6 +146,8 @@ impl V30MZ {
     /// RETN instruction
     /// 
     /// Pops the `PC` from the stack and adds the operand to `SP`
+    /// 
+    /// The operand is added once the return address has been popped, odd values included, and `SP` wraps around within the stack segment.
     pub fn retn(&mut self, op: Operand) {
         // println!("RETN before PC: {:04X} PS: {:04X} SP: {:04X}", self.PC, self.PS, self.SP);
         let temp_pc = self.pop();
@@ -159,7 +164,7 @@ impl V30MZ {
 
     /// RETF instruction
     /// 
-    /// Pops the `PC` and `PS` from the stack and adds the operand to `SP`
+    /// Pops the `PC` and `PS` from the stack and adds the operand to `SP`, in the same way as RETN
     pub fn retf(&mut self, op: Operand) {
         // println!("RETF before PC: {:04X} PS: {:04X} SP: {:04X}", self.PC, self.PS, self.SP);
         let temp_pc = self.pop();
@@ -189,4 +194,342 @@ impl V30MZ {
         self.pc_displacement = 0;
         // println!("RETI after PC: {:04X} PS: {:04X}", self.PC, self.PS);
     }
-}
\ No newline at end of file
+}
+#[cfg(test)]
+#[cfg_attr(coverage_nightly, coverage(off))]
+mod test {
+    use alloc::{vec, vec::Vec};
+
+    use crate::soc::SoC;
+    use crate::bus::io_bus::{IOBusConnection, Model};
+    use crate::assert_eq_hex;
+
+    /// Stack segment at 0x2000, data segment at 0x1000 and SP at 0x0800, so that SS and DS0 relative accesses can be told apart
+    fn stack_soc(program: Vec<u8>) -> SoC {
+        let mut soc = SoC::test_build();
+        soc.set_wram(program);
+        let cpu = soc.get_cpu();
+        cpu.SS = 0x0200;
+        cpu.DS0 = 0x0100;
+        cpu.SP = 0x0800;
+        soc
+    }
+
+    fn write_word(soc: &mut SoC, addr: usize, word: u16) {
+        let [lo, hi] = word.to_le_bytes();
+        soc.get_wram().borrow_mut()[addr] = lo;
+        soc.get_wram().borrow_mut()[addr + 1] = hi;
+    }
+
+    fn write_bytes(soc: &mut SoC, addr: usize, bytes: &[u8]) {
+        for (i, byte) in bytes.iter().enumerate() {
+            soc.get_wram().borrow_mut()[addr + i] = *byte;
+        }
+    }
+
+    fn read_word(soc: &mut SoC, addr: usize) -> u16 {
+        let wram = soc.get_wram();
+        let wram = wram.borrow();
+        u16::from_le_bytes([wram[addr], wram[addr + 1]])
+    }
+
+    #[test]
+    fn test_bp_default_segment() {
+        let mut soc = stack_soc(vec![
+            0x8B, 0x46, 0xFE,       // MOV AW, [BP-2]
+            0x3E, 0x8B, 0x4E, 0xFE, // MOV CW, DS0:[BP-2]
+            0x8B, 0x12,             // MOV DW, [BP+IX]
+            0x8B, 0x36, 0x00, 0x01, // MOV IX, [0x0100]
+            0x36, 0x8B, 0x3F,       // MOV IY, SS:[BW]
+            0x8B, 0x1F,             // MOV BW, [BW]
+        ]);
+        soc.get_cpu().BP = 0x0100;
+        soc.get_cpu().IX = 0x0010;
+        soc.get_cpu().BW = 0x0200;
+        write_word(&mut soc, 0x20FE, 0x1111);
+        write_word(&mut soc, 0x10FE, 0x2222);
+        write_word(&mut soc, 0x2110, 0x3333);
+        write_word(&mut soc, 0x1110, 0x4444);
+        write_word(&mut soc, 0x1100, 0x5555);
+        write_word(&mut soc, 0x2100, 0x6666);
+        write_word(&mut soc, 0x2200, 0x7777);
+        write_word(&mut soc, 0x1200, 0x8888);
+
+        for _ in 0..8 {
+            soc.tick_cpu_no_cycles();
+        }
+
+        // BP based operands default to SS, except for direct offsets which only share BP's encoding
+        assert_eq_hex!(soc.get_cpu().AW, 0x1111);
+        assert_eq_hex!(soc.get_cpu().CW, 0x2222);
+        assert_eq_hex!(soc.get_cpu().DW, 0x3333);
+        assert_eq_hex!(soc.get_cpu().IX, 0x5555);
+        assert_eq_hex!(soc.get_cpu().IY, 0x7777);
+        assert_eq_hex!(soc.get_cpu().BW, 0x8888);
+    }
+
+    #[test]
+    fn test_compiler_stack_frame() {
+        // The same function compiled with an explicit prologue and with PREPARE/DISPOSE
+        let body = [
+            0xC7, 0x46, 0xFE, 0x34, 0x12, // MOV word [BP-2], 0x1234
+            0x8B, 0x5E, 0x04,             // MOV BW, [BP+4]
+            0x3E, 0x8B, 0x4E, 0xFE,       // MOV CW, DS0:[BP-2]
+        ];
+        let explicit = [&[0x55, 0x8B, 0xEC, 0x83, 0xEC, 0x04][..], &body, &[0x8B, 0xE5, 0x5D]].concat();
+        let enter_leave = [&[0xC8, 0x04, 0x00, 0x00][..], &body, &[0xC9]].concat();
+
+        for (program, instructions) in [(explicit, 9), (enter_leave, 6)] {
+            let mut soc = stack_soc([&[0xB8, 0xEF, 0xBE, 0x50, 0x50][..], &program].concat());
+            soc.get_cpu().BP = 0xAAAA;
+            write_word(&mut soc, 0x17F8, 0x5678);
+
+            // MOV AW, 0xBEEF then PUSH AW twice, for the argument and the return address
+            for _ in 0..3 + instructions {
+                soc.tick_cpu_no_cycles();
+            }
+
+            assert_eq_hex!(soc.get_cpu().BW, 0xBEEF);
+            assert_eq_hex!(soc.get_cpu().CW, 0x5678);
+            assert_eq_hex!(read_word(&mut soc, 0x27F8), 0x1234);
+            assert_eq_hex!(read_word(&mut soc, 0x27FA), 0xAAAA);
+            assert_eq_hex!(soc.get_cpu().SP, 0x07FC);
+            assert_eq_hex!(soc.get_cpu().BP, 0xAAAA);
+        }
+    }
+
+    #[test]
+    fn test_nested_prepare_ignores_override() {
+        let mut soc = stack_soc(vec![
+            0x3E, 0xC8, 0x02, 0x00, 0x03, // PREPARE 2, 3 with a DS0 prefix
+            0x3E, 0xC9,                   // DISPOSE with a DS0 prefix
+        ]);
+        // The outer frames' pointers, placed in the stack and at the same offsets in DS0
+        soc.get_cpu().BP = 0x0780;
+        write_word(&mut soc, 0x277E, 0x0770);
+        write_word(&mut soc, 0x277C, 0x0760);
+        write_word(&mut soc, 0x177E, 0xDEAD);
+        write_word(&mut soc, 0x177C, 0xDEAD);
+
+        soc.tick_cpu_no_cycles();
+        soc.tick_cpu_no_cycles();
+
+        assert_eq_hex!(soc.get_cpu().BP, 0x07FE);
+        assert_eq_hex!(soc.get_cpu().SP, 0x07F6);
+        assert_eq_hex!(read_word(&mut soc, 0x27FE), 0x0780);
+        assert_eq_hex!(read_word(&mut soc, 0x27FC), 0x0770);
+        assert_eq_hex!(read_word(&mut soc, 0x27FA), 0x0760);
+        assert_eq_hex!(read_word(&mut soc, 0x27F8), 0x07FE);
+
+        soc.tick_cpu_no_cycles();
+        soc.tick_cpu_no_cycles();
+
+        assert_eq_hex!(soc.get_cpu().BP, 0x0780);
+        assert_eq_hex!(soc.get_cpu().SP, 0x0800);
+    }
+
+    #[test]
+    fn test_push_mem_override() {
+        let mut soc = stack_soc(vec![
+            0xFF, 0x76, 0x04,       // PUSH [BP+4]
+            0x3E, 0xFF, 0x76, 0x04, // PUSH DS0:[BP+4]
+            0x3E, 0x8F, 0x46, 0x06, // POP DS0:[BP+6]
+        ]);
+        soc.get_cpu().BP = 0x0100;
+        write_word(&mut soc, 0x2104, 0x1111);
+        write_word(&mut soc, 0x1104, 0x2222);
+
+        for _ in 0..5 {
+            soc.tick_cpu_no_cycles();
+        }
+
+        // The operand follows the override, the stack is always in SS
+        assert_eq_hex!(read_word(&mut soc, 0x27FE), 0x1111);
+        assert_eq_hex!(read_word(&mut soc, 0x27FC), 0x2222);
+        assert_eq_hex!(read_word(&mut soc, 0x1106), 0x2222);
+        assert_eq_hex!(soc.get_cpu().SP, 0x07FE);
+    }
+
+    #[test]
+    fn test_retn_imm16() {
+        let mut soc = stack_soc(vec![
+            0xE8, 0x03, 0x00, // CALL 0x0006
+            0x90,             // NOP
+            0x90,             // NOP
+            0x90,             // NOP
+            0xC2, 0x03, 0x00, // RETN 3
+        ]);
+
+        // The return address pushed by CALL is only committed at the end of the instruction, RETN must still see it
+        soc.tick_cpu_no_cycles();
+        assert_eq_hex!(soc.get_cpu().SP, 0x07FE);
+        soc.tick_cpu_no_cycles();
+
+        // The immediate is added after the return address is popped, odd values leave SP unaligned
+        assert_eq_hex!(soc.get_cpu().PC, 0x0003);
+        assert_eq_hex!(soc.get_cpu().SP, 0x0803);
+        soc.tick_cpu_no_cycles();
+        assert_eq_hex!(soc.get_cpu().PC, 0x0004);
+    }
+
+    #[test]
+    fn test_retn_imm16_wraparound() {
+        let mut soc = stack_soc(vec![
+            0xC2, 0x01, 0xF8, // RETN 0xF801
+        ]);
+        soc.get_cpu().SP = 0x07FE;
+        write_word(&mut soc, 0x27FE, 0x1234);
+
+        soc.tick_cpu_no_cycles();
+
+        assert_eq_hex!(soc.get_cpu().PC, 0x1234);
+        assert_eq_hex!(soc.get_cpu().SP, 0x0001);
+    }
+
+    #[test]
+    fn test_retf_imm16() {
+        for (imm, sp) in [(0x0000, 0x0800), (0x0005, 0x0805), (0xFFFF, 0x07FF), (0xF801, 0x0001)] {
+            let [lo, hi] = u16::to_le_bytes(imm);
+            let mut soc = stack_soc(vec![0xCA, lo, hi]); // RETF imm
+            soc.get_cpu().SP = 0x07FC;
+            write_word(&mut soc, 0x27FC, 0x1234);
+            write_word(&mut soc, 0x27FE, 0x5678);
+
+            soc.tick_cpu_no_cycles();
+
+            assert_eq_hex!(soc.get_cpu().PC, 0x1234);
+            assert_eq_hex!(soc.get_cpu().PS, 0x5678);
+            assert_eq_hex!(soc.get_cpu().SP, sp);
+        }
+    }
+
+    #[test]
+    fn test_retf_stack_wraparound() {
+        // RETF 2, placed past the bottom of the stack segment
+        let mut soc = stack_soc([vec![0x90; 0x10], vec![0xCA, 0x02, 0x00]].concat());
+        // PC is popped from the top of the stack segment and PS from its bottom
+        soc.get_cpu().PC = 0x0010;
+        soc.get_cpu().SS = 0x0000;
+        soc.get_cpu().SP = 0xFFFE;
+        soc.set_model(Model::COLOR);
+        soc.io_bus.borrow_mut().write_io(0x60, 0x80);
+        write_word(&mut soc, 0xFFFE, 0x1234);
+        write_word(&mut soc, 0x0000, 0x5678);
+
+        soc.tick_cpu_no_cycles();
+
+        assert_eq_hex!(soc.get_cpu().PC, 0x1234);
+        assert_eq_hex!(soc.get_cpu().PS, 0x5678);
+        assert_eq_hex!(soc.get_cpu().SP, 0x0004);
+    }
+
+    #[test]
+    fn test_call_far_retf() {
+        let mut soc = stack_soc(vec![
+            0x9A, 0x03, 0x00, 0x00, 0x01, // CALL 0x0100:0x0003
+            0x90,                         // NOP
+        ]);
+        write_bytes(&mut soc, 0x1003, &[
+            0xCA, 0x01, 0x00,             // RETF 1
+        ]);
+
+        // The pushes are only written once the instruction's cycles have elapsed
+        soc.tick();
+        assert_eq!(soc.get_cpu().base, 10);
+        assert_eq_hex!(soc.get_cpu().PS, 0x0100);
+        assert_eq_hex!(soc.get_cpu().PC, 0x0003);
+        assert_eq_hex!(soc.get_cpu().SP, 0x07FC);
+        assert_eq_hex!(read_word(&mut soc, 0x27FC), 0x0101);
+        for _ in 1..10 {
+            soc.tick();
+        }
+        // PS is pushed first, so the return address is on top
+        assert_eq_hex!(read_word(&mut soc, 0x27FE), 0x0000);
+        assert_eq_hex!(read_word(&mut soc, 0x27FC), 0x0005);
+
+        soc.tick();
+        assert_eq!(soc.get_cpu().base, 9);
+        assert_eq_hex!(soc.get_cpu().PS, 0x0000);
+        assert_eq_hex!(soc.get_cpu().PC, 0x0005);
+        assert_eq_hex!(soc.get_cpu().SP, 0x0801);
+    }
+
+    #[test]
+    fn test_far_odd_sp() {
+        let mut soc = stack_soc(vec![
+            0x9A, 0x03, 0x00, 0x00, 0x01, // CALL 0x0100:0x0003
+        ]);
+        soc.get_wram().borrow_mut()[0x1003] = 0xCB; // RETF
+        soc.get_cpu().SP = 0x0801;
+
+        soc.tick_cpu_no_cycles();
+        assert_eq_hex!(soc.get_cpu().SP, 0x07FD);
+        assert_eq_hex!(read_word(&mut soc, 0x27FF), 0x0000);
+        assert_eq_hex!(read_word(&mut soc, 0x27FD), 0x0005);
+
+        soc.tick_cpu_no_cycles();
+        assert_eq!(soc.get_cpu().base, 8);
+        assert_eq_hex!(soc.get_cpu().PS, 0x0000);
+        assert_eq_hex!(soc.get_cpu().PC, 0x0005);
+        assert_eq_hex!(soc.get_cpu().SP, 0x0801);
+    }
+
+    #[test]
+    fn test_far_indirect() {
+        let mut soc = stack_soc(vec![
+            0xFF, 0x1E, 0x00, 0x01, // CALL far [0x0100]
+        ]);
+        // Far pointers are stored offset first
+        write_word(&mut soc, 0x1100, 0x0010);
+        write_word(&mut soc, 0x1102, 0x0020);
+        write_word(&mut soc, 0x1104, 0x0007);
+        write_word(&mut soc, 0x1106, 0x0000);
+        write_bytes(&mut soc, 0x0210, &[
+            0xFF, 0x2E, 0x04, 0x01, // BR far [0x0104]
+        ]);
+
+        soc.tick_cpu_no_cycles();
+        assert_eq!(soc.get_cpu().base, 12);
+        assert_eq_hex!(soc.get_cpu().PS, 0x0020);
+        assert_eq_hex!(soc.get_cpu().PC, 0x0010);
+        assert_eq_hex!(soc.get_cpu().SP, 0x07FC);
+        assert_eq_hex!(read_word(&mut soc, 0x27FE), 0x0000);
+        assert_eq_hex!(read_word(&mut soc, 0x27FC), 0x0004);
+
+        soc.tick_cpu_no_cycles();
+        assert_eq!(soc.get_cpu().base, 10);
+        assert_eq_hex!(soc.get_cpu().PS, 0x0000);
+        assert_eq_hex!(soc.get_cpu().PC, 0x0007);
+        assert_eq_hex!(soc.get_cpu().SP, 0x07FC);
+    }
+
+    #[test]
+    fn test_segment_boundary() {
+        let mut soc = stack_soc(Vec::new());
+        soc.set_model(Model::COLOR);
+        soc.io_bus.borrow_mut().write_io(0x60, 0x80);
+
+        // The operand of a far branch at the end of the segment continues from its start
+        write_bytes(&mut soc, 0xFFFD, &[0xEA, 0x00, 0x01]);
+        write_bytes(&mut soc, 0x0000, &[0x00, 0x00]);
+        soc.get_cpu().PC = 0xFFFD;
+        soc.tick_cpu_no_cycles();
+        assert_eq!(soc.get_cpu().base, 7);
+        assert_eq_hex!(soc.get_cpu().PS, 0x0000);
+        assert_eq_hex!(soc.get_cpu().PC, 0x0100);
+
+        // So does the return address of a call there
+        write_bytes(&mut soc, 0xFFFD, &[0xE8, 0x10, 0x00]);
+        soc.get_wram().borrow_mut()[0x0010] = 0xC3;
+        soc.get_cpu().PC = 0xFFFD;
+        soc.tick_cpu_no_cycles();
+        assert_eq!(soc.get_cpu().base, 5);
+        assert_eq_hex!(soc.get_cpu().PC, 0x0010);
+        assert_eq_hex!(read_word(&mut soc, 0x27FE), 0x0000);
+
+        soc.tick_cpu_no_cycles();
+        assert_eq!(soc.get_cpu().base, 6);
+        assert_eq_hex!(soc.get_cpu().PC, 0x0000);
+        assert_eq_hex!(soc.get_cpu().SP, 0x0800);
+    }
+}
@@ -241,7 +241,7 @@ impl V30MZ {
         // or two bytes to be loaded into AL and AH respectively
         match mode {
             Mode::M8 => {
-                let AL = self.read_io(addr as u8 as u16);
+                let AL = self.read_io(addr);
 
                 self.AW = swap_l(self.AW, AL);
             }
@@ -264,7 +264,7 @@ impl V30MZ {
 
         let dest = self.get_io_address(dest);
         match mode {
-            Mode::M8 => self.write_io(dest as u8 as u16, self.AW as u8),
+            Mode::M8 => self.write_io(dest, self.AW as u8),
             Mode::M16 => self.write_io_16(dest, self.AW),
             Mode::M32 => unreachable!()
         }
@@ -309,7 +309,9 @@ impl V30MZ {
 #[cfg(test)]
 #[cfg_attr(coverage_nightly, coverage(off))]
 mod test {
-    use crate::soc::SoC;
+    use alloc::vec;
+
+    use crate::{bus::io_bus::Model, soc::SoC};
     use crate::assert_eq_hex;
 
     use super::*;
@@ -879,4 +881,37 @@ mod test {
         soc.tick_cpu_no_cycles();
         assert_eq_hex!(soc.get_cpu().AW, 0xABCD);
     }
-}
\ No newline at end of file
+
+    #[test]
+    fn test_0xec_in_mirrored_ports() {
+        for (model, dw, expected) in [
+            (Model::MONO, 0x0100, 0x90),
+            (Model::MONO, 0x0200, 0xCD),
+            (Model::MONO, 0xFE00, 0xCD),
+            (Model::MONO, 0x02BA, 0x00),
+            (Model::COLOR, 0x01FF, 0x90),
+            (Model::COLOR, 0x0200, 0xCD),
+            (Model::COLOR, 0x02B8, 0x00),
+            (Model::COLOR, 0x02BA, 0x90),
+            (Model::COLOR, 0x00BA, 0x00),
+        ] {
+            let mut soc = SoC::test_build();
+            soc.set_model(model);
+            soc.set_wram(vec![0xEC, 0xFF]);
+            soc.set_io(vec![0xCD, 0xAB]);
+            soc.get_cpu().DW = dw;
+            soc.tick_cpu_no_cycles();
+            assert_eq_hex!(soc.get_cpu().AW & 0xFF, expected);
+        }
+    }
+
+    #[test]
+    fn test_0xed_in_mirrored_ports() {
+        let mut soc = SoC::test_build();
+        soc.set_wram(vec![0xED, 0xFF]);
+        soc.set_io(vec![0xCD, 0xAB]);
+        soc.get_cpu().DW = 0x0400;
+        soc.tick_cpu_no_cycles();
+        assert_eq_hex!(soc.get_cpu().AW, 0xABCD);
+    }
+}
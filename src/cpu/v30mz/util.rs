@@ -29,34 +29,31 @@ impl V30MZ {
 
         if op.code == 0x9A || op.code == 0xEA {
             self.pc_displacement = 5;
-            let (word1, word2) = self.read_mem_32(addr.wrapping_add(1));
-            let ([byte1, byte2], [byte3, byte4]) = (word1.to_le_bytes(), word2.to_le_bytes());
-            self.current_op.push(byte1);
-            self.current_op.push(byte2);
-            self.current_op.push(byte3);
-            self.current_op.push(byte4);
+            for offset in 1..=4 {
+                let byte = self.fetch_byte(offset);
+                self.current_op.push(byte);
+            }
             return op;
         }
 
         if op.code == 0xC8 {
             self.pc_displacement = 4;
-            let imm16 = self.read_mem_16(addr.wrapping_add(1)).to_le_bytes();
-            let imm8 = self.read_mem(addr.wrapping_add(3));
-            self.current_op.push(imm16[0]);
-            self.current_op.push(imm16[1]);
-            self.current_op.push(imm8);
+            for offset in 1..=3 {
+                let byte = self.fetch_byte(offset);
+                self.current_op.push(byte);
+            }
             return op;
         }
 
         if match_operand(op, Operand::MEMORY) {
-            let mod_rm = self.read_mem(addr.wrapping_add(1));
+            let mod_rm = self.fetch_byte(1);
             self.current_op.push(mod_rm);
             self.pc_displacement += 1;
 
             let mem_bytes = allocate_mod_rm(mod_rm);
             self.pc_displacement += mem_bytes as u16;
             for i in 0..mem_bytes {
-                let byte = self.read_mem(addr.wrapping_add(2 + (i as u32)));
+                let byte = self.fetch_byte(2 + i as u16);
                 self.current_op.push(byte);
             }
 
@@ -71,19 +68,28 @@ impl V30MZ {
         if (((imm && op.mode == Mode::M8) || (match_operand(op, Operand::IMMEDIATE_S))) && op.code != 0xE8 && op.code != 0xE9) || 
             op.code == 0xC1 || op.code == 0xE5 || op.code == 0xE7
         {
-            let imm8 = self.read_mem(addr.wrapping_add(self.pc_displacement as u32));
+            let imm8 = self.fetch_byte(self.pc_displacement);
             self.pc_displacement += 1;
             self.current_op.push(imm8);
         } else if imm || match_operand(op, Operand::DIRECT) || op.code == 0xE8 || op.code == 0xE9 {
-            let imm16 = self.read_mem_16(addr.wrapping_add(self.pc_displacement as u32)).to_le_bytes();
-            self.pc_displacement += 2;
-            self.current_op.push(imm16[0]);
-            self.current_op.push(imm16[1]);
+            for _ in 0..2 {
+                let byte = self.fetch_byte(self.pc_displacement);
+                self.pc_displacement += 1;
+                self.current_op.push(byte);
+            }
         }
 
         op
     }
 
+    /// Reads a byte of the current instruction, `offset` bytes after its start
+    /// 
+    /// The offset is added to `PC` before the segment is applied, so an instruction at the end of a segment continues from its start.
+    fn fetch_byte(&mut self, offset: u16) -> u8 {
+        let addr = self.apply_segment(self.PC.wrapping_add(offset), self.PS);
+        self.read_mem(addr)
+    }
+
     /// Get the last byte of the instruction
     pub fn get_imm8(&mut self) -> u8 {
         *self.current_op.last().unwrap()
@@ -468,6 +474,7 @@ impl V30MZ {
         else if self.cycles == self.base {
             self.cycles += extra;
         }
+        self.mod_rm = Some(byte);
 
         // When a is 0 and m is 6, the operand's memory offset is not given by an expression.
         // Instead, the literal 16-bit offset is present as two additional bytes of program code (low byte first).
@@ -514,15 +521,24 @@ impl V30MZ {
     }
 
     /// Gets the physical address by applying to the offset either a default segment or the segment override
-    pub fn get_physical_address(&self, offset: u16, default_segment: u16) -> u32 {
+    /// 
+    /// The address is remembered as the instruction's memory operand, along with the mod/rm byte it was resolved from if there is one.
+    pub fn get_physical_address(&mut self, offset: u16, default_segment: u16) -> u32 {
         let segment = match self.segment_override {
             None => default_segment,
             Some(s) => s,
         };
 
+        self.operands.push(OperandAddress {segment, offset, mod_rm: self.mod_rm.take()});
         self.apply_segment(offset, segment)
     }
 
+    /// Gets the physical address of a block instruction's destination, IY in the DS1 segment, which cannot be overridden
+    pub fn get_block_destination(&mut self) -> u32 {
+        self.operands.push(OperandAddress {segment: self.DS1, offset: self.IY, mod_rm: None});
+        self.apply_segment(self.IY, self.DS1)
+    }
+
     /// Gets the port address for the IN and OUT operations
     pub fn get_io_address(&mut self, src: Operand) -> u16 {
         // Use either the next byte padded with 0s or DW as the io_address
@@ -0,0 +1,175 @@
+use alloc::{vec, vec::Vec};
+
+use crate::{cartridge::Mapper, options::SharedOptions, soc::SoC, sound::buffer::SharedSamples};
+
+/// Size of the generated ROM, mirrored over the whole cartridge ROM area
+const ROM_SIZE: usize = 0x10000;
+/// Offset of the tiles within the ROM
+const TILES: usize = 0x1000;
+/// Offset of the screen map within the ROM
+const MAP: usize = 0x2000;
+/// Amount of tiles in the pattern
+const TILE_COUNT: usize = 4;
+
+/// Where the tiles are copied to, the start of 2 bits per pixel tile data
+const TILE_RAM: u16 = 0x2000;
+/// Where the screen map is copied to, selected by writing 3 to SCR_BASE
+const MAP_RAM: u16 = 0x1800;
+/// Size of a 32x32 screen map
+const MAP_SIZE: usize = 32 * 32 * 2;
+/// Line at which vblank starts
+const VBLANK_LINE: u8 = 144;
+
+/// Builds a SoC running the demo, used when no ROM is given
+pub fn demo_soc(samples: SharedSamples, options: SharedOptions) -> SoC {
+    SoC::new(Vec::new(), Vec::new(), Vec::new(), rom(), Mapper::B_2001, true, samples, options, 0)
+}
+
+/// Generates the demo ROM, a mono program scrolling a test pattern diagonally once per frame
+///
+/// The pattern uses every shade of the palette and covers screen 1 with 4 tiles: blank, a checkerboard, diagonal stripes and a frame.
+/// Everything is generated, so no copyrighted data is bundled and the ROM always matches the emulator.
+pub fn rom() -> Vec<u8> {
+    let mut rom = vec![0xFF; ROM_SIZE];
+    let tiles = tiles();
+    let map = map();
+    let code = program(tiles.len() as u16);
+    rom[..code.len()].copy_from_slice(&code);
+    rom[TILES..TILES + tiles.len()].copy_from_slice(&tiles);
+    rom[MAP..MAP + map.len()].copy_from_slice(&map);
+
+    // The CPU starts at FFFF:0000, the 16th byte from the end, which jumps to the program at F000:0000
+    let footer = ROM_SIZE - 16;
+    rom[footer..footer + 5].copy_from_slice(&[0xEA, 0x00, 0x00, 0x00, 0xF0]);
+    // No publisher, mono, no save, horizontal, 2001 mapper
+    rom[footer + 5..footer + 14].copy_from_slice(&[0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x04, 0x00]);
+    let checksum = rom[..ROM_SIZE - 2].iter().fold(0u16, |sum, byte| sum.wrapping_add(*byte as u16));
+    rom[ROM_SIZE - 2..].copy_from_slice(&checksum.to_le_bytes());
+    rom
+}
+
+/// Returns the color of a pixel of one of the pattern's tiles
+fn pattern(tile: usize, x: usize, y: usize) -> u8 {
+    match tile {
+        0 => 0,
+        1 => 1 + ((x / 2 + y / 2) % 2) as u8,
+        2 => ((x + y) / 2 % 4) as u8,
+        _ => if x == 0 || y == 0 || x == 7 || y == 7 {3} else {1},
+    }
+}
+
+/// Encodes the pattern's tiles in the 2 bits per pixel planar format, each row being a byte per plane
+fn tiles() -> Vec<u8> {
+    let mut tiles = Vec::with_capacity(TILE_COUNT * 16);
+    for tile in 0..TILE_COUNT {
+        for y in 0..8 {
+            let (mut plane_0, mut plane_1) = (0u8, 0u8);
+            for x in 0..8 {
+                let color = pattern(tile, x, y);
+                plane_0 |= (color & 1) << (7 - x);
+                plane_1 |= (color >> 1) << (7 - x);
+            }
+            tiles.extend([plane_0, plane_1]);
+        }
+    }
+    tiles
+}
+
+/// Lays the tiles out in bands of 4 by 4 tiles, using palette 0
+fn map() -> Vec<u8> {
+    let mut map = Vec::with_capacity(MAP_SIZE);
+    for y in 0..32 {
+        for x in 0..32 {
+            let tile = ((x / 4 + y / 4) % TILE_COUNT) as u16;
+            map.extend(tile.to_le_bytes());
+        }
+    }
+    map
+}
+
+/// Assembles the program, which sets up the display then scrolls screen 1 at the start of every vblank
+fn program(tiles_len: u16) -> Vec<u8> {
+    let mut code = vec![
+        0xFA,                   // DI
+        0x31, 0xC0,             // XOR AW, AW
+        0x8E, 0xD0,             // MOV SS, AW
+        0x8E, 0xC0,             // MOV DS1, AW
+        0xBC, 0x00, 0x18,       // MOV SP, 0x1800
+        // Shades 0, 5, 10 and 15 for the first 4 shades of the LUT, used by palette 0
+        0xB0, 0x50, 0xE6, 0x1C, // MOV AL, 0x50; OUT 0x1C, AL
+        0xB0, 0xFA, 0xE6, 0x1D, // MOV AL, 0xFA; OUT 0x1D, AL
+        0xB0, 0x10, 0xE6, 0x20, // MOV AL, 0x10; OUT 0x20, AL
+        0xB0, 0x32, 0xE6, 0x21, // MOV AL, 0x32; OUT 0x21, AL
+        0xB8, 0x00, 0xF0,       // MOV AW, 0xF000
+        0x8E, 0xD8,             // MOV DS0, AW
+        0xFC,                   // CLR1 DIR
+    ];
+    // Copies the tiles and the map from the ROM into WRAM
+    for (src, dest, len) in [(TILES as u16, TILE_RAM, tiles_len), (MAP as u16, MAP_RAM, MAP_SIZE as u16)] {
+        code.push(0xBE);
+        code.extend(src.to_le_bytes());
+        code.push(0xBF);
+        code.extend(dest.to_le_bytes());
+        code.push(0xB9);
+        code.extend(len.to_le_bytes());
+        code.extend([0xF3, 0xA4]); // REP MOVBK
+    }
+    code.extend([
+        0xB0, 0x03, 0xE6, 0x07, // MOV AL, 3; OUT 0x07, AL, screen 1 at 0x1800
+        0xB0, 0x01, 0xE6, 0x00, // MOV AL, 1; OUT 0x00, AL, enable screen 1
+    ]);
+
+    // Waits for vblank, scrolls, then waits for vblank to end
+    let wait = code.len();
+    code.extend([0xE4, 0x02, 0x3C, VBLANK_LINE, 0x75]); // IN AL, 0x02; CMP AL, 144; BNE wait
+    code.push(rel8(wait, code.len() + 1));
+    code.extend([
+        0xE4, 0x10, 0xFE, 0xC0, 0xE6, 0x10, // IN AL, 0x10; INC AL; OUT 0x10, AL
+        0xE4, 0x11, 0xFE, 0xC0, 0xE6, 0x11, // IN AL, 0x11; INC AL; OUT 0x11, AL
+    ]);
+    let leave = code.len();
+    code.extend([0xE4, 0x02, 0x3C, VBLANK_LINE, 0x74]); // IN AL, 0x02; CMP AL, 144; BE leave
+    code.push(rel8(leave, code.len() + 1));
+    code.push(0xEB); // BR wait
+    code.push(rel8(wait, code.len() + 1));
+    code
+}
+
+/// Returns the displacement of a short branch to `target`, `next` being the address of the following instruction
+fn rel8(target: usize, next: usize) -> u8 {
+    (target as isize - next as isize) as i8 as u8
+}
+
+#[cfg(test)]
+#[cfg_attr(coverage_nightly, coverage(off))]
+mod test {
+    use crate::{cartridge::header::RomInfo, options::EmulatorOptions};
+
+    use super::*;
+
+    #[test]
+    fn test_demo_footer() {
+        let info = RomInfo::parse(&rom()).unwrap();
+        assert!(info.checksum_valid());
+        assert!(!info.color);
+        assert_eq!(info.mapper, Mapper::B_2001);
+    }
+
+    #[test]
+    fn test_demo_runs() {
+        let options = SharedOptions::new(EmulatorOptions {mute: true, ..EmulatorOptions::new()});
+        let mut soc = demo_soc(crate::sound::buffer::SampleBuffer::shared(), options);
+        soc.run_frame();
+        soc.run_frame();
+        let first = soc.get_lcd().borrow().to_vec();
+        soc.run_frame();
+        let second = soc.get_lcd().borrow().to_vec();
+
+        assert!(soc.cpu.fault.is_none());
+        // Every shade of the pattern is shown, and it moves every frame
+        for gray in [0xFF, 0xAA, 0x55, 0x00] {
+            assert!(first.chunks(3).any(|pixel| pixel[0] == gray), "missing {:02X}", gray);
+        }
+        assert_ne!(first, second);
+    }
+}
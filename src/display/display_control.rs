@@ -1,8 +1,16 @@
-use std::{cell::RefCell, rc::Rc};
+use alloc::{boxed::Box, rc::Rc, vec::Vec};
+use core::cell::RefCell;
 
-use crate::bus::{io_bus::{IOBus, IOBusConnection}, mem_bus::{MemBus, MemBusConnection}};
+use crate::{bus::{io_bus::{IOBus, IOBusConnection}, mem_bus::{Accessor, MemBus, MemBusConnection}}, options::ColorSettings, state::{Snapshot, StateError, StateReader, StateWriter}};
 
-use super::{screen::ScreenElement, sprite::SpriteElement, PaletteFormat};
+use super::{palette::{PaletteMode, PaletteUnit}, raster::RasterLine, screen::ScreenElement, sprite::SpriteElement, timing::DisplayTiming, Layers, PaletteFormat, Source};
+
+/// First scanline during which the sprite table is copied into the display's internal memory
+const SPRITE_COPY_FIRST_LINE: u8 = 142;
+/// Last scanline during which the sprite table is copied into the display's internal memory
+const SPRITE_COPY_LAST_LINE: u8 = 143;
+/// Amount of dots spent copying each sprite table entry
+const SPRITE_COPY_DOTS: u16 = 4;
 
 /// WonderSwan display chip
 /// 
@@ -43,13 +51,24 @@ pub struct Display {
     /// Array of screen 2's pixels
     screen_2_pixels: Box<[[Option<(u8, u8, u8)>; 256]; 256]>,
 
-    /// Array of sprites
+    /// Array of sprites displayed on the current frame, ordered from highest to lowest priority
     sprite_table: [SpriteElement; 128],
     /// Array of sprite tiles
     sprite_tiles: [[[u8; 8]; 8]; 128],
+    /// Number of valid entries in the sprite table
+    sprite_count: usize,
     /// Array of pixels displayed to the sprite plane
     sprite_pixels: Box<[[Option<(u8, u8, u8)>; 256]; 256]>,
-    /// Number of sprites to read
+
+    /// Sprites copied during the current frame's copy window, displayed starting with the next frame
+    sprite_buffer: [SpriteElement; 128],
+    /// Tiles of the sprites copied during the current frame's copy window
+    sprite_tile_buffer: [[[u8; 8]; 8]; 128],
+    /// Number of sprites copied during the current frame's copy window
+    sprite_buffer_count: usize,
+    /// Index of the first sprite to copy, latched from SPR_FIRST at the start of the copy window
+    sprite_first: u8,
+    /// Number of sprites left to copy, latched from SPR_COUNT at the start of the copy window
     sprite_counter: u8,
     /// Indicates that there are no more sprites to read on the current frame
     finished_sprites: bool,
@@ -60,6 +79,9 @@ pub struct Display {
     /// A buffer to which the frame is written before being transferred to the larger buffer
     lcd: Box<[u8; 3 * 224 * 144]>,
 
+    /// Whether the LCD is switched on, latched from port 0x14 on the first dot of each line
+    lcd_enabled: bool,
+
     /// Current scanline
     scanline: u8,
     /// Current dot
@@ -67,15 +89,32 @@ pub struct Display {
 
     /// The color-map of the current scanline, `None` represents a transparent pixel
     color_map: [[Option<(u8, u8, u8)>; 16]; 16],
+    /// Palette registers latched at the start of the current line
+    palette: PaletteUnit,
+
+    /// Layers shown in the rendered output, set by the front-end for debugging
+    pub(crate) layers: Layers,
+    /// If set, each scanline is fetched and drawn all at once on its first dot instead of dot by dot
+    pub(crate) scanline_rendering: bool,
+    /// Most sprites drawn on a line, none if the limit is removed
+    pub(crate) sprites_per_line: Option<usize>,
+    /// Indices of the sprites drawn on the line being output, in table order
+    line_sprites: Vec<usize>,
+    /// Whether the registers used for each line are captured, set by the debugger
+    pub(crate) raster_trace: bool,
+    /// Registers of the lines output so far on the current frame
+    raster_lines: Vec<RasterLine>,
+    /// Registers of every line of the last frame output
+    raster_frame: Vec<RasterLine>,
 }
 
 impl MemBusConnection for Display {
     fn read_mem(&mut self, addr: u32) -> u8 {
-        self.mem_bus.borrow_mut().read_mem(addr)
+        self.mem_bus.borrow_mut().read_by(addr, Accessor::DISPLAY)
     }
 
     fn write_mem(&mut self, addr: u32, byte: u8) {
-        self.mem_bus.borrow_mut().write_mem(addr, byte);
+        self.mem_bus.borrow_mut().write_by(addr, byte, Accessor::DISPLAY);
     }
 }
 
@@ -87,6 +126,10 @@ impl IOBusConnection for Display {
     fn write_io(&mut self, addr: u16, byte: u8) {
         self.io_bus.borrow_mut().write_io(addr, byte);
     }
+
+    fn write_io_16(&mut self, addr: u16, word: u16) {
+        self.io_bus.borrow_mut().write_io_16(addr, word);
+    }
 }
 
 impl Display {
@@ -97,6 +140,7 @@ impl Display {
         Self {
             mem_bus, io_bus,
             scanline: 0, cycle: 0,
+            lcd_enabled: true,
 
             format,
             color,
@@ -106,62 +150,58 @@ impl Display {
             screen_1_tiles: [[[[0; 8]; 8]; 32]; 32],  screen_2_tiles: [[[[0; 8]; 8]; 32]; 32],
             screen_2_pixels: Box::new([[None; 256]; 256]),
 
-            sprite_table: [SpriteElement::dummy(); 128], sprite_tiles: [[[0; 8]; 8]; 128], sprite_pixels: Box::new([[None; 256]; 256]),
-            sprite_counter: 0, finished_sprites: false,
+            sprite_table: [SpriteElement::dummy(); 128], sprite_tiles: [[[0; 8]; 8]; 128], sprite_count: 0,
+            sprite_pixels: Box::new([[None; 256]; 256]),
+            sprite_buffer: [SpriteElement::dummy(); 128], sprite_tile_buffer: [[[0; 8]; 8]; 128], sprite_buffer_count: 0,
+            sprite_first: 0, sprite_counter: 0, finished_sprites: false,
             
             shared_lcd, lcd: Box::new([0; 3 * 224 * 144]),
-            color_map: [[None; 16]; 16]
+            color_map: [[None; 16]; 16],
+            palette: PaletteUnit::new(),
+            layers: Layers::new(),
+            scanline_rendering: false,
+            sprites_per_line: Some(32),
+            line_sprites: Vec::with_capacity(128),
+            raster_trace: false, raster_lines: Vec::with_capacity(144), raster_frame: Vec::new(),
         }
     }
 
-    /// Moves the display one dot further along, fetches data, potentially changes scanlines, may trigger interrupts and calls functions to place pixels.
-    pub fn tick(&mut self) {
-        self.color = self.io_bus.borrow_mut().color_mode();
-        self.format = self.io_bus.borrow_mut().palette_format();
+    /// Returns the scanline and dot the display will process next
+    pub fn position(&self) -> (u8, u8) {
+        (self.scanline, self.cycle)
+    }
 
-        let (x, y) = (self.cycle as usize, self.scanline as usize);
+    /// Changes how colors from palette RAM are output, from the next line on
+    pub fn set_colors(&mut self, colors: ColorSettings) {
+        self.palette.set_colors(colors);
+    }
 
-        match self.cycle {
-            // Find screen 1's tile and element data
-            0 => {
-                if self.scanline == 0 {
-                    self.get_screen_1_base();
-                    self.get_sprite_base();
-                    self.get_sprite_counter();
-                }
-                self.generate_color_map();
-                self.finished_sprites = false;
+    /// Moves the display one dot further along, fetches data, potentially changes scanlines, may trigger interrupts and calls functions to place pixels.
+    pub fn tick(&mut self) {
+        if !self.scanline_rendering || self.cycle == 0 {
+            self.color = self.io_bus.borrow_mut().color_mode();
+            self.format = self.io_bus.borrow_mut().palette_format();
+        }
+        if self.cycle == 0 {
+            self.lcd_enabled = self.io_bus.borrow().lcd_enabled();
+        }
 
-                let row = y >> 3;
-                let address = self.screen_1_base | ((row as u16) << 6);
-                self.screen_1_elements[row][0] = self.read_screen_element(address);
-            }
-            1..=63 => {
-                let (row, col) = (y >> 3, x / 2);
-                if self.cycle % 2 == 1 {
-                    self.screen_1_tiles[row][col] = self.read_tile(self.screen_1_elements[row][col].tile_idx, self.format);
-                } else {
-                    let address = self.screen_1_base | ((row as u16) << 6) | (col as u16 * 2);
-                    self.screen_1_elements[row][col] = self.read_screen_element(address);
-                }
-            }
+        // The display owns the bus while fetching the screens on visible lines and while copying the sprite table
+        let fetching = (self.scanline < 144 && self.cycle <= 129) || (SPRITE_COPY_FIRST_LINE..=SPRITE_COPY_LAST_LINE).contains(&self.scanline);
+        self.mem_bus.borrow_mut().set_display_fetch(fetching);
 
-            // Find screen 2's tile and element data
-            65 => {
-                if self.scanline == 0 {self.get_screen_2_base()};
-                self.screen_2_elements[y >> 3][0] = self.read_screen_element(self.screen_2_base);
-            }
-            66..=129 => {
-                let (row, col) = (y >> 3, (x - 66) / 2);
-                if self.cycle % 2 == 1 {
-                    self.screen_2_tiles[row][col] = self.read_tile(self.screen_2_elements[row][col].tile_idx, self.format);
-                } else {
-                    let address = self.screen_2_base | ((row as u16) << 6) | (col as u16 * 2);
-                    self.screen_2_elements[row][col] = self.read_screen_element(address);
+        if self.scanline_rendering {
+            if self.cycle == 0 {
+                for dot in 0..=129 {
+                    self.fetch(dot);
                 }
             }
+        } else {
+            self.fetch(self.cycle);
+        }
 
-            /*
+        /*
+        match self.cycle {
             // Find sprite data
             158 => self.fetch_sprite_tile(1),
             160 => self.fetch_sprite_tile(2),
@@ -195,85 +235,164 @@ impl Display {
             234 => self.fetch_sprite_tile(30),
             238 => self.fetch_sprite_tile(31),
             240 => self.fetch_sprite_tile(32),
-            */
+        }
+        */
 
-            255 => {
-                self.scanline += 1;
-                self.io_bus.borrow_mut().hblank();
-                self.io_bus.borrow_mut().set_lcd_line(self.scanline);
+        let last_dot = self.cycle as usize == DisplayTiming::DOTS - 1;
+        if last_dot {
+            // LCD_VTOTAL is read at the end of every line, a line past a lowered total also ends the frame
+            self.scanline = if self.scanline >= self.timing().last_line {0} else {self.scanline + 1};
+            self.io_bus.borrow_mut().hblank();
+            self.io_bus.borrow_mut().set_lcd_line(self.scanline);
+        }
+
+        // Display pixels of previous scanline
+        if let Some(line) = self.output_line() {
+            if self.raster_trace && self.cycle == 0 {self.capture_raster(line)}
+            if self.scanline_rendering {
+                if self.cycle == 0 {
+                    for dot in 0..224 {
+                        self.overlay_pixels(dot, line);
+                    }
+                }
+            } else if self.cycle < 224 {
+                self.overlay_pixels(self.cycle, line);
             }
-            _ => {}
         }
 
-        if self.scanline == 0 && self.cycle < 224 {
-            self.overlay_pixels(self.cycle, 144 - 1);
+        if (SPRITE_COPY_FIRST_LINE..=SPRITE_COPY_LAST_LINE).contains(&self.scanline) {
+            self.copy_sprites();
         }
 
-        // Display pixels of previous scanline
-        if (1..=143).contains(&self.scanline) && self.cycle < 224 {
-            self.overlay_pixels(self.cycle, self.scanline - 1);
+        if self.scanline == 144 && last_dot {
+            self.sprite_table = self.sprite_buffer;
+            self.sprite_tiles = self.sprite_tile_buffer;
+            self.sprite_count = self.sprite_buffer_count;
+            *self.shared_lcd.borrow_mut() = *self.lcd;
+            self.io_bus.borrow_mut().vblank();
         }
 
-        if self.scanline == 144 {
-            if self.cycle == 0 {
-                self.sprite_table = [SpriteElement::dummy(); 128];
-                self.sprite_tiles = [[[0; 8]; 8]; 128];
-            }
-            if self.sprite_counter > 0 && self.cycle % 2 == 0 {
-                let sprite_start = self.read_io(0x05) & 0x7F;
-                let sprite_idx = (self.cycle / 2).wrapping_add(sprite_start) & 0x7F;
-                let sprite_addr = self.sprite_base.wrapping_add(sprite_idx as u16 * 4);
-                let sprite = self.read_sprite(sprite_addr);
-                self.sprite_table[sprite_idx as usize] = sprite;
-                self.sprite_tiles[sprite_idx as usize] = self.read_tile(sprite.tile_idx, self.format);
-                self.sprite_counter -= 1;
+        self.cycle = if last_dot {0} else {self.cycle + 1};
+    }
+
+    /// Returns the frame timing currently selected through LCD_VTOTAL
+    pub(crate) fn timing(&self) -> DisplayTiming {
+        self.io_bus.borrow().display_timing()
+    }
+
+    /// Returns whether the display is at the first dot of a frame, about to output line 0
+    pub(crate) fn frame_start(&self) -> bool {
+        self.scanline == 0 && self.cycle == 0
+    }
+
+    /// Returns the line whose pixels are output during the current scanline, none during vblank
+    fn output_line(&self) -> Option<u8> {
+        match self.scanline {
+            0 => Some(144 - 1),
+            1..=143 => Some(self.scanline - 1),
+            _ => None,
+        }
+    }
+
+    /// Fetches the screen data belonging to a single dot of the current scanline
+    fn fetch(&mut self, dot: u8) {
+        let (x, y) = (dot as usize, self.scanline as usize);
+
+        match dot {
+            // Find screen 1's tile and element data
+            0 => {
+                if self.scanline == 0 {
+                    self.get_screen_1_base();
+                }
+                self.generate_color_map();
+                self.finished_sprites = false;
+
+                let row = y >> 3;
+                let address = self.screen_1_base | ((row as u16) << 6);
+                self.screen_1_elements[row][0] = self.read_screen_element(address);
             }
-            if self.cycle == 255 {
-                *self.shared_lcd.borrow_mut() = *self.lcd;
-                self.io_bus.borrow_mut().vblank();
+            1..=63 => {
+                let (row, col) = (y >> 3, x / 2);
+                if dot % 2 == 1 {
+                    self.screen_1_tiles[row][col] = self.read_tile(self.screen_1_elements[row][col].tile_idx, self.format);
+                } else {
+                    let address = self.screen_1_base | ((row as u16) << 6) | (col as u16 * 2);
+                    self.screen_1_elements[row][col] = self.read_screen_element(address);
+                }
             }
-        }
 
-        if self.scanline == 255 {
-            self.scanline = 0;
-            self.io_bus.borrow_mut().set_lcd_line(self.scanline);
+            // Find screen 2's tile and element data
+            65 => {
+                if self.scanline == 0 {self.get_screen_2_base()};
+                self.screen_2_elements[y >> 3][0] = self.read_screen_element(self.screen_2_base);
+            }
+            66..=129 => {
+                let (row, col) = (y >> 3, (x - 66) / 2);
+                if dot % 2 == 1 {
+                    self.screen_2_tiles[row][col] = self.read_tile(self.screen_2_elements[row][col].tile_idx, self.format);
+                } else {
+                    let address = self.screen_2_base | ((row as u16) << 6) | (col as u16 * 2);
+                    self.screen_2_elements[row][col] = self.read_screen_element(address);
+                }
+            }
+            _ => {}
         }
-
-        self.cycle = self.cycle.wrapping_add(1);
     }
 
     /// Reads the base address for screen 1 from the appropriate I/O port
     fn get_screen_1_base(&mut self) {
-        self.screen_1_base = ((self.io_bus.borrow_mut().read_io(0x07) & 0x0F) as u16) << 11;
-        if !self.color {self.screen_1_base &= 0x3800}
-        // println!("Screen 1 base: {:014X}", self.screen_1_base);
+        self.screen_1_base = self.io_bus.borrow().screen_1_base(self.color);
     }
 
     /// Reads the base address for screen 2 from the appropriate I/O port
     fn get_screen_2_base(&mut self) {
-        self.screen_2_base = (((self.io_bus.borrow_mut().read_io(0x07) >> 4) & 0x0F) as u16) << 11;
-        if !self.color {self.screen_2_base &= 0x3800}
+        self.screen_2_base = self.io_bus.borrow().screen_2_base(self.color);
     }
 
     /// Reads the base address for sprites from the appropriate I/O port
     fn get_sprite_base(&mut self) {
-        self.sprite_base = ((self.read_io(0x04) & 0x3F) as u16) << 9;
-        if !self.color {self.sprite_base &= 0x3E00}
+        self.sprite_base = self.io_bus.borrow().sprite_base(self.color);
     }
 
-    /// Reads the sprite count from the appropriate I/O port
+    /// Reads the first sprite and sprite count from the appropriate I/O ports
+    /// 
+    /// SPR_COUNT can hold values up to 128, anything above that is clamped.
     fn get_sprite_counter(&mut self) {
-        self.sprite_counter = self.read_io(0x06) & 0x7F;
+        self.sprite_first = self.read_io(0x05) & 0x7F;
+        self.sprite_counter = self.read_io(0x06).min(128);
+    }
+
+    /// Copies one entry of the sprite table into the internal sprite buffer every few dots of the copy window
+    /// 
+    /// The values of SPR_BASE, SPR_FIRST and SPR_COUNT are latched at the start of the window.
+    /// Entries are read starting from SPR_FIRST and wrap around after the 128th entry of the table.
+    fn copy_sprites(&mut self) {
+        let dot = (self.scanline - SPRITE_COPY_FIRST_LINE) as u16 * 256 + self.cycle as u16;
+        if dot == 0 {
+            self.get_sprite_base();
+            self.get_sprite_counter();
+            self.sprite_buffer_count = 0;
+        }
+
+        if self.sprite_counter > 0 && dot.is_multiple_of(SPRITE_COPY_DOTS) {
+            let sprite_idx = self.sprite_first.wrapping_add(self.sprite_buffer_count as u8) & 0x7F;
+            let sprite_addr = self.sprite_base.wrapping_add(sprite_idx as u16 * 4);
+            let sprite = self.read_sprite(sprite_addr);
+            self.sprite_buffer[self.sprite_buffer_count] = sprite;
+            self.sprite_tile_buffer[self.sprite_buffer_count] = self.read_tile(sprite.tile_idx, self.format);
+            self.sprite_buffer_count += 1;
+            self.sprite_counter -= 1;
+        }
     }
 
     /// Reads a tile of 8x8 pixels and returns a 2D array containing indices that can be used to fetch RGB values from the color map
     fn read_tile(&mut self, index: u16, format: PaletteFormat) -> [[u8; 8]; 8] {
-        std::array::from_fn(|row| {
+        core::array::from_fn(|row| {
             match format {
                 PaletteFormat::PLANAR_2BPP => {
                     let base = 0x2000 + (index as u32) * 16;
                     let [plane0, plane1] = self.read_mem_16(base + (row as u32 * 2)).to_le_bytes();
-                    std::array::from_fn(|col| {
+                    core::array::from_fn(|col| {
                         let b = 7 - col as u8;
                         let b0 = (plane0 >> b) & 1;
                         let b1 = (plane1 >> b) & 1;
@@ -285,7 +404,7 @@ impl Display {
                     let data = self.read_mem_32(base + (row as u32 * 4));
                     let [plane0, plane1] = data.0.to_le_bytes();
                     let [plane2, plane3] = data.1.to_le_bytes();
-                    std::array::from_fn(|col| {
+                    core::array::from_fn(|col| {
                         let b = 7 - col as u8;
                             let b0 = (plane0 >> b) & 1;
                             let b1 = (plane1 >> b) & 1;
@@ -296,7 +415,7 @@ impl Display {
                 }
                 PaletteFormat::PACKED_4BPP => {
                     let base = 0x4000 + (index as u32) * 32;
-                    std::array::from_fn(|col| {
+                    core::array::from_fn(|col| {
                         let bk_idx = row * 4 + col / 2;
                         let byte = self.read_mem(base + bk_idx as u32);
                         match col % 2 {
@@ -363,6 +482,18 @@ impl Display {
     }
     */
 
+    /// Selects the sprites drawn on a line
+    /// 
+    /// The first sprites of the table that cover the line are kept, up to the limit, the rest are not drawn at all.
+    fn select_line_sprites(&mut self, y: u8) {
+        let limit = self.sprites_per_line.unwrap_or(usize::MAX);
+        self.line_sprites.clear();
+        self.line_sprites.extend(self.sprite_table[..self.sprite_count].iter().enumerate()
+            .filter(|(_, s)| s.covers_line(y))
+            .map(|(i, _)| i)
+            .take(limit));
+    }
+
     /// Places a pixel on the LCD at coordinates (x,y)
     /// 
     /// # Optimization
@@ -370,6 +501,13 @@ impl Display {
     /// This function alone accounted for over 60% of the application's runtime in an older test.
     /// That test was performed before adding sprites. Any optimizations made to this function will drastically improve performance.
     fn overlay_pixels(&mut self, x: u8, y: u8) {
+        // A switched off LCD shows the bare panel until it is switched back on, starting with the next line
+        if !self.lcd_enabled {
+            let dot = (x as usize + y as usize * 224) * 3;
+            self.lcd[dot..dot + 3].fill(0xFF);
+            return;
+        }
+
         let (lo, hi) = self.io_bus.borrow_mut().read_io_16(0x00);
         let lcd_ctrl = u16::from_le_bytes([lo, hi]);
 
@@ -387,49 +525,33 @@ impl Display {
         }
 
         self.sprite_pixels[y as usize][x as usize] = None;
+        let mut sprite_source = Source::Sprite;
+        if x == 0 {self.select_line_sprites(y)}
         if spr {
-            let filtered_indices: Vec<usize> = match (scr2, sprwe) {
-                (true, true) => {
-                    let (x1, x2) = (self.read_io(0x0C), self.read_io(0x0E));
-                    if x2 < x1 {Vec::new()} else {
-                        let (y1, y2) = (self.read_io(0x0D), self.read_io(0x0F));
-                        if y2 < y1 {Vec::new()} else {
-                            self.sprite_table.iter().enumerate()
-                                .filter(|(_, s)| {(s.x..s.x.wrapping_add(8)).contains(&x)})
-                                .filter(|(_, s)| {(s.y..s.y.wrapping_add(8)).contains(&y)})
-                                .filter(|(_, s)| {s.ct != (x1..=x2).contains(&x) && s.ct != (y1..=y2).contains(&y)})
-                                .filter(|(_, s)| {s.pr || self.screen_2_pixels[y as usize][x as usize].is_none()})
-                                .map(|(i, _)| {i}).collect()
-                        }
-                    }
+            // With the window enabled but inverted no sprite is drawn at all
+            let window = sprwe.then(|| {
+                let (x1, x2) = (self.read_io(0x0C), self.read_io(0x0E));
+                let (y1, y2) = (self.read_io(0x0D), self.read_io(0x0F));
+                (x1 <= x2 && y1 <= y2).then_some((x1..=x2, y1..=y2))
+            });
+            // Screen 2's pixel is always transparent while it is disabled, so priority only matters when it is drawn
+            let screen_2_px = self.screen_2_pixels[y as usize][x as usize];
+
+            let filtered: Vec<(usize, (u8, u8))> = match window {
+                Some(None) => Vec::new(),
+                window => {
+                    let window = window.flatten();
+                    self.line_sprites.iter().filter_map(|&i| {
+                        let sprite = &self.sprite_table[i];
+                        let offset = sprite.offset(x, y)?;
+                        let in_window = window.as_ref().is_none_or(|(xs, ys)| sprite.ct != xs.contains(&x) && sprite.ct != ys.contains(&y));
+                        (in_window && (sprite.pr || screen_2_px.is_none())).then_some((i, offset))
+                    }).collect()
                 }
-                (true, false) => self.sprite_table.iter().enumerate()
-                        .filter(|(_, s)| {(s.x..s.x.wrapping_add(8)).contains(&x)})
-                        .filter(|(_, s)| {(s.y..s.y.wrapping_add(8)).contains(&y)})
-                        .filter(|(_, s)| {s.pr || self.screen_2_pixels[y as usize][x as usize] == None})
-                        .map(|(i, _)| {i}).collect(),
-                (false, true) => {
-                    let (x1, x2) = (self.read_io(0x0C), self.read_io(0x0E));
-                    if x2 < x1 {Vec::new()} else {
-                        let (y1, y2) = (self.read_io(0x0D), self.read_io(0x0F));
-                        if y2 < y1 {Vec::new()} else {
-                            self.sprite_table.iter().enumerate()
-                                .filter(|(_, s)| {(s.x..s.x.wrapping_add(8)).contains(&x)})
-                                .filter(|(_, s)| {(s.y..s.y.wrapping_add(8)).contains(&y)})
-                                .filter(|(_, s)| {s.ct != (x1..=x2).contains(&x) && s.ct != (y1..=y2).contains(&y)})
-                                .map(|(i, _)| {i}).collect()
-                        }
-                    }
-                }
-                (false, false) => self.sprite_table.iter().enumerate()
-                    .filter(|(_, s)| {(s.x..s.x.wrapping_add(8)).contains(&x)})
-                    .filter(|(_, s)| {(s.y..s.y.wrapping_add(8)).contains(&y)})
-                    .map(|(i, _)| {i}).collect(),
             };
 
-            for idx in filtered_indices {
+            for (idx, (dx, dy)) in filtered {
                 let sprite = &self.sprite_table[idx];
-                let (dx, dy) = (x - sprite.x, y - sprite.y);
                 let (dx, dy) = (
                     if sprite.hm {7 - dx} else {dx},
                     if sprite.vm {7 - dy} else {dy},
@@ -438,17 +560,18 @@ impl Display {
                 let palette = sprite.palette;
                 if let Some(color) = self.color_map[palette as usize + 8][raw_px as usize] {
                     self.sprite_pixels[y as usize][x as usize] = Some(color);
+                    sprite_source = if sprite.pr {Source::PrioritySprite} else {Source::Sprite};
                     break;
                 }
             }
         }
 
-        let pixel =
-            if let Some(spr_px) = self.sprite_pixels[y as usize][x as usize] {spr_px} 
-            else if let Some(scr2_px) = self.screen_2_pixels[y as usize][x as usize] {scr2_px}
+        let (pixel, source) =
+            if let (Some(spr_px), true) = (self.sprite_pixels[y as usize][x as usize], self.layers.sprites) {(spr_px, sprite_source)} 
+            else if let (Some(scr2_px), true) = (self.screen_2_pixels[y as usize][x as usize], self.layers.screen_2) {(scr2_px, Source::Screen2)}
             else {
                 if let Some(scr1_px) = 
-                    if scr1 {
+                    if scr1 && self.layers.screen_1 {
                         let scroll_x = self.read_io(0x10);
                         let scroll_y = self.read_io(0x11);
 
@@ -457,8 +580,8 @@ impl Display {
 
                         let element = self.screen_1_elements[element_idx.1 as usize][element_idx.0 as usize];
 
-                        if element.hm {pixel.0 = 7 - pixel.0};
-                        if element.vm {pixel.1 = 7 - pixel.1};
+                        if element.hm {pixel.0 = 7 - (pixel.0 & 7)};
+                        if element.vm {pixel.1 = 7 - (pixel.1 & 7)};
 
                         let raw_px = self.screen_1_tiles[element_idx.1 as usize][element_idx.0 as usize][pixel.1 as usize & 7][pixel.0 as usize & 7];
 
@@ -466,22 +589,11 @@ impl Display {
                     } else {
                         None
                     }
-                {scr1_px} else {
-                    if self.color {
-                        let mut color = (lcd_ctrl >> 8) & 0x0F;
-                        if self.format == PaletteFormat::PLANAR_2BPP {color &= 0x3}
-                        let (r, g, b) = self.get_color_palette((lcd_ctrl >> 12) as u8)[color as usize];
-                        (r * 17, g * 17, b * 17)
-                    } else {
-                        let index = ((lcd_ctrl >> 8) & 0x7) as u8;
-                        let (port, shift) = (index / 2, index % 2);
-                        let color_raw = (self.read_io(0x1C + port as u16) >> shift * 4) & 0x0F;
-                        let color = 0xFF - 0x11 * color_raw;
-
-                        (color, color, color)   
-                    }
+                {(scr1_px, Source::Screen1)} else {
+                    (self.palette.background(self.format, (lcd_ctrl >> 8) as u8), Source::Background)
                 }
             };
+            let pixel = if self.layers.tint {source.tint(pixel)} else {pixel};
 
             let dot = (x as usize + y as usize * 224) * 3;
 
@@ -490,6 +602,23 @@ impl Display {
             self.lcd[dot + 2] = pixel.2;
     }
 
+    /// Records the registers used to output a line, handing the frame over once its last line is recorded
+    fn capture_raster(&mut self, line: u8) {
+        let (lo, hi) = self.io_bus.borrow_mut().read_io_16(0x00);
+        let scroll_1 = (self.read_io(0x10), self.read_io(0x11));
+        let scroll_2 = (self.read_io(0x12), self.read_io(0x13));
+        if line == 0 {self.raster_lines.clear()}
+        self.raster_lines.push(RasterLine::capture(self.lcd_enabled, u16::from_le_bytes([lo, hi]), scroll_1, scroll_2, &self.palette));
+        if line == 143 && self.raster_lines.len() == 144 {
+            self.raster_frame = core::mem::replace(&mut self.raster_lines, Vec::with_capacity(144));
+        }
+    }
+
+    /// Returns the registers used for each line of the last frame output while the raster trace was on
+    pub fn raster_frame(&self) -> &[RasterLine] {
+        &self.raster_frame
+    }
+
     /// Finds the RGB value of a pixel on screen 2 or None if the pixel is transparent or clipped by the window
     fn apply_scr2_window(&mut self, s2we: bool, s2wc: bool, x: u8, y: u8) -> Option<(u8, u8, u8)> {
         let scroll_x = self.read_io(0x12);
@@ -500,8 +629,8 @@ impl Display {
 
         let element = self.screen_2_elements[element_idx.1 as usize][element_idx.0 as usize];
 
-        if element.hm {pixel.0 = 7 - pixel.0};
-        if element.vm {pixel.1 = 7 - pixel.1};
+        if element.hm {pixel.0 = 7 - (pixel.0 & 7)};
+        if element.vm {pixel.1 = 7 - (pixel.1 & 7)};
 
         let raw_px = self.screen_2_tiles[element_idx.1 as usize][element_idx.0 as usize][pixel.1 as usize & 7][pixel.0 as usize & 7];
 
@@ -522,57 +651,35 @@ impl Display {
         }
     }
 
-    /// Caches the color map at the time that this function is invoked
+    /// Latches the palette registers and caches the color map at the time that this function is invoked
     fn generate_color_map(&mut self) {
-        self.color_map = std::array::from_fn(|palette| {
-            std::array::from_fn(|raw_px| {
-                match self.format {
-                    PaletteFormat::PLANAR_2BPP => {
-                        if raw_px >= 4 || (raw_px == 0 && palette >= 4) {
-                            None
-                        } else {
-                            Some(if self.color {self.get_color_palette(palette as u8)[raw_px]} else {self.get_monochrome_palette(palette as u8)[raw_px]})
-                        }
-                    }
-                    PaletteFormat::PLANAR_4BPP | PaletteFormat::PACKED_4BPP => {
-                        if raw_px == 0 {None} else {Some(self.get_color_palette(palette as u8)[raw_px])}
-                    }
-                }
-            })
+        self.palette.mode = if self.color {PaletteMode::COLOR} else {PaletteMode::MONO};
+        for i in 0..4 {
+            self.palette.shade_lut[i] = self.read_io(0x1C + i as u16);
+        }
+        for i in 0..32 {
+            self.palette.mono_palettes[i] = self.read_io(0x20 + i as u16);
+        }
+        // Palette RAM does not exist in mono mode
+        if self.color {
+            for i in 0..256 {
+                self.palette.palette_ram[i] = self.read_mem_16(0x0FE00 + i as u32 * 2);
+            }
+        }
+
+        self.color_map = core::array::from_fn(|palette| {
+            core::array::from_fn(|raw_px| self.palette.color(self.format, palette as u8, raw_px as u8))
         });
     }
 
-    /// Returns the RGB value of a monochrome WonderSwan pixel
-    fn get_monochrome_palette(&mut self, palette: u8) -> [(u8, u8, u8); 4] {
-        // if palette != 0 {println!("{}", palette)}
-        let (lo, hi) = self.read_io_16(0x20 + (palette as u16) * 2);
-        let (c0, c1) = (lo & 0x07, (lo >> 4) & 0x07);
-        let (c2, c3) = (hi & 0x07, (hi >> 4) & 0x07);
-
-        std::array::from_fn(|i| {
-            let raw_px = [c0, c1, c2, c3][i];
-            let (port, shift) = (raw_px / 2, raw_px % 2);
-            let color_raw = (self.read_io(0x1C + port as u16) >> (shift * 4)) & 0x0F;
-            let color = 0xFF - 0x11 * color_raw;
-
-            // if color != 255 {println!("color: {}, raw_px: {}", color, raw_px)};
-
-            (color, color, color)
-        })
-    }
-
-    /// Returns the RGB value of a color mode pixel
-    fn get_color_palette(&mut self, palette: u8) -> [(u8, u8, u8); 16] {
-        let base = 0x0FE00 + (palette as u32) * 32;
-
-        std::array::from_fn(|i| {
-            let word = self.read_mem_16(base + i as u32 * 2);
-            let (r, g, b) = (((word >> 8) & 0x0F) as u8, ((word >> 4) & 0x0F) as u8, (word & 0x0F) as u8);
-            (r * 17, g * 17, b * 17)
-        })
+    /// Returns the RGB values of a palette's colors
+    #[cfg(feature = "std")]
+    fn get_palette(&self, palette: u8) -> [(u8, u8, u8); 16] {
+        core::array::from_fn(|i| if self.color {self.palette.rgb(palette, i as u8)} else {PaletteUnit::gray(self.palette.gradation(self.palette.mono_shade(palette, i as u8)))})
     }
 
     #[doc(hidden)]
+    #[cfg(feature = "std")]
     pub fn debug_screen_1(&mut self) {
         let element = self.screen_1_elements[0][0];
         println!("Element: {:#?}", element);
@@ -580,11 +687,12 @@ impl Display {
         println!("Reading tile from {:04X}", base);
         println!("Tile: {:#?}", self.screen_1_tiles[0][0]);
         println!("Correct tile: {:#?}", self.read_tile(element.tile_idx, PaletteFormat::PLANAR_4BPP));
-        println!("Palette RGB: {:#?}", self.get_color_palette(element.palette));
+        println!("Palette RGB: {:#?}", self.get_palette(element.palette));
         println!("Scroll 1 x: {} y: {}", self.read_io(0x10), self.read_io(0x11));
     }
 
     #[doc(hidden)]
+    #[cfg(feature = "std")]
     pub fn debug_screen_2(&mut self) {
         let element = self.screen_2_elements[13][9];
         println!("Element: {:#?}", element);
@@ -592,11 +700,12 @@ impl Display {
         println!("Reading tile from {:04X}", base);
         println!("Tile: {:#?}", self.screen_2_tiles[13][9]);
         println!("Correct tile: {:#?}", self.read_tile(element.tile_idx, PaletteFormat::PACKED_4BPP));
-        println!("Palette RGB: {:#?}", self.get_color_palette(element.palette));
+        println!("Palette RGB: {:#?}", self.get_palette(element.palette));
         println!("Scroll 1 x: {} y: {}", self.read_io(0x10), self.read_io(0x11));
     }
 
     #[doc(hidden)]
+    #[cfg(feature = "std")]
     pub fn debug_sprites(&mut self) {
         let sprite = self.sprite_table[0];
         println!("Sprite: {:#?}", sprite);
@@ -615,7 +724,409 @@ impl Display {
             let gradation = self.read_io(addr) >> (shift * 4) & 0x0F;
             println!("Gradation {} at port {:02X}, from raw_px {}", gradation, addr, i);
         };
-        println!("Palette RGB: {:#?}", self.get_monochrome_palette(sprite.palette));
+        println!("Palette RGB: {:#?}", self.get_palette(sprite.palette));
         // println!("Sprite pixels: {:#?}", self.sprite_pixels);
     }
-}
\ No newline at end of file
+}
+
+/// The display's state contains its position, the screen data fetched for the line being drawn, the sprite tables and both LCD buffers
+///
+/// The pixel planes and line sprites are rebuilt for each pixel before being read, so they are not part of it.
+impl Snapshot for Display {
+    const TAG: [u8; 4] = *b"DISP";
+    const VERSION: u16 = 2;
+
+    fn save_fields(&self, w: &mut StateWriter) {
+        w.write_u8(self.format as u8);
+        w.write_bool(self.color);
+        for base in [self.screen_1_base, self.screen_2_base, self.sprite_base] {w.write_u16(base)}
+
+        for element in self.screen_1_elements.iter().chain(&self.screen_2_elements).flatten() {
+            w.write_bool(element.vm);
+            w.write_bool(element.hm);
+            w.write_u8(element.palette);
+            w.write_u16(element.tile_idx);
+        }
+        for tile in self.screen_1_tiles.iter().chain(&self.screen_2_tiles).flatten() {
+            w.write_bytes(tile.as_flattened());
+        }
+
+        for (table, tiles, count) in [(&self.sprite_table, &self.sprite_tiles, self.sprite_count), (&self.sprite_buffer, &self.sprite_tile_buffer, self.sprite_buffer_count)] {
+            for sprite in table {
+                for flag in [sprite.vm, sprite.hm, sprite.pr, sprite.ct] {w.write_bool(flag)}
+                w.write_u8(sprite.palette);
+                w.write_u16(sprite.tile_idx);
+                w.write_u8(sprite.x);
+                w.write_u8(sprite.y);
+            }
+            for tile in tiles {w.write_bytes(tile.as_flattened())}
+            w.write_u8(count as u8);
+        }
+        w.write_u8(self.sprite_first);
+        w.write_u8(self.sprite_counter);
+        w.write_bool(self.finished_sprites);
+
+        w.write_bytes(&self.shared_lcd.borrow()[..]);
+        w.write_bytes(&self.lcd[..]);
+        w.write_u8(self.scanline);
+        w.write_u8(self.cycle);
+        w.write_bool(self.lcd_enabled);
+
+        for color in self.color_map.iter().flatten() {
+            w.write_bool(color.is_some());
+            let (r, g, b) = color.unwrap_or_default();
+            w.write_bytes(&[r, g, b]);
+        }
+        w.write_bool(self.palette.mode == PaletteMode::COLOR);
+        w.write_bytes(&self.palette.shade_lut);
+        w.write_bytes(&self.palette.mono_palettes);
+        for color in self.palette.palette_ram {w.write_u16(color)}
+    }
+
+    fn load_fields(&mut self, r: &mut StateReader, version: u16) -> Result<(), StateError> {
+        // Every field has a fixed size, so once the length and format are checked nothing can fail halfway through.
+        // The fields are then read in place, as copies of the tiles and LCDs would not fit on the stack of a debug build.
+        // Version 1 did not save whether the LCD was switched on
+        let mut expected = StateWriter::new();
+        self.save_fields(&mut expected);
+        if r.remaining() + (version < 2) as usize != expected.finish().len() {return Err(StateError::UnexpectedEnd)}
+        self.format = match r.read_u8()? {
+            0 => PaletteFormat::PLANAR_2BPP,
+            1 => PaletteFormat::PLANAR_4BPP,
+            2 => PaletteFormat::PACKED_4BPP,
+            _ => return Err(StateError::Mismatch("palette format")),
+        };
+        self.color = r.read_bool()?;
+        for base in [&mut self.screen_1_base, &mut self.screen_2_base, &mut self.sprite_base] {*base = r.read_u16()?}
+
+        for element in self.screen_1_elements.iter_mut().chain(&mut self.screen_2_elements).flatten() {
+            *element = ScreenElement::new(r.read_bool()?, r.read_bool()?, r.read_u8()?, r.read_u16()?);
+        }
+        for tile in self.screen_1_tiles.iter_mut().chain(&mut self.screen_2_tiles).flatten() {
+            tile.as_flattened_mut().copy_from_slice(r.read_bytes(64)?);
+        }
+
+        for (table, tiles, count) in [(&mut self.sprite_table, &mut self.sprite_tiles, &mut self.sprite_count), (&mut self.sprite_buffer, &mut self.sprite_tile_buffer, &mut self.sprite_buffer_count)] {
+            for sprite in table.iter_mut() {
+                let flags = [r.read_bool()?, r.read_bool()?, r.read_bool()?, r.read_bool()?];
+                *sprite = SpriteElement::new(flags[0], flags[1], flags[2], flags[3], r.read_u8()?, r.read_u16()?, r.read_u8()?, r.read_u8()?);
+            }
+            for tile in tiles.iter_mut() {tile.as_flattened_mut().copy_from_slice(r.read_bytes(64)?)}
+            *count = (r.read_u8()? as usize).min(128);
+        }
+        (self.sprite_first, self.sprite_counter, self.finished_sprites) = (r.read_u8()?, r.read_u8()?, r.read_bool()?);
+
+        self.shared_lcd.borrow_mut().copy_from_slice(r.read_bytes(3 * 224 * 144)?);
+        self.lcd.copy_from_slice(r.read_bytes(3 * 224 * 144)?);
+        (self.scanline, self.cycle) = (r.read_u8()?, r.read_u8()?);
+        self.lcd_enabled = if version >= 2 {r.read_bool()?} else {true};
+
+        for color in self.color_map.iter_mut().flatten() {
+            let (some, [red, green, blue]) = (r.read_bool()?, r.read_array()?);
+            *color = some.then_some((red, green, blue));
+        }
+        self.palette.mode = if r.read_bool()? {PaletteMode::COLOR} else {PaletteMode::MONO};
+        self.palette.shade_lut = r.read_array()?;
+        self.palette.mono_palettes = r.read_array()?;
+        for color in &mut self.palette.palette_ram {*color = r.read_u16()?}
+
+        // The sprites of a line are picked on its first dot, so states taken partway through a line pick them again
+        self.line_sprites.clear();
+        if let Some(line) = self.output_line() {self.select_line_sprites(line)}
+        Ok(())
+    }
+}
+#[cfg(test)]
+#[cfg_attr(coverage_nightly, coverage(off))]
+mod test {
+    use alloc::vec;
+
+    use crate::{assert_eq_hex, cartridge::Cartridge};
+
+    use super::*;
+
+    fn test_display() -> Display {
+        let cartridge = Rc::new(RefCell::new(Cartridge::test_build()));
+        let io_bus = Rc::new(RefCell::new(IOBus::new(Rc::clone(&cartridge), Vec::new(), None, false, 0)));
+        let mem_bus = Rc::new(RefCell::new(MemBus::test_build(Rc::clone(&io_bus), Rc::clone(&cartridge))));
+        let lcd = Rc::new(RefCell::new([0; 3 * 224 * 144]));
+        Display::new(mem_bus, io_bus, lcd)
+    }
+
+    fn run_until(display: &mut Display, scanline: u8, cycle: u8) {
+        while display.scanline != scanline || display.cycle != cycle {
+            display.tick();
+        }
+    }
+
+    fn write_sprite(display: &mut Display, idx: u16, tile_idx: u16, x: u8, y: u8) {
+        let addr = 0x3800 + idx as u32 * 4;
+        display.write_mem_16(addr, tile_idx);
+        display.write_mem(addr + 2, y);
+        display.write_mem(addr + 3, x);
+    }
+
+    fn setup_sprites(display: &mut Display, first: u8, count: u8) {
+        display.write_io(0x04, 0x1C);
+        display.write_io(0x05, first);
+        display.write_io(0x06, count);
+        for idx in 0..128 {
+            write_sprite(display, idx, idx, idx as u8, 0);
+        }
+    }
+
+    #[test]
+    fn test_frame_timing() {
+        let mut display = test_display();
+        display.io_bus.borrow_mut().write_io(0xB2, 1 << 6);
+        let mut lines = Vec::new();
+        let mut vblanks = 0;
+        for _ in 0..2 * display.timing().frame_ticks() {
+            display.tick();
+            let line = display.io_bus.borrow_mut().read_io(0x02);
+            if lines.last() != Some(&line) {lines.push(line)}
+            if display.io_bus.borrow_mut().read_io(0xB4) & (1 << 6) != 0 {
+                vblanks += 1;
+                display.io_bus.borrow_mut().write_io(0xB6, 1 << 6);
+            }
+        }
+
+        // The line counter wraps after the last line of vblank, each frame has a single vblank
+        let frame: Vec<u8> = (0..159).collect();
+        assert_eq!(lines, [&frame[..], &frame, &[0]].concat());
+        assert_eq!(vblanks, 2);
+        assert_eq!(display.position(), (0, 0));
+    }
+
+    #[test]
+    fn test_vtotal() {
+        let mut display = test_display();
+        display.io_bus.borrow_mut().write_io(0x16, 199);
+        let mut last_line = 0;
+        for _ in 0..display.timing().frame_ticks() {
+            display.tick();
+            last_line = last_line.max(display.io_bus.borrow_mut().read_io(0x02));
+        }
+        assert_eq!(last_line, 199);
+        assert!(display.frame_start());
+
+        // Lowering the total past the current line ends the frame at the end of that line
+        run_until(&mut display, 170, 0);
+        display.io_bus.borrow_mut().write_io(0x16, 150);
+        for _ in 0..DisplayTiming::DOTS {display.tick()}
+        assert!(display.frame_start());
+        assert_eq!(display.timing().lines(), 151);
+    }
+
+    #[test]
+    fn test_sprite_copy_first_count() {
+        let mut display = test_display();
+        setup_sprites(&mut display, 10, 3);
+
+        run_until(&mut display, 145, 0);
+        assert_eq!(display.sprite_count, 3);
+        assert_eq!(display.sprite_table[0].tile_idx, 10);
+        assert_eq!(display.sprite_table[1].tile_idx, 11);
+        assert_eq!(display.sprite_table[2].tile_idx, 12);
+    }
+
+    #[test]
+    fn test_sprite_copy_wraps_at_128() {
+        let mut display = test_display();
+        setup_sprites(&mut display, 126, 4);
+
+        run_until(&mut display, 145, 0);
+        assert_eq!(display.sprite_count, 4);
+        assert_eq!(display.sprite_table[0].tile_idx, 126);
+        assert_eq!(display.sprite_table[1].tile_idx, 127);
+        assert_eq!(display.sprite_table[2].tile_idx, 0);
+        assert_eq!(display.sprite_table[3].tile_idx, 1);
+    }
+
+    #[test]
+    fn test_sprite_copy_count_clamped() {
+        let mut display = test_display();
+        setup_sprites(&mut display, 0, 0xFF);
+
+        run_until(&mut display, 145, 0);
+        assert_eq!(display.sprite_count, 128);
+        assert_eq!(display.sprite_table[127].tile_idx, 127);
+    }
+
+    #[test]
+    fn test_sprite_line_limit() {
+        let mut display = test_display();
+        setup_sprites(&mut display, 0, 40);
+        run_until(&mut display, 145, 0);
+
+        // Every sprite covers lines 0-7, only the first 32 of the table are drawn
+        display.select_line_sprites(3);
+        assert_eq!(display.line_sprites, (0..32).collect::<Vec<_>>());
+        display.select_line_sprites(8);
+        assert!(display.line_sprites.is_empty());
+
+        display.sprites_per_line = None;
+        display.select_line_sprites(3);
+        assert_eq!(display.line_sprites.len(), 40);
+    }
+
+    /// Draws a single opaque sprite with only sprites enabled, returns the columns of a line it covers
+    fn sprite_columns(display: &mut Display, sprite: SpriteElement, y: u8) -> Vec<u8> {
+        display.sprite_table[0] = sprite;
+        display.sprite_count = 1;
+        display.sprite_tiles[0] = [[1; 8]; 8];
+        display.color_map[sprite.palette as usize + 8][1] = Some((0xFF, 0x00, 0x00));
+        (0..224).filter(|&x| {
+            display.overlay_pixels(x, y);
+            display.sprite_pixels[y as usize][x as usize].is_some()
+        }).collect()
+    }
+
+    #[test]
+    fn test_sprite_wraparound() {
+        let cases: [(u8, u8, u8, Vec<u8>); 7] = [
+            (100, 0, 0, (100..108).collect()),
+            (220, 0, 7, (220..224).collect()),
+            (250, 0, 0, vec![0, 1]),
+            (248, 0, 0, vec![]),
+            (0, 252, 3, (0..8).collect()),
+            (0, 252, 4, vec![]),
+            (250, 252, 0, vec![0, 1]),
+        ];
+        for (x, y, line, columns) in cases {
+            let mut display = test_display();
+            display.write_io(0x00, 0x04);
+            let sprite = SpriteElement::new(false, false, false, false, 0, 0, x, y);
+            assert_eq!(sprite_columns(&mut display, sprite, line), columns, "sprite at ({}, {}) on line {}", x, y, line);
+        }
+    }
+
+    #[test]
+    fn test_sprite_window() {
+        // Window from 0x0C-0x0F as (x1, y1, x2, y2), contained flag and expected columns of line 0
+        let cases: [([u8; 4], bool, Vec<u8>); 5] = [
+            ([0, 0, 3, 143], false, (0..4).collect()),
+            ([0, 0, 3, 143], true, vec![]),
+            ([0, 100, 3, 143], false, vec![]),
+            ([0, 100, 3, 143], true, (4..8).collect()),
+            ([5, 0, 3, 143], false, vec![]),
+        ];
+        for (window, ct, columns) in cases {
+            let mut display = test_display();
+            display.write_io(0x00, 0x0C);
+            for (port, value) in (0x0C..).zip(window) {
+                display.write_io(port, value);
+            }
+            let sprite = SpriteElement::new(false, false, false, ct, 0, 0, 0, 0);
+            assert_eq!(sprite_columns(&mut display, sprite, 0), columns, "window {:?}, contained {}", window, ct);
+        }
+    }
+
+    #[test]
+    fn test_sprite_table_modified_mid_frame() {
+        let mut display = test_display();
+        setup_sprites(&mut display, 0, 1);
+
+        run_until(&mut display, 100, 0);
+        write_sprite(&mut display, 0, 0x42, 0x20, 0x30);
+
+        run_until(&mut display, 145, 0);
+        assert_eq!(display.sprite_table[0], SpriteElement::new(false, false, false, false, 0, 0x42, 0x20, 0x30));
+    }
+
+    #[test]
+    fn test_sprite_table_modified_during_vblank() {
+        let mut display = test_display();
+        setup_sprites(&mut display, 0, 1);
+
+        run_until(&mut display, 145, 0);
+        write_sprite(&mut display, 0, 0x42, 0x20, 0x30);
+        run_until(&mut display, 0, 0);
+        assert_eq!(display.sprite_table[0].tile_idx, 0);
+
+        run_until(&mut display, 145, 0);
+        assert_eq!(display.sprite_table[0].tile_idx, 0x42);
+    }
+
+    #[test]
+    fn test_sprite_table_latched_until_vblank() {
+        let mut display = test_display();
+        setup_sprites(&mut display, 0, 1);
+
+        run_until(&mut display, 145, 0);
+        write_sprite(&mut display, 0, 0x42, 0x20, 0x30);
+
+        run_until(&mut display, SPRITE_COPY_LAST_LINE, 255);
+        assert_eq!(display.sprite_table[0].tile_idx, 0);
+        assert_eq!(display.sprite_buffer[0].tile_idx, 0x42);
+    }
+
+    #[test]
+    fn test_hidden_screen_1() {
+        for (shown, expected) in [(true, 0x00), (false, 0xFF)] {
+            let mut display = test_display();
+            // Screen 1 uses palette 0 color 0, which is shade 7, the darkest
+            display.write_io(0x00, 0x01);
+            display.write_io(0x20, 0x07);
+            display.write_io(0x1F, 0xF0);
+            display.layers.screen_1 = shown;
+
+            run_until(&mut display, 145, 0);
+            assert_eq_hex!(display.shared_lcd.borrow()[0], expected);
+        }
+    }
+
+    #[test]
+    fn test_layer_tint() {
+        let mut display = test_display();
+        display.write_io(0x00, 0x01);
+        display.write_io(0x20, 0x07);
+        display.write_io(0x1F, 0xF0);
+        display.layers.tint = true;
+
+        run_until(&mut display, 145, 0);
+        let (r, g, b) = Source::Screen1.tint((0x00, 0x00, 0x00));
+        assert_eq!(&display.shared_lcd.borrow()[0..3], &[r, g, b]);
+        // Every source gets a color of its own
+        let sources = [Source::Background, Source::Screen1, Source::Screen2, Source::Sprite, Source::PrioritySprite];
+        let tints: Vec<_> = sources.iter().map(|source| source.tint((0x80, 0x80, 0x80))).collect();
+        assert!(tints.iter().enumerate().all(|(i, tint)| !tints[..i].contains(tint)));
+    }
+
+    #[test]
+    fn test_color_background() {
+        let mut display = test_display();
+        display.write_io(0x60, 0xE0);
+        display.write_io(0x00, 0x00);
+        display.write_io(0x01, 0x2F);
+        display.write_mem_16(0x0FE00 + 0x2F * 2, 0x0F84);
+
+        run_until(&mut display, 145, 0);
+        assert_eq!(&display.shared_lcd.borrow()[0..3], &[0xFF, 0x88, 0x44]);
+    }
+
+    #[test]
+    fn test_scanline_rendering_static_frame() {
+        let frame = |scanline_rendering: bool| {
+            let mut display = test_display();
+            display.scanline_rendering = scanline_rendering;
+            setup_sprites(&mut display, 0, 16);
+            display.write_io(0x00, 0x05);
+            display.write_io(0x1C, 0x73);
+            display.write_io(0x1D, 0x51);
+            display.write_io(0x20, 0x31);
+            display.write_io(0x21, 0x64);
+            // Tile data, which screen 1's map at 0x0000 also reads as tile indices
+            for addr in 0x2000..0x2400 {
+                display.write_mem(addr, (addr * 7) as u8);
+            }
+
+            run_until(&mut display, 145, 0);
+            let lcd = display.shared_lcd.borrow().to_vec();
+            lcd
+        };
+        let expected = frame(false);
+        assert!(expected.iter().any(|pixel| *pixel != expected[0]));
+        assert_eq!(frame(true), expected);
+    }
+}
@@ -2,10 +2,78 @@
 /// 
 /// This module is public so that main can send the contents of the frame to SDL for display
 pub mod display_control;
+/// Shade LUT and palette RAM handling
+pub mod palette;
+/// Per-scanline capture of the display's registers for debugging raster effects
+pub mod raster;
 /// Contains information related to screen elements
 mod screen;
 /// Contains information related to sprites
 mod sprite;
+/// Lines and dots per frame of each console model
+pub mod timing;
+
+/// Debugging overrides that hide layers from the rendered output or tint pixels by the layer they come from
+/// 
+/// Hidden layers are still fetched and take part in sprite priority and windowing as usual, they are only left out when the pixels are composed.
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub struct Layers {
+    /// Whether screen 1 is drawn
+    pub screen_1: bool,
+    /// Whether screen 2 is drawn
+    pub screen_2: bool,
+    /// Whether sprites are drawn
+    pub sprites: bool,
+    /// Whether pixels are tinted by their [`Source`]
+    pub tint: bool,
+}
+
+impl Layers {
+    /// Returns overrides with every layer shown
+    pub fn new() -> Self {
+        Self {screen_1: true, screen_2: true, sprites: true, tint: false}
+    }
+}
+
+impl Default for Layers {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+/// Layer a pixel of the output came from
+///
+/// Tinting pixels by their source makes layer priority bugs visible at a glance when comparing against captures of the hardware.
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum Source {
+    /// The back color, no layer having an opaque pixel there
+    Background,
+    /// Screen 1, tinted blue
+    Screen1,
+    /// Screen 2, tinted green
+    Screen2,
+    /// A sprite without the priority bit, which screen 2 hides, tinted yellow
+    Sprite,
+    /// A sprite with the priority bit, drawn over screen 2, tinted red
+    PrioritySprite,
+}
+
+impl Source {
+    /// Replaces the hue of a pixel with the source's, keeping its brightness so that the picture stays recognizable
+    pub fn tint(&self, pixel: (u8, u8, u8)) -> (u8, u8, u8) {
+        let tint = match self {
+            Source::Background => return (pixel.0 / 4, pixel.1 / 4, pixel.2 / 4),
+            Source::Screen1 => (0x40, 0x60, 0xFF),
+            Source::Screen2 => (0x40, 0xFF, 0x40),
+            Source::Sprite => (0xFF, 0xE0, 0x20),
+            Source::PrioritySprite => (0xFF, 0x30, 0x30),
+        };
+        // Dark pixels keep half of the tint so that black shades still tell which layer they come from
+        let brightness = (pixel.0 as u32 + pixel.1 as u32 + pixel.2 as u32) / 3 / 2 + 0x80;
+        let channel = |tint: u8| (tint as u32 * brightness / 0xFF) as u8;
+        (channel(tint.0), channel(tint.1), channel(tint.2))
+    }
+}
 
 /// Format encoding the color index of each pixel within the tile's palette
 #[derive(Clone, Copy, PartialEq, Eq)]
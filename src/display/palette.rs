@@ -0,0 +1,215 @@
+use crate::options::ColorSettings;
+
+use super::PaletteFormat;
+
+/// Where the palette unit takes its colors from
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum PaletteMode {
+    /// Palettes pick shades from the shade LUT
+    MONO,
+    /// Palettes are read from palette RAM
+    COLOR,
+}
+
+/// Converts the palette indices of pixels into RGB values
+///
+/// In mono mode each palette, stored in ports 0x20-0x3F, picks 4 of the 8 shades of the shade LUT in ports 0x1C-0x1F.
+/// The LUT packs its shades in pairs, the low nibble of each port holding the even shade and the high nibble the odd one.
+/// Each shade is a 4-bit gradation from 0 (white) to 15 (black).
+///
+/// In color mode the palettes are read from palette RAM at 0xFE00-0xFFFF, 16 words of 12-bit RGB each.
+/// Palette RAM is the end of the upper 48 KB of WRAM, which only exists in color mode,
+/// so in mono mode writes to it are dropped and the renderer never reads it.
+///
+/// In 2 bits per pixel formats color 0 is transparent for palettes 4-7 and 12-15 only,
+/// which is why bits 0-2 of those palettes' ports cannot be written.
+/// In 4 bits per pixel formats color 0 is transparent for every palette.
+///
+/// Colors from palette RAM are expanded to 8 bits per channel following the color profile, see [`crate::options::ColorProfile`].
+pub struct PaletteUnit {
+    /// Whether colors come from the shade LUT or from palette RAM
+    pub mode: PaletteMode,
+    /// The shade LUT, ports 0x1C-0x1F
+    pub shade_lut: [u8; 4],
+    /// The mono palettes, ports 0x20-0x3F
+    pub mono_palettes: [u8; 32],
+    /// Palette RAM, 16 palettes of 16 colors
+    pub palette_ram: [u16; 256],
+    /// The 8-bit level shown for each 4-bit level of a channel
+    levels: [u8; 16],
+    /// Saturation applied to colors, in percent
+    saturation: u16,
+}
+
+impl PaletteUnit {
+    /// Creates a palette unit in mono mode with every register cleared
+    pub fn new() -> Self {
+        let colors = ColorSettings::new();
+        Self {mode: PaletteMode::MONO, shade_lut: [0; 4], mono_palettes: [0; 32], palette_ram: [0; 256], levels: colors.profile.levels(), saturation: colors.total_saturation()}
+    }
+
+    /// Changes how colors from palette RAM are output
+    pub fn set_colors(&mut self, colors: ColorSettings) {
+        self.levels = colors.profile.levels();
+        self.saturation = colors.total_saturation();
+    }
+
+    /// Returns the 4-bit gradation of one of the 8 shades of the LUT
+    pub fn gradation(&self, shade: u8) -> u8 {
+        let shade = shade & 0x07;
+        (self.shade_lut[shade as usize / 2] >> ((shade % 2) * 4)) & 0x0F
+    }
+
+    /// Converts a 4-bit gradation into the gray shown on screen
+    pub fn gray(gradation: u8) -> (u8, u8, u8) {
+        let gray = 0xFF - 0x11 * (gradation & 0x0F);
+        (gray, gray, gray)
+    }
+
+    /// Returns the shade a mono palette assigns to a color
+    pub fn mono_shade(&self, palette: u8, color: u8) -> u8 {
+        let port = self.mono_palettes[(palette as usize & 0x0F) * 2 + (color as usize & 0x03) / 2];
+        (port >> ((color % 2) * 4)) & 0x07
+    }
+
+    /// Converts a 12-bit color from palette RAM into RGB
+    pub fn rgb(&self, palette: u8, color: u8) -> (u8, u8, u8) {
+        let word = self.palette_ram[(palette as usize & 0x0F) * 16 + (color as usize & 0x0F)];
+        let [r, g, b] = [8, 4, 0].map(|shift| self.levels[(word >> shift) as usize & 0x0F]);
+        if self.saturation == 100 {return (r, g, b)}
+
+        // Channels are moved away from or towards the color's luma
+        let luma = (r as i32 * 77 + g as i32 * 150 + b as i32 * 29) >> 8;
+        let [r, g, b] = [r, g, b].map(|channel| (luma + (channel as i32 - luma) * self.saturation as i32 / 100).clamp(0, 255) as u8);
+        (r, g, b)
+    }
+
+    /// Whether color 0 of a palette is transparent
+    pub fn is_transparent(format: PaletteFormat, palette: u8, color: u8) -> bool {
+        color == 0 && (format != PaletteFormat::PLANAR_2BPP || palette & 0x04 != 0)
+    }
+
+    /// Returns the RGB value of a color of a palette, or None if it is transparent
+    pub fn color(&self, format: PaletteFormat, palette: u8, color: u8) -> Option<(u8, u8, u8)> {
+        if Self::is_transparent(format, palette, color) {return None}
+        match (self.mode, format) {
+            (PaletteMode::MONO, _) => {
+                if color >= 4 {return None}
+                Some(Self::gray(self.gradation(self.mono_shade(palette, color))))
+            }
+            (PaletteMode::COLOR, PaletteFormat::PLANAR_2BPP) => if color >= 4 {None} else {Some(self.rgb(palette, color))},
+            (PaletteMode::COLOR, _) => Some(self.rgb(palette, color)),
+        }
+    }
+
+    /// Returns the color shown where no layer is drawn, selected by port 0x01
+    ///
+    /// In mono mode the port selects one of the LUT's shades directly, in color mode the high nibble selects the palette and the low nibble the color.
+    /// Only the first 4 colors can be selected in 2 bits per pixel formats.
+    pub fn background(&self, format: PaletteFormat, back_color: u8) -> (u8, u8, u8) {
+        match self.mode {
+            PaletteMode::MONO => Self::gray(self.gradation(back_color & 0x07)),
+            PaletteMode::COLOR => {
+                let color = if format == PaletteFormat::PLANAR_2BPP {back_color & 0x03} else {back_color & 0x0F};
+                self.rgb(back_color >> 4, color)
+            }
+        }
+    }
+}
+
+impl Default for PaletteUnit {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+#[cfg(test)]
+#[cfg_attr(coverage_nightly, coverage(off))]
+mod test {
+    use alloc::vec::Vec;
+
+    use crate::options::ColorProfile;
+
+    use super::*;
+
+    #[test]
+    fn test_every_gradation() {
+        let mut unit = PaletteUnit::new();
+        for gradation in 0..16 {
+            for shade in 0..8u8 {
+                unit.shade_lut = [0; 4];
+                unit.shade_lut[shade as usize / 2] = gradation << ((shade % 2) * 4);
+                assert_eq!(unit.gradation(shade), gradation);
+                // The other shade of the pair is unaffected
+                assert_eq!(unit.gradation(shade ^ 1), 0);
+
+                let gray = 0xFF - gradation * 0x11;
+                assert_eq!(PaletteUnit::gray(unit.gradation(shade)), (gray, gray, gray));
+            }
+        }
+        assert_eq!(PaletteUnit::gray(0), (0xFF, 0xFF, 0xFF));
+        assert_eq!(PaletteUnit::gray(15), (0x00, 0x00, 0x00));
+    }
+
+    #[test]
+    fn test_mono_palette() {
+        let mut unit = PaletteUnit::new();
+        unit.shade_lut = [0x10, 0x32, 0x54, 0x76];
+        // Palette 1 maps its colors to shades 7, 5, 3 and 1
+        unit.mono_palettes[2] = 0x57;
+        unit.mono_palettes[3] = 0x13;
+
+        let colors: Vec<_> = (0..4).map(|color| unit.color(PaletteFormat::PLANAR_2BPP, 1, color)).collect();
+        assert_eq!(colors, [7, 5, 3, 1].map(|gradation| Some(PaletteUnit::gray(gradation))));
+        assert_eq!(unit.background(PaletteFormat::PLANAR_2BPP, 0x06), PaletteUnit::gray(6));
+    }
+
+    #[test]
+    fn test_transparency() {
+        for palette in 0..16 {
+            let transparent = (4..8).contains(&palette) || (12..16).contains(&palette);
+            assert_eq!(PaletteUnit::is_transparent(PaletteFormat::PLANAR_2BPP, palette, 0), transparent);
+            assert!(!PaletteUnit::is_transparent(PaletteFormat::PLANAR_2BPP, palette, 1));
+            assert!(PaletteUnit::is_transparent(PaletteFormat::PLANAR_4BPP, palette, 0));
+            assert!(PaletteUnit::is_transparent(PaletteFormat::PACKED_4BPP, palette, 0));
+        }
+    }
+
+    #[test]
+    fn test_color_palette() {
+        let mut unit = PaletteUnit::new();
+        unit.mode = PaletteMode::COLOR;
+        unit.palette_ram[0x1F] = 0x0F84;
+        unit.palette_ram[0x13] = 0x0123;
+
+        assert_eq!(unit.color(PaletteFormat::PLANAR_4BPP, 1, 15), Some((0xFF, 0x88, 0x44)));
+        assert_eq!(unit.color(PaletteFormat::PLANAR_2BPP, 1, 15), None);
+        assert_eq!(unit.background(PaletteFormat::PLANAR_4BPP, 0x1F), (0xFF, 0x88, 0x44));
+        // Only the first 4 colors can be the background in 2 bits per pixel formats
+        assert_eq!(unit.background(PaletteFormat::PLANAR_2BPP, 0x1F), (0x11, 0x22, 0x33));
+    }
+
+    #[test]
+    fn test_color_profiles() {
+        let mut unit = PaletteUnit::new();
+        unit.palette_ram[0] = 0x0F00;
+        unit.palette_ram[1] = 0x0888;
+
+        unit.set_colors(ColorSettings {profile: ColorProfile::WscLcd, saturation: 100});
+        // The LCD never shows a full black or white, and grays stay gray whatever the saturation
+        let (r, g, b) = unit.rgb(0, 0);
+        assert!(r < 232 && g > 0 && g == b);
+        let (r, g, b) = unit.rgb(0, 1);
+        assert!(r == g && g == b);
+
+        unit.set_colors(ColorSettings {profile: ColorProfile::SwanCrystal, saturation: 0});
+        let (r, g, b) = unit.rgb(0, 0);
+        assert!(r == g && g == b);
+
+        unit.set_colors(ColorSettings::new());
+        assert_eq!(unit.rgb(0, 0), (0xFF, 0x00, 0x00));
+        assert_eq!(ColorProfile::SwanCrystal.levels()[0], 0);
+        assert_eq!(ColorProfile::SwanCrystal.levels()[15], 255);
+        assert!(ColorProfile::SwanCrystal.levels()[8] < ColorProfile::Raw.levels()[8]);
+    }
+}
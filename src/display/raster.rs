@@ -0,0 +1,104 @@
+use alloc::{format, string::{String, ToString}, vec::Vec};
+use core::fmt::Display;
+
+use crate::state::{fnv1a, FNV_OFFSET};
+
+use super::palette::{PaletteMode, PaletteUnit};
+
+/// Registers the display used to output a scanline, for debugging raster effects
+///
+/// Captured on the first dot each line is output.
+/// Scroll and layer registers are still read on every dot, so values changed partway through a line are not shown.
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub struct RasterLine {
+    /// Whether the LCD was switched on
+    pub lcd_enabled: bool,
+    /// DISP_CTRL and BACK_COLOR, ports 0x00-0x01
+    pub control: u16,
+    /// Screen 1's scroll, ports 0x10-0x11
+    pub scroll_1: (u8, u8),
+    /// Screen 2's scroll, ports 0x12-0x13
+    pub scroll_2: (u8, u8),
+    /// Whether colors came from palette RAM
+    pub color: bool,
+    /// The latched shade LUT, ports 0x1C-0x1F
+    pub shade_lut: [u8; 4],
+    /// Hash of the latched mono palettes and palette RAM, telling whether any palette changed between lines
+    pub palettes: u64,
+}
+
+impl RasterLine {
+    /// Captures the registers in use for a line
+    pub fn capture(lcd_enabled: bool, control: u16, scroll_1: (u8, u8), scroll_2: (u8, u8), palette: &PaletteUnit) -> Self {
+        let mut palettes = fnv1a(FNV_OFFSET, &palette.mono_palettes);
+        if palette.mode == PaletteMode::COLOR {
+            palettes = palette.palette_ram.iter().fold(palettes, |hash, color| fnv1a(hash, &color.to_le_bytes()));
+        }
+        Self {lcd_enabled, control, scroll_1, scroll_2, color: palette.mode == PaletteMode::COLOR, shade_lut: palette.shade_lut, palettes}
+    }
+
+    /// Lists the layers enabled, along with the windows applied to them
+    pub fn layers(&self) -> String {
+        if !self.lcd_enabled {return "off".to_string()}
+        let control = self.control;
+        let layers = [
+            (control & 0x01 != 0).then(|| "scr1".to_string()),
+            (control & 0x02 != 0).then(|| match (control & 0x20 != 0, control & 0x10 != 0) {
+                (false, _) => "scr2".to_string(),
+                (true, inside) => format!("scr2[{}]", if inside {"out"} else {"in"}),
+            }),
+            (control & 0x04 != 0).then(|| if control & 0x08 != 0 {"spr[win]"} else {"spr"}.to_string()),
+        ];
+        let layers: Vec<_> = layers.into_iter().flatten().collect();
+        if layers.is_empty() {"none".to_string()} else {layers.join(" ")}
+    }
+}
+
+impl Display for RasterLine {
+    fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
+        write!(f, "scr1 {:3},{:3}  scr2 {:3},{:3}  back {:02X}  lut {:08X}  {} {:016X}  {}",
+            self.scroll_1.0, self.scroll_1.1, self.scroll_2.0, self.scroll_2.1, self.control >> 8,
+            u32::from_le_bytes(self.shade_lut), if self.color {"ram"} else {"pal"}, self.palettes, self.layers())
+    }
+}
+
+/// Describes a frame's lines, merging consecutive lines with the same registers so that the lines where they change stand out
+pub fn report(lines: &[RasterLine]) -> String {
+    let mut report = String::new();
+    let mut start = 0;
+    for (i, line) in lines.iter().enumerate() {
+        if lines.get(i + 1) != Some(line) {
+            let range = if start == i {i.to_string()} else {format!("{}-{}", start, i)};
+            report += &format!("{:>7}  {}\n", range, line);
+            start = i + 1;
+        }
+    }
+    report
+}
+
+#[cfg(test)]
+#[cfg_attr(coverage_nightly, coverage(off))]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_raster_report() {
+        let palette = PaletteUnit::new();
+        let plain = RasterLine::capture(true, 0x0003, (0, 0), (0, 0), &palette);
+        let scrolled = RasterLine {scroll_1: (8, 0), ..plain};
+        let lines: Vec<_> = (0..144).map(|line| if (40..60).contains(&line) {scrolled} else {plain}).collect();
+
+        let report = report(&lines);
+        assert_eq!(report.lines().count(), 3);
+        assert!(report.lines().next().unwrap().trim_start().starts_with("0-39  scr1   0,  0"));
+        assert!(report.contains("40-59  scr1   8,  0"));
+        assert!(report.contains("60-143"));
+
+        assert_eq!(plain.layers(), "scr1 scr2");
+        assert_eq!(RasterLine {control: 0x3E, ..plain}.layers(), "scr2[out] spr[win]");
+        assert_eq!(RasterLine {lcd_enabled: false, ..plain}.layers(), "off");
+        let mut changed = PaletteUnit::new();
+        changed.mono_palettes[5] = 0x21;
+        assert_ne!(RasterLine::capture(true, 0x0003, (0, 0), (0, 0), &changed).palettes, plain.palettes);
+    }
+}
@@ -2,9 +2,7 @@
 /// 
 /// A sprite is a free moving tile of 8x8 pixels, this struct is used to describe sprites
 /// 
-/// # TODO
-/// 
-/// Sprite coordinates should wrap around within the visible section of the screen, this is not currently supported
+/// Sprite coordinates wrap around at 256, so a sprite placed near the right or bottom end of that range is partially drawn at the opposite edge of the screen.
 #[derive(Clone, Copy, PartialEq, Eq, Debug)]
 pub struct SpriteElement {
     /// Vertical mirroring
@@ -41,4 +39,15 @@ impl SpriteElement {
     pub fn dummy() -> Self {
         Self {vm: false, hm: false, pr: false, ct: false, palette: 0, tile_idx: 0, x: 0, y: 0}
     }
+
+    /// Whether the sprite covers part of a line
+    pub fn covers_line(&self, y: u8) -> bool {
+        y.wrapping_sub(self.y) < 8
+    }
+
+    /// Returns the position within the sprite of a pixel on screen, or None if the sprite does not cover it
+    pub fn offset(&self, x: u8, y: u8) -> Option<(u8, u8)> {
+        let (dx, dy) = (x.wrapping_sub(self.x), y.wrapping_sub(self.y));
+        (dx < 8 && dy < 8).then_some((dx, dy))
+    }
 }
\ No newline at end of file
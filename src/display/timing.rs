@@ -0,0 +1,64 @@
+/// Frequency of the clock driving the display, in Hz
+const CLOCK: u32 = 3_072_000;
+
+/// Lines shown on the LCD, the ones after them up to the end of the frame are vblank
+pub const VISIBLE_LINES: u8 = 144;
+
+/// How many lines and dots make up a frame of the LCD
+///
+/// The line counter in port 0x02 runs from 0 to the value of LCD_VTOTAL in port 0x16, lines 144 and onward being vblank.
+/// The IPL sets LCD_VTOTAL to 158 on every model, so games that leave it alone and count frames for their music or logic run at about 75.47 Hz.
+/// Values below 144 would leave no room for vblank and are treated as 144.
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub struct DisplayTiming {
+    /// Last line of a frame, counting from 0
+    pub last_line: u8,
+}
+
+impl DisplayTiming {
+    /// Dots in a line, including hblank, the dot counter is 8 bits wide and wraps around at the end of each line
+    pub const DOTS: usize = 256;
+    /// Value of LCD_VTOTAL left by the IPL
+    pub const DEFAULT_VTOTAL: u8 = 158;
+    /// Timing set by the IPL
+    pub const DEFAULT: Self = Self::from_vtotal(Self::DEFAULT_VTOTAL);
+    /// Longest frame LCD_VTOTAL can select
+    pub const LONGEST: Self = Self::from_vtotal(0xFF);
+
+    /// Returns the timing selected by a value of LCD_VTOTAL
+    pub const fn from_vtotal(vtotal: u8) -> Self {
+        Self {last_line: if vtotal < VISIBLE_LINES {VISIBLE_LINES} else {vtotal}}
+    }
+
+    /// Lines in a frame, including vblank
+    pub fn lines(&self) -> usize {
+        self.last_line as usize + 1
+    }
+
+    /// Ticks in a frame
+    pub fn frame_ticks(&self) -> usize {
+        self.lines() * Self::DOTS
+    }
+
+    /// Frames per second
+    pub fn refresh_rate(&self) -> f64 {
+        CLOCK as f64 / self.frame_ticks() as f64
+    }
+}
+
+#[cfg(test)]
+#[cfg_attr(coverage_nightly, coverage(off))]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_vtotal_timing() {
+        let timing = DisplayTiming::DEFAULT;
+        assert_eq!(timing.lines(), 159);
+        assert_eq!(timing.frame_ticks(), 40704);
+        assert!((timing.refresh_rate() - 75.47).abs() < 0.01);
+
+        assert_eq!(DisplayTiming::from_vtotal(0).lines(), 145);
+        assert_eq!(DisplayTiming::LONGEST.frame_ticks(), 256 * 256);
+    }
+}
@@ -1,6 +1,7 @@
-use std::{cell::RefCell, rc::Rc};
+use alloc::{rc::Rc, vec::Vec};
+use core::cell::RefCell;
 
-use crate::{bus::{io_bus::{IOBus, IOBusConnection}, mem_bus::{MemBus, MemBusConnection, Owner}}, dma::DMA};
+use crate::{bus::{io_bus::{IOBus, IOBusConnection}, mem_bus::{Accessor, MemBus, MemBusConnection, Owner}}, dma::{DmaState, DmaTransition, DMA}, state::{Snapshot, StateError, StateReader, StateWriter}};
 
 /// General DMA
 /// 
@@ -28,15 +29,20 @@ pub struct GDMA {
     /// 
     /// If set the addresses will be decremented after each transfer, otherwise they will be incremented.
     dir: bool,
+    /// Control port as last read, kept as reading the port clears it
+    control: u8,
+
+    /// Transfers started and completed since the SoC last took them
+    transitions: Vec<(DmaTransition, DmaState)>,
 }
 
 impl MemBusConnection for GDMA {
     fn read_mem(&mut self, addr: u32) -> u8 {
-        self.mem_bus.borrow_mut().read_mem(addr)
+        self.mem_bus.borrow_mut().read_by(addr, Accessor::GDMA)
     }
 
     fn write_mem(&mut self, addr: u32, byte: u8) {
-        self.mem_bus.borrow_mut().write_mem(addr, byte);
+        self.mem_bus.borrow_mut().write_by(addr, byte, Accessor::GDMA);
     }
 }
 
@@ -48,6 +54,22 @@ impl IOBusConnection for GDMA {
     fn write_io(&mut self, addr: u16, byte: u8) {
         self.io_bus.borrow_mut().write_io(addr, byte);
     }
+
+    fn write_io_16(&mut self, addr: u16, word: u16) {
+        self.io_bus.borrow_mut().write_io_16(addr, word);
+    }
+}
+
+impl core::fmt::Debug for GDMA {
+    fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
+        f.debug_struct("GDMA")
+            .field("cycles", &self.cycles)
+            .field("src_addr", &format_args!("{:05X}", self.src_addr))
+            .field("dest_addr", &format_args!("{:04X}", self.dest_addr))
+            .field("counter", &format_args!("{:04X}", self.counter))
+            .field("dir", &self.dir)
+            .finish()
+    }
 }
 
 impl DMA for GDMA {
@@ -55,7 +77,7 @@ impl DMA for GDMA {
         if !self.io_bus.borrow_mut().color_mode() {return false}
         
         let ctrl = self.read_io(0x48);
-        // if ctrl != 0 {println!("DMA ctrl: {:02X}", ctrl)};
+        self.control = ctrl;
         self.dir = ctrl & 0x40 != 0;
         ctrl & 0x80 != 0
     }
@@ -63,7 +85,6 @@ impl DMA for GDMA {
     fn start_op(&mut self) {
         self.get_counter();
         if self.counter != 0 {
-            // println!("counter: {:02X}", self.counter);
             self.get_src_addr();
             match self.src_addr {
                 0x10000..=0x1FFFF => return,
@@ -71,7 +92,7 @@ impl DMA for GDMA {
                     self.cycles = 7;
                     self.get_dest_addr();
                     self.mem_bus.borrow_mut().owner = Owner::DMA;
-                    // println!("dest_addr: {:04X}", self.dest_addr)
+                    self.transitions.push((DmaTransition::Start, self.state()));
                 }
             }
         }
@@ -85,8 +106,6 @@ impl DMA for GDMA {
             let byte = self.read_mem(self.src_addr);
             self.write_mem(self.dest_addr as u32, byte);
 
-            // println!("DMA: [{:05X}] <- [{:05X}] = {:02X}", self.dest_addr, self.src_addr, byte);
-
             if self.dir {
                 self.src_addr = self.src_addr.wrapping_sub(1);
                 self.dest_addr = self.dest_addr.wrapping_sub(1);
@@ -95,11 +114,7 @@ impl DMA for GDMA {
                 self.dest_addr = self.dest_addr.wrapping_add(1);
             }
 
-            if (0x10000..=0x1FFFF).contains(&self.src_addr) {
-                self.cycles = 0;
-                self.mem_bus.borrow_mut().owner = Owner::NONE;
-            }
-
+            // The transfer ends with its counter, or early if the source enters SRAM
             self.counter -= 1;
             if self.counter == 0 {
                 self.write_io_16(0x40, self.src_addr as u16);
@@ -108,17 +123,29 @@ impl DMA for GDMA {
                 self.write_io_16(0x46, 0);
                 let ctrl = self.read_io(0x48);
                 self.write_io(0x48, ctrl & 0x7F);
-                self.cycles = 0;
-                self.mem_bus.borrow_mut().owner = Owner::NONE;
+                self.control &= 0x7F;
+            } else if !(0x10000..=0x1FFFF).contains(&self.src_addr) {
+                return
             }
+            self.cycles = 0;
+            self.mem_bus.borrow_mut().owner = Owner::NONE;
+            self.transitions.push((DmaTransition::Complete, self.state()));
         }
     }
+
+    fn state(&self) -> DmaState {
+        DmaState {src_addr: self.src_addr, dest_addr: Some(self.dest_addr), counter: self.counter as u32, control: self.control, active: self.cycles > 0}
+    }
+
+    fn take_transitions(&mut self) -> Vec<(DmaTransition, DmaState)> {
+        core::mem::take(&mut self.transitions)
+    }
 }
 
 impl GDMA {
     /// Generates a new GDMA
     pub fn new(mem_bus: Rc<RefCell<MemBus>>, io_bus: Rc<RefCell<IOBus>>) -> Self {
-        Self {mem_bus, io_bus, cycles: 0, src_addr: 0, dest_addr: 0, counter: 0, dir: false}
+        Self {mem_bus, io_bus, cycles: 0, src_addr: 0, dest_addr: 0, counter: 0, dir: false, control: 0, transitions: Vec::new()}
     }
 
     /// Reads the source address from the appropriate I/O ports
@@ -140,4 +167,26 @@ impl GDMA {
         let (lo, hi) = self.read_io_16(0x46);
         self.counter = u16::from_le_bytes([lo, hi]);
     }
-}
\ No newline at end of file
+}
+
+/// The general DMA's state contains the transfer in progress, the ports it was started from are saved along with the I/O bus
+impl Snapshot for GDMA {
+    const TAG: [u8; 4] = *b"GDMA";
+    const VERSION: u16 = 2;
+
+    fn save_fields(&self, w: &mut StateWriter) {
+        w.write_u8(self.cycles);
+        w.write_u32(self.src_addr);
+        w.write_u16(self.dest_addr);
+        w.write_u16(self.counter);
+        w.write_bool(self.dir);
+        w.write_u8(self.control);
+    }
+
+    fn load_fields(&mut self, r: &mut StateReader, version: u16) -> Result<(), StateError> {
+        (self.cycles, self.src_addr, self.dest_addr, self.counter, self.dir) = (r.read_u8()?, r.read_u32()?, r.read_u16()?, r.read_u16()?, r.read_bool()?);
+        // Version 1 did not have the control port, which only holds the enable and direction bits
+        self.control = if version >= 2 {r.read_u8()?} else {((self.cycles > 0) as u8 * 0x80) | ((self.dir as u8) << 6)};
+        Ok(())
+    }
+}
@@ -1,3 +1,6 @@
+use alloc::vec::Vec;
+use core::fmt;
+
 /// General DMA
 pub mod gdma;
 /// Sound DMA
@@ -13,4 +16,43 @@ pub trait DMA {
     /// 
     /// DMAs do not receive their own master clock quadrant and instead hijack the CPU's quadrant
     fn tick(&mut self);
-}
\ No newline at end of file
+    /// Returns the transfer in progress, or the last one if none is
+    fn state(&self) -> DmaState;
+    /// Returns the transfers started and completed since the last call, oldest first
+    fn take_transitions(&mut self) -> Vec<(DmaTransition, DmaState)>;
+}
+
+/// The registers of a DMA, for debuggers
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub struct DmaState {
+    /// Address the next byte is read from
+    pub src_addr: u32,
+    /// Address the next byte is written to, the sound DMA always writing to channel 2's sample port
+    pub dest_addr: Option<u16>,
+    /// Bytes left to transfer
+    pub counter: u32,
+    /// Value of the control port
+    pub control: u8,
+    /// Whether a transfer is in progress
+    pub active: bool,
+}
+
+impl fmt::Display for DmaState {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "src={:05X} ", self.src_addr)?;
+        match self.dest_addr {
+            Some(dest_addr) => write!(f, "dest={:04X} ", dest_addr)?,
+            None => write!(f, "dest=port 89 ")?,
+        }
+        write!(f, "counter={:05X} control={:02X} {}", self.counter, self.control, if self.active {"active"} else {"idle"})
+    }
+}
+
+/// A change in whether a DMA is transferring
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum DmaTransition {
+    /// A transfer started
+    Start,
+    /// A transfer ended, either because its counter reached 0 or because the general DMA's source entered SRAM
+    Complete,
+}
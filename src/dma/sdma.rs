@@ -1,10 +1,24 @@
-use std::{cell::RefCell, rc::Rc};
+use alloc::{rc::Rc, vec::Vec};
+use core::cell::RefCell;
 
-use crate::{bus::{io_bus::{IOBus, IOBusConnection}, mem_bus::{MemBus, MemBusConnection}}, dma::DMA};
+use crate::{bus::{io_bus::{IOBus, IOBusConnection}, mem_bus::{Accessor, MemBus, MemBusConnection}}, dma::{DmaState, DmaTransition, DMA}, state::{Snapshot, StateError, StateReader, StateWriter}};
 
 /// Sound DMA
 /// 
 /// This component is used for transferring 8-bit audio samples into channel 2, used mainly for voice clips
+///
+/// SDMA_CTRL (0x52) starts transfers and decides how they run:
+///
+/// | Bit | Meaning                                                                          |
+/// |-----|----------------------------------------------------------------------------------|
+/// | 7   | Enable, cleared once a transfer that does not repeat completes                   |
+/// | 6   | Direction, the source address is decremented after each sample when set          |
+/// | 4   | Target, channel 2's PCM sample port 0x89 when clear, Hyper Voice's 0x95 when set |
+/// | 3   | Repeat, the source and length start over from their initial values at the end   |
+/// | 2   | Hold, 0 is output instead of reading memory, without advancing                   |
+/// | 0-1 | Rate, 4000Hz, 6000Hz, 12000Hz or 24000Hz                                         |
+///
+/// The source address is in 0x4A-0x4C and the length in 0x4E-0x50, both 20-bit and updated as samples are transferred.
 pub struct SDMA {
     /// A reference to the shared memory bus
     mem_bus: Rc<RefCell<MemBus>>,
@@ -47,15 +61,18 @@ pub struct SDMA {
     /// 
     /// If not set, then the source address and counter getters will also write to the shadow.
     running: bool,
+
+    /// Transfers started and completed since the SoC last took them
+    transitions: Vec<(DmaTransition, DmaState)>,
 }
 
 impl MemBusConnection for SDMA {
     fn read_mem(&mut self, addr: u32) -> u8 {
-        self.mem_bus.borrow_mut().read_mem(addr)
+        self.mem_bus.borrow_mut().read_by(addr, Accessor::SDMA)
     }
 
     fn write_mem(&mut self, addr: u32, byte: u8) {
-        self.mem_bus.borrow_mut().write_mem(addr, byte);
+        self.mem_bus.borrow_mut().write_by(addr, byte, Accessor::SDMA);
     }
 }
 
@@ -67,6 +84,27 @@ impl IOBusConnection for SDMA {
     fn write_io(&mut self, addr: u16, byte: u8) {
         self.io_bus.borrow_mut().write_io(addr, byte);
     }
+
+    fn write_io_16(&mut self, addr: u16, word: u16) {
+        self.io_bus.borrow_mut().write_io_16(addr, word);
+    }
+}
+
+impl core::fmt::Debug for SDMA {
+    fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
+        f.debug_struct("SDMA")
+            .field("cycles", &self.cycles)
+            .field("src_addr", &format_args!("{:05X}", self.src_addr))
+            .field("counter", &format_args!("{:05X}", self.counter))
+            .field("src_shadow", &format_args!("{:05X}", self.src_shadow))
+            .field("counter_shadow", &format_args!("{:05X}", self.counter_shadow))
+            .field("dir", &self.dir)
+            .field("rep", &self.rep)
+            .field("hold", &self.hold)
+            .field("rate", &self.rate)
+            .field("running", &self.running)
+            .finish()
+    }
 }
 
 impl DMA for SDMA {
@@ -92,18 +130,24 @@ impl DMA for SDMA {
         if self.counter != 0 {
             self.get_src_addr();
             self.cycles = 7;
-            self.running = true;
+            // Every sample restarts the DMA, only the first one of a transfer starts it
+            if !self.running {
+                self.running = true;
+                self.transitions.push((DmaTransition::Start, self.state()));
+            }
         }
     }
 
     fn tick(&mut self) {
         self.cycles -= 1;
         if self.cycles == 0 {
+            // Hyper Voice is not emulated, its samples are only stored in its port
+            let target = if self.read_io(0x52) & 0x10 != 0 {0x95} else {0x89};
             if self.hold {
-                self.write_io(0x89, 0x00);
+                self.write_io(target, 0x00);
             } else {
                 let byte = self.read_mem(self.src_addr);
-                self.write_io(0x89, byte);
+                self.write_io(target, byte);
 
                 self.src_addr = if self.dir {
                     self.src_addr.wrapping_sub(1)
@@ -114,12 +158,16 @@ impl DMA for SDMA {
                 self.counter -= 1;
                 if self.counter == 0 {
                     if self.rep {
+                        // A repeating transfer completes and starts over in the same cycle
+                        self.transitions.push((DmaTransition::Complete, self.state()));
                         self.counter = self.counter_shadow;
                         self.src_addr = self.src_shadow;
+                        self.transitions.push((DmaTransition::Start, self.state()));
                     } else {
                         let ctrl = self.read_io(0x52);
                         self.write_io(0x52, ctrl & 0x7F);
                         self.running = false;
+                        self.transitions.push((DmaTransition::Complete, self.state()));
                     }
                 }
 
@@ -128,6 +176,15 @@ impl DMA for SDMA {
             }
         }
     }
+
+    fn state(&self) -> DmaState {
+        let control = self.io_bus.borrow().peek_ports()[0x52];
+        DmaState {src_addr: self.src_addr, dest_addr: None, counter: self.counter, control, active: self.running}
+    }
+
+    fn take_transitions(&mut self) -> Vec<(DmaTransition, DmaState)> {
+        core::mem::take(&mut self.transitions)
+    }
 }
 
 impl SDMA {
@@ -143,7 +200,8 @@ impl SDMA {
             dir: false, rep: false, hold: false,
             rate: 1,
             
-            running: false
+            running: false,
+            transitions: Vec::new(),
         }
     }
 
@@ -175,9 +233,98 @@ impl SDMA {
 
     /// Writes the source address to the appropriate I/O port
     fn write_src_addr(&mut self) {
-        let offset = self.counter as u16;
-        let segment = ((self.counter >> 16) & 0x0F) as u8;
+        let offset = self.src_addr as u16;
+        let segment = ((self.src_addr >> 16) & 0x0F) as u8;
         self.write_io_16(0x4A, offset);
         self.write_io(0x4C, segment);
     }
-}
\ No newline at end of file
+}
+
+/// The sound DMA's state contains the transfer in progress and its shadows
+impl Snapshot for SDMA {
+    const TAG: [u8; 4] = *b"SDMA";
+    const VERSION: u16 = 1;
+
+    fn save_fields(&self, w: &mut StateWriter) {
+        w.write_u8(self.cycles);
+        for value in [self.src_addr, self.counter, self.src_shadow, self.counter_shadow] {w.write_u32(value)}
+        for flag in [self.dir, self.rep, self.hold, self.running] {w.write_bool(flag)}
+        w.write_u8(self.rate);
+    }
+
+    fn load_fields(&mut self, r: &mut StateReader, _version: u16) -> Result<(), StateError> {
+        let cycles = r.read_u8()?;
+        let addresses = [r.read_u32()?, r.read_u32()?, r.read_u32()?, r.read_u32()?];
+        let flags = [r.read_bool()?, r.read_bool()?, r.read_bool()?, r.read_bool()?];
+        self.rate = r.read_u8()?;
+        self.cycles = cycles;
+        [self.src_addr, self.counter, self.src_shadow, self.counter_shadow] = addresses;
+        [self.dir, self.rep, self.hold, self.running] = flags;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+#[cfg_attr(coverage_nightly, coverage(off))]
+mod test {
+    use alloc::{boxed::Box, vec, vec::Vec};
+
+    use crate::{bus::{io_bus::IOBusConnection, mem_bus::MemBusConnection}, cartridge::Mapper, options::{EmulatorOptions, SharedOptions}, soc::SoC, sound::buffer::SampleBuffer};
+
+    /// Ticks of the master clock between two samples at 24kHz
+    const SAMPLE_TICKS: usize = 128;
+
+    /// Returns a color SoC, boxed as it is too large for the test threads' stacks, in color mode with voice mode on and samples in WRAM at 0x1000, the SDMA set to play them from there
+    fn voice_soc(ctrl: u8) -> Box<SoC> {
+        // The ROM is a jump to itself at the reset vector
+        let mut rom = vec![0; 0x10000];
+        rom[0xFFF0..0xFFF2].copy_from_slice(&[0xEB, 0xFE]);
+        let mut soc = Box::new(SoC::new(vec![0; 0x10000], Vec::new(), Vec::new(), rom, Mapper::B_2001, true, SampleBuffer::shared(), SharedOptions::new(EmulatorOptions {color: true, ..EmulatorOptions::new()}), 0));
+        soc.write_io(0x60, 0x80);
+        soc.write_io(0x90, 0x20);
+        soc.write_io(0x94, 0x0F);
+        for (offset, sample) in [0x10, 0x20, 0x30, 0x40].into_iter().enumerate() {soc.write_mem(0x1000 + offset as u32, sample)}
+        soc.write_io_16(0x4A, 0x1000);
+        soc.write_io(0x4C, 0x00);
+        soc.write_io_16(0x4E, 0x0004);
+        soc.write_io(0x50, 0x00);
+        soc.write_io(0x52, ctrl);
+        soc
+    }
+
+    #[test]
+    fn test_voice_transfer() {
+        let mut soc = voice_soc(0x83);
+        let mut played = Vec::new();
+        for _ in 0..6 * SAMPLE_TICKS {
+            soc.tick();
+            let pcm = soc.read_io(0x89);
+            if played.last() != Some(&pcm) {played.push(pcm)}
+        }
+        assert_eq!(played, [0x00, 0x10, 0x20, 0x30, 0x40]);
+        // The registers follow the transfer, which stops at its end
+        assert_eq!((soc.read_io(0x4A), soc.read_io(0x4B), soc.read_io(0x4E)), (0x04, 0x10, 0x00));
+        assert_eq!(soc.read_io(0x52) & 0x80, 0);
+    }
+
+    #[test]
+    fn test_voice_repeat_and_rate() {
+        // 12kHz repeating backwards from the last sample
+        let mut soc = voice_soc(0x80 | 0x40 | 0x08 | 0x02);
+        soc.write_io_16(0x4A, 0x1003);
+        let mut played = Vec::new();
+        for _ in 0..2 * 10 * SAMPLE_TICKS {
+            soc.tick();
+            let pcm = soc.read_io(0x89);
+            if played.last() != Some(&pcm) {played.push(pcm)}
+        }
+        assert_eq!(played, [0x00, 0x40, 0x30, 0x20, 0x10, 0x40, 0x30, 0x20, 0x10, 0x40, 0x30]);
+        assert_ne!(soc.read_io(0x52) & 0x80, 0);
+
+        // Hyper Voice samples do not reach channel 2
+        let mut soc = voice_soc(0x80 | 0x10 | 0x03);
+        for _ in 0..3 * SAMPLE_TICKS {soc.tick();}
+        assert_eq!(soc.read_io(0x89), 0x00);
+        assert_eq!((soc.read_io(0x95), soc.read_io(0x4E)), (0x20, 0x02));
+    }
+}
@@ -0,0 +1,425 @@
+use std::{panic::{self, AssertUnwindSafe}, rc::Rc, sync::mpsc::{self, Receiver, Sender, SyncSender, TryRecvError, TrySendError}, thread::{self, JoinHandle}, time::{Duration, Instant}};
+
+use crate::{bus::io_bus::keypad::Keys, cartridge::{autosave::AutoSaver, header::SaveType}, cpu::v30mz::InvalidOpcode, headless::{self, InputRecorder, InputSource, ScriptedInput}, options::SharedOptions, osd, owner::Owner, pacing::{self, FrameTimings, Phase}, png::encode_png, regress::hash_frame, save_game, speed::Speed, soc::{diagnostics, lockup::{Lockup, LockupDetector, LOCKUP_FRAMES}, power::PowerState, profiler::FrameProfile, SoC}};
+
+/// Amount of frames between writes of changed SRAM pages to the journal
+const JOURNAL_FRAMES: u32 = 4;
+/// Amount of frames each printed performance profile is averaged over, about a second
+const PROFILE_FRAMES: u32 = 75;
+
+/// A finished frame, 224x144 pixels of 24-bit RGB
+pub type Frame = Box<[u8; 3 * 224 * 144]>;
+
+/// Requests sent by the front-end to the emulation thread
+pub enum Command {
+    /// A console button was pressed or released
+    Key(Keys, bool),
+    /// Write a bug report next to the ROM
+    BugReport,
+    /// Send the owner settings stored in the internal EEPROM back
+    ReadOwner(Sender<Owner>),
+    /// Store owner settings in the internal EEPROM
+    WriteOwner(Owner),
+    /// Show or hide the heat map of memory accesses on the frames sent back
+    HeatMap(bool),
+    /// Show or hide the oscilloscope of the sound channels on the frames sent back
+    Scope(bool),
+    /// Report the battery as running low or not
+    LowBattery(bool),
+    /// Start capturing audio next to the ROM, or finish the capture in progress
+    AudioCapture,
+    /// Start recording the buttons pressed as a macro, replacing any recording in progress
+    RecordMacro,
+    /// Stop recording and send the macro back
+    FinishMacro(Sender<ScriptedInput>),
+    /// Play a macro back from the next frame on, on top of the buttons held
+    PlayMacro(ScriptedInput),
+    /// How long the front-end took to upload and present a frame
+    PresentTime(Duration),
+    /// Print the summary of the frame timings and write them in full next to the ROM
+    TimingReport,
+    /// Stop or go on emulating, while paused the last frame keeps being sent back so that menus can be drawn over it
+    Pause(bool),
+    /// Save the state of the console next to the ROM as \[game\].state, and send back what happened
+    SaveState(Sender<String>),
+    /// Load the state saved next to the ROM, and send back what happened
+    LoadState(Sender<String>),
+    /// Save the last frame next to the ROM as \[game\]-screenshot-N.png, and send back what happened
+    Screenshot(Sender<String>),
+    /// Restart the game from the state it was built in, keeping its save media
+    Reset,
+    /// Save the game and stop emulating
+    Quit,
+}
+
+/// What went on while emulating, handed back when the emulation thread stops
+#[derive(Clone, Copy, Debug, Default)]
+pub struct Session {
+    /// Frames emulated
+    pub frames: u64,
+    /// The first invalid instruction the CPU stopped at
+    pub fault: Option<InvalidOpcode>,
+    /// The first lockup the game got into
+    pub lockup: Option<Lockup>,
+}
+
+impl Session {
+    /// Describes the session in a sentence, for compatibility reports
+    pub fn describe(&self) -> String {
+        let mut description = format!("{} frames", self.frames);
+        if let Some(fault) = self.fault {
+            description += &format!(", invalid instruction {:02X} at {:05X}", fault.code, fault.address);
+        }
+        if let Some(lockup) = self.lockup {
+            description += &format!(", {}", lockup);
+        }
+        description
+    }
+}
+
+/// Tells the front-end which frames are worth presenting
+///
+/// Paused games, menus and still screens hand the same picture over frame after frame,
+/// uploading and presenting it again only keeps the GPU busy, so frames are compared by their hash with the last one presented.
+/// The hash is taken after the front-end's overlays have been drawn, so that changes to them are still presented.
+#[derive(Clone, Copy, Debug, Default)]
+pub struct PresentFilter {
+    /// Hash of the last frame presented, `None` when the next frame has to be presented regardless
+    last: Option<u64>,
+    /// Frames skipped since the filter was made
+    pub skipped: u64,
+}
+
+impl PresentFilter {
+    /// Returns whether the frame differs from the last one presented, remembering it if so
+    pub fn should_present(&mut self, frame: &[u8]) -> bool {
+        let hash = hash_frame(frame);
+        if self.last == Some(hash) {
+            self.skipped += 1;
+            return false;
+        }
+        self.last = Some(hash);
+        true
+    }
+
+    /// Makes the next frame be presented, for when the window was resized, exposed or rotated and has to be drawn again
+    pub fn invalidate(&mut self) {
+        self.last = None;
+    }
+}
+
+/// Handle to the thread running the SoC
+///
+/// SDL's window, canvas and event pump have to stay on the thread that initialized video, so the front-end keeps them on the main thread
+/// and the SoC, which is not `Send`, is built and run on its own thread instead.
+/// Finished frames are handed to the front-end over a channel with room for a single frame.
+/// If the front-end is still presenting the previous one, for example while waiting on vsync, the new frame is dropped instead of stalling emulation.
+/// Presented frames are sent back to be reused, so that frames are double-buffered instead of allocated each time.
+pub struct EmulationThread {
+    /// The emulation thread itself, finishes once the game has been saved
+    handle: JoinHandle<Session>,
+    /// Commands for the emulation thread
+    commands: Sender<Command>,
+    /// Frames finished by the emulation thread
+    pub frames: Receiver<Frame>,
+    /// Frames that have been presented and can be reused
+    recycled: Sender<Frame>,
+}
+
+impl EmulationThread {
+    /// Starts emulating on a new thread
+    ///
+    /// `build` is called on the new thread to create the SoC. When a game is given its saves are journaled and written when quitting.
+    pub fn spawn<F>(build: F, game: Option<String>, color: bool, options: SharedOptions) -> Self
+    where F: FnOnce() -> SoC + Send + 'static {
+        let (commands, command_rx) = mpsc::channel();
+        let (frame_tx, frames) = mpsc::sync_channel(1);
+        let (recycled, recycled_rx) = mpsc::channel();
+
+        let handle = thread::spawn(move || run(build(), game, color, options, command_rx, frame_tx, recycled_rx));
+        Self {handle, commands, frames, recycled}
+    }
+
+    /// Sends a command to the emulation thread, commands sent after it has stopped are ignored
+    pub fn send(&self, command: Command) {
+        let _ = self.commands.send(command);
+    }
+
+    /// Returns a presented frame to the emulation thread
+    pub fn recycle(&self, frame: Frame) {
+        let _ = self.recycled.send(frame);
+    }
+
+    /// Asks the emulation thread to save and stop, then waits for it to do so and returns what went on
+    pub fn quit(self) -> Session {
+        self.send(Command::Quit);
+        self.handle.join().unwrap()
+    }
+}
+
+/// The emulation thread's loop, runs until told to quit or until the front-end goes away
+fn run(mut soc: SoC, game: Option<String>, color: bool, options: SharedOptions, commands: Receiver<Command>, frames: SyncSender<Frame>, recycled: Receiver<Frame>) -> Session {
+    let mut auto_saver = game.as_ref().and_then(|game| {
+        let cartridge = Rc::clone(&soc.io_bus.borrow().cartridge);
+        let sram = &cartridge.borrow().sram;
+        if sram.is_empty() {return None}
+        AutoSaver::spawn(&format!("{}.sram", game), sram).map_err(|e| println!("Could not open SRAM journal: {}", e)).ok()
+    });
+    let mut journal_frames = 0;
+
+    let mut previous: Option<Instant> = None;
+    let mut session = Session::default();
+    let mut lockups = LockupDetector::new(LOCKUP_FRAMES);
+    let mut spare = None;
+    let (mut profile, mut profile_frames) = (FrameProfile::default(), 0);
+    let mut heat_map = false;
+    let mut power = PowerState::On;
+    let mut probed = None;
+    // Buttons held on the front-end, the macro being recorded and the macro being played along with the frame it started on
+    let mut held = Keys::empty();
+    let mut recorder: Option<InputRecorder> = None;
+    let mut playback: Option<(u64, ScriptedInput)> = None;
+    let mut timings = FrameTimings::default();
+    let mut paused = false;
+    // What resets go back to, taken before the first frame
+    let boot = soc.save();
+    let name = game.clone().unwrap_or("wonderswan".to_string());
+
+    loop {
+        loop {
+            match commands.try_recv() {
+                Ok(Command::Key(key, pressed)) => {
+                    soc.io_bus.borrow_mut().set_key(key, pressed);
+                    held.set(key, pressed);
+                    if let Some(recorder) = &mut recorder {recorder.record(soc.frames(), held)}
+                }
+                Ok(Command::BugReport) => match diagnostics::write_bug_report(&soc, game.as_deref().unwrap_or("wonderswan")) {
+                    Ok(path) => println!("Wrote bug report to {}", path),
+                    Err(e) => println!("Could not write bug report: {}", e),
+                },
+                Ok(Command::ReadOwner(reply)) => {let _ = reply.send(soc.io_bus.borrow().owner());}
+                Ok(Command::WriteOwner(owner)) => soc.io_bus.borrow_mut().set_owner(&owner),
+                Ok(Command::HeatMap(shown)) => heat_map = shown,
+                Ok(Command::Scope(shown)) => soc.set_scope(shown),
+                Ok(Command::LowBattery(low)) => soc.set_low_battery(low),
+                Ok(Command::AudioCapture) => match soc.stop_audio_capture() {
+                    Ok(Some(samples)) => println!("Captured {} samples", samples),
+                    Ok(None) => {
+                        let path = headless::capture_path(game.as_deref().unwrap_or("wonderswan"));
+                        match soc.start_audio_capture(&path) {
+                            Ok(()) => println!("Capturing audio to {}", path),
+                            Err(e) => println!("Could not capture audio: {}", e),
+                        }
+                    }
+                    Err(e) => println!("Could not finish audio capture: {}", e),
+                },
+                Ok(Command::RecordMacro) => recorder = Some(InputRecorder::new(soc.frames(), held)),
+                Ok(Command::FinishMacro(reply)) => {
+                    let recorded = recorder.take().map(|recorder| recorder.finish(soc.frames())).unwrap_or_default();
+                    let _ = reply.send(recorded);
+                }
+                Ok(Command::PlayMacro(recorded)) => playback = Some((soc.frames(), recorded)),
+                Ok(Command::PresentTime(time)) => timings.add(Phase::Present, time),
+                Ok(Command::TimingReport) => {
+                    println!("{}", timings.summary());
+                    match pacing::write_report(&timings, game.as_deref().unwrap_or("wonderswan")) {
+                        Ok(path) => println!("Wrote frame timings to {}", path),
+                        Err(e) => println!("Could not write frame timings: {}", e),
+                    }
+                }
+                Ok(Command::Pause(pause)) => paused = pause,
+                Ok(Command::SaveState(reply)) => {
+                    let path = format!("{}.state", name);
+                    let _ = reply.send(match std::fs::write(&path, soc.save()) {
+                        Ok(()) => format!("Saved state to {}", path),
+                        Err(e) => format!("Could not save state: {}", e),
+                    });
+                }
+                Ok(Command::LoadState(reply)) => {
+                    let path = format!("{}.state", name);
+                    let _ = reply.send(match std::fs::read(&path).map_err(|e| e.to_string()).and_then(|state| soc.load(&state).map_err(|e| e.to_string())) {
+                        Ok(()) => format!("Loaded state from {}", path),
+                        Err(e) => format!("Could not load state: {}", e),
+                    });
+                }
+                Ok(Command::Screenshot(reply)) => {
+                    let path = (1..).map(|n| format!("{}-screenshot-{}.png", name, n)).find(|path| !std::path::Path::new(path).exists()).unwrap();
+                    let _ = reply.send(match std::fs::write(&path, encode_png(224, 144, &soc.get_lcd().borrow()[..])) {
+                        Ok(()) => format!("Saved screenshot to {}", path),
+                        Err(e) => format!("Could not save screenshot: {}", e),
+                    });
+                }
+                Ok(Command::Reset) => match soc.load_keeping_saves(&boot) {
+                    Ok(()) => println!("Reset"),
+                    Err(e) => println!("Could not reset: {}", e),
+                },
+                Ok(Command::Quit) | Err(TryRecvError::Disconnected) => {
+                    // The journal is only removed once every snapshot reached it and the SRAM file has been written in full
+                    if let Err(e) = soc.stop_audio_capture() {println!("Could not finish audio capture: {}", e)}
+                    if let Some(report) = soc.opcode_report() {
+                        let path = format!("{}-opcodes.txt", game.as_deref().unwrap_or("wonderswan"));
+                        match std::fs::write(&path, report) {
+                            Ok(()) => println!("Wrote instruction counts to {}", path),
+                            Err(e) => println!("Could not write instruction counts: {}", e),
+                        }
+                    }
+                    let journal = auto_saver.take().and_then(AutoSaver::finish);
+                    if let Some(game) = &game {save_game(Rc::clone(&soc.io_bus), color, game)};
+                    if let Some(journal) = journal {journal.close().unwrap()};
+                    return session;
+                }
+                Err(TryRecvError::Empty) => break,
+            }
+        }
+
+        // While paused nothing is emulated, the last frame is sent again at the same pace for the front-end's menus to be drawn over
+        let emulation_time = if paused {Duration::ZERO} else {
+            // Macros press their buttons along with the held ones, which are all that is left held once the macro is over
+            if let Some((start, recorded)) = &mut playback {
+                let frame = soc.frames() - *start;
+                headless::hold_keys(&mut soc, recorded.keys(frame) | held);
+                if frame >= recorded.last_frame() {playback = None}
+            }
+
+            // A panicking frame still leaves its screen and state behind for triage before the thread goes down
+            let emulation_start = Instant::now();
+            if let Err(payload) = panic::catch_unwind(AssertUnwindSafe(|| soc.run_frame())) {
+                triage(&soc, game.as_deref(), &options, &format!("panic on frame {}", soc.frames()));
+                panic::resume_unwind(payload);
+            }
+            session.frames += 1;
+            let emulation_time = emulation_start.elapsed();
+            timings.add(Phase::Emulation, emulation_time);
+
+            if let (Some(fault), None) = (soc.cpu.fault, session.fault) {
+                println!("CPU stopped at invalid instruction {:02X} at {:05X}", fault.code, fault.address);
+                session.fault = Some(fault);
+                triage(&soc, game.as_deref(), &options, &format!("invalid instruction {:02X} at {:05X}", fault.code, fault.address));
+            }
+            if soc.power_state() != power {
+                power = soc.power_state();
+                println!("Power: {}", power.name());
+            }
+            if soc.probed_save() != probed {
+                probed = soc.probed_save();
+                match probed {
+                    Some(SaveType::Sram(size)) => println!("Warning: the game wrote to SRAM although its footer declares no save memory, keeping {} KB of it", size / 1024),
+                    Some(SaveType::Eeprom(size)) => println!("Warning: the game used an EEPROM although its footer declares no save memory, keeping {} B of it", size),
+                    _ => {}
+                }
+                // Probed SRAM is journaled from then on like declared SRAM, probed EEPROM is saved when quitting like declared EEPROM
+                if let (Some(SaveType::Sram(_)), None, Some(game)) = (probed, &auto_saver, &game) {
+                    let cartridge = Rc::clone(&soc.io_bus.borrow().cartridge);
+                    auto_saver = AutoSaver::spawn(&format!("{}.sram", game), &cartridge.borrow().sram).map_err(|e| println!("Could not open SRAM journal: {}", e)).ok();
+                }
+            }
+            if let Some(lockup) = lockups.check(&soc) {
+                println!("The game appears to have crashed, the CPU is {}", lockup);
+                session.lockup = session.lockup.or(Some(lockup));
+                triage(&soc, game.as_deref(), &options, &format!("lockup, the CPU is {}", lockup));
+            }
+
+            if let Some(frame_profile) = soc.profile() {
+                profile.add(&frame_profile);
+                profile_frames += 1;
+                if profile_frames == PROFILE_FRAMES {
+                    println!("Frame time: {}", profile.average(profile_frames));
+                    (profile, profile_frames) = (FrameProfile::default(), 0);
+                }
+            }
+
+            journal_frames += 1;
+            if journal_frames >= JOURNAL_FRAMES {
+                journal_frames = 0;
+                if let Some(saver) = &auto_saver {
+                    let cartridge = Rc::clone(&soc.io_bus.borrow().cartridge);
+                    saver.submit(cartridge.borrow_mut().snapshot_sram());
+                }
+            }
+            emulation_time
+        };
+
+        // Pace emulation here rather than on the front-end's presents
+        let now = Instant::now();
+        let delta = previous.map_or(Duration::ZERO, |previous| now - previous);
+        // A paused game is sent at normal speed, which is plenty for menus, rather than as fast as possible when the speed is unlimited
+        let frame_time = if paused {Speed::Normal.frame_time()} else {options.get().speed.speed.frame_time()};
+        std::thread::sleep(frame_time.saturating_sub(delta));
+        let woken = Instant::now();
+        if !paused {
+            timings.add(Phase::Sleep, woken - now);
+            if let Some(previous) = previous {timings.add_interval(soc.frames() - 1, woken - previous, emulation_time, frame_time)}
+        }
+        previous = Some(woken);
+
+        let mut frame = spare.take().or_else(|| recycled.try_recv().ok()).unwrap_or_else(|| Box::new([0; 3 * 224 * 144]));
+        frame.copy_from_slice(&soc.get_lcd().borrow()[..]);
+        // Post-processing of the game's picture, the overlays drawn over it keep their colors
+        options.get().grayscale.apply(&mut frame[..]);
+        osd::draw_power(&mut frame[..], power);
+        if heat_map {osd::draw_heat_map(&mut frame[..], soc.access_heat())}
+        if let Some(scope) = soc.scope() {osd::draw_scope(&mut frame[..], scope)}
+        if lockups.locked() {osd::draw_banner(&mut frame[..], &["GAME APPEARS TO HAVE CRASHED", "F12 SAVES A BUG REPORT"])}
+        match frames.try_send(frame) {
+            Ok(()) => {}
+            Err(TrySendError::Full(frame)) => spare = Some(frame),
+            Err(TrySendError::Disconnected(_)) => {}
+        }
+    }
+}
+
+/// Saves a triage bundle of the SoC when the option is on
+fn triage(soc: &SoC, game: Option<&str>, options: &SharedOptions, reason: &str) {
+    if !options.get().triage {return}
+    match diagnostics::write_triage(soc, game.unwrap_or("wonderswan"), reason) {
+        Ok(path) => println!("Wrote triage bundle to {}", path),
+        Err(e) => println!("Could not write triage bundle: {}", e),
+    }
+}
+
+#[cfg(test)]
+#[cfg_attr(coverage_nightly, coverage(off))]
+mod test {
+    use crate::options::EmulatorOptions;
+
+    use super::*;
+
+    #[test]
+    fn test_frame_handoff() {
+        let options = SharedOptions::new(EmulatorOptions {mute: true, ..EmulatorOptions::new()});
+        let emulation = EmulationThread::spawn(SoC::test_build, None, false, options);
+
+        // Frames keep coming while the previous ones are not presented, and presented ones are reused
+        let frame = emulation.frames.recv_timeout(Duration::from_secs(10)).unwrap();
+        emulation.recycle(frame);
+        let frame = emulation.frames.recv_timeout(Duration::from_secs(10)).unwrap();
+        assert_eq!(frame.len(), 3 * 224 * 144);
+
+        emulation.send(Command::Key(Keys::Start, true));
+        // The macro holds Start from the frame recording started on until it finishes
+        emulation.send(Command::RecordMacro);
+        // A frame may already be waiting or be finished while the command is sent, the third one comes after recording started
+        for _ in 0..3 {emulation.recycle(emulation.frames.recv_timeout(Duration::from_secs(10)).unwrap())}
+        let (reply, recorded) = mpsc::channel();
+        emulation.send(Command::FinishMacro(reply));
+        let recorded = recorded.recv_timeout(Duration::from_secs(10)).unwrap();
+        assert!(recorded.to_script().starts_with("0 Start\n"));
+        emulation.send(Command::PlayMacro(recorded));
+        let session = emulation.quit();
+        assert!(session.frames >= 2);
+        assert_eq!(session.fault, None);
+    }
+
+    #[test]
+    fn test_present_filter() {
+        let mut filter = PresentFilter::default();
+        let mut frame = [0; 3 * 224 * 144];
+        assert!(filter.should_present(&frame));
+        assert!(!filter.should_present(&frame));
+        frame[100] = 1;
+        assert!(filter.should_present(&frame));
+        filter.invalidate();
+        assert!(filter.should_present(&frame));
+        assert_eq!(filter.skipped, 1);
+    }
+}
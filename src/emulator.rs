@@ -0,0 +1,209 @@
+use alloc::{boxed::Box, rc::Rc, string::String, vec::Vec};
+use core::cell::{Ref, RefCell};
+#[cfg(feature = "std")]
+use std::panic::{self, AssertUnwindSafe};
+
+use crate::{bus::io_bus::keypad::Keys, cartridge::header::RomInfo, headless::hold_keys, options::{EmulatorOptions, SharedOptions}, romdb::{self, GameInfo}, save_layout, soc::{frame::FrameReport, hooks::{HookEvent, HookId, Predicate}, SoC}, sound::buffer::{SampleBuffer, SharedSamples}, state::StateError, storage::{MemoryStorage, SaveMedia, Storage}, sync::{Arc, Mutex}};
+
+/// Width of the framebuffer in pixels, the screen in landscape orientation
+pub const SCREEN_WIDTH: usize = 224;
+/// Height of the framebuffer in pixels
+pub const SCREEN_HEIGHT: usize = 144;
+
+/// Most samples kept when the front-end does not take them, one second's worth, older ones are dropped
+pub const MAX_SAMPLES: usize = 24000;
+
+/// A console running a ROM, for front-ends and test harnesses embedding the emulator
+///
+/// Everything goes through memory: the ROM is given as bytes, save media go through a [`Storage`], and the frames, samples and states are handed back.
+/// Nothing here needs SDL or files, so the crate can be built without its sdl and std features for embedding, see the readme.
+/// The SoC itself stays reachable through [`Emulator::soc_mut`] for anything this does not cover, such as the debugger.
+pub struct Emulator {
+    /// The console
+    ///
+    /// Boxed as the SoC is large enough for the copies made while moving it around to overflow the stack.
+    soc: Box<SoC>,
+    /// The LCD of the SoC, holding the last finished frame
+    pub(crate) lcd: Rc<RefCell<[u8; 3 * SCREEN_WIDTH * SCREEN_HEIGHT]>>,
+    /// Samples produced by the SoC and not yet taken by the front-end
+    samples: SharedSamples,
+    /// Whether the ROM runs on a WonderSwan Color, which has its own internal EEPROM
+    color: bool,
+    /// Whether the game is played with the console held vertically
+    vertical: bool,
+}
+
+impl Emulator {
+    /// Loads a ROM image with the default options, returns an error if it is not a valid ROM
+    pub fn new(rom: Vec<u8>) -> Result<Self, String> {
+        Self::with_options(rom, SharedOptions::new(EmulatorOptions::new()), Arc::new(Mutex::new(SampleBuffer::new(MAX_SAMPLES))))
+    }
+
+    /// Loads a ROM image, with options and a sample buffer shared with the front-end, starting with blank save media
+    pub fn with_options(rom: Vec<u8>, options: SharedOptions, samples: SharedSamples) -> Result<Self, String> {
+        Self::with_storage(rom, "", &MemoryStorage::default(), options, samples)
+    }
+
+    /// Loads a ROM image along with the save media stored for the game, see [`SaveMedia`] for their names
+    ///
+    /// The ROM's footer decides the model, mapper and save media, with the ROM database's quirks applied.
+    pub fn with_storage(rom: Vec<u8>, game: &str, storage: &dyn Storage, options: SharedOptions, samples: SharedSamples) -> Result<Self, String> {
+        let info = romdb::lookup(&rom);
+        Self::with_info(rom, info, game, storage, options, samples)
+    }
+
+    /// Same as [`Emulator::with_storage`] with the game's database entry given, for front-ends that keep a database of their own
+    pub fn with_info(rom: Vec<u8>, info: Option<GameInfo>, game: &str, storage: &dyn Storage, options: SharedOptions, samples: SharedSamples) -> Result<Self, String> {
+        let header = RomInfo::parse(&rom)?;
+        let quirks = info.as_ref().map(|info| info.quirks).unwrap_or_default();
+        let (ram_size, sram) = save_layout(&header, info.as_ref());
+        let saves = SaveMedia::load(storage, game, header.color, ram_size);
+        samples.lock().unwrap().clear();
+        let soc_samples = Arc::clone(&samples);
+        options.update(|options| options.color = header.color);
+        let build = || Box::new(SoC::new(saves.sram, saves.ieeprom, saves.eeprom, rom, header.mapper, sram, soc_samples, options, header.port_a0_bits()));
+        #[cfg(feature = "std")]
+        let mut soc = panic::catch_unwind(AssertUnwindSafe(build)).map_err(|_| "The ROM could not be loaded".to_string())?;
+        // Panics cannot be caught without the standard library, a ROM the SoC cannot map stops the host like any other panic
+        #[cfg(not(feature = "std"))]
+        let mut soc = build();
+        soc.apply_quirks(quirks);
+        soc.restore_rtc(&saves.rtc);
+        let lcd = soc.get_lcd();
+        Ok(Self {soc, lcd, samples, color: header.color, vertical: header.vertical() || quirks.vertical})
+    }
+
+    /// Writes the save media of the game to the storage, for example when quitting or every few seconds
+    pub fn store_saves(&self, storage: &mut dyn Storage, game: &str) -> Result<(), String> {
+        SaveMedia::from_io_bus(&self.soc.io_bus.borrow()).store(storage, game, self.color)
+    }
+
+    /// Whether the game is played with the console held vertically, as its footer or the ROM database says
+    pub fn vertical(&self) -> bool {
+        self.vertical
+    }
+
+    /// Emulates a single frame and reports what happened during it
+    pub fn run_frame(&mut self) -> FrameReport {
+        self.soc.run_frame()
+    }
+
+    /// Returns the last finished frame, 224x144 pixels of 24-bit RGB in landscape orientation
+    ///
+    /// The frame has to be released before running the next one.
+    pub fn framebuffer(&self) -> Ref<'_, [u8; 3 * SCREEN_WIDTH * SCREEN_HEIGHT]> {
+        self.lcd.borrow()
+    }
+
+    /// Holds exactly the given buttons until the next call, releasing every other one
+    pub fn set_keys(&mut self, keys: Keys) {
+        hold_keys(&mut self.soc, keys);
+    }
+
+    /// Takes up to `count` of the samples produced so far, as left and right 16-bit values played at 24kHz
+    pub fn take_samples(&mut self, count: usize) -> Vec<(u16, u16)> {
+        self.samples.lock().unwrap().take(count)
+    }
+
+    /// Registers a memory predicate checked at the end of every frame, see [`SoC::add_hook`]
+    pub fn add_hook(&mut self, predicate: Predicate) -> HookId {
+        self.soc.add_hook(predicate)
+    }
+
+    /// Unregisters a hook, returns whether it was registered
+    pub fn remove_hook(&mut self, id: HookId) -> bool {
+        self.soc.remove_hook(id)
+    }
+
+    /// Takes the frames on which hooks started holding since the last call, oldest first
+    pub fn take_hook_events(&mut self) -> Vec<HookEvent> {
+        self.soc.take_hook_events()
+    }
+
+    /// Returns a save state of the console
+    pub fn save_state(&self) -> Vec<u8> {
+        self.soc.save()
+    }
+
+    /// Loads a save state made for the same ROM, the console is left untouched if it cannot be loaded
+    pub fn load_state(&mut self, state: &[u8]) -> Result<(), StateError> {
+        self.soc.load(state)
+    }
+
+    /// Returns the options of the SoC, changes are applied at the end of the frame
+    pub fn options(&self) -> SharedOptions {
+        self.soc.options()
+    }
+
+    /// Returns the SoC, for anything the emulator does not wrap
+    pub fn soc(&self) -> &SoC {
+        &self.soc
+    }
+
+    /// Returns the SoC mutably, for anything the emulator does not wrap
+    pub fn soc_mut(&mut self) -> &mut SoC {
+        &mut self.soc
+    }
+}
+
+#[cfg(test)]
+#[cfg_attr(coverage_nightly, coverage(off))]
+mod test {
+    use alloc::{string::{String, ToString}, vec};
+
+    use crate::{demo, romdb::Quirks};
+
+    use super::*;
+
+    #[test]
+    fn test_emulator() {
+        assert!(Emulator::new(vec![0; 4]).is_err());
+
+        let mut emulator = Emulator::new(demo::rom()).unwrap();
+        emulator.set_keys(Keys::Start);
+        for _ in 0..3 {emulator.run_frame();}
+        let state = emulator.save_state();
+        for _ in 0..2 {emulator.run_frame();}
+        let frame = emulator.framebuffer().to_vec();
+
+        emulator.load_state(&state).unwrap();
+        assert_eq!(emulator.run_frame().frame, 3);
+        emulator.run_frame();
+        assert_eq!(&emulator.framebuffer()[..], &frame[..]);
+        assert_eq!(emulator.take_samples(64).len(), 64);
+
+        // Save media written by one run are there for the next
+        let mut storage = MemoryStorage::default();
+        emulator.store_saves(&mut storage, "demo").unwrap();
+        storage.files.get_mut("ws.ieeprom").unwrap()[0] ^= 0xFF;
+        let ieeprom = storage.files["ws.ieeprom"].clone();
+        let emulator = Emulator::with_storage(demo::rom(), "demo", &storage, emulator.options(), Arc::new(Mutex::new(SampleBuffer::new(MAX_SAMPLES)))).unwrap();
+        assert_eq!(emulator.soc().io_bus.borrow().ieeprom.contents, ieeprom);
+    }
+
+    #[test]
+    fn test_quirks() {
+        // The demo's footer declares no save memory, a horizontal game and the 2001 mapper, which has no clock
+        let rom = demo::rom();
+        let header = RomInfo::parse(&rom).unwrap();
+        let hash = romdb::sha1(&rom);
+        let entry = |quirks| {
+            let info = GameInfo {title: "Quirky".to_string(), quirks: Quirks::parse(quirks), status: None, notes: String::new()};
+            romdb::find(&info.database_entry(&hash), &hash)
+        };
+        let load = |info| Emulator::with_info(rom.clone(), info, "", &MemoryStorage::default(), SharedOptions::new(EmulatorOptions::new()), Arc::new(Mutex::new(SampleBuffer::new(MAX_SAMPLES)))).unwrap();
+
+        assert_eq!(save_layout(&header, entry("").as_ref()), (0, true));
+        let plain = load(entry(""));
+        assert!(plain.soc().io_bus.borrow().eeprom.is_none());
+        assert_eq!(plain.soc().rtc_time(), None);
+        assert!(!plain.vertical());
+
+        let eeprom = entry("eeprom=0x2000");
+        assert_eq!(save_layout(&header, eeprom.as_ref()), (0x2000, false));
+        assert_eq!(load(eeprom).soc().io_bus.borrow().eeprom.as_ref().map(|eeprom| eeprom.contents.len()), Some(0x2000));
+
+        assert!(load(entry("rtc")).soc().rtc_time().is_some());
+        assert!(load(entry("vertical")).vertical());
+    }
+}
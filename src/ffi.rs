@@ -0,0 +1,308 @@
+use std::{ffi::{c_char, CString}, panic::{self, AssertUnwindSafe}, ptr, rc::Rc, slice, sync::{Arc, Mutex}};
+
+use crate::{bus::io_bus::keypad::Keys, emulator::{self, MAX_SAMPLES}, options::{EmulatorOptions, SharedOptions}, sound::buffer::{SampleBuffer, SharedSamples}};
+
+/// Cartridge SRAM, as selected by `wc_get_save` and `wc_set_save`
+const MEDIA_SRAM: u32 = 0;
+/// Cartridge EEPROM
+const MEDIA_EEPROM: u32 = 1;
+/// The console's internal EEPROM, holding the owner settings
+const MEDIA_IEEPROM: u32 = 2;
+
+/// An emulator handed to C as an opaque pointer
+///
+/// The SoC is not thread safe, so each emulator has to be used from a single thread at a time.
+pub struct Emulator {
+    /// The console, none until a ROM is loaded or after it panicked
+    running: Option<emulator::Emulator>,
+    /// Samples produced by the SoC and not yet taken by the front-end
+    samples: SharedSamples,
+    /// Options of the SoC
+    options: SharedOptions,
+    /// The last error, returned by `wc_last_error`
+    error: CString,
+}
+
+impl Emulator {
+    /// Records an error and returns false, for functions reporting success as a bool
+    fn fail(&mut self, error: impl Into<String>) -> bool {
+        self.error = CString::new(error.into().replace('\0', " ")).unwrap();
+        false
+    }
+
+    /// Loads a ROM, without any save files
+    fn load_rom(&mut self, rom: Vec<u8>) -> Result<(), String> {
+        self.running = Some(emulator::Emulator::with_options(rom, self.options.clone(), Arc::clone(&self.samples))?);
+        Ok(())
+    }
+}
+
+/// Copies bytes to a buffer given by C if they fit and returns how many there are
+///
+/// # Safety
+///
+/// `buffer` must be null or valid for writes of `len` bytes.
+unsafe fn copy_out(bytes: &[u8], buffer: *mut u8, len: usize) -> usize {
+    if !buffer.is_null() && len >= bytes.len() {
+        ptr::copy_nonoverlapping(bytes.as_ptr(), buffer, bytes.len());
+    }
+    bytes.len()
+}
+
+/// Creates an emulator without a ROM, it has to be freed with `wc_destroy`
+#[no_mangle]
+pub extern "C" fn wc_create() -> *mut Emulator {
+    Box::into_raw(Box::new(Emulator {
+        running: None,
+        samples: Arc::new(Mutex::new(SampleBuffer::new(MAX_SAMPLES))),
+        options: SharedOptions::new(EmulatorOptions::new()),
+        error: CString::default(),
+    }))
+}
+
+/// Frees an emulator
+///
+/// # Safety
+///
+/// `emulator` must be null or have been returned by `wc_create` and not freed yet.
+#[no_mangle]
+pub unsafe extern "C" fn wc_destroy(emulator: *mut Emulator) {
+    if !emulator.is_null() {drop(Box::from_raw(emulator))}
+}
+
+/// Returns the last error as a NUL-terminated string, empty if nothing failed yet
+///
+/// # Safety
+///
+/// `emulator` must be a valid emulator, the string lasts until the next call that fails.
+#[no_mangle]
+pub unsafe extern "C" fn wc_last_error(emulator: *const Emulator) -> *const c_char {
+    (*emulator).error.as_ptr()
+}
+
+/// Loads a ROM image from memory, returns false if it is not a valid ROM
+///
+/// The ROM is copied and starts with blank save media, use `wc_set_save` to load saves.
+///
+/// # Safety
+///
+/// `emulator` must be a valid emulator and `rom` valid for reads of `len` bytes.
+#[no_mangle]
+pub unsafe extern "C" fn wc_load_rom(emulator: *mut Emulator, rom: *const u8, len: usize) -> bool {
+    let emulator = &mut *emulator;
+    if rom.is_null() {return emulator.fail("No ROM given")}
+    let rom = slice::from_raw_parts(rom, len).to_vec();
+    emulator.load_rom(rom).map_or_else(|e| emulator.fail(e), |_| true)
+}
+
+/// Emulates a single frame, returns false if no ROM is loaded or the emulator panicked
+///
+/// After a panic the ROM has to be loaded again.
+///
+/// # Safety
+///
+/// `emulator` must be a valid emulator.
+#[no_mangle]
+pub unsafe extern "C" fn wc_run_frame(emulator: *mut Emulator) -> bool {
+    let emulator = &mut *emulator;
+    let Some(running) = &mut emulator.running else {return emulator.fail("No ROM is loaded")};
+    if panic::catch_unwind(AssertUnwindSafe(|| running.run_frame())).is_err() {
+        emulator.running = None;
+        return emulator.fail("The emulator panicked, the ROM has to be loaded again");
+    }
+    true
+}
+
+/// Returns the last finished frame, 224x144 pixels of 24-bit RGB in landscape orientation, or null if no ROM is loaded
+///
+/// # Safety
+///
+/// `emulator` must be a valid emulator, the frame is only valid until the next call to `wc_run_frame` or `wc_load_rom`.
+#[no_mangle]
+pub unsafe extern "C" fn wc_get_framebuffer(emulator: *const Emulator) -> *const u8 {
+    (*emulator).running.as_ref().map_or(ptr::null(), |running| running.lcd.as_ptr() as *const u8)
+}
+
+/// Sets the buttons being held, using the `WC_KEY_*` bits
+///
+/// # Safety
+///
+/// `emulator` must be a valid emulator.
+#[no_mangle]
+pub unsafe extern "C" fn wc_set_input(emulator: *mut Emulator, keys: u16) {
+    let Some(running) = &mut (*emulator).running else {return};
+    running.set_keys(Keys::from_bits_truncate(keys));
+}
+
+/// Takes up to `len` unsigned 8-bit mono samples played at 24kHz, returns how many were written
+///
+/// The two sides of the headphone output are mixed together.
+///
+/// # Safety
+///
+/// `emulator` must be a valid emulator and `buffer` valid for writes of `len` bytes.
+#[no_mangle]
+pub unsafe extern "C" fn wc_get_audio(emulator: *mut Emulator, buffer: *mut u8, len: usize) -> usize {
+    if buffer.is_null() {return 0}
+    let samples = (*emulator).samples.lock().unwrap().take(len);
+    for (i, sample) in samples.iter().enumerate() {
+        *buffer.add(i) = (((sample.0 as u32 + sample.1 as u32) / 2) >> 8) as u8;
+    }
+    samples.len()
+}
+
+/// Writes a save state to `buffer` if it is at least `len` bytes long and returns its size, or 0 if no ROM is loaded
+///
+/// Passing a null buffer only returns the size.
+///
+/// # Safety
+///
+/// `emulator` must be a valid emulator and `buffer` null or valid for writes of `len` bytes.
+#[no_mangle]
+pub unsafe extern "C" fn wc_save_state(emulator: *const Emulator, buffer: *mut u8, len: usize) -> usize {
+    (*emulator).running.as_ref().map_or(0, |running| copy_out(&running.save_state(), buffer, len))
+}
+
+/// Loads a save state made by `wc_save_state` for the same ROM, returns false and leaves the emulator untouched if it cannot be loaded
+///
+/// # Safety
+///
+/// `emulator` must be a valid emulator and `state` valid for reads of `len` bytes.
+#[no_mangle]
+pub unsafe extern "C" fn wc_load_state(emulator: *mut Emulator, state: *const u8, len: usize) -> bool {
+    let emulator = &mut *emulator;
+    let Some(running) = &mut emulator.running else {return emulator.fail("No ROM is loaded")};
+    if state.is_null() {return emulator.fail("No state given")}
+    match running.load_state(slice::from_raw_parts(state, len)) {
+        Ok(()) => true,
+        Err(e) => emulator.fail(e.to_string()),
+    }
+}
+
+/// Copies one of the save media to `buffer` if it is at least `len` bytes long and returns its size
+///
+/// Returns 0 if no ROM is loaded or the cartridge does not have that medium.
+///
+/// # Safety
+///
+/// `emulator` must be a valid emulator and `buffer` null or valid for writes of `len` bytes.
+#[no_mangle]
+pub unsafe extern "C" fn wc_get_save(emulator: *const Emulator, media: u32, buffer: *mut u8, len: usize) -> usize {
+    let Some(running) = &(*emulator).running else {return 0};
+    let io_bus = running.soc().io_bus.borrow();
+    match media {
+        MEDIA_SRAM => copy_out(&io_bus.cartridge.borrow().sram, buffer, len),
+        MEDIA_EEPROM => io_bus.eeprom.as_ref().map_or(0, |eeprom| copy_out(&eeprom.contents, buffer, len)),
+        MEDIA_IEEPROM => copy_out(&io_bus.ieeprom.contents, buffer, len),
+        _ => 0,
+    }
+}
+
+/// Replaces the contents of one of the save media, returns false if the cartridge does not have it or the size differs
+///
+/// Saves are best loaded right after the ROM, before the game reads them.
+///
+/// # Safety
+///
+/// `emulator` must be a valid emulator and `data` valid for reads of `len` bytes.
+#[no_mangle]
+pub unsafe extern "C" fn wc_set_save(emulator: *mut Emulator, media: u32, data: *const u8, len: usize) -> bool {
+    let emulator = &mut *emulator;
+    let Some(running) = &emulator.running else {return emulator.fail("No ROM is loaded")};
+    if data.is_null() {return emulator.fail("No save given")}
+    let data = slice::from_raw_parts(data, len);
+
+    let mut io_bus = running.soc().io_bus.borrow_mut();
+    let cartridge = Rc::clone(&io_bus.cartridge);
+    let mut cartridge = cartridge.borrow_mut();
+    let contents = match media {
+        MEDIA_SRAM => Some(&mut cartridge.sram),
+        MEDIA_EEPROM => io_bus.eeprom.as_mut().map(|eeprom| &mut eeprom.contents),
+        MEDIA_IEEPROM => Some(&mut io_bus.ieeprom.contents),
+        _ => None,
+    };
+    match contents {
+        Some(contents) if contents.len() == len => {
+            contents.copy_from_slice(data);
+            true
+        }
+        Some(contents) => {
+            let error = format!("The save is {} bytes long but the cartridge's is {}", len, contents.len());
+            drop((io_bus, cartridge));
+            emulator.fail(error)
+        }
+        None => {
+            drop((io_bus, cartridge));
+            emulator.fail("The cartridge does not have this save medium")
+        }
+    }
+}
+
+#[cfg(test)]
+#[cfg_attr(coverage_nightly, coverage(off))]
+mod test {
+    use std::ffi::CStr;
+
+    use crate::demo;
+
+    use super::*;
+
+    #[test]
+    fn test_header_declares_functions() {
+        let header = include_str!("../include/wondercrab.h");
+        let source = include_str!("ffi.rs");
+        let functions: Vec<_> = source.lines()
+            .filter_map(|line| line.split("extern \"C\" fn ").nth(1))
+            .map(|rest| &rest[..rest.find('(').unwrap()])
+            .collect();
+        assert!(functions.len() >= 12);
+        for function in functions {
+            assert!(header.contains(&format!("{}(", function)), "{} is missing from the header", function);
+        }
+        for (name, value) in [("WC_MEDIA_SRAM", MEDIA_SRAM), ("WC_MEDIA_EEPROM", MEDIA_EEPROM), ("WC_MEDIA_IEEPROM", MEDIA_IEEPROM)] {
+            assert!(header.contains(&format!("{} = {}", name, value)));
+        }
+        assert!(header.contains(&format!("WC_KEY_START 0x{:04X}", Keys::Start.bits())));
+        assert!(header.contains(&format!("WC_KEY_Y4 0x{:04X}", Keys::Y4.bits())));
+    }
+
+    #[test]
+    fn test_emulator() {
+        unsafe {
+            let emulator = wc_create();
+            assert!(!wc_run_frame(emulator));
+            assert!(wc_get_framebuffer(emulator).is_null());
+            assert!(!wc_load_rom(emulator, [0u8; 4].as_ptr(), 4));
+            assert!(!CStr::from_ptr(wc_last_error(emulator)).to_bytes().is_empty());
+
+            let rom = demo::rom();
+            assert!(wc_load_rom(emulator, rom.as_ptr(), rom.len()));
+            wc_set_input(emulator, Keys::Start.bits());
+            for _ in 0..3 {assert!(wc_run_frame(emulator))}
+
+            let mut state = vec![0; wc_save_state(emulator, ptr::null_mut(), 0)];
+            assert_eq!(wc_save_state(emulator, state.as_mut_ptr(), state.len()), state.len());
+            for _ in 0..2 {wc_run_frame(emulator);}
+            let frame = slice::from_raw_parts(wc_get_framebuffer(emulator), 3 * 224 * 144).to_vec();
+            assert!(wc_load_state(emulator, state.as_ptr(), state.len()));
+            for _ in 0..2 {wc_run_frame(emulator);}
+            assert_eq!(slice::from_raw_parts(wc_get_framebuffer(emulator), 3 * 224 * 144), &frame[..]);
+            assert!(!wc_load_state(emulator, state.as_ptr(), 16));
+
+            let mut samples = [0; 64];
+            assert_eq!(wc_get_audio(emulator, samples.as_mut_ptr(), samples.len()), samples.len());
+
+            let mut ieeprom = vec![0; wc_get_save(emulator, MEDIA_IEEPROM, ptr::null_mut(), 0)];
+            assert_eq!(ieeprom.len(), 128);
+            ieeprom[0x60] = 0x12;
+            assert!(wc_set_save(emulator, MEDIA_IEEPROM, ieeprom.as_ptr(), ieeprom.len()));
+            let mut read = vec![0; ieeprom.len()];
+            wc_get_save(emulator, MEDIA_IEEPROM, read.as_mut_ptr(), read.len());
+            assert_eq!(read, ieeprom);
+            assert!(!wc_set_save(emulator, MEDIA_IEEPROM, ieeprom.as_ptr(), 4));
+            assert_eq!(wc_get_save(emulator, MEDIA_EEPROM, ptr::null_mut(), 0), 0);
+
+            wc_destroy(emulator);
+        }
+    }
+}
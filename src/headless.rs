@@ -0,0 +1,345 @@
+use alloc::{format, string::{String, ToString}, vec::Vec};
+#[cfg(feature = "std")]
+use std::{fs, panic::{self, AssertUnwindSafe}, time::{SystemTime, UNIX_EPOCH}};
+
+use crate::{bus::io_bus::keypad::Keys, soc::SoC};
+#[cfg(feature = "std")]
+use crate::{options::{EmulatorOptions, SharedOptions}, parse_rom, soc::{diagnostics, lockup::{LockupDetector, LOCKUP_FRAMES}}, sound::buffer::SampleBuffer};
+
+/// Frames random input holds the same buttons for, short presses are often ignored by games
+const RANDOM_HOLD_FRAMES: u64 = 6;
+/// Default amount of frames the fuzzer plays for, about 5 minutes
+#[cfg(feature = "std")]
+const DEFAULT_FUZZ_FRAMES: u64 = 22500;
+/// Default amount of frames captured by the dump command, about a minute
+#[cfg(feature = "std")]
+const DEFAULT_DUMP_FRAMES: u64 = 4500;
+
+/// Decides which buttons are held on each frame of a headless run
+pub trait InputSource {
+    /// Returns the buttons held during a frame, frames are counted from 0
+    fn keys(&mut self, frame: u64) -> Keys;
+}
+
+/// Never presses anything, so that only the ROM's own code paths are exercised
+pub struct NullInput;
+
+impl InputSource for NullInput {
+    fn keys(&mut self, _frame: u64) -> Keys {
+        Keys::empty()
+    }
+}
+
+/// Presses buttons following a script
+///
+/// A script has one change per line, made of the frame it happens on followed by the names of the buttons held from then on.
+/// A frame without names releases every button, empty lines and lines starting with `#` are ignored.
+///
+/// ```text
+/// # Skip the title screen, then hold right and A
+/// 120 Start
+/// 126
+/// 300 X2 A
+/// ```
+///
+/// Button names are those of [`Keys`], for example `Y1`, `X3`, `A` or `Start`.
+#[derive(Clone, Debug, Default)]
+pub struct ScriptedInput {
+    /// The changes, ordered by frame
+    changes: Vec<(u64, Keys)>,
+}
+
+impl ScriptedInput {
+    /// Parses a script, the changes have to be in order
+    pub fn parse(script: &str) -> Result<Self, String> {
+        let mut changes: Vec<(u64, Keys)> = Vec::new();
+        for (number, line) in script.lines().enumerate().map(|(idx, line)| (idx + 1, line.trim())) {
+            if line.is_empty() || line.starts_with('#') {continue}
+            let mut words = line.split_whitespace();
+            let frame: u64 = words.next().unwrap().parse().map_err(|_| format!("Line {}: invalid frame", number))?;
+            if changes.last().is_some_and(|(last, _)| *last > frame) {
+                return Err(format!("Line {}: frame {} comes before the previous change", number, frame));
+            }
+            let keys = words.map(|name| Keys::from_name(name).ok_or(format!("Line {}: unknown button {}", number, name)))
+                .collect::<Result<Vec<_>, _>>()?
+                .into_iter().fold(Keys::empty(), |keys, key| keys | key);
+            changes.push((frame, keys));
+        }
+        Ok(Self {changes})
+    }
+
+    /// Reads and parses a script file
+    #[cfg(feature = "std")]
+    pub fn load(path: &str) -> Result<Self, String> {
+        Self::parse(&fs::read_to_string(path).map_err(|e| format!("{}: {}", path, e))?)
+    }
+
+    /// Writes the script back in the format [`ScriptedInput::parse`] reads
+    pub fn to_script(&self) -> String {
+        self.changes.iter().map(|(frame, keys)| {
+            keys.iter_names().fold(frame.to_string(), |line, (name, _)| line + " " + name) + "\n"
+        }).collect()
+    }
+
+    /// Returns the frame of the last change, the buttons stay as it left them afterwards
+    pub fn last_frame(&self) -> u64 {
+        self.changes.last().map_or(0, |(frame, _)| *frame)
+    }
+
+    /// Returns whether the script never presses any button
+    pub fn is_empty(&self) -> bool {
+        self.changes.iter().all(|(_, keys)| keys.is_empty())
+    }
+}
+
+/// Records the buttons held as a script, for example to play a combo back later
+///
+/// Frames are counted from the start of the recording, so that the script can be played back from any frame.
+pub struct InputRecorder {
+    /// Frame the recording started on
+    start: u64,
+    /// The changes recorded so far, ordered by frame
+    changes: Vec<(u64, Keys)>,
+}
+
+impl InputRecorder {
+    /// Starts recording on a frame with some buttons already held
+    pub fn new(frame: u64, held: Keys) -> Self {
+        let mut recorder = Self {start: frame, changes: Vec::new()};
+        recorder.record(frame, held);
+        recorder
+    }
+
+    /// Records the buttons held from a frame on, only the last change made on a frame is kept
+    pub fn record(&mut self, frame: u64, keys: Keys) {
+        let frame = frame.saturating_sub(self.start);
+        if self.changes.last().is_some_and(|(last, _)| *last == frame) {self.changes.pop();}
+        if self.changes.last().is_some_and(|(_, last)| last.bits() == keys.bits()) || (self.changes.is_empty() && keys.is_empty()) {return}
+        self.changes.push((frame, keys));
+    }
+
+    /// Stops recording on a frame and returns the script, which releases every button on that frame
+    pub fn finish(mut self, frame: u64) -> ScriptedInput {
+        if !self.changes.is_empty() {self.record(frame, Keys::empty())}
+        ScriptedInput {changes: self.changes}
+    }
+}
+
+impl InputSource for ScriptedInput {
+    fn keys(&mut self, frame: u64) -> Keys {
+        self.changes.iter().rev().find(|(start, _)| *start <= frame).map_or(Keys::empty(), |(_, keys)| *keys)
+    }
+}
+
+/// Presses random buttons, changing them every few frames
+///
+/// The buttons only depend on the seed and the frame, so a run that found a bug can be repeated exactly.
+pub struct RandomInput {
+    /// Seed of the generator
+    seed: u64,
+}
+
+impl RandomInput {
+    /// Creates random input from a seed
+    pub fn new(seed: u64) -> Self {
+        Self {seed}
+    }
+}
+
+impl InputSource for RandomInput {
+    fn keys(&mut self, frame: u64) -> Keys {
+        // SplitMix64 of the seed and the current hold period
+        let mut x = self.seed.wrapping_add((frame / RANDOM_HOLD_FRAMES).wrapping_mul(0x9E37_79B9_7F4A_7C15));
+        x = (x ^ (x >> 30)).wrapping_mul(0xBF58_476D_1CE4_E5B9);
+        x = (x ^ (x >> 27)).wrapping_mul(0x94D0_49BB_1331_11EB);
+        x ^= x >> 31;
+        Keys::from_bits_truncate(x as u16)
+    }
+}
+
+/// Holds exactly the given buttons, releasing every other one
+pub fn hold_keys(soc: &mut SoC, keys: Keys) {
+    let mut io_bus = soc.io_bus.borrow_mut();
+    io_bus.set_key(keys.complement(), false);
+    io_bus.set_key(keys, true);
+}
+
+/// Runs the SoC for the given amount of frames, holding the buttons an input source asks for
+///
+/// `on_frame` is called after each frame with its index, for example to hash it or check for lockups.
+pub fn run(soc: &mut SoC, input: &mut dyn InputSource, frames: u64, mut on_frame: impl FnMut(&mut SoC, u64)) {
+    for frame in 0..frames {
+        hold_keys(soc, input.keys(frame));
+        soc.run_frame();
+        on_frame(soc, frame);
+    }
+}
+
+/// Entry point of the `fuzz` command
+///
+/// Usage: `fuzz <rom> [frames] [seed]`
+///
+/// Plays the ROM with [`RandomInput`] until the emulator panics, the CPU runs into an invalid instruction or the game locks up,
+/// then prints the seed and frame and writes a bug report. Without a seed one is taken from the clock, passing it again repeats the run.
+#[cfg(feature = "std")]
+pub fn fuzz(args: &[String]) -> Result<(), String> {
+    let game = args.first().ok_or("Usage: fuzz <rom> [frames] [seed]")?;
+    let frames = args.get(1).and_then(|frames| frames.parse().ok()).unwrap_or(DEFAULT_FUZZ_FRAMES);
+    let seed = args.get(2).and_then(|seed| seed.parse().ok())
+        .unwrap_or_else(|| SystemTime::now().duration_since(UNIX_EPOCH).map_or(0, |time| time.as_nanos() as u64));
+    println!("Fuzzing {} for {} frames with seed {}", game, frames, seed);
+
+    // Save files are ignored so that the seed alone decides the run
+    let (color, ram_content, _, _, rom, mapper, sram, rom_info, _) = parse_rom(game);
+    let ram_content = vec![0; ram_content.len()];
+    let options = SharedOptions::new(EmulatorOptions {color, mute: true, ..EmulatorOptions::new()});
+    let mut soc = SoC::new(ram_content, Vec::new(), Vec::new(), rom, mapper, sram, SampleBuffer::shared(), options, rom_info);
+
+    let mut input = RandomInput::new(seed);
+    let mut lockups = LockupDetector::new(LOCKUP_FRAMES);
+    let mut problem = None;
+    let mut finished = 0;
+    let result = panic::catch_unwind(AssertUnwindSafe(|| {
+        run(&mut soc, &mut input, frames, |soc, frame| {
+            finished = frame + 1;
+            if problem.is_some() {return}
+            if let Some(fault) = soc.cpu.fault {
+                problem = Some(format!("invalid instruction {:02X} at {:05X} on frame {}", fault.code, fault.address, frame));
+            } else if let Some(lockup) = lockups.check(soc) {
+                problem = Some(format!("lockup on frame {}, the CPU is {}", frame, lockup));
+            }
+        });
+    }));
+    if result.is_err() {problem = Some(format!("panic on frame {}", finished))}
+
+    let Some(problem) = problem else {
+        println!("No problems found");
+        return Ok(());
+    };
+    println!("Found a {} with seed {}", problem, seed);
+    let path = diagnostics::write_bug_report(&soc, game).map_err(|e| e.to_string())?;
+    println!("Wrote bug report to {}", path);
+    Ok(())
+}
+
+/// Returns the path of the next audio capture of a game, captures are numbered so that earlier ones are never overwritten
+#[cfg(feature = "std")]
+pub fn capture_path(game: &str) -> String {
+    (1..).map(|n| format!("{}-audio-{}.wav", game, n)).find(|path| !std::path::Path::new(path).exists()).unwrap()
+}
+
+/// Loads the ROM of a headless command along with its input and amount of frames, from `<rom> [frames] [script]`
+///
+/// Save files are ignored and sound is muted, so the same ROM and script always run the same way.
+#[cfg(feature = "std")]
+fn headless_run(args: &[String], usage: &str) -> Result<(SoC, Box<dyn InputSource>, u64), String> {
+    let game = args.first().ok_or(usage)?;
+    let frames = args.get(1).and_then(|frames| frames.parse().ok()).unwrap_or(DEFAULT_DUMP_FRAMES);
+    let input: Box<dyn InputSource> = match args.get(2) {
+        Some(script) => Box::new(ScriptedInput::load(script)?),
+        None => Box::new(NullInput),
+    };
+
+    let (color, ram_content, _, _, rom, mapper, sram, rom_info, _) = parse_rom(game);
+    let ram_content = vec![0; ram_content.len()];
+    let options = SharedOptions::new(EmulatorOptions {color, mute: true, ..EmulatorOptions::new()});
+    Ok((SoC::new(ram_content, Vec::new(), Vec::new(), rom, mapper, sram, SampleBuffer::shared(), options, rom_info), input, frames))
+}
+
+/// Entry point of the `dump` command
+///
+/// Usage: `dump <rom> [frames] [script]`
+///
+/// Plays the ROM without a window, holding the buttons of a [`ScriptedInput`] script if one is given, and captures its audio to a WAV file next to it.
+/// Save files are ignored and nothing is paced or played back, so the same ROM and script always give the same file.
+#[cfg(feature = "std")]
+pub fn dump(args: &[String]) -> Result<(), String> {
+    let (mut soc, mut input, frames) = headless_run(args, "Usage: dump <rom> [frames] [script]")?;
+    let path = capture_path(&args[0]);
+    soc.start_audio_capture(&path).map_err(|e| format!("{}: {}", path, e))?;
+    run(&mut soc, input.as_mut(), frames, |_, _| {});
+    let samples = soc.stop_audio_capture().map_err(|e| format!("{}: {}", path, e))?.unwrap_or(0);
+    println!("Wrote {} samples to {}", samples, path);
+    Ok(())
+}
+
+/// Plays the ROM without a window like [`dump`], recording a binary trace of every instruction to \[game\]-trace-N.bin
+///
+/// Usage: `btrace record <rom> [frames] [script]`
+#[cfg(feature = "std")]
+pub fn record_trace(args: &[String]) -> Result<(), String> {
+    let (mut soc, mut input, frames) = headless_run(args, "Usage: btrace record <rom> [frames] [script]")?;
+    let path = (1..).map(|n| format!("{}-trace-{}.bin", args[0], n)).find(|path| !std::path::Path::new(path).exists()).unwrap();
+    soc.start_binary_trace(&path).map_err(|e| format!("{}: {}", path, e))?;
+    run(&mut soc, input.as_mut(), frames, |_, _| {});
+    let steps = soc.stop_binary_trace().map_err(|e| format!("{}: {}", path, e))?.unwrap_or(0);
+    println!("Wrote {} instructions to {}", steps, path);
+    Ok(())
+}
+
+#[cfg(test)]
+#[cfg_attr(coverage_nightly, coverage(off))]
+mod test {
+    use crate::bus::io_bus::IOBusConnection;
+
+    use super::*;
+
+    #[test]
+    fn test_script() {
+        let mut script = ScriptedInput::parse("# Title screen\n120 Start\n\n126\n300 X2 A\n").unwrap();
+        assert_eq!(script.keys(0).bits(), 0);
+        assert_eq!(script.keys(120).bits(), Keys::Start.bits());
+        assert_eq!(script.keys(125).bits(), Keys::Start.bits());
+        assert_eq!(script.keys(126).bits(), 0);
+        assert_eq!(script.keys(1000).bits(), (Keys::X2 | Keys::A).bits());
+
+        assert!(ScriptedInput::parse("10 Select").is_err());
+        assert!(ScriptedInput::parse("10 A\n5 B").is_err());
+        assert!(ScriptedInput::parse("soon A").is_err());
+    }
+
+    #[test]
+    fn test_recorder() {
+        let mut recorder = InputRecorder::new(100, Keys::empty());
+        recorder.record(102, Keys::X2);
+        recorder.record(105, Keys::X2 | Keys::A);
+        // Only the last change of a frame counts and repeating the held buttons changes nothing
+        recorder.record(106, Keys::B);
+        recorder.record(106, Keys::X2 | Keys::A);
+        recorder.record(108, Keys::empty());
+        recorder.record(110, Keys::A);
+        let script = recorder.finish(112);
+        assert_eq!(script.to_script(), "2 X2\n5 X2 A\n8\n10 A\n12\n");
+        assert_eq!(script.last_frame(), 12);
+        assert!(!script.is_empty());
+
+        let reparsed = ScriptedInput::parse(&script.to_script()).unwrap();
+        assert_eq!(reparsed.to_script(), script.to_script());
+        assert!(InputRecorder::new(0, Keys::empty()).finish(60).is_empty());
+        assert_eq!(InputRecorder::new(7, Keys::Start).finish(9).to_script(), "0 Start\n2\n");
+    }
+
+    #[test]
+    fn test_random_input() {
+        let (mut a, mut b) = (RandomInput::new(1), RandomInput::new(1));
+        let keys: Vec<_> = (0..60).map(|frame| a.keys(frame).bits()).collect();
+        assert_eq!(keys, (0..60).map(|frame| b.keys(frame).bits()).collect::<Vec<_>>());
+        // Buttons are held for a few frames and change afterwards
+        assert!(keys.chunks(RANDOM_HOLD_FRAMES as usize).all(|hold| hold.iter().all(|k| *k == hold[0])));
+        assert!(keys.chunks(RANDOM_HOLD_FRAMES as usize).any(|hold| hold[0] != keys[0]));
+        assert_ne!(keys, (0..60).map(|frame| RandomInput::new(2).keys(frame).bits()).collect::<Vec<_>>());
+    }
+
+    #[test]
+    fn test_run_presses_keys() {
+        let mut soc = SoC::test_build();
+        let mut script = ScriptedInput::parse("1 A\n2").unwrap();
+        let mut held = Vec::new();
+        run(&mut soc, &mut script, 3, |soc, _| {
+            // Selects the action buttons in the key scan port
+            soc.io_bus.borrow_mut().write_io(0xB5, 0x40);
+            held.push(soc.io_bus.borrow_mut().read_io(0xB5) & 0x0F);
+        });
+        assert_eq!(held, [0, Keys::A.bits() as u8, 0]);
+    }
+}
@@ -0,0 +1,314 @@
+use std::{collections::HashMap, io};
+
+use sdl2::keyboard::Keycode;
+
+use wonderswan::{bus::io_bus::keypad::Keys, headless::ScriptedInput, options::{Choice, PerGame}};
+
+/// Keyboard layouts the user can switch between
+///
+/// The selected preset is stored per game in \[game\].input
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum Preset {
+    /// The console is held horizontally, the X pad is the d-pad and A/B are the buttons
+    Horizontal,
+    /// The console is held vertically and both pads are used as d-pads,
+    /// the arrow keys control the X pad and WASD the Y pad, each direction matching the rotated screen
+    Vertical,
+}
+
+impl Choice for Preset {
+    const ALL: &'static [Self] = &[Preset::Horizontal, Preset::Vertical];
+
+    fn name(&self) -> &'static str {
+        match self {
+            Preset::Horizontal => "horizontal",
+            Preset::Vertical => "vertical",
+        }
+    }
+}
+
+impl PerGame for Preset {
+    const EXTENSION: &'static str = "input";
+}
+
+impl Preset {
+    /// Returns the default preset for an orientation of the console
+    pub fn for_orientation(vertical: bool) -> Self {
+        if vertical {Preset::Vertical} else {Preset::Horizontal}
+    }
+
+    /// Returns the mapping from keyboard keys to console buttons
+    ///
+    /// On the console X1/Y1 point up, X2/Y2 right, X3/Y3 down and X4/Y4 left when it is held horizontally.
+    /// The screen is rotated 90 degrees counterclockwise when vertical, so up on the screen is right on the pads.
+    pub fn key_map(&self) -> HashMap<Keycode, Keys> {
+        let mut key_map = HashMap::new();
+        match self {
+            Preset::Horizontal => {
+                key_map.insert(Keycode::A, Keys::Y1);
+                key_map.insert(Keycode::W, Keys::Y2);
+                key_map.insert(Keycode::D, Keys::Y3);
+                key_map.insert(Keycode::S, Keys::Y4);
+                key_map.insert(Keycode::U, Keys::X1);
+                key_map.insert(Keycode::K, Keys::X2);
+                key_map.insert(Keycode::J, Keys::X3);
+                key_map.insert(Keycode::H, Keys::X4);
+                key_map.insert(Keycode::KP_4, Keys::X1);
+                key_map.insert(Keycode::KP_8, Keys::X2);
+                key_map.insert(Keycode::KP_6, Keys::X3);
+                key_map.insert(Keycode::KP_5, Keys::X4);
+            }
+            Preset::Vertical => {
+                key_map.insert(Keycode::Up, Keys::X2);
+                key_map.insert(Keycode::Right, Keys::X3);
+                key_map.insert(Keycode::Down, Keys::X4);
+                key_map.insert(Keycode::Left, Keys::X1);
+                key_map.insert(Keycode::W, Keys::Y2);
+                key_map.insert(Keycode::D, Keys::Y3);
+                key_map.insert(Keycode::S, Keys::Y4);
+                key_map.insert(Keycode::A, Keys::Y1);
+            }
+        }
+        key_map.insert(Keycode::Return, Keys::Start);
+        key_map.insert(Keycode::Z, Keys::B);
+        key_map.insert(Keycode::X, Keys::A);
+        key_map
+    }
+}
+
+/// Actions bound to keyboard keys besides the console's buttons
+///
+/// The front-end dispatches keys through this table and the F1 help is generated from it, so that the help always lists the keys as bound.
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum Hotkey {
+    Help,
+    Rotate,
+    InputPreset,
+    Accuracy,
+    SoundProfile,
+    SpriteLimit,
+    ColorProfile,
+    Grayscale,
+    Scaling,
+    Faster,
+    Slower,
+    PreservePitch,
+    Screen1,
+    Screen2,
+    Sprites,
+    LayerTint,
+    Scope,
+    InputOverlay,
+    OwnerEditor,
+    HeatMap,
+    LowBattery,
+    AudioCapture,
+    Macro,
+    BugReport,
+    Timing,
+    Menu,
+}
+
+impl Hotkey {
+    /// Every hotkey, in the order the help lists them
+    const ALL: [Hotkey; 26] = [
+        Hotkey::Help, Hotkey::Rotate, Hotkey::InputPreset, Hotkey::Accuracy, Hotkey::SoundProfile, Hotkey::SpriteLimit, Hotkey::ColorProfile, Hotkey::Grayscale, Hotkey::Scaling,
+        Hotkey::Faster, Hotkey::Slower, Hotkey::PreservePitch, Hotkey::Screen1, Hotkey::Screen2, Hotkey::Sprites, Hotkey::LayerTint, Hotkey::Scope,
+        Hotkey::InputOverlay, Hotkey::OwnerEditor, Hotkey::HeatMap, Hotkey::LowBattery, Hotkey::AudioCapture, Hotkey::Macro, Hotkey::BugReport,
+        Hotkey::Timing, Hotkey::Menu,
+    ];
+
+    /// Returns the key the action is bound to
+    pub fn key(&self) -> Keycode {
+        match self {
+            Hotkey::Help => Keycode::F1,
+            Hotkey::Rotate => Keycode::R,
+            Hotkey::InputPreset => Keycode::I,
+            Hotkey::Accuracy => Keycode::O,
+            Hotkey::SoundProfile => Keycode::F3,
+            Hotkey::SpriteLimit => Keycode::F4,
+            Hotkey::ColorProfile => Keycode::F9,
+            Hotkey::Grayscale => Keycode::Num7,
+            Hotkey::Scaling => Keycode::F11,
+            Hotkey::Faster => Keycode::Equals,
+            Hotkey::Slower => Keycode::Minus,
+            Hotkey::PreservePitch => Keycode::P,
+            Hotkey::Screen1 => Keycode::Num1,
+            Hotkey::Screen2 => Keycode::Num2,
+            Hotkey::Sprites => Keycode::Num3,
+            Hotkey::LayerTint => Keycode::Num4,
+            Hotkey::Scope => Keycode::Num5,
+            Hotkey::InputOverlay => Keycode::F2,
+            Hotkey::OwnerEditor => Keycode::F5,
+            Hotkey::HeatMap => Keycode::F6,
+            Hotkey::LowBattery => Keycode::F7,
+            Hotkey::AudioCapture => Keycode::F8,
+            Hotkey::Macro => Keycode::F10,
+            Hotkey::BugReport => Keycode::F12,
+            Hotkey::Timing => Keycode::Num6,
+            Hotkey::Menu => Keycode::Escape,
+        }
+    }
+
+    /// Returns what the action does, as shown in the help
+    pub fn description(&self) -> &'static str {
+        match self {
+            Hotkey::Help => "Help",
+            Hotkey::Rotate => "Rotate screen",
+            Hotkey::InputPreset => "Input preset",
+            Hotkey::Accuracy => "Accuracy",
+            Hotkey::SoundProfile => "Sound profile",
+            Hotkey::SpriteLimit => "Sprite limit",
+            Hotkey::ColorProfile => "Color profile",
+            Hotkey::Grayscale => "Grayscale",
+            Hotkey::Scaling => "Scaling",
+            Hotkey::Faster => "Faster",
+            Hotkey::Slower => "Slower",
+            Hotkey::PreservePitch => "Keep pitch",
+            Hotkey::Screen1 => "Screen 1 layer",
+            Hotkey::Screen2 => "Screen 2 layer",
+            Hotkey::Sprites => "Sprite layer",
+            Hotkey::LayerTint => "Layer tint",
+            Hotkey::Scope => "Sound scope",
+            Hotkey::InputOverlay => "Input overlay",
+            Hotkey::OwnerEditor => "Owner settings",
+            Hotkey::HeatMap => "Heat map",
+            Hotkey::LowBattery => "Low battery",
+            Hotkey::AudioCapture => "Audio capture",
+            Hotkey::Macro => "Record macro",
+            Hotkey::BugReport => "Bug report",
+            Hotkey::Timing => "Frame timings",
+            Hotkey::Menu => "Pause menu",
+        }
+    }
+
+    /// Returns the action bound to a key
+    pub fn from_keycode(keycode: Keycode) -> Option<Self> {
+        Self::ALL.into_iter().find(|hotkey| hotkey.key() == keycode)
+    }
+
+    /// Returns a line of help for each hotkey, the key's name followed by the action, aligned after the longest name
+    pub fn help() -> Vec<String> {
+        let width = Self::ALL.iter().map(|hotkey| hotkey.key().name().len()).max().unwrap_or(0) + 1;
+        Self::ALL.iter().map(|hotkey| format!("{:<width$}{}", hotkey.key().name(), hotkey.description()).to_uppercase()).collect()
+    }
+}
+
+/// Input macros bound to keys, each one a script of the buttons to press from the frame its key is pressed on
+///
+/// Macros are stored per game in \[game\].macros, as the key's name on a line starting with `@` followed by the macro's script.
+#[derive(Clone, Debug, Default)]
+pub struct MacroBindings {
+    /// The macros along with the keys they are bound to, in the order they were bound
+    macros: Vec<(Keycode, ScriptedInput)>,
+}
+
+impl MacroBindings {
+    /// Returns whether a key can play a macro, which it cannot when it is a hotkey or a button in any preset
+    pub fn bindable(keycode: Keycode) -> bool {
+        Hotkey::from_keycode(keycode).is_none() && Preset::ALL.iter().all(|preset| !preset.key_map().contains_key(&keycode))
+    }
+
+    /// Returns the macro bound to a key
+    pub fn get(&self, keycode: Keycode) -> Option<&ScriptedInput> {
+        self.macros.iter().find(|(key, _)| *key == keycode).map(|(_, recorded)| recorded)
+    }
+
+    /// Binds a macro to a key, replacing the one bound to it before
+    pub fn bind(&mut self, keycode: Keycode, recorded: ScriptedInput) {
+        self.macros.retain(|(key, _)| *key != keycode);
+        self.macros.push((keycode, recorded));
+    }
+
+    /// Parses the macros of a file, keys that cannot be bound are rejected
+    pub fn parse(text: &str) -> Result<Self, String> {
+        let mut bindings = Self::default();
+        for section in text.split('@').skip(1) {
+            let (name, script) = section.split_once('\n').unwrap_or((section, ""));
+            let keycode = Keycode::from_name(name.trim()).filter(|keycode| Self::bindable(*keycode))
+                .ok_or(format!("Macros cannot be bound to {}", name.trim()))?;
+            bindings.bind(keycode, ScriptedInput::parse(script).map_err(|e| format!("Macro on {}: {}", name.trim(), e))?);
+        }
+        Ok(bindings)
+    }
+
+    /// Writes the macros in the format [`MacroBindings::parse`] reads
+    pub fn to_text(&self) -> String {
+        self.macros.iter().map(|(keycode, recorded)| format!("@{}\n{}", keycode.name(), recorded.to_script())).collect()
+    }
+
+    /// Loads the macros saved for a game, a game without any has none bound
+    pub fn load(game: &str) -> Result<Self, String> {
+        match std::fs::read_to_string(format!("{}.macros", game)) {
+            Ok(text) => Self::parse(&text),
+            Err(e) if e.kind() == io::ErrorKind::NotFound => Ok(Self::default()),
+            Err(e) => Err(e.to_string()),
+        }
+    }
+
+    /// Saves the macros for a game
+    pub fn save(&self, game: &str) -> io::Result<()> {
+        std::fs::write(format!("{}.macros", game), self.to_text())
+    }
+}
+
+#[cfg(test)]
+#[cfg_attr(coverage_nightly, coverage(off))]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_preset_names() {
+        for &preset in Preset::ALL {
+            assert_eq!(Preset::from_name(preset.name()), Some(preset));
+        }
+        assert_eq!(Preset::from_name("vertical\n"), Some(Preset::Vertical));
+        assert_eq!(Preset::Vertical.next(), Preset::Horizontal);
+    }
+
+    #[test]
+    fn test_vertical_directions() {
+        let key_map = Preset::Vertical.key_map();
+        // Both pads move the same way for the same screen direction
+        for (x, y) in [(Keycode::Up, Keycode::W), (Keycode::Right, Keycode::D), (Keycode::Down, Keycode::S), (Keycode::Left, Keycode::A)] {
+            assert_eq!(key_map[&x].bits() >> 4, key_map[&y].bits() >> 8);
+        }
+        assert_eq!(key_map[&Keycode::Return].bits(), Keys::Start.bits());
+    }
+
+    #[test]
+    fn test_hotkeys() {
+        for hotkey in Hotkey::ALL {
+            assert_eq!(Hotkey::from_keycode(hotkey.key()), Some(hotkey), "{:?} shares its key", hotkey);
+            for &preset in Preset::ALL {
+                assert!(!preset.key_map().contains_key(&hotkey.key()), "{:?} is also a button in the {} preset", hotkey, preset.name());
+            }
+        }
+        // Each line ends with the action, aligned after the key
+        let help = Hotkey::help();
+        assert_eq!(help.len(), Hotkey::ALL.len());
+        let width = help[0].len() - "HELP".len();
+        for (line, hotkey) in help.iter().zip(Hotkey::ALL) {
+            assert_eq!(line[width..], hotkey.description().to_uppercase());
+        }
+    }
+
+    #[test]
+    fn test_macro_bindings() {
+        let text = "@F11\n2 X2\n5 X2 A\n8\n@Space\n0 Start\n1\n";
+        let mut bindings = MacroBindings::parse(text).unwrap();
+        assert_eq!(bindings.get(Keycode::F11).unwrap().last_frame(), 8);
+        assert!(bindings.get(Keycode::Q).is_none());
+        assert_eq!(bindings.to_text(), text);
+
+        bindings.bind(Keycode::F11, ScriptedInput::parse("0 B\n3").unwrap());
+        assert_eq!(bindings.to_text(), "@Space\n0 Start\n1\n@F11\n0 B\n3\n");
+
+        // Hotkeys and buttons keep their own actions
+        assert!(!MacroBindings::bindable(Hotkey::Macro.key()));
+        assert!(!MacroBindings::bindable(Keycode::X));
+        assert!(!MacroBindings::bindable(Keycode::Up));
+        assert!(MacroBindings::parse("@F10\n0 A\n").is_err());
+        assert!(MacroBindings::parse("@F11\n0 Select\n").is_err());
+    }
+}
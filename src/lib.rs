@@ -0,0 +1,281 @@
+//! Core of the WonderCrab emulator
+//! 
+//! Contains the emulated console along with everything that does not need a window,
+//! the SDL front-end in main.rs and the C interface in [`ffi`] are both built on top of it.
+//!
+//! Reading files, spawning threads and the host's clock are left to the host layer built with the std feature,
+//! the core only reaching them through the [`storage::Storage`] and [`cartridge::rtc::TimeSource`] traits.
+//! Without that feature the crate is `no_std`, needing only `alloc` and a global allocator from the host.
+
+#![cfg_attr(not(feature = "std"), no_std)]
+
+extern crate alloc;
+
+#[cfg(feature = "std")]
+use std::{cell::RefCell, rc::Rc, sync::atomic::AtomicBool};
+
+#[cfg(feature = "std")]
+use bus::io_bus::IOBus;
+use cartridge::header::{RomInfo, SaveType};
+#[cfg(feature = "std")]
+use cartridge::{journal, overrides::Overrides, Mapper};
+use romdb::GameInfo;
+#[cfg(feature = "std")]
+use storage::{FileStorage, SaveMedia};
+
+/// This module contains the I/O and memory busses
+/// 
+/// The WonderSwan contains only a single memory bus and a single I/O bus.
+/// These classes are therefore intended to produce singletons, to which multiple
+/// references can be shared between the different components, mimicking the
+/// system's original architecture.
+pub mod bus;
+
+/// This module contains the cartridge
+#[allow(non_camel_case_types)]
+#[allow(non_snake_case)]
+pub mod cartridge;
+
+/// This module contains the WonderSwan's CPU
+/// 
+/// This file's contents specifically are made up of things that would be useful to both defining the opcodes and operating the CPU
+#[allow(non_snake_case)]
+pub mod cpu;
+
+/// Generated demo ROM
+/// 
+/// Runs when no game is given, so that the emulator shows something right away and always has a workload to profile.
+pub mod demo;
+
+/// This module contains the WonderSwan's display chip
+/// 
+/// Actually displaying the screen to the Window is hadnled through SDL in main
+#[allow(non_camel_case_types)]
+#[allow(non_snake_case)]
+pub mod display;
+
+/// The WonderSwan color and WonderCrystal DMAs
+pub mod dma;
+
+/// A console running a ROM given as bytes, the API for embedding the emulator in other front-ends or test harnesses
+/// 
+/// Does not depend on SDL, which is only needed by the front-end in main.rs
+pub mod emulator;
+
+/// The thread running the SoC
+/// 
+/// Emulation runs apart from SDL so that waiting on the GPU to present frames does not slow it down
+#[cfg(feature = "std")]
+pub mod emulation;
+
+/// Runtime options of the emulator
+/// 
+/// The options are shared between the front-end and the SoC, see [`options::EmulatorOptions`] for what each of them does
+pub mod options;
+
+/// On-screen display
+/// 
+/// Drawn by the front-end over presented frames, such as the input overlay used when streaming or verifying TAS inputs
+#[cfg(feature = "std")]
+pub mod osd;
+
+/// Owner settings stored in the internal EEPROM, shared with the splash tool
+pub mod owner;
+
+/// ROM database
+/// 
+/// Maps the SHA-1s of known ROMs to their titles, quirks and compatibility, the database itself is only embedded with the romdb feature
+pub mod romdb;
+
+/// Reader of the flat subset of TOML used by the overrides and the ROM database
+pub mod toml;
+
+/// Minimal PNG encoder, used for screenshots, and decoder for reference screenshots
+pub mod png;
+
+/// Reader of ZIP archives, stored or deflated, used to load ROMs from zipped sets
+pub mod archive;
+
+/// Reading and decompressing ROMs on a worker thread, so that the front-end keeps responding while large sets load
+#[cfg(feature = "std")]
+pub mod loader;
+
+/// Comparison of frames against screenshots taken by other emulators, used by the compare subcommand
+#[cfg(feature = "std")]
+pub mod compare;
+
+/// Side by side runs of the per-dot and scanline renderers, used by the renderers subcommand
+#[cfg(feature = "std")]
+pub mod renderers;
+
+/// Comparison of CPU traces, used by the tracediff subcommand
+#[cfg(feature = "std")]
+pub mod tracediff;
+
+/// Timed runs of frames without pacing, used by the --time flag
+#[cfg(feature = "std")]
+pub mod bench;
+
+/// C interface for embedding the emulator in other front-ends, only built with the ffi feature
+#[cfg(feature = "ffi")]
+pub mod ffi;
+
+/// Front-ends without a window, feeding scripted or random input
+pub mod headless;
+
+/// Headless regression runner
+/// 
+/// Runs a directory of ROMs and compares the hashes of their frames against a stored baseline
+#[cfg(feature = "std")]
+pub mod regress;
+
+/// Self-checks of each subsystem with a scorecard, run by the verify command before submitting changes
+#[cfg(feature = "std")]
+pub mod verify;
+
+/// Rollback netplay, predicting the remote player's input and replaying frames it was mispredicted on
+pub mod netplay;
+
+/// Conversion of save files from and to other emulators, used by the save subcommand
+#[cfg(feature = "std")]
+pub mod saves;
+
+/// System on a chip
+pub mod soc;
+
+/// The WonderSwan's sound chip
+pub mod sound;
+
+/// Save states
+/// 
+/// Contains the versioned format components use to serialize their state
+pub mod state;
+
+/// Persistence of save media through a storage the front-end provides, files on disk with the std feature
+pub mod storage;
+
+/// Locks shared between the core and the host's threads, the standard library's with the std feature and spin locks without it
+pub mod sync;
+
+/// Floating point methods missing from `core`, for builds without the std feature
+pub mod math;
+
+/// Host timing of frames, kept as rolling histograms to diagnose uneven pacing
+pub mod pacing;
+
+/// Emulation speed settings
+/// 
+/// Also contains the audio time-stretching used to keep the pitch constant when not running at normal speed
+pub mod speed;
+
+/// Everything [`parse_rom`] extracts from a ROM image and its save files, in the order of its return value
+#[cfg(feature = "std")]
+pub type ParsedRom = (bool, Vec<u8>, Vec<u8>, Vec<u8>, Vec<u8>, Mapper, bool, u8, Option<GameInfo>);
+
+/// Extracts information from the requested ROM image and any existing save files
+/// 
+/// # Return value
+/// This function returns a tuple containing the following:
+/// - `color: bool` whether or not the ROM supports color output
+/// - `save: Vec<u8>` contents of SRAM
+/// - `ieeprom: Vec<u8>` contents of the IEEPROM
+/// - `eeprom: Vec<u8>` contents of the cartridge EEPROM
+/// - `rom: Vec<u8>` contents of the ROM
+/// - `mapper: Mapper` the mapper chip used by the cartridge
+/// - `sram: bool` whether or not the cartridge contains SRAM
+/// - `rom_info: u8` bits 2 and 3 of the system control port 0xA0
+/// - `info: Option<GameInfo>` the game's entry in the ROM database, if it has one
+#[cfg(feature = "std")]
+pub fn parse_rom(game: &str) -> ParsedRom {
+    parse_rom_data(game, read_rom(game).unwrap())
+}
+
+/// Same as [`parse_rom`] for a ROM that has already been read, such as one loaded by a [`loader::RomLoader`]
+#[cfg(feature = "std")]
+pub fn parse_rom_data(game: &str, rom: Vec<u8>) -> ParsedRom {
+    let (header, info) = read_header(game, &rom).unwrap();
+    if let Some(info) = &info {println!("{}", info.title)}
+
+    let color = header.color;
+    let (ram_size, sram) = save_layout(&header, info.as_ref());
+
+    let SaveMedia {ieeprom, eeprom, sram: mut save, ..} = SaveMedia::load(&FileStorage, game, color, ram_size);
+    if sram {journal::replay_file(&format!("{}.sram", game), &mut save)}
+
+    let mapper = header.mapper;
+    let rom_info = header.port_a0_bits();
+
+    if mapper == Mapper::B_2003 {println!("Mapper 2003")}
+
+    (color, save, ieeprom, eeprom, rom, mapper, sram, rom_info, info)
+}
+
+/// Parses a game's footer and looks it up in the ROM database, applying the overrides next to the ROM if there are any
+///
+/// Overrides that cannot be read are reported and ignored, see [`Overrides`] for their format.
+#[cfg(feature = "std")]
+pub fn read_header(game: &str, rom: &[u8]) -> Result<(RomInfo, Option<GameInfo>), String> {
+    let overrides = Overrides::load(game).unwrap_or_else(|e| {
+        println!("Ignoring {}", e);
+        Overrides::default()
+    });
+    if overrides != Overrides::default() {println!("Applying {}", Overrides::path(game))}
+
+    let header = RomInfo::parse_with(rom, &overrides)?;
+    let mut info = romdb::lookup(rom);
+    overrides.apply_quirks(game, &mut info);
+    Ok((header, info))
+}
+
+/// Returns the size of the cartridge's save memory and whether it is SRAM rather than EEPROM
+/// 
+/// Cartridges without any save memory are treated as having empty SRAM, the ROM database can override the EEPROM size of the footer.
+pub fn save_layout(header: &RomInfo, info: Option<&GameInfo>) -> (usize, bool) {
+    match (info.and_then(|info| info.quirks.eeprom_size), header.save) {
+        (Some(size), _) => (size, false),
+        (None, SaveType::None) => (0, true),
+        (None, SaveType::Sram(size)) => (size, true),
+        (None, SaveType::Eeprom(size)) => (size, false),
+    }
+}
+
+/// Reads a ROM image, trying the .ws extension before .wsc and then the first ROM of a .zip archive
+#[cfg(feature = "std")]
+pub fn read_rom(game: &str) -> Result<Vec<u8>, String> {
+    read_rom_cancellable(game, &AtomicBool::new(false))
+}
+
+/// Same as [`read_rom`], but a zipped ROM is given up on between reading and decompressing it once `cancelled` is set
+#[cfg(feature = "std")]
+pub fn read_rom_cancellable(game: &str, cancelled: &AtomicBool) -> Result<Vec<u8>, String> {
+    let zip = format!("{}.zip", game);
+    std::fs::read(format!("{}.ws", game)).or_else(|_| std::fs::read(format!("{}.wsc", game))).or_else(|e| match std::path::Path::new(&zip).exists() {
+        true => loader::read_zipped(&zip, cancelled),
+        false => Err(format!("Could not read {}.ws or {}.wsc: {}", game, game, e)),
+    })
+}
+
+/// Saves the game and console's rewrittable memory to files
+/// 
+/// The files are named as listed in [`SaveMedia`].
+/// While the game is running SRAM changes are instead written to \[game\].sram.journal, which is removed after this.
+#[cfg(feature = "std")]
+pub fn save_game(io_bus: Rc<RefCell<IOBus>>, color: bool, game: &str) {
+    SaveMedia::from_io_bus(&io_bus.borrow()).store(&mut FileStorage, game, color).unwrap();
+}
+
+/// Same as assert_eq but prints the values in hex instead
+/// 
+/// I wrote it so it so it would be easier to make CPU tests
+#[macro_export]
+macro_rules! assert_eq_hex {
+    ($left:expr, $right:expr) => {
+        let left_val = $left;
+        let right_val = $right;
+        assert!(
+            left_val == right_val,
+            "assertion `left == right` failed\n  left: 0x{:X}\n right: 0x{:X}",
+            left_val, right_val,
+        )
+    };
+}
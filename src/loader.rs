@@ -0,0 +1,82 @@
+use std::{sync::{atomic::{AtomicBool, Ordering}, mpsc::{self, Receiver, TryRecvError}, Arc}, thread};
+
+use crate::{archive, read_rom_cancellable};
+
+/// Reads the ROM of a zipped set, giving up between steps once cancelled
+///
+/// Reading the archive and decompressing the ROM each run to completion, so a cancelled load stops at the next of them.
+pub fn read_zipped(path: &str, cancelled: &AtomicBool) -> Result<Vec<u8>, String> {
+    let zip = std::fs::read(path).map_err(|e| format!("Could not read {}: {}", path, e))?;
+    if cancelled.load(Ordering::Relaxed) {return Err("Loading was cancelled".to_string())}
+    let entry = archive::find_rom(&zip).map_err(|e| format!("{}: {}", path, e))?;
+    if cancelled.load(Ordering::Relaxed) {return Err("Loading was cancelled".to_string())}
+    archive::extract(&zip, &entry).map_err(|e| format!("{}: {}", path, e))
+}
+
+/// A ROM being read and decompressed on a worker thread
+///
+/// Large zipped sets take long enough to read and inflate that the front-end would stop responding,
+/// so it polls the loader between its own events and draws a loading notice instead.
+pub struct RomLoader {
+    /// Tells the worker to give up at its next step
+    cancelled: Arc<AtomicBool>,
+    /// The ROM or why it could not be read, sent once the worker is done
+    result: Receiver<Result<Vec<u8>, String>>,
+}
+
+impl RomLoader {
+    /// Starts reading a game's ROM, trying \[game\].ws, \[game\].wsc and then \[game\].zip
+    pub fn spawn(game: &str) -> Self {
+        let cancelled = Arc::new(AtomicBool::new(false));
+        let (sender, result) = mpsc::channel();
+        let game = game.to_string();
+        let worker_cancelled = Arc::clone(&cancelled);
+        thread::spawn(move || {
+            let rom = read_rom_cancellable(&game, &worker_cancelled);
+            // Nobody is waiting for a cancelled load
+            let _ = sender.send(rom);
+        });
+        Self {cancelled, result}
+    }
+
+    /// Returns the ROM once it has been read, or `None` while the worker is still at it
+    pub fn poll(&self) -> Option<Result<Vec<u8>, String>> {
+        match self.result.try_recv() {
+            Ok(rom) => Some(rom),
+            Err(TryRecvError::Empty) => None,
+            Err(TryRecvError::Disconnected) => Some(Err("The ROM loader stopped unexpectedly".to_string())),
+        }
+    }
+
+    /// Waits for the ROM to be read
+    pub fn wait(self) -> Result<Vec<u8>, String> {
+        self.result.recv().map_err(|_| "The ROM loader stopped unexpectedly".to_string())?
+    }
+
+    /// Abandons the load, the worker stops at its next step and its result is dropped
+    pub fn cancel(self) {
+        self.cancelled.store(true, Ordering::Relaxed);
+    }
+}
+
+#[cfg(test)]
+#[cfg_attr(coverage_nightly, coverage(off))]
+mod test {
+    use crate::soc::diagnostics::zip;
+
+    use super::*;
+
+    #[test]
+    fn test_load_zipped() {
+        let dir = std::env::temp_dir().join(format!("wondercrab-loader-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let game = dir.join("game").to_str().unwrap().to_string();
+        std::fs::write(format!("{}.zip", game), zip("set", &[("game.ws", vec![0xEA; 16])])).unwrap();
+
+        let loader = RomLoader::spawn(&game);
+        assert_eq!(loader.wait().unwrap(), vec![0xEA; 16]);
+        assert!(read_zipped(&format!("{}.zip", game), &AtomicBool::new(true)).is_err());
+        assert!(RomLoader::spawn(&dir.join("missing").to_str().unwrap().to_string()).wait().is_err());
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+}
@@ -7,14 +7,13 @@
 
 #[warn(missing_docs)]
 
-use std::{cell::RefCell, collections::HashMap, env, rc::Rc, sync::{Arc, Mutex}, time::{Duration, Instant}};
+use std::{env, sync::{mpsc::{self, RecvTimeoutError}, Arc}, time::{Duration, Instant}};
 
-use cartridge::Mapper;
+use input::{Hotkey, MacroBindings, Preset};
 use mimalloc::MiMalloc;
-use sdl2::{audio::{AudioCallback, AudioSpecDesired}, event::Event, keyboard::Keycode, pixels::PixelFormatEnum, rect::Rect};
-use soc::SoC;
-
-use crate::bus::io_bus::{keypad::Keys, IOBus};
+use sdl2::{event::Event, keyboard::Keycode, rect::Rect, render::Canvas, video::Window, EventPump};
+use video::{Scaler, Scaling};
+use wonderswan::{bench, bus::io_bus::keypad::Keys, compare, cartridge::{header::RomInfo, rtc}, cpu::{trace, v30mz::InvalidOpcodeBehavior}, demo, emulation::{Command, EmulationThread, PresentFilter}, headless, loader::RomLoader, options::{Accuracy, Choice, ColorProfile, ColorSettings, EmulatorOptions, PerGame, Grayscale, SharedOptions, SpriteLimit, CPU_CLOCKS}, osd::{self, EditKey, MenuItem, Osd, OwnerEditor, PauseMenu}, parse_rom, parse_rom_data, read_header, read_rom, regress, renderers, romdb::{self, GameInfo}, saves, soc::{self, diagnostics, SoC}, sound::{buffer::SampleBuffer, filter::{SoundProfile, SpeakerSettings}}, speed::SpeedSettings, storage::{FileStorage, Storage}, tracediff, verify};
 
 #[global_allocator]
 /// This is a fast memory allocator made by Microsoft.
@@ -22,40 +21,19 @@ use crate::bus::io_bus::{keypad::Keys, IOBus};
 /// It improved performance quite significantly when I added it.
 static GLOBAL: MiMalloc = MiMalloc;
 
-/// This module contains the I/O and memory busses
-/// 
-/// The WonderSwan contains only a single memory bus and a single I/O bus.
-/// These classes are therefore intended to produce singletons, to which multiple
-/// references can be shared between the different components, mimicking the
-/// system's original architecture.
-pub mod bus;
-
-/// This module contains the cartridge
-#[allow(non_camel_case_types)]
-#[allow(non_snake_case)]
-pub mod cartridge;
-
-/// This module contains the WonderSwan's CPU
+/// Audio output
 /// 
-/// This file's contents specifically are made up of things that would be useful to both defining the opcodes and operating the CPU
-#[allow(non_snake_case)]
-pub mod cpu;
+/// Plays the SoC's samples through one of several backends, chosen on the command line
+pub mod audio;
 
-/// This module contains the WonderSwan's display chip
+/// Keyboard input presets
 /// 
-/// Actually displaying the screen to the Window is hadnled through SDL in main
-#[allow(non_camel_case_types)]
-#[allow(non_snake_case)]
-pub mod display;
+/// Maps keys to the console's buttons for either orientation of the console
+pub mod input;
 
-/// The WonderSwan color and WonderCrystal DMAs
-pub mod dma;
+/// Scaling of frames to the window, which can be resized to any size
+pub mod video;
 
-/// System on a chip
-pub mod soc;
-
-/// The WonderSwan's sound chip
-pub mod sound;
 
 /// Width of the window that appears when you run the program
 const WINDOW_WIDTH: u32 = 1344;
@@ -67,34 +45,10 @@ const FRAME_WIDTH: u32 = 224;
 /// Height of the WonderSwan's screen when in landscape orientation
 const FRAME_HEIGHT: u32 = 144;
 
-/// A struct holding a vector of audio samples behind a Mutex
-/// 
-/// The samples in here are generated by the audio system and the vector is updated at the WonderSwan's samplerate of 24kHz
-struct SampleStream {
-    /// Vector containing the samples
-    /// 
-    /// In the current implementation only the 8-bit monaural speaker audio is supported.
-    /// The vector is set up to contain u16 tuplets to make it easier to extend this project
-    /// to output stereo 16-bit headphone audio.
-    samples: Arc<Mutex<Vec<(u16, u16)>>>
-}
-
-/// This block will likely need to be rewritten to add headphone support.
-/// 
-/// It currently outputs only the low byte of the left stereo channel.
-/// This is not a problem for the current implementation as only monaural audio is supported.
-impl AudioCallback for SampleStream {
-    type Channel = u8;
-
-    fn callback(&mut self, out: &mut [Self::Channel]) {
-        let mut buffer = self.samples.lock().unwrap();
-        for request in out {
-            if let Some(sample) = buffer.pop() {
-                *request = sample.0 as u8
-            }
-        }
-    }
-}
+/// How long the front-end waits for a frame before handling events again
+const PRESENT_POLL: Duration = Duration::from_millis(4);
+/// How long the known issues of a game are shown for after loading it
+const NOTICE_TIME: Duration = Duration::from_secs(8);
 
 /// The emulator's main function
 /// 
@@ -106,233 +60,610 @@ impl AudioCallback for SampleStream {
 /// If an `Err<String>` is produced it will instead return it and close the emulator.
 fn main() -> Result<(), String> {
     let args: Vec<_> = env::args().collect();
+    if args.get(1).map(String::as_str) == Some("regress") {
+        return regress::run(&args[2..]);
+    }
+    if args.get(1).map(String::as_str) == Some("report") {
+        return report(&args[2..]);
+    }
+    if args.get(1).map(String::as_str) == Some("info") {
+        return info(&args[2..]);
+    }
+    if args.get(1).map(String::as_str) == Some("--validate-rom") {
+        return validate_roms(&args[2..]);
+    }
+    if args.get(1).map(String::as_str) == Some("debug") {
+        return soc::debugger::run(&args[2..]);
+    }
+    if args.get(1).map(String::as_str) == Some("fuzz") {
+        return headless::fuzz(&args[2..]);
+    }
+    if args.get(1).map(String::as_str) == Some("dump") {
+        return headless::dump(&args[2..]);
+    }
+    if args.get(1).map(String::as_str) == Some("save") {
+        return saves::run(&args[2..]);
+    }
+    if args.get(1).map(String::as_str) == Some("compare") {
+        return compare::run(&args[2..]);
+    }
+    if args.get(1).map(String::as_str) == Some("renderers") {
+        return renderers::run(&args[2..]);
+    }
+    if args.get(1).map(String::as_str) == Some("tracediff") {
+        return tracediff::run(&args[2..]);
+    }
+    if args.get(1).map(String::as_str) == Some("btrace") {
+        return trace::run(&args[2..]);
+    }
+    if args.get(1).map(String::as_str) == Some("verify") {
+        return verify::run(&args[2..]);
+    }
+    if args.iter().skip(2).any(|arg| arg == "--time") {
+        return bench::run(&args[1..]);
+    }
     let game = if args.len() > 1 {Some(&args[1])} else {None};
     let trace = args.get(2) == Some(&"trace".to_string());
     let mute = args.get(2) == Some(&"mute".to_string()) || trace;
-
-    let samples = Arc::new(Mutex::new(Vec::new()));
-
-    let mut global_color = false;
-
-    let mut soc = if let Some(game) = game {
-        let (color, ram_content, ieeprom, eeprom, rom, mapper, sram, rom_info) = parse_rom(game);
-        global_color = color;
-        SoC::new(color, ram_content, ieeprom, eeprom, rom, mapper, sram, trace, Arc::clone(&samples), mute, rom_info)
-    } else {SoC::test_build()};
+    let strict = args.iter().skip(2).any(|arg| arg == "strict");
+    let opcode_stats = args.iter().skip(2).any(|arg| arg == "opstats");
+    let triage = args.iter().skip(2).any(|arg| arg == "triage");
+    // Escape quits right away instead of opening the pause menu
+    let classic_escape = args.iter().skip(2).any(|arg| arg == "--classic-escape");
+    // Keeps the saves of bad dumps whose footer declares no save memory
+    let probe_saves = args.iter().skip(2).any(|arg| arg == "probe");
+    // Enhancements saved for the game or toggled later are ignored, so that reports come from the hardware's behavior
+    let pure = args.iter().skip(2).any(|arg| arg == "--pure");
+    // A tier given on the command line overrides the one saved for the game
+    let accuracy = args.iter().skip(2).find_map(|arg| Accuracy::from_name(arg))
+        .or(game.and_then(|game| Accuracy::load(game)))
+        .unwrap_or(Accuracy::Balanced);
+    let sound_profile = args.iter().skip(2).find_map(|arg| SoundProfile::from_name(arg)).unwrap_or(SoundProfile::Clean);
+    let sprite_limit = args.iter().skip(2).find_map(|arg| SpriteLimit::from_name(arg))
+        .or(game.and_then(|game| SpriteLimit::load(game)))
+        .unwrap_or(SpriteLimit::Hardware);
+    // Rates the session for the compatibility list, the report is written when quitting
+    let compat_report = args.iter().skip(2).position(|arg| arg == "--report").map(|idx| {
+        // The status follows the flag
+        let status = args.get(2 + idx + 1).ok_or("--report needs a status, perfect, playable, ingame or broken")?;
+        romdb::Status::from_name(status).ok_or(format!("Unknown status {}, expected perfect, playable, ingame or broken", status))
+    }).transpose()?;
+    let compat_notes = args.iter().skip(2).find_map(|arg| arg.strip_prefix("notes=")).unwrap_or("");
+    let audio_backend = args.iter().skip(2).find_map(|arg| audio::Backend::from_name(arg)).unwrap_or(audio::Backend::Sdl);
+    let scaling = args.iter().skip(2).find_map(|arg| Scaling::from_name(arg)).unwrap_or(Scaling::Nearest);
+    let mut speaker = SpeakerSettings::new();
+    for arg in args.iter().skip(2) {speaker.parse_setting(arg);}
+    let mut colors = game.and_then(|game| ColorSettings::load(game)).unwrap_or_default();
+    if let Some(profile) = args.iter().skip(2).find_map(|arg| ColorProfile::from_name(arg)) {colors.profile = profile}
+    for arg in args.iter().skip(2) {colors.parse_setting(arg);}
+    let grayscale = args.iter().skip(2).find_map(|arg| Grayscale::from_setting(arg)).unwrap_or(Grayscale::Off);
+    let speed = game.and_then(|game| SpeedSettings::load(game)).unwrap_or_default();
+    // Overclocks or underclocks the CPU, in percent of the console's clock
+    let cpu_clock = args.iter().skip(2).find_map(|arg| arg.strip_prefix("cpu=")).map(|clock| {
+        clock.parse().ok().filter(|clock| CPU_CLOCKS.contains(clock))
+            .ok_or(format!("Invalid CPU clock {}, expected a percentage from {} to {}", clock, CPU_CLOCKS.start(), CPU_CLOCKS.end()))
+    }).transpose()?.unwrap_or(100);
+    // Sets the cartridge clock, which then runs along with the host's
+    let rtc_start = args.iter().skip(2).find_map(|arg| arg.strip_prefix("rtc=")).map(|time| {
+        rtc::parse_time(time).ok_or(format!("Invalid time {}, expected YYYY-MM-DD or YYYY-MM-DDTHH:MM:SS", time))
+    }).transpose()?;
+
+    let samples = SampleBuffer::shared();
+    let options = SharedOptions::new(EmulatorOptions {
+        trace,
+        mute,
+        invalid_opcode: if strict {InvalidOpcodeBehavior::Fault} else {InvalidOpcodeBehavior::Nop},
+        accuracy,
+        sound_profile,
+        speaker,
+        sprite_limit,
+        colors,
+        grayscale,
+        speed,
+        opcode_stats,
+        cpu_clock,
+        pure,
+        triage,
+        probe_saves,
+        ..EmulatorOptions::new()
+    });
 
     let sdl_context = sdl2::init()?;
     let video_subsystem = sdl_context.video()?;
     let window = video_subsystem
         .window("WonderCrab", WINDOW_WIDTH, WINDOW_HEIGHT)
         .position_centered()
+        .resizable()
         .build().unwrap();
 
-    let audio_subsystem = sdl_context.audio()?;
-    let desired_spec = AudioSpecDesired {
-        freq: Some(24000),
-        channels: Some(1),
-        samples: Some(1024),
-    };
-    let audio_device = audio_subsystem.open_playback(None, &desired_spec, |_| SampleStream {samples: Arc::clone(&samples)})?;
-    audio_device.resume();
+    // SDL stays available as a fallback when another backend cannot open a device
+    let _audio = audio::open(audio_backend, &sdl_context, Arc::clone(&samples), options.clone()).or_else(|e| match audio_backend {
+        audio::Backend::Sdl => Err(e),
+        backend => {
+            println!("Could not open {} audio, falling back to SDL: {}", backend.name(), e);
+            audio::open(audio::Backend::Sdl, &sdl_context, Arc::clone(&samples), options.clone())
+        }
+    })?;
 
     let mut canvas = window.into_canvas().present_vsync().build().unwrap();
     canvas.set_logical_size(FRAME_WIDTH, FRAME_HEIGHT).unwrap();
     let creator = canvas.texture_creator();
-    let mut texture = creator.create_texture_target(PixelFormatEnum::RGB24, FRAME_WIDTH, FRAME_HEIGHT).unwrap();
+    let mut scaler = Scaler::new(&creator, (FRAME_WIDTH, FRAME_HEIGHT), scaling)?;
     let mut event_pump = sdl_context.event_pump()?;
 
-    let mut key_map = HashMap::new();
-    key_map.insert(Keycode::A, Keys::Y1);
-    key_map.insert(Keycode::W, Keys::Y2);
-    key_map.insert(Keycode::D, Keys::Y3);
-    key_map.insert(Keycode::S, Keys::Y4);
-    key_map.insert(Keycode::U, Keys::X1);
-    key_map.insert(Keycode::K, Keys::X2);
-    key_map.insert(Keycode::J, Keys::X3);
-    key_map.insert(Keycode::H, Keys::X4);
-    key_map.insert(Keycode::KP_4, Keys::X1);
-    key_map.insert(Keycode::KP_8, Keys::X2);
-    key_map.insert(Keycode::KP_6, Keys::X3);
-    key_map.insert(Keycode::KP_5, Keys::X4);
-    key_map.insert(Keycode::Return, Keys::Start);
-    key_map.insert(Keycode::Z, Keys::B);
-    key_map.insert(Keycode::X, Keys::A);
-
-    let mut previous = Instant::now();
-    let mut rotated = false;
+    // The ROM is read on a worker thread while the window shows that it is loading, as large zipped sets take a while to inflate
+    let rom = match game {
+        Some(game) => match load_rom(game, &mut canvas, &mut scaler, &mut event_pump)? {
+            Some(rom) => Some(rom),
+            None => return Ok(()),
+        },
+        None => None,
+    };
+    // The ROM is parsed here as the window needs its title and orientation, the SoC itself is built on the emulation thread
+    let machine = game.zip(rom).map(|(game, rom)| parse_rom_data(game, rom));
+    let color = machine.as_ref().is_some_and(|machine| machine.0);
+    // The demo is in the database too, so that the window says what is running
+    let game_info = machine.as_ref().map_or_else(|| romdb::lookup(&demo::rom()), |machine| machine.8.clone());
+    let rom_hash = machine.as_ref().map(|machine| romdb::sha1(&machine.4));
+
+    let rtc_battery = game.and_then(|game| FileStorage.read(&format!("{}.rtc", game)));
+
+    let soc_samples = Arc::clone(&samples);
+    let soc_options = options.clone();
+    options.update(|options| options.color = color);
+    let build = move || match machine {
+        Some((_, ram_content, ieeprom, eeprom, rom, mapper, sram, rom_info, info)) => {
+            let mut soc = SoC::new(ram_content, ieeprom, eeprom, rom, mapper, sram, soc_samples, soc_options, rom_info);
+            if let Some(info) = info {soc.apply_quirks(info.quirks)}
+            // The time the game set last time is kept like a cartridge's battery would, unless another start was asked for
+            match rtc_start {
+                Some(time) => soc.set_time_source(Box::new(rtc::OffsetClock::starting_at(rtc::HostClock, time))),
+                None => {
+                    soc.set_time_source(Box::new(rtc::HostClock));
+                    if let Some(battery) = rtc_battery {soc.restore_rtc(&battery);}
+                }
+            }
+            soc
+        }
+        None => demo::demo_soc(soc_samples, soc_options),
+    };
+    if let Some(info) = &game_info {canvas.window_mut().set_title(&format!("WonderCrab - {}", info.title)).unwrap()}
+
+    let mut rotated = game_info.as_ref().is_some_and(|info| info.quirks.vertical);
     let mut dst = Rect::new(0, 0, FRAME_WIDTH, FRAME_HEIGHT);
-    let mut first_frame = true;
+    if rotated {set_rotation(&mut canvas, &mut dst, rotated)}
+
+    let mut preset = game.and_then(|game| Preset::load(game)).unwrap_or(Preset::for_orientation(rotated));
+    let mut key_map = preset.key_map();
+    let mut osd = Osd::new();
+    let mut low_battery = false;
+    if let Some(known_issues) = game_info.as_ref().and_then(known_issues) {
+        println!("{}", known_issues);
+        osd.notify(&known_issues, NOTICE_TIME);
+    }
+    // Buttons currently held, as sent to the emulation thread
+    let mut held = Keys::empty();
+    let mut macros = game.map_or(Ok(MacroBindings::default()), |game| MacroBindings::load(game)).unwrap_or_else(|e| {
+        println!("Could not load macros: {}", e);
+        MacroBindings::default()
+    });
+    // Whether a macro is being recorded, and the recorded macro waiting for a key to be bound to
+    let mut recording = false;
+    let mut unbound = None;
+    // Frames identical to the last one presented, such as on still screens, are neither uploaded nor presented again
+    let mut presents = PresentFilter::default();
+
+    let emulation = EmulationThread::spawn(build, game.cloned(), color, options.clone());
 
     loop {
-        if soc.tick() {
-            let now = Instant::now();
-            let delta = if first_frame {
-                first_frame = false;
-                Instant::now() - Instant::now()
-            } else {
-                now - previous
-            };
-            previous = now;
-
-            std::thread::sleep(Duration::from_micros(13_250u64.saturating_sub(delta.as_micros() as u64)));
-
-            canvas.clear();
-
-            let frame = soc.get_lcd();
-            texture.update(None,&frame.borrow()[..], FRAME_WIDTH as usize * 3).unwrap();
-            
-            let angle = if rotated {270.0} else {0.0};
-            if rotated {
-                canvas.copy_ex(&texture, None, dst, angle, None, false, false).unwrap();
-            } else {
-                canvas.copy(&texture, None, None)?;
+        match emulation.frames.recv_timeout(PRESENT_POLL) {
+            Ok(mut frame) => {
+                // Only the newest frame is worth presenting
+                while let Ok(newer) = emulation.frames.try_recv() {
+                    emulation.recycle(std::mem::replace(&mut frame, newer));
+                }
+
+                osd.draw(&mut frame[..], held);
+                if presents.should_present(&frame[..]) {
+                    let present_start = Instant::now();
+                    canvas.clear();
+                    let texture = scaler.texture(&mut canvas, &frame[..])?;
+
+                    let angle = if rotated {270.0} else {0.0};
+                    if rotated {
+                        canvas.copy_ex(texture, None, dst, angle, None, false, false).unwrap();
+                    } else {
+                        canvas.copy(texture, None, None)?;
+                    }
+                    canvas.present();
+                    emulation.send(Command::PresentTime(present_start.elapsed()));
+                }
+                emulation.recycle(frame);
             }
-            canvas.present();
-            
-            for event in event_pump.poll_iter() {
-                match event {
-                    Event::Quit { .. } | Event::KeyDown {keycode: Some(Keycode::Escape), ..} => {
-                        // for addr in 0x3B52..=0x3B53 {println!("SCREEN ELEMENT: [{:04X}] = {:02X}", addr, soc.read_mem(addr))}
-                        // for addr in 0x4340..=0x435F {println!("TILE: [{:04X}] = {:02X}", addr, soc.read_mem(addr))}
-                        // soc.get_display().debug_screen_1();
-                        // soc.io_bus.borrow().debug_eeprom();
-                        if let Some(game) = game {save_game(soc.io_bus, global_color, game)};
-                        return Ok(());
-                    },
-                    Event::KeyDown { keycode, .. } => {
-                        if let Some(key) = keycode {
-                            if let Some(Keycode::R) = keycode {
-                                rotated = !rotated;
-                                if rotated {
-                                    canvas.window_mut().set_size(WINDOW_HEIGHT, WINDOW_WIDTH).unwrap();
-                                    canvas.window_mut().set_position(sdl2::video::WindowPos::Centered, sdl2::video::WindowPos::Centered);
-                                    canvas.set_logical_size(FRAME_HEIGHT, FRAME_WIDTH).unwrap();
-                                    dst.set_x(-40);
-                                    dst.set_y(40);
-                                    canvas.clear();
-                                } else {
-                                    canvas.window_mut().set_size(WINDOW_WIDTH, WINDOW_HEIGHT).unwrap();
-                                    canvas.window_mut().set_position(sdl2::video::WindowPos::Centered, sdl2::video::WindowPos::Centered);
-                                    canvas.set_logical_size(FRAME_WIDTH, FRAME_HEIGHT).unwrap();
-                                    dst.set_x(0);
-                                    dst.set_y(0);
-                                    canvas.clear();
-                                }
-                            }
-                            // Tracing makes the framerate unplayable,
-                            // this is disabled to make sure the user
-                            // doesn't press it by accident
-                            
-                            /*
-                            if let Some(Keycode::T) = keycode {
-                                soc.cpu.trace = !trace;
-                                soc.mute = !mute;
+            Err(RecvTimeoutError::Timeout) => {}
+            Err(RecvTimeoutError::Disconnected) => return Err("The emulation thread stopped unexpectedly".to_string()),
+        }
+
+        let mut quitting = false;
+        for event in event_pump.poll_iter() {
+            match event {
+                // While paused the keyboard only goes to the menu, Escape resumes
+                Event::KeyDown {keycode: Some(keycode), ..} if osd.pause_menu.is_some() => match keycode {
+                    Keycode::Return => {
+                        let item = osd.pause_menu.take().unwrap().selected();
+                        let outcome = match item {
+                            MenuItem::Resume => None,
+                            MenuItem::SaveState => Some(request(&emulation, Command::SaveState)),
+                            MenuItem::LoadState => Some(request(&emulation, Command::LoadState)),
+                            MenuItem::Screenshot => Some(request(&emulation, Command::Screenshot)),
+                            MenuItem::Reset => {
+                                emulation.send(Command::Reset);
+                                Some("Reset".to_string())
                             }
-                            */
-                            
-                            if let Some(key) = key_map.get(&key) {
-                                soc.io_bus.borrow_mut().set_key(*key, true);
+                            MenuItem::Quit => {
+                                quitting = true;
+                                None
                             }
+                        };
+                        if let Some(outcome) = outcome {
+                            println!("{}", outcome);
+                            osd.notify(&outcome, NOTICE_TIME);
                         }
+                        emulation.send(Command::Pause(false));
+                    }
+                    Keycode::Escape => {
+                        osd.pause_menu = None;
+                        emulation.send(Command::Pause(false));
                     }
-                    Event::KeyUp { keycode, .. } => {
-                        if let Some(key) = keycode {
-                            if let Some(key) = key_map.get(&key) {
-                                soc.io_bus.borrow_mut().set_key(*key, false);
+                    keycode => if let (Some(menu), Some(key)) = (&mut osd.pause_menu, edit_key(keycode)) {menu.handle(key)},
+                },
+                // While the owner editor is open the keyboard only goes to it
+                Event::KeyDown {keycode: Some(keycode), ..} if osd.owner_editor.is_some() => match keycode {
+                    Keycode::Return => {
+                        if let Some(editor) = osd.owner_editor.take() {emulation.send(Command::WriteOwner(editor.owner))}
+                    }
+                    Keycode::Escape => osd.owner_editor = None,
+                    keycode => if let (Some(editor), Some(key)) = (&mut osd.owner_editor, edit_key(keycode)) {editor.handle(key)},
+                },
+                // The key pressed after recording a macro plays it from then on, Escape discards it
+                Event::KeyDown {keycode: Some(keycode), ..} if unbound.is_some() => match keycode {
+                    Keycode::Escape => {
+                        unbound = None;
+                        osd.notify("Macro discarded", NOTICE_TIME);
+                    }
+                    keycode if MacroBindings::bindable(keycode) => {
+                        macros.bind(keycode, unbound.take().unwrap());
+                        osd.notify(&format!("Macro bound to {}", keycode.name()), NOTICE_TIME);
+                        if let Some(game) = game {macros.save(game).unwrap_or_else(|e| println!("Could not save macros: {}", e))}
+                    }
+                    keycode => osd.notify(&format!("{} is already bound, press another key", keycode.name()), NOTICE_TIME),
+                },
+                // The window has to be drawn again once resized or uncovered, even if the game's picture did not change
+                Event::Window {..} => presents.invalidate(),
+                Event::Quit { .. } => quitting = true,
+                Event::KeyDown {keycode: Some(Keycode::Escape), ..} if classic_escape => quitting = true,
+                Event::KeyDown { keycode: Some(key), .. } => {
+                    match Hotkey::from_keycode(key) {
+                        // Help listing every hotkey, generated from the bindings
+                        Some(Hotkey::Help) => osd.help = if osd.help.is_some() {None} else {Some(Hotkey::help())},
+                        Some(Hotkey::Rotate) => {
+                            rotated = !rotated;
+                            set_rotation(&mut canvas, &mut dst, rotated);
+                            presents.invalidate();
+                            preset = Preset::for_orientation(rotated);
+                            key_map = preset.key_map();
+                        }
+                        // Input presets, the choice is remembered for the game
+                        Some(Hotkey::InputPreset) => {
+                            preset = preset.next();
+                            key_map = preset.key_map();
+                            println!("Input preset: {}", preset.name());
+                            if let Some(game) = game {preset.save(game).unwrap_or_else(|e| println!("Could not save input preset: {}", e))}
+                        }
+                        // Accuracy tiers, the choice is remembered for the game
+                        Some(Hotkey::Accuracy) => {
+                            let accuracy = options.get().accuracy.next();
+                            options.update(|options| options.accuracy = accuracy);
+                            println!("Accuracy: {}", accuracy.name());
+                            if let Some(game) = game {accuracy.save(game).unwrap_or_else(|e| println!("Could not save accuracy: {}", e))}
+                        }
+                        // Sound profiles
+                        Some(Hotkey::SoundProfile) => {
+                            let profile = options.get().sound_profile.next();
+                            options.update(|options| options.sound_profile = profile);
+                            println!("Sound profile: {}", profile.name());
+                        }
+                        // Sprite limit, an enhancement so it is only switched on request and remembered per game
+                        Some(Hotkey::SpriteLimit) => {
+                            let limit = options.get().sprite_limit.next();
+                            options.update(|options| options.sprite_limit = limit);
+                            println!("Sprite limit: {}", limit.name());
+                            if let Some(game) = game {limit.save(game).unwrap_or_else(|e| println!("Could not save sprite limit: {}", e))}
+                        }
+                        // Color profiles, remembered per game along with the saturation
+                        // Scaling to the window
+                        Some(Hotkey::Scaling) => {
+                            scaler.set_scaling(scaler.scaling().next())?;
+                            presents.invalidate();
+                            println!("Scaling: {}", scaler.scaling().name());
+                        }
+                        Some(Hotkey::ColorProfile) => {
+                            let colors = ColorSettings {profile: options.get().colors.profile.next(), ..options.get().colors};
+                            options.update(|options| options.colors = colors);
+                            println!("Color profile: {}", colors.profile.name());
+                            if let Some(game) = game {colors.save(game).unwrap_or_else(|e| println!("Could not save color profile: {}", e))}
+                        }
+                        // Grayscale, an accessibility setting of the player rather than of the game so it is not remembered
+                        Some(Hotkey::Grayscale) => {
+                            let grayscale = options.get().grayscale.next();
+                            options.update(|options| options.grayscale = grayscale);
+                            println!("Grayscale: {}", grayscale.name());
+                        }
+                        // Speed settings, remembered per game
+                        Some(hotkey @ (Hotkey::Faster | Hotkey::Slower | Hotkey::PreservePitch)) => {
+                            let mut speed = options.get().speed;
+                            match hotkey {
+                                Hotkey::Faster => speed.speed = speed.speed.faster(),
+                                Hotkey::Slower => speed.speed = speed.speed.slower(),
+                                _ => speed.preserve_pitch = !speed.preserve_pitch,
+                            }
+                            options.update(|options| options.speed = speed);
+                            println!("Speed: {}, {}", speed.speed.name(), if speed.preserve_pitch {"pitch kept"} else {"pitch scaled"});
+                            if let Some(game) = game {speed.save(game).unwrap_or_else(|e| println!("Could not save speed: {}", e))}
+                        }
+
+                        // Layer toggles
+                        Some(Hotkey::Screen1) => options.update(|options| options.layers.screen_1 = !options.layers.screen_1),
+                        Some(Hotkey::Screen2) => options.update(|options| options.layers.screen_2 = !options.layers.screen_2),
+                        Some(Hotkey::Sprites) => options.update(|options| options.layers.sprites = !options.layers.sprites),
+                        // Tints pixels by the layer they come from, to check layer priority
+                        Some(Hotkey::LayerTint) => options.update(|options| options.layers.tint = !options.layers.tint),
+
+                        // Input overlay, for streaming or checking TAS inputs
+                        Some(Hotkey::InputOverlay) => osd.input_overlay = !osd.input_overlay,
+                        // Owner settings editor, the settings are fetched from the emulation thread so that they match the IEEPROM
+                        Some(Hotkey::OwnerEditor) => {
+                            let (reply, owner) = mpsc::channel();
+                            emulation.send(Command::ReadOwner(reply));
+                            match owner.recv_timeout(Duration::from_secs(1)) {
+                                Ok(owner) => osd.owner_editor = Some(OwnerEditor::new(owner)),
+                                Err(e) => println!("Could not read owner settings: {}", e),
+                            }
+                        }
+                        // Heat map of memory accesses, drawn by the emulation thread as that is where the counters are
+                        Some(Hotkey::HeatMap) => {
+                            osd.heat_map = !osd.heat_map;
+                            emulation.send(Command::HeatMap(osd.heat_map));
+                        }
+                        // Oscilloscope of the sound channels, recorded by the sound chip only while shown
+                        Some(Hotkey::Scope) => {
+                            osd.scope = !osd.scope;
+                            emulation.send(Command::Scope(osd.scope));
+                        }
+                        // Low battery, to see how the game handles it
+                        Some(Hotkey::LowBattery) => {
+                            low_battery = !low_battery;
+                            emulation.send(Command::LowBattery(low_battery));
+                            println!("Low battery: {}", low_battery);
+                        }
+                        // Audio capture of the emulated samples, unaffected by the playback speed
+                        Some(Hotkey::AudioCapture) => emulation.send(Command::AudioCapture),
+                        // Input macros, recorded by the emulation thread so that they keep the frames between presses
+                        Some(Hotkey::Macro) if recording => {
+                            recording = false;
+                            let (reply, recorded) = mpsc::channel();
+                            emulation.send(Command::FinishMacro(reply));
+                            match recorded.recv_timeout(Duration::from_secs(1)) {
+                                Ok(recorded) if recorded.is_empty() => osd.notify("Nothing was pressed, macro discarded", NOTICE_TIME),
+                                Ok(recorded) => {
+                                    unbound = Some(recorded);
+                                    osd.notify("Press a key to bind the macro to, Escape to discard it", NOTICE_TIME);
+                                }
+                                Err(e) => println!("Could not record macro: {}", e),
                             }
                         }
+                        Some(Hotkey::Macro) => {
+                            recording = true;
+                            emulation.send(Command::RecordMacro);
+                            osd.notify(&format!("Recording macro, {} stops", Hotkey::Macro.key().name()), NOTICE_TIME);
+                        }
+                        // Diagnostic bundle for bug reports
+                        Some(Hotkey::BugReport) => emulation.send(Command::BugReport),
+                        // Histograms of the host's frame timings, for reports of uneven pacing
+                        Some(Hotkey::Timing) => emulation.send(Command::TimingReport),
+                        // The game stays paused until the menu is closed, as the emulation thread keeps sending the last frame to draw it over
+                        Some(Hotkey::Menu) => {
+                            emulation.send(Command::Pause(true));
+                            osd.pause_menu = Some(PauseMenu::default());
+                        }
+                        None => {}
+                    }
+                    // Tracing makes the framerate unplayable,
+                    // this is disabled to make sure the user
+                    // doesn't press it by accident
+                    
+                    /*
+                    if let Some(Keycode::T) = keycode {
+                        options.update(|options| {
+                            options.trace = !options.trace;
+                            options.mute = options.trace;
+                        });
+                    }
+                    */
+                    
+                    if let Some(recorded) = macros.get(key) {emulation.send(Command::PlayMacro(recorded.clone()))}
+                    if let Some(key) = key_map.get(&key) {
+                        held.insert(*key);
+                        emulation.send(Command::Key(*key, true));
                     }
-                    _ => {}
                 }
+                Event::KeyUp { keycode, .. } => {
+                    if let Some(key) = keycode {
+                        if let Some(key) = key_map.get(&key) {
+                            held.remove(*key);
+                            emulation.send(Command::Key(*key, false));
+                        }
+                    }
+                }
+                _ => {}
+            }
+        }
+        if quitting {
+            let session = emulation.quit();
+            if let (Some(status), Some(game), Some(hash)) = (compat_report, game, rom_hash) {
+                // Games missing from the database are reported under their file name
+                let entry = GameInfo {
+                    status: Some(status),
+                    notes: [compat_notes.to_string(), format!("[{}{}]", session.describe(), if pure {", pure"} else {""})].join(" ").trim().to_string(),
+                    ..game_info.clone().unwrap_or(GameInfo {title: game.clone(), quirks: Default::default(), status: None, notes: String::new()})
+                };
+                romdb::append_report(&mut FileStorage, &entry, &hash)?;
+                println!("Added a compatibility report to {}", romdb::COMPAT_REPORT);
             }
+            return Ok(());
         }
     }
 }
 
-/// Extracts information from the requested ROM image and any existing save files
-/// 
-/// # Return value
-/// This function returns a tuple containing the following:
-/// - `color: bool` whether or not the ROM supports color output
-/// - `save: Vec<u8>` contents of SRAM
-/// - `ieeprom: Vec<u8>` contents of the IEEPROM
-/// - `eeprom: Vec<u8>` contents of the cartridge EEPROM
-/// - `rom: Vec<u8>` contents of the ROM
-/// - `mapper: Mapper` the mapper chip used by the cartridge
-/// - `sram: bool` whether or not the cartridge contains SRAM
-/// - `rom_info: u8` bits 2 and 3 of the system control port 0xA0
-fn parse_rom(game: &str) -> (bool, Vec<u8>, Vec<u8>, Vec<u8>, Vec<u8>, Mapper, bool, u8) {
-    let rom = std::fs::read(format!("{}.ws", game)).or_else(|_| {std::fs::read(format!("{}.wsc", game))}).unwrap();
-    let footer = rom.last_chunk::<16>().unwrap();
-    let color = footer[0x7] & 1 != 0;
-    let (ram_size, sram) = match footer[0xB] {
-        0x00 => (0x0u32, true),
-        0x01 | 0x02 => (0x08000, true),
-        0x03 => (0x20000, true),
-        0x04 => (0x40000, true),
-        0x05 => (0x80000, true),
-        0x10 => (0x0400, false),
-        0x20 => (0x4000, false),
-        0x50 => (0x2000, false),
-        _ => panic!("Unknown save type!")
-    };
+/// Sends a command whose outcome the emulation thread sends back, and returns it to be shown
+fn request(emulation: &EmulationThread, command: fn(mpsc::Sender<String>) -> Command) -> String {
+    let (reply, outcome) = mpsc::channel();
+    emulation.send(command(reply));
+    outcome.recv_timeout(Duration::from_secs(5)).unwrap_or_else(|e| format!("No answer from the emulation thread: {}", e))
+}
 
-    let ieeprom_path = if color {"wsc.ieeprom"} else {"ws.ieeprom"};
-    let eeprom_path = format!("{}.eeprom", game);
-    let sram_path = format!("{}.sram", game);
+/// Describes the status and known issues of a game, unless it runs perfectly
+fn known_issues(info: &GameInfo) -> Option<String> {
+    let status = info.status?;
+    if status == romdb::Status::Perfect && info.notes.is_empty() {return None}
+    Some(if info.notes.is_empty() {format!("Compatibility: {}", status.name())} else {format!("Compatibility: {} - {}", status.name(), info.notes)})
+}
 
-    let ieeprom = std::fs::read(ieeprom_path).or_else(|_| Ok::<_, ()>(Vec::new())).unwrap();
-    let eeprom = std::fs::read(eeprom_path).or_else(|_| Ok::<_, ()>(Vec::new())).unwrap();
-    let save = std::fs::read(sram_path).or_else(|_| {Ok::<_, ()>(vec![0; ram_size as usize])}).unwrap();
+/// Entry point of the `info` command
+/// 
+/// Usage: `info <rom>`
+/// 
+/// Prints the ROM's footer along with its SHA-1, and its title, status and known issues in the ROM database.
+fn info(args: &[String]) -> Result<(), String> {
+    let game = args.first().ok_or("Usage: info <rom>")?;
+    let rom = read_rom(game)?;
+    let (header, info) = read_header(game, &rom)?;
+    if let Some(info) = &info {println!("Title:       {}", info.title)}
+    print!("{}", header.describe());
+    println!("SHA-1:       {}", romdb::sha1(&rom).iter().map(|byte| format!("{:02x}", byte)).collect::<String>());
+    if let Some(status) = info.as_ref().and_then(|info| info.status) {println!("Status:      {}", status.name())}
+    if let Some(info) = info.filter(|info| !info.notes.is_empty()) {println!("Notes:       {}", info.notes)}
+    Ok(())
+}
 
-    let mapper = match footer[0xD] {
-        0 => Mapper::B_2001,
-        1 => Mapper::B_2003,
-        _ => panic!("Unknown mapper!"),
-    };
+/// Entry point of the `--validate-rom` mode
+/// 
+/// Usage: `--validate-rom <rom>...`
+/// 
+/// Checks the footer, size and checksum of each ROM without launching it, printing one line per problem or `ok`.
+/// ROMs may be given with or without their extension. Fails if any ROM has a problem or cannot be read, so that scripts can tell from the exit status.
+fn validate_roms(args: &[String]) -> Result<(), String> {
+    if args.is_empty() {return Err("Usage: --validate-rom <rom>...".to_string())}
+    let mut failed = 0;
+    for path in args {
+        let problems = std::fs::read(path).or_else(|_| read_rom(path))
+            .and_then(|rom| RomInfo::parse(&rom).map(|header| header.problems(&rom)))
+            .unwrap_or_else(|e| vec![e]);
+        if problems.is_empty() {println!("{}: ok", path)}
+        for problem in &problems {println!("{}: {}", path, problem)}
+        if !problems.is_empty() {failed += 1}
+    }
+    match failed {
+        0 => Ok(()),
+        failed => Err(format!("{} of {} ROMs failed validation", failed, args.len())),
+    }
+}
 
-    let rom_info = footer[0xC] & 0x0C;
+/// Entry point of the `report` command
+/// 
+/// Usage: `report <rom> [frames]`
+/// 
+/// Runs the ROM without a window for the given amount of frames, or until it panics, and writes a bug report next to it.
+fn report(args: &[String]) -> Result<(), String> {
+    let game = args.first().ok_or("Usage: report <rom> [frames]")?;
+    let frames = args.get(1).and_then(|frames| frames.parse().ok()).unwrap_or(60);
+
+    let (color, ram_content, ieeprom, eeprom, rom, mapper, sram, rom_info, _) = parse_rom(game);
+    let options = SharedOptions::new(EmulatorOptions {color, mute: true, ..EmulatorOptions::new()});
+    let mut soc = SoC::new(ram_content, ieeprom, eeprom, rom, mapper, sram, SampleBuffer::shared(), options, rom_info);
+
+    let mut frame = 0;
+    let result = std::panic::catch_unwind(std::panic::AssertUnwindSafe(|| {
+        while frame < frames {
+            if soc.tick() {frame += 1}
+        }
+    }));
+    if result.is_err() {println!("The emulator panicked on frame {}", frame)}
 
-    if mapper == Mapper::B_2003 {println!("Mapper 2003")}
+    let path = diagnostics::write_bug_report(&soc, game).map_err(|e| e.to_string())?;
+    println!("Wrote bug report to {}", path);
+    Ok(())
+}
 
-    (color, save, ieeprom, eeprom, rom, mapper, sram, rom_info)
+/// Translates a key pressed while the owner editor is open
+fn edit_key(keycode: Keycode) -> Option<EditKey> {
+    Some(match keycode {
+        Keycode::Up => EditKey::Up,
+        Keycode::Down => EditKey::Down,
+        Keycode::Left => EditKey::Left,
+        Keycode::Right => EditKey::Right,
+        Keycode::Backspace => EditKey::Backspace,
+        keycode => {
+            // Letters, digits and symbols are named after the character they type
+            let name = keycode.name();
+            let mut chars = name.chars();
+            match (chars.next(), chars.next()) {
+                (Some(c), None) => EditKey::Char(c),
+                _ if keycode == Keycode::Space => EditKey::Char(' '),
+                _ => return None,
+            }
+        }
+    })
 }
 
-/// Saves the game and console's rewrittable memory to files
-/// 
-/// This function will save the contents of the following media to the following addresses:
-/// 
-/// - IEEPROM to either wsc.ieeprom or ws.ieeprom depending on color
-/// - Cart EEPROM to \[game\].eeprom
-/// - SRAM to \[game\].sram
-fn save_game(io_bus: Rc<RefCell<IOBus>>, color: bool, game: &str) {
-    let local_io_bus = io_bus.borrow();
-    let ieeprom = &local_io_bus.ieeprom;
-    let eeprom = &local_io_bus.eeprom;
-    let sram = &local_io_bus.cartridge.borrow().sram;
-
-    let ieeprom_path = if color {"wsc.ieeprom"} else {"ws.ieeprom"};
-    let eeprom_path = format!("{}.eeprom", game);
-    let sram_path = format!("{}.sram", game);
-
-    std::fs::write(ieeprom_path, ieeprom.contents.clone()).unwrap();
-    if let Some(eeprom) = eeprom {std::fs::write(eeprom_path, eeprom.contents.clone()).unwrap()}
-    if !sram.is_empty() {std::fs::write(sram_path, sram.clone()).unwrap()}
+/// Reads a game's ROM on a worker thread, presenting a loading notice and handling the window's events until it is done
+///
+/// Returns `None` when the window is closed or Escape is pressed, which cancels the load.
+fn load_rom(game: &str, canvas: &mut Canvas<Window>, scaler: &mut Scaler, event_pump: &mut EventPump) -> Result<Option<Vec<u8>>, String> {
+    let loader = RomLoader::spawn(game);
+    let mut frame = vec![0; FRAME_WIDTH as usize * FRAME_HEIGHT as usize * 3];
+    osd::draw_banner(&mut frame, &["LOADING", "ESCAPE CANCELS"]);
+    loop {
+        if let Some(rom) = loader.poll() {return rom.map(Some)}
+        for event in event_pump.poll_iter() {
+            if let Event::Quit {..} | Event::KeyDown {keycode: Some(Keycode::Escape), ..} = event {
+                loader.cancel();
+                return Ok(None);
+            }
+        }
+        // Presenting waits for vsync, which paces the polling
+        canvas.clear();
+        let texture = scaler.texture(canvas, &frame)?;
+        canvas.copy(texture, None, None)?;
+        canvas.present();
+    }
 }
 
-/// Same as assert_eq but prints the values in hex instead
-/// 
-/// I wrote it so it so it would be easier to make CPU tests
-#[macro_export]
-macro_rules! assert_eq_hex {
-    ($left:expr, $right:expr) => {
-        let left_val = $left;
-        let right_val = $right;
-        assert!(
-            left_val == right_val,
-            "assertion `left == right` failed\n  left: 0x{:X}\n right: 0x{:X}",
-            left_val, right_val,
-        )
-    };
+/// Resizes the window and sets up the destination rectangle for either orientation of the console
+fn set_rotation(canvas: &mut Canvas<Window>, dst: &mut Rect, rotated: bool) {
+    if rotated {
+        canvas.window_mut().set_size(WINDOW_HEIGHT, WINDOW_WIDTH).unwrap();
+        canvas.window_mut().set_position(sdl2::video::WindowPos::Centered, sdl2::video::WindowPos::Centered);
+        canvas.set_logical_size(FRAME_HEIGHT, FRAME_WIDTH).unwrap();
+        dst.set_x(-40);
+        dst.set_y(40);
+        canvas.clear();
+    } else {
+        canvas.window_mut().set_size(WINDOW_WIDTH, WINDOW_HEIGHT).unwrap();
+        canvas.window_mut().set_position(sdl2::video::WindowPos::Centered, sdl2::video::WindowPos::Centered);
+        canvas.set_logical_size(FRAME_WIDTH, FRAME_HEIGHT).unwrap();
+        dst.set_x(0);
+        dst.set_y(0);
+        canvas.clear();
+    }
 }
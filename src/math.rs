@@ -0,0 +1,114 @@
+/// Floating point methods the core uses that the standard library provides and `core` does not
+///
+/// The standard library's inherent methods take precedence wherever it is linked, so this trait is only imported without the std feature outside of tests.
+/// The results are close enough for curves and filters that end up rounded to 8 or 16 bits, not correctly rounded like the standard library's.
+pub trait Float: Copy {
+    /// Rounds to the nearest integer, halfway cases away from 0
+    fn round(self) -> Self;
+    /// Rounds up to the next integer
+    fn ceil(self) -> Self;
+    /// Raises a positive number to a power
+    fn powf(self, n: Self) -> Self;
+    /// Hyperbolic tangent
+    fn tanh(self) -> Self;
+}
+
+/// Above this every `f64` is already an integer
+const INTEGRAL: f64 = (1u64 << 52) as f64;
+
+/// Drops the fractional part
+fn trunc(x: f64) -> f64 {
+    if x.abs() >= INTEGRAL {x} else {x as i64 as f64}
+}
+
+/// Rounds to the nearest integer, halfway cases away from 0
+fn round(x: f64) -> f64 {
+    if x.abs() >= INTEGRAL {x} else {trunc(x + 0.5f64.copysign(x))}
+}
+
+/// Rounds up to the next integer
+fn ceil(x: f64) -> f64 {
+    let t = trunc(x);
+    if t < x {t + 1.0} else {t}
+}
+
+/// e to the power of x, from a Taylor series on what is left after taking out a power of 2
+fn exp(x: f64) -> f64 {
+    if x > 709.0 {return f64::INFINITY}
+    if x < -745.0 {return 0.0}
+    let k = round(x / core::f64::consts::LN_2);
+    let r = x - k * core::f64::consts::LN_2;
+    let (mut sum, mut term) = (1.0, 1.0);
+    for n in 1..=16 {
+        term *= r / n as f64;
+        sum += term;
+    }
+    // 2^k built from its exponent bits as two factors, so that the extremes of k stay in range
+    let half = f64::from_bits(((k as i64 / 2 + 1023) as u64) << 52);
+    sum * half * f64::from_bits(((k as i64 - k as i64 / 2 + 1023) as u64) << 52)
+}
+
+/// Natural logarithm of a positive number, from the series of atanh on its mantissa
+fn ln(x: f64) -> f64 {
+    if x <= 0.0 {return if x == 0.0 {f64::NEG_INFINITY} else {f64::NAN}}
+    // Subnormals are scaled up first so that their exponent can be read
+    let (x, offset) = if x < f64::MIN_POSITIVE {(x * INTEGRAL, 52.0)} else {(x, 0.0)};
+    let bits = x.to_bits();
+    let exponent = ((bits >> 52) & 0x7FF) as f64 - 1023.0 - offset;
+    let mantissa = f64::from_bits((bits & 0xF_FFFF_FFFF_FFFF) | (1023 << 52));
+    let s = (mantissa - 1.0) / (mantissa + 1.0);
+    let (mut sum, mut power) = (0.0, s);
+    for n in (1..40).step_by(2) {
+        sum += power / n as f64;
+        power *= s * s;
+    }
+    2.0 * sum + exponent * core::f64::consts::LN_2
+}
+
+/// Raises a positive number to a power
+fn powf(x: f64, n: f64) -> f64 {
+    if x == 0.0 {return if n == 0.0 {1.0} else {0.0}}
+    exp(n * ln(x))
+}
+
+/// Hyperbolic tangent
+fn tanh(x: f64) -> f64 {
+    if x.abs() > 20.0 {return 1.0f64.copysign(x)}
+    1.0 - 2.0 / (exp(2.0 * x) + 1.0)
+}
+
+impl Float for f64 {
+    fn round(self) -> Self {round(self)}
+    fn ceil(self) -> Self {ceil(self)}
+    fn powf(self, n: Self) -> Self {powf(self, n)}
+    fn tanh(self) -> Self {tanh(self)}
+}
+
+impl Float for f32 {
+    fn round(self) -> Self {round(self as f64) as f32}
+    fn ceil(self) -> Self {ceil(self as f64) as f32}
+    fn powf(self, n: Self) -> Self {powf(self as f64, n as f64) as f32}
+    fn tanh(self) -> Self {tanh(self as f64) as f32}
+}
+
+#[cfg(test)]
+#[cfg_attr(coverage_nightly, coverage(off))]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_float() {
+        // The inherent methods are the standard library's, which these stand in for
+        for x in [0.0, 0.3, 0.5, 1.5, -1.5, -0.7, 2.49, 1e6 + 0.5, 3e16, f64::MIN_POSITIVE / 4.0] {
+            assert_eq!(Float::round(x), x.round(), "round {}", x);
+            assert_eq!(Float::ceil(x), x.ceil(), "ceil {}", x);
+            assert!((Float::tanh(x) - x.tanh()).abs() < 1e-12, "tanh {}", x);
+            for n in [0.8, 1.2, 2.0] {
+                assert!((Float::powf(x.abs(), n) - x.abs().powf(n)).abs() <= 1e-12 * x.abs().powf(n).max(1.0), "{} ^ {}", x, n);
+            }
+        }
+        assert_eq!(Float::powf(0.0f32, 0.8), 0.0);
+        assert_eq!(Float::tanh(-50.0f32), -1.0);
+        assert_eq!(Float::round(-0.5f32), -1.0);
+    }
+}
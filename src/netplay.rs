@@ -0,0 +1,185 @@
+use alloc::{collections::{BTreeMap, VecDeque}, format, string::{String, ToString}, vec::Vec};
+
+use crate::{bus::io_bus::keypad::Keys, headless, soc::SoC, sound::buffer::SampleBuffer};
+
+/// Default amount of frames a session can roll back, about a tenth of a second
+pub const DEFAULT_ROLLBACK_FRAMES: usize = 8;
+
+/// Rollback netplay over a single console
+///
+/// The WonderSwan has a single keypad, so both players' buttons are combined into it.
+/// Each frame runs right away with the local buttons and a prediction of the remote ones, the last buttons the remote player confirmed.
+/// When the remote player's buttons for a frame arrive and differ from the prediction,
+/// the session loads the state saved before that frame and replays every frame since with the corrected buttons.
+/// Emulation being deterministic, both players end up with the same console as long as inputs arrive within the rollback window.
+///
+/// Sending and receiving buttons is left to the front-end, which passes the remote player's buttons to [`Rollback::remote_input`] as they arrive.
+pub struct Rollback {
+    /// Most frames that can be rolled back
+    window: usize,
+    /// Next frame to run
+    frame: u64,
+    /// States saved at the start of the last frames run, oldest first
+    states: VecDeque<(u64, Vec<u8>)>,
+    /// Local buttons of the frames that can still be replayed
+    local: BTreeMap<u64, Keys>,
+    /// Remote buttons received, the last one before the window kept to predict from
+    remote: BTreeMap<u64, Keys>,
+    /// Remote buttons each frame of the window ran with
+    used: BTreeMap<u64, Keys>,
+    /// Earliest frame that ran with mispredicted buttons
+    rollback: Option<u64>,
+    /// Amount of rollbacks so far
+    pub rollbacks: u64,
+    /// Amount of frames replayed so far
+    pub replayed: u64,
+}
+
+impl Rollback {
+    /// Starts a session at frame 0, able to roll back `window` frames
+    pub fn new(window: usize) -> Self {
+        Self {
+            window: window.max(1), frame: 0, states: VecDeque::new(),
+            local: BTreeMap::new(), remote: BTreeMap::new(), used: BTreeMap::new(),
+            rollback: None, rollbacks: 0, replayed: 0,
+        }
+    }
+
+    /// Returns the next frame to run
+    pub fn frame(&self) -> u64 {
+        self.frame
+    }
+
+    /// Returns the remote buttons assumed for a frame, the ones received for it or else the last ones received before it
+    pub fn predict(&self, frame: u64) -> Keys {
+        self.remote.range(..=frame).next_back().map_or(Keys::empty(), |(_, keys)| *keys)
+    }
+
+    /// Records the remote player's buttons for a frame, scheduling a rollback if the frame already ran with other buttons
+    ///
+    /// Fails if the frame is too old to be rolled back to, after which the players' consoles may have desynced.
+    pub fn remote_input(&mut self, frame: u64, keys: Keys) -> Result<(), String> {
+        if let Some((oldest, _)) = self.states.front() {
+            if frame < *oldest {return Err(format!("Remote input for frame {} arrived after it left the {} frame rollback window", frame, self.window))}
+        }
+        self.remote.insert(frame, keys);
+        let later = self.used.range(frame..).any(|(used_frame, used)| used.bits() != self.predict(*used_frame).bits());
+        if later {
+            self.rollback = Some(self.rollback.map_or(frame, |rollback| rollback.min(frame)));
+        }
+        Ok(())
+    }
+
+    /// Replays the frames that ran with mispredicted buttons, returns how many were replayed
+    ///
+    /// Replayed frames push their samples into a buffer that is thrown away, their audio having already been played.
+    pub fn resync(&mut self, soc: &mut SoC) -> Result<usize, String> {
+        let Some(rollback) = self.rollback.take() else {return Ok(0)};
+        let index = self.states.iter().position(|(frame, _)| *frame == rollback).ok_or(format!("No state saved for frame {}", rollback))?;
+        soc.load(&self.states[index].1).map_err(|e| e.to_string())?;
+
+        let samples = core::mem::replace(&mut soc.samples, SampleBuffer::shared());
+        for frame in rollback..self.frame {
+            self.states[index + (frame - rollback) as usize].1 = soc.save();
+            self.run(soc, frame);
+        }
+        soc.samples = samples;
+
+        let replayed = (self.frame - rollback) as usize;
+        self.rollbacks += 1;
+        self.replayed += replayed as u64;
+        Ok(replayed)
+    }
+
+    /// Rolls back if needed, then runs the next frame with the local player's buttons, returns how many frames were replayed first
+    pub fn advance(&mut self, soc: &mut SoC, local: Keys) -> Result<usize, String> {
+        let replayed = self.resync(soc)?;
+        let frame = self.frame;
+        self.states.push_back((frame, soc.save()));
+        self.local.insert(frame, local);
+        self.run(soc, frame);
+        self.frame += 1;
+
+        if self.states.len() > self.window {
+            self.states.pop_front();
+            let oldest = self.states[0].0;
+            self.local = self.local.split_off(&oldest);
+            self.used = self.used.split_off(&oldest);
+            // The last buttons received before the window are kept, as later frames may still be predicted from them
+            let kept = self.remote.split_off(&oldest);
+            let last = self.remote.pop_last();
+            self.remote = kept;
+            if let Some((frame, keys)) = last {self.remote.entry(frame).or_insert(keys);}
+        }
+        Ok(replayed)
+    }
+
+    /// Runs a frame with both players' buttons
+    fn run(&mut self, soc: &mut SoC, frame: u64) {
+        let remote = self.predict(frame);
+        headless::hold_keys(soc, self.local[&frame] | remote);
+        soc.run_frame();
+        self.used.insert(frame, remote);
+    }
+}
+
+#[cfg(test)]
+#[cfg_attr(coverage_nightly, coverage(off))]
+mod test {
+    use alloc::vec;
+
+    use super::*;
+
+    /// A test SoC adding the action buttons held to a byte at 0x1000 over and over, so that WRAM depends on every input
+    fn keypad_soc() -> SoC {
+        let mut soc = SoC::test_build();
+        soc.set_wram(vec![
+            0xB0, 0x40,             // MOV AL, 0x40
+            0xE6, 0xB5,             // OUT 0xB5, AL
+            0xE4, 0xB5,             // IN AL, 0xB5
+            0x00, 0x06, 0x00, 0x10, // ADD [0x1000], AL
+            0xEB, 0xF4,             // BR -12
+        ]);
+        soc
+    }
+
+    /// The remote player's buttons, changing every few frames
+    fn remote_keys(frame: u64) -> Keys {
+        [Keys::empty(), Keys::A, Keys::A | Keys::B, Keys::Start][(frame / 5 % 4) as usize]
+    }
+
+    #[test]
+    fn test_rollback_matches_lockstep() {
+        let mut lockstep = keypad_soc();
+        for frame in 0..40 {
+            headless::hold_keys(&mut lockstep, Keys::B | remote_keys(frame));
+            lockstep.run_frame();
+        }
+
+        // Remote buttons arrive 3 frames late
+        let mut soc = keypad_soc();
+        let mut session = Rollback::new(DEFAULT_ROLLBACK_FRAMES);
+        for frame in 0..40 {
+            if frame >= 3 {session.remote_input(frame - 3, remote_keys(frame - 3)).unwrap()}
+            session.advance(&mut soc, Keys::B).unwrap();
+        }
+        for frame in 37..40 {session.remote_input(frame, remote_keys(frame)).unwrap()}
+        session.resync(&mut soc).unwrap();
+
+        assert_eq!(soc.sync_hash(0), lockstep.sync_hash(0));
+        assert_eq!(session.frame(), 40);
+        assert!(session.rollbacks > 0 && session.rollbacks < 40);
+        assert_eq!(session.resync(&mut soc), Ok(0));
+    }
+
+    #[test]
+    fn test_input_outside_window() {
+        let mut soc = keypad_soc();
+        let mut session = Rollback::new(4);
+        for _ in 0..10 {session.advance(&mut soc, Keys::empty()).unwrap();}
+        assert!(session.remote_input(5, Keys::A).is_err());
+        assert!(session.remote_input(6, Keys::A).is_ok());
+        assert_eq!(session.advance(&mut soc, Keys::empty()), Ok(4));
+        assert_eq!(session.predict(20).bits(), Keys::A.bits());
+    }
+}
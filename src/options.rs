@@ -0,0 +1,483 @@
+use core::{ops::RangeInclusive, sync::atomic::{AtomicUsize, Ordering}};
+
+use crate::{cpu::v30mz::InvalidOpcodeBehavior, display::Layers, sound::filter::{SoundProfile, SpeakerSettings}, speed::SpeedSettings, sync::{Arc, Mutex}};
+#[cfg(not(any(feature = "std", test)))]
+use crate::math::Float;
+
+/// A setting picked among a few named values, given by name on the command line and cycled through with a hotkey
+pub trait Choice: Copy + PartialEq + 'static {
+    /// Every value, in the order they are cycled through
+    const ALL: &'static [Self];
+
+    /// Returns the name the value is selected and stored as
+    fn name(&self) -> &'static str;
+
+    /// Returns the value with the given name
+    fn from_name(name: &str) -> Option<Self> {
+        Self::ALL.iter().copied().find(|value| value.name() == name.trim())
+    }
+
+    /// Returns the value after this one
+    fn next(self) -> Self {
+        let idx = Self::ALL.iter().position(|value| *value == self).unwrap();
+        Self::ALL[(idx + 1) % Self::ALL.len()]
+    }
+}
+
+/// A [`Choice`] remembered per game, stored by name in \[game\].\[extension\]
+pub trait PerGame: Choice {
+    /// Extension of the file the value is stored in, next to the ROM
+    const EXTENSION: &'static str;
+
+    /// Loads the value saved for a game
+    #[cfg(feature = "std")]
+    fn load(game: &str) -> Option<Self> {
+        std::fs::read_to_string(format!("{}.{}", game, Self::EXTENSION)).ok().and_then(|name| Self::from_name(&name))
+    }
+
+    /// Saves the value for a game
+    #[cfg(feature = "std")]
+    fn save(&self, game: &str) -> std::io::Result<()> {
+        std::fs::write(format!("{}.{}", game, Self::EXTENSION), self.name())
+    }
+}
+
+/// Trade-offs between accuracy and speed
+/// 
+/// | Tier       | Rendering         | DMA               | Bus timing                         |
+/// |------------|-------------------|-------------------|------------------------------------|
+/// | `Fast`     | Whole scanlines   | Instant           | No stalls                          |
+/// | `Balanced` | Dot by dot        | Cycle by cycle    | No stalls                          |
+/// | `Accurate` | Dot by dot        | Cycle by cycle    | CPU stalls on display fetches      |
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum Accuracy {
+    /// For slow hosts, mid-scanline effects and DMA timing are lost
+    Fast,
+    /// The default, emulates everything but bus contention
+    Balanced,
+    /// Emulates everything the emulator is able to
+    Accurate,
+}
+
+impl Choice for Accuracy {
+    const ALL: &'static [Self] = &[Accuracy::Fast, Accuracy::Balanced, Accuracy::Accurate];
+
+    fn name(&self) -> &'static str {
+        match self {
+            Accuracy::Fast => "fast",
+            Accuracy::Balanced => "balanced",
+            Accuracy::Accurate => "accurate",
+        }
+    }
+}
+
+impl PerGame for Accuracy {
+    const EXTENSION: &'static str = "accuracy";
+}
+
+impl Accuracy {
+    /// Whether the display draws whole scanlines at once
+    pub fn scanline_rendering(&self) -> bool {
+        *self == Accuracy::Fast
+    }
+
+    /// Whether DMA transfers complete within a single tick
+    pub fn instant_dma(&self) -> bool {
+        *self == Accuracy::Fast
+    }
+
+    /// Whether the CPU is stalled by the display's fetches, regardless of the `vram_stalls` option
+    pub fn exact_bus_timing(&self) -> bool {
+        *self == Accuracy::Accurate
+    }
+}
+
+/// How many sprites the display draws on each line
+/// 
+/// The hardware draws the first 32 sprites of the table that cover a line and drops the rest,
+/// which games that put more sprites on a line work around by flickering them.
+/// Removing the limit is an enhancement that does not match the hardware, it is switched per game.
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum SpriteLimit {
+    /// At most 32 sprites per line, like the hardware
+    Hardware,
+    /// Every sprite is drawn, reduces flicker in some games
+    Unlimited,
+}
+
+impl Choice for SpriteLimit {
+    const ALL: &'static [Self] = &[SpriteLimit::Hardware, SpriteLimit::Unlimited];
+
+    fn name(&self) -> &'static str {
+        match self {
+            SpriteLimit::Hardware => "limit",
+            SpriteLimit::Unlimited => "nolimit",
+        }
+    }
+}
+
+impl PerGame for SpriteLimit {
+    const EXTENSION: &'static str = "sprites";
+}
+
+impl SpriteLimit {
+    /// Returns the most sprites drawn on a line, if there is a limit
+    pub fn sprites_per_line(&self) -> Option<usize> {
+        match self {
+            SpriteLimit::Hardware => Some(32),
+            SpriteLimit::Unlimited => None,
+        }
+    }
+}
+
+/// The screen color output imitates, by how the 4-bit channels of palette RAM are expanded to 8 bits
+///
+/// | Profile       | Curve                                             | Saturation |
+/// |---------------|---------------------------------------------------|------------|
+/// | `Raw`         | Linear, each level multiplied by 17               | 100%       |
+/// | `WscLcd`      | Lifted blacks and dimmed whites, brighter mids    | 75%        |
+/// | `SwanCrystal` | Full range with darker mids, like its TFT         | 110%       |
+///
+/// Only colors from palette RAM are affected, the grays of mono mode are always linear.
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum ColorProfile {
+    /// The values of palette RAM as they are
+    Raw,
+    /// The washed out reflective LCD of the WonderSwan Color
+    WscLcd,
+    /// The vivid TFT of the SwanCrystal
+    SwanCrystal,
+}
+
+impl Choice for ColorProfile {
+    const ALL: &'static [Self] = &[ColorProfile::Raw, ColorProfile::WscLcd, ColorProfile::SwanCrystal];
+
+    fn name(&self) -> &'static str {
+        match self {
+            ColorProfile::Raw => "raw",
+            ColorProfile::WscLcd => "lcd",
+            ColorProfile::SwanCrystal => "swancrystal",
+        }
+    }
+}
+
+impl ColorProfile {
+    /// Returns the 8-bit level shown for each 4-bit level of a channel
+    pub fn levels(&self) -> [u8; 16] {
+        // Range and gamma of each curve, a gamma above 1 darkens the mids
+        let (black, white, gamma) = match self {
+            ColorProfile::Raw => return core::array::from_fn(|level| level as u8 * 17),
+            ColorProfile::WscLcd => (24.0, 232.0, 0.8),
+            ColorProfile::SwanCrystal => (0.0, 255.0, 1.2),
+        };
+        core::array::from_fn(|level| (black + (white - black) * (level as f32 / 15.0).powf(gamma)).round() as u8)
+    }
+
+    /// Returns the saturation of the profile in percent, before the user's setting is applied
+    pub fn saturation(&self) -> u16 {
+        match self {
+            ColorProfile::Raw => 100,
+            ColorProfile::WscLcd => 75,
+            ColorProfile::SwanCrystal => 110,
+        }
+    }
+}
+
+/// How colors are output, switched per game
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub struct ColorSettings {
+    /// The screen imitated
+    pub profile: ColorProfile,
+    /// Saturation in percent applied over the profile's own, 100 leaves it unchanged and 0 gives grays
+    pub saturation: u16,
+}
+
+impl ColorSettings {
+    /// Highest saturation accepted, in percent
+    pub const MAX_SATURATION: u16 = 200;
+
+    /// Returns the raw colors, unchanged
+    pub fn new() -> Self {
+        Self {profile: ColorProfile::Raw, saturation: 100}
+    }
+
+    /// Changes a setting given on the command line as `saturation=<percent>`, returns whether it was one
+    pub fn parse_setting(&mut self, setting: &str) -> bool {
+        let Some(("saturation", value)) = setting.split_once('=') else {return false};
+        let Ok(value) = value.trim().parse::<u16>() else {return false};
+        self.saturation = value.min(Self::MAX_SATURATION);
+        true
+    }
+
+    /// Returns the saturation applied to colors in percent, combining the profile's and the user's
+    pub fn total_saturation(&self) -> u16 {
+        (self.profile.saturation() as u32 * self.saturation as u32 / 100) as u16
+    }
+
+    /// Loads the settings saved for a game, stored as the profile's name followed by the saturation
+    #[cfg(feature = "std")]
+    pub fn load(game: &str) -> Option<Self> {
+        let text = std::fs::read_to_string(format!("{}.colors", game)).ok()?;
+        let mut words = text.split_whitespace();
+        let profile = ColorProfile::from_name(words.next()?)?;
+        let saturation = words.next().and_then(|saturation| saturation.parse().ok()).unwrap_or(100u16);
+        Some(Self {profile, saturation: saturation.min(Self::MAX_SATURATION)})
+    }
+
+    /// Saves the settings for a game
+    #[cfg(feature = "std")]
+    pub fn save(&self, game: &str) -> std::io::Result<()> {
+        std::fs::write(format!("{}.colors", game), format!("{} {}", self.profile.name(), self.saturation))
+    }
+}
+
+impl Default for ColorSettings {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+/// Desaturation of the finished frame, for players who tell colors apart poorly or to see a color game as on a mono console
+///
+/// Unlike the saturation of [`ColorSettings`] this is applied to the whole frame after the display has drawn it, on the way to the window,
+/// so save states, screenshots in bug reports and regression hashes keep the game's colors. Each variant weighs the channels into the gray differently:
+///
+/// | Grayscale  | Red    | Green  | Blue   |                                                            |
+/// |------------|--------|--------|--------|------------------------------------------------------------|
+/// | `Off`      |        |        |        | Colors are left as they are                                |
+/// | `Bt601`    | 0.299  | 0.587  | 0.114  | The usual luma, close to how bright colors look            |
+/// | `Bt709`    | 0.2126 | 0.7152 | 0.0722 | Luma of HD video, greens stand out more and blues less     |
+/// | `Average`  | 1/3    | 1/3    | 1/3    | Every channel counts the same, reds and blues stay lighter |
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum Grayscale {
+    /// Colors are shown
+    Off,
+    /// ITU-R BT.601 luma weights
+    Bt601,
+    /// ITU-R BT.709 luma weights
+    Bt709,
+    /// Equal weights
+    Average,
+}
+
+impl Choice for Grayscale {
+    const ALL: &'static [Self] = &[Grayscale::Off, Grayscale::Bt601, Grayscale::Bt709, Grayscale::Average];
+
+    fn name(&self) -> &'static str {
+        match self {
+            Grayscale::Off => "off",
+            Grayscale::Bt601 => "bt601",
+            Grayscale::Bt709 => "bt709",
+            Grayscale::Average => "average",
+        }
+    }
+}
+
+impl Grayscale {
+    /// Returns the setting given on the command line as `gray=<name>`, if this is one
+    pub fn from_setting(setting: &str) -> Option<Self> {
+        setting.strip_prefix("gray=").and_then(Self::from_name)
+    }
+
+    /// Returns the weights of the red, green and blue channels out of 256, `None` when off
+    pub fn weights(&self) -> Option<[u32; 3]> {
+        match self {
+            Grayscale::Off => None,
+            Grayscale::Bt601 => Some([77, 150, 29]),
+            Grayscale::Bt709 => Some([54, 183, 19]),
+            Grayscale::Average => Some([85, 86, 85]),
+        }
+    }
+
+    /// Replaces every pixel of a 24-bit RGB frame by its gray
+    pub fn apply(&self, frame: &mut [u8]) {
+        let Some([r, g, b]) = self.weights() else {return};
+        for pixel in frame.chunks_exact_mut(3) {
+            let gray = ((pixel[0] as u32 * r + pixel[1] as u32 * g + pixel[2] as u32 * b) >> 8) as u8;
+            pixel.fill(gray);
+        }
+    }
+}
+
+/// CPU clocks allowed, in percent of the console's
+///
+/// Overclocking smooths games that slow down when their frames take too long, underclocking brings the slowdown out.
+/// The display and sound keep the console's timing, so games run at their usual frame rate whatever the CPU's clock.
+pub const CPU_CLOCKS: RangeInclusive<u16> = 25..=400;
+
+/// Runtime settings of the emulator
+///
+/// Front-ends change these through a [`SharedOptions`] handle, the SoC picks the changes up at the end of the next frame.
+///
+/// | Option           | Effect                                                                        |
+/// |------------------|-------------------------------------------------------------------------------|
+/// | `color`          | Builds a WonderSwan Color instead of a WonderSwan, only read by `SoC::new`    |
+/// | `trace`          | The CPU prints every instruction it executes, destroys framerates             |
+/// | `mute`           | The SoC stops pushing samples to the audio thread                             |
+/// | `invalid_opcode` | Whether invalid instructions run as NOPs or stop the CPU with a fault         |
+/// | `vram_stalls`    | The CPU is stalled when accessing WRAM while the display is fetching from it |
+/// | `speed`          | Emulation speed and pitch preservation, read directly by main and audio       |
+/// | `layers`         | Hides screen 1, screen 2 or sprites, or tints pixels by their layer           |
+/// | `accuracy`       | Switches rendering, DMA and bus timing between faster and more exact models   |
+/// | `sound_profile`  | Whether the sound goes through the speaker filters                            |
+/// | `speaker`        | Cutoffs and drive of the speaker filters                                      |
+/// | `sprite_limit`   | Whether sprites beyond the 32 per line the hardware draws are shown           |
+/// | `colors`         | Expansion curve and saturation of the colors of palette RAM                   |
+/// | `grayscale`      | Desaturates the finished frame on its way to the window, left on in pure mode |
+/// | `cpu_clock`      | Runs the CPU faster or slower while the display and sound keep their timing   |
+/// | `pure`           | Overrides every enhancement with the hardware's behavior, see `effective`     |
+/// | `triage`         | Faults, lockups and panics save a screenshot and savestate for bug triage     |
+/// | `probe_saves`    | Save media the footer does not declare are allocated once the game writes     |
+#[derive(Clone, Copy, Debug)]
+pub struct EmulatorOptions {
+    /// Runs the game on a WonderSwan Color, changing it only takes effect once the SoC is built again
+    pub color: bool,
+    /// Enables the CPU trace
+    pub trace: bool,
+    /// Mutes the emulator
+    pub mute: bool,
+    /// What the CPU does when it decodes an invalid instruction
+    pub invalid_opcode: InvalidOpcodeBehavior,
+    /// Enables CPU stalls on WRAM accesses during the display's fetch windows
+    pub vram_stalls: bool,
+    /// Emulation speed settings
+    pub speed: SpeedSettings,
+    /// Display layers shown in the output
+    pub layers: Layers,
+    /// Accuracy tier
+    pub accuracy: Accuracy,
+    /// Whether the sound is colored like the internal speaker
+    pub sound_profile: SoundProfile,
+    /// Filters used by the speaker profile
+    pub speaker: SpeakerSettings,
+    /// Sprites drawn per line
+    pub sprite_limit: SpriteLimit,
+    /// Color profile and saturation
+    pub colors: ColorSettings,
+    /// Desaturation of the frames presented
+    pub grayscale: Grayscale,
+    /// Counts the executions of each instruction, see [`crate::cpu::stats::OpcodeStats`]
+    pub opcode_stats: bool,
+    /// CPU clock in percent of the console's 3.072 MHz, within [`CPU_CLOCKS`]
+    pub cpu_clock: u16,
+    /// Runs the canonical accurate configuration whatever the other options say, see [`EmulatorOptions::effective`]
+    pub pure: bool,
+    /// Saves a triage bundle when the emulation thread runs into a fault, lockup or panic, see [`crate::soc::diagnostics::write_triage`]
+    pub triage: bool,
+    /// Allocates SRAM or EEPROM when a game whose footer declares no save memory writes to it, as some bad dumps do
+    ///
+    /// Off by default, since some games write to SRAM to find out whether the cartridge has any, which probing would answer wrongly.
+    pub probe_saves: bool,
+}
+
+impl EmulatorOptions {
+    /// Returns the default options, which emulate the hardware as closely as the emulator is able to by default
+    pub fn new() -> Self {
+        Self {
+            color: false,
+            trace: false,
+            mute: false,
+            invalid_opcode: InvalidOpcodeBehavior::Nop,
+            vram_stalls: false,
+            speed: SpeedSettings::new(),
+            layers: Layers::new(),
+            accuracy: Accuracy::Balanced,
+            sound_profile: SoundProfile::Clean,
+            speaker: SpeakerSettings::new(),
+            sprite_limit: SpriteLimit::Hardware,
+            colors: ColorSettings::new(),
+            grayscale: Grayscale::Off,
+            opcode_stats: false,
+            cpu_clock: 100,
+            pure: false,
+            triage: false,
+            probe_saves: false,
+        }
+    }
+
+    /// Returns the options the SoC runs with, which are these options unless pure mode is on
+    ///
+    /// Pure mode puts every option that makes the output differ from the hardware back to the hardware's behavior:
+    /// the accurate tier with its bus stalls, the sprite limit, raw colors, every layer shown, the console's CPU clock and only the footer's save media.
+    /// Compatibility reports and regression baselines made with it therefore come from the same configuration,
+    /// whatever was saved for the game or toggled with hotkeys since.
+    pub fn effective(&self) -> Self {
+        if !self.pure {return *self}
+        Self {
+            vram_stalls: true,
+            layers: Layers::new(),
+            accuracy: Accuracy::Accurate,
+            sprite_limit: SpriteLimit::Hardware,
+            colors: ColorSettings::new(),
+            cpu_clock: 100,
+            probe_saves: false,
+            ..*self
+        }
+    }
+}
+
+impl Default for EmulatorOptions {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+/// A handle to the emulator's options that can be shared between threads
+///
+/// Every update increments a generation counter, so that the owner of a copy of the options
+/// can cheaply check whether or not it needs to re-read them.
+#[derive(Clone)]
+pub struct SharedOptions {
+    /// The options themselves
+    options: Arc<Mutex<EmulatorOptions>>,
+    /// Incremented after each update
+    generation: Arc<AtomicUsize>,
+}
+
+impl SharedOptions {
+    /// Creates a new handle containing the given options
+    pub fn new(options: EmulatorOptions) -> Self {
+        Self {options: Arc::new(Mutex::new(options)), generation: Arc::new(AtomicUsize::new(0))}
+    }
+
+    /// Returns a copy of the current options
+    pub fn get(&self) -> EmulatorOptions {
+        *self.options.lock().unwrap()
+    }
+
+    /// Changes the options and notifies everyone holding a handle
+    pub fn update(&self, f: impl FnOnce(&mut EmulatorOptions)) {
+        f(&mut self.options.lock().unwrap());
+        self.generation.fetch_add(1, Ordering::Release);
+    }
+
+    /// Returns the amount of updates that have been made so far
+    pub fn generation(&self) -> usize {
+        self.generation.load(Ordering::Acquire)
+    }
+}
+
+#[cfg(test)]
+#[cfg_attr(coverage_nightly, coverage(off))]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_grayscale() {
+        let colors = [0xFF, 0x00, 0x00, 0x00, 0xFF, 0x00, 0x80, 0x80, 0x80, 0xFF, 0xFF, 0xFF];
+        let mut frame = colors;
+        Grayscale::Off.apply(&mut frame);
+        assert_eq!(frame, colors);
+
+        Grayscale::Bt601.apply(&mut frame);
+        assert_eq!(frame, [76, 76, 76, 149, 149, 149, 0x80, 0x80, 0x80, 0xFF, 0xFF, 0xFF]);
+        // The weights decide which colors end up lighter, grays stay the same whatever they are
+        let mut frame = colors;
+        Grayscale::Average.apply(&mut frame);
+        assert_eq!(frame, [84, 84, 84, 85, 85, 85, 0x80, 0x80, 0x80, 0xFF, 0xFF, 0xFF]);
+
+        assert_eq!(Grayscale::from_setting("gray=bt709"), Some(Grayscale::Bt709));
+        assert_eq!(Grayscale::from_setting("saturation=50"), None);
+        assert_eq!(Grayscale::Average.next(), Grayscale::Off);
+    }
+}
@@ -0,0 +1,695 @@
+use std::time::{Duration, Instant};
+
+use crate::{bus::{io_bus::keypad::Keys, mem_bus::{AccessHeat, HEAT_PAGES}}, owner::{Owner, BLOOD_TYPES, CHARSET, NAME_LEN, SEXES}, soc::power::PowerState, sound::scope::{Scope, SCOPE_SAMPLES, SCOPE_TRACES}};
+
+/// Width of the frames the OSD draws on
+const FRAME_WIDTH: usize = 224;
+/// Height of the frames the OSD draws on
+const FRAME_HEIGHT: usize = 144;
+
+/// Width of the input overlay
+const INPUT_WIDTH: usize = 48;
+/// Height of the input overlay
+const INPUT_HEIGHT: usize = 28;
+
+/// Color of buttons that are not pressed
+const RELEASED: (u8, u8, u8) = (0x60, 0x60, 0x60);
+/// Color of buttons that are pressed
+const PRESSED: (u8, u8, u8) = (0xFF, 0xD0, 0x40);
+
+/// Color of the text of the owner editor
+const TEXT: (u8, u8, u8) = (0xE0, 0xE0, 0xE0);
+/// Color of the selected field of the owner editor
+const SELECTED: (u8, u8, u8) = (0xFF, 0xD0, 0x40);
+
+/// Width and height of the cell drawn for each page of the heat map, including a pixel of spacing
+const HEAT_CELL: usize = 6;
+/// Pages drawn on each row of the heat map, a row covers 64 KB
+const HEAT_COLUMNS: usize = 16;
+
+/// Height of each trace of the oscilloscope
+const SCOPE_HEIGHT: usize = 22;
+/// Labels and colors of the oscilloscope's traces, the channels followed by the mix
+const SCOPE_TRACE_STYLES: [(&str, (u8, u8, u8)); SCOPE_TRACES] = [
+    ("1", (0x40, 0xC0, 0xFF)), ("2", (0xFF, 0x80, 0x40)), ("3", (0x40, 0xFF, 0x40)), ("4", (0xFF, 0x40, 0xFF)), ("MIX", TEXT),
+];
+
+/// Glyphs of the OSD's font, 3 pixels wide and 5 tall, each row's bits going from left to right starting at bit 2
+///
+/// The font covers the IPL's character set and the few symbols used by the OSD itself.
+const GLYPHS: [(char, [u8; 5]); 47] = [
+    (' ', [0, 0, 0, 0, 0]), ('0', [7, 5, 5, 5, 7]), ('1', [2, 6, 2, 2, 7]), ('2', [7, 1, 7, 4, 7]), ('3', [7, 1, 3, 1, 7]),
+    ('4', [5, 5, 7, 1, 1]), ('5', [7, 4, 7, 1, 7]), ('6', [7, 4, 7, 5, 7]), ('7', [7, 1, 1, 2, 2]), ('8', [7, 5, 7, 5, 7]),
+    ('9', [7, 5, 7, 1, 7]), ('A', [2, 5, 7, 5, 5]), ('B', [6, 5, 6, 5, 6]), ('C', [3, 4, 4, 4, 3]), ('D', [6, 5, 5, 5, 6]),
+    ('E', [7, 4, 6, 4, 7]), ('F', [7, 4, 6, 4, 4]), ('G', [3, 4, 5, 5, 3]), ('H', [5, 5, 7, 5, 5]), ('I', [7, 2, 2, 2, 7]),
+    ('J', [1, 1, 1, 5, 2]), ('K', [5, 5, 6, 5, 5]), ('L', [4, 4, 4, 4, 7]), ('M', [5, 7, 7, 5, 5]), ('N', [6, 5, 5, 5, 5]),
+    ('O', [2, 5, 5, 5, 2]), ('P', [6, 5, 6, 4, 4]), ('Q', [2, 5, 5, 6, 3]), ('R', [6, 5, 6, 5, 5]), ('S', [3, 4, 2, 1, 6]),
+    ('T', [7, 2, 2, 2, 2]), ('U', [5, 5, 5, 5, 7]), ('V', [5, 5, 5, 5, 2]), ('W', [5, 5, 7, 7, 5]), ('X', [5, 5, 2, 5, 5]),
+    ('Y', [5, 5, 2, 2, 2]), ('Z', [7, 1, 2, 4, 7]), ('♥', [5, 7, 7, 2, 0]), ('♪', [3, 2, 2, 6, 6]), ('+', [0, 2, 7, 2, 0]),
+    ('-', [0, 0, 7, 0, 0]), ('?', [6, 1, 2, 0, 2]), ('.', [0, 0, 0, 0, 2]), (':', [0, 2, 0, 2, 0]), ('>', [4, 2, 1, 2, 4]),
+    ('_', [0, 0, 0, 0, 7]), ('=', [0, 7, 0, 7, 0]),
+];
+/// Horizontal distance between characters
+const ADVANCE: usize = 4;
+/// Vertical distance between lines of text
+const LINE_HEIGHT: usize = 7;
+
+/// Keys the owner editor responds to, translated from the front-end's keyboard
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum EditKey {
+    /// Selects the previous field
+    Up,
+    /// Selects the next field
+    Down,
+    /// Decreases the selected value
+    Left,
+    /// Increases the selected value
+    Right,
+    /// Types a character into the name
+    Char(char),
+    /// Erases the last character of the name
+    Backspace,
+}
+
+/// Fields of the owner editor, in the order they are shown
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+enum Field {
+    Name,
+    Year,
+    Month,
+    Day,
+    Sex,
+    Blood,
+}
+
+impl Field {
+    /// Every field, from top to bottom
+    const ALL: [Field; 6] = [Field::Name, Field::Year, Field::Month, Field::Day, Field::Sex, Field::Blood];
+
+    /// Returns the label shown before the field's value
+    fn label(&self) -> &'static str {
+        match self {
+            Field::Name => "NAME",
+            Field::Year => "YEAR",
+            Field::Month => "MONTH",
+            Field::Day => "DAY",
+            Field::Sex => "SEX",
+            Field::Blood => "BLOOD",
+        }
+    }
+}
+
+/// Editor for the owner settings stored in the internal EEPROM
+///
+/// The front-end fetches the settings from the emulation thread, edits them here and sends them back once confirmed,
+/// so that games greeting the player by name see the new settings.
+#[derive(Clone, PartialEq, Eq, Debug)]
+pub struct OwnerEditor {
+    /// The settings being edited
+    pub owner: Owner,
+    /// Index of the selected field
+    field: usize,
+}
+
+impl OwnerEditor {
+    /// Opens the editor on the given settings, with the name selected
+    pub fn new(owner: Owner) -> Self {
+        Self {owner, field: 0}
+    }
+
+    /// Changes the settings or the selected field according to a key
+    pub fn handle(&mut self, key: EditKey) {
+        let field = Field::ALL[self.field];
+        let (year, month, day) = &mut self.owner.birthday;
+        match (key, field) {
+            (EditKey::Up, _) => self.field = (self.field + Field::ALL.len() - 1) % Field::ALL.len(),
+            (EditKey::Down, _) => self.field = (self.field + 1) % Field::ALL.len(),
+            (EditKey::Char(c), Field::Name) => {
+                let c = c.to_ascii_uppercase();
+                if CHARSET.contains(c) && self.owner.name.chars().count() < NAME_LEN {self.owner.name.push(c)}
+            }
+            (EditKey::Backspace, Field::Name) => {self.owner.name.pop();}
+            (EditKey::Left | EditKey::Right, _) => {
+                let step = if key == EditKey::Right {1} else {-1};
+                match field {
+                    Field::Name => {}
+                    Field::Year => *year = cycle(*year as i32, step, 1900, 2099) as u16,
+                    Field::Month => *month = cycle(*month as i32, step, 1, 12) as u8,
+                    Field::Day => *day = cycle(*day as i32, step, 1, 31) as u8,
+                    Field::Sex => self.owner.sex = cycle(self.owner.sex as i32, step, 0, SEXES.len() as i32 - 1) as u8,
+                    Field::Blood => self.owner.blood_type = cycle(self.owner.blood_type as i32, step, 0, BLOOD_TYPES.len() as i32 - 1) as u8,
+                }
+            }
+            _ => {}
+        }
+    }
+
+    /// Returns the lines of text shown by the editor, along with whether each one is selected
+    fn lines(&self) -> Vec<(String, bool)> {
+        let (year, month, day) = self.owner.birthday;
+        let name_of = |names: &[&str], idx: u8| names.get(idx as usize).filter(|name| !name.is_empty()).map_or("-".to_string(), |name| name.to_uppercase());
+        Field::ALL.iter().enumerate().map(|(idx, field)| {
+            let value = match field {
+                Field::Name => format!("{}_", self.owner.name),
+                Field::Year => year.to_string(),
+                Field::Month => month.to_string(),
+                Field::Day => day.to_string(),
+                Field::Sex => name_of(&SEXES, self.owner.sex),
+                Field::Blood => name_of(&BLOOD_TYPES, self.owner.blood_type),
+            };
+            let cursor = if idx == self.field {">"} else {" "};
+            (format!("{}{}: {}", cursor, field.label(), value), idx == self.field)
+        }).collect()
+    }
+}
+
+/// Steps a value within an inclusive range, wrapping around at both ends
+fn cycle(value: i32, step: i32, min: i32, max: i32) -> i32 {
+    (value.clamp(min, max) - min + step).rem_euclid(max - min + 1) + min
+}
+
+/// Actions of the pause menu, in the order they are listed
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum MenuItem {
+    /// Closes the menu and goes on emulating
+    Resume,
+    /// Saves the state of the console next to the ROM
+    SaveState,
+    /// Loads the state saved next to the ROM
+    LoadState,
+    /// Saves the game's picture next to the ROM
+    Screenshot,
+    /// Restarts the game, keeping its save media
+    Reset,
+    /// Saves the game and closes the emulator
+    Quit,
+}
+
+impl MenuItem {
+    /// Every item, from top to bottom
+    pub const ALL: [MenuItem; 6] = [MenuItem::Resume, MenuItem::SaveState, MenuItem::LoadState, MenuItem::Screenshot, MenuItem::Reset, MenuItem::Quit];
+
+    /// Returns the label of the item
+    fn label(&self) -> &'static str {
+        match self {
+            MenuItem::Resume => "RESUME",
+            MenuItem::SaveState => "SAVE STATE",
+            MenuItem::LoadState => "LOAD STATE",
+            MenuItem::Screenshot => "SCREENSHOT",
+            MenuItem::Reset => "RESET",
+            MenuItem::Quit => "QUIT",
+        }
+    }
+}
+
+/// Menu shown while the game is paused
+///
+/// The menu only keeps track of the selected item, the front-end runs the action chosen.
+#[derive(Clone, Copy, PartialEq, Eq, Debug, Default)]
+pub struct PauseMenu {
+    /// Index of the selected item
+    item: usize,
+}
+
+impl PauseMenu {
+    /// Moves the selection with the up and down keys, wrapping around at both ends
+    pub fn handle(&mut self, key: EditKey) {
+        match key {
+            EditKey::Up => self.item = (self.item + MenuItem::ALL.len() - 1) % MenuItem::ALL.len(),
+            EditKey::Down => self.item = (self.item + 1) % MenuItem::ALL.len(),
+            _ => {}
+        }
+    }
+
+    /// Returns the selected item
+    pub fn selected(&self) -> MenuItem {
+        MenuItem::ALL[self.item]
+    }
+}
+
+/// Shape of a button in the input overlay
+#[derive(Clone, Copy)]
+enum Shape {
+    /// A 3x3 square, used for the X and Y pads
+    Pad,
+    /// A 5x5 circle, used for A and B
+    Round,
+    /// A 6x2 bar, used for Start
+    Bar,
+}
+
+/// Buttons of the input overlay, along with their shape and the position of their top left corner within the widget
+///
+/// The widget is laid out like the console held horizontally, with the Y pad above the X pad on the left and the buttons on the right.
+const BUTTONS: [(Keys, Shape, usize, usize); 11] = [
+    (Keys::Y1, Shape::Pad, 9, 2), (Keys::Y2, Shape::Pad, 12, 5), (Keys::Y3, Shape::Pad, 9, 8), (Keys::Y4, Shape::Pad, 6, 5),
+    (Keys::X1, Shape::Pad, 9, 14), (Keys::X2, Shape::Pad, 13, 18), (Keys::X3, Shape::Pad, 9, 22), (Keys::X4, Shape::Pad, 5, 18),
+    (Keys::B, Shape::Round, 32, 15), (Keys::A, Shape::Round, 40, 11),
+    (Keys::Start, Shape::Bar, 35, 24),
+];
+
+/// On-screen display drawn over presented frames
+///
+/// The OSD only draws on the copy of the frame sent to the front-end, the emulated LCD and everything derived from it, such as regression hashes, never contain it.
+pub struct Osd {
+    /// Whether the input overlay is shown
+    pub input_overlay: bool,
+    /// The owner editor, if it is open
+    pub owner_editor: Option<OwnerEditor>,
+    /// The pause menu, if the game is paused
+    pub pause_menu: Option<PauseMenu>,
+    /// Whether the heat map of memory accesses is shown
+    pub heat_map: bool,
+    /// Whether the oscilloscope of the sound channels is shown
+    pub scope: bool,
+    /// Lines of the hotkey help, if it is shown
+    pub help: Option<Vec<String>>,
+    /// Lines of a notice shown in a banner, and until when it is shown
+    notice: Option<(Vec<String>, Instant)>,
+}
+
+impl Osd {
+    /// Creates an OSD with every element hidden
+    pub fn new() -> Self {
+        Self {input_overlay: false, owner_editor: None, pause_menu: None, heat_map: false, scope: false, help: None, notice: None}
+    }
+
+    /// Shows a notice in a banner for a while, the text is wrapped to the width of the frame
+    pub fn notify(&mut self, text: &str, duration: Duration) {
+        let width = FRAME_WIDTH / ADVANCE - 2;
+        let mut lines: Vec<String> = Vec::new();
+        for word in text.to_uppercase().split_whitespace() {
+            match lines.last_mut() {
+                Some(line) if line.chars().count() + 1 + word.chars().count() <= width => {
+                    line.push(' ');
+                    line.push_str(word);
+                }
+                _ => lines.push(word.chars().take(width).collect()),
+            }
+        }
+        self.notice = Some((lines, Instant::now() + duration));
+    }
+
+    /// Draws every shown element on a frame of 24-bit RGB
+    ///
+    /// `keys` are the console buttons currently held, as fed to the I/O bus.
+    pub fn draw(&self, frame: &mut [u8], keys: Keys) {
+        if self.input_overlay {
+            draw_input(frame, keys, 2, FRAME_HEIGHT - INPUT_HEIGHT - 2);
+        }
+        if let Some(editor) = &self.owner_editor {
+            draw_editor(frame, editor);
+        }
+        if let Some(help) = &self.help {
+            draw_help(frame, help);
+        }
+        if let Some(menu) = &self.pause_menu {
+            draw_menu(frame, menu);
+        }
+        if let Some((lines, until)) = &self.notice {
+            if Instant::now() < *until {
+                draw_banner(frame, &lines.iter().map(String::as_str).collect::<Vec<_>>());
+            }
+        }
+    }
+}
+
+impl Default for Osd {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+/// Draws a WonderSwan-shaped widget with the held buttons highlighted, its top left corner placed at `(x, y)`
+fn draw_input(frame: &mut [u8], keys: Keys, x: usize, y: usize) {
+    darken(frame, x, y, INPUT_WIDTH, INPUT_HEIGHT);
+
+    for (key, shape, bx, by) in BUTTONS {
+        let color = if keys.contains(key) {PRESSED} else {RELEASED};
+        let (width, height) = match shape {
+            Shape::Pad => (3, 3),
+            Shape::Round => (5, 5),
+            Shape::Bar => (6, 2),
+        };
+        for (dx, dy) in (0..height).flat_map(|dy| (0..width).map(move |dx| (dx, dy))) {
+            // Leave out the corners of round buttons
+            if let Shape::Round = shape {
+                if (dx == 0 || dx == 4) && (dy == 0 || dy == 4) {continue}
+            }
+            if let Some(pixel) = pixel(frame, x + bx + dx, y + by + dy) {
+                pixel.copy_from_slice(&[color.0, color.1, color.2]);
+            }
+        }
+    }
+}
+
+/// Draws the owner editor in a darkened panel with a title and instructions
+fn draw_editor(frame: &mut [u8], editor: &OwnerEditor) {
+    let (x, y) = (8, 8);
+    let lines = editor.lines();
+    darken(frame, x, y, FRAME_WIDTH - 2 * x, (lines.len() + 3) * LINE_HEIGHT + 4);
+
+    draw_text(frame, "OWNER", x + 4, y + 4, TEXT);
+    for (idx, (line, selected)) in lines.iter().enumerate() {
+        draw_text(frame, line, x + 4, y + 4 + (idx + 1) * LINE_HEIGHT, if *selected {SELECTED} else {TEXT});
+    }
+    draw_text(frame, "ENTER SAVE  ESC CANCEL", x + 4, y + 4 + (lines.len() + 2) * LINE_HEIGHT, TEXT);
+}
+
+/// Draws the pause menu in a darkened panel in the middle of the frame, with the selected item highlighted
+fn draw_menu(frame: &mut [u8], menu: &PauseMenu) {
+    let (width, height) = (20 * ADVANCE, (MenuItem::ALL.len() + 2) * LINE_HEIGHT + 4);
+    let (x, y) = ((FRAME_WIDTH - width) / 2, (FRAME_HEIGHT - height) / 2);
+    darken(frame, x, y, width, height);
+
+    draw_text(frame, "PAUSED", x + 4, y + 4, TEXT);
+    for (idx, item) in MenuItem::ALL.iter().enumerate() {
+        let selected = *item == menu.selected();
+        let line = format!("{}{}", if selected {">"} else {" "}, item.label());
+        draw_text(frame, &line, x + 4, y + 4 + (idx + 2) * LINE_HEIGHT, if selected {SELECTED} else {TEXT});
+    }
+}
+
+/// Draws the hotkey help in a darkened panel, the lines split into two columns
+fn draw_help(frame: &mut [u8], lines: &[String]) {
+    let (x, y) = (4, 4);
+    let rows = lines.len().div_ceil(2);
+    darken(frame, x, y, FRAME_WIDTH - 2 * x, (rows + 1) * LINE_HEIGHT + 6);
+
+    draw_text(frame, "HOTKEYS", x + 4, y + 4, SELECTED);
+    for (idx, line) in lines.iter().enumerate() {
+        let column = x + 4 + idx / rows * (FRAME_WIDTH - 2 * x) / 2;
+        draw_text(frame, line, column, y + 6 + (idx % rows + 1) * LINE_HEIGHT, TEXT);
+    }
+}
+
+/// Draws the access heat of the last frame in a darkened panel, one cell per 4 KB page with a row for each 64 KB
+///
+/// Reads light up the green channel of a page and writes the red one, on a logarithmic scale so that a handful of accesses still shows.
+/// Unlike the other elements this is drawn by the emulation thread, which is where the counters are.
+pub fn draw_heat_map(frame: &mut [u8], heat: &AccessHeat) {
+    let (x, y) = (8, 8);
+    let rows = HEAT_PAGES / HEAT_COLUMNS;
+    let (grid_x, grid_y) = (x + 4 + ADVANCE + 2, y + 4 + 2 * LINE_HEIGHT);
+    darken(frame, x, y, grid_x - x + HEAT_COLUMNS * HEAT_CELL + 3, grid_y - y + rows * HEAT_CELL + 3);
+
+    draw_text(frame, "HEAT", x + 4, y + 4, TEXT);
+    draw_text(frame, "READ", x + 4 + 6 * ADVANCE, y + 4, (0x40, 0xFF, 0x40));
+    draw_text(frame, "WRITE", x + 4 + 12 * ADVANCE, y + 4, (0xFF, 0x40, 0x40));
+    let digits = |n: usize| char::from_digit(n as u32, 16).unwrap().to_ascii_uppercase().to_string();
+    for n in 0..HEAT_COLUMNS {
+        draw_text(frame, &digits(n), grid_x + n * HEAT_CELL + 1, y + 4 + LINE_HEIGHT, TEXT);
+    }
+
+    // Logarithm of the count scaled so that 2^15 accesses, about one per tick, is the brightest
+    let level = |count: u32| if count == 0 {0} else {(0x30 + (32 - count.leading_zeros()) * 13).min(0xFF) as u8};
+    for row in 0..rows {
+        draw_text(frame, &digits(row), x + 4, grid_y + row * HEAT_CELL, TEXT);
+        for column in 0..HEAT_COLUMNS {
+            let page = row * HEAT_COLUMNS + column;
+            let color = [level(heat.writes[page]), level(heat.reads[page]), 0x20];
+            for (dx, dy) in (0..HEAT_CELL - 1).flat_map(|dy| (0..HEAT_CELL - 1).map(move |dx| (dx, dy))) {
+                if let Some(pixel) = pixel(frame, grid_x + column * HEAT_CELL + dx, grid_y + row * HEAT_CELL + dy) {
+                    pixel.copy_from_slice(&color);
+                }
+            }
+        }
+    }
+}
+
+/// Draws the recent output of each sound channel and of the mix in a darkened panel, one trace above the other
+///
+/// Each column is a sample taken from the sound chip with the newest on the right, joined to the previous one so that square waves keep their edges.
+/// Like the heat map this is drawn by the emulation thread, which is where the samples are.
+pub fn draw_scope(frame: &mut [u8], scope: &Scope) {
+    let trace_x = 4 + 3 * ADVANCE + 2;
+    let width = trace_x + SCOPE_SAMPLES + 3;
+    let (x, y) = (FRAME_WIDTH.saturating_sub(width) / 2, 5);
+    let top = y + 4 + LINE_HEIGHT;
+    darken(frame, x, y, width, top - y + SCOPE_TRACES * (SCOPE_HEIGHT + 2) + 1);
+    draw_text(frame, "SCOPE", x + 4, y + 4, TEXT);
+
+    for (trace, (label, color)) in SCOPE_TRACE_STYLES.iter().enumerate() {
+        let top = top + trace * (SCOPE_HEIGHT + 2);
+        draw_text(frame, label, x + 4, top + (SCOPE_HEIGHT - LINE_HEIGHT) / 2, *color);
+        let mut previous = None;
+        for (column, level) in scope.trace(trace).enumerate() {
+            let row = top + (SCOPE_HEIGHT - 1) - level as usize * (SCOPE_HEIGHT - 1) / 0xFF;
+            let (from, to) = previous.map_or((row, row), |previous: usize| (previous.min(row), previous.max(row)));
+            for row in from..=to {
+                if let Some(pixel) = pixel(frame, x + trace_x + column, row) {
+                    pixel.copy_from_slice(&[color.0, color.1, color.2]);
+                }
+            }
+            previous = Some(row);
+        }
+    }
+}
+
+/// Draws lines of text centered in a darkened band across the top of the frame
+///
+/// Like the heat map this is drawn by the emulation thread for notices about the state of the emulated console, the OSD also uses it for its own notices.
+pub fn draw_banner(frame: &mut [u8], lines: &[&str]) {
+    darken(frame, 0, 0, FRAME_WIDTH, lines.len() * LINE_HEIGHT + 6);
+    for (idx, line) in lines.iter().enumerate() {
+        let width = line.chars().count() * ADVANCE;
+        draw_text(frame, line, FRAME_WIDTH.saturating_sub(width) / 2, 4 + idx * LINE_HEIGHT, SELECTED);
+    }
+}
+
+/// Shows the power state the game put the console in, the frame is dimmed with a tag in the corner while asleep and replaced by a notice once switched off
+///
+/// Like the banner this is drawn by the emulation thread, so that the console visibly sleeps instead of looking frozen.
+pub fn draw_power(frame: &mut [u8], state: PowerState) {
+    match state {
+        PowerState::On => {}
+        PowerState::Sleep => {
+            darken(frame, 0, 0, FRAME_WIDTH, FRAME_HEIGHT);
+            draw_text(frame, "SLEEP", FRAME_WIDTH - 4 - 5 * ADVANCE, 4, TEXT);
+        }
+        PowerState::Off => {
+            frame.fill(0);
+            draw_banner(frame, &["POWER OFF", "RESTART THE EMULATOR TO SWITCH IT ON"]);
+        }
+    }
+}
+
+/// Draws text in the OSD's font with its top left corner at `(x, y)`, characters the font does not cover are left blank
+fn draw_text(frame: &mut [u8], text: &str, x: usize, y: usize, color: (u8, u8, u8)) {
+    for (idx, c) in text.chars().enumerate() {
+        let Some((_, rows)) = GLYPHS.iter().find(|(glyph, _)| *glyph == c) else {continue};
+        for (dy, row) in rows.iter().enumerate() {
+            for dx in 0..3 {
+                if row >> (2 - dx) & 1 == 0 {continue}
+                if let Some(pixel) = pixel(frame, x + idx * ADVANCE + dx, y + dy) {
+                    pixel.copy_from_slice(&[color.0, color.1, color.2]);
+                }
+            }
+        }
+    }
+}
+
+/// Darkens an area of the frame so that whatever is drawn over it stays readable on any game
+fn darken(frame: &mut [u8], x: usize, y: usize, width: usize, height: usize) {
+    for (dx, dy) in (0..height).flat_map(|dy| (0..width).map(move |dx| (dx, dy))) {
+        if let Some(pixel) = pixel(frame, x + dx, y + dy) {
+            for channel in pixel {
+                *channel = *channel / 3 + 0x10;
+            }
+        }
+    }
+}
+
+/// Returns the channels of a pixel of the frame, or None if it is out of bounds
+fn pixel(frame: &mut [u8], x: usize, y: usize) -> Option<&mut [u8]> {
+    if x >= FRAME_WIDTH || y >= FRAME_HEIGHT {return None}
+    let idx = (y * FRAME_WIDTH + x) * 3;
+    frame.get_mut(idx..idx + 3)
+}
+
+#[cfg(test)]
+#[cfg_attr(coverage_nightly, coverage(off))]
+mod test {
+    use super::*;
+
+    /// Returns the color of the top left pixel of a button when the overlay is drawn at the origin
+    fn button_color(frame: &[u8], key: Keys) -> (u8, u8, u8) {
+        let (_, _, x, y) = BUTTONS.iter().find(|button| button.0.bits() == key.bits()).unwrap();
+        let idx = (y * FRAME_WIDTH + x + 1) * 3;
+        (frame[idx], frame[idx + 1], frame[idx + 2])
+    }
+
+    #[test]
+    fn test_input_overlay() {
+        let mut frame = vec![0xFF; FRAME_WIDTH * FRAME_HEIGHT * 3];
+        draw_input(&mut frame, Keys::A | Keys::X3, 0, 0);
+
+        assert_eq!(button_color(&frame, Keys::A), PRESSED);
+        assert_eq!(button_color(&frame, Keys::X3), PRESSED);
+        assert_eq!(button_color(&frame, Keys::B), RELEASED);
+        assert_eq!(button_color(&frame, Keys::Y1), RELEASED);
+        // The background is darkened, everything outside the widget is untouched
+        assert_eq!(&frame[0..3], &[0x65, 0x65, 0x65]);
+        assert_eq!(frame[INPUT_WIDTH * 3], 0xFF);
+    }
+
+    #[test]
+    fn test_hidden_overlay() {
+        let mut frame = vec![0xFF; FRAME_WIDTH * FRAME_HEIGHT * 3];
+        Osd::new().draw(&mut frame, Keys::all());
+        assert!(frame.iter().all(|channel| *channel == 0xFF));
+
+        // Drawing past the edge of the frame is clipped
+        draw_input(&mut frame, Keys::all(), FRAME_WIDTH - 10, FRAME_HEIGHT - 10);
+    }
+
+    #[test]
+    fn test_pause_menu() {
+        let mut menu = PauseMenu::default();
+        assert_eq!(menu.selected(), MenuItem::Resume);
+        menu.handle(EditKey::Up);
+        assert_eq!(menu.selected(), MenuItem::Quit);
+        menu.handle(EditKey::Down);
+        menu.handle(EditKey::Down);
+        menu.handle(EditKey::Char('A'));
+        assert_eq!(menu.selected(), MenuItem::SaveState);
+
+        let mut frame = vec![0xFF; FRAME_WIDTH * FRAME_HEIGHT * 3];
+        Osd {pause_menu: Some(menu), ..Osd::new()}.draw(&mut frame, Keys::empty());
+        assert!(frame.chunks(3).any(|pixel| pixel == [SELECTED.0, SELECTED.1, SELECTED.2]));
+        assert!(MenuItem::ALL.iter().flat_map(|item| item.label().chars()).all(|c| GLYPHS.iter().any(|(glyph, _)| *glyph == c)));
+    }
+
+    #[test]
+    fn test_font_covers_charset() {
+        for c in CHARSET.chars().chain("OWNER:>_HEATDWRI0123456789ABCDEF=".chars()) {
+            assert!(GLYPHS.iter().any(|(glyph, _)| *glyph == c), "missing glyph for {}", c);
+        }
+    }
+
+    #[test]
+    fn test_owner_editor() {
+        let owner = Owner {name: "CRAB".to_string(), birthday: (2000, 12, 31), sex: 0, blood_type: 4, name_color: 0, custom_splash: false};
+        let mut editor = OwnerEditor::new(owner);
+        for key in [EditKey::Backspace, EditKey::Char('o'), EditKey::Char('!'), EditKey::Char('?')] {
+            editor.handle(key);
+        }
+        assert_eq!(editor.owner.name, "CRAO?");
+
+        // Values wrap around at both ends, typing only affects the name
+        for key in [EditKey::Down, EditKey::Left, EditKey::Down, EditKey::Right, EditKey::Char('1'), EditKey::Up, EditKey::Up, EditKey::Up, EditKey::Left] {
+            editor.handle(key);
+        }
+        assert_eq!(editor.owner.birthday, (1999, 1, 31));
+        assert_eq!(editor.owner.blood_type, 3);
+
+        let lines = editor.lines();
+        assert_eq!(lines[0], (" NAME: CRAO?_".to_string(), false));
+        assert_eq!(lines[5], (">BLOOD: O".to_string(), true));
+
+        let mut frame = vec![0xFF; FRAME_WIDTH * FRAME_HEIGHT * 3];
+        Osd {owner_editor: Some(editor), ..Osd::new()}.draw(&mut frame, Keys::empty());
+        assert!(frame.chunks(3).any(|pixel| pixel == [SELECTED.0, SELECTED.1, SELECTED.2]));
+    }
+
+    #[test]
+    fn test_help() {
+        let lines: Vec<String> = ["F1 HELP", "= FASTER", "- SLOWER"].map(String::from).to_vec();
+        let mut frame = vec![0xFF; FRAME_WIDTH * FRAME_HEIGHT * 3];
+        Osd {help: Some(lines), ..Osd::new()}.draw(&mut frame, Keys::empty());
+        // The third line goes in the second column
+        let right = FRAME_WIDTH / 2 + 2;
+        let row = |y: usize| frame[(y * FRAME_WIDTH + right) * 3..((y + 1) * FRAME_WIDTH) * 3].to_vec();
+        assert!(row(4 + 6 + LINE_HEIGHT + 2).chunks(3).any(|pixel| pixel == [TEXT.0, TEXT.1, TEXT.2]));
+        assert!(frame[FRAME_WIDTH * (3 * LINE_HEIGHT + 10) * 3..].iter().all(|channel| *channel == 0xFF));
+    }
+
+    #[test]
+    fn test_heat_map() {
+        let mut heat = AccessHeat::default();
+        heat.reads[0x00] = 1;
+        heat.writes[0x11] = 1 << 15;
+
+        let mut frame = vec![0; FRAME_WIDTH * FRAME_HEIGHT * 3];
+        draw_heat_map(&mut frame, &heat);
+        let cell = |page: usize| {
+            let (x, y) = (8 + 4 + ADVANCE + 2 + (page % HEAT_COLUMNS) * HEAT_CELL, 8 + 4 + 2 * LINE_HEIGHT + (page / HEAT_COLUMNS) * HEAT_CELL);
+            let idx = (y * FRAME_WIDTH + x) * 3;
+            [frame[idx], frame[idx + 1], frame[idx + 2]]
+        };
+        // A single read is dim, an access every tick is as bright as it gets
+        assert_eq!(cell(0x00), [0x00, 0x3D, 0x20]);
+        assert_eq!(cell(0x11), [0xFF, 0x00, 0x20]);
+        assert_eq!(cell(0xFF), [0x00, 0x00, 0x20]);
+    }
+
+    #[test]
+    fn test_scope() {
+        let mut scope = Scope::default();
+        for sample in 0..SCOPE_SAMPLES {
+            scope.push([if sample % 2 == 0 {0xFF} else {0}, 0, 0, 0, 0x80]);
+        }
+        let mut frame = vec![0; FRAME_WIDTH * FRAME_HEIGHT * 3];
+        draw_scope(&mut frame, &scope);
+
+        let trace_x = (FRAME_WIDTH - (4 + 3 * ADVANCE + 2 + SCOPE_SAMPLES + 3)) / 2 + 4 + 3 * ADVANCE + 2;
+        let top = 5 + 4 + LINE_HEIGHT;
+        let color = |x: usize, y: usize| {
+            let idx = (y * FRAME_WIDTH + x) * 3;
+            (frame[idx], frame[idx + 1], frame[idx + 2])
+        };
+        // Channel 1 swings from the bottom to the top of its trace on every sample, the mix stays in the middle of its own
+        assert_eq!(color(trace_x, top), SCOPE_TRACE_STYLES[0].1);
+        assert_eq!(color(trace_x + 1, top + SCOPE_HEIGHT - 1), SCOPE_TRACE_STYLES[0].1);
+        assert_eq!(color(trace_x + 1, top + SCOPE_HEIGHT / 2), SCOPE_TRACE_STYLES[0].1);
+        let mix_top = top + 4 * (SCOPE_HEIGHT + 2);
+        assert_eq!(color(trace_x + 5, mix_top + (SCOPE_HEIGHT - 1) - 0x80 * (SCOPE_HEIGHT - 1) / 0xFF), TEXT);
+        assert_ne!(color(trace_x + 5, mix_top), TEXT);
+    }
+
+    #[test]
+    fn test_banner() {
+        let mut frame = vec![0xFF; FRAME_WIDTH * FRAME_HEIGHT * 3];
+        draw_banner(&mut frame, &["GAME APPEARS TO HAVE CRASHED", "F12 SAVES A BUG REPORT"]);
+        let (band, rest) = frame.split_at(FRAME_WIDTH * (2 * LINE_HEIGHT + 6) * 3);
+        assert!(band.chunks(3).any(|pixel| pixel == [SELECTED.0, SELECTED.1, SELECTED.2]));
+        assert!(rest.iter().all(|channel| *channel == 0xFF));
+    }
+
+    #[test]
+    fn test_notice() {
+        let mut osd = Osd::new();
+        osd.notify("Playable: the music in stage 2 skips, and saving sometimes fails after a long session", Duration::from_secs(60));
+        let (lines, _) = osd.notice.as_ref().unwrap();
+        assert_eq!(lines.len(), 2);
+        assert!(lines[0].starts_with("PLAYABLE: THE MUSIC"));
+        assert!(lines.iter().all(|line| line.len() <= FRAME_WIDTH / ADVANCE - 2));
+
+        let mut frame = vec![0xFF; FRAME_WIDTH * FRAME_HEIGHT * 3];
+        osd.draw(&mut frame, Keys::empty());
+        assert!(frame.chunks(3).any(|pixel| pixel == [SELECTED.0, SELECTED.1, SELECTED.2]));
+
+        // Expired notices are not drawn
+        osd.notify("Broken", Duration::ZERO);
+        let mut frame = vec![0xFF; FRAME_WIDTH * FRAME_HEIGHT * 3];
+        osd.draw(&mut frame, Keys::empty());
+        assert!(frame.iter().all(|channel| *channel == 0xFF));
+    }
+
+    #[test]
+    fn test_power() {
+        let mut frame = vec![0xFF; FRAME_WIDTH * FRAME_HEIGHT * 3];
+        draw_power(&mut frame, PowerState::On);
+        assert!(frame.iter().all(|channel| *channel == 0xFF));
+
+        // Asleep the whole frame is dimmed, the tag being drawn over it
+        draw_power(&mut frame, PowerState::Sleep);
+        assert!(frame.iter().all(|channel| *channel < 0xFF));
+        assert!(frame.chunks(3).any(|pixel| pixel == [TEXT.0, TEXT.1, TEXT.2]));
+
+        draw_power(&mut frame, PowerState::Off);
+        let (band, rest) = frame.split_at(FRAME_WIDTH * (2 * LINE_HEIGHT + 6) * 3);
+        assert!(band.chunks(3).any(|pixel| pixel == [SELECTED.0, SELECTED.1, SELECTED.2]));
+        assert!(rest.iter().all(|channel| *channel == 0));
+    }
+}
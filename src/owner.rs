@@ -0,0 +1,162 @@
+use alloc::{format, string::{String, ToString}, vec::Vec};
+
+/// Owner's name, 16 characters in the IPL's character set
+pub const NAME: usize = 0x60;
+/// Length of the owner's name
+pub const NAME_LEN: usize = 16;
+/// Birth year as 2 BCD bytes, century first
+pub const BIRTH_YEAR: usize = 0x70;
+/// Birth month as BCD
+pub const BIRTH_MONTH: usize = 0x72;
+/// Birth day as BCD
+pub const BIRTH_DAY: usize = 0x73;
+/// 1 for male, 2 for female
+pub const SEX: usize = 0x74;
+/// 1 for A, 2 for B, 3 for O and 4 for AB
+pub const BLOOD_TYPE: usize = 0x75;
+/// Bit 7 enables the custom splash
+pub const SETTINGS: usize = 0x83;
+/// Color of the owner's name on the boot screen
+pub const NAME_COLOR: usize = 0x84;
+/// End of the owner settings
+///
+/// The mono IEEPROM is only 128 bytes, so it holds the name, birthday, sex and blood type but not the color settings.
+const OWNER_END: usize = 0x85;
+
+/// The IPL's character set, indexed by character code
+pub const CHARSET: &str = " 0123456789ABCDEFGHIJKLMNOPQRSTUVWXYZ♥♪+-?.";
+
+/// Names of the values of the sex setting
+pub const SEXES: [&str; 3] = ["", "male", "female"];
+/// Names of the values of the blood type setting
+pub const BLOOD_TYPES: [&str; 5] = ["", "a", "b", "o", "ab"];
+
+/// Settings of the console's owner, as entered in the IPL's setup screen
+#[derive(Clone, PartialEq, Eq, Debug)]
+pub struct Owner {
+    pub name: String,
+    /// Year, month and day
+    pub birthday: (u16, u8, u8),
+    pub sex: u8,
+    pub blood_type: u8,
+    pub name_color: u8,
+    pub custom_splash: bool,
+}
+
+/// Converts a BCD byte to binary
+fn from_bcd(byte: u8) -> u8 {
+    (byte >> 4) * 10 + (byte & 0x0F)
+}
+
+/// Converts a number below 100 to BCD
+fn to_bcd(value: u8) -> u8 {
+    ((value / 10) << 4) | (value % 10)
+}
+
+impl Owner {
+    /// Reads the owner settings from an IEEPROM, settings past its end read as 0
+    pub fn read(ieeprom: &[u8]) -> Self {
+        let mut padded = ieeprom.to_vec();
+        padded.resize(padded.len().max(OWNER_END), 0);
+        let ieeprom = &padded;
+
+        let name = ieeprom[NAME..NAME + NAME_LEN].iter()
+            .map(|code| CHARSET.chars().nth(*code as usize).unwrap_or(' '))
+            .collect::<String>().trim_end().to_string();
+        let year = from_bcd(ieeprom[BIRTH_YEAR]) as u16 * 100 + from_bcd(ieeprom[BIRTH_YEAR + 1]) as u16;
+        Self {
+            name,
+            birthday: (year, from_bcd(ieeprom[BIRTH_MONTH]), from_bcd(ieeprom[BIRTH_DAY])),
+            sex: ieeprom[SEX],
+            blood_type: ieeprom[BLOOD_TYPE],
+            name_color: ieeprom[NAME_COLOR],
+            custom_splash: ieeprom[SETTINGS] & 0x80 != 0,
+        }
+    }
+
+    /// Writes the owner settings to an IEEPROM, characters outside the IPL's character set become spaces
+    ///
+    /// Settings past the end of the IEEPROM are dropped.
+    pub fn write(&self, ieeprom: &mut [u8]) {
+        let len = ieeprom.len();
+        let mut padded = ieeprom.to_vec();
+        padded.resize(len.max(OWNER_END), 0);
+
+        let mut name = self.name.to_uppercase().chars()
+            .map(|c| CHARSET.chars().position(|x| x == c).unwrap_or(0) as u8)
+            .collect::<Vec<_>>();
+        name.resize(NAME_LEN, 0);
+        padded[NAME..NAME + NAME_LEN].copy_from_slice(&name);
+
+        let (year, month, day) = self.birthday;
+        padded[BIRTH_YEAR] = to_bcd((year / 100 % 100) as u8);
+        padded[BIRTH_YEAR + 1] = to_bcd((year % 100) as u8);
+        padded[BIRTH_MONTH] = to_bcd(month);
+        padded[BIRTH_DAY] = to_bcd(day);
+        padded[SEX] = self.sex;
+        padded[BLOOD_TYPE] = self.blood_type;
+        padded[NAME_COLOR] = self.name_color;
+        padded[SETTINGS] = (padded[SETTINGS] & 0x7F) | if self.custom_splash {0x80} else {0};
+
+        ieeprom.copy_from_slice(&padded[..len]);
+    }
+
+    /// Changes a single setting from its `key=value` form
+    pub fn set(&mut self, setting: &str) -> Result<(), String> {
+        let (key, value) = setting.split_once('=').ok_or(format!("Expected key=value, got {}", setting))?;
+        let invalid = || format!("Invalid value for {}: {}", key, value);
+        match key {
+            "name" => self.name = value.chars().take(NAME_LEN).collect(),
+            "birthday" => {
+                let mut fields = value.split('-');
+                let mut field = || fields.next().and_then(|field| field.parse::<u16>().ok()).ok_or_else(invalid);
+                let (year, month, day) = (field()?, field()?, field()?);
+                if !(1..=12).contains(&month) || !(1..=31).contains(&day) {return Err(invalid())}
+                self.birthday = (year, month as u8, day as u8);
+            }
+            "sex" => self.sex = SEXES.iter().position(|x| *x == value).ok_or_else(invalid)? as u8,
+            "blood" => self.blood_type = BLOOD_TYPES.iter().position(|x| x.eq_ignore_ascii_case(value)).ok_or_else(invalid)? as u8,
+            "color" => self.name_color = value.parse().map_err(|_| invalid())?,
+            "splash" => self.custom_splash = match value {"on" => true, "off" => false, _ => return Err(invalid())},
+            _ => return Err(format!("Unknown setting {}", key)),
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+#[cfg_attr(coverage_nightly, coverage(off))]
+mod test {
+    use alloc::vec;
+
+    use super::*;
+
+    #[test]
+    fn test_owner_round_trip() {
+        let mut ieeprom = vec![0; 0x800];
+        let mut owner = Owner::read(&ieeprom);
+        for setting in ["name=Crab 01?", "birthday=1999-12-31", "sex=female", "blood=AB", "color=3", "splash=on"] {
+            owner.set(setting).unwrap();
+        }
+        owner.write(&mut ieeprom);
+
+        assert_eq!(&ieeprom[NAME..NAME + 5], &[0x0D, 0x1C, 0x0B, 0x0C, 0x00]);
+        assert_eq!(&ieeprom[BIRTH_YEAR..=BIRTH_DAY], &[0x19, 0x99, 0x12, 0x31]);
+        assert_eq!(Owner::read(&ieeprom), Owner {name: "CRAB 01?".to_string(), birthday: (1999, 12, 31), sex: 2, blood_type: 4, name_color: 3, custom_splash: true});
+        assert!(owner.set("birthday=1999-13-01").is_err());
+    }
+
+    #[test]
+    fn test_mono_ieeprom() {
+        let mut ieeprom = vec![0; 0x80];
+        let mut owner = Owner::read(&ieeprom);
+        for setting in ["name=Crab", "birthday=2000-01-02", "blood=o", "color=3", "splash=on"] {
+            owner.set(setting).unwrap();
+        }
+        owner.write(&mut ieeprom);
+
+        // The color settings do not fit in the mono IEEPROM
+        assert_eq!(ieeprom.len(), 0x80);
+        assert_eq!(Owner::read(&ieeprom), Owner {name: "CRAB".to_string(), birthday: (2000, 1, 2), sex: 0, blood_type: 3, name_color: 0, custom_splash: false});
+    }
+}
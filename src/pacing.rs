@@ -0,0 +1,245 @@
+use alloc::{collections::VecDeque, format, string::String, vec::Vec};
+use core::{fmt::Write, time::Duration};
+
+#[cfg(not(any(feature = "std", test)))]
+use crate::math::Float;
+
+/// Width of each bucket of the histograms
+pub const BUCKET_WIDTH: Duration = Duration::from_micros(500);
+/// Amount of buckets, the last one holding every time past the others, from 32ms on
+pub const BUCKETS: usize = 65;
+/// Amount of frames the histograms cover, about a minute at normal speed
+pub const WINDOW: usize = 4500;
+/// Amount of spikes kept, older ones are dropped
+pub const MAX_SPIKES: usize = 64;
+
+/// The times measured on the host for each frame
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum Phase {
+    /// Running the SoC for a frame
+    Emulation,
+    /// Uploading and presenting a frame on the front-end, which includes waiting on vsync
+    Present,
+    /// Sleeping to pace emulation, as long as the thread actually slept rather than as long as it asked to
+    Sleep,
+}
+
+impl Phase {
+    /// Every phase, in the order they are reported
+    pub const ALL: [Self; 3] = [Self::Emulation, Self::Present, Self::Sleep];
+
+    /// Name shown in reports
+    pub fn name(self) -> &'static str {
+        match self {
+            Self::Emulation => "emulation",
+            Self::Present => "present",
+            Self::Sleep => "sleep",
+        }
+    }
+}
+
+/// Histogram of the times of the last [`WINDOW`] frames
+///
+/// The times themselves are kept so that the oldest one can be taken out of its bucket as a new one comes in,
+/// which also gives exact percentiles instead of ones rounded to a bucket.
+#[derive(Clone, Debug)]
+pub struct RollingHistogram {
+    /// Times in the window, oldest first
+    times: VecDeque<Duration>,
+    /// Amount of times in each bucket
+    counts: [u32; BUCKETS],
+}
+
+impl Default for RollingHistogram {
+    fn default() -> Self {
+        Self {times: VecDeque::with_capacity(WINDOW), counts: [0; BUCKETS]}
+    }
+}
+
+impl RollingHistogram {
+    /// Returns the bucket a time falls into
+    fn bucket(time: Duration) -> usize {
+        ((time.as_micros() / BUCKET_WIDTH.as_micros()) as usize).min(BUCKETS - 1)
+    }
+
+    /// Adds a time, dropping the oldest one once the window is full
+    pub fn add(&mut self, time: Duration) {
+        if self.times.len() == WINDOW {
+            let oldest = self.times.pop_front().unwrap();
+            self.counts[Self::bucket(oldest)] -= 1;
+        }
+        self.times.push_back(time);
+        self.counts[Self::bucket(time)] += 1;
+    }
+
+    /// Amount of times in the window
+    pub fn len(&self) -> usize {
+        self.times.len()
+    }
+
+    /// Whether no time was added yet
+    pub fn is_empty(&self) -> bool {
+        self.times.is_empty()
+    }
+
+    /// Amount of times in each bucket, the first one starting at 0 and each one [`BUCKET_WIDTH`] wide
+    pub fn counts(&self) -> &[u32; BUCKETS] {
+        &self.counts
+    }
+
+    /// Average of the times in the window, 0 if there are none
+    pub fn mean(&self) -> Duration {
+        if self.times.is_empty() {return Duration::ZERO}
+        self.times.iter().sum::<Duration>() / self.times.len() as u32
+    }
+
+    /// Time under which the given fraction of the times in the window fall, 0 if there are none
+    pub fn percentile(&self, fraction: f64) -> Duration {
+        let mut sorted: Vec<_> = self.times.iter().copied().collect();
+        sorted.sort_unstable();
+        let idx = ((sorted.len() as f64 * fraction).ceil() as usize).clamp(1, sorted.len().max(1)) - 1;
+        sorted.get(idx).copied().unwrap_or_default()
+    }
+
+    /// Longest time in the window, 0 if there are none
+    pub fn max(&self) -> Duration {
+        self.times.iter().max().copied().unwrap_or_default()
+    }
+}
+
+/// A frame that came noticeably later than the previous one
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub struct Spike {
+    /// Index of the frame
+    pub frame: u64,
+    /// Time between the previous frame and this one
+    pub interval: Duration,
+    /// Time the frame took to emulate
+    pub emulation: Duration,
+}
+
+/// Host timings of the frames, to diagnose uneven pacing such as stutters every few seconds with data
+///
+/// Emulation and sleep times are measured on the emulation thread, present times on the front-end, so each phase has its own histogram.
+/// Frames further apart than the frame time by more than half of it are also kept as spikes along with their index,
+/// since the interval between spikes tells apart a periodic stall, such as a save or a garbage collecting host process, from random jitter.
+#[derive(Clone, Debug, Default)]
+pub struct FrameTimings {
+    /// Histogram of each phase, indexed like [`Phase::ALL`]
+    histograms: [RollingHistogram; 3],
+    /// The last spikes, oldest first
+    spikes: VecDeque<Spike>,
+}
+
+impl FrameTimings {
+    /// Adds the time taken by a phase of a frame
+    pub fn add(&mut self, phase: Phase, time: Duration) {
+        self.histograms[phase as usize].add(time);
+    }
+
+    /// Returns the histogram of a phase
+    pub fn histogram(&self, phase: Phase) -> &RollingHistogram {
+        &self.histograms[phase as usize]
+    }
+
+    /// Records the interval between a frame and the previous one, keeping it as a spike if it is too long
+    ///
+    /// Intervals are not checked when the speed is unlimited, which is given as a frame time of 0.
+    pub fn add_interval(&mut self, frame: u64, interval: Duration, emulation: Duration, frame_time: Duration) {
+        if frame_time.is_zero() || interval <= frame_time * 3 / 2 {return}
+        if self.spikes.len() == MAX_SPIKES {self.spikes.pop_front();}
+        self.spikes.push_back(Spike {frame, interval, emulation});
+    }
+
+    /// The last spikes, oldest first
+    pub fn spikes(&self) -> impl Iterator<Item = &Spike> {
+        self.spikes.iter()
+    }
+
+    /// One line per phase with its average, median, 99th percentile and longest time
+    pub fn summary(&self) -> String {
+        Phase::ALL.iter().map(|phase| {
+            let histogram = self.histogram(*phase);
+            format!("{:<9} mean {:>6.2}ms  p50 {:>6.2}ms  p99 {:>6.2}ms  max {:>6.2}ms over {} frames",
+                phase.name(), ms(histogram.mean()), ms(histogram.percentile(0.5)), ms(histogram.percentile(0.99)), ms(histogram.max()), histogram.len())
+        }).collect::<Vec<_>>().join("\n")
+    }
+
+    /// Describes the timings in full: the summary, the histograms as a table with a row for each bucket and the spikes
+    ///
+    /// Buckets are named after the time they start at, empty buckets past the last used one are left out.
+    pub fn report(&self) -> String {
+        let mut report = format!("{}\n\nbucket_ms", self.summary());
+        for phase in Phase::ALL {write!(report, ",{}", phase.name()).unwrap()}
+        report.push('\n');
+        let used = (0..BUCKETS).rev().find(|bucket| self.histograms.iter().any(|histogram| histogram.counts()[*bucket] != 0)).map_or(0, |bucket| bucket + 1);
+        for bucket in 0..used {
+            let start = ms(BUCKET_WIDTH * bucket as u32);
+            if bucket == BUCKETS - 1 {write!(report, ">={:.1}", start).unwrap()} else {write!(report, "{:.1}", start).unwrap()}
+            for histogram in &self.histograms {write!(report, ",{}", histogram.counts()[bucket]).unwrap()}
+            report.push('\n');
+        }
+
+        writeln!(report, "\nspikes, frame,interval_ms,emulation_ms").unwrap();
+        for spike in &self.spikes {
+            writeln!(report, "{},{:.2},{:.2}", spike.frame, ms(spike.interval), ms(spike.emulation)).unwrap();
+        }
+        report
+    }
+}
+
+/// A duration in milliseconds
+fn ms(duration: Duration) -> f64 {
+    duration.as_secs_f64() * 1000.0
+}
+
+/// Writes the report of the timings next to the ROM as \[game\]-timing-N.txt, returns its path
+#[cfg(feature = "std")]
+pub fn write_report(timings: &FrameTimings, game: &str) -> std::io::Result<String> {
+    let path = (1..).map(|n| format!("{}-timing-{}.txt", game, n)).find(|path| !std::path::Path::new(path).exists()).unwrap();
+    std::fs::write(&path, timings.report())?;
+    Ok(path)
+}
+
+#[cfg(test)]
+#[cfg_attr(coverage_nightly, coverage(off))]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_rolling_histogram() {
+        let mut histogram = RollingHistogram::default();
+        assert_eq!((histogram.mean(), histogram.percentile(0.99)), (Duration::ZERO, Duration::ZERO));
+        for _ in 0..WINDOW {histogram.add(Duration::from_micros(1200))}
+        histogram.add(Duration::from_millis(40));
+        // The oldest time left the window along with its bucket
+        assert_eq!(histogram.len(), WINDOW);
+        assert_eq!((histogram.counts()[2], histogram.counts()[BUCKETS - 1]), (WINDOW as u32 - 1, 1));
+        assert_eq!(histogram.percentile(0.5), Duration::from_micros(1200));
+        assert_eq!(histogram.max(), Duration::from_millis(40));
+    }
+
+    #[test]
+    fn test_frame_timings_report() {
+        let mut timings = FrameTimings::default();
+        let frame_time = Duration::from_micros(13_250);
+        for frame in 0..10 {
+            timings.add(Phase::Emulation, Duration::from_millis(3));
+            timings.add(Phase::Sleep, Duration::from_millis(10));
+            timings.add(Phase::Present, Duration::from_millis(1));
+            let interval = if frame == 7 {Duration::from_millis(40)} else {frame_time};
+            timings.add_interval(frame, interval, Duration::from_millis(3), frame_time);
+        }
+        // Unlimited speed has no frame time to be late on
+        timings.add_interval(10, Duration::from_millis(40), Duration::from_millis(3), Duration::ZERO);
+        assert_eq!(timings.spikes().map(|spike| spike.frame).collect::<Vec<_>>(), [7]);
+
+        let report = timings.report();
+        assert!(report.starts_with("emulation mean   3.00ms"));
+        assert!(report.contains("\n3.0,10,0,0\n"));
+        assert!(report.contains("\n10.0,0,0,10\n"));
+        // The table stops at the last bucket used
+        assert!(!report.contains("\n10.5,"));
+        assert!(report.ends_with("7,40.00,3.00\n"));
+    }
+}
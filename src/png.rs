@@ -0,0 +1,287 @@
+use alloc::{format, string::{String, ToString}, vec, vec::Vec};
+
+/// CRC-32 as used by PNG chunks and ZIP archives
+pub fn crc32(data: &[u8]) -> u32 {
+    !data.iter().fold(!0u32, |crc, byte| {
+        (0..8).fold(crc ^ *byte as u32, |crc, _| if crc & 1 != 0 {(crc >> 1) ^ 0xEDB88320} else {crc >> 1})
+    })
+}
+
+/// Encodes 24-bit RGB pixels as a PNG
+///
+/// The image data is stored uncompressed, the images are small enough for it not to matter.
+pub fn encode_png(width: usize, height: usize, pixels: &[u8]) -> Vec<u8> {
+    let mut raw = Vec::with_capacity((width * 3 + 1) * height);
+    for row in pixels.chunks(width * 3) {
+        raw.push(0);
+        raw.extend_from_slice(row);
+    }
+
+    // zlib stream made of stored blocks
+    let mut zlib = vec![0x78, 0x01];
+    let blocks = raw.chunks(0xFFFF).collect::<Vec<_>>();
+    for (i, block) in blocks.iter().enumerate() {
+        zlib.push((i == blocks.len() - 1) as u8);
+        zlib.extend((block.len() as u16).to_le_bytes());
+        zlib.extend((!(block.len() as u16)).to_le_bytes());
+        zlib.extend_from_slice(block);
+    }
+    let (a, b) = raw.iter().fold((1u32, 0u32), |(a, b), byte| ((a + *byte as u32) % 65521, (b + a + *byte as u32) % 65521));
+    zlib.extend(((b << 16) | a).to_be_bytes());
+
+    let mut ihdr = Vec::new();
+    ihdr.extend((width as u32).to_be_bytes());
+    ihdr.extend((height as u32).to_be_bytes());
+    ihdr.extend([8, 2, 0, 0, 0]);
+
+    let mut png = vec![0x89, b'P', b'N', b'G', 0x0D, 0x0A, 0x1A, 0x0A];
+    for (tag, data) in [(b"IHDR", ihdr), (b"IDAT", zlib), (b"IEND", Vec::new())] {
+        png.extend((data.len() as u32).to_be_bytes());
+        let start = png.len();
+        png.extend_from_slice(tag);
+        png.extend(data);
+        let crc = crc32(&png[start..]);
+        png.extend(crc.to_be_bytes());
+    }
+    png
+}
+
+/// Base lengths of the length codes 257 to 285
+const LENGTH_BASES: [u16; 29] = [3, 4, 5, 6, 7, 8, 9, 10, 11, 13, 15, 17, 19, 23, 27, 31, 35, 43, 51, 59, 67, 83, 99, 115, 131, 163, 195, 227, 258];
+/// Extra bits of the length codes 257 to 285
+const LENGTH_EXTRA: [u8; 29] = [0, 0, 0, 0, 0, 0, 0, 0, 1, 1, 1, 1, 2, 2, 2, 2, 3, 3, 3, 3, 4, 4, 4, 4, 5, 5, 5, 5, 0];
+/// Base distances of the distance codes
+const DISTANCE_BASES: [u16; 30] = [1, 2, 3, 4, 5, 7, 9, 13, 17, 25, 33, 49, 65, 97, 129, 193, 257, 385, 513, 769, 1025, 1537, 2049, 3073, 4097, 6145, 8193, 12289, 16385, 24577];
+/// Extra bits of the distance codes
+const DISTANCE_EXTRA: [u8; 30] = [0, 0, 0, 0, 1, 1, 2, 2, 3, 3, 4, 4, 5, 5, 6, 6, 7, 7, 8, 8, 9, 9, 10, 10, 11, 11, 12, 12, 13, 13];
+/// Order in which the lengths of the code length code are stored
+const CODE_LENGTH_ORDER: [usize; 19] = [16, 17, 18, 0, 8, 7, 9, 6, 10, 5, 11, 4, 12, 3, 13, 2, 14, 1, 15];
+
+/// Reads a deflate stream bit by bit, least significant bit first
+struct BitReader<'a> {
+    data: &'a [u8],
+    /// Index of the next bit
+    pos: usize,
+}
+
+impl BitReader<'_> {
+    fn bits(&mut self, count: u8) -> Result<u32, String> {
+        (0..count).try_fold(0, |value, i| {
+            let byte = self.data.get(self.pos / 8).ok_or("Truncated deflate stream")?;
+            let bit = (byte >> (self.pos % 8)) & 1;
+            self.pos += 1;
+            Ok(value | (bit as u32) << i)
+        })
+    }
+}
+
+/// A canonical Huffman code, stored as the amount of codes of each length and the symbols sorted by code
+struct Huffman {
+    counts: [u16; 16],
+    symbols: Vec<u16>,
+}
+
+impl Huffman {
+    fn new(lengths: &[u8]) -> Self {
+        let mut counts = [0; 16];
+        for length in lengths {counts[*length as usize] += 1}
+        counts[0] = 0;
+        let mut symbols: Vec<u16> = (0..lengths.len() as u16).filter(|symbol| lengths[*symbol as usize] != 0).collect();
+        symbols.sort_by_key(|symbol| lengths[*symbol as usize]);
+        Self {counts, symbols}
+    }
+
+    fn decode(&self, bits: &mut BitReader) -> Result<u16, String> {
+        let (mut code, mut first, mut index) = (0, 0, 0);
+        for count in &self.counts[1..] {
+            code |= bits.bits(1)? as i32;
+            if code - first < *count as i32 {return Ok(self.symbols[(index + code - first) as usize])}
+            index += *count as i32;
+            first = (first + *count as i32) << 1;
+            code <<= 1;
+        }
+        Err("Invalid Huffman code".to_string())
+    }
+}
+
+/// Decompresses a zlib stream
+///
+/// The checksum is not verified, PNG chunks having their own.
+pub fn inflate(zlib: &[u8]) -> Result<Vec<u8>, String> {
+    inflate_raw(zlib.get(2..).ok_or("Truncated zlib stream")?)
+}
+
+/// Decompresses a deflate stream without any zlib header, as stored in ZIP archives
+pub fn inflate_raw(deflate: &[u8]) -> Result<Vec<u8>, String> {
+    let mut bits = BitReader {data: deflate, pos: 0};
+    let mut out = Vec::new();
+    loop {
+        let last = bits.bits(1)? != 0;
+        match bits.bits(2)? {
+            0 => {
+                let start = bits.pos.div_ceil(8);
+                let len = u16::from_le_bytes(bits.data.get(start..start + 2).ok_or("Truncated stored block")?.try_into().unwrap()) as usize;
+                out.extend_from_slice(bits.data.get(start + 4..start + 4 + len).ok_or("Truncated stored block")?);
+                bits.pos = (start + 4 + len) * 8;
+            }
+            1 => {
+                let lengths: Vec<u8> = (0..288).map(|symbol| match symbol {0..=143 => 8, 144..=255 => 9, 256..=279 => 7, _ => 8}).collect();
+                inflate_block(&mut bits, &mut out, &Huffman::new(&lengths), &Huffman::new(&[5; 30]))?;
+            }
+            2 => {
+                let literals = bits.bits(5)? as usize + 257;
+                let distances = bits.bits(5)? as usize + 1;
+                let mut code_lengths = [0; 19];
+                for index in CODE_LENGTH_ORDER.iter().take(bits.bits(4)? as usize + 4) {code_lengths[*index] = bits.bits(3)? as u8}
+                let code_lengths = Huffman::new(&code_lengths);
+
+                let mut lengths = Vec::with_capacity(literals + distances);
+                while lengths.len() < literals + distances {
+                    let (value, repeat) = match code_lengths.decode(&mut bits)? {
+                        symbol @ 0..=15 => (symbol as u8, 1),
+                        16 => (*lengths.last().ok_or("Repeated length without a previous one")?, bits.bits(2)? + 3),
+                        17 => (0, bits.bits(3)? + 3),
+                        _ => (0, bits.bits(7)? + 11),
+                    };
+                    lengths.extend(core::iter::repeat_n(value, repeat as usize));
+                }
+                if lengths.len() != literals + distances {return Err("Code lengths overflow".to_string())}
+                inflate_block(&mut bits, &mut out, &Huffman::new(&lengths[..literals]), &Huffman::new(&lengths[literals..]))?;
+            }
+            _ => return Err("Invalid deflate block type".to_string()),
+        }
+        if last {return Ok(out)}
+    }
+}
+
+/// Decodes the symbols of a compressed block until its end
+fn inflate_block(bits: &mut BitReader, out: &mut Vec<u8>, literals: &Huffman, distances: &Huffman) -> Result<(), String> {
+    loop {
+        match literals.decode(bits)? {
+            symbol @ 0..=255 => out.push(symbol as u8),
+            256 => return Ok(()),
+            symbol => {
+                let code = symbol as usize - 257;
+                let len = *LENGTH_BASES.get(code).ok_or("Invalid length code")? as usize + bits.bits(LENGTH_EXTRA[code])? as usize;
+                let code = distances.decode(bits)? as usize;
+                let distance = *DISTANCE_BASES.get(code).ok_or("Invalid distance code")? as usize + bits.bits(DISTANCE_EXTRA[code])? as usize;
+                let start = out.len().checked_sub(distance).ok_or("Distance too far back")?;
+                for i in 0..len {out.push(out[start + i])}
+            }
+        }
+    }
+}
+
+/// Decodes a PNG into its width, height and 24-bit RGB pixels
+///
+/// Only non-interlaced images with 8 bits per channel are supported, which covers the grayscale, RGB, palette and alpha screenshots emulators save.
+/// Alpha is dropped.
+pub fn decode_png(png: &[u8]) -> Result<(usize, usize, Vec<u8>), String> {
+    if png.get(..8) != Some(&[0x89, b'P', b'N', b'G', 0x0D, 0x0A, 0x1A, 0x0A]) {return Err("Not a PNG".to_string())}
+    let (mut header, mut palette, mut zlib) = (None, Vec::new(), Vec::new());
+    let mut pos = 8;
+    while let Some(len) = png.get(pos..pos + 4) {
+        let len = u32::from_be_bytes(len.try_into().unwrap()) as usize;
+        let data = png.get(pos + 8..pos + 8 + len).ok_or("Truncated PNG chunk")?;
+        match &png[pos + 4..pos + 8] {
+            b"IHDR" if len == 13 => header = Some(data),
+            b"PLTE" => palette = data.to_vec(),
+            b"IDAT" => zlib.extend_from_slice(data),
+            b"IEND" => break,
+            _ => {}
+        }
+        pos += len + 12;
+    }
+    let header = header.ok_or("PNG without a header")?;
+    let width = u32::from_be_bytes(header[0..4].try_into().unwrap()) as usize;
+    let height = u32::from_be_bytes(header[4..8].try_into().unwrap()) as usize;
+    let (depth, color_type, interlace) = (header[8], header[9], header[12]);
+    if depth != 8 || interlace != 0 {return Err(format!("Unsupported PNG with {} bits per channel and interlace {}", depth, interlace))}
+    let channels = match color_type {0 => 1, 2 => 3, 3 => 1, 4 => 2, 6 => 4, _ => return Err(format!("Invalid PNG color type {}", color_type))};
+
+    let raw = inflate(&zlib)?;
+    let stride = width * channels;
+    if raw.len() < (stride + 1) * height {return Err("Truncated PNG image data".to_string())}
+    let mut data = vec![0u8; stride * height];
+    for y in 0..height {
+        let filter = raw[y * (stride + 1)];
+        for x in 0..stride {
+            let byte = raw[y * (stride + 1) + 1 + x];
+            let left = if x >= channels {data[y * stride + x - channels]} else {0};
+            let up = if y > 0 {data[(y - 1) * stride + x]} else {0};
+            let up_left = if x >= channels && y > 0 {data[(y - 1) * stride + x - channels]} else {0};
+            data[y * stride + x] = byte.wrapping_add(match filter {
+                0 => 0,
+                1 => left,
+                2 => up,
+                3 => ((left as u16 + up as u16) / 2) as u8,
+                4 => paeth(left, up, up_left),
+                _ => return Err(format!("Invalid PNG filter {}", filter)),
+            });
+        }
+    }
+
+    let mut rgb = Vec::with_capacity(width * height * 3);
+    for pixel in data.chunks(channels) {
+        match color_type {
+            0 | 4 => rgb.extend([pixel[0]; 3]),
+            3 => rgb.extend_from_slice(palette.get(pixel[0] as usize * 3..pixel[0] as usize * 3 + 3).ok_or("Color outside of the PNG palette")?),
+            _ => rgb.extend_from_slice(&pixel[..3]),
+        }
+    }
+    Ok((width, height, rgb))
+}
+
+/// Predicts a byte from its neighbours with the Paeth filter
+fn paeth(left: u8, up: u8, up_left: u8) -> u8 {
+    let p = left as i16 + up as i16 - up_left as i16;
+    let (pa, pb, pc) = ((p - left as i16).abs(), (p - up as i16).abs(), (p - up_left as i16).abs());
+    if pa <= pb && pa <= pc {left} else if pb <= pc {up} else {up_left}
+}
+
+#[cfg(test)]
+#[cfg_attr(coverage_nightly, coverage(off))]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_png() {
+        assert_eq!(crc32(b"IEND"), 0xAE426082);
+        let png = encode_png(1, 1, &[1, 2, 3]);
+        assert_eq!(&png[png.len() - 12..], &[0, 0, 0, 0, b'I', b'E', b'N', b'D', 0xAE, 0x42, 0x60, 0x82]);
+    }
+
+    #[test]
+    fn test_inflate() {
+        // Fixed and dynamic Huffman codes, both compressed by zlib
+        let fixed = [0x78, 0xDA, 0x0B, 0xCF, 0xCF, 0x4B, 0x49, 0x2D, 0x0A, 0x2E, 0x4F, 0xCC, 0x53, 0x08, 0xA7, 0x11, 0xD3, 0x39, 0x3F, 0x27, 0xBF, 0x08, 0x00, 0x1A, 0x51, 0x27, 0x68];
+        assert_eq!(inflate(&fixed).unwrap(), [&b"WonderSwan ".repeat(8)[..], b"WonderSwan Color"].concat());
+        let dynamic = [
+            0x78, 0xDA, 0x65, 0x50, 0x41, 0x0E, 0xC0, 0x20, 0x08, 0xFB, 0x8A, 0x5F, 0x63, 0xEA, 0xC1, 0x84, 0xA8, 0x41, 0x92, 0x65, 0xBF, 0xDF, 0xC6, 0x20,
+            0x11, 0x76, 0x81, 0x80, 0xA5, 0xAD, 0xCD, 0x03, 0x07, 0xA5, 0x73, 0xF4, 0x52, 0x29, 0x1D, 0xD0, 0x0B, 0x34, 0x9B, 0x26, 0x60, 0x65, 0xAE, 0xBF,
+            0xBE, 0x26, 0xB5, 0xA7, 0x65, 0xBA, 0x16, 0x03, 0x46, 0xF4, 0x3A, 0xA1, 0x1B, 0xC4, 0xDA, 0xBB, 0xB2, 0x77, 0xD5, 0x08, 0xD7, 0xDC, 0xD0, 0x4E,
+            0x43, 0xF1, 0x62, 0x3B, 0xA3, 0xED, 0xA2, 0x3F, 0xDB, 0x0B, 0xA7, 0x0D, 0x11, 0xAC, 0x36, 0x76, 0x0D, 0xB5, 0x92, 0x25, 0x11, 0x1F, 0xC5, 0x67,
+            0xCF, 0x5B, 0x51, 0x84, 0xB6, 0x10, 0x8E, 0xFB, 0x72, 0x34, 0xAF, 0x3C, 0xA2, 0x23, 0xCC, 0x52, 0x7C, 0x8E, 0x37, 0x02, 0x8D, 0x9B, 0x05,
+        ];
+        let text = inflate(&dynamic).unwrap();
+        assert_eq!((text.len(), crc32(&text)), (408, 0xE6D821E4));
+        assert!(text.starts_with(b"color wonder bandai"));
+        assert!(inflate(&fixed[..10]).is_err());
+    }
+
+    #[test]
+    fn test_decode_png() {
+        let pixels: Vec<u8> = (0..2 * 3 * 3).collect();
+        assert_eq!(decode_png(&encode_png(3, 2, &pixels)).unwrap(), (3, 2, pixels));
+
+        // RGBA with the sub and Paeth filters, compressed by zlib
+        let rgba = [
+            0x89, 0x50, 0x4E, 0x47, 0x0D, 0x0A, 0x1A, 0x0A, 0x00, 0x00, 0x00, 0x0D, 0x49, 0x48, 0x44, 0x52, 0x00, 0x00, 0x00, 0x03, 0x00, 0x00, 0x00, 0x02,
+            0x08, 0x06, 0x00, 0x00, 0x00, 0x9D, 0x74, 0x66, 0x1A, 0x00, 0x00, 0x00, 0x20, 0x49, 0x44, 0x41, 0x54, 0x78, 0xDA, 0x63, 0xE4, 0x12, 0x91, 0xFB,
+            0x2F, 0x22, 0xA7, 0xF1, 0x5F, 0x4E, 0xC3, 0xA8, 0x81, 0x85, 0x95, 0x95, 0x95, 0x81, 0x91, 0x89, 0x99, 0x85, 0x01, 0x08, 0x00, 0x44, 0x0D, 0x03,
+            0xAB, 0x94, 0x2A, 0x07, 0x27, 0x00, 0x00, 0x00, 0x00, 0x49, 0x45, 0x4E, 0x44, 0xAE, 0x42, 0x60, 0x82,
+        ];
+        assert_eq!(decode_png(&rgba).unwrap(), (3, 2, vec![10, 20, 30, 30, 50, 70, 60, 90, 120, 15, 25, 35, 31, 52, 73, 60, 90, 120]));
+        assert!(decode_png(b"GIF89a").is_err());
+    }
+}
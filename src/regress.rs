@@ -0,0 +1,195 @@
+use std::{collections::BTreeMap, fs, panic::{self, AssertUnwindSafe}, path::{Path, PathBuf}};
+
+use crate::{headless::{self, InputSource, NullInput, ScriptedInput}, options::{EmulatorOptions, SharedOptions}, parse_rom, soc::SoC, sound::buffer::SampleBuffer, state::{fnv1a, FNV_OFFSET}};
+
+/// Default amount of frames each ROM is run for
+pub const DEFAULT_FRAMES: usize = 600;
+
+/// Name of the baseline file, stored in the ROM directory
+pub const BASELINE_FILE: &str = "regress.baseline";
+/// Name of the baseline file of runs in pure mode, kept apart as they run with a different configuration
+pub const PURE_BASELINE_FILE: &str = "regress.pure.baseline";
+
+/// The result of running a single ROM
+#[derive(Clone, PartialEq, Eq, Debug)]
+pub enum RunResult {
+    /// The hash of the framebuffer after each frame
+    Frames(Vec<u64>),
+    /// The emulator panicked, contains the frame it panicked on
+    Panicked(usize),
+}
+
+/// How a ROM's output compares to the baseline
+#[derive(Clone, PartialEq, Eq, Debug)]
+pub enum Verdict {
+    /// The output is identical
+    Unchanged,
+    /// The output first differs on the given frame
+    Changed(usize),
+    /// The ROM is not in the baseline
+    New,
+    /// The ROM is in the baseline but was not run
+    Missing,
+}
+
+/// Hashes a frame with FNV-1a
+pub fn hash_frame(frame: &[u8]) -> u64 {
+    fnv1a(FNV_OFFSET, frame)
+}
+
+/// Runs a ROM headlessly for the given amount of frames, holding the buttons the input source asks for
+///
+/// Save files are ignored so that the output only depends on the ROM, the input and the emulator.
+/// With `pure` the ROM runs in the canonical configuration of [`EmulatorOptions::effective`] instead of the default options.
+pub fn run_rom(game: &str, frames: usize, input: &mut dyn InputSource, pure: bool) -> RunResult {
+    let (color, ram_content, _, _, rom, mapper, sram, rom_info, _) = parse_rom(game);
+    let ram_content = vec![0; ram_content.len()];
+    let options = SharedOptions::new(EmulatorOptions {color, mute: true, pure, ..EmulatorOptions::new()});
+    let mut soc = SoC::new(ram_content, Vec::new(), Vec::new(), rom, mapper, sram, SampleBuffer::shared(), options, rom_info);
+
+    let mut hashes = Vec::with_capacity(frames);
+    let result = panic::catch_unwind(AssertUnwindSafe(|| {
+        headless::run(&mut soc, input, frames as u64, |soc, _| hashes.push(hash_frame(&soc.get_lcd().borrow()[..])));
+    }));
+
+    match result {
+        Ok(()) => RunResult::Frames(hashes),
+        Err(_) => RunResult::Panicked(hashes.len()),
+    }
+}
+
+/// Serializes results into the baseline format, one ROM per line
+pub fn write_baseline(results: &BTreeMap<String, RunResult>) -> String {
+    results.iter().map(|(name, result)| match result {
+        RunResult::Frames(hashes) => format!("{}\t{}\n", name, hashes.iter().map(|h| format!("{:016X}", h)).collect::<Vec<_>>().join(",")),
+        RunResult::Panicked(frame) => format!("{}\tpanic {}\n", name, frame),
+    }).collect()
+}
+
+/// Parses a baseline, lines that cannot be parsed are skipped
+pub fn read_baseline(baseline: &str) -> BTreeMap<String, RunResult> {
+    baseline.lines().filter_map(|line| {
+        let (name, data) = line.split_once('\t')?;
+        let result = if let Some(frame) = data.strip_prefix("panic ") {
+            RunResult::Panicked(frame.parse().ok()?)
+        } else {
+            RunResult::Frames(data.split(',').filter(|h| !h.is_empty()).map(|h| u64::from_str_radix(h, 16).ok()).collect::<Option<_>>()?)
+        };
+        Some((name.to_string(), result))
+    }).collect()
+}
+
+/// Compares a result against the baseline
+pub fn compare(baseline: Option<&RunResult>, result: &RunResult) -> Verdict {
+    let Some(baseline) = baseline else {return Verdict::New};
+    if baseline == result {return Verdict::Unchanged}
+
+    let frames = |result: &RunResult| match result {
+        RunResult::Frames(hashes) => (hashes.clone(), None),
+        RunResult::Panicked(frame) => (Vec::new(), Some(*frame)),
+    };
+    let ((old, old_panic), (new, new_panic)) = (frames(baseline), frames(result));
+    let first_diff = old.iter().zip(new.iter()).position(|(a, b)| a != b);
+    Verdict::Changed(first_diff.or(old_panic).or(new_panic).unwrap_or(old.len().min(new.len())))
+}
+
+/// Lists the .ws and .wsc files in a directory, sorted by name
+pub fn list_roms(dir: &str) -> Result<Vec<PathBuf>, String> {
+    let mut games: Vec<_> = fs::read_dir(dir).map_err(|e| e.to_string())?
+        .filter_map(|entry| entry.ok().map(|entry| entry.path()))
+        .filter(|path| matches!(path.extension().and_then(|ext| ext.to_str()), Some("ws") | Some("wsc")))
+        .collect();
+    games.sort();
+    Ok(games)
+}
+
+/// Runs a ROM found by [`list_roms`], with the [`ScriptedInput`] script next to it if there is one
+///
+/// A panic is reported as [`RunResult::Panicked`] instead of unwinding.
+pub fn run_listed_rom(game: &Path, frames: usize, pure: bool) -> Result<RunResult, String> {
+    let stem = game.with_extension("");
+    let script = stem.with_extension("script");
+    let mut input: Box<dyn InputSource> = match script.exists() {
+        true => Box::new(ScriptedInput::load(&script.to_string_lossy())?),
+        false => Box::new(NullInput),
+    };
+    Ok(panic::catch_unwind(AssertUnwindSafe(|| run_rom(&stem.to_string_lossy(), frames, input.as_mut(), pure))).unwrap_or(RunResult::Panicked(0)))
+}
+
+/// Entry point of the `regress` command
+///
+/// Usage: `regress <directory> [frames] [update] [--pure]`
+///
+/// Every .ws and .wsc file in the directory is run for the given amount of frames and compared against the baseline stored in the directory.
+/// The baseline is created if it does not exist yet, passing `update` replaces it with the current results.
+/// Passing `--pure` runs the ROMs in pure mode against a baseline of its own.
+/// A ROM with a [`ScriptedInput`] script next to it, named after it with the .script extension, is played with that input so that more than its title screen is covered.
+pub fn run(args: &[String]) -> Result<(), String> {
+    let dir = args.first().ok_or("Usage: regress <directory> [frames] [update] [--pure]")?;
+    let frames = args.get(1).and_then(|frames| frames.parse().ok()).unwrap_or(DEFAULT_FRAMES);
+    let update = args.iter().any(|arg| arg == "update");
+    let pure = args.iter().any(|arg| arg == "--pure");
+
+    let games = list_roms(dir)?;
+
+    // Keep the output of panicking ROMs readable
+    let hook = panic::take_hook();
+    panic::set_hook(Box::new(|_| {}));
+
+    let mut results = BTreeMap::new();
+    for game in games {
+        let name = game.file_name().unwrap().to_string_lossy().to_string();
+        println!("Running {}", name);
+        results.insert(name, run_listed_rom(&game, frames, pure)?);
+    }
+    panic::set_hook(hook);
+
+    let baseline_path = Path::new(dir).join(if pure {PURE_BASELINE_FILE} else {BASELINE_FILE});
+    let baseline = fs::read_to_string(&baseline_path).ok();
+    if update || baseline.is_none() {
+        fs::write(&baseline_path, write_baseline(&results)).map_err(|e| e.to_string())?;
+        println!("Wrote baseline for {} ROMs to {}", results.len(), baseline_path.display());
+        return Ok(());
+    }
+
+    let baseline = read_baseline(&baseline.unwrap());
+    let mut changed = 0;
+    for (name, result) in &results {
+        match compare(baseline.get(name), result) {
+            Verdict::Unchanged => {}
+            Verdict::Changed(frame) => {
+                changed += 1;
+                println!("CHANGED  {} (first difference on frame {})", name, frame);
+            }
+            verdict => println!("{:?}  {}", verdict, name),
+        }
+    }
+    for name in baseline.keys().filter(|name| !results.contains_key(*name)) {
+        println!("{:?}  {}", Verdict::Missing, name);
+    }
+    println!("{} of {} ROMs changed", changed, results.len());
+    Ok(())
+}
+
+#[cfg(test)]
+#[cfg_attr(coverage_nightly, coverage(off))]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_baseline_round_trip() {
+        let mut results = BTreeMap::new();
+        results.insert("a.ws".to_string(), RunResult::Frames(vec![hash_frame(&[1, 2, 3]), 0]));
+        results.insert("b.wsc".to_string(), RunResult::Panicked(12));
+        assert_eq!(read_baseline(&write_baseline(&results)), results);
+    }
+
+    #[test]
+    fn test_compare() {
+        let old = RunResult::Frames(vec![1, 2, 3]);
+        assert_eq!(compare(Some(&old), &RunResult::Frames(vec![1, 2, 3])), Verdict::Unchanged);
+        assert_eq!(compare(Some(&old), &RunResult::Frames(vec![1, 5, 3])), Verdict::Changed(1));
+        assert_eq!(compare(Some(&old), &RunResult::Panicked(2)), Verdict::Changed(2));
+        assert_eq!(compare(None, &old), Verdict::New);
+    }
+}
@@ -0,0 +1,166 @@
+use std::{fs, time::{Duration, Instant}};
+
+use crate::{compare::ScreenDiff, headless::{self, InputSource, NullInput, ScriptedInput}, options::{EmulatorOptions, SharedOptions}, parse_rom, soc::SoC, sound::buffer::SampleBuffer};
+
+/// Frames compared when none are given, ten seconds of play
+const DEFAULT_FRAMES: u64 = 600;
+
+/// Results of running the per-dot and scanline renderers side by side
+#[derive(Clone, PartialEq, Eq, Debug, Default)]
+pub struct RendererComparison {
+    /// Frames run by each renderer
+    pub frames: u64,
+    /// Time spent running frames with the per-dot renderer
+    pub per_dot_time: Duration,
+    /// Time spent running frames with the scanline renderer
+    pub scanline_time: Duration,
+    /// Frames whose pixels differ between the renderers
+    pub differing_frames: u64,
+    /// Pixels differing across every frame
+    pub differing_pixels: u64,
+    /// The frame with the most differing pixels along with how many differ, if any does
+    pub worst: Option<(u64, usize)>,
+    /// The first frame after which the consoles' WRAM differs, meaning the renderer changed how the game ran
+    pub desync: Option<u64>,
+}
+
+impl RendererComparison {
+    /// Returns how many times faster the scanline renderer ran
+    pub fn speedup(&self) -> f64 {
+        self.per_dot_time.as_secs_f64() / self.scanline_time.as_secs_f64().max(f64::EPSILON)
+    }
+
+    /// Describes the speed of both renderers and how much their frames differ
+    pub fn report(&self) -> String {
+        let fps = |time: Duration| self.frames as f64 / time.as_secs_f64().max(f64::EPSILON);
+        let mut report = format!(
+            "Per-dot:  {:8.1} fps\nScanline: {:8.1} fps ({:.2}x)\n{} of {} frames differ, {} pixels in total",
+            fps(self.per_dot_time), fps(self.scanline_time), self.speedup(), self.differing_frames, self.frames, self.differing_pixels,
+        );
+        if let Some((frame, pixels)) = self.worst {
+            report += &format!("\nFrame {} differs the most, by {} pixels", frame, pixels);
+        }
+        if let Some(frame) = self.desync {
+            report += &format!("\nThe games' memory differs from frame {} on", frame);
+        }
+        report
+    }
+}
+
+/// Runs two SoCs in lockstep, one with the per-dot renderer and one with the scanline renderer, holding the same buttons on both
+///
+/// Everything but the renderer is left as the SoCs' options set it, so that the differences only come from drawing whole lines at once.
+/// `on_frame` is called after each frame that differs with its index and both frames.
+pub fn compare_renderers(per_dot: &mut SoC, scanline: &mut SoC, input: &mut dyn InputSource, frames: u64, mut on_frame: impl FnMut(u64, &[u8], &[u8])) -> RendererComparison {
+    per_dot.set_scanline_rendering(false);
+    scanline.set_scanline_rendering(true);
+
+    let mut comparison = RendererComparison {frames, ..RendererComparison::default()};
+    for frame in 0..frames {
+        let keys = input.keys(frame);
+        for (soc, time) in [(&mut *per_dot, &mut comparison.per_dot_time), (&mut *scanline, &mut comparison.scanline_time)] {
+            headless::hold_keys(soc, keys);
+            let start = Instant::now();
+            soc.run_frame();
+            *time += start.elapsed();
+        }
+
+        let (dot_lcd, line_lcd) = (per_dot.get_lcd().borrow().to_vec(), scanline.get_lcd().borrow().to_vec());
+        let pixels = ScreenDiff::between(&dot_lcd, &line_lcd, 0).count();
+        if pixels > 0 {
+            comparison.differing_frames += 1;
+            comparison.differing_pixels += pixels as u64;
+            if comparison.worst.is_none_or(|(_, worst)| pixels > worst) {comparison.worst = Some((frame, pixels))}
+            on_frame(frame, &dot_lcd, &line_lcd);
+        }
+        if comparison.desync.is_none() && per_dot.wram_hash() != scanline.wram_hash() {
+            comparison.desync = Some(frame);
+        }
+    }
+    comparison
+}
+
+/// Entry point of the `renderers` command
+///
+/// Usage: `renderers <rom> [frames] [script]`
+///
+/// Plays the ROM without a window with both renderers, holding the buttons of a [`ScriptedInput`] script if one is given,
+/// then prints how fast each one ran and how many pixels the scanline renderer got wrong, writing the frame it got the most wrong as a PNG.
+/// Save files are ignored so that both runs start the same way.
+pub fn run(args: &[String]) -> Result<(), String> {
+    let game = args.first().ok_or("Usage: renderers <rom> [frames] [script]")?;
+    let frames = match args.get(1) {
+        Some(frames) => frames.parse().map_err(|_| format!("Invalid frame count: {}", frames))?,
+        None => DEFAULT_FRAMES,
+    };
+    let mut input: Box<dyn InputSource> = match args.get(2) {
+        Some(script) => Box::new(ScriptedInput::load(script)?),
+        None => Box::new(NullInput),
+    };
+
+    let build = || {
+        let (color, ram_content, _, _, rom, mapper, sram, rom_info, _) = parse_rom(game);
+        let ram_content = vec![0; ram_content.len()];
+        let options = SharedOptions::new(EmulatorOptions {color, mute: true, ..EmulatorOptions::new()});
+        SoC::new(ram_content, Vec::new(), Vec::new(), rom, mapper, sram, SampleBuffer::shared(), options, rom_info)
+    };
+    let mut per_dot = build();
+    let mut scanline = build();
+
+    let mut worst: Option<(usize, u64, Vec<u8>)> = None;
+    let comparison = compare_renderers(&mut per_dot, &mut scanline, input.as_mut(), frames, |frame, dot_lcd, line_lcd| {
+        let diff = ScreenDiff::between(dot_lcd, line_lcd, 0);
+        if worst.as_ref().is_none_or(|(pixels, _, _)| diff.count() > *pixels) {
+            worst = Some((diff.count(), frame, diff.png(dot_lcd, line_lcd)));
+        }
+    });
+    println!("{}", comparison.report());
+
+    if let Some((_, frame, png)) = worst {
+        let path = format!("{}.renderers-{}.png", game, frame);
+        fs::write(&path, png).map_err(|e| format!("{}: {}", path, e))?;
+        println!("Wrote the per-dot frame, the scanline frame and their differences to {}", path);
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+#[cfg_attr(coverage_nightly, coverage(off))]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_static_frames_match() {
+        let mut per_dot = SoC::test_build();
+        let mut scanline = SoC::test_build();
+        let comparison = compare_renderers(&mut per_dot, &mut scanline, &mut NullInput, 3, |_, _, _| panic!("The renderers differ"));
+        assert_eq!((comparison.differing_frames, comparison.worst, comparison.desync), (0, None, None));
+        assert!(comparison.report().contains("0 of 3 frames differ"));
+    }
+
+    #[test]
+    fn test_mid_line_scroll_differs() {
+        // Scrolls screen 1 over and over, which only the per-dot renderer shows partway through lines
+        let program = vec![
+            0xB0, 0x01, // MOV AL, 0x01
+            0xE6, 0x14, // OUT 0x14, AL, switching the LCD on
+            0xE6, 0x00, // OUT 0x00, AL, showing screen 1 alone
+            0xB0, 0x70, // MOV AL, 0x70
+            0xE6, 0x21, // OUT 0x21, AL, so that the tiles' set pixels stand out
+            0xFE, 0xC0, // INC AL
+            0xE6, 0x10, // OUT 0x10, AL
+            0xEB, 0xFA, // BR -6
+        ];
+        let mut per_dot = SoC::test_build();
+        let mut scanline = SoC::test_build();
+        per_dot.set_wram(program.clone());
+        scanline.set_wram(program);
+
+        let mut differing = Vec::new();
+        let comparison = compare_renderers(&mut per_dot, &mut scanline, &mut NullInput, 3, |frame, _, _| differing.push(frame));
+        assert!(comparison.differing_pixels > 0);
+        assert_eq!(differing.len() as u64, comparison.differing_frames);
+        assert!(comparison.worst.is_some_and(|(frame, _)| differing.contains(&frame)));
+        assert_eq!(comparison.desync, None);
+    }
+}
@@ -0,0 +1,300 @@
+use alloc::{format, string::{String, ToString}, vec::Vec};
+
+use crate::{storage::Storage, toml::{self, Line, Value}};
+
+/// The embedded database, see the file itself for its format
+#[cfg(feature = "romdb")]
+const DATABASE: &str = include_str!("romdb.toml");
+/// The database is left out when the feature is disabled, every lookup fails
+#[cfg(not(feature = "romdb"))]
+const DATABASE: &str = "";
+
+/// Per-game settings the emulator cannot derive from the ROM's footer
+#[derive(Clone, Copy, PartialEq, Eq, Debug, Default)]
+pub struct Quirks {
+    /// The game is played with the console held vertically, the window starts out rotated
+    pub vertical: bool,
+    /// The cartridge contains a real-time clock
+    pub rtc: bool,
+    /// Size of the cartridge EEPROM in bytes, for games whose footer reports the wrong save type
+    pub eeprom_size: Option<usize>,
+}
+
+impl Quirks {
+    /// Parses a comma separated list of `vertical`, `rtc` and `eeprom=<size>`, the size being in hexadecimal bytes
+    ///
+    /// Unknown quirks are ignored.
+    pub fn parse(list: &str) -> Self {
+        let mut quirks = Quirks::default();
+        for quirk in list.split(',').map(str::trim) {
+            match quirk.split_once('=') {
+                Some(("eeprom", size)) => quirks.eeprom_size = usize::from_str_radix(size.trim_start_matches("0x"), 16).ok(),
+                _ if quirk == "vertical" => quirks.vertical = true,
+                _ if quirk == "rtc" => quirks.rtc = true,
+                _ => {}
+            }
+        }
+        quirks
+    }
+}
+
+impl core::fmt::Display for Quirks {
+    /// Formats the quirks the way the database lists them
+    fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
+        let mut quirks = Vec::new();
+        if self.vertical {quirks.push("vertical".to_string())}
+        if self.rtc {quirks.push("rtc".to_string())}
+        if let Some(size) = self.eeprom_size {quirks.push(format!("eeprom=0x{:X}", size))}
+        write!(f, "{}", quirks.join(","))
+    }
+}
+
+/// How well a game runs in the emulator
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum Status {
+    /// No known issues
+    Perfect,
+    /// Can be finished, with minor issues
+    Playable,
+    /// Gets in-game, but has issues that prevent finishing it
+    InGame,
+    /// Does not get in-game
+    Broken,
+}
+
+impl Status {
+    /// Every status, from best to worst
+    const ALL: [Status; 4] = [Status::Perfect, Status::Playable, Status::InGame, Status::Broken];
+
+    /// Returns the name the status is stored as
+    pub fn name(&self) -> &'static str {
+        match self {
+            Status::Perfect => "perfect",
+            Status::Playable => "playable",
+            Status::InGame => "ingame",
+            Status::Broken => "broken",
+        }
+    }
+
+    /// Returns the status with the given name
+    pub fn from_name(name: &str) -> Option<Self> {
+        Self::ALL.into_iter().find(|status| status.name() == name.trim())
+    }
+}
+
+/// An entry of the ROM database
+#[derive(Clone, PartialEq, Eq, Debug)]
+pub struct GameInfo {
+    /// The game's title
+    pub title: String,
+    /// Known quirks of the game
+    pub quirks: Quirks,
+    /// How well the game runs, if it has been tested
+    pub status: Option<Status>,
+    /// Known issues of the game, empty if there are none
+    pub notes: String,
+}
+
+impl GameInfo {
+    /// Formats the entry as a table of the database for the ROM with the given hash, leaving out the keys that are empty
+    ///
+    /// Double quotes in the title and notes become single quotes, as strings cannot escape them.
+    pub fn database_entry(&self, hash: &[u8; 20]) -> String {
+        let mut entry = format!("[{}]\ntitle = \"{}\"\n", hex(hash), self.title.replace('"', "'"));
+        let quirks = self.quirks.to_string();
+        if !quirks.is_empty() {entry += &format!("quirks = \"{}\"\n", quirks)}
+        if let Some(status) = self.status {entry += &format!("status = \"{}\"\n", status.name())}
+        if !self.notes.is_empty() {entry += &format!("notes = \"{}\"\n", self.notes.replace('"', "'"))}
+        entry
+    }
+}
+
+/// File compatibility reports are appended to, in the format of the database
+pub const COMPAT_REPORT: &str = "compat-report.toml";
+
+/// Appends an entry for the ROM with the given hash to the compatibility reports, after the ones already written
+pub fn append_report(storage: &mut dyn Storage, entry: &GameInfo, hash: &[u8; 20]) -> Result<(), String> {
+    let mut reports = storage.read(COMPAT_REPORT).unwrap_or_default();
+    reports.extend_from_slice(entry.database_entry(hash).as_bytes());
+    reports.push(b'\n');
+    storage.write(COMPAT_REPORT, &reports)
+}
+
+/// Formats a hash as lowercase hexadecimal, the way the database keys entries
+fn hex(hash: &[u8; 20]) -> String {
+    hash.iter().map(|byte| format!("{:02x}", byte)).collect()
+}
+
+/// Looks up a ROM image in the embedded database by its SHA-1
+pub fn lookup(rom: &[u8]) -> Option<GameInfo> {
+    find(DATABASE, &sha1(rom))
+}
+
+/// Parses every entry of a database, along with the hash it is listed under in lowercase
+///
+/// Each game is a table named after the SHA-1 of its ROM as hexadecimal, holding the keys
+///
+/// | Key      | Value                                                                  |
+/// |----------|------------------------------------------------------------------------|
+/// | `title`  | The game's title, required                                             |
+/// | `quirks` | A comma separated list, see [`Quirks::parse`]                          |
+/// | `status` | One of `perfect`, `playable`, `ingame` and `broken`                    |
+/// | `notes`  | Known issues, shown when the game is loaded                            |
+///
+/// All of them are strings, see [`crate::toml`] for the subset of TOML understood.
+pub fn entries(database: &str) -> Result<Vec<(String, GameInfo)>, String> {
+    let mut entries: Vec<(String, Option<String>, GameInfo)> = Vec::new();
+    for line in toml::lines(database) {
+        let (number, line) = line?;
+        let error = |e: String| format!("line {}: {}", number, e);
+        match line {
+            Line::Table(hash) => {
+                entries.push((hash.to_ascii_lowercase(), None, GameInfo {title: String::new(), quirks: Quirks::default(), status: None, notes: String::new()}));
+            }
+            Line::Pair(key, value) => {
+                let (_, title, info) = entries.last_mut().ok_or_else(|| error(format!("{} is not part of an entry", key)))?;
+                let Value::String(value) = value else {return Err(error(format!("{} must be a string", key)))};
+                match key.as_str() {
+                    "title" => *title = Some(value),
+                    "quirks" => info.quirks = Quirks::parse(&value),
+                    "status" => info.status = Some(Status::from_name(&value).ok_or_else(|| error(format!("Unknown status {}", value)))?),
+                    "notes" => info.notes = value.trim().to_string(),
+                    key => return Err(error(format!("Unknown key {}", key))),
+                }
+            }
+        }
+    }
+    entries.into_iter()
+        .map(|(hash, title, info)| match title {
+            Some(title) => Ok((hash, GameInfo {title, ..info})),
+            None => Err(format!("The entry {} has no title", hash)),
+        })
+        .collect()
+}
+
+/// Finds the entry with the given hash in a database, none if it is not listed or the database cannot be parsed
+pub fn find(database: &str, hash: &[u8; 20]) -> Option<GameInfo> {
+    let hash = hex(hash);
+    entries(database).ok()?.into_iter().find(|(key, _)| *key == hash).map(|(_, info)| info)
+}
+
+/// Computes the SHA-1 of the data, the hash used by No-Intro's databases
+pub fn sha1(data: &[u8]) -> [u8; 20] {
+    let mut h: [u32; 5] = [0x67452301, 0xEFCDAB89, 0x98BADCFE, 0x10325476, 0xC3D2E1F0];
+
+    let mut message = data.to_vec();
+    message.push(0x80);
+    while message.len() % 64 != 56 {
+        message.push(0);
+    }
+    message.extend((data.len() as u64 * 8).to_be_bytes());
+
+    for chunk in message.chunks_exact(64) {
+        let mut w = [0u32; 80];
+        for i in 0..16 {
+            w[i] = u32::from_be_bytes([chunk[i * 4], chunk[i * 4 + 1], chunk[i * 4 + 2], chunk[i * 4 + 3]]);
+        }
+        for i in 16..80 {
+            w[i] = (w[i - 3] ^ w[i - 8] ^ w[i - 14] ^ w[i - 16]).rotate_left(1);
+        }
+
+        let [mut a, mut b, mut c, mut d, mut e] = h;
+        for (i, word) in w.iter().enumerate() {
+            let (f, k) = match i {
+                0..=19 => ((b & c) | (!b & d), 0x5A827999),
+                20..=39 => (b ^ c ^ d, 0x6ED9EBA1),
+                40..=59 => ((b & c) | (b & d) | (c & d), 0x8F1BBCDC),
+                _ => (b ^ c ^ d, 0xCA62C1D6),
+            };
+            let temp = a.rotate_left(5).wrapping_add(f).wrapping_add(e).wrapping_add(k).wrapping_add(*word);
+            (e, d, c, b, a) = (d, c, b.rotate_left(30), a, temp);
+        }
+
+        for (h, x) in h.iter_mut().zip([a, b, c, d, e]) {
+            *h = h.wrapping_add(x);
+        }
+    }
+
+    let mut hash = [0; 20];
+    for (i, word) in h.iter().enumerate() {
+        hash[i * 4..i * 4 + 4].copy_from_slice(&word.to_be_bytes());
+    }
+    hash
+}
+
+#[cfg(test)]
+#[cfg_attr(coverage_nightly, coverage(off))]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_sha1() {
+        assert_eq!(hex(&sha1(b"abc")), "a9993e364706816aba3e25717850c26c9cd0d89d");
+        assert_eq!(hex(&sha1(b"")), "da39a3ee5e6b4b0d3255bfef95601890afd80709");
+        assert_eq!(hex(&sha1(&[b'a'; 1000])), "291e9a6c66994949b57ba5e650361e98fc36b1ba");
+    }
+
+    #[test]
+    fn test_find() {
+        let hash = sha1(b"rom");
+        let database = format!("# comment\n\n[{}]\ntitle = \"Some Game\"\nquirks = \"vertical, eeprom=0x800\"  # a comment\n", hex(&hash).to_uppercase());
+
+        let info = find(&database, &hash).unwrap();
+        assert_eq!(info.title, "Some Game");
+        assert_eq!(info.quirks, Quirks {vertical: true, rtc: false, eeprom_size: Some(0x800)});
+        assert_eq!(info.status, None);
+        assert_eq!(find(&database, &sha1(b"other")), None);
+
+        assert!(entries("title = \"Orphan\"").unwrap_err().contains("line 1"));
+        assert!(entries("[abc]\nquirks = \"rtc\"").unwrap_err().contains("no title"));
+        assert!(entries("[abc]\ntitle = \"Game\"\nstatus = \"best\"").unwrap_err().contains("Unknown status best"));
+    }
+
+    #[test]
+    #[cfg(feature = "romdb")]
+    fn test_database() {
+        // The bundled database parses as a whole, a broken entry would otherwise hide every game from lookups
+        let entries = entries(DATABASE).unwrap();
+        assert!(entries.iter().all(|(hash, _)| hash.len() == 40 && hash.chars().all(|c| c.is_ascii_hexdigit())));
+    }
+
+    #[test]
+    #[cfg(feature = "romdb")]
+    fn test_lookup() {
+        let info = lookup(&crate::demo::rom()).unwrap();
+        assert_eq!(info.title, "Test pattern demo");
+        assert_eq!(info.status, Some(Status::Perfect));
+        assert_eq!(lookup(b"rom"), None);
+    }
+
+    #[test]
+    fn test_status() {
+        let hash = sha1(b"rom");
+        let info = GameInfo {
+            title: "Some Game".to_string(),
+            quirks: Quirks {vertical: true, rtc: true, eeprom_size: None},
+            status: Some(Status::InGame),
+            notes: "Hangs after the second boss".to_string(),
+        };
+        // Entries written for reports can be pasted into the database as they are
+        let database = info.database_entry(&hash);
+        assert_eq!(find(&database, &hash), Some(info));
+        let quoted = GameInfo {title: "The \"Game\"".to_string(), quirks: Quirks::default(), status: None, notes: String::new()};
+        assert_eq!(quoted.database_entry(&hash), format!("[{}]\ntitle = \"The 'Game'\"\n", hex(&hash)));
+    }
+
+    #[test]
+    fn test_append_report() {
+        let mut storage = crate::storage::MemoryStorage::default();
+        let first = GameInfo {title: "Some Game".to_string(), quirks: Quirks::default(), status: Some(Status::Playable), notes: "[600 frames]".to_string()};
+        let second = GameInfo {title: "Other Game".to_string(), status: Some(Status::Broken), ..first.clone()};
+        append_report(&mut storage, &first, &sha1(b"rom")).unwrap();
+        append_report(&mut storage, &second, &sha1(b"other")).unwrap();
+
+        // Reports add up and read back as a database
+        let reports = String::from_utf8(storage.files[COMPAT_REPORT].clone()).unwrap();
+        assert_eq!(reports, format!("{}\n{}\n", first.database_entry(&sha1(b"rom")), second.database_entry(&sha1(b"other"))));
+        assert_eq!(find(&reports, &sha1(b"rom")), Some(first));
+        assert_eq!(find(&reports, &sha1(b"other")), Some(second));
+    }
+}
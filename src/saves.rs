@@ -0,0 +1,187 @@
+use std::fs;
+
+use crate::{cartridge::journal, read_header, read_rom, save_layout};
+
+/// Usage of the save subcommand
+const USAGE: &str = "Usage: save <import|export> <mednafen|ares|oswan> <rom|ws.ieeprom|wsc.ieeprom> <file>";
+
+/// Emulators whose save files can be converted
+///
+/// All of them store save memory as raw bytes, with 16-bit EEPROM words in little endian like WonderCrab.
+/// What differs is the size of the files, as some emulators round save memory up or keep the internal EEPROM at another size,
+/// and whether the internal EEPROM is kept at all.
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum SaveFormat {
+    /// Mednafen's `.sav` files, holding the SRAM or EEPROM of a cartridge
+    ///
+    /// Mednafen builds the internal EEPROM from its settings on every boot, so it has none to convert.
+    Mednafen,
+    /// ares, keeping the cartridge's save memory apart from the internal EEPROM in the system's folder
+    Ares,
+    /// Oswan's `.sav` files and internal EEPROM
+    Oswan,
+}
+
+impl SaveFormat {
+    /// Every format, in the order they are listed to the user
+    pub const ALL: [SaveFormat; 3] = [SaveFormat::Mednafen, SaveFormat::Ares, SaveFormat::Oswan];
+
+    /// Returns the name used on the command line
+    pub fn name(&self) -> &'static str {
+        match self {
+            SaveFormat::Mednafen => "mednafen",
+            SaveFormat::Ares => "ares",
+            SaveFormat::Oswan => "oswan",
+        }
+    }
+
+    /// Parses a name returned by [`SaveFormat::name`]
+    pub fn from_name(name: &str) -> Option<Self> {
+        Self::ALL.into_iter().find(|format| format.name() == name)
+    }
+
+    /// Returns whether the emulator keeps the console's internal EEPROM in a file
+    pub fn keeps_ieeprom(&self) -> bool {
+        *self != SaveFormat::Mednafen
+    }
+}
+
+/// Fits save memory taken from another emulator to the size WonderCrab expects
+///
+/// Shorter files are padded with zeroes, like blank saves are created.
+/// Longer files are cut short, as long as the bytes cut off all hold the same value and are therefore padding rather than data.
+pub fn fit(data: &[u8], size: usize) -> Result<Vec<u8>, String> {
+    if data.len() <= size {
+        let mut fitted = data.to_vec();
+        fitted.resize(size, 0);
+        return Ok(fitted);
+    }
+    let excess = &data[size..];
+    if excess.iter().any(|byte| *byte != excess[0]) {
+        return Err(format!("The save is {} bytes long, more than the {} bytes expected, and its end holds data", data.len(), size));
+    }
+    Ok(data[..size].to_vec())
+}
+
+/// Where a save is converted to or from
+enum Target {
+    /// The save memory of a cartridge, in `[game].sram` or `[game].eeprom`
+    Cartridge {path: String, size: usize, sram: bool},
+    /// The internal EEPROM of a model, in `ws.ieeprom` or `wsc.ieeprom`
+    Ieeprom {path: &'static str, size: usize},
+}
+
+impl Target {
+    /// Finds out where the save of a game or of an internal EEPROM is kept
+    fn parse(target: &str) -> Result<Self, String> {
+        match target {
+            "ws.ieeprom" => Ok(Target::Ieeprom {path: "ws.ieeprom", size: 128}),
+            "wsc.ieeprom" => Ok(Target::Ieeprom {path: "wsc.ieeprom", size: 0x800}),
+            game => {
+                let rom = read_rom(game)?;
+                let (header, info) = read_header(game, &rom)?;
+                let (size, sram) = save_layout(&header, info.as_ref());
+                if size == 0 {return Err(format!("{} does not have any save memory", game))}
+                let path = if sram {format!("{}.sram", game)} else {format!("{}.eeprom", game)};
+                Ok(Target::Cartridge {path, size, sram})
+            }
+        }
+    }
+
+    /// Returns the file WonderCrab keeps the save in and its size
+    fn file(&self) -> (&str, usize) {
+        match self {
+            Target::Cartridge {path, size, ..} => (path, *size),
+            Target::Ieeprom {path, size} => (path, *size),
+        }
+    }
+}
+
+/// Converts a save file of another emulator into WonderCrab's, replacing the current save
+///
+/// Any journal left over from a crash is removed along with the SRAM it applied to.
+pub fn import(format: SaveFormat, target: &str, file: &str) -> Result<String, String> {
+    let target = Target::parse(target)?;
+    if matches!(target, Target::Ieeprom {..}) && !format.keeps_ieeprom() {
+        return Err(format!("{} does not keep the internal EEPROM in a file", format.name()));
+    }
+    let data = fs::read(file).map_err(|e| format!("Could not read {}: {}", file, e))?;
+    let (path, size) = target.file();
+    let save = fit(&data, size)?;
+    fs::write(path, save).map_err(|e| format!("Could not write {}: {}", path, e))?;
+    if let Target::Cartridge {sram: true, ..} = target {
+        let _ = fs::remove_file(journal::journal_path(path));
+    }
+    Ok(format!("Imported {} bytes from {} into {}", data.len(), file, path))
+}
+
+/// Writes WonderCrab's save in the format of another emulator
+///
+/// SRAM changes still in the journal are included, as they are when the game is loaded.
+pub fn export(format: SaveFormat, target: &str, file: &str) -> Result<String, String> {
+    let target = Target::parse(target)?;
+    if matches!(target, Target::Ieeprom {..}) && !format.keeps_ieeprom() {
+        return Err(format!("{} does not keep the internal EEPROM in a file", format.name()));
+    }
+    let (path, size) = target.file();
+    let mut save = fit(&fs::read(path).map_err(|e| format!("Could not read {}: {}", path, e))?, size)?;
+    if let Target::Cartridge {sram: true, ..} = target {journal::replay_file(path, &mut save)}
+    fs::write(file, &save).map_err(|e| format!("Could not write {}: {}", file, e))?;
+    Ok(format!("Exported {} to {} for {}", path, file, format.name()))
+}
+
+/// Runs the save subcommand
+pub fn run(args: &[String]) -> Result<(), String> {
+    let [direction, format, target, file] = args else {return Err(USAGE.to_string())};
+    let format = SaveFormat::from_name(format).ok_or(format!("Unknown format {}, expected mednafen, ares or oswan", format))?;
+    let message = match direction.as_str() {
+        "import" => import(format, target, file)?,
+        "export" => export(format, target, file)?,
+        _ => return Err(USAGE.to_string()),
+    };
+    println!("{}", message);
+    Ok(())
+}
+
+#[cfg(test)]
+#[cfg_attr(coverage_nightly, coverage(off))]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_fit() {
+        assert_eq!(fit(&[1, 2], 4), Ok(vec![1, 2, 0, 0]));
+        // A 32KB save rounded up to 64KB with blank bytes
+        let mut rounded = vec![0x12; 0x8000];
+        rounded.resize(0x10000, 0xFF);
+        assert_eq!(fit(&rounded, 0x8000), Ok(vec![0x12; 0x8000]));
+        assert!(fit(&[1, 2, 3, 4], 2).is_err());
+    }
+
+    #[test]
+    fn test_cartridge_round_trip() {
+        let dir = std::env::temp_dir().join(format!("wondercrab-saves-{}", std::process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        let game = dir.join("game").to_str().unwrap().to_string();
+        let file = dir.join("game.sav").to_str().unwrap().to_string();
+        // A ROM whose footer declares 32KB of SRAM
+        let mut rom = vec![0; 0x10000];
+        rom[0xFFFF - 0xF + 0xB] = 0x01;
+        fs::write(format!("{}.ws", game), rom).unwrap();
+
+        let mut foreign = vec![0x56; 0x8000];
+        foreign.resize(0x10000, 0x00);
+        fs::write(&file, &foreign).unwrap();
+        fs::write(journal::journal_path(&format!("{}.sram", game)), [0xFF]).unwrap();
+        import(SaveFormat::Oswan, &game, &file).unwrap();
+        assert_eq!(fs::read(format!("{}.sram", game)).unwrap(), vec![0x56; 0x8000]);
+        assert!(!journal::journal_path(&format!("{}.sram", game)).exists());
+
+        fs::remove_file(&file).unwrap();
+        export(SaveFormat::Mednafen, &game, &file).unwrap();
+        assert_eq!(fs::read(&file).unwrap(), vec![0x56; 0x8000]);
+
+        assert!(import(SaveFormat::Mednafen, "ws.ieeprom", &file).is_err());
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}
@@ -1,6 +1,14 @@
-use std::{cell::RefCell, rc::Rc, sync::{Arc, Mutex}};
+use alloc::{boxed::Box, collections::VecDeque, rc::Rc, string::String, vec::Vec};
+use core::cell::RefCell;
+#[cfg(feature = "std")]
+use std::io;
 
-use crate::{bus::{io_bus::{IOBus, IOBusConnection}, mem_bus::{MemBus, MemBusConnection, Owner}}, cartridge::{Cartridge, Mapper}, cpu::v30mz::V30MZ, display::display_control::Display, dma::{gdma::GDMA, sdma::SDMA, DMA}, sound::Sound};
+use frame::{FastPaths, FrameStats};
+use profiler::{Profiler, Subsystem};
+
+use crate::{bus::{io_bus::{serial::SerialPeer, IOBus, IOBusConnection}, mem_bus::{AccessHeat, MemBus, MemBusConnection, Owner}}, cartridge::{header::SaveType, rtc::{Rtc, TimeSource}, Cartridge, Mapper}, cpu::{stats::OpcodeStats, v30mz::V30MZ}, display::display_control::Display, dma::{gdma::GDMA, sdma::SDMA, DMA}, options::{EmulatorOptions, SharedOptions, CPU_CLOCKS}, romdb::Quirks, sound::{buffer::{SampleBuffer, SharedSamples}, scope::Scope, Sound}};
+#[cfg(feature = "std")]
+use crate::sound::capture::AudioCapture;
 
 /// System on a chip
 /// 
@@ -26,17 +34,47 @@ pub struct SoC {
     cycles: usize,
 
     /// The vector shared with the audio thread
-    pub(super) samples: Arc<Mutex<Vec<(u16, u16)>>>,
+    pub(super) samples: SharedSamples,
     /// A counter for how many cycles there have been since the last sample was pushed
     sample_acc: u64,
     /// A counter for how many cycles have been pushed since the SDMA last operated
     sdma_clock: u8,
+    /// Capture of every sample taken, tapped before the front-end adapts them to the playback speed
+    #[cfg(feature = "std")]
+    audio_capture: Option<AudioCapture>,
 
     /// The LCD shared with the display chip and SDL
     lcd: Rc<RefCell<[u8; 3 * 224 * 144]>>,
 
-    /// Mute flag, if set will stop the SoC from pushing samples
-    pub(super) mute: bool,
+    /// Percents of a CPU tick owed, the CPU is ticked once for every 100
+    cpu_clock_acc: u16,
+
+    /// Options shared with the front-end
+    options: SharedOptions,
+    /// Effective options as of the last time they were applied, read by the SoC on every tick instead of locking the shared ones
+    applied: EmulatorOptions,
+    /// Generation of the options that were last applied
+    options_generation: usize,
+
+    /// Time spent in each subsystem, only measured with the profiling feature
+    profiler: Profiler,
+
+    /// Amount of frames finished so far
+    frames: u64,
+    /// Counters of the frame being emulated
+    frame_stats: FrameStats,
+    /// Counters of the last finished frame
+    last_frame: FrameStats,
+    /// Accesses to each page of the address space during the last finished frame
+    last_heat: AccessHeat,
+    /// Watchpoint hits not yet taken by the debugger
+    watch_reports: Vec<debugger::WatchReport>,
+    /// Port trigger hits not yet taken by the debugger
+    port_reports: Vec<debugger::PortReport>,
+    /// Transfers the DMAs recently started and completed
+    dma_events: VecDeque<dma_trace::DmaEvent>,
+    /// Memory predicates checked at the end of every frame, and the events they reported
+    hooks: hooks::Hooks,
 }
 
 impl MemBusConnection for SoC {
@@ -57,21 +95,27 @@ impl IOBusConnection for SoC {
     fn write_io(&mut self, addr: u16, byte: u8) {
         self.io_bus.borrow_mut().write_io(addr, byte);
     }
+
+    fn write_io_16(&mut self, addr: u16, word: u16) {
+        self.io_bus.borrow_mut().write_io_16(addr, word);
+    }
 }
 
 impl SoC {
     /// Generates a new SoC
     /// 
-    /// Requires data about the current ROM, CLI parameters, IEEPROM and a reference to the sample vector
-    pub fn new(color: bool, ram_content: Vec<u8>, ieeprom: Vec<u8>, eeprom: Vec<u8>, rom: Vec<u8>, mapper: Mapper, sram: bool, trace: bool, samples: Arc<Mutex<Vec<(u16, u16)>>>, mute: bool, rom_info: u8) -> Self {
+    /// Requires data about the current ROM, IEEPROM, the emulator options and a reference to the sample vector, the options deciding whether the console is a WonderSwan Color
+    pub fn new(ram_content: Vec<u8>, ieeprom: Vec<u8>, eeprom: Vec<u8>, rom: Vec<u8>, mapper: Mapper, sram: bool, samples: SharedSamples, options: SharedOptions, rom_info: u8) -> Self {
+        // Footers declaring no save memory leave SRAM empty, which is how an EEPROM found by probing on an earlier run is picked up again
+        let sram = sram && (!ram_content.is_empty() || eeprom.is_empty());
         let (cartridge, eeprom) = if sram {
             (Rc::new(RefCell::new(Cartridge::new(mapper, ram_content, rom, sram))), None)
         } else {
             (Rc::new(RefCell::new(Cartridge::new(mapper, Vec::new(), rom, false))), if eeprom.len() > 0 {Some(eeprom)} else {Some(ram_content)})
         };
-        let io_bus = Rc::new(RefCell::new(IOBus::new(Rc::clone(&cartridge), ieeprom, eeprom, color, rom_info)));
+        let io_bus = Rc::new(RefCell::new(IOBus::new(Rc::clone(&cartridge), ieeprom, eeprom, options.get().color, rom_info)));
         let mem_bus = Rc::new(RefCell::new(MemBus::new(Rc::clone(&io_bus), Rc::clone(&cartridge))));
-        let mut cpu = V30MZ::new(Rc::clone(&mem_bus), Rc::clone(&io_bus), trace);
+        let mut cpu = V30MZ::new(Rc::clone(&mem_bus), Rc::clone(&io_bus), false);
         let gdma = GDMA::new(Rc::clone(&mem_bus), Rc::clone(&io_bus));
         let sdma = SDMA::new(Rc::clone(&mem_bus), Rc::clone(&io_bus));
         let sound = Sound::new(Rc::clone(&mem_bus), Rc::clone(&io_bus));
@@ -80,32 +124,50 @@ impl SoC {
 
         cpu.reset();
 
-        Self {cpu, gdma, sdma, sound, display, mem_bus, io_bus, cycles: 0, samples, sample_acc: 0, sdma_clock: 0, lcd, mute}
+        let mut soc = Self {cpu, gdma, sdma, sound, display, mem_bus, io_bus, cycles: 0, samples, sample_acc: 0, sdma_clock: 0, #[cfg(feature = "std")] audio_capture: None, lcd, cpu_clock_acc: 0, options, applied: EmulatorOptions::new(), options_generation: 0, profiler: Profiler::default(), frames: 0, frame_stats: FrameStats::default(), last_frame: FrameStats::default(), last_heat: AccessHeat::default(), watch_reports: Vec::new(), port_reports: Vec::new(), dma_events: VecDeque::new(), hooks: hooks::Hooks::default()};
+        soc.apply_options();
+        soc
     }
 
     /// Executes four ticks of the master clock, returns true if a new frame has finished rendering
     pub fn tick(&mut self) -> bool {
+        let start = self.profiler.start();
         if self.gdma.cycles == 0 {
             if self.gdma.is_enabled() {
                 self.gdma.start_op();
+                if self.applied.accuracy.instant_dma() {
+                    while self.gdma.cycles > 0 {self.gdma.tick()}
+                    self.frame_stats.fast_paths.insert(FastPaths::INSTANT_DMA);
+                }
             }
         }
 
         if self.gdma.cycles > 0 {
             self.gdma.tick();
+            self.profiler.stop(Subsystem::Dma, start);
         } else {
             if self.sdma.cycles > 0 {
                 self.sdma.tick();
-            } else {
-                self.cpu.tick();
+                self.profiler.stop(Subsystem::Dma, start);
+            } else if self.cpu_powered() {
+                self.tick_cpu();
+                self.profiler.stop(Subsystem::Cpu, start);
             }
         };
 
+        self.record_dma_transitions();
+        if !self.mem_bus.borrow().watch_hits.is_empty() {self.report_watch_hits()}
+        if !self.io_bus.borrow().port_hits.is_empty() {self.report_port_hits()}
+        #[cfg(feature = "validate")]
+        self.check_invariants();
+
         if self.mem_bus.borrow().owner == Owner::CPU {
             return false;
         }
 
-        let sample = self.sound.tick();
+        let start = self.profiler.start();
+        // A console that is switched off stays silent
+        if !self.io_bus.borrow().powered_off() {self.sound.tick()}
         self.sample_acc += 1;
         if self.sample_acc >= 128 {
             self.sample_acc -= 128;
@@ -114,22 +176,199 @@ impl SoC {
                 self.sdma_clock = self.sdma_clock.saturating_sub(self.sdma.rate);
                 if self.sdma.is_enabled() {
                     self.sdma.start_op();
+                    if self.applied.accuracy.instant_dma() {
+                        while self.sdma.cycles > 0 {self.sdma.tick()}
+                        self.frame_stats.fast_paths.insert(FastPaths::INSTANT_DMA);
+                    }
+                    self.record_dma_transitions();
                 }
             }
-            if !self.mute {self.samples.lock().unwrap().push(sample)};
+            let sample = self.sound.take_sample();
+            self.frame_stats.samples += 1;
+            #[cfg(feature = "std")]
+            if let Some(Err(e)) = self.audio_capture.as_mut().map(|capture| capture.push(sample)) {
+                println!("Audio capture stopped: {}", e);
+                self.audio_capture = None;
+            }
+            if !self.applied.mute {self.samples.lock().unwrap().push(sample)};
         }
+        self.profiler.stop(Subsystem::Sound, start);
 
+        let start = self.profiler.start();
         self.display.tick();
+        self.profiler.stop(Subsystem::Display, start);
 
         self.cycles += 1;
 
-        if self.cycles == 40704 {
+        if self.display.frame_start() {
+            self.profiler.end_frame();
+            if self.display.scanline_rendering {self.frame_stats.fast_paths.insert(FastPaths::SCANLINE_RENDERING)}
+            self.last_frame = core::mem::take(&mut self.frame_stats);
+            self.last_heat = core::mem::take(&mut self.mem_bus.borrow_mut().heat);
+            if let Some(rtc) = &mut self.io_bus.borrow().cartridge.borrow_mut().rtc {rtc.advance(self.cycles as u64)}
             self.cycles = 0;
+            self.frames += 1;
+            if !self.hooks.is_empty() {self.check_hooks()}
+            if self.options.generation() != self.options_generation {
+                self.apply_options();
+            }
             return true;
         }
         return false;
     }
 
+    /// Ticks the CPU as many times as its clock asks for over one tick of the master clock
+    ///
+    /// At the nominal clock that is once, faster clocks tick it more often and slower ones skip ticks,
+    /// while the DMAs, sound and display keep running at the console's speed.
+    fn tick_cpu(&mut self) {
+        if self.applied.cpu_clock == 100 {
+            self.cpu.tick();
+            return;
+        }
+        self.cpu_clock_acc += self.applied.cpu_clock;
+        while self.cpu_clock_acc >= 100 {
+            self.cpu_clock_acc -= 100;
+            self.cpu.tick();
+        }
+    }
+
+    /// Returns a handle to the options used by this SoC
+    /// 
+    /// Changes made through the handle take effect at the end of the current frame.
+    pub fn options(&self) -> SharedOptions {
+        self.options.clone()
+    }
+
+    /// Replaces the options used by this SoC and applies them immediately
+    pub fn set_options(&mut self, options: SharedOptions) {
+        self.options = options;
+        self.apply_options();
+    }
+
+    /// Copies the current options into the components they concern
+    fn apply_options(&mut self) {
+        self.options_generation = self.options.generation();
+        let mut options = self.options.get().effective();
+        options.cpu_clock = options.cpu_clock.clamp(*CPU_CLOCKS.start(), *CPU_CLOCKS.end());
+        self.cpu.trace = options.trace;
+        self.cpu.invalid_opcode = options.invalid_opcode;
+        // Counts gathered so far are kept until counting is switched off
+        match (options.opcode_stats, &self.cpu.opcode_stats) {
+            (true, None) => self.cpu.opcode_stats = Some(OpcodeStats::new()),
+            (false, Some(_)) => self.cpu.opcode_stats = None,
+            _ => {}
+        }
+        self.mem_bus.borrow_mut().vram_stalls = options.vram_stalls || options.accuracy.exact_bus_timing();
+        self.display.layers = options.layers;
+        self.display.scanline_rendering = options.accuracy.scanline_rendering();
+        self.display.sprites_per_line = options.sprite_limit.sprites_per_line();
+        self.display.set_colors(options.colors);
+        self.sound.speaker_filter.configure(options.sound_profile, options.speaker);
+        self.io_bus.borrow().cartridge.borrow_mut().probe = options.probe_saves;
+        self.applied = options;
+    }
+
+    /// Starts writing every sample taken from the sound chip to a WAV file, finishing any capture in progress first
+    /// 
+    /// Captures hold the samples as emulated, whether the SoC is muted and whatever the speed the front-end plays them at.
+    #[cfg(feature = "std")]
+    pub fn start_audio_capture(&mut self, path: &str) -> io::Result<()> {
+        self.stop_audio_capture()?;
+        self.audio_capture = Some(AudioCapture::create(path)?);
+        Ok(())
+    }
+
+    /// Finishes the capture in progress, returns how many samples it holds or `None` if nothing was being captured
+    #[cfg(feature = "std")]
+    pub fn stop_audio_capture(&mut self) -> io::Result<Option<u32>> {
+        let Some(capture) = self.audio_capture.take() else {return Ok(None)};
+        let samples = capture.samples();
+        capture.finish()?;
+        Ok(Some(samples))
+    }
+
+    /// Starts recording a binary trace of every instruction the CPU executes to a file, finishing any trace in progress first
+    ///
+    /// See [`TraceWriter`](crate::cpu::trace::TraceWriter) for the format, which takes a few bytes per instruction so that traces of several minutes stay manageable.
+    #[cfg(feature = "std")]
+    pub fn start_binary_trace(&mut self, path: &str) -> io::Result<()> {
+        self.stop_binary_trace()?;
+        let file: Box<dyn io::Write> = Box::new(io::BufWriter::new(std::fs::File::create(path)?));
+        self.cpu.binary_trace = Some(crate::cpu::trace::TraceWriter::new(file)?);
+        Ok(())
+    }
+
+    /// Finishes the binary trace in progress, returns how many instructions it holds or `None` if nothing was being traced
+    #[cfg(feature = "std")]
+    pub fn stop_binary_trace(&mut self) -> io::Result<Option<u64>> {
+        let Some(trace) = self.cpu.binary_trace.take() else {return Ok(None)};
+        let (_, steps) = trace.finish()?;
+        Ok(Some(steps))
+    }
+
+    /// Starts or stops recording the output of each sound channel for the oscilloscope, which is cleared when stopped
+    pub fn set_scope(&mut self, enabled: bool) {
+        if enabled != self.sound.scope.is_some() {self.sound.scope = enabled.then(Scope::default)}
+    }
+
+    /// Returns the recent output of each sound channel, or `None` if it is not being recorded
+    pub fn scope(&self) -> Option<&Scope> {
+        self.sound.scope.as_ref()
+    }
+
+    /// Makes the cartridge's real-time clock take the time from another source, does nothing if the cartridge has no clock
+    /// 
+    /// A time the game set is kept relative to the new source, so only the time that passes changes.
+    pub fn set_time_source(&mut self, source: Box<dyn TimeSource>) {
+        if let Some(rtc) = &mut self.io_bus.borrow().cartridge.borrow_mut().rtc {rtc.set_source(source)}
+    }
+
+    /// Plugs a peer into the link port, or unplugs whatever is plugged in
+    pub fn set_serial_peer(&mut self, peer: Option<Box<dyn SerialPeer>>) {
+        self.io_bus.borrow_mut().serial.peer = peer;
+    }
+
+    /// Moves the cartridge's real-time clock forward, or backward for negative amounts of seconds
+    pub fn time_travel(&mut self, seconds: i64) {
+        if let Some(rtc) = &mut self.io_bus.borrow().cartridge.borrow_mut().rtc {rtc.time_travel(seconds)}
+    }
+
+    /// Applies the quirks the ROM database or the game's overrides list, see [`Quirks`]
+    ///
+    /// Only the real-time clock is fitted to the cartridge here, the EEPROM size is taken into account through [`crate::save_layout`]
+    /// before building the SoC and the orientation is up to the front-end.
+    pub fn apply_quirks(&mut self, quirks: Quirks) {
+        if quirks.rtc {self.io_bus.borrow().cartridge.borrow_mut().fit_rtc()}
+    }
+
+    /// Restores the time the game set on the cartridge's real-time clock on a previous run, see [`Rtc::battery`]
+    ///
+    /// Returns whether it could, which it cannot if the cartridge has no clock or the data is not the clock's.
+    pub fn restore_rtc(&mut self, data: &[u8]) -> bool {
+        self.io_bus.borrow().cartridge.borrow_mut().rtc.as_mut().is_some_and(|rtc| rtc.restore_battery(data))
+    }
+
+    /// Returns the time the game reads from the cartridge's real-time clock, or `None` if it has none
+    pub fn rtc_time(&self) -> Option<i64> {
+        self.io_bus.borrow().cartridge.borrow().rtc.as_ref().map(Rtc::now)
+    }
+
+    /// Returns the report of the instructions executed so far, or `None` if they are not being counted
+    pub fn opcode_report(&self) -> Option<String> {
+        self.cpu.opcode_stats.as_ref().map(OpcodeStats::report)
+    }
+
+    /// Makes the display draw whole scanlines at once or dot by dot regardless of the accuracy tier, until the options change
+    pub fn set_scanline_rendering(&mut self, enabled: bool) {
+        self.display.scanline_rendering = enabled;
+    }
+
+    /// Returns the save media allocated because the game wrote to them although its footer declared none, see [`EmulatorOptions::probe_saves`]
+    pub fn probed_save(&self) -> Option<SaveType> {
+        self.io_bus.borrow().cartridge.borrow().probed
+    }
+
     /// Returns the LCD screen to main
     pub fn get_lcd(&mut self) -> Rc<RefCell<[u8; 3 * 224 * 144]>> {
         Rc::clone(&self.lcd)
@@ -153,10 +392,46 @@ impl SoC {
         io_bus.borrow_mut().write_io(0x00, 0xFF);
         io_bus.borrow_mut().write_io(0x1F, 0xF8);
 
-        Self {cpu, gdma, sdma, sound, mem_bus, io_bus, display, cycles: 0, samples: Arc::new(Mutex::new(Vec::new())), sample_acc: 0, sdma_clock: 0, lcd, mute: true}
+        let options = SharedOptions::new(EmulatorOptions {mute: true, ..EmulatorOptions::new()});
+        Self {cpu, gdma, sdma, sound, mem_bus, io_bus, display, cycles: 0, samples: SampleBuffer::shared(), sample_acc: 0, sdma_clock: 0, #[cfg(feature = "std")] audio_capture: None, lcd, cpu_clock_acc: 0, options, applied: EmulatorOptions {mute: true, ..EmulatorOptions::new()}, options_generation: 0, profiler: Profiler::default(), frames: 0, frame_stats: FrameStats::default(), last_frame: FrameStats::default(), last_heat: AccessHeat::default(), watch_reports: Vec::new(), port_reports: Vec::new(), dma_events: VecDeque::new(), hooks: hooks::Hooks::default()}
     }
 }
 
+/// Diagnostic bundles for bug reports
+pub mod diagnostics;
+
+/// Debugger commands that run the SoC until something happens
+pub mod debugger;
+
+/// Frame stepping with a report of what happened during each frame
+pub mod frame;
+
+/// Per subsystem performance counters, only measured with the profiling feature
+pub mod profiler;
+
+/// Desync detection for movies and netplay
+pub mod desync;
+/// Save states of the whole console
+pub mod snapshot;
+/// Detection of games that crashed into a state the CPU cannot leave
+pub mod lockup;
+/// Sleep and power off, as requested by games through the power ports
+pub mod power;
+/// Checks of the bits registers always keep, run after every tick with the validate feature
+pub mod invariants;
+/// Inspection of the DMAs' registers and of the transfers they start and complete
+pub mod dma_trace;
+/// Logging of every access to the bus, exported for waveform viewers
+pub mod bus_log;
+/// Snapshots of video memory and the changes between them, for graphics debugging
+pub mod vram;
+/// Hex dumps and disassembly of the ROM segments visible through the current banks
+pub mod rom_window;
+/// The cartridge's bank ports as the debugger shows and edits them
+pub mod banks;
+/// Memory predicates checked at the end of every frame, for achievements and auto-splitters
+pub mod hooks;
+
 #[cfg(test)]
 #[cfg_attr(coverage_nightly, coverage(off))]
 pub mod test;
\ No newline at end of file
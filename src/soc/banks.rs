@@ -0,0 +1,105 @@
+use alloc::{format, string::String};
+use core::fmt::Write;
+
+use crate::{bus::io_bus::IOBusConnection, cartridge::Mapper};
+
+use super::SoC;
+
+/// The cartridge's bank ports and their names, the ports from 0xCF on only exist on the 2003 mapper
+///
+/// 0xC0 to 0xC3 are the banks of the 2001 mapper, which the 2003 keeps for compatibility.
+/// 0xD0 to 0xD5 shadow the low bytes of 0xC1 to 0xC3 and add the high bytes of the 2003's 16-bit banks, while 0xCF shadows 0xC0.
+pub const BANK_PORTS: [(u8, &str); 11] = [
+    (0xC0, "LINEAR_ADDR_OFF"), (0xC1, "RAM_BANK"), (0xC2, "ROM_BANK_0"), (0xC3, "ROM_BANK_1"),
+    (0xCF, "LINEAR_ADDR_OFF_SHADOW"),
+    (0xD0, "RAM_BANK_L"), (0xD1, "RAM_BANK_H"), (0xD2, "ROM_BANK_0_L"), (0xD3, "ROM_BANK_0_H"), (0xD4, "ROM_BANK_1_L"), (0xD5, "ROM_BANK_1_H"),
+];
+
+/// Returns the bank port given by its number in hex or its name, in any case
+pub fn parse_bank_port(word: &str) -> Result<u8, String> {
+    BANK_PORTS.iter()
+        .find(|(port, name)| name.eq_ignore_ascii_case(word) || u8::from_str_radix(word.trim_start_matches("0x"), 16) == Ok(*port))
+        .map(|(port, _)| *port)
+        .ok_or(format!("Not a bank port, expected C0-C3, CF, D0-D5 or their names: {}", word))
+}
+
+impl SoC {
+    /// Lists the bank ports as the CPU reads them, followed by where each window of the cartridge is mapped
+    ///
+    /// Ports the mapper does not have read as open bus, just as they do for the CPU.
+    pub fn bank_report(&self) -> String {
+        let mut io_bus = self.io_bus.borrow_mut();
+        let mapper = io_bus.cartridge.borrow().mapper();
+        let mut report = format!("{:?} mapper\n", mapper);
+        for (port, name) in BANK_PORTS {
+            if mapper == Mapper::B_2001 && port >= 0xCF {continue}
+            writeln!(report, "{:02X} {:<22} {:02X}", port, name, io_bus.read_io(port as u16)).unwrap();
+        }
+
+        let cartridge = io_bus.cartridge.borrow();
+        for (window, addr) in [("ROM bank 0", 0x20000), ("ROM bank 1", 0x30000), ("linear window", 0x40000)] {
+            let start = cartridge.rom_offset(addr).unwrap_or_default();
+            writeln!(report, "{:05X} {} -> ROM {:06X}", addr, window, start).unwrap();
+        }
+        match cartridge.sram_offset(0x10000) {
+            Some(start) => write!(report, "10000 RAM bank -> SRAM {:06X}", start).unwrap(),
+            None => write!(report, "10000 RAM bank -> open bus").unwrap(),
+        }
+        report
+    }
+
+    /// Writes one of the bank ports through the I/O bus, as an OUT instruction would
+    ///
+    /// The write goes through the same path as the CPU's so that the mapper masks and ignores it the same way,
+    /// only port triggers are not hit since the CPU did not make it.
+    pub fn write_bank(&mut self, port: u8, byte: u8) -> Result<(), String> {
+        if !BANK_PORTS.iter().any(|(bank_port, _)| *bank_port == port) {return Err(format!("Not a bank port: {:02X}", port))}
+        let mut io_bus = self.io_bus.borrow_mut();
+        let hits = io_bus.port_hits.len();
+        io_bus.write_io(port as u16, byte);
+        io_bus.port_hits.truncate(hits);
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+#[cfg_attr(coverage_nightly, coverage(off))]
+mod test {
+    use alloc::{vec, vec::Vec};
+
+    use crate::{bus::{io_bus::PortTrigger, mem_bus::MemBusConnection}, options::{EmulatorOptions, SharedOptions}, sound::buffer::SampleBuffer};
+
+    use super::*;
+
+    #[test]
+    fn test_write_bank() {
+        let mut soc = SoC::test_build();
+        soc.watch_port(PortTrigger {port: 0xC2, mask: 0, value: 0});
+        soc.write_bank(0xC2, 0x03).unwrap();
+        assert!(soc.io_bus.borrow().port_hits.is_empty());
+        assert!(soc.write_bank(0xC8, 0x00).is_err());
+
+        // The linear offset only keeps 6 bits, and the 2001 mapper does not have the shadow registers
+        soc.write_bank(parse_bank_port("linear_addr_off").unwrap(), 0xFF).unwrap();
+        soc.write_bank(0xD3, 0x01).unwrap();
+        let report = soc.bank_report();
+        assert!(report.contains("C0 LINEAR_ADDR_OFF        3F"));
+        assert!(report.contains("C2 ROM_BANK_0             03"));
+        assert!(!report.contains("D3"));
+        assert!(report.contains("20000 ROM bank 0 -> ROM 030000"));
+        assert_eq!(soc.io_bus.borrow().cartridge.borrow().bank_registers()[3], ("ROM_BANK_0_H", 0xFF));
+    }
+
+    #[test]
+    fn test_2003_banks() {
+        let rom: Vec<u8> = (0..0x400000u32).map(|offset| (offset >> 16) as u8).collect();
+        let mut soc = SoC::new(vec![0; 0x8000], Vec::new(), Vec::new(), rom, Mapper::B_2003, true, SampleBuffer::shared(), SharedOptions::new(EmulatorOptions::new()), 0);
+        soc.write_bank(0xD4, 0x21).unwrap();
+        soc.write_bank(0xD5, 0x00).unwrap();
+        assert_eq!(soc.mem_bus.borrow_mut().read_mem(0x30000), 0x21);
+        let report = soc.bank_report();
+        assert!(report.contains("C3 ROM_BANK_1             21"));
+        assert!(report.contains("D5 ROM_BANK_1_H           00"));
+        assert!(report.contains("30000 ROM bank 1 -> ROM 210000"));
+    }
+}
@@ -0,0 +1,116 @@
+use alloc::{collections::BTreeMap, format, string::{String, ToString}, vec::Vec};
+use core::fmt::Write;
+
+use crate::bus::mem_bus::{Accessor, BusLog};
+
+use super::SoC;
+
+/// Most ticks a single log covers, about 5 frames, as every tick adds a few accesses
+pub const MAX_LOG_TICKS: usize = 200_000;
+/// Length of a tick in picoseconds, the SoC being clocked at 3.072 MHz
+const TICK_PS: u64 = 325_521;
+
+impl SoC {
+    /// Runs for the given amount of ticks while recording every access to the bus
+    pub fn log_bus(&mut self, ticks: usize) -> BusLog {
+        self.mem_bus.borrow_mut().bus_log = Some(BusLog::default());
+        for _ in 0..ticks.min(MAX_LOG_TICKS) {
+            let tick = self.frames * self.display.timing().frame_ticks() as u64 + self.cycles as u64;
+            if let Some(log) = &mut self.mem_bus.borrow_mut().bus_log {log.tick = tick}
+            self.tick();
+        }
+        self.mem_bus.borrow_mut().bus_log.take().unwrap_or_default()
+    }
+}
+
+impl BusLog {
+    /// Lists the accesses as comma separated values, one per line
+    pub fn csv(&self) -> String {
+        let mut csv = "tick,accessor,access,address,value\n".to_string();
+        for transaction in &self.transactions {
+            let access = if transaction.write {"W"} else {"R"};
+            writeln!(csv, "{},{:?},{},{:05X},{:02X}", transaction.tick, transaction.accessor, access, transaction.addr, transaction.value).unwrap();
+        }
+        csv
+    }
+
+    /// Describes the accesses as a value change dump, which waveform viewers such as GTKWave open
+    ///
+    /// Each component gets its own address, data, read and write signals, the strobes being raised for half of an access.
+    /// Accesses made during the same tick are spread evenly across it, in the order they were made,
+    /// and the time starts from the first tick logged.
+    pub fn vcd(&self) -> String {
+        let mut vcd = "$version WonderCrab $end\n$timescale 1 ps $end\n$scope module bus $end\n".to_string();
+        // Identifiers of each component's address, data, read and write signals
+        let ids = |accessor: Accessor| {
+            let index = Accessor::ALL.iter().position(|other| *other == accessor).unwrap() as u8;
+            core::array::from_fn::<char, 4, _>(|signal| (b'!' + index * 4 + signal as u8) as char)
+        };
+        for accessor in Accessor::ALL {
+            let [addr, data, read, write] = ids(accessor);
+            writeln!(vcd, "$scope module {} $end", format!("{:?}", accessor).to_lowercase()).unwrap();
+            writeln!(vcd, "$var wire 20 {} addr $end\n$var wire 8 {} data $end\n$var wire 1 {} read $end\n$var wire 1 {} write $end", addr, data, read, write).unwrap();
+            vcd += "$upscope $end\n";
+        }
+        vcd += "$upscope $end\n$enddefinitions $end\n#0\n$dumpvars\n";
+        for accessor in Accessor::ALL {
+            let [addr, data, read, write] = ids(accessor);
+            writeln!(vcd, "bx {}\nbx {}\n0{}\n0{}", addr, data, read, write).unwrap();
+        }
+        vcd += "$end\n";
+
+        let first = self.transactions.first().map_or(0, |transaction| transaction.tick);
+        let mut changes: BTreeMap<u64, Vec<String>> = BTreeMap::new();
+        for tick in self.transactions.chunk_by(|a, b| a.tick == b.tick) {
+            let slot = TICK_PS / tick.len() as u64;
+            for (i, transaction) in tick.iter().enumerate() {
+                let [addr, data, read, write] = ids(transaction.accessor);
+                let strobe = if transaction.write {write} else {read};
+                let time = (transaction.tick - first) * TICK_PS + i as u64 * slot;
+                changes.entry(time).or_default().extend([format!("b{:b} {}", transaction.addr, addr), format!("b{:b} {}", transaction.value, data), format!("1{}", strobe)]);
+                changes.entry(time + (slot / 2).max(1)).or_default().push(format!("0{}", strobe));
+            }
+        }
+        for (time, values) in changes {
+            writeln!(vcd, "#{}", time).unwrap();
+            for value in values {writeln!(vcd, "{}", value).unwrap()}
+        }
+        vcd
+    }
+}
+
+#[cfg(test)]
+#[cfg_attr(coverage_nightly, coverage(off))]
+mod test {
+    use alloc::vec;
+
+    use super::*;
+
+    #[test]
+    fn test_bus_log() {
+        let mut soc = SoC::test_build();
+        soc.set_wram(vec![
+            0xB0, 0x34,             // MOV AL, 0x34
+            0x88, 0x06, 0x00, 0x20, // MOV [0x2000], AL
+        ]);
+        let log = soc.log_bus(20);
+        assert!(soc.mem_bus.borrow().bus_log.is_none());
+
+        let write = log.transactions.iter().find(|transaction| transaction.write && transaction.accessor == Accessor::CPU).unwrap();
+        assert_eq!((write.addr, write.value), (0x2000, 0x34));
+        assert!(log.transactions.iter().any(|transaction| transaction.accessor == Accessor::DISPLAY));
+        assert!(log.transactions.is_sorted_by_key(|transaction| transaction.tick));
+
+        let csv = log.csv();
+        assert_eq!(csv.lines().count(), log.transactions.len() + 1);
+        assert!(csv.contains(",CPU,W,02000,34\n"));
+
+        let vcd = log.vcd();
+        assert!(vcd.contains("$scope module cpu $end\n$var wire 20 ! addr $end"));
+        assert!(vcd.contains("b10000000000000 !\nb110100 \"\n1$\n"));
+        // Every access raises and lowers a strobe at times of their own
+        let times: Vec<u64> = vcd.lines().filter_map(|line| line.strip_prefix('#')?.parse().ok()).collect();
+        assert!(times.is_sorted());
+        assert_eq!(times.len(), 1 + 2 * log.transactions.len());
+    }
+}
@@ -0,0 +1,534 @@
+use alloc::{format, string::String, vec::Vec};
+use core::fmt::Display;
+#[cfg(feature = "std")]
+use std::io::{self, BufRead, Write};
+
+use crate::{bus::{io_bus::{PortHit, PortTrigger}, mem_bus::{Accessor, WatchHit, Watchpoint}}, cpu::v30mz::{CpuAccess, V30MZ}};
+#[cfg(feature = "std")]
+use crate::{display::raster, options::{EmulatorOptions, SharedOptions}, parse_rom, sound::buffer::SampleBuffer};
+
+use super::{banks::parse_bank_port, bus_log::MAX_LOG_TICKS, rom_window::DEFAULT_SEGMENTS, SoC};
+#[cfg(feature = "std")]
+use super::vram::{VramDiff, VramSnapshot};
+
+/// Most frames a single command runs for before giving up, so that targets that are never reached do not hang the debugger
+#[cfg(feature = "std")]
+const MAX_FRAMES: usize = 600;
+/// First scanline of vblank
+const VBLANK_LINE: u8 = 144;
+
+/// Where a run command stops
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum Target {
+    /// The next time the CPU dispatches a hardware interrupt
+    Interrupt,
+    /// The next time the display reaches a scanline and dot
+    Scanline(u8, u8),
+    /// The start of the next vblank
+    Vblank,
+}
+
+/// A command typed into the debugger
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum DebugCommand {
+    /// Runs until the target is reached
+    Run(Target),
+    /// Adds a watchpoint
+    Watch(Watchpoint),
+    /// Adds a port trigger
+    Port(PortTrigger),
+    /// Removes every watchpoint and port trigger
+    Unwatch,
+    /// Prints the CPU's registers
+    Registers,
+    /// Prints the DMAs' registers and recent transfers
+    Dma,
+    /// Takes a snapshot of video memory
+    Snap,
+    /// Compares the last two snapshots of video memory
+    Diff,
+    /// Prints the registers used for each line of the last frame
+    Raster,
+    /// Runs for some ticks while logging every access to the bus
+    Log(usize),
+    /// Exports the ROM segments whose bits are set as a hex dump and disassembly, with colors if set
+    Rom(u16, bool),
+    /// Prints the cartridge's bank ports, after writing a byte to one of them if given
+    Bank(Option<(u8, u8)>),
+    /// Lists the commands
+    Help,
+    /// Leaves the debugger
+    Quit,
+}
+
+impl DebugCommand {
+    /// Parses a line of input
+    ///
+    /// | Command             | Action                                        |
+    /// |---------------------|-----------------------------------------------|
+    /// | `int`               | Runs until the next interrupt is dispatched   |
+    /// | `line <n> [dot]`    | Runs until scanline n, at dot 0 unless given  |
+    /// | `vblank`            | Runs until the start of the next vblank       |
+    /// | `watch <a>[-b] [rw]`| Stops runs at reads and/or writes of a range  |
+    /// | `port <p> [v[/m]]`  | Stops runs at writes to a port, of a value    |
+    /// | `unwatch`           | Removes every watchpoint and port trigger     |
+    /// | `regs`              | Prints the CPU's registers                    |
+    /// | `dma`               | Prints the DMAs' state and recent transfers   |
+    /// | `snap`              | Takes a snapshot of video memory              |
+    /// | `diff`              | Compares the last two snapshots, with a PNG   |
+    /// | `raster`            | Prints each line's scroll, layers and palettes|
+    /// | `log <ticks>`       | Runs while logging the bus to VCD and CSV     |
+    /// | `rom [seg] [color]` | Dumps and disassembles the banked ROM         |
+    /// | `bank [port byte]`  | Prints the bank ports, writing one if given   |
+    /// | `help`              | Lists the commands                            |
+    /// | `quit`              | Leaves the debugger                           |
+    pub fn parse(line: &str) -> Result<Self, String> {
+        let mut words = line.split_whitespace();
+        let number = |word: Option<&str>, name: &str| -> Result<Option<u8>, String> {
+            word.map(|word| word.parse().map_err(|_| format!("Invalid {}: {}", name, word))).transpose()
+        };
+        let command = match words.next().unwrap_or("") {
+            "int" | "i" => Self::Run(Target::Interrupt),
+            "line" | "l" => {
+                let line = number(words.next(), "scanline")?.ok_or("Missing scanline")?;
+                let dot = number(words.next(), "dot")?.unwrap_or(0);
+                Self::Run(Target::Scanline(line, dot))
+            }
+            "vblank" | "v" => Self::Run(Target::Vblank),
+            "watch" | "w" => {
+                let range = words.next().ok_or("Missing address")?;
+                let address = |word: &str| u32::from_str_radix(word.trim_start_matches("0x"), 16).ok().filter(|addr| *addr <= 0xFFFFF)
+                    .ok_or(format!("Invalid address: {}", word));
+                let (start, end) = match range.split_once('-') {
+                    Some((start, end)) => (address(start)?, address(end)?),
+                    None => (address(range)?, address(range)?),
+                };
+                if end < start {return Err(format!("Invalid range: {}", range))}
+                let (reads, writes) = match words.next().unwrap_or("rw") {
+                    "r" => (true, false),
+                    "w" => (false, true),
+                    "rw" => (true, true),
+                    access => return Err(format!("Invalid access, expected r, w or rw: {}", access)),
+                };
+                Self::Watch(Watchpoint {start, end, reads, writes})
+            }
+            "port" | "p" => {
+                let byte = |word: &str, name: &str| u8::from_str_radix(word.trim_start_matches("0x"), 16).map_err(|_| format!("Invalid {}: {}", name, word));
+                let port = byte(words.next().ok_or("Missing port")?, "port")?;
+                let (value, mask) = match words.next().map(|word| word.split_once('/').unwrap_or((word, "FF"))) {
+                    Some((value, mask)) => (byte(value, "value")?, byte(mask, "mask")?),
+                    None => (0, 0),
+                };
+                Self::Port(PortTrigger {port, mask, value})
+            }
+            "unwatch" => Self::Unwatch,
+            "regs" | "r" => Self::Registers,
+            "dma" => Self::Dma,
+            "snap" | "s" => Self::Snap,
+            "diff" | "d" => Self::Diff,
+            "raster" | "t" => Self::Raster,
+            "log" => {
+                let ticks = words.next().ok_or("Missing tick count")?;
+                let ticks = ticks.parse().ok().filter(|ticks| (1..=MAX_LOG_TICKS).contains(ticks))
+                    .ok_or(format!("Invalid tick count, at most {}: {}", MAX_LOG_TICKS, ticks))?;
+                Self::Log(ticks)
+            }
+            "rom" => {
+                let (mut segments, mut color) = (0u16, false);
+                for word in words.by_ref() {
+                    match (word, u8::from_str_radix(word, 16)) {
+                        ("color", _) => color = true,
+                        (_, Ok(segment @ 0x2..=0xF)) => segments |= 1 << segment,
+                        _ => return Err(format!("Invalid ROM segment, expected 2 to F: {}", word)),
+                    }
+                }
+                if segments == 0 {segments = DEFAULT_SEGMENTS.iter().fold(0, |segments, segment| segments | 1 << segment)}
+                Self::Rom(segments, color)
+            }
+            "bank" | "b" => match words.next() {
+                Some(port) => {
+                    let port = parse_bank_port(port)?;
+                    let byte = words.next().ok_or("Missing byte")?;
+                    let byte = u8::from_str_radix(byte.trim_start_matches("0x"), 16).map_err(|_| format!("Invalid byte: {}", byte))?;
+                    Self::Bank(Some((port, byte)))
+                }
+                None => Self::Bank(None),
+            },
+            "help" | "h" | "" => Self::Help,
+            "quit" | "q" => Self::Quit,
+            command => return Err(format!("Unknown command: {}", command)),
+        };
+        match words.next() {
+            Some(extra) => Err(format!("Unexpected argument: {}", extra)),
+            None => Ok(command),
+        }
+    }
+}
+
+/// A watchpoint hit, along with what made the access
+#[derive(Clone, Copy, Debug)]
+pub struct WatchReport {
+    /// The access itself
+    pub hit: WatchHit,
+    /// What the CPU was doing, for accesses made by it
+    pub cpu: Option<CpuAccess>,
+    /// Scanline and dot of the display when the access was made
+    pub position: (u8, u8),
+}
+
+impl Display for WatchReport {
+    fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
+        let hit = self.hit;
+        let access = if hit.write {format!("write {:05X} {:02X} -> {:02X}", hit.addr, hit.before, hit.after)} else {format!("read {:05X} = {:02X}", hit.addr, hit.after)};
+        write!(f, "{} by {:?} on line {} dot {}", access, hit.accessor, self.position.0, self.position.1)?;
+        match self.cpu {
+            Some(cpu) => write_cpu_access(f, cpu),
+            None => Ok(()),
+        }
+    }
+}
+
+/// A port trigger hit, along with the instruction that wrote the port
+#[derive(Clone, Copy, Debug)]
+pub struct PortReport {
+    /// The write itself
+    pub hit: PortHit,
+    /// What the CPU was doing, only the CPU writes to ports
+    pub cpu: CpuAccess,
+    /// Scanline and dot of the display when the write was made
+    pub position: (u8, u8),
+}
+
+impl Display for PortReport {
+    fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
+        write!(f, "write port {:02X} <- {:02X} on line {} dot {}", self.hit.port, self.hit.byte, self.position.0, self.position.1)?;
+        write_cpu_access(f, self.cpu)
+    }
+}
+
+/// Writes the instruction the CPU was executing during an access, or the exception it was raising, on lines of their own
+fn write_cpu_access(f: &mut core::fmt::Formatter<'_>, cpu: CpuAccess) -> core::fmt::Result {
+    if let Some(vector) = cpu.exception {
+        write!(f, "\n  raising vector {:02X}", vector)?;
+    }
+    if let Some(instruction) = cpu.instruction {
+        let bytes = instruction.bytes[..instruction.len as usize].iter().map(|byte| format!("{:02X}", byte)).collect::<String>();
+        write!(f, "\n  {} {:05X}  {}  {}", if cpu.exception.is_some() {"after"} else {"at"}, instruction.address, bytes, instruction.name())?;
+    }
+    if let Some(operand) = cpu.operand {
+        write!(f, "\n  operand {}", operand)?;
+    }
+    Ok(())
+}
+
+impl SoC {
+    /// Adds a watchpoint, runs stop once an access hits it
+    ///
+    /// Reads include the CPU's instruction fetches, while the display and sound chips' fetches are never reported.
+    pub fn watch(&mut self, watchpoint: Watchpoint) {
+        self.mem_bus.borrow_mut().watchpoints.push(watchpoint);
+    }
+
+    /// Adds a port trigger, runs stop once the CPU writes a matching byte to the port
+    pub fn watch_port(&mut self, trigger: PortTrigger) {
+        self.io_bus.borrow_mut().port_triggers.push(trigger);
+    }
+
+    /// Removes every watchpoint and port trigger
+    pub fn unwatch(&mut self) {
+        self.mem_bus.borrow_mut().watchpoints.clear();
+        self.io_bus.borrow_mut().port_triggers.clear();
+    }
+
+    /// Takes the watchpoint hits reported since the last call, oldest first
+    pub fn take_watch_reports(&mut self) -> Vec<WatchReport> {
+        core::mem::take(&mut self.watch_reports)
+    }
+
+    /// Takes the port trigger hits reported since the last call, oldest first
+    pub fn take_port_reports(&mut self) -> Vec<PortReport> {
+        core::mem::take(&mut self.port_reports)
+    }
+
+    /// Turns the watchpoint hits of the current tick into reports, while the component that made them still remembers why
+    pub(super) fn report_watch_hits(&mut self) {
+        let hits = core::mem::take(&mut self.mem_bus.borrow_mut().watch_hits);
+        let position = self.display.position();
+        for hit in hits {
+            let cpu = (hit.accessor == Accessor::CPU).then(|| self.cpu.access(hit.addr));
+            self.watch_reports.push(WatchReport {hit, cpu, position});
+        }
+    }
+
+    /// Turns the port trigger hits of the current tick into reports, while the CPU still remembers the instruction that wrote them
+    pub(super) fn report_port_hits(&mut self) {
+        let hits = core::mem::take(&mut self.io_bus.borrow_mut().port_hits);
+        let position = self.display.position();
+        for hit in hits {
+            // Ports are not addresses of memory operands
+            let cpu = CpuAccess {operand: None, ..self.cpu.access(hit.port as u32)};
+            self.port_reports.push(PortReport {hit, cpu, position});
+        }
+    }
+
+    /// Runs until the target is reached or a watchpoint or port trigger is hit, ticking at least once, returns false if none happened within `max_ticks`
+    pub fn run_to(&mut self, target: Target, max_ticks: usize) -> bool {
+        let interrupts = self.cpu.interrupts;
+        let position = match target {
+            Target::Interrupt => None,
+            Target::Scanline(line, dot) => Some((line, dot)),
+            Target::Vblank => Some((VBLANK_LINE, 0)),
+        };
+
+        for _ in 0..max_ticks {
+            self.tick();
+            let reached = match position {
+                None => self.cpu.interrupts != interrupts,
+                Some(position) => self.display.position() == position,
+            };
+            if reached || !self.watch_reports.is_empty() || !self.port_reports.is_empty() {return true}
+        }
+        false
+    }
+
+    /// Describes where the SoC stopped, for the debugger
+    pub fn debug_status(&self) -> String {
+        let (line, dot) = self.display.position();
+        let registers = V30MZ::REGISTER_NAMES.iter().zip(self.cpu.registers())
+            .map(|(name, value)| format!("{}={:04X}", name, value))
+            .collect::<Vec<_>>().join(" ");
+        let interrupt = self.cpu.last_interrupt.map_or(String::new(), |vector| format!(" last interrupt={:02X}", vector));
+        format!("line {} dot {}{}\n{} PSW={:04X}", line, dot, interrupt, registers, self.cpu.psw())
+    }
+}
+
+/// Entry point of the `debug` command, reads debugger commands from standard input
+#[cfg(feature = "std")]
+pub fn run(args: &[String]) -> Result<(), String> {
+    let game = args.first().ok_or("Usage: debug <rom>")?;
+    let (color, ram_content, ieeprom, eeprom, rom, mapper, sram, rom_info, _) = parse_rom(game);
+    let options = SharedOptions::new(EmulatorOptions {color, mute: true, ..EmulatorOptions::new()});
+    let mut soc = SoC::new(ram_content, ieeprom, eeprom, rom, mapper, sram, SampleBuffer::shared(), options, rom_info);
+
+    println!("{}", soc.debug_status());
+    soc.display.raster_trace = true;
+    let mut snapshots: Vec<VramSnapshot> = Vec::new();
+    let stdin = io::stdin();
+    loop {
+        print!("> ");
+        io::stdout().flush().map_err(|e| e.to_string())?;
+        let mut line = String::new();
+        if stdin.lock().read_line(&mut line).map_err(|e| e.to_string())? == 0 {return Ok(())}
+
+        match DebugCommand::parse(&line) {
+            Ok(DebugCommand::Run(target)) => {
+                if !soc.run_to(target, MAX_FRAMES * soc.display.timing().frame_ticks()) {println!("Not reached within {} frames", MAX_FRAMES)}
+                for report in soc.take_watch_reports() {println!("{}", report)}
+                for report in soc.take_port_reports() {println!("{}", report)}
+                println!("{}", soc.debug_status());
+            }
+            Ok(DebugCommand::Watch(watchpoint)) => soc.watch(watchpoint),
+            Ok(DebugCommand::Port(trigger)) => soc.watch_port(trigger),
+            Ok(DebugCommand::Unwatch) => soc.unwatch(),
+            Ok(DebugCommand::Registers) => println!("{}", soc.debug_status()),
+            Ok(DebugCommand::Dma) => println!("{}", soc.dma_report()),
+            Ok(DebugCommand::Snap) => {
+                snapshots = snapshots.into_iter().last().into_iter().chain([soc.vram_snapshot()]).collect();
+                println!("VRAM snapshot taken on frame {}", soc.frames);
+            }
+            Ok(DebugCommand::Diff) => match &snapshots[..] {
+                [before, after] => {
+                    let diff = VramDiff::between(before.clone(), after.clone());
+                    print!("{}", diff.report());
+                    let path = format!("{}.vram-{}-{}.png", game, before.frame, after.frame);
+                    std::fs::write(&path, diff.png()).map_err(|e| format!("Could not write {}: {}", path, e))?;
+                    println!("Changes drawn to {}", path);
+                }
+                _ => println!("Take two snapshots with snap first"),
+            },
+            Ok(DebugCommand::Raster) => match soc.display.raster_frame() {
+                [] => println!("No frame has been output yet"),
+                lines => print!("{}", raster::report(lines)),
+            },
+            Ok(DebugCommand::Log(ticks)) => {
+                let log = soc.log_bus(ticks);
+                let start = log.transactions.first().map_or(0, |transaction| transaction.tick);
+                for (extension, contents) in [("vcd", log.vcd()), ("csv", log.csv())] {
+                    let path = format!("{}.bus-{}.{}", game, start, extension);
+                    std::fs::write(&path, contents).map_err(|e| format!("Could not write {}: {}", path, e))?;
+                    println!("{} accesses logged to {}", log.transactions.len(), path);
+                }
+                println!("{}", soc.debug_status());
+            }
+            Ok(DebugCommand::Rom(segments, color)) => {
+                let segments: Vec<u8> = (0x2..=0xF).filter(|segment| segments & 1 << segment != 0).collect();
+                let path = format!("{}.rom-{}.txt", game, soc.frames);
+                std::fs::write(&path, soc.export_rom_window(&segments, color)).map_err(|e| format!("Could not write {}: {}", path, e))?;
+                println!("Segments {} exported to {}", segments.iter().map(|segment| format!("{:X}000", segment)).collect::<Vec<_>>().join(", "), path);
+            }
+            Ok(DebugCommand::Bank(write)) => {
+                if let Some((port, byte)) = write {soc.write_bank(port, byte)?}
+                println!("{}", soc.bank_report());
+            }
+            Ok(DebugCommand::Help) => println!("int, line <n> [dot], vblank, watch <addr>[-<end>] [r|w|rw], port <port> [value[/mask]], unwatch, regs, dma, snap, diff, raster, log <ticks>, rom [2-F].. [color], bank [port byte], help, quit"),
+            Ok(DebugCommand::Quit) => return Ok(()),
+            Err(e) => println!("{}", e),
+        }
+    }
+}
+
+#[cfg(test)]
+#[cfg_attr(coverage_nightly, coverage(off))]
+mod test {
+    use alloc::{string::ToString, vec};
+
+    use crate::{bus::io_bus::IOBusConnection, cpu::v30mz::OperandAddress, display::raster};
+
+    use super::*;
+
+    /// Ticks in a frame
+    const FRAME_TICKS: usize = 40704;
+
+    #[test]
+    fn test_parse_commands() {
+        assert_eq!(DebugCommand::parse("int\n"), Ok(DebugCommand::Run(Target::Interrupt)));
+        assert_eq!(DebugCommand::parse("line 100"), Ok(DebugCommand::Run(Target::Scanline(100, 0))));
+        assert_eq!(DebugCommand::parse("l 100 32"), Ok(DebugCommand::Run(Target::Scanline(100, 32))));
+        assert_eq!(DebugCommand::parse(" vblank "), Ok(DebugCommand::Run(Target::Vblank)));
+        assert_eq!(DebugCommand::parse(""), Ok(DebugCommand::Help));
+        assert!(DebugCommand::parse("line").is_err());
+        assert!(DebugCommand::parse("line 300").is_err());
+        assert!(DebugCommand::parse("vblank 1").is_err());
+        assert!(DebugCommand::parse("step").is_err());
+        assert_eq!(DebugCommand::parse("dma"), Ok(DebugCommand::Dma));
+        assert_eq!(DebugCommand::parse("snap"), Ok(DebugCommand::Snap));
+        assert_eq!(DebugCommand::parse("d"), Ok(DebugCommand::Diff));
+        assert_eq!(DebugCommand::parse("raster"), Ok(DebugCommand::Raster));
+        assert_eq!(DebugCommand::parse("log 1000"), Ok(DebugCommand::Log(1000)));
+        assert!(DebugCommand::parse("log 0").is_err());
+        assert!(DebugCommand::parse("log 999999999").is_err());
+        assert_eq!(DebugCommand::parse("rom"), Ok(DebugCommand::Rom(0x800C, false)));
+        assert_eq!(DebugCommand::parse("rom 4 f color"), Ok(DebugCommand::Rom(0x8010, true)));
+        assert!(DebugCommand::parse("rom 1").is_err());
+        assert_eq!(DebugCommand::parse("bank"), Ok(DebugCommand::Bank(None)));
+        assert_eq!(DebugCommand::parse("b D2 0x1F"), Ok(DebugCommand::Bank(Some((0xD2, 0x1F)))));
+        assert_eq!(DebugCommand::parse("bank rom_bank_1 4"), Ok(DebugCommand::Bank(Some((0xC3, 0x04)))));
+        assert!(DebugCommand::parse("bank C8 00").is_err());
+        assert!(DebugCommand::parse("bank C2").is_err());
+
+        assert_eq!(DebugCommand::parse("watch 2000"), Ok(DebugCommand::Watch(Watchpoint {start: 0x2000, end: 0x2000, reads: true, writes: true})));
+        assert_eq!(DebugCommand::parse("w 0x10000-1FFFF w"), Ok(DebugCommand::Watch(Watchpoint {start: 0x10000, end: 0x1FFFF, reads: false, writes: true})));
+        assert!(DebugCommand::parse("watch 2000-1000").is_err());
+        assert!(DebugCommand::parse("watch 100000").is_err());
+        assert!(DebugCommand::parse("watch 2000 x").is_err());
+
+        assert_eq!(DebugCommand::parse("port 00"), Ok(DebugCommand::Port(PortTrigger {port: 0x00, mask: 0x00, value: 0x00})));
+        assert_eq!(DebugCommand::parse("p B2 40"), Ok(DebugCommand::Port(PortTrigger {port: 0xB2, mask: 0xFF, value: 0x40})));
+        assert_eq!(DebugCommand::parse("port 0x00 00/04"), Ok(DebugCommand::Port(PortTrigger {port: 0x00, mask: 0x04, value: 0x00})));
+        assert!(DebugCommand::parse("port").is_err());
+        assert!(DebugCommand::parse("port 100").is_err());
+        assert!(DebugCommand::parse("port 00 04/x").is_err());
+    }
+
+    #[test]
+    fn test_run_to_scanline() {
+        let mut soc = SoC::test_build();
+        assert!(soc.run_to(Target::Scanline(10, 50), FRAME_TICKS));
+        assert_eq!(soc.display.position(), (10, 50));
+
+        // Running to the same position again first moves away from it
+        assert!(!soc.run_to(Target::Scanline(10, 50), 1));
+        assert!(soc.run_to(Target::Scanline(10, 50), FRAME_TICKS));
+
+        assert!(soc.run_to(Target::Vblank, FRAME_TICKS));
+        assert_eq!(soc.display.position(), (VBLANK_LINE, 0));
+    }
+
+    #[test]
+    fn test_run_to_interrupt() {
+        let mut soc = SoC::test_build();
+        // Enables interrupts, then runs ADD instructions until vblank is dispatched
+        soc.set_wram(vec![0xFB]);
+        soc.io_bus.borrow_mut().write_io(0xB2, 0x40);
+
+        assert!(soc.run_to(Target::Interrupt, 2 * FRAME_TICKS));
+        assert_eq!(soc.cpu.last_interrupt, Some(6));
+        // The interrupt is dispatched at the end of the instruction running when vblank started
+        assert_eq!(soc.display.position().0, VBLANK_LINE);
+        assert!(soc.debug_status().contains("last interrupt=06"));
+    }
+
+    #[test]
+    fn test_watchpoint_report() {
+        let mut soc = SoC::test_build();
+        soc.set_wram(vec![
+            0xB0, 0x34,             // MOV AL, 0x34
+            0x88, 0x06, 0x00, 0x20, // MOV [0x2000], AL
+        ]);
+        soc.watch(Watchpoint {start: 0x2000, end: 0x2000, reads: false, writes: true});
+
+        assert!(soc.run_to(Target::Vblank, FRAME_TICKS));
+        let reports = soc.take_watch_reports();
+        assert_eq!(reports.len(), 1);
+        let report = reports[0];
+        assert_eq!(report.hit, WatchHit {accessor: Accessor::CPU, addr: 0x2000, write: true, before: 0x01, after: 0x34});
+        let cpu = report.cpu.unwrap();
+        assert_eq!(cpu.instruction.unwrap().address, 0x0002);
+        assert_eq!(cpu.operand, Some(OperandAddress {segment: 0x0000, offset: 0x2000, mod_rm: Some(0x06)}));
+        assert!(report.to_string().contains("mod/rm=06 [disp16]"));
+
+        // Accesses outside of the range or of the wrong kind are not reported
+        soc.unwatch();
+        soc.watch(Watchpoint {start: 0x2001, end: 0x2FFF, reads: true, writes: true});
+        soc.watch(Watchpoint {start: 0x2000, end: 0x2000, reads: true, writes: false});
+        assert!(soc.run_to(Target::Scanline(10, 0), FRAME_TICKS));
+        assert!(soc.take_watch_reports().is_empty());
+    }
+
+    #[test]
+    fn test_port_trigger_report() {
+        let mut soc = SoC::test_build();
+        soc.set_wram(vec![
+            0xB0, 0x07, // MOV AL, 0x07
+            0xE6, 0x00, // OUT 0x00, AL
+            0xB0, 0x03, // MOV AL, 0x03
+            0xE6, 0x00, // OUT 0x00, AL, switching sprites off
+        ]);
+        // Writes to DISPLAY_CTRL with the sprite bit clear
+        soc.watch_port(PortTrigger {port: 0x00, mask: 0x04, value: 0x00});
+
+        assert!(soc.run_to(Target::Vblank, FRAME_TICKS));
+        let reports = soc.take_port_reports();
+        assert_eq!(reports.len(), 1);
+        assert_eq!(reports[0].hit, PortHit {port: 0x00, byte: 0x03});
+        assert_eq!(reports[0].cpu.instruction.unwrap().address, 0x0006);
+        assert!(reports[0].to_string().starts_with("write port 00 <- 03"));
+        assert!(reports[0].to_string().contains("at 00006  E600"));
+
+        soc.unwatch();
+        soc.set_wram(vec![0xB0, 0x03, 0xE6, 0x00]);
+        assert!(soc.run_to(Target::Scanline(10, 0), FRAME_TICKS));
+        assert!(soc.take_port_reports().is_empty());
+    }
+
+    #[test]
+    fn test_raster_trace() {
+        let mut soc = SoC::test_build();
+        soc.display.raster_trace = true;
+        soc.io_bus.borrow_mut().write_io(0x00, 0x01);
+        soc.io_bus.borrow_mut().write_io(0x10, 0x20);
+
+        // Screen 2 is switched on and scrolled partway through the frame
+        assert!(soc.run_to(Target::Scanline(81, 0), 2 * FRAME_TICKS));
+        soc.io_bus.borrow_mut().write_io(0x00, 0x03);
+        soc.io_bus.borrow_mut().write_io(0x13, 0x08);
+        assert!(soc.run_to(Target::Scanline(1, 0), FRAME_TICKS));
+
+        let lines = soc.display.raster_frame();
+        assert_eq!(lines.len(), 144);
+        assert_eq!((lines[0].scroll_1, lines[0].layers()), ((0x20, 0), "scr1".to_string()));
+        assert_eq!((lines[80].scroll_2, lines[80].layers()), ((0, 0x08), "scr1 scr2".to_string()));
+        assert_eq!(lines[79], lines[0]);
+        assert!(raster::report(lines).contains("0-79"));
+    }
+}
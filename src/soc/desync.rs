@@ -0,0 +1,176 @@
+use alloc::{format, string::String, vec::Vec};
+
+use crate::state::{fnv1a, FNV_OFFSET};
+
+use super::SoC;
+
+/// Default amount of frames between sync hashes, one second of emulation
+pub const SYNC_INTERVAL: usize = 75;
+
+impl SoC {
+    /// Hashes WRAM and the last finished frame, continuing from the previous sync hash
+    ///
+    /// Since each hash includes the previous one, a desync stays visible in every later hash even if the game's state converges again.
+    pub fn sync_hash(&self, previous: u64) -> u64 {
+        let hash = fnv1a(previous, &self.mem_bus.borrow().wram);
+        fnv1a(hash, &self.lcd.borrow()[..])
+    }
+
+    /// Hashes WRAM alone, telling whether two consoles ran a game the same way even if they drew it differently
+    pub fn wram_hash(&self) -> u64 {
+        fnv1a(FNV_OFFSET, &self.mem_bus.borrow().wram)
+    }
+}
+
+/// Sync hashes taken every few frames, stored alongside recorded inputs
+///
+/// In text form the first line holds the interval and each following line one hash in hexadecimal.
+#[derive(Clone, PartialEq, Eq, Debug)]
+pub struct SyncLog {
+    /// Amount of frames between hashes
+    pub interval: usize,
+    /// The hash taken at the end of each interval
+    pub hashes: Vec<u64>,
+}
+
+impl SyncLog {
+    /// Creates an empty log taking a hash every `interval` frames
+    pub fn new(interval: usize) -> Self {
+        Self {interval: interval.max(1), hashes: Vec::new()}
+    }
+
+    /// Serializes the log into its text form
+    pub fn to_text(&self) -> String {
+        let mut text = format!("interval {}\n", self.interval);
+        for hash in &self.hashes {
+            text.push_str(&format!("{:016X}\n", hash));
+        }
+        text
+    }
+
+    /// Parses a log from its text form, returns None if it is malformed
+    pub fn from_text(text: &str) -> Option<Self> {
+        let mut lines = text.lines();
+        let interval = lines.next()?.strip_prefix("interval ")?.parse().ok().filter(|interval| *interval > 0)?;
+        let hashes = lines.map(|line| u64::from_str_radix(line, 16).ok()).collect::<Option<_>>()?;
+        Some(Self {interval, hashes})
+    }
+}
+
+/// Whether a detector records new hashes or checks against recorded ones
+enum Mode {
+    Recording,
+    Checking,
+}
+
+/// Records sync hashes while a movie is recorded, or checks them while it plays back or during netplay
+///
+/// The first divergent frame is only known to the interval it falls in, running the movie again with an interval of 1 pinpoints it.
+pub struct DesyncDetector {
+    /// Hashes recorded so far, or the ones being checked against
+    log: SyncLog,
+    /// Whether hashes are being recorded or checked
+    mode: Mode,
+    /// Amount of frames finished so far
+    frame: usize,
+    /// The last sync hash
+    rolling: u64,
+    /// Index of the next hash to check
+    next: usize,
+    /// The frame on which a desync was first detected
+    pub desync: Option<usize>,
+}
+
+impl DesyncDetector {
+    /// Creates a detector recording a new log
+    pub fn record(interval: usize) -> Self {
+        Self {log: SyncLog::new(interval), mode: Mode::Recording, frame: 0, rolling: FNV_OFFSET, next: 0, desync: None}
+    }
+
+    /// Creates a detector checking against a recorded log
+    pub fn check(log: SyncLog) -> Self {
+        Self {log, mode: Mode::Checking, frame: 0, rolling: FNV_OFFSET, next: 0, desync: None}
+    }
+
+    /// Called after every finished frame
+    ///
+    /// Returns the frame the first time a hash does not match the recorded one, later mismatches are not reported again.
+    /// Frames past the end of the recorded log are not checked.
+    pub fn frame_end(&mut self, soc: &SoC) -> Option<usize> {
+        self.frame += 1;
+        if !self.frame.is_multiple_of(self.log.interval) {return None}
+
+        self.rolling = soc.sync_hash(self.rolling);
+        match self.mode {
+            Mode::Recording => {
+                self.log.hashes.push(self.rolling);
+                None
+            }
+            Mode::Checking => {
+                let expected = self.log.hashes.get(self.next).copied();
+                self.next += 1;
+                if self.desync.is_some() || expected.is_none_or(|expected| expected == self.rolling) {return None}
+                self.desync = Some(self.frame);
+                self.desync
+            }
+        }
+    }
+
+    /// Returns the log, containing the hashes recorded so far when recording
+    pub fn into_log(self) -> SyncLog {
+        self.log
+    }
+}
+
+#[cfg(test)]
+#[cfg_attr(coverage_nightly, coverage(off))]
+mod test {
+    use alloc::vec;
+
+    use super::*;
+
+    /// Runs a SoC for the given amount of frames, writing to WRAM after `poke` frames if given
+    fn run(detector: &mut DesyncDetector, frames: usize, poke: Option<usize>) -> Option<usize> {
+        let mut soc = SoC::test_build();
+        let mut desync = None;
+        for frame in 0..frames {
+            if poke == Some(frame) {
+                soc.mem_bus.borrow_mut().wram[0x2000] ^= 0xFF;
+            }
+            while !soc.tick() {}
+            desync = desync.or(detector.frame_end(&soc));
+        }
+        desync
+    }
+
+    #[test]
+    fn test_replay_in_sync() {
+        let mut recorder = DesyncDetector::record(2);
+        run(&mut recorder, 4, None);
+        let log = recorder.into_log();
+        assert_eq!(log.hashes.len(), 2);
+
+        // Frames past the end of the log are not checked
+        let mut checker = DesyncDetector::check(log);
+        assert_eq!(run(&mut checker, 5, None), None);
+    }
+
+    #[test]
+    fn test_first_desync() {
+        let mut recorder = DesyncDetector::record(2);
+        run(&mut recorder, 6, None);
+
+        // The state diverges during frame 3, which ends the second interval
+        let mut checker = DesyncDetector::check(recorder.into_log());
+        assert_eq!(run(&mut checker, 6, Some(2)), Some(4));
+        assert_eq!(checker.desync, Some(4));
+    }
+
+    #[test]
+    fn test_sync_log_text() {
+        let log = SyncLog {interval: 75, hashes: vec![0x0123_4567_89AB_CDEF, 0]};
+        assert_eq!(SyncLog::from_text(&log.to_text()), Some(log));
+        assert_eq!(SyncLog::from_text("interval 0\n"), None);
+        assert_eq!(SyncLog::from_text("interval 1\nnot a hash\n"), None);
+    }
+}
@@ -0,0 +1,217 @@
+use alloc::{format, string::String, vec, vec::Vec};
+use core::fmt::Write;
+
+use crate::{cpu::v30mz::V30MZ, png::{crc32, encode_png}};
+
+use super::SoC;
+
+impl SoC {
+    /// Collects a diagnostic bundle for bug reports, returns the name and contents of each file
+    ///
+    /// | File             | Contents                                                              |
+    /// |------------------|-----------------------------------------------------------------------|
+    /// | `cpu.txt`        | Registers, PSW, fault and lockup of the CPU                           |
+    /// | `trace.txt`      | The last instructions executed, with the registers before each        |
+    /// | `io.txt`         | Every I/O port, peeked so that reading them has no side effects       |
+    /// | `cartridge.txt`  | The cartridge's bank registers                                        |
+    /// | `dma.txt`        | State of the general and sound DMAs                                   |
+    /// | `options.txt`    | The emulator options in use                                           |
+    /// | `audio.txt`      | Samples waiting to be played and how many were dropped                |
+    /// | `profile.txt`    | Time spent in each subsystem over the last frame, only when profiling |
+    /// | `screenshot.png` | The last finished frame                                               |
+    pub fn bug_report(&self) -> Vec<(&'static str, Vec<u8>)> {
+        let registers = |values: [u16; 13]| V30MZ::REGISTER_NAMES.iter().zip(values)
+            .map(|(name, value)| format!("{}={:04X}", name, value))
+            .collect::<Vec<_>>().join(" ");
+
+        let mut cpu = String::new();
+        writeln!(cpu, "{}", registers(self.cpu.registers())).unwrap();
+        writeln!(cpu, "PSW={:04X}", self.cpu.psw()).unwrap();
+        writeln!(cpu, "fault={:?}", self.cpu.fault).unwrap();
+        writeln!(cpu, "lockup={:?}", self.lockup()).unwrap();
+
+        let mut trace = String::new();
+        for entry in self.cpu.history() {
+            let bytes = entry.bytes[..entry.len as usize].iter().map(|byte| format!("{:02X}", byte)).collect::<String>();
+            writeln!(trace, "{:05X}  {:<16}  {:<6}  {}", entry.address, bytes, entry.name(), registers(entry.registers)).unwrap();
+        }
+
+        let mut io = String::new();
+        let io_bus = self.io_bus.borrow();
+        for (row, ports) in io_bus.peek_ports().chunks(16).enumerate() {
+            let ports = ports.iter().map(|port| format!("{:02X}", port)).collect::<Vec<_>>().join(" ");
+            writeln!(io, "{:02X}: {}", row * 16, ports).unwrap();
+        }
+        writeln!(io, "model={:?}", io_bus.model).unwrap();
+
+        let mut cartridge = String::new();
+        for (name, value) in io_bus.cartridge.borrow().bank_registers() {
+            writeln!(cartridge, "{}={:02X}", name, value).unwrap();
+        }
+
+        let dma = format!("{:#?}\n{:#?}\n", self.gdma, self.sdma);
+        let options = format!("{:#?}\n", self.options.get());
+        let samples = self.samples.lock().unwrap();
+        let audio = format!("queued={}\ncapacity={}\ndropped={}\n", samples.len(), samples.capacity(), samples.dropped());
+        drop(samples);
+        let screenshot = encode_png(224, 144, &self.lcd.borrow()[..]);
+
+        let mut files = vec![
+            ("cpu.txt", cpu.into_bytes()),
+            ("trace.txt", trace.into_bytes()),
+            ("io.txt", io.into_bytes()),
+            ("cartridge.txt", cartridge.into_bytes()),
+            ("dma.txt", dma.into_bytes()),
+            ("options.txt", options.into_bytes()),
+            ("audio.txt", audio.into_bytes()),
+            ("screenshot.png", screenshot),
+        ];
+        if let Some(profile) = self.profile() {
+            files.push(("profile.txt", format!("{}\n", profile).into_bytes()));
+        }
+        files
+    }
+}
+
+/// Writes a bug report for a game next to it, returns the path of the archive
+///
+/// Reports are numbered so that earlier ones are never overwritten.
+#[cfg(feature = "std")]
+pub fn write_bug_report(soc: &SoC, game: &str) -> std::io::Result<String> {
+    let path = (1..).map(|n| format!("{}-report-{}.zip", game, n)).find(|path| !std::path::Path::new(path).exists()).unwrap();
+    let folder = std::path::Path::new(&path).file_stem().unwrap().to_string_lossy().to_string();
+    std::fs::write(&path, zip(&folder, &soc.bug_report()))?;
+    Ok(path)
+}
+
+/// Writes a triage bundle for an abnormal condition into the game's triage folder, returns the path of the archive
+///
+/// The bundle is the bug report along with why it was taken and a savestate of the moment,
+/// bundles are named after the frame they were taken on and never overwrite earlier ones.
+#[cfg(feature = "std")]
+pub fn write_triage(soc: &SoC, game: &str, reason: &str) -> std::io::Result<String> {
+    let folder = format!("{}-triage", game);
+    std::fs::create_dir_all(&folder)?;
+    let name = (1..).map(|n| format!("frame-{}-{}", soc.frames(), n))
+        .find(|name| !std::path::Path::new(&folder).join(format!("{}.zip", name)).exists()).unwrap();
+    let mut files = soc.bug_report();
+    files.push(("reason.txt", format!("{}\n", reason).into_bytes()));
+    files.push(("state.bin", soc.save()));
+    let path = format!("{}/{}.zip", folder, name);
+    std::fs::write(&path, zip(&name, &files))?;
+    Ok(path)
+}
+
+/// Packs files into an uncompressed ZIP archive, placing them in the given folder
+pub fn zip(folder: &str, files: &[(&str, Vec<u8>)]) -> Vec<u8> {
+    let mut zip = Vec::new();
+    let mut directory = Vec::new();
+
+    for (name, data) in files {
+        let name = format!("{}/{}", folder, name);
+        let offset = zip.len() as u32;
+        // Version needed, flags, stored, time, date (1980-01-01), CRC and sizes
+        let mut header = Vec::new();
+        header.extend(20u16.to_le_bytes());
+        header.extend([0; 6]);
+        header.extend(0x21u16.to_le_bytes());
+        header.extend(crc32(data).to_le_bytes());
+        header.extend((data.len() as u32).to_le_bytes());
+        header.extend((data.len() as u32).to_le_bytes());
+        header.extend((name.len() as u16).to_le_bytes());
+        header.extend([0; 2]);
+
+        zip.extend(0x04034B50u32.to_le_bytes());
+        zip.extend(&header);
+        zip.extend(name.as_bytes());
+        zip.extend(data);
+
+        directory.extend(0x02014B50u32.to_le_bytes());
+        directory.extend(20u16.to_le_bytes());
+        directory.extend(&header);
+        // Comment length, disk, internal and external attributes
+        directory.extend([0; 10]);
+        directory.extend(offset.to_le_bytes());
+        directory.extend(name.as_bytes());
+    }
+
+    let directory_offset = zip.len() as u32;
+    zip.extend(&directory);
+    zip.extend(0x06054B50u32.to_le_bytes());
+    zip.extend([0; 4]);
+    zip.extend((files.len() as u16).to_le_bytes());
+    zip.extend((files.len() as u16).to_le_bytes());
+    zip.extend((directory.len() as u32).to_le_bytes());
+    zip.extend(directory_offset.to_le_bytes());
+    zip.extend([0; 2]);
+    zip
+}
+
+#[cfg(test)]
+#[cfg_attr(coverage_nightly, coverage(off))]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_bug_report() {
+        let mut soc = SoC::test_build();
+        for _ in 0..100 {
+            soc.tick();
+        }
+
+        let files = soc.bug_report();
+        let trace = String::from_utf8(files.iter().find(|(name, _)| *name == "trace.txt").unwrap().1.clone()).unwrap();
+        assert!(!trace.is_empty());
+        assert!(trace.lines().count() <= crate::cpu::v30mz::HISTORY_LEN);
+        let io = String::from_utf8(files.iter().find(|(name, _)| *name == "io.txt").unwrap().1.clone()).unwrap();
+        assert!(io.starts_with("00: FF"));
+    }
+
+    #[test]
+    fn test_bug_report_dropped_samples() {
+        let mut soc = SoC::test_build();
+        soc.options().update(|options| options.mute = false);
+        soc.apply_options();
+        // Nothing plays the samples, so the buffer fills up after about 19 frames
+        for _ in 0..20 {soc.run_frame();}
+
+        let files = soc.bug_report();
+        let audio = String::from_utf8(files.iter().find(|(name, _)| *name == "audio.txt").unwrap().1.clone()).unwrap();
+        let dropped = soc.samples.lock().unwrap().dropped();
+        assert!(dropped > 0);
+        assert_eq!(audio, format!("queued=6000\ncapacity=6000\ndropped={}\n", dropped));
+    }
+
+    #[test]
+    fn test_zip() {
+        let zip = zip("report", &[("a.txt", b"abc".to_vec())]);
+        assert_eq!(&zip[0..4], &[0x50, 0x4B, 0x03, 0x04]);
+        assert_eq!(&zip[14..18], &crc32(b"abc").to_le_bytes());
+        assert_eq!(&zip[30..42], b"report/a.txt");
+        // The end of central directory record points at the single entry of the directory
+        let end = zip.len() - 22;
+        assert_eq!(&zip[end..end + 4], &[0x50, 0x4B, 0x05, 0x06]);
+        let directory_offset = u32::from_le_bytes(zip[end + 16..end + 20].try_into().unwrap()) as usize;
+        assert_eq!(directory_offset, 30 + 12 + 3);
+        assert_eq!(&zip[directory_offset..directory_offset + 4], &[0x50, 0x4B, 0x01, 0x02]);
+    }
+
+    #[test]
+    #[cfg(feature = "std")]
+    fn test_write_triage() {
+        let mut soc = SoC::test_build();
+        soc.run_frame();
+        let dir = std::env::temp_dir().join(format!("wondercrab-triage-{}", std::process::id()));
+        let game = dir.join("game").to_str().unwrap().to_string();
+
+        let first = write_triage(&soc, &game, "lockup").unwrap();
+        let second = write_triage(&soc, &game, "lockup").unwrap();
+        assert!(first.ends_with("game-triage/frame-1-1.zip"));
+        assert!(second.ends_with("game-triage/frame-1-2.zip"));
+        let bundle = std::fs::read(&first).unwrap();
+        let state = soc.save();
+        assert!(bundle.windows(state.len()).any(|window| window == state));
+        assert!(bundle.windows(27).any(|window| window == b"frame-1-1/reason.txtlockup\n"));
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+}
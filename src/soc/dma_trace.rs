@@ -0,0 +1,130 @@
+use alloc::{collections::VecDeque, format, string::{String, ToString}, vec::Vec};
+use core::fmt;
+
+use crate::{bus::mem_bus::Accessor, dma::{DmaState, DmaTransition, DMA}};
+
+use super::SoC;
+
+/// Most DMA events kept, older ones being dropped
+pub const DMA_EVENTS: usize = 64;
+
+/// A DMA starting or completing a transfer
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub struct DmaEvent {
+    /// Which DMA, either [`Accessor::GDMA`] or [`Accessor::SDMA`]
+    pub dma: Accessor,
+    /// Whether the transfer started or completed
+    pub transition: DmaTransition,
+    /// Ticks since power on, each being 4 cycles of the master clock
+    pub tick: u64,
+    /// Registers of the DMA right after the transition
+    pub state: DmaState,
+}
+
+impl fmt::Display for DmaEvent {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "tick {:>10} {:?} {:<8} {}", self.tick, self.dma, format!("{:?}", self.transition), self.state)
+    }
+}
+
+impl SoC {
+    /// Returns the registers of the general and sound DMA
+    pub fn dma_states(&self) -> [(Accessor, DmaState); 2] {
+        [(Accessor::GDMA, self.gdma.state()), (Accessor::SDMA, self.sdma.state())]
+    }
+
+    /// Returns the last [`DMA_EVENTS`] transfers started or completed, oldest first
+    pub fn dma_events(&self) -> &VecDeque<DmaEvent> {
+        &self.dma_events
+    }
+
+    /// Lists the DMAs' registers followed by their recent events
+    pub fn dma_report(&self) -> String {
+        self.dma_states().iter().map(|(dma, state)| format!("{:?} {}", dma, state))
+            .chain(self.dma_events.iter().map(|event| event.to_string()))
+            .collect::<Vec<_>>().join("\n")
+    }
+
+    /// Stamps the transfers the DMAs started or completed during this tick, printing them along with the CPU trace
+    pub(super) fn record_dma_transitions(&mut self) {
+        let tick = self.frames * self.display.timing().frame_ticks() as u64 + self.cycles as u64;
+        let gdma = self.gdma.take_transitions().into_iter().map(|transition| (Accessor::GDMA, transition));
+        let sdma = self.sdma.take_transitions().into_iter().map(|transition| (Accessor::SDMA, transition));
+        for (dma, (transition, state)) in gdma.chain(sdma) {
+            let event = DmaEvent {dma, transition, tick, state};
+            #[cfg(feature = "std")]
+            if self.cpu.trace {println!("DMA: {}", event)}
+            if self.dma_events.len() == DMA_EVENTS {self.dma_events.pop_front();}
+            self.dma_events.push_back(event);
+        }
+    }
+}
+
+#[cfg(test)]
+#[cfg_attr(coverage_nightly, coverage(off))]
+mod test {
+    use crate::bus::io_bus::IOBusConnection;
+
+    use super::*;
+
+    #[test]
+    fn test_gdma_events() {
+        let mut soc = SoC::test_build();
+        soc.io_bus.borrow_mut().write_io(0x60, 0x80);
+        // 4 bytes from 0x0100 to 0x0200
+        soc.write_io_16(0x40, 0x0100);
+        soc.write_io(0x42, 0x00);
+        soc.write_io_16(0x44, 0x0200);
+        soc.write_io_16(0x46, 0x0004);
+        soc.write_io(0x48, 0x80);
+        for _ in 0..8 {soc.tick();}
+
+        // A state taken mid-transfer brings back the control port along with the rest of the transfer
+        let mut other = SoC::test_build();
+        other.load(&soc.save()).unwrap();
+        assert_eq!(other.dma_states()[0].1, soc.dma_states()[0].1);
+        assert_eq!(other.dma_states()[0].1.control, 0x80);
+
+        for _ in 8..20 {soc.tick();}
+
+        let events: Vec<_> = soc.dma_events().iter().collect();
+        assert_eq!(events.len(), 2);
+        assert_eq!((events[0].dma, events[0].transition), (Accessor::GDMA, DmaTransition::Start));
+        assert_eq!(events[0].state, DmaState {src_addr: 0x0100, dest_addr: Some(0x0200), counter: 4, control: 0x80, active: true});
+        assert_eq!(events[1].transition, DmaTransition::Complete);
+        assert_eq!(events[1].state, DmaState {src_addr: 0x0104, dest_addr: Some(0x0204), counter: 0, control: 0x00, active: false});
+        // The first byte moves on the 7th cycle, counting the one the transfer starts on, and every following byte 2 cycles later
+        assert_eq!(events[1].tick - events[0].tick, 6 + 3 * 2);
+
+        let [(_, gdma), (_, sdma)] = soc.dma_states();
+        assert!(!gdma.active && !sdma.active);
+        assert!(soc.dma_report().contains("GDMA Complete"));
+    }
+
+    #[test]
+    fn test_gdma_ends_in_sram() {
+        let mut soc = SoC::test_build();
+        soc.io_bus.borrow_mut().write_io(0x60, 0x80);
+        // The source reaches SRAM right after the last of 2 bytes
+        soc.write_io_16(0x40, 0xFFFE);
+        soc.write_io(0x42, 0x00);
+        soc.write_io_16(0x44, 0x0200);
+        soc.write_io_16(0x46, 0x0002);
+        soc.write_io(0x48, 0x80);
+        for _ in 0..20 {soc.tick();}
+
+        let events: Vec<_> = soc.dma_events().iter().map(|event| event.transition).collect();
+        assert_eq!(events, [DmaTransition::Start, DmaTransition::Complete]);
+        assert_eq!(soc.read_io(0x48) & 0x80, 0);
+
+        // Entering it earlier stops the transfer with the rest of its counter left
+        soc.write_io_16(0x40, 0xFFFE);
+        soc.write_io(0x42, 0x00);
+        soc.write_io_16(0x46, 0x0004);
+        soc.write_io(0x48, 0x80);
+        for _ in 0..20 {soc.tick();}
+        let events: Vec<_> = soc.dma_events().iter().skip(2).collect();
+        assert_eq!(events.len(), 2);
+        assert_eq!(events[1].state, DmaState {src_addr: 0x10000, dest_addr: Some(0x0204), counter: 2, control: 0x80, active: false});
+    }
+}
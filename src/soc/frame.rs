@@ -0,0 +1,134 @@
+use bitflags::bitflags;
+
+use crate::bus::mem_bus::AccessHeat;
+
+use super::SoC;
+
+bitflags! {
+    /// Fast paths of the accuracy tiers that were taken during a frame
+    #[derive(Clone, Copy, Default, PartialEq, Eq, Debug)]
+    pub struct FastPaths: u8 {
+        /// The display drew whole scanlines at once
+        const SCANLINE_RENDERING = 0b01;
+        /// A DMA transfer completed instantly
+        const INSTANT_DMA        = 0b10;
+    }
+}
+
+/// Counters the SoC keeps while a frame is emulated
+#[derive(Clone, Copy, Default, PartialEq, Eq, Debug)]
+pub struct FrameStats {
+    /// Audio samples taken from the sound chip, whether or not they were output
+    pub samples: usize,
+    /// Fast paths taken
+    pub fast_paths: FastPaths,
+}
+
+/// What happened while a frame was emulated
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub struct FrameReport {
+    /// Index of the frame, the first frame is 0
+    pub frame: u64,
+    /// Ticks executed, more than the ticks of a frame when the CPU locked the bus
+    pub cycles: usize,
+    /// Hardware interrupts dispatched by the CPU
+    pub interrupts: u64,
+    /// Instructions and prefixes executed by the CPU
+    pub instructions: u64,
+    /// Audio samples produced
+    pub samples: usize,
+    /// Fast paths taken instead of emulating the hardware exactly
+    pub fast_paths: FastPaths,
+}
+
+impl SoC {
+    /// Runs until the current frame is finished and reports what happened during it
+    pub fn run_frame(&mut self) -> FrameReport {
+        let (interrupts, instructions) = (self.cpu.interrupts, self.cpu.instructions);
+        let mut cycles = 1;
+        while !self.tick() {
+            cycles += 1;
+        }
+
+        FrameReport {
+            frame: self.frames - 1,
+            cycles,
+            interrupts: self.cpu.interrupts - interrupts,
+            instructions: self.cpu.instructions - instructions,
+            samples: self.last_frame.samples,
+            fast_paths: self.last_frame.fast_paths,
+        }
+    }
+
+    /// Amount of frames finished so far
+    pub fn frames(&self) -> u64 {
+        self.frames
+    }
+
+    /// Reads and writes to each page of the address space during the last finished frame
+    pub fn access_heat(&self) -> &AccessHeat {
+        &self.last_heat
+    }
+}
+
+#[cfg(test)]
+#[cfg_attr(coverage_nightly, coverage(off))]
+mod test {
+    use alloc::vec;
+
+    use crate::{bus::io_bus::IOBusConnection, options::Accuracy};
+
+    use super::*;
+
+    #[test]
+    fn test_frame_report() {
+        let mut soc = SoC::test_build();
+        // Enables interrupts and the vblank interrupt
+        soc.set_wram(vec![0xFB]);
+        soc.io_bus.borrow_mut().write_io(0xB2, 0x40);
+
+        let report = soc.run_frame();
+        assert_eq!(report.frame, 0);
+        assert_eq!(report.cycles, 40704);
+        assert_eq!(report.interrupts, 1);
+        assert!(report.instructions > 0);
+        // A sample is taken every 128 ticks
+        assert_eq!(report.samples, 40704 / 128);
+        assert_eq!(report.fast_paths, FastPaths::empty());
+
+        assert_eq!(soc.run_frame().frame, 1);
+        assert_eq!(soc.frames(), 2);
+    }
+
+    #[test]
+    fn test_fast_path_flags() {
+        let mut soc = SoC::test_build();
+        soc.options().update(|options| options.accuracy = Accuracy::Fast);
+        // The options are applied at the end of the first frame
+        soc.run_frame();
+
+        let report = soc.run_frame();
+        assert!(report.fast_paths.contains(FastPaths::SCANLINE_RENDERING));
+        assert!(!report.fast_paths.contains(FastPaths::INSTANT_DMA));
+    }
+
+    #[test]
+    fn test_access_heat() {
+        let mut soc = SoC::test_build();
+        soc.set_wram(vec![
+            0xA2, 0x45, 0x23, // MOV [0x2345], AL
+            0xEB, 0xFB,       // BR 0x0000
+        ]);
+
+        // The first frame also counts the writes made while building the SoC
+        soc.run_frame();
+        soc.run_frame();
+        let heat = soc.access_heat();
+        // Instructions are fetched from page 0 and only page 2 is written to
+        assert!(heat.reads[0] > 0);
+        assert!(heat.writes[2] > 0);
+        assert_eq!(heat.writes.iter().sum::<u32>(), heat.writes[2]);
+        // The display's fetches of tiles from page 3 are not counted
+        assert_eq!(heat.reads.iter().sum::<u32>(), heat.reads[0]);
+    }
+}
@@ -0,0 +1,289 @@
+use alloc::{boxed::Box, vec::Vec};
+use core::fmt::{self, Display};
+
+use super::SoC;
+
+/// Start of the cartridge's SRAM in the address space hooks read, right after the 64KB of WRAM
+///
+/// This is the layout RetroAchievements uses for the WonderSwan, so addresses can be taken from its sets as they are.
+pub const SRAM_START: u32 = 0x10000;
+
+/// Memory as hooks see it at the end of a frame: WRAM from 0, followed by the cartridge's SRAM from [`SRAM_START`]
+///
+/// Reading it has none of the side effects of the CPU's reads, it does not hit watchpoints, count towards the heat map or stall.
+/// Addresses past the end of SRAM read as 0.
+pub struct MemoryView<'a> {
+    /// Work RAM
+    wram: &'a [u8],
+    /// Cartridge SRAM, empty if it has none
+    sram: &'a [u8],
+}
+
+impl MemoryView<'_> {
+    /// Reads a byte
+    pub fn read_u8(&self, addr: u32) -> u8 {
+        let (memory, offset) = match addr.checked_sub(SRAM_START) {
+            None => (self.wram, addr),
+            Some(offset) => (self.sram, offset),
+        };
+        memory.get(offset as usize).copied().unwrap_or(0)
+    }
+
+    /// Reads a value of the given width, in little endian like the CPU
+    pub fn read(&self, addr: u32, width: Width) -> u32 {
+        (0..width.bytes()).fold(0, |value, byte| value | (self.read_u8(addr.wrapping_add(byte)) as u32) << (8 * byte))
+    }
+}
+
+/// Width of the values conditions compare
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum Width {
+    /// 8 bits
+    Byte,
+    /// 16 bits
+    Word,
+    /// 32 bits
+    Dword,
+}
+
+impl Width {
+    /// Number of bytes read
+    pub fn bytes(&self) -> u32 {
+        match self {
+            Width::Byte => 1,
+            Width::Word => 2,
+            Width::Dword => 4,
+        }
+    }
+}
+
+/// How a condition compares the value in memory with its operand
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum Comparison {
+    /// Equal
+    Eq,
+    /// Not equal
+    Ne,
+    /// Less than
+    Lt,
+    /// Less than or equal
+    Le,
+    /// Greater than
+    Gt,
+    /// Greater than or equal
+    Ge,
+}
+
+impl Comparison {
+    /// Compares two values
+    pub fn holds(&self, left: u32, right: u32) -> bool {
+        match self {
+            Comparison::Eq => left == right,
+            Comparison::Ne => left != right,
+            Comparison::Lt => left < right,
+            Comparison::Le => left <= right,
+            Comparison::Gt => left > right,
+            Comparison::Ge => left >= right,
+        }
+    }
+}
+
+/// What the value in memory is compared with
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum Operand {
+    /// A constant
+    Value(u32),
+    /// The value at the same address at the end of the previous frame, to catch changes such as a counter going up
+    ///
+    /// On the first frame a hook is checked there is no previous value, so it compares with the current one.
+    Previous,
+}
+
+/// A comparison of a value in memory, the building block of hooks
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub struct Condition {
+    /// Address of the value, see [`MemoryView`]
+    pub address: u32,
+    /// Width of the value
+    pub width: Width,
+    /// How it is compared
+    pub comparison: Comparison,
+    /// What it is compared with
+    pub operand: Operand,
+}
+
+/// What a hook checks at the end of every frame
+pub enum Predicate {
+    /// Conditions that must all hold, the previous values they need are kept by the SoC
+    Conditions(Vec<Condition>),
+    /// Any check written in Rust, for anything conditions cannot express
+    ///
+    /// It is called once per frame, with the memory at the end of it.
+    Custom(Box<dyn FnMut(&MemoryView) -> bool>),
+}
+
+/// Identifies a registered hook
+#[derive(Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash, Debug)]
+pub struct HookId(pub u32);
+
+/// A hook whose predicate started holding at the end of a frame
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub struct HookEvent {
+    /// The hook
+    pub hook: HookId,
+    /// Index of the frame at the end of which it started holding
+    pub frame: u64,
+}
+
+impl Display for HookEvent {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "Hook {} triggered on frame {}", self.hook.0, self.frame)
+    }
+}
+
+/// A registered predicate along with what the SoC remembers of it between frames
+pub(super) struct Hook {
+    /// Id the hook's events are reported with
+    id: HookId,
+    /// What is checked
+    predicate: Predicate,
+    /// Values of the conditions at the end of the previous frame, `None` before the first check
+    previous: Option<Vec<u32>>,
+    /// Whether the predicate held at the end of the previous frame
+    held: bool,
+}
+
+impl Hook {
+    /// Checks the predicate against memory, keeping the values conditions compare with on the next frame
+    fn check(&mut self, memory: &MemoryView) -> bool {
+        match &mut self.predicate {
+            Predicate::Custom(predicate) => predicate(memory),
+            Predicate::Conditions(conditions) => {
+                let values: Vec<u32> = conditions.iter().map(|condition| memory.read(condition.address, condition.width)).collect();
+                let previous = self.previous.as_ref().unwrap_or(&values);
+                let holds = conditions.iter().zip(&values).zip(previous).all(|((condition, value), previous)| match condition.operand {
+                    Operand::Value(operand) => condition.comparison.holds(*value, operand),
+                    Operand::Previous => condition.comparison.holds(*value, *previous),
+                });
+                self.previous = Some(values);
+                holds
+            }
+        }
+    }
+}
+
+/// The hooks registered on a SoC
+///
+/// Hooks are edge triggered, an event is reported on the frame a predicate starts holding and not again until it has stopped holding.
+/// They belong to the front-end rather than to the console, so save states neither hold nor reset them.
+#[derive(Default)]
+pub(super) struct Hooks {
+    /// Registered hooks, in the order they were added
+    hooks: Vec<Hook>,
+    /// Id of the next hook
+    next_id: u32,
+    /// Events not yet taken by the front-end
+    events: Vec<HookEvent>,
+}
+
+impl Hooks {
+    /// Whether no hook is registered, in which case nothing is checked
+    pub(super) fn is_empty(&self) -> bool {
+        self.hooks.is_empty()
+    }
+}
+
+impl SoC {
+    /// Registers a predicate checked at the end of every frame, returns the id its events are reported with
+    ///
+    /// This is meant for achievements or auto-splitters built on top of the emulator. Checking hooks only costs anything while some are registered,
+    /// and conditions only read the handful of bytes they compare.
+    pub fn add_hook(&mut self, predicate: Predicate) -> HookId {
+        let id = HookId(self.hooks.next_id);
+        self.hooks.next_id += 1;
+        self.hooks.hooks.push(Hook {id, predicate, previous: None, held: false});
+        id
+    }
+
+    /// Unregisters a hook, returns whether it was registered
+    pub fn remove_hook(&mut self, id: HookId) -> bool {
+        let len = self.hooks.hooks.len();
+        self.hooks.hooks.retain(|hook| hook.id != id);
+        self.hooks.hooks.len() != len
+    }
+
+    /// Takes the events reported since the last call, oldest first
+    pub fn take_hook_events(&mut self) -> Vec<HookEvent> {
+        core::mem::take(&mut self.hooks.events)
+    }
+
+    /// Checks every hook against memory at the end of a frame
+    pub(super) fn check_hooks(&mut self) {
+        let frame = self.frames - 1;
+        let mem_bus = self.mem_bus.borrow();
+        let io_bus = self.io_bus.borrow();
+        let cartridge = io_bus.cartridge.borrow();
+        let memory = MemoryView {wram: &mem_bus.wram, sram: &cartridge.sram};
+        for hook in &mut self.hooks.hooks {
+            let holds = hook.check(&memory);
+            if holds && !hook.held {self.hooks.events.push(HookEvent {hook: hook.id, frame})}
+            hook.held = holds;
+        }
+    }
+}
+
+#[cfg(test)]
+#[cfg_attr(coverage_nightly, coverage(off))]
+mod test {
+    use alloc::{rc::Rc, vec};
+    use core::cell::Cell;
+
+    use super::*;
+
+    #[test]
+    fn test_condition_hooks() {
+        let mut soc = SoC::test_build();
+        // Fires when the word at 0x100 reaches 3, and whenever the byte at 0x200 goes up
+        let reached = soc.add_hook(Predicate::Conditions(vec![Condition {address: 0x100, width: Width::Word, comparison: Comparison::Ge, operand: Operand::Value(0x0300)}]));
+        let increased = soc.add_hook(Predicate::Conditions(vec![Condition {address: 0x200, width: Width::Byte, comparison: Comparison::Gt, operand: Operand::Previous}]));
+
+        soc.mem_bus.borrow_mut().wram[0x100..0x300].fill(0);
+        soc.run_frame();
+        assert!(soc.take_hook_events().is_empty());
+
+        soc.mem_bus.borrow_mut().wram[0x101] = 0x03;
+        soc.mem_bus.borrow_mut().wram[0x200] = 0x01;
+        soc.run_frame();
+        assert_eq!(soc.take_hook_events(), [HookEvent {hook: reached, frame: 1}, HookEvent {hook: increased, frame: 1}]);
+
+        // Still holding is not reported again, while a value that stopped going up has to go up again
+        soc.run_frame();
+        soc.mem_bus.borrow_mut().wram[0x200] = 0x02;
+        soc.run_frame();
+        assert_eq!(soc.take_hook_events(), [HookEvent {hook: increased, frame: 3}]);
+
+        assert!(soc.remove_hook(increased));
+        assert!(!soc.remove_hook(increased));
+        soc.mem_bus.borrow_mut().wram[0x200] = 0x03;
+        soc.run_frame();
+        assert!(soc.take_hook_events().is_empty());
+    }
+
+    #[test]
+    fn test_custom_hook() {
+        let mut soc = SoC::test_build();
+        soc.io_bus.borrow().cartridge.borrow_mut().sram = vec![0; 0x100];
+        soc.io_bus.borrow().cartridge.borrow_mut().sram[0x10] = 0x34;
+        soc.io_bus.borrow().cartridge.borrow_mut().sram[0x11] = 0x12;
+        let seen = Rc::new(Cell::new(0));
+        let seen_by_hook = Rc::clone(&seen);
+        soc.add_hook(Predicate::Custom(Box::new(move |memory| {
+            seen_by_hook.set(memory.read(SRAM_START + 0x10, Width::Word));
+            memory.read(SRAM_START + 0x1000, Width::Dword) == 0
+        })));
+
+        soc.run_frame();
+        assert_eq!(seen.get(), 0x1234);
+        assert_eq!(soc.take_hook_events().len(), 1);
+    }
+}
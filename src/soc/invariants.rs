@@ -0,0 +1,82 @@
+use alloc::{format, string::{String, ToString}, vec::Vec};
+
+use crate::cpu::v30mz::CpuStatus;
+
+use super::SoC;
+
+/// PSW bits that always read as 1
+const PSW_ONES: u16 = 0xF002;
+/// PSW bits that always read as 0
+const PSW_ZEROES: u16 = CpuStatus::FIXED_OFF_1.bits() | CpuStatus::FIXED_OFF_2.bits();
+
+impl SoC {
+    /// Returns every invariant the console's registers break, empty when the state is consistent
+    ///
+    /// | Component | Invariant                                                            |
+    /// |-----------|----------------------------------------------------------------------|
+    /// | CPU       | The fixed bits of the PSW hold their values                          |
+    /// | I/O ports | Undefined and read-only bits that writes mask off stay clear         |
+    /// | Cartridge | Bank registers the mapper does not have are untouched, masks fit     |
+    pub fn invariant_violations(&self) -> Vec<String> {
+        let mut violations = Vec::new();
+        let psw = self.cpu.psw();
+        if psw & PSW_ONES != PSW_ONES || psw & PSW_ZEROES != 0 {
+            violations.push(format!("PSW = {:04X} does not keep its fixed bits", psw));
+        }
+        let io_bus = self.io_bus.borrow();
+        violations.extend(io_bus.invariant_violations());
+        violations.extend(io_bus.cartridge.borrow().invariant_violations());
+        violations
+    }
+
+    /// Panics if an invariant is broken, naming the last instruction executed so that the corruption is found where it happens
+    ///
+    /// Only called on every tick with the validate feature, as checking is much slower than emulating.
+    #[cfg_attr(not(feature = "validate"), allow(dead_code))]
+    pub(super) fn check_invariants(&self) {
+        let violations = self.invariant_violations();
+        if violations.is_empty() {return}
+        let instruction = self.cpu.history().last()
+            .map_or("no instruction".to_string(), |entry| format!("{} at {:05X}", entry.name(), entry.address));
+        let (line, dot) = self.display.position();
+        panic!("Invariants broken on frame {} line {} dot {} after {}:\n{}", self.frames, line, dot, instruction, violations.join("\n"));
+    }
+}
+
+#[cfg(test)]
+#[cfg_attr(coverage_nightly, coverage(off))]
+mod test {
+    use alloc::vec;
+
+    use crate::bus::io_bus::IOBusConnection;
+
+    use super::*;
+
+    #[test]
+    fn test_invariants_hold() {
+        let mut soc = SoC::test_build();
+        soc.set_wram(vec![
+            0xB0, 0xFF, // MOV AL, 0xFF
+            0xE6, 0x20, // OUT 0x20, AL
+            0xE6, 0x42, // OUT 0x42, AL
+            0xE6, 0xD1, // OUT 0xD1, AL
+            0x6A, 0x00, // PUSH 0x00
+            0x9D,       // POP PSW
+            0xEB, 0xF3, // BR -13
+        ]);
+        for _ in 0..1000 {
+            soc.tick();
+            soc.check_invariants();
+        }
+        assert!(soc.invariant_violations().is_empty());
+    }
+
+    #[test]
+    #[should_panic(expected = "PSW")]
+    fn test_broken_invariant_panics() {
+        let mut soc = SoC::test_build();
+        soc.write_io(0x20, 0x77);
+        soc.cpu.set_psw(0x0000);
+        soc.check_invariants();
+    }
+}
@@ -0,0 +1,129 @@
+use alloc::vec::Vec;
+use core::fmt::Display;
+
+use crate::cpu::v30mz::{CpuStatus, HISTORY_LEN};
+
+use super::{power::PowerState, SoC};
+
+/// Frames a lockup has to last before it is reported, 2 seconds
+pub const LOCKUP_FRAMES: u32 = 150;
+
+/// A state the CPU cannot leave, as no interrupt that could get it out of it is enabled
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum Lockup {
+    /// Halted while every interrupt source is disabled in port 0xB2
+    ///
+    /// The I/O bus keeps the vblank interrupt enabled at all times, so this only happens if that ever changes.
+    Halt,
+    /// Branching to itself at the address while interrupts are disabled
+    Loop(u32),
+}
+
+impl Display for Lockup {
+    fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
+        match self {
+            Lockup::Halt => write!(f, "halted with every interrupt disabled"),
+            Lockup::Loop(address) => write!(f, "looping at {:05X} with interrupts disabled", address),
+        }
+    }
+}
+
+impl SoC {
+    /// Returns the lockup the CPU is in, if any
+    ///
+    /// A halted CPU is woken by any enabled interrupt source even when the interrupt flag is clear,
+    /// while a jump to self can only be left through an interrupt that is both enabled and let through by the flag.
+    /// Loops longer than a single instruction are not detected, as they usually poll something that can change.
+    pub fn lockup(&self) -> Option<Lockup> {
+        // A console the game switched off is meant to stay still
+        if self.power_state() == PowerState::Off {return None}
+        let sources = self.io_bus.borrow().peek_ports()[0xB2];
+        if self.cpu.halted() {
+            return if sources == 0 {Some(Lockup::Halt)} else {None};
+        }
+
+        let interrupts = CpuStatus::from_bits_truncate(self.cpu.psw()).contains(CpuStatus::INTERRUPT);
+        let addresses: Vec<_> = self.cpu.history().map(|entry| entry.address).collect();
+        let spinning = addresses.len() == HISTORY_LEN && addresses.iter().all(|address| *address == addresses[0]);
+        if spinning && (!interrupts || sources == 0) {Some(Lockup::Loop(addresses[0]))} else {None}
+    }
+}
+
+/// Tells when the same lockup lasted long enough to be reported
+pub struct LockupDetector {
+    /// Frames the lockup has to last
+    threshold: u32,
+    /// The lockup seen at the end of the last frame
+    lockup: Option<Lockup>,
+    /// Consecutive frames that ended in that lockup
+    frames: u32,
+}
+
+impl LockupDetector {
+    /// Creates a detector reporting lockups that last for the given amount of frames
+    pub fn new(threshold: u32) -> Self {
+        Self {threshold, lockup: None, frames: 0}
+    }
+
+    /// Checks the SoC at the end of a frame, returns the lockup on the frame it reaches the threshold
+    pub fn check(&mut self, soc: &SoC) -> Option<Lockup> {
+        let lockup = soc.lockup();
+        if lockup.is_some() && lockup == self.lockup {
+            self.frames = self.frames.saturating_add(1);
+        } else {
+            self.lockup = lockup;
+            self.frames = lockup.map_or(0, |_| 1);
+        }
+        if self.frames == self.threshold {self.lockup} else {None}
+    }
+
+    /// Whether the SoC has been locked up for at least the threshold
+    pub fn locked(&self) -> bool {
+        self.frames >= self.threshold
+    }
+}
+
+#[cfg(test)]
+#[cfg_attr(coverage_nightly, coverage(off))]
+mod test {
+    use alloc::vec;
+
+    use crate::bus::io_bus::IOBusConnection;
+
+    use super::*;
+
+    /// Runs a program for a few frames, returns what the detector reported and whether it still considers the SoC locked up
+    fn detect(program: Vec<u8>, sources: u8) -> (Option<Lockup>, bool) {
+        let mut soc = SoC::test_build();
+        soc.set_wram(program);
+        soc.io_bus.borrow_mut().write_io(0xB2, sources);
+
+        let mut detector = LockupDetector::new(3);
+        let mut reported = None;
+        for _ in 0..4 {
+            soc.run_frame();
+            reported = reported.or(detector.check(&soc));
+        }
+        (reported, detector.locked())
+    }
+
+    #[test]
+    fn test_jump_to_self() {
+        // DI, then BR to itself
+        assert_eq!(detect(vec![0xFA, 0xEB, 0xFE], 0x40), (Some(Lockup::Loop(0x00001)), true));
+        // Waiting for an interrupt that can come is not a lockup, the vblank handler at 0x0020 acknowledges it and returns
+        let mut wait = vec![0x01; 0x25];
+        wait[0x00..0x03].copy_from_slice(&[0xFB, 0xEB, 0xFE]);
+        wait[0x18..0x1C].copy_from_slice(&[0x20, 0x00, 0x00, 0x00]);
+        wait[0x20..0x25].copy_from_slice(&[0xB0, 0x40, 0xE6, 0xB6, 0xCF]);
+        assert_eq!(detect(wait, 0x40), (None, false));
+    }
+
+    #[test]
+    fn test_no_lockup() {
+        // The vblank interrupt is always enabled, so it wakes the CPU up every frame even with interrupts disabled
+        assert_eq!(detect(vec![0xFA, 0xF4, 0xEB, 0xFD], 0x00), (None, false));
+        // A loop that makes progress is never reported
+        assert_eq!(detect(vec![0xFA, 0x40, 0xEB, 0xFD], 0x00), (None, false));
+    }
+}
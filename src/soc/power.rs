@@ -0,0 +1,172 @@
+use super::SoC;
+
+/// How much of the console is running, as set by the game through the power ports
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum PowerState {
+    /// Running normally
+    On,
+    /// The LCD is switched off through port 0x14 and the CPU halted, only the keypad interrupt or the low battery NMI wake it up
+    Sleep,
+    /// Switched off through port 0x62 on a WonderSwan Color, nothing runs until the console is started again
+    Off,
+}
+
+impl PowerState {
+    /// Returns the name shown to the user
+    pub fn name(&self) -> &'static str {
+        match self {
+            PowerState::On => "on",
+            PowerState::Sleep => "sleep",
+            PowerState::Off => "off",
+        }
+    }
+}
+
+impl SoC {
+    /// Returns the power state the game put the console in
+    pub fn power_state(&self) -> PowerState {
+        let io_bus = self.io_bus.borrow();
+        if io_bus.powered_off() {
+            PowerState::Off
+        } else if self.cpu.halted() && !io_bus.lcd_enabled() {
+            PowerState::Sleep
+        } else {
+            PowerState::On
+        }
+    }
+
+    /// Returns whether the CPU runs on this tick
+    ///
+    /// While asleep the CPU stays halted through the other interrupts, which are left pending until a key or the NMI wakes it up.
+    pub(super) fn cpu_powered(&self) -> bool {
+        match self.power_state() {
+            PowerState::On => true,
+            PowerState::Sleep => {
+                let io_bus = self.io_bus.borrow();
+                io_bus.peek_ports()[0xB4] & (1 << 1) != 0 || io_bus.nmi_pending()
+            }
+            PowerState::Off => false,
+        }
+    }
+
+    /// Reports the battery as running low or not, to test how the game reacts
+    pub fn set_low_battery(&mut self, low: bool) {
+        self.io_bus.borrow_mut().set_low_battery(low);
+    }
+}
+
+#[cfg(test)]
+#[cfg_attr(coverage_nightly, coverage(off))]
+mod test {
+    use alloc::vec;
+
+    use crate::{bus::io_bus::{keypad::Keys, IOBusConnection, Model}, state::{Snapshot, StateReader, StateWriter}};
+
+    use super::*;
+
+    /// Puts the console to sleep with only the keypad interrupt enabled, the way games do it
+    const SLEEP: [u8; 13] = [
+        0xB0, 0x02, // MOV AL, 0x02
+        0xE6, 0xB2, // OUT 0xB2, AL
+        0xB0, 0x00, // MOV AL, 0x00
+        0xE6, 0x14, // OUT 0x14, AL
+        0xF4,       // HALT
+        0x40,       // INC AW
+        0xEB, 0xFD, // BR -3
+        0x90,       // NOP
+    ];
+
+    #[test]
+    fn test_sleep() {
+        let mut soc = SoC::test_build();
+        soc.set_wram(SLEEP.to_vec());
+        soc.run_frame();
+        soc.run_frame();
+        assert_eq!(soc.power_state(), PowerState::Sleep);
+        // The vblank interrupt stays pending without waking the CPU, and the LCD shows the bare panel
+        assert!(soc.io_bus.borrow().peek_ports()[0xB4] & (1 << 6) != 0);
+        assert!(soc.get_lcd().borrow().iter().all(|channel| *channel == 0xFF));
+
+        let counter = soc.cpu.registers()[0];
+        soc.io_bus.borrow_mut().write_io(0xB5, 0x70);
+        soc.io_bus.borrow_mut().set_key(Keys::A, true);
+        soc.run_frame();
+        assert_ne!(soc.cpu.registers()[0], counter);
+    }
+
+    #[test]
+    fn test_lcd_switches_on_next_line() {
+        let mut soc = SoC::test_build();
+        // A dark background, so that lines that are output differ from the bare panel
+        soc.io_bus.borrow_mut().write_io(0x1C, 0x77);
+        soc.io_bus.borrow_mut().write_io(0x14, 0x00);
+        soc.run_frame();
+        while soc.display.position() != (100, 10) {soc.tick();}
+        soc.io_bus.borrow_mut().write_io(0x14, 0x01);
+        soc.run_frame();
+
+        // The switch is latched on the first dot of line 101, which outputs line 100 as lines are drawn a line late
+        let lcd = soc.get_lcd();
+        let lcd = lcd.borrow();
+        let line = |y: usize| &lcd[y * 224 * 3..(y + 1) * 224 * 3];
+        assert!(line(99).iter().all(|channel| *channel == 0xFF));
+        assert!(line(100).iter().all(|channel| *channel != 0xFF));
+    }
+
+    #[test]
+    fn test_low_battery_nmi() {
+        let mut soc = SoC::test_build();
+        soc.set_wram(SLEEP.to_vec());
+        soc.run_frame();
+
+        // Without the NMI enabled the battery running low goes unnoticed
+        soc.set_low_battery(true);
+        soc.run_frame();
+        assert_eq!(soc.power_state(), PowerState::Sleep);
+        assert_eq!(soc.cpu.last_interrupt, None);
+
+        soc.set_low_battery(false);
+        soc.io_bus.borrow_mut().write_io(0xB7, 0x10);
+        soc.set_low_battery(true);
+        for _ in 0..16 {soc.tick();}
+        assert_eq!(soc.cpu.last_interrupt, Some(2));
+        assert!(!soc.io_bus.borrow().nmi_pending());
+    }
+
+    #[test]
+    fn test_power_off() {
+        let mut soc = SoC::test_build();
+        soc.set_wram(vec![
+            0xB0, 0x01, // MOV AL, 0x01
+            0xE6, 0x62, // OUT 0x62, AL
+            0x40,       // INC AW
+            0xEB, 0xFD, // BR -3
+        ]);
+        soc.run_frame();
+        // Port 0x62 does not exist on the original WonderSwan
+        assert_eq!(soc.power_state(), PowerState::On);
+
+        let mut soc = SoC::test_build();
+        soc.set_model(Model::COLOR);
+        soc.set_wram(vec![
+            0xB0, 0x01, // MOV AL, 0x01
+            0xE6, 0x62, // OUT 0x62, AL
+            0x40,       // INC AW
+            0xEB, 0xFD, // BR -3
+        ]);
+        soc.run_frame();
+        soc.run_frame();
+        assert_eq!(soc.power_state(), PowerState::Off);
+        assert!(soc.get_lcd().borrow().iter().all(|channel| *channel == 0xFF));
+        let registers = soc.cpu.registers();
+        soc.run_frame();
+        assert_eq!(soc.cpu.registers(), registers);
+
+        // The power state survives save states
+        let mut w = StateWriter::new();
+        soc.io_bus.borrow().save_state(&mut w);
+        let other = SoC::test_build();
+        other.io_bus.borrow_mut().load_state(&mut StateReader::new(&w.finish())).unwrap();
+        assert_eq!(other.power_state(), PowerState::Off);
+    }
+}
@@ -0,0 +1,166 @@
+use core::{fmt, time::Duration};
+#[cfg(feature = "std")]
+use std::time::Instant;
+
+use super::SoC;
+
+/// Whether the emulator was built with the profiling feature
+///
+/// Without it no time is measured, and since this is a constant the checks compile away.
+pub const ENABLED: bool = cfg!(feature = "profiling");
+
+/// The subsystems the SoC ticks
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum Subsystem {
+    /// The V30MZ
+    Cpu,
+    /// The general and sound DMAs
+    Dma,
+    /// The sound chip
+    Sound,
+    /// The display chip
+    Display,
+}
+
+impl Subsystem {
+    /// Every subsystem, in the order they are reported
+    pub const ALL: [Self; 4] = [Self::Cpu, Self::Dma, Self::Sound, Self::Display];
+
+    /// Name shown in reports
+    pub fn name(self) -> &'static str {
+        match self {
+            Self::Cpu => "cpu",
+            Self::Dma => "dma",
+            Self::Sound => "sound",
+            Self::Display => "display",
+        }
+    }
+}
+
+/// Wall time spent in each subsystem's ticks
+#[derive(Clone, Copy, Default, PartialEq, Eq, Debug)]
+pub struct FrameProfile {
+    /// Time spent in each subsystem, indexed like [`Subsystem::ALL`]
+    pub times: [Duration; 4],
+}
+
+impl FrameProfile {
+    /// Time spent in a subsystem
+    pub fn time(&self, subsystem: Subsystem) -> Duration {
+        self.times[subsystem as usize]
+    }
+
+    /// Time spent in every subsystem combined
+    pub fn total(&self) -> Duration {
+        self.times.iter().sum()
+    }
+
+    /// Adds the times of another profile to this one
+    pub fn add(&mut self, other: &Self) {
+        for (time, other) in self.times.iter_mut().zip(other.times) {
+            *time += other;
+        }
+    }
+
+    /// Divides every time, used to average profiles over several frames
+    pub fn average(&self, frames: u32) -> Self {
+        Self {times: self.times.map(|time| time / frames.max(1))}
+    }
+}
+
+impl fmt::Display for FrameProfile {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        for subsystem in Subsystem::ALL {
+            write!(f, "{} {:.2}ms, ", subsystem.name(), self.time(subsystem).as_secs_f64() * 1000.0)?;
+        }
+        write!(f, "total {:.2}ms", self.total().as_secs_f64() * 1000.0)
+    }
+}
+
+/// Stand-in for the host's clock, which only the standard library reads
+///
+/// Profiling requires the std feature, so no time is ever measured with it.
+#[cfg(not(feature = "std"))]
+#[derive(Clone, Copy, Debug)]
+pub struct Instant;
+
+#[cfg(not(feature = "std"))]
+impl Instant {
+    /// Returns the only instant there is
+    fn now() -> Self {
+        Self
+    }
+
+    /// Returns no time at all
+    fn elapsed(&self) -> Duration {
+        Duration::ZERO
+    }
+}
+
+/// Measures the time spent in each subsystem over a frame
+#[derive(Default)]
+pub struct Profiler {
+    /// Times of the frame being emulated
+    current: FrameProfile,
+    /// Times of the last finished frame
+    last: FrameProfile,
+}
+
+impl Profiler {
+    /// Starts measuring, returns None when profiling is disabled
+    #[inline(always)]
+    pub fn start(&self) -> Option<Instant> {
+        if ENABLED {Some(Instant::now())} else {None}
+    }
+
+    /// Adds the time since `start` to a subsystem
+    #[inline(always)]
+    pub fn stop(&mut self, subsystem: Subsystem, start: Option<Instant>) {
+        if let Some(start) = start {
+            self.current.times[subsystem as usize] += start.elapsed();
+        }
+    }
+
+    /// Finishes the current frame
+    pub fn end_frame(&mut self) {
+        if ENABLED {self.last = core::mem::take(&mut self.current)}
+    }
+}
+
+impl SoC {
+    /// Returns the time spent in each subsystem over the last frame, or None without the profiling feature
+    pub fn profile(&self) -> Option<FrameProfile> {
+        if ENABLED {Some(self.profiler.last)} else {None}
+    }
+}
+
+#[cfg(test)]
+#[cfg_attr(coverage_nightly, coverage(off))]
+mod test {
+    use alloc::string::ToString;
+
+    use super::*;
+
+    #[test]
+    fn test_frame_profile() {
+        let mut soc = SoC::test_build();
+        while !soc.tick() {}
+
+        assert_eq!(soc.profile().is_some(), ENABLED);
+        if let Some(profile) = soc.profile() {
+            assert!(profile.time(Subsystem::Cpu) > Duration::ZERO);
+            assert!(profile.time(Subsystem::Display) > Duration::ZERO);
+        }
+    }
+
+    #[test]
+    fn test_profile_average() {
+        let mut profile = FrameProfile {times: [1, 0, 2, 3].map(Duration::from_millis)};
+        profile.add(&profile.clone());
+        assert_eq!(profile.total(), Duration::from_millis(12));
+
+        let average = profile.average(2);
+        assert_eq!(average.time(Subsystem::Display), Duration::from_millis(3));
+        assert_eq!(average.to_string(), "cpu 1.00ms, dma 0.00ms, sound 2.00ms, display 3.00ms, total 6.00ms");
+    }
+}
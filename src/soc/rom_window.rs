@@ -0,0 +1,135 @@
+use alloc::{collections::BTreeSet, format, string::{String, ToString}, vec::Vec};
+use core::fmt::Write;
+
+use crate::{cartridge::Cartridge, cpu::disasm::{disassemble, InstructionKind}};
+
+use super::SoC;
+
+/// Segments exported when none are given: both ROM banks and the last segment of the linear window, which holds the boot code
+pub const DEFAULT_SEGMENTS: [u8; 3] = [0x2, 0x3, 0xF];
+/// Bytes per line of the hex dump
+const DUMP_WIDTH: usize = 16;
+
+/// ANSI escape codes used when exporting with colors, meant for viewing with `less -R`
+const DIM: &str = "\x1b[2m";
+const GREEN: &str = "\x1b[32m";
+const CYAN: &str = "\x1b[36m";
+const YELLOW: &str = "\x1b[33m";
+const RED: &str = "\x1b[31m";
+const BOLD: &str = "\x1b[1m";
+const RESET: &str = "\x1b[0m";
+
+/// Wraps text in an escape code when colors are enabled
+fn paint(text: &str, code: &str, color: bool) -> String {
+    if color && !code.is_empty() {format!("{}{}{}", code, text, RESET)} else {text.to_string()}
+}
+
+/// Reads a byte of a ROM segment through the current banks, without the access being seen by anything else
+fn peek(cartridge: &Cartridge, addr: u32) -> u8 {
+    match addr {
+        0x20000..=0x2FFFF => cartridge.read_rom_0(addr),
+        0x30000..=0x3FFFF => cartridge.read_rom_1(addr),
+        _ => cartridge.read_rom_ex(addr),
+    }
+}
+
+impl SoC {
+    /// Dumps the ROM segments visible through the current banks, segment 2 being ROM bank 0, 3 bank 1 and 4 to F the linear window
+    ///
+    /// Each segment starts with the bank registers and the part of the ROM it is mapped to, followed by a hex dump and a disassembly.
+    /// The disassembly is a linear sweep from the start of the segment, so data between instructions is disassembled as well.
+    /// Targets of near branches get a label and the instruction the CPU is about to execute is marked.
+    /// With `color`, ANSI escape codes tint padding, text, branches, I/O and invalid instructions.
+    pub fn export_rom_window(&self, segments: &[u8], color: bool) -> String {
+        let mem_bus = self.mem_bus.borrow();
+        let cartridge = mem_bus.cartridge.borrow();
+        let registers = cartridge.bank_registers().iter().map(|(name, value)| format!("{}={:02X}", name, value)).collect::<Vec<_>>().join(" ");
+        let cpu = self.cpu.registers();
+        // PS and PC, in the order of the CPU's register names
+        let pc = (cpu[6], cpu[12]);
+
+        let mut export = format!("; Frame {}, {}\n", self.frames, registers);
+        for &segment in segments {
+            let base = (segment as u32) << 16;
+            let bytes: Vec<u8> = (0..0x10000).map(|offset| peek(&cartridge, base | offset)).collect();
+            let window = match segment {
+                0x2 => "ROM bank 0",
+                0x3 => "ROM bank 1",
+                _ => "linear window",
+            };
+            let start = cartridge.rom_offset(base).unwrap_or_default();
+            writeln!(export, "\n; Segment {:X}000, {}, mapped to ROM {:06X}-{:06X}", segment, window, start, start + 0xFFFF).unwrap();
+
+            writeln!(export, "\n; Hex dump").unwrap();
+            for (line, chunk) in bytes.chunks(DUMP_WIDTH).enumerate() {
+                let hex = chunk.iter().map(|&byte| {
+                    let code = match byte {0x00 | 0xFF => DIM, 0x20..=0x7E => GREEN, _ => ""};
+                    paint(&format!("{:02X}", byte), code, color)
+                }).collect::<Vec<_>>().join(" ");
+                let text: String = chunk.iter().map(|&byte| if (0x20..=0x7E).contains(&byte) {byte as char} else {'.'}).collect();
+                writeln!(export, "{:X}000:{:04X}  {}  |{}|", segment, line * DUMP_WIDTH, hex, text).unwrap();
+            }
+
+            writeln!(export, "\n; Disassembly").unwrap();
+            let fetch = |offset: u16| bytes[offset as usize];
+            let mut instructions = Vec::new();
+            let mut offset = 0usize;
+            while offset < bytes.len() {
+                let instruction = disassemble(fetch, offset as u16);
+                offset += instruction.len as usize;
+                instructions.push((offset - instruction.len as usize, instruction));
+            }
+            let labels: BTreeSet<u16> = instructions.iter().filter_map(|(_, instruction)| instruction.target).collect();
+            for (offset, instruction) in instructions {
+                let offset = offset as u16;
+                if labels.contains(&offset) {writeln!(export, "{}", paint(&format!("loc_{:04X}:", offset), BOLD, color)).unwrap()}
+                let hex: String = (0..instruction.len as u16).map(|i| format!("{:02X}", fetch(offset.wrapping_add(i)))).collect();
+                let code = match instruction.kind {
+                    InstructionKind::Normal => "",
+                    InstructionKind::Branch => CYAN,
+                    InstructionKind::Io => YELLOW,
+                    InstructionKind::Invalid => RED,
+                };
+                let text = paint(&instruction.text, code, color);
+                let at_pc = pc == ((segment as u16) << 12, offset);
+                writeln!(export, "{:X}000:{:04X}  {:<12}  {}{}", segment, offset, hex, text, if at_pc {"  ; <- PC"} else {""}).unwrap();
+            }
+        }
+        export
+    }
+}
+
+#[cfg(test)]
+#[cfg_attr(coverage_nightly, coverage(off))]
+mod test {
+    use alloc::vec;
+
+    use crate::cartridge::Mapper;
+
+    use super::*;
+
+    #[test]
+    fn test_export_rom_window() {
+        // Every 64 KB bank starts with a jump to itself followed by its index
+        let mut rom = vec![0x90; 0x100000];
+        for bank in 0..16 {
+            rom[bank << 16..(bank << 16) + 3].copy_from_slice(&[0xEB, 0xFE, bank as u8]);
+        }
+        let mut soc = SoC::test_build();
+        *soc.mem_bus.borrow().cartridge.borrow_mut() = Cartridge::new(Mapper::B_2001, Vec::new(), rom, false);
+        soc.mem_bus.borrow().cartridge.borrow_mut().write_rom_bank_0(0x05);
+        soc.set_wram(vec![0xEA, 0x00, 0x00, 0x00, 0x30]); // BR 3000:0000
+        for _ in 0..20 {soc.tick();}
+
+        let export = soc.export_rom_window(&[0x2, 0x3], false);
+        assert!(export.contains("Segment 2000, ROM bank 0, mapped to ROM 050000-05FFFF"));
+        assert!(export.contains("2000:0000  EB FE 05 90"));
+        assert!(export.contains("loc_0000:\n2000:0000  EBFE          BR      0000\n"));
+        assert!(export.contains("3000:0000  EBFE          BR      0000  ; <- PC"));
+        assert!(!export.contains('\x1b'));
+
+        let colored = soc.export_rom_window(&[0xF], true);
+        assert!(colored.contains(&format!("{}BR      0000{}", CYAN, RESET)));
+        assert!(colored.contains("mapped to ROM 0F0000-0FFFFF"));
+    }
+}
@@ -0,0 +1,204 @@
+use alloc::vec::Vec;
+
+use crate::{display::timing::DisplayTiming, state::{verify_checksums, write_checksums, Snapshot, StateError, StateReader, StateWriter}, storage::SaveMedia};
+
+use super::SoC;
+
+/// The SoC's state contains every component along with the clocks the SoC keeps between them
+///
+/// The ROM, options and anything only kept for diagnostics, such as the heat map or profiler, are not part of it.
+/// Since version 2 the components are followed by their checksums, which are all checked before any of them is loaded.
+/// States can be taken on any tick, not only between frames: every component saves whatever it carries from one tick to the next,
+/// including the display's position within the line and the screen and sprite data it has fetched, and recomputes what it derives from them on load.
+/// Components saved in the SoC's state, each with a checksum
+const COMPONENTS: usize = 7;
+
+impl Snapshot for SoC {
+    const TAG: [u8; 4] = *b"WSOC";
+    const VERSION: u16 = 2;
+
+    fn save_fields(&self, w: &mut StateWriter) {
+        w.write_u32(self.cycles as u32);
+        w.write_u64(self.sample_acc);
+        w.write_u8(self.sdma_clock);
+        w.write_u64(self.frames);
+
+        let start = w.position();
+        self.cpu.save_state(w);
+        self.gdma.save_state(w);
+        self.sdma.save_state(w);
+        self.sound.save_state(w);
+        self.display.save_state(w);
+        self.mem_bus.borrow().save_state(w);
+        self.io_bus.borrow().save_state(w);
+        write_checksums(w, start);
+    }
+
+    fn load_fields(&mut self, r: &mut StateReader, version: u16) -> Result<(), StateError> {
+        let (cycles, sample_acc, sdma_clock, frames) = (r.read_u32()? as usize, r.read_u64()?, r.read_u8()?, r.read_u64()?);
+        if cycles >= DisplayTiming::LONGEST.frame_ticks() {return Err(StateError::Mismatch("frame length"))}
+        if version >= 2 {verify_checksums(r.rest(), COMPONENTS)?}
+
+        self.cpu.load_state(r)?;
+        self.gdma.load_state(r)?;
+        self.sdma.load_state(r)?;
+        self.sound.load_state(r)?;
+        self.display.load_state(r)?;
+        self.mem_bus.borrow_mut().load_state(r)?;
+        self.io_bus.borrow_mut().load_state(r)?;
+        (self.cycles, self.sample_acc, self.sdma_clock, self.frames) = (cycles, sample_acc, sdma_clock, frames);
+        Ok(())
+    }
+}
+
+impl SoC {
+    /// Saves the state of the whole console
+    pub fn save(&self) -> Vec<u8> {
+        let mut w = StateWriter::new();
+        self.save_state(&mut w);
+        w.finish()
+    }
+
+    /// Loads a state made by [`SoC::save`] for the same ROM
+    ///
+    /// Components are loaded one after the other, so if one of them refuses its part the SoC is put back the way it was.
+    pub fn load(&mut self, state: &[u8]) -> Result<(), StateError> {
+        let backup = self.save();
+        let result = self.load_state(&mut StateReader::new(state));
+        if result.is_err() {
+            self.load_state(&mut StateReader::new(&backup)).unwrap();
+        }
+        result
+    }
+
+    /// Loads a state made by [`SoC::save`] while keeping the save media as they are, see [`SaveMedia`]
+    ///
+    /// This is how the front-end resets the game, by loading the state the SoC was in before its first frame:
+    /// a plain load would also take the game's saves back to that point, as states hold SRAM and the EEPROMs.
+    pub fn load_keeping_saves(&mut self, state: &[u8]) -> Result<(), StateError> {
+        let saves = SaveMedia::from_io_bus(&self.io_bus.borrow());
+        self.load(state)?;
+        {
+            let mut io_bus = self.io_bus.borrow_mut();
+            io_bus.ieeprom.contents = saves.ieeprom;
+            if let Some(eeprom) = &mut io_bus.eeprom {eeprom.contents = saves.eeprom}
+            io_bus.cartridge.borrow_mut().sram = saves.sram;
+        }
+        self.restore_rtc(&saves.rtc);
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+#[cfg_attr(coverage_nightly, coverage(off))]
+mod test {
+    use alloc::vec;
+
+    use super::*;
+
+    /// Returns the sync hashes of the next few frames
+    fn run(soc: &mut SoC, frames: usize) -> Vec<u64> {
+        (0..frames).map(|_| {
+            soc.run_frame();
+            soc.sync_hash(0)
+        }).collect()
+    }
+
+    #[test]
+    fn test_save_load() {
+        let mut soc = SoC::test_build();
+        // Sets up a timer and line compare interrupts so that there is some state to restore
+        soc.set_wram(vec![
+            0xB0, 0x10, // MOV AL, 0x10
+            0xE6, 0x03, // OUT 0x03, AL
+            0x40,       // INC AW
+            0x40,       // INC AW
+            0xEB, 0xFC, // BR -4
+        ]);
+        run(&mut soc, 2);
+
+        let state = soc.save();
+        let expected = run(&mut soc, 3);
+        let registers = soc.cpu.registers();
+
+        soc.load(&state).unwrap();
+        assert_eq!(soc.frames, 2);
+        assert_eq!(run(&mut soc, 3), expected);
+        assert_eq!(soc.cpu.registers(), registers);
+
+        // Loading into another SoC gives back the same state
+        let mut other = SoC::test_build();
+        other.load(&state).unwrap();
+        assert_eq!(other.save(), state);
+    }
+
+    #[test]
+    fn test_load_keeping_saves() {
+        let mut soc = SoC::test_build();
+        soc.io_bus.borrow().cartridge.borrow_mut().sram = vec![0; 0x100];
+        soc.set_wram(vec![0x40, 0xEB, 0xFD]);
+        let boot = soc.save();
+        run(&mut soc, 2);
+
+        soc.io_bus.borrow().cartridge.borrow_mut().sram[0x10] = 0x55;
+        soc.io_bus.borrow_mut().ieeprom.contents[0x60] ^= 0xFF;
+        let ieeprom = soc.io_bus.borrow().ieeprom.contents.clone();
+        soc.load_keeping_saves(&boot).unwrap();
+        assert_eq!(soc.frames, 0);
+        assert_eq!(soc.io_bus.borrow().cartridge.borrow().sram[0x10], 0x55);
+        assert_eq!(soc.io_bus.borrow().ieeprom.contents, ieeprom);
+    }
+
+    #[test]
+    fn test_save_load_mid_frame() {
+        // Shows sprites in a bright shade over the screens, then keeps scrolling screen 1 and changing a tile so that every line is drawn differently
+        let mut wram = vec![0x01; 0x1080];
+        wram[..26].copy_from_slice(&[
+            0xB0, 0x07, 0xE6, 0x00, // DISP_CTRL = 0x07, every layer without windows
+            0xB0, 0x08, 0xE6, 0x04, // SPR_BASE = 0x1000
+            0xB0, 0x70, 0xE6, 0x30, // Color 1 of sprite palette 0 is shade 7
+            0xB0, 0x20, 0xE6, 0x06, // SPR_COUNT = 32
+            0xFE, 0xC0,             // INC AL
+            0xE6, 0x10,             // OUT 0x10, AL
+            0x00, 0x06, 0x00, 0x20, // ADD [0x2000], AL
+            0xEB, 0xF6,             // BR -10
+        ]);
+        // Sprites with the priority bit spread across the screen
+        for i in 0..32 {
+            wram[0x1000 + i * 4..0x1004 + i * 4].copy_from_slice(&[0x00, 0x20, i as u8 * 4, i as u8 * 7]);
+        }
+        let mut soc = SoC::test_build();
+        soc.set_wram(wram);
+        run(&mut soc, 2);
+
+        // States taken at arbitrary dots, in the middle of lines and instructions, continue exactly like the SoC they were taken from
+        let mut seed = 0x2545F491u32;
+        for _ in 0..8 {
+            seed = seed.wrapping_mul(1_103_515_245).wrapping_add(12_345);
+            let (line, dot) = (1 + (seed >> 8) % 143, 1 + (seed >> 16) % 223);
+            while soc.display.position() != (line as u8, dot as u8) {soc.tick();}
+            let state = soc.save();
+            let mut other = SoC::test_build();
+            other.load(&state).unwrap();
+            assert_eq!(run(&mut other, 2), run(&mut soc, 2), "desynced after loading a state taken on line {} dot {}", line, dot);
+            assert_eq!(other.save(), soc.save());
+        }
+    }
+
+    #[test]
+    fn test_load_failure() {
+        let mut soc = SoC::test_build();
+        run(&mut soc, 1);
+        let state = soc.save();
+
+        let before = soc.save();
+        assert_eq!(soc.load(&state[..state.len() - 1]), Err(StateError::UnexpectedEnd));
+        assert_eq!(soc.save(), before);
+
+        // A flipped bit in the CPU's registers is caught before anything is loaded
+        let mut corrupted = state.clone();
+        corrupted[10 + 21 + 10] ^= 1;
+        assert_eq!(soc.load(&corrupted), Err(StateError::Checksum {component: *b"V30M"}));
+        assert_eq!(soc.save(), before);
+    }
+}
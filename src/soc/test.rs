@@ -1,3 +1,5 @@
+use alloc::vec;
+
 use crate::assert_eq_hex;
 
 use super::*;
@@ -23,6 +25,10 @@ impl SoC {
         Rc::clone(&self.mem_bus)
     }
 
+    pub fn set_model(&mut self, model: crate::bus::io_bus::Model) {
+        self.io_bus.borrow_mut().model = model;
+    }
+
     pub fn tick_cpu_no_cycles(&mut self) {
         self.cpu.tick_ignore_cycles();
     }
@@ -34,4 +40,108 @@ fn test_io_open_bus() {
     let mut soc = SoC::test_build();
     assert_eq_hex!(soc.read_io(0x100), 0x90);
     assert_eq_hex!(soc.read_io(0x1B9), 0x90);
-}
\ No newline at end of file
+}
+#[test]
+fn test_options_applied_at_frame_end() {
+    let mut soc = SoC::test_build();
+    let options = soc.options();
+    options.update(|options| {
+        options.vram_stalls = true;
+        options.invalid_opcode = crate::cpu::v30mz::InvalidOpcodeBehavior::Fault;
+    });
+    assert!(!soc.mem_bus.borrow().vram_stalls);
+
+    while !soc.tick() {}
+    assert!(soc.mem_bus.borrow().vram_stalls);
+    assert_eq!(soc.cpu.invalid_opcode, crate::cpu::v30mz::InvalidOpcodeBehavior::Fault);
+    assert!(soc.applied.mute);
+}
+#[test]
+#[cfg(feature = "std")]
+fn test_audio_capture_matches_emulated_samples() {
+    let dir = std::env::temp_dir();
+    let capture = |mute: bool, name: &str| {
+        let mut soc = SoC::test_build();
+        soc.options().update(|options| options.mute = mute);
+        soc.apply_options();
+        // Channel 2 in voice mode plays whatever is written to the PCM port at full volume
+        soc.write_io(0x90, 0x22);
+        soc.write_io(0x94, 0x05);
+        let path = dir.join(name).to_string_lossy().to_string();
+        soc.start_audio_capture(&path).unwrap();
+        for pcm in [0x00, 0x40, 0xC0, 0xFF] {
+            soc.write_io(0x89, pcm);
+            soc.run_frame();
+        }
+        let samples = soc.stop_audio_capture().unwrap().unwrap();
+        assert_eq!(soc.stop_audio_capture().unwrap(), None);
+        let wav = std::fs::read(&path).unwrap();
+        std::fs::remove_file(&path).unwrap();
+        assert_eq!(wav.len(), 44 + 4 * samples as usize);
+        let played: Vec<_> = soc.samples.lock().unwrap().iter().copied().collect();
+        (wav, played)
+    };
+
+    // The capture holds the samples handed to playback, before any stretching, and does not depend on playback being muted
+    let (wav, played) = capture(false, "wondercrab-capture-played.wav");
+    assert_eq!(wav[44..], played.iter().flat_map(|sample| [sample.0, sample.1]).flat_map(|side| ((side ^ 0x8000) as i16).to_le_bytes()).collect::<Vec<_>>());
+    assert!(wav[44..].iter().any(|sample| *sample != wav[44]));
+    let (muted, _) = capture(true, "wondercrab-capture-muted.wav");
+    assert_eq!(muted, wav);
+}
+#[test]
+fn test_opcode_stats() {
+    let mut soc = SoC::test_build();
+    soc.set_wram(vec![
+        0x40,             // INC AW
+        0x83, 0xF8, 0x10, // CMP AW, 0x10
+        0xEB, 0xFA,       // BR -6
+    ]);
+    assert_eq!(soc.opcode_report(), None);
+
+    soc.options().update(|options| options.opcode_stats = true);
+    soc.run_frame();
+    soc.run_frame();
+    let stats = soc.cpu.opcode_stats.as_ref().unwrap();
+    let loops = stats.count(0x40, 0);
+    assert!(loops > 0);
+    assert_eq!(stats.count(0x83, 7), loops);
+    assert!(stats.count(0xEB, 0).abs_diff(loops) <= 1);
+    assert_eq!(stats.count(0x83, 0), 0);
+    assert!(soc.opcode_report().unwrap().contains("83/7  CMP"));
+
+    soc.options().update(|options| options.opcode_stats = false);
+    soc.run_frame();
+    assert_eq!(soc.opcode_report(), None);
+}
+#[test]
+fn test_cpu_clock() {
+    let instructions = |clock: u16| {
+        let mut soc = SoC::test_build();
+        soc.set_wram(vec![0xEB, 0xFE]);
+        soc.options().update(|options| options.cpu_clock = clock);
+        soc.run_frame();
+        let report = soc.run_frame();
+        assert_eq!(soc.frames(), 2);
+        report.instructions
+    };
+    let nominal = instructions(100);
+    assert!(instructions(200).abs_diff(2 * nominal) <= 2);
+    assert!(instructions(50).abs_diff(nominal / 2) <= 2);
+}
+#[test]
+fn test_pure_options() {
+    let mut soc = SoC::test_build();
+    soc.options().update(|options| {
+        options.pure = true;
+        options.vram_stalls = false;
+        options.sprite_limit = crate::options::SpriteLimit::Unlimited;
+        options.cpu_clock = 200;
+    });
+    while !soc.tick() {}
+    assert!(soc.mem_bus.borrow().vram_stalls);
+    assert_eq!(soc.applied.cpu_clock, 100);
+    assert_eq!(soc.display.sprites_per_line, crate::options::SpriteLimit::Hardware.sprites_per_line());
+    // Options without an effect on the output are kept
+    assert!(soc.applied.mute);
+}
@@ -0,0 +1,261 @@
+use alloc::{boxed::Box, format, string::String, vec, vec::Vec};
+use core::fmt::Write;
+
+use crate::{display::PaletteFormat, png::encode_png};
+
+use super::SoC;
+
+/// Size of the part of WRAM the display reads from
+const VRAM_SIZE: usize = 0x10000;
+/// Entries in each side of a screen's map
+const MAP_SIZE: usize = 32;
+/// Entries in the sprite table
+const SPRITES: usize = 128;
+/// Address of palette RAM
+const PALETTE_RAM: usize = 0xFE00;
+/// Tiles per row of the tile sheet drawn under the maps
+const SHEET_COLUMNS: usize = 64;
+
+/// A copy of video memory along with the registers locating its tables
+#[derive(Clone)]
+pub struct VramSnapshot {
+    /// Frames finished when the snapshot was taken
+    pub frame: u64,
+    /// The first 64 KB of WRAM, holding tiles, maps, the sprite table and palette RAM
+    vram: Box<[u8]>,
+    /// Format of the tiles
+    format: PaletteFormat,
+    /// Whether the console was in color mode, which doubles the tiles and addressable VRAM
+    color: bool,
+    /// Addresses of screen 1 and screen 2's maps
+    map_bases: [u16; 2],
+    /// Address of the sprite table
+    sprite_base: u16,
+}
+
+impl VramSnapshot {
+    /// Returns the amount of tiles and the bytes each takes, in the snapshot's format
+    fn tile_layout(&self) -> (usize, usize, usize) {
+        match self.format {
+            PaletteFormat::PLANAR_2BPP => (if self.color {1024} else {512}, 0x2000, 16),
+            PaletteFormat::PLANAR_4BPP | PaletteFormat::PACKED_4BPP => (1024, 0x4000, 32),
+        }
+    }
+
+    /// Returns the bytes of a tile
+    fn tile(&self, index: usize) -> &[u8] {
+        let (_, base, size) = self.tile_layout();
+        &self.vram[base + index * size..base + (index + 1) * size]
+    }
+
+    /// Returns an entry of one of the maps, screens being 0 and 1
+    fn map_entry(&self, screen: usize, x: usize, y: usize) -> u16 {
+        let addr = self.map_bases[screen] as usize + (y * MAP_SIZE + x) * 2;
+        u16::from_le_bytes([self.vram[addr], self.vram[addr + 1]])
+    }
+
+    /// Returns an entry of the sprite table
+    fn sprite(&self, index: usize) -> [u8; 4] {
+        let addr = self.sprite_base as usize + index * 4;
+        self.vram[addr..addr + 4].try_into().unwrap()
+    }
+
+    /// Returns a color of palette RAM
+    fn color(&self, index: usize) -> u16 {
+        u16::from_le_bytes([self.vram[PALETTE_RAM + index * 2], self.vram[PALETTE_RAM + index * 2 + 1]])
+    }
+
+    /// Returns the color index of a pixel of a tile
+    fn pixel(&self, index: usize, x: usize, y: usize) -> u8 {
+        let tile = self.tile(index);
+        let bit = 7 - x;
+        match self.format {
+            PaletteFormat::PLANAR_2BPP => ((tile[y * 2] >> bit) & 1) | (((tile[y * 2 + 1] >> bit) & 1) << 1),
+            PaletteFormat::PLANAR_4BPP => (0..4).map(|plane| ((tile[y * 4 + plane] >> bit) & 1) << plane).sum(),
+            PaletteFormat::PACKED_4BPP => (tile[y * 4 + x / 2] >> if x.is_multiple_of(2) {4} else {0}) & 0x0F,
+        }
+    }
+
+    /// Returns the gray a pixel is drawn with, from white for color 0 to black for the last color
+    fn gray(&self, index: usize, x: usize, y: usize) -> u8 {
+        let step = if self.format == PaletteFormat::PLANAR_2BPP {85} else {17};
+        0xFF - self.pixel(index, x, y) * step
+    }
+}
+
+/// A map entry that changed between two snapshots
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub struct MapChange {
+    /// Screen of the map, 1 or 2
+    pub screen: u8,
+    /// Column of the entry
+    pub x: u8,
+    /// Row of the entry
+    pub y: u8,
+    /// The entry in the older snapshot
+    pub before: u16,
+    /// The entry in the newer snapshot
+    pub after: u16,
+}
+
+/// What a game changed in video memory between two snapshots
+///
+/// Tiles are compared in the format of the newer snapshot, and maps and the sprite table at the addresses each snapshot had them at,
+/// so a game moving a map shows up as the entries on screen changing.
+pub struct VramDiff {
+    /// The older snapshot
+    pub before: VramSnapshot,
+    /// The newer snapshot
+    pub after: VramSnapshot,
+    /// Indices of the tiles whose data changed
+    pub tiles: Vec<u16>,
+    /// Map entries that changed
+    pub map_entries: Vec<MapChange>,
+    /// Indices of the sprites whose entry changed
+    pub sprites: Vec<u8>,
+    /// Indices of the colors of palette RAM that changed, palette times 16 plus color
+    pub colors: Vec<u8>,
+}
+
+impl VramDiff {
+    /// Compares two snapshots, the first one being the older
+    pub fn between(before: VramSnapshot, after: VramSnapshot) -> Self {
+        let (tile_count, _, _) = after.tile_layout();
+        let tiles = (0..tile_count).filter(|index| before.tile(*index) != after.tile(*index)).map(|index| index as u16).collect();
+        let map_entries = (0..2).flat_map(|screen| (0..MAP_SIZE * MAP_SIZE).map(move |i| (screen, i % MAP_SIZE, i / MAP_SIZE)))
+            .filter(|(screen, x, y)| before.map_entry(*screen, *x, *y) != after.map_entry(*screen, *x, *y))
+            .map(|(screen, x, y)| MapChange {screen: screen as u8 + 1, x: x as u8, y: y as u8, before: before.map_entry(screen, x, y), after: after.map_entry(screen, x, y)})
+            .collect();
+        let sprites = (0..SPRITES).filter(|index| before.sprite(*index) != after.sprite(*index)).map(|index| index as u8).collect();
+        let colors = if before.color || after.color {
+            (0..256).filter(|index| before.color(*index) != after.color(*index)).map(|index| index as u8).collect()
+        } else {Vec::new()};
+        Self {before, after, tiles, map_entries, sprites, colors}
+    }
+
+    /// Returns whether nothing changed
+    pub fn is_empty(&self) -> bool {
+        self.tiles.is_empty() && self.map_entries.is_empty() && self.sprites.is_empty() && self.colors.is_empty()
+    }
+
+    /// Lists every change, with the addresses of the tables and the values before and after
+    pub fn report(&self) -> String {
+        let (before, after) = (&self.before, &self.after);
+        let mut report = String::new();
+        writeln!(report, "VRAM changes from frame {} to frame {}", before.frame, after.frame).unwrap();
+
+        let (_, tile_base, tile_size) = after.tile_layout();
+        writeln!(report, "{} tiles changed", self.tiles.len()).unwrap();
+        for tile in &self.tiles {
+            writeln!(report, "  tile {:03X} at {:04X}", tile, tile_base + *tile as usize * tile_size).unwrap();
+        }
+        for screen in 0..2 {
+            let changes: Vec<_> = self.map_entries.iter().filter(|change| change.screen as usize == screen + 1).collect();
+            writeln!(report, "screen {} map at {:04X} -> {:04X}, {} entries changed", screen + 1, before.map_bases[screen], after.map_bases[screen], changes.len()).unwrap();
+            for change in changes {
+                writeln!(report, "  ({:2}, {:2}) {:04X} -> {:04X}", change.x, change.y, change.before, change.after).unwrap();
+            }
+        }
+        writeln!(report, "sprite table at {:04X} -> {:04X}, {} sprites changed", before.sprite_base, after.sprite_base, self.sprites.len()).unwrap();
+        for sprite in &self.sprites {
+            let [a, b] = [before, after].map(|snapshot| snapshot.sprite(*sprite as usize).map(|byte| format!("{:02X}", byte)).concat());
+            writeln!(report, "  sprite {:3} {} -> {}", sprite, a, b).unwrap();
+        }
+        if !self.colors.is_empty() {
+            writeln!(report, "{} colors changed", self.colors.len()).unwrap();
+            for color in &self.colors {
+                writeln!(report, "  palette {:2} color {:2} {:03X} -> {:03X}", color / 16, color % 16, before.color(*color as usize), after.color(*color as usize)).unwrap();
+            }
+        }
+        report
+    }
+
+    /// Draws the newer snapshot as a PNG with what changed tinted red
+    ///
+    /// Screen 1 and screen 2's maps are drawn side by side at the top, 256x256 pixels each, with the whole tile sheet under them.
+    /// Pixels are the grays of their color indices, as palettes would hide changes to tiles drawn with the same colors.
+    pub fn png(&self) -> Vec<u8> {
+        let after = &self.after;
+        let (tile_count, _, _) = after.tile_layout();
+        let width = 2 * MAP_SIZE * 8;
+        let height = MAP_SIZE * 8 + tile_count.div_ceil(SHEET_COLUMNS) * 8;
+        let mut pixels = vec![0; width * height * 3];
+        let mut plot = |x: usize, y: usize, gray: u8, changed: bool| {
+            let rgb = if changed {[gray / 2 + 0x80, gray / 2, gray / 2]} else {[gray; 3]};
+            pixels[(y * width + x) * 3..(y * width + x) * 3 + 3].copy_from_slice(&rgb);
+        };
+
+        let changed_tiles: Vec<bool> = (0..tile_count).map(|index| self.tiles.contains(&(index as u16))).collect();
+        for screen in 0..2 {
+            for (y, x) in (0..MAP_SIZE).flat_map(|y| (0..MAP_SIZE).map(move |x| (y, x))) {
+                let entry = after.map_entry(screen, x, y);
+                let tile = ((entry & 0x1FF) | ((entry & 0x2000) >> 4)) as usize % tile_count;
+                let changed = changed_tiles[tile] || self.map_entries.iter().any(|change| change.screen as usize == screen + 1 && (change.x as usize, change.y as usize) == (x, y));
+                for (py, px) in (0..8).flat_map(|py| (0..8).map(move |px| (py, px))) {
+                    let (tx, ty) = (if entry & 0x4000 != 0 {7 - px} else {px}, if entry & 0x8000 != 0 {7 - py} else {py});
+                    plot(screen * MAP_SIZE * 8 + x * 8 + px, y * 8 + py, after.gray(tile, tx, ty), changed);
+                }
+            }
+        }
+        for (tile, changed) in changed_tiles.iter().enumerate() {
+            let (x, y) = (tile % SHEET_COLUMNS * 8, MAP_SIZE * 8 + tile / SHEET_COLUMNS * 8);
+            for (py, px) in (0..8).flat_map(|py| (0..8).map(move |px| (py, px))) {
+                plot(x + px, y + py, after.gray(tile, px, py), *changed);
+            }
+        }
+        encode_png(width, height, &pixels)
+    }
+}
+
+impl SoC {
+    /// Copies video memory, to be compared with a later copy through [`VramDiff::between`]
+    pub fn vram_snapshot(&self) -> VramSnapshot {
+        let vram = self.mem_bus.borrow().wram[..VRAM_SIZE].to_vec().into_boxed_slice();
+        let mut io_bus = self.io_bus.borrow_mut();
+        let color = io_bus.color_mode();
+        VramSnapshot {
+            frame: self.frames, vram, format: io_bus.palette_format(), color,
+            map_bases: [io_bus.screen_1_base(color), io_bus.screen_2_base(color)],
+            sprite_base: io_bus.sprite_base(color),
+        }
+    }
+}
+
+#[cfg(test)]
+#[cfg_attr(coverage_nightly, coverage(off))]
+mod test {
+    use crate::bus::{io_bus::IOBusConnection, mem_bus::MemBusConnection};
+
+    use super::*;
+
+    #[test]
+    fn test_vram_diff() {
+        let mut soc = SoC::test_build();
+        // Screen 1's map at 0x0800, screen 2's at 0x1000, the sprite table at 0x1800
+        soc.write_io(0x07, 0x21);
+        soc.write_io(0x04, 0x0C);
+        let before = soc.vram_snapshot();
+        assert!(VramDiff::between(before.clone(), soc.vram_snapshot()).is_empty());
+
+        soc.write_mem(0x2000 + 5 * 16 + 3, 0xAA);
+        soc.write_mem(0x1000 + (2 * 32 + 7) * 2, 0x05);
+        soc.write_mem(0x1800 + 9 * 4, 0x42);
+        soc.run_frame();
+        let diff = VramDiff::between(before, soc.vram_snapshot());
+
+        assert_eq!(diff.tiles, [5]);
+        assert_eq!(diff.map_entries, [MapChange {screen: 2, x: 7, y: 2, before: 0x0101, after: 0x0105}]);
+        assert_eq!(diff.sprites, [9]);
+        assert!(diff.colors.is_empty());
+        let report = diff.report();
+        assert!(report.contains("from frame 0 to frame 1"));
+        assert!(report.contains("tile 005 at 2050"));
+        assert!(report.contains("( 7,  2) 0101 -> 0105"));
+        assert!(report.contains("sprite   9 01010101 -> 42010101"));
+
+        let png = diff.png();
+        assert_eq!(&png[1..4], b"PNG");
+        assert_eq!(u32::from_be_bytes(png[16..20].try_into().unwrap()), 512);
+        assert_eq!(u32::from_be_bytes(png[20..24].try_into().unwrap()), 256 + 8 * 8);
+    }
+}
@@ -0,0 +1,99 @@
+use alloc::{collections::VecDeque, vec::Vec};
+
+use crate::sync::{Arc, Mutex};
+
+/// Most samples buffered by default, a quarter of a second at 24kHz
+///
+/// Enough to ride out a late audio callback, while keeping the latency low once emulation runs ahead of playback.
+pub const DEFAULT_CAPACITY: usize = 6000;
+
+/// Samples shared between the SoC producing them and the front-end playing them
+pub type SharedSamples = Arc<Mutex<SampleBuffer>>;
+
+/// A bounded queue of samples waiting to be played
+///
+/// When emulation runs faster than playback, such as while fast-forwarding or when vsync and the audio device disagree on the rate,
+/// the oldest samples are dropped instead of letting the queue and the latency grow without end.
+/// The dropped samples are counted, so that bug reports show whether audio was cut.
+pub struct SampleBuffer {
+    /// The queued samples, oldest first
+    samples: VecDeque<(u16, u16)>,
+    /// Most samples kept
+    capacity: usize,
+    /// Samples dropped since the buffer was created
+    dropped: u64,
+}
+
+impl SampleBuffer {
+    /// Creates an empty buffer holding up to `capacity` samples
+    pub fn new(capacity: usize) -> Self {
+        Self {samples: VecDeque::with_capacity(capacity), capacity, dropped: 0}
+    }
+
+    /// Creates an empty buffer with the default capacity, ready to be shared
+    pub fn shared() -> SharedSamples {
+        Arc::new(Mutex::new(Self::new(DEFAULT_CAPACITY)))
+    }
+
+    /// Appends a sample, dropping the oldest one if the buffer is full
+    pub fn push(&mut self, sample: (u16, u16)) {
+        if self.samples.len() >= self.capacity {
+            self.samples.pop_front();
+            self.dropped += 1;
+        }
+        self.samples.push_back(sample);
+    }
+
+    /// Takes up to `count` of the oldest samples
+    pub fn take(&mut self, count: usize) -> Vec<(u16, u16)> {
+        let count = count.min(self.samples.len());
+        self.samples.drain(..count).collect()
+    }
+
+    /// Returns the amount of samples queued
+    pub fn len(&self) -> usize {
+        self.samples.len()
+    }
+
+    /// Returns whether no samples are queued
+    pub fn is_empty(&self) -> bool {
+        self.samples.is_empty()
+    }
+
+    /// Returns the most samples kept
+    pub fn capacity(&self) -> usize {
+        self.capacity
+    }
+
+    /// Returns the amount of samples dropped because the buffer was full
+    pub fn dropped(&self) -> u64 {
+        self.dropped
+    }
+
+    /// Drops every queued sample, without counting them as dropped
+    pub fn clear(&mut self) {
+        self.samples.clear();
+    }
+
+    /// Returns the queued samples, oldest first
+    pub fn iter(&self) -> impl Iterator<Item = &(u16, u16)> {
+        self.samples.iter()
+    }
+}
+
+#[cfg(test)]
+#[cfg_attr(coverage_nightly, coverage(off))]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_drops_oldest() {
+        let mut buffer = SampleBuffer::new(4);
+        for i in 0..6 {buffer.push((i, i))}
+        assert_eq!(buffer.len(), 4);
+        assert_eq!(buffer.dropped(), 2);
+        assert_eq!(buffer.take(3), [(2, 2), (3, 3), (4, 4)]);
+        assert_eq!(buffer.take(3), [(5, 5)]);
+        assert!(buffer.is_empty());
+    }
+}
@@ -0,0 +1,104 @@
+use std::{fs::File, io::{self, BufWriter, Seek, SeekFrom, Write}};
+
+/// Rate of the samples taken from the sound chip, and of the captures
+pub const SAMPLE_RATE: u32 = 24000;
+/// Size of a WAV header with a single format chunk
+const HEADER_SIZE: u32 = 44;
+
+/// Writes every sample the SoC produces to a WAV file, 16-bit stereo at 24kHz
+///
+/// Samples are tapped as the SoC takes them from the sound chip, before the front-end stretches them to the playback speed
+/// and resamples them to the device's rate, so a capture is the same byte for byte whatever the speed it was emulated at.
+/// Both sides are kept, they only differ while the game sends its output to the headphones.
+pub struct AudioCapture<W: Write + Seek = BufWriter<File>> {
+    /// Where the WAV file is written
+    writer: W,
+    /// Samples written so far
+    samples: u32,
+}
+
+impl AudioCapture {
+    /// Creates a WAV file and starts capturing to it
+    #[cfg(feature = "std")]
+    pub fn create(path: &str) -> io::Result<Self> {
+        Self::new(BufWriter::new(File::create(path)?))
+    }
+}
+
+impl<W: Write + Seek> AudioCapture<W> {
+    /// Starts capturing to a writer, the header is written with empty sizes until the capture is finished
+    pub fn new(mut writer: W) -> io::Result<Self> {
+        writer.write_all(&header(0))?;
+        Ok(Self {writer, samples: 0})
+    }
+
+    /// Appends a sample
+    pub fn push(&mut self, sample: (u16, u16)) -> io::Result<()> {
+        // 16-bit WAV samples are signed
+        for side in [sample.0, sample.1] {self.writer.write_all(&((side ^ 0x8000) as i16).to_le_bytes())?}
+        self.samples += 1;
+        Ok(())
+    }
+
+    /// Returns the amount of samples captured so far
+    pub fn samples(&self) -> u32 {
+        self.samples
+    }
+
+    /// Fills in the header's sizes and returns the writer
+    pub fn finish(mut self) -> io::Result<W> {
+        self.writer.seek(SeekFrom::Start(0))?;
+        self.writer.write_all(&header(self.samples))?;
+        self.writer.seek(SeekFrom::End(0))?;
+        self.writer.flush()?;
+        Ok(self.writer)
+    }
+}
+
+/// Size of a sample in the WAV file, two sides of two bytes
+const SAMPLE_SIZE: u32 = 4;
+
+/// Returns the header of a WAV file holding the given amount of 16-bit stereo samples
+fn header(samples: u32) -> Vec<u8> {
+    let mut header = Vec::with_capacity(HEADER_SIZE as usize);
+    header.extend(b"RIFF");
+    header.extend((HEADER_SIZE - 8 + samples * SAMPLE_SIZE).to_le_bytes());
+    header.extend(b"WAVEfmt ");
+    header.extend(16u32.to_le_bytes());
+    // PCM, two channels, two bytes per channel
+    header.extend(1u16.to_le_bytes());
+    header.extend(2u16.to_le_bytes());
+    header.extend(SAMPLE_RATE.to_le_bytes());
+    header.extend((SAMPLE_RATE * SAMPLE_SIZE).to_le_bytes());
+    header.extend((SAMPLE_SIZE as u16).to_le_bytes());
+    header.extend(16u16.to_le_bytes());
+    header.extend(b"data");
+    header.extend((samples * SAMPLE_SIZE).to_le_bytes());
+    header
+}
+
+#[cfg(test)]
+#[cfg_attr(coverage_nightly, coverage(off))]
+mod test {
+    use std::io::Cursor;
+
+    use super::*;
+
+    #[test]
+    fn test_wav() {
+        let mut capture = AudioCapture::new(Cursor::new(Vec::new())).unwrap();
+        for sample in [(0x8000, 0x8000), (0xFFFF, 0x0000), (0x0000, 0x0000)] {
+            capture.push(sample).unwrap();
+        }
+        assert_eq!(capture.samples(), 3);
+
+        let wav = capture.finish().unwrap().into_inner();
+        assert_eq!(wav.len(), HEADER_SIZE as usize + 12);
+        assert_eq!(&wav[..4], b"RIFF");
+        assert_eq!(u32::from_le_bytes(wav[4..8].try_into().unwrap()), HEADER_SIZE - 8 + 12);
+        assert_eq!(u16::from_le_bytes(wav[22..24].try_into().unwrap()), 2);
+        assert_eq!(u32::from_le_bytes(wav[24..28].try_into().unwrap()), SAMPLE_RATE);
+        assert_eq!(u32::from_le_bytes(wav[40..44].try_into().unwrap()), 12);
+        assert_eq!(&wav[44..], [0x00, 0x00, 0x00, 0x00, 0xFF, 0x7F, 0x00, 0x80, 0x00, 0x80, 0x00, 0x80]);
+    }
+}
@@ -1,3 +1,5 @@
+use crate::state::{Snapshot, StateError, StateReader, StateWriter};
+
 /// Waveform sound channel
 /// 
 /// This struct only describes the waveform sampler behaviour of the sound channels.
@@ -50,4 +52,24 @@ impl Channel {
 
         return self.sample;
     }
-}
\ No newline at end of file
+}
+
+impl Snapshot for Channel {
+    const TAG: [u8; 4] = *b"CHAN";
+    const VERSION: u16 = 1;
+
+    fn save_fields(&self, w: &mut StateWriter) {
+        w.write_bytes(&self.waveform);
+        w.write_u16(self.frequency);
+        w.write_u16(self.sample_clock);
+        w.write_u8(self.sample_idx as u8);
+        w.write_u8(self.sample);
+    }
+
+    fn load_fields(&mut self, r: &mut StateReader, _version: u16) -> Result<(), StateError> {
+        let (waveform, frequency, sample_clock) = (r.read_array()?, r.read_u16()?, r.read_u16()?);
+        let (sample_idx, sample) = (r.read_u8()? as usize & 0x1F, r.read_u8()?);
+        *self = Self {waveform, frequency, sample_clock, sample_idx, sample};
+        Ok(())
+    }
+}
@@ -0,0 +1,201 @@
+use core::f32::consts::PI;
+
+use crate::options::Choice;
+#[cfg(not(any(feature = "std", test)))]
+use crate::math::Float;
+
+/// Rate at which samples are taken from the sound chip
+const SAMPLE_RATE: f32 = 24000.0;
+
+/// How the sound of the internal speaker is reproduced
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum SoundProfile {
+    /// The chip's output as is, as heard through headphones
+    Clean,
+    /// The chip's output colored like the console's small speaker
+    Speaker,
+}
+
+impl Choice for SoundProfile {
+    const ALL: &'static [Self] = &[SoundProfile::Clean, SoundProfile::Speaker];
+
+    fn name(&self) -> &'static str {
+        match self {
+            SoundProfile::Clean => "clean",
+            SoundProfile::Speaker => "speaker",
+        }
+    }
+}
+
+/// Settings of the speaker profile's filters, part of the emulator options
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub struct SpeakerSettings {
+    /// Cutoff of the low-pass filter in Hz, the speaker cannot reproduce high frequencies
+    pub lowpass_hz: f32,
+    /// Cutoff of the high-pass filter in Hz, the speaker has next to no bass
+    pub highpass_hz: f32,
+    /// Amount of soft clipping, 0 disables it
+    pub drive: f32,
+}
+
+impl SpeakerSettings {
+    /// Returns settings resembling the console's speaker
+    pub fn new() -> Self {
+        Self {lowpass_hz: 5000.0, highpass_hz: 400.0, drive: 1.5}
+    }
+
+    /// Applies a setting given as `name=value`, returns false if it is not a speaker setting
+    pub fn parse_setting(&mut self, setting: &str) -> bool {
+        let Some((name, value)) = setting.split_once('=') else {return false};
+        let Ok(value) = value.trim().parse::<f32>() else {return false};
+        match name.trim() {
+            "lowpass" => self.lowpass_hz = value.clamp(1.0, SAMPLE_RATE / 2.0),
+            "highpass" => self.highpass_hz = value.clamp(0.0, SAMPLE_RATE / 2.0),
+            "drive" => self.drive = value.max(0.0),
+            _ => return false,
+        }
+        true
+    }
+}
+
+impl Default for SpeakerSettings {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+/// State of the filters for one channel
+#[derive(Clone, Copy, Default)]
+struct FilterState {
+    /// Last input of the high-pass filter
+    highpass_in: f32,
+    /// Last output of the high-pass filter
+    highpass_out: f32,
+    /// Last output of the low-pass filter
+    lowpass_out: f32,
+}
+
+/// Filter chain applied to the speaker's output
+///
+/// The chain is a one-pole high-pass filter, a one-pole low-pass filter and soft clipping, in that order.
+/// When disabled samples pass through untouched.
+pub struct SpeakerFilter {
+    /// Whether samples are filtered
+    enabled: bool,
+    /// The settings the coefficients were computed from
+    settings: SpeakerSettings,
+    /// Coefficient of the high-pass filter
+    highpass: f32,
+    /// Coefficient of the low-pass filter
+    lowpass: f32,
+    /// Filter state of the left and right channels
+    state: [FilterState; 2],
+}
+
+impl SpeakerFilter {
+    /// Creates a disabled filter
+    pub fn new() -> Self {
+        let mut filter = Self {enabled: false, settings: SpeakerSettings::new(), highpass: 0.0, lowpass: 0.0, state: Default::default()};
+        filter.configure(SoundProfile::Clean, SpeakerSettings::new());
+        filter
+    }
+
+    /// Enables the filter for the speaker profile and recomputes its coefficients
+    pub fn configure(&mut self, profile: SoundProfile, settings: SpeakerSettings) {
+        let enabled = profile == SoundProfile::Speaker;
+        if enabled != self.enabled {self.state = Default::default()}
+        self.enabled = enabled;
+        self.settings = settings;
+
+        let dt = 1.0 / SAMPLE_RATE;
+        let rc = |cutoff: f32| 1.0 / (2.0 * PI * cutoff.max(f32::EPSILON));
+        self.highpass = if settings.highpass_hz > 0.0 {rc(settings.highpass_hz) / (rc(settings.highpass_hz) + dt)} else {1.0};
+        self.lowpass = dt / (rc(settings.lowpass_hz) + dt);
+    }
+
+    /// Filters a sample of both channels
+    pub fn process(&mut self, sample: (u16, u16)) -> (u16, u16) {
+        if !self.enabled {return sample}
+        (self.process_channel(0, sample.0), self.process_channel(1, sample.1))
+    }
+
+    /// Filters a sample of one channel, samples are unsigned 16-bit values centered on 0x8000
+    fn process_channel(&mut self, channel: usize, sample: u16) -> u16 {
+        let state = &mut self.state[channel];
+        let input = (sample as f32 - 32767.5) / 32767.5;
+
+        state.highpass_out = self.highpass * (state.highpass_out + input - state.highpass_in);
+        state.highpass_in = input;
+        state.lowpass_out += self.lowpass * (state.highpass_out - state.lowpass_out);
+
+        let drive = self.settings.drive;
+        let output = if drive > 0.0 {(state.lowpass_out * drive).tanh() / drive.tanh()} else {state.lowpass_out};
+        (output * 32767.5 + 32767.5).round().clamp(0.0, 65535.0) as u16
+    }
+}
+
+impl Default for SpeakerFilter {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+#[cfg(test)]
+#[cfg_attr(coverage_nightly, coverage(off))]
+mod test {
+    use alloc::vec::Vec;
+
+    use super::*;
+
+    fn speaker() -> SpeakerFilter {
+        let mut filter = SpeakerFilter::new();
+        filter.configure(SoundProfile::Speaker, SpeakerSettings::new());
+        filter
+    }
+
+    #[test]
+    fn test_clean_passthrough() {
+        let mut filter = SpeakerFilter::new();
+        for sample in [0, 0x4000, 0x8000, 0xFFFF] {
+            assert_eq!(filter.process((sample, sample)), (sample, sample));
+        }
+    }
+
+    #[test]
+    fn test_highpass_removes_dc() {
+        let mut filter = speaker();
+        let mut output = (0, 0);
+        for _ in 0..24000 {
+            output = filter.process((0xE000, 0xE000));
+        }
+        assert!(output.0.abs_diff(0x8000) <= 0x100);
+    }
+
+    #[test]
+    fn test_lowpass_attenuates_high_frequencies() {
+        // A square wave at the Nyquist frequency against one at 1 kHz
+        let amplitude = |period: usize| {
+            let mut filter = speaker();
+            let outputs: Vec<u16> = (0..4800).map(|i| {
+                let sample = if (i / (period / 2)).is_multiple_of(2) {0x2000} else {0xE000};
+                filter.process((sample, sample)).0
+            }).collect();
+            let tail = &outputs[2400..];
+            tail.iter().max().unwrap() - tail.iter().min().unwrap()
+        };
+        assert!(amplitude(2) * 2 < amplitude(24));
+    }
+
+    #[test]
+    fn test_speaker_settings() {
+        let mut settings = SpeakerSettings::new();
+        assert!(settings.parse_setting("lowpass=3000"));
+        assert!(settings.parse_setting("drive=0"));
+        assert!(!settings.parse_setting("mute"));
+        assert!(!settings.parse_setting("lowpass=loud"));
+        assert_eq!(settings, SpeakerSettings {lowpass_hz: 3000.0, highpass_hz: 400.0, drive: 0.0});
+
+        assert_eq!(SoundProfile::from_name("speaker"), Some(SoundProfile::Speaker));
+        assert_eq!(SoundProfile::Speaker.next(), SoundProfile::Clean);
+    }
+}
@@ -1,14 +1,25 @@
-use std::{cell::RefCell, rc::Rc};
+use alloc::rc::Rc;
+use core::cell::RefCell;
 
 use bitflags::bitflags;
 
-use crate::{bus::{io_bus::{IOBus, IOBusConnection}, mem_bus::{MemBus, MemBusConnection}}, sound::channel::Channel};
+use crate::{bus::{io_bus::{IOBus, IOBusConnection}, mem_bus::{Accessor, MemBus, MemBusConnection}}, sound::{channel::Channel, filter::SpeakerFilter, scope::{Scope, SCOPE_TRACES}}, state::{Snapshot, StateError, StateReader, StateWriter}};
 
 /// Channel module
 /// 
 /// This channel only handles the operation of modules as waveform samplers, it does not module the noise, sweep or voice features.
 mod channel;
 
+/// Filters coloring the output like the console's internal speaker
+pub mod filter;
+/// WAV captures of the samples taken from the sound chip
+#[cfg(feature = "std")]
+pub mod capture;
+/// Bounded queue of the samples waiting to be played
+pub mod buffer;
+/// Recent output of each channel, for the oscilloscope view
+pub mod scope;
+
 bitflags! {
     /// The sound chip's control byte
     #[derive(Clone, Copy)]
@@ -58,6 +69,26 @@ pub struct Sound {
     noise_clock: u16,
     /// Channel 4 base volume as determined by LSFR
     noise: Option<u8>,
+
+    /// Copy of the sound ports 0x80-0x9F, refreshed only after one of them is written
+    registers: [u8; 0x20],
+
+    /// The last PCM sample written to port 0x89 in voice mode
+    pcm: u8,
+    /// Sum of the outputs since the last sample was taken
+    output_acc: (u32, u32),
+    /// Number of ticks since the last sample was taken
+    output_ticks: u32,
+    /// Whether the output went to the headphones instead of the speaker on the last tick
+    headphones: bool,
+
+    /// Filters applied to the samples while the speaker is in use
+    pub(crate) speaker_filter: SpeakerFilter,
+
+    /// Recent output of each channel, only recorded while the oscilloscope is shown
+    pub(crate) scope: Option<Scope>,
+    /// Sum of each channel's output and of the mix since the last sample was taken, only kept while recording the scope
+    scope_acc: [u32; SCOPE_TRACES],
 }
 
 impl Sound {
@@ -73,31 +104,93 @@ impl Sound {
 
             sweep_clock: 0, step_clock: 0,
             noise_clock: 0, noise: None,
+
+            registers: [0; 0x20],
+            pcm: 0, output_acc: (0, 0), output_ticks: 0, headphones: false,
+
+            speaker_filter: SpeakerFilter::new(),
+
+            scope: None, scope_acc: [0; SCOPE_TRACES],
         }
     }
 
+    /// Returns the average output since the last call, as unsigned 16-bit values for the left and right side
+    /// 
+    /// Averaging over the whole period instead of taking the output of a single tick
+    /// keeps PCM samples written at a different rate than the output rate from aliasing.
+    pub fn take_sample(&mut self) -> (u16, u16) {
+        let ticks = self.output_ticks.max(1);
+        let sample = ((self.output_acc.0 / ticks) as u16, (self.output_acc.1 / ticks) as u16);
+        (self.output_acc, self.output_ticks) = ((0, 0), 0);
+        if let Some(scope) = &mut self.scope {
+            scope.push(self.scope_acc.map(|acc| (acc / ticks) as u8));
+            self.scope_acc = [0; SCOPE_TRACES];
+        }
+        // The speaker profile only colors what goes through the speaker
+        if self.headphones {sample} else {self.speaker_filter.process(sample)}
+    }
+
     /// Ticks the sound chip by one cycle
-    pub fn tick(&mut self) -> (u16, u16) {
-        self.control = SoundControl::from_bits_truncate(self.read_io(0x90));
+    pub fn tick(&mut self) {
+        let output = self.output();
+        self.output_acc.0 += output.0 as u32;
+        self.output_acc.1 += output.1 as u32;
+        self.output_ticks += 1;
+    }
+
+    /// Latches the PCM writes made up to the current tick and the sound ports if they were written
+    /// 
+    /// Writes are timestamped by the I/O bus as they happen, the latest one that has already happened is the current sample.
+    /// This is the only time the I/O bus is borrowed on ticks where no sweep or noise step happens.
+    fn sync_io(&mut self) {
+        let mut io_bus = self.io_bus.borrow_mut();
+        let now = io_bus.sound_clock;
+        if let Some(&(_, pcm)) = io_bus.take_pcm_writes(now).last() {
+            self.pcm = pcm;
+        }
+        io_bus.sound_clock += 1;
+
+        if let Some(registers) = io_bus.take_sound_registers() {
+            drop(io_bus);
+            self.registers = registers;
+            self.control = SoundControl::from_bits_truncate(self.register(0x90));
+            self.load_frequencies();
+        }
+    }
+
+    /// Returns the latched value of a sound port
+    fn register(&self, port: u16) -> u8 {
+        self.registers[(port - 0x80) as usize]
+    }
+
+    /// Returns the latched value of a pair of sound ports, the first one holding the low byte
+    fn register_16(&self, port: u16) -> u16 {
+        u16::from_le_bytes([self.register(port), self.register(port + 1)])
+    }
+
+    /// Produces the output of the current tick
+    fn output(&mut self) -> (u16, u16) {
+        self.sync_io();
         self.sweep();
         self.noise();
         self.load_waveforms();
-        self.load_frequencies();
 
         let samples = self.channel_outputs();
 
-        let volumes: [(u8, u8); 4] = std::array::from_fn(|i| {
-            let volume = self.read_io(0x88 + i as u16);
+        let volumes: [(u8, u8); 4] = core::array::from_fn(|i| {
+            let volume = self.register(0x88 + i as u16);
             (volume >> 4, volume & 0xF)
         });
 
-        let mut stereo_samples: [(u8, u8); 4] = std::array::from_fn(|i| {
-            (samples[i] * volumes[i].0, samples[i] * volumes[i].1)
+        // The voice sample is 8 bits wide, so it cannot go through the 4-bit volume multiplication
+        let voice = self.control.contains(SoundControl::VOICE);
+        let mut stereo_samples: [(u8, u8); 4] = core::array::from_fn(|i| {
+            if i == 1 && voice {(0, 0)} else {(samples[i] * volumes[i].0, samples[i] * volumes[i].1)}
         });
 
-        if self.control.contains(SoundControl::VOICE) {
+        if voice {
             let voice = samples[1];
-            let voice_volume = self.read_io(0x94);
+            let voice_volume = self.register(0x94);
 
             let right = if voice_volume & 0b0001 != 0 {
                 voice
@@ -114,20 +207,20 @@ impl Sound {
             stereo_samples[1] = (left, right);
         }
 
-        let stereo_output = stereo_samples.iter()
-            .copied()
-            .map(|(l, r)| (l as u16, r as u16))
-            .reduce(|(left_out, right_out), (left_in, right_in)| (left_out + left_in, right_out + right_in))
-            .unwrap();
-
-        let out_ctrl = self.read_io(0x91);
-        if out_ctrl & 0x80 != 0 {
-            panic!("Headphones not yey implemented!");
+        let out_ctrl = self.register(0x91);
+        self.headphones = out_ctrl & 0x80 != 0;
+        let output = if self.headphones {
+            headphone_output(stereo_samples)
         } else {
             let rng_s = (out_ctrl >> 1) & 3;
-            let output = ((stereo_output.0 + stereo_output.1) >> rng_s) as u8;
-            (output as u16, output as u16)
+            let output = (speaker_output(stereo_samples, rng_s) as u16) << 8;
+            (output, output)
+        };
+        if self.scope.is_some() {
+            for (acc, (left, right)) in self.scope_acc.iter_mut().zip(stereo_samples) {*acc += (left as u32 + right as u32) / 2}
+            self.scope_acc[4] += ((output.0 as u32 + output.1 as u32) / 2) >> 8;
         }
+        output
     }
 
     /// Ticks all the channels and returns an array of their outputs.
@@ -148,7 +241,7 @@ impl Sound {
             } else {0},
 
             if self.control.contains(SoundControl::VOICE) {
-                self.read_io(0x89)
+                self.pcm
             } else {sample_2},
 
             if self.control.contains(SoundControl::Enb3) {
@@ -163,11 +256,11 @@ impl Sound {
 
     /// Load the waveform data into the channels
     fn load_waveforms(&mut self) {
-        let wave_p = self.read_io(0x8F) as u32;
+        let wave_p = self.register(0x8F) as u32;
         let base = wave_p << 6;
 
-        let groups: [[u8; 16]; 4] = std::array::from_fn(|channel| {
-            std::array::from_fn(|index| {
+        let groups: [[u8; 16]; 4] = core::array::from_fn(|channel| {
+            core::array::from_fn(|index| {
                 let addr = base + (index as u32) + ((channel * 16) as u32);
                 self.read_mem(addr)
             })
@@ -181,10 +274,7 @@ impl Sound {
 
     /// Load the frequency data into the channels
     fn load_frequencies(&mut self) {
-        let frequencies: [u16; 4] = std::array::from_fn(|i| {
-            let (lo, hi) = self.read_io_16(0x80 + (i * 2) as u16);
-            u16::from_le_bytes([lo, hi]) & 0x7FF
-        });
+        let frequencies: [u16; 4] = core::array::from_fn(|i| self.register_16(0x80 + (i * 2) as u16) & 0x7FF);
 
         self.channel_1.frequency = 2048 - frequencies[0];
         self.channel_2.frequency = 2048 - frequencies[1];
@@ -199,10 +289,9 @@ impl Sound {
             if self.sweep_clock > 8192 {
                 self.sweep_clock = 0;
                 if self.step_clock == 0 {
-                    self.step_clock = ((self.read_io(0x8D) & 0x1F) - 1) as usize;
-                    let sweep = self.read_io(0x8C) as i8 as i16;
-                    let (lo, hi) = self.read_io_16(0x84);
-                    let old_frequency = (u16::from_le_bytes([lo, hi]) & 0x7FF) as i16;
+                    self.step_clock = ((self.register(0x8D) & 0x1F) - 1) as usize;
+                    let sweep = self.register(0x8C) as i8 as i16;
+                    let old_frequency = (self.register_16(0x84) & 0x7FF) as i16;
                     let mut new_frequency = old_frequency + sweep;
                     if new_frequency > 2047 {
                         new_frequency = 0;
@@ -220,20 +309,21 @@ impl Sound {
     /// Ticks the noise unit if channel 4 is enabled and the noise flag is set
     fn noise(&mut self) {
         if self.control.contains(SoundControl::NOISE) && self.control.contains(SoundControl::Enb4) {
-            let noise_ctrl = self.read_io(0x8E);
+            let noise_ctrl = self.register(0x8E);
             if noise_ctrl & 0x10 == 0 {return}
 
             if self.noise_clock == 0 {
-                let (lo, hi) = self.read_io_16(0x86);
-                self.noise_clock = 2048 - u16::from_le_bytes([lo, hi]) & 0x1FF;
+                self.noise_clock = 2048 - self.register_16(0x86) & 0x1FF;
 
-                if noise_ctrl & 0x08 != 0 {
+                // The latched ports are only refreshed on the next tick, so the reset LSFR is not read back from them
+                let mut lsfr = if noise_ctrl & 0x08 != 0 {
                     self.write_io(0x92, 0);
                     self.write_io(0x8E, noise_ctrl & 0xF7);
-                }
-
-                let (lo, hi) = self.read_io_16(0x92);
-                let mut lsfr = u16::from_le_bytes([lo, hi]) & 0x7FFF;
+                    self.registers[0x0E] = noise_ctrl & 0xF7;
+                    0
+                } else {
+                    self.register_16(0x92) & 0x7FFF
+                };
 
                 let tap = noise_ctrl & 7;
                 let tap_bit = (lsfr >> match tap {
@@ -253,7 +343,7 @@ impl Sound {
                 lsfr &= 0x7FFF;
                 lsfr |= random_bit as u16;
                 self.io_bus.borrow_mut().set_lsfr(lsfr);
-                self.noise = Some(if random_bit {0xFF} else {0x00});
+                self.noise = Some(if random_bit {0x0F} else {0x00});
             } else {
                 self.noise_clock -= 1;
             }
@@ -265,11 +355,11 @@ impl Sound {
 
 impl MemBusConnection for Sound {
     fn read_mem(&mut self, addr: u32) -> u8 {
-        self.mem_bus.borrow_mut().read_mem(addr)
+        self.mem_bus.borrow_mut().read_by(addr, Accessor::SOUND)
     }
 
     fn write_mem(&mut self, addr: u32, byte: u8) {
-        self.mem_bus.borrow_mut().write_mem(addr, byte);
+        self.mem_bus.borrow_mut().write_by(addr, byte, Accessor::SOUND);
     }
 }
 
@@ -281,4 +371,182 @@ impl IOBusConnection for Sound {
     fn write_io(&mut self, addr: u16, byte: u8) {
         self.io_bus.borrow_mut().write_io(addr, byte);
     }
-}
\ No newline at end of file
+
+    fn write_io_16(&mut self, addr: u16, word: u16) {
+        self.io_bus.borrow_mut().write_io_16(addr, word);
+    }
+}
+
+/// The sound chip's state contains its channels, clocks and the output accumulated towards the next sample
+///
+/// The speaker filter only colors the output and is configured from the options, so it is not part of it.
+impl Snapshot for Sound {
+    const TAG: [u8; 4] = *b"SNDC";
+    const VERSION: u16 = 1;
+
+    fn save_fields(&self, w: &mut StateWriter) {
+        for channel in [&self.channel_1, &self.channel_2, &self.channel_3, &self.channel_4] {channel.save_state(w)}
+        w.write_u8(self.control.bits());
+        w.write_u32(self.sweep_clock as u32);
+        w.write_u32(self.step_clock as u32);
+        w.write_u16(self.noise_clock);
+        w.write_bool(self.noise.is_some());
+        w.write_u8(self.noise.unwrap_or(0));
+        w.write_bytes(&self.registers);
+        w.write_u8(self.pcm);
+        w.write_u32(self.output_acc.0);
+        w.write_u32(self.output_acc.1);
+        w.write_u32(self.output_ticks);
+    }
+
+    fn load_fields(&mut self, r: &mut StateReader, _version: u16) -> Result<(), StateError> {
+        let mut channels = [self.channel_1, self.channel_2, self.channel_3, self.channel_4];
+        for channel in &mut channels {channel.load_state(r)?}
+        let control = SoundControl::from_bits_truncate(r.read_u8()?);
+        let clocks = (r.read_u32()? as usize, r.read_u32()? as usize, r.read_u16()?);
+        let noise = (r.read_bool()?, r.read_u8()?);
+        let registers = r.read_array()?;
+        let pcm = r.read_u8()?;
+        let output = ((r.read_u32()?, r.read_u32()?), r.read_u32()?);
+
+        [self.channel_1, self.channel_2, self.channel_3, self.channel_4] = channels;
+        self.control = control;
+        (self.sweep_clock, self.step_clock, self.noise_clock) = clocks;
+        self.noise = noise.0.then_some(noise.1);
+        (self.registers, self.pcm) = (registers, pcm);
+        (self.output_acc, self.output_ticks) = output;
+        Ok(())
+    }
+}
+
+/// Mixes the channels' outputs into the 8-bit value fed to the speaker's DAC
+/// 
+/// Each channel outputs an 8-bit value per side, the sum of each side is 10 bits wide
+/// and the speaker adds both sides together into an 11-bit value, so no overflow can happen before the DAC.
+/// The sum is shifted right by the amount selected in SND_OUT_CTRL, anything that still does not fit into 8 bits is clipped to the maximum.
+fn speaker_output(channels: [(u8, u8); 4], shift: u8) -> u8 {
+    let (left, right) = channels.iter().fold((0u16, 0u16), |(left, right), (l, r)| (left + *l as u16, right + *r as u16));
+    ((left + right) >> shift).min(0xFF) as u8
+}
+
+/// Mixes the channels for the headphones, used instead of the speaker while bit 7 of SND_OUT_CTRL (0x91) is set
+///
+/// Each side keeps its own sum of the channels, a 10-bit value that is neither mixed to mono nor shifted like the speaker's,
+/// and is widened to 16 bits.
+fn headphone_output(channels: [(u8, u8); 4]) -> (u16, u16) {
+    let (left, right) = channels.iter().fold((0u16, 0u16), |(left, right), (l, r)| (left + *l as u16, right + *r as u16));
+    (left.min(0x3FF) << 6, right.min(0x3FF) << 6)
+}
+
+#[cfg(test)]
+#[cfg_attr(coverage_nightly, coverage(off))]
+mod test {
+    use alloc::vec::Vec;
+
+    use super::*;
+
+    #[test]
+    fn test_speaker_output_clips() {
+        let loud = [(15 * 15, 15 * 15); 4];
+        assert_eq!(speaker_output(loud, 0), 0xFF);
+        assert_eq!(speaker_output(loud, 2), 0xFF);
+        assert_eq!(speaker_output(loud, 3), 225);
+        assert_eq!(speaker_output([(0, 0); 4], 0), 0);
+    }
+
+    #[test]
+    fn test_speaker_output_voice() {
+        // Full volume voice on both sides plus all other channels at maximum, the widest possible sum
+        let channels = [(225, 225), (0xFF, 0xFF), (225, 225), (225, 225)];
+        assert_eq!(speaker_output(channels, 3), ((225 * 6 + 0xFF * 2) >> 3) as u8);
+        assert_eq!(speaker_output([(0, 0), (0x80, 0x40), (0, 0), (0, 0)], 0), 0xC0);
+    }
+
+    fn test_sound() -> Sound {
+        let cartridge = Rc::new(RefCell::new(crate::cartridge::Cartridge::test_build()));
+        let io_bus = Rc::new(RefCell::new(IOBus::new(Rc::clone(&cartridge), Vec::new(), None, false, 0)));
+        let mem_bus = Rc::new(RefCell::new(MemBus::test_build(Rc::clone(&io_bus), cartridge)));
+        let mut sound = Sound::new(mem_bus, io_bus);
+        // Voice mode at full volume on both sides, speaker shift of 0
+        sound.write_io(0x90, 0x20);
+        sound.write_io(0x94, 0x05);
+        sound
+    }
+
+    #[test]
+    fn test_pcm_latched_only_in_voice_mode() {
+        let mut sound = test_sound();
+        sound.write_io(0x90, 0x00);
+        sound.write_io(0x89, 0x40);
+        sound.write_io(0x90, 0x20);
+        sound.tick();
+        assert_eq!(sound.take_sample(), (0, 0));
+
+        sound.write_io(0x89, 0x40);
+        sound.tick();
+        assert_eq!(sound.take_sample(), (0x8000, 0x8000));
+    }
+
+    #[test]
+    fn test_pcm_averaged_over_sample() {
+        let mut sound = test_sound();
+        // Two PCM samples within a single output period both contribute
+        sound.write_io(0x89, 0x20);
+        for _ in 0..64 {sound.tick()}
+        sound.write_io(0x89, 0x60);
+        for _ in 0..64 {sound.tick()}
+        assert_eq!(sound.take_sample(), (0x8000, 0x8000));
+    }
+
+    #[test]
+    fn test_headphones() {
+        let mut sound = test_sound();
+        // Voice at full volume on the left and half on the right, sent to the headphones
+        sound.write_io(0x94, 0x06);
+        sound.write_io(0x91, 0x80);
+        sound.write_io(0x89, 0x40);
+        sound.tick();
+        assert_eq!(sound.take_sample(), (0x40 << 6, 0x20 << 6));
+        assert_eq!(headphone_output([(225, 225), (0xFF, 0), (225, 225), (225, 225)]), ((225 * 3 + 0xFF) << 6, (225 * 3) << 6));
+
+        // Back on the speaker both sides are mixed
+        sound.write_io(0x91, 0x00);
+        sound.tick();
+        assert_eq!(sound.take_sample(), (0x6000, 0x6000));
+    }
+
+    #[test]
+    fn test_scope() {
+        let mut sound = test_sound();
+        sound.tick();
+        sound.take_sample();
+        assert_eq!(sound.scope, None);
+
+        // Voice at 0x40 goes to both sides and is all the speaker hears
+        sound.scope = Some(Scope::default());
+        sound.write_io(0x89, 0x40);
+        for _ in 0..128 {sound.tick()}
+        sound.take_sample();
+        let scope = sound.scope.as_ref().unwrap();
+        assert_eq!((0..SCOPE_TRACES).map(|trace| scope.trace(trace).last().unwrap()).collect::<Vec<_>>(), [0, 0x40, 0, 0, 0x80]);
+    }
+
+    #[test]
+    fn test_registers_latched_on_write() {
+        let mut sound = test_sound();
+        sound.write_io_16(0x80, 0x07FE);
+        sound.tick();
+        assert_eq!(sound.channel_1.frequency, 2);
+        assert!(sound.io_bus.borrow_mut().take_sound_registers().is_none());
+
+        // Writes to other ports leave the latched sound ports alone
+        sound.write_io(0x10, 0x55);
+        assert!(sound.io_bus.borrow_mut().take_sound_registers().is_none());
+
+        // Writes through a mirror of the sound ports are latched
+        sound.write_io(0x1088, 0xF0);
+        sound.tick();
+        assert_eq!(sound.register(0x88), 0xF0);
+        assert_eq!(sound.register(0x90), 0x20);
+    }
+}
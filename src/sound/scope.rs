@@ -0,0 +1,58 @@
+/// Samples kept for each trace, about two thirds of a frame at 24 kHz
+pub const SCOPE_SAMPLES: usize = 192;
+/// Traces recorded, one per channel followed by the mix
+pub const SCOPE_TRACES: usize = 5;
+
+/// Recent output of each channel and of the mix fed to the speaker, one value per sample taken from the sound chip
+///
+/// Channels are recorded after their volume, the average of both sides over the sample's ticks, so that sweeps, noise and voice show as heard.
+/// The mix is the speaker's input before its filters.
+#[derive(Clone, PartialEq, Eq, Debug)]
+pub struct Scope {
+    /// Ring buffer of each trace's values
+    traces: [[u8; SCOPE_SAMPLES]; SCOPE_TRACES],
+    /// Index the next sample is written to, which is also the oldest sample
+    next: usize,
+}
+
+impl Default for Scope {
+    fn default() -> Self {
+        Self {traces: [[0; SCOPE_SAMPLES]; SCOPE_TRACES], next: 0}
+    }
+}
+
+impl Scope {
+    /// Adds a sample of every trace, dropping the oldest
+    pub fn push(&mut self, levels: [u8; SCOPE_TRACES]) {
+        for (trace, level) in self.traces.iter_mut().zip(levels) {
+            trace[self.next] = level;
+        }
+        self.next = (self.next + 1) % SCOPE_SAMPLES;
+    }
+
+    /// Returns the values of a trace, oldest first
+    pub fn trace(&self, trace: usize) -> impl Iterator<Item = u8> + '_ {
+        let (newer, older) = self.traces[trace].split_at(self.next);
+        older.iter().chain(newer).copied()
+    }
+}
+
+#[cfg(test)]
+#[cfg_attr(coverage_nightly, coverage(off))]
+mod test {
+    use alloc::vec::Vec;
+
+    use super::*;
+
+    #[test]
+    fn test_scope_order() {
+        let mut scope = Scope::default();
+        for level in 0..SCOPE_SAMPLES as u8 + 2 {
+            scope.push([level, 0, 0, 0, 255 - level]);
+        }
+        let trace: Vec<u8> = scope.trace(0).collect();
+        assert_eq!(trace.len(), SCOPE_SAMPLES);
+        assert_eq!((trace[0], trace[SCOPE_SAMPLES - 1]), (2, SCOPE_SAMPLES as u8 + 1));
+        assert_eq!(scope.trace(4).last(), Some(255 - (SCOPE_SAMPLES as u8 + 1)));
+    }
+}
@@ -0,0 +1,209 @@
+use alloc::vec::Vec;
+use core::time::Duration;
+
+use crate::options::Choice;
+
+/// Time taken by a single frame at normal speed, the WonderSwan runs at roughly 75.47 frames per second
+const FRAME_TIME_US: u64 = 13_250;
+
+/// Size of the blocks of samples that are repeated or skipped when preserving pitch
+const GRAIN: usize = 256;
+/// Samples at the start of each block faded in over the continuation of the previous block, so that the seams do not click
+const OVERLAP: usize = 32;
+
+/// Emulation speeds the user can switch between
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum Speed {
+    /// 50% speed
+    Half,
+    /// Normal speed
+    Normal,
+    /// 200% speed
+    Double,
+    /// As fast as the host allows
+    Unlimited,
+}
+
+impl Speed {
+    /// Returns the factor by which emulation is sped up, or `None` if the speed is unlimited
+    pub fn factor(&self) -> Option<f64> {
+        match self {
+            Speed::Half => Some(0.5),
+            Speed::Normal => Some(1.0),
+            Speed::Double => Some(2.0),
+            Speed::Unlimited => None,
+        }
+    }
+
+    /// Returns the next faster speed, unlimited is the fastest
+    pub fn faster(self) -> Self {
+        match self {
+            Speed::Half => Speed::Normal,
+            Speed::Normal => Speed::Double,
+            Speed::Double | Speed::Unlimited => Speed::Unlimited,
+        }
+    }
+
+    /// Returns the next slower speed, half speed is the slowest
+    pub fn slower(self) -> Self {
+        match self {
+            Speed::Unlimited => Speed::Double,
+            Speed::Double => Speed::Normal,
+            Speed::Normal | Speed::Half => Speed::Half,
+        }
+    }
+
+    /// Returns the time each frame should take, or 0 if the speed is unlimited
+    pub fn frame_time(&self) -> Duration {
+        match self.factor() {
+            Some(factor) => Duration::from_micros((FRAME_TIME_US as f64 / factor) as u64),
+            None => Duration::ZERO,
+        }
+    }
+}
+
+impl Choice for Speed {
+    const ALL: &'static [Self] = &[Speed::Half, Speed::Normal, Speed::Double, Speed::Unlimited];
+
+    fn name(&self) -> &'static str {
+        match self {
+            Speed::Half => "half",
+            Speed::Normal => "normal",
+            Speed::Double => "double",
+            Speed::Unlimited => "unlimited",
+        }
+    }
+}
+
+/// Speed settings, part of the emulator options
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub struct SpeedSettings {
+    /// The current emulation speed
+    pub speed: Speed,
+    /// If set, audio keeps its original pitch when the speed changes, otherwise the pitch scales with the speed
+    pub preserve_pitch: bool,
+}
+
+impl SpeedSettings {
+    /// Returns the default settings, normal speed with pitch preservation
+    pub fn new() -> Self {
+        Self {speed: Speed::Normal, preserve_pitch: true}
+    }
+
+    /// Loads the settings saved for a game, stored as the speed's name followed by whether pitch is preserved
+    #[cfg(feature = "std")]
+    pub fn load(game: &str) -> Option<Self> {
+        let text = std::fs::read_to_string(format!("{}.speed", game)).ok()?;
+        let mut words = text.split_whitespace();
+        let speed = Speed::from_name(words.next()?)?;
+        let preserve_pitch = words.next().and_then(|preserve_pitch| preserve_pitch.parse().ok()).unwrap_or(true);
+        Some(Self {speed, preserve_pitch})
+    }
+
+    /// Saves the settings for a game
+    #[cfg(feature = "std")]
+    pub fn save(&self, game: &str) -> std::io::Result<()> {
+        std::fs::write(format!("{}.speed", game), format!("{} {}", self.speed.name(), self.preserve_pitch))
+    }
+}
+
+impl Default for SpeedSettings {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+/// Converts samples produced at `factor` times the normal speed into samples played back at the normal rate
+///
+/// The output contains `input.len() / factor` samples.
+///
+/// When preserving pitch the input is split into blocks of samples that are skipped or repeated as a whole,
+/// so the waveform inside each block is played back unchanged, apart from its first samples being crossfaded
+/// from where the previous block would have continued.
+/// Otherwise the input is resampled, which scales the pitch along with the speed.
+pub fn stretch(input: &[(u16, u16)], factor: f64, preserve_pitch: bool) -> Vec<(u16, u16)> {
+    if input.is_empty() || factor <= 0.0 {
+        return Vec::new();
+    }
+
+    let len = (input.len() as f64 / factor) as usize;
+    let sample = |src: usize| input[src.min(input.len() - 1)];
+    let start = |grain: usize| (grain as f64 * GRAIN as f64 * factor) as usize;
+    (0..len).map(|i| {
+        if !preserve_pitch {return sample((i as f64 * factor) as usize)}
+        let (grain, offset) = (i / GRAIN, i % GRAIN);
+        let current = sample(start(grain) + offset);
+        if grain == 0 || offset >= OVERLAP {return current}
+        // Linear window over the seam, the previous block fading out as this one fades in
+        let previous = sample(start(grain - 1) + GRAIN + offset);
+        let weight = offset as u32 + 1;
+        let mix = |from: u16, to: u16| ((from as u32 * (OVERLAP as u32 + 1 - weight) + to as u32 * weight) / (OVERLAP as u32 + 1)) as u16;
+        (mix(previous.0, current.0), mix(previous.1, current.1))
+    }).collect()
+}
+
+#[cfg(test)]
+#[cfg_attr(coverage_nightly, coverage(off))]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_stretch_normal_speed() {
+        let input: Vec<_> = (0..1024).map(|i| (i, i)).collect();
+        assert_eq!(stretch(&input, 1.0, true), input);
+        assert_eq!(stretch(&input, 1.0, false), input);
+    }
+
+    #[test]
+    fn test_stretch_double_speed_preserves_grains() {
+        let input: Vec<_> = (0..2048).map(|i| (i, i)).collect();
+        let output = stretch(&input, 2.0, true);
+        assert_eq!(output.len(), 1024);
+        assert_eq!(&output[..GRAIN], &input[..GRAIN]);
+        assert_eq!(&output[GRAIN + OVERLAP..2 * GRAIN], &input[2 * GRAIN + OVERLAP..3 * GRAIN]);
+        // The seam is crossfaded from where the first block would have continued instead of jumping ahead
+        assert_eq!(output[GRAIN], (263, 263));
+        assert!(output.windows(2).all(|pair| pair[1].0 > pair[0].0 && pair[1].0 - pair[0].0 < 16));
+    }
+
+    #[test]
+    fn test_stretch_double_speed_scales_pitch() {
+        let input: Vec<_> = (0..2048).map(|i| (i, i)).collect();
+        let output = stretch(&input, 2.0, false);
+        assert_eq!(output.len(), 1024);
+        assert_eq!(output[1], (2, 2));
+        assert_eq!(output[1023], (2046, 2046));
+    }
+
+    #[test]
+    fn test_stretch_half_speed_repeats_grains() {
+        let input: Vec<_> = (0..512).map(|i| (i, i)).collect();
+        let output = stretch(&input, 0.5, true);
+        assert_eq!(output.len(), 1024);
+        assert_eq!(&output[..GRAIN], &input[..GRAIN]);
+        assert_eq!(&output[GRAIN + OVERLAP..2 * GRAIN], &input[GRAIN / 2 + OVERLAP..GRAIN / 2 + GRAIN]);
+        assert!(output.windows(2).all(|pair| pair[1].0.abs_diff(pair[0].0) < 16));
+    }
+
+    #[test]
+    fn test_speed_steps() {
+        assert_eq!(Speed::Half.slower(), Speed::Half);
+        assert_eq!(Speed::Normal.faster(), Speed::Double);
+        assert_eq!(Speed::Double.faster(), Speed::Unlimited);
+        assert_eq!(Speed::Unlimited.faster(), Speed::Unlimited);
+        assert_eq!(Speed::Double.frame_time(), Duration::from_micros(FRAME_TIME_US / 2));
+        assert_eq!(Speed::Unlimited.frame_time(), Duration::ZERO);
+    }
+
+    #[test]
+    #[cfg(feature = "std")]
+    fn test_settings_saved() {
+        let game = std::env::temp_dir().join(format!("wondercrab-speed-{}", std::process::id())).to_string_lossy().into_owned();
+        assert_eq!(SpeedSettings::load(&game), None);
+        let settings = SpeedSettings {speed: Speed::Double, preserve_pitch: false};
+        settings.save(&game).unwrap();
+        assert_eq!(SpeedSettings::load(&game), Some(settings));
+        std::fs::remove_file(format!("{}.speed", game)).unwrap();
+        assert_eq!(Speed::from_name("unlimited"), Some(Speed::Unlimited));
+    }
+}
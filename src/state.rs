@@ -0,0 +1,401 @@
+use alloc::{boxed::Box, string::String, vec::Vec};
+use core::fmt;
+
+use crate::png::crc32;
+
+/// Errors that can occur while loading a save state
+#[derive(Clone, PartialEq, Eq, Debug)]
+pub enum StateError {
+    /// The state ended before all of the fields could be read
+    UnexpectedEnd,
+    /// A component was found where a different one was expected
+    WrongComponent { expected: [u8; 4], found: [u8; 4] },
+    /// The component was saved by a newer version of the emulator
+    UnsupportedVersion { component: [u8; 4], version: u16, supported: u16 },
+    /// The state does not match the hardware it is being loaded into, for example an EEPROM of a different size
+    Mismatch(&'static str),
+    /// A component's data differs from the checksum saved along with it, the state was corrupted after being saved
+    Checksum { component: [u8; 4] },
+    /// A component's fields could not be loaded, wraps the error of the innermost component it happened in
+    InComponent { component: [u8; 4], error: Box<StateError> },
+}
+
+impl fmt::Display for StateError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            StateError::UnexpectedEnd => write!(f, "save state is truncated"),
+            StateError::WrongComponent { expected, found } => write!(
+                f, "expected component {} but found {}",
+                String::from_utf8_lossy(expected), String::from_utf8_lossy(found),
+            ),
+            StateError::UnsupportedVersion { component, version, supported } => write!(
+                f, "component {} has version {} but only versions up to {} are supported",
+                String::from_utf8_lossy(component), version, supported,
+            ),
+            StateError::Mismatch(what) => write!(f, "save state does not match the loaded ROM: {}", what),
+            StateError::Checksum { component } => write!(f, "component {} does not match its checksum, the save state is corrupted", String::from_utf8_lossy(component)),
+            StateError::InComponent { component, error } => write!(f, "component {}: {}", String::from_utf8_lossy(component), error),
+        }
+    }
+}
+
+/// Trait shared by every component that is part of a save state
+///
+/// Each component is written as a header followed by its fields.
+/// The header contains a four byte tag, the version of the layout of the fields and the length of the fields in bytes.
+///
+/// Loading checks the tag and refuses components with a newer version than `VERSION`,
+/// older versions are handed to `load_fields` so that fields added since can be given their default values.
+pub trait Snapshot {
+    /// Tag identifying the component
+    const TAG: [u8; 4];
+    /// Current version of the component's layout, must be incremented whenever the layout changes
+    const VERSION: u16;
+
+    /// Writes the component's fields
+    fn save_fields(&self, w: &mut StateWriter);
+    /// Reads fields written by `version` of the component's layout
+    ///
+    /// Implementations should validate everything before modifying the component,
+    /// so that a failed load leaves it untouched.
+    fn load_fields(&mut self, r: &mut StateReader, version: u16) -> Result<(), StateError>;
+
+    /// Writes the component, header included
+    fn save_state(&self, w: &mut StateWriter) {
+        w.write_bytes(&Self::TAG);
+        w.write_u16(Self::VERSION);
+        let len_pos = w.data.len();
+        w.write_u32(0);
+        self.save_fields(w);
+        let len = (w.data.len() - len_pos - 4) as u32;
+        w.data[len_pos..len_pos + 4].copy_from_slice(&len.to_le_bytes());
+    }
+
+    /// Reads the component, header included
+    fn load_state(&mut self, r: &mut StateReader) -> Result<(), StateError> {
+        let tag = r.read_array::<4>()?;
+        if tag != Self::TAG {
+            return Err(StateError::WrongComponent { expected: Self::TAG, found: tag });
+        }
+        let version = r.read_u16()?;
+        if version > Self::VERSION {
+            return Err(StateError::UnsupportedVersion { component: tag, version, supported: Self::VERSION });
+        }
+        let len = r.read_u32()? as usize;
+        let mut fields = StateReader::new(r.read_bytes(len)?);
+        // Errors that already name a component come from a component nested in this one
+        self.load_fields(&mut fields, version).map_err(|error| match error {
+            StateError::UnexpectedEnd | StateError::Mismatch(_) => StateError::InComponent { component: Self::TAG, error: Box::new(error) },
+            error => error,
+        })
+    }
+}
+
+/// Computes the CRC-32 of each component in a run of components, as written by [`Snapshot::save_state`]
+///
+/// Containers write the checksums after their components with [`write_checksums`], so that loading can tell which of them was corrupted.
+pub fn checksums(records: &[u8]) -> Result<Vec<([u8; 4], u32)>, StateError> {
+    let mut r = StateReader::new(records);
+    let mut checksums = Vec::new();
+    while r.remaining() > 0 {
+        let start = r.pos;
+        let tag = r.read_array::<4>()?;
+        r.read_u16()?;
+        let len = r.read_u32()? as usize;
+        r.read_bytes(len)?;
+        checksums.push((tag, crc32(&records[start..r.pos])));
+    }
+    Ok(checksums)
+}
+
+/// Writes the checksums of the components written since `start`
+pub fn write_checksums(w: &mut StateWriter, start: usize) {
+    let checksums = checksums(&w.data[start..]).unwrap();
+    w.write_u8(checksums.len() as u8);
+    for (tag, checksum) in checksums {
+        w.write_bytes(&tag);
+        w.write_u32(checksum);
+    }
+}
+
+/// Checks the `count` components at the start of `data` against the checksums written after them, returns the first that does not match
+///
+/// Meant to be called before loading any of the components, so that a corrupted state is refused as a whole.
+pub fn verify_checksums(data: &[u8], count: usize) -> Result<(), StateError> {
+    let mut r = StateReader::new(data);
+    for _ in 0..count {
+        r.read_bytes(6)?;
+        let len = r.read_u32()? as usize;
+        r.read_bytes(len)?;
+    }
+    let records = &data[..r.pos];
+    let saved = (0..r.read_u8()?).map(|_| Ok((r.read_array::<4>()?, r.read_u32()?))).collect::<Result<Vec<_>, StateError>>()?;
+    let checksums = checksums(records)?;
+    if saved.len() != checksums.len() {return Err(StateError::Mismatch("amount of component checksums"))}
+    match checksums.into_iter().zip(saved).find(|(checksum, saved)| checksum != saved) {
+        Some(((component, _), _)) => Err(StateError::Checksum { component }),
+        None => Ok(()),
+    }
+}
+
+/// Builds a save state out of little-endian values
+pub struct StateWriter {
+    /// The bytes written so far
+    data: Vec<u8>,
+}
+
+impl StateWriter {
+    /// Creates an empty writer
+    pub fn new() -> Self {
+        Self {data: Vec::new()}
+    }
+
+    /// Returns the amount of bytes written so far, where the next value will be written
+    pub fn position(&self) -> usize {
+        self.data.len()
+    }
+
+    /// Returns the finished state
+    pub fn finish(self) -> Vec<u8> {
+        self.data
+    }
+
+    /// Writes a single byte
+    pub fn write_u8(&mut self, value: u8) {
+        self.data.push(value);
+    }
+
+    /// Writes a boolean as a single byte, 1 for true and 0 for false
+    pub fn write_bool(&mut self, value: bool) {
+        self.data.push(value as u8);
+    }
+
+    /// Writes a 16-bit value as two little-endian bytes
+    pub fn write_u16(&mut self, value: u16) {
+        self.data.extend(value.to_le_bytes());
+    }
+
+    /// Writes a 32-bit value as four little-endian bytes
+    pub fn write_u32(&mut self, value: u32) {
+        self.data.extend(value.to_le_bytes());
+    }
+
+    /// Writes a 64-bit value as eight little-endian bytes
+    pub fn write_u64(&mut self, value: u64) {
+        self.data.extend(value.to_le_bytes());
+    }
+
+    /// Writes raw bytes without a length
+    pub fn write_bytes(&mut self, bytes: &[u8]) {
+        self.data.extend_from_slice(bytes);
+    }
+
+    /// Writes a vector of bytes preceded by its length
+    pub fn write_vec(&mut self, bytes: &[u8]) {
+        self.write_u32(bytes.len() as u32);
+        self.write_bytes(bytes);
+    }
+}
+
+impl Default for StateWriter {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+/// Reads little-endian values out of a save state
+pub struct StateReader<'a> {
+    /// The state being read
+    data: &'a [u8],
+    /// Index of the next byte to be read
+    pos: usize,
+}
+
+impl<'a> StateReader<'a> {
+    /// Creates a reader starting at the beginning of the state
+    pub fn new(data: &'a [u8]) -> Self {
+        Self {data, pos: 0}
+    }
+
+    /// Returns the amount of bytes left to read
+    pub fn remaining(&self) -> usize {
+        self.data.len() - self.pos
+    }
+
+    /// Returns the bytes left to read without reading them
+    pub fn rest(&self) -> &'a [u8] {
+        &self.data[self.pos..]
+    }
+
+    /// Reads raw bytes of a known length
+    pub fn read_bytes(&mut self, len: usize) -> Result<&'a [u8], StateError> {
+        let bytes = self.data.get(self.pos..self.pos + len).ok_or(StateError::UnexpectedEnd)?;
+        self.pos += len;
+        Ok(bytes)
+    }
+
+    /// Reads `N` raw bytes, fails with [`StateError::UnexpectedEnd`] without consuming anything if fewer are left
+    pub fn read_array<const N: usize>(&mut self) -> Result<[u8; N], StateError> {
+        Ok(self.read_bytes(N)?.try_into().unwrap())
+    }
+
+    /// Reads a single byte, fails with [`StateError::UnexpectedEnd`] at the end of the state
+    pub fn read_u8(&mut self) -> Result<u8, StateError> {
+        Ok(self.read_array::<1>()?[0])
+    }
+
+    /// Reads a boolean stored as a single byte, any non-zero byte is true, fails with [`StateError::UnexpectedEnd`] at the end of the state
+    pub fn read_bool(&mut self) -> Result<bool, StateError> {
+        Ok(self.read_u8()? != 0)
+    }
+
+    /// Reads a 16-bit value from two little-endian bytes, fails with [`StateError::UnexpectedEnd`] without consuming anything if fewer are left
+    pub fn read_u16(&mut self) -> Result<u16, StateError> {
+        Ok(u16::from_le_bytes(self.read_array()?))
+    }
+
+    /// Reads a 32-bit value from four little-endian bytes, fails with [`StateError::UnexpectedEnd`] without consuming anything if fewer are left
+    pub fn read_u32(&mut self) -> Result<u32, StateError> {
+        Ok(u32::from_le_bytes(self.read_array()?))
+    }
+
+    /// Reads a 64-bit value from eight little-endian bytes, fails with [`StateError::UnexpectedEnd`] without consuming anything if fewer are left
+    pub fn read_u64(&mut self) -> Result<u64, StateError> {
+        Ok(u64::from_le_bytes(self.read_array()?))
+    }
+
+    /// Reads a vector of bytes preceded by its length
+    pub fn read_vec(&mut self) -> Result<Vec<u8>, StateError> {
+        let len = self.read_u32()? as usize;
+        Ok(self.read_bytes(len)?.to_vec())
+    }
+}
+
+/// Starting value of FNV-1a hashes
+pub const FNV_OFFSET: u64 = 0xCBF2_9CE4_8422_2325;
+
+/// 64-bit FNV-1a, used because its output is stable across Rust versions unlike the standard library's hasher
+/// 
+/// Continues hashing from `hash`, which is [`FNV_OFFSET`] for a new hash.
+pub fn fnv1a(hash: u64, data: &[u8]) -> u64 {
+    data.iter().fold(hash, |hash, byte| (hash ^ *byte as u64).wrapping_mul(0x0100_0000_01B3))
+}
+
+#[cfg(test)]
+#[cfg_attr(coverage_nightly, coverage(off))]
+mod test {
+    use alloc::{string::ToString, vec};
+
+    use crate::{bus::io_bus::{keypad::Keys, IOBus, IOBusConnection}, cartridge::Cartridge};
+
+    use super::*;
+
+    struct Counter {
+        count: u16,
+        step: u16,
+    }
+
+    impl Snapshot for Counter {
+        const TAG: [u8; 4] = *b"TEST";
+        const VERSION: u16 = 2;
+
+        fn save_fields(&self, w: &mut StateWriter) {
+            w.write_u16(self.count);
+            w.write_u16(self.step);
+        }
+
+        fn load_fields(&mut self, r: &mut StateReader, version: u16) -> Result<(), StateError> {
+            let count = r.read_u16()?;
+            // Version 1 did not have a step
+            let step = if version >= 2 {r.read_u16()?} else {1};
+            (self.count, self.step) = (count, step);
+            Ok(())
+        }
+    }
+
+    fn test_io_bus() -> IOBus {
+        let cartridge = alloc::rc::Rc::new(core::cell::RefCell::new(Cartridge::test_build()));
+        IOBus::new(cartridge, vec![0; 0x80], Some(vec![0; 0x400]), false, 0)
+    }
+
+    #[test]
+    fn test_component_versions() {
+        let mut w = StateWriter::new();
+        w.write_bytes(b"TEST");
+        w.write_u16(1);
+        w.write_u32(2);
+        w.write_u16(0x1234);
+        let old = w.finish();
+
+        let mut counter = Counter {count: 0, step: 5};
+        counter.load_state(&mut StateReader::new(&old)).unwrap();
+        assert_eq!((counter.count, counter.step), (0x1234, 1));
+
+        let mut newer = old.clone();
+        newer[4] = 3;
+        assert_eq!(
+            counter.load_state(&mut StateReader::new(&newer)),
+            Err(StateError::UnsupportedVersion {component: *b"TEST", version: 3, supported: 2}),
+        );
+
+        let mut wrong = old.clone();
+        wrong[0] = b'X';
+        assert!(matches!(counter.load_state(&mut StateReader::new(&wrong)), Err(StateError::WrongComponent {..})));
+        assert_eq!(counter.load_state(&mut StateReader::new(&old[..8])), Err(StateError::UnexpectedEnd));
+    }
+
+    #[test]
+    fn test_eeprom_mid_command_round_trip() {
+        let mut io_bus = test_io_bus();
+        // Latch a WRITE of 0xBEEF to address 3 without starting it
+        io_bus.write_io_16(0xC4, 0xBEEF);
+        io_bus.write_io_16(0xC6, 0x0143);
+        // HBLANK timer reload, also sets the counter
+        io_bus.write_io_16(0xA4, 0x1234);
+        io_bus.set_key(Keys::A, true);
+
+        let mut w = StateWriter::new();
+        io_bus.save_state(&mut w);
+        let state = w.finish();
+
+        let mut restored = test_io_bus();
+        restored.load_state(&mut StateReader::new(&state)).unwrap();
+        assert_eq!(restored.read_io_16(0xA8), (0x34, 0x12));
+
+        let mut w = StateWriter::new();
+        restored.save_state(&mut w);
+        assert_eq!(w.finish(), state);
+
+        restored.write_io(0xC8, 0x20);
+        assert_eq!(restored.eeprom.as_ref().unwrap().contents[6..8], [0xEF, 0xBE]);
+    }
+
+    #[test]
+    fn test_eeprom_size_mismatch() {
+        let io_bus = test_io_bus();
+        let mut w = StateWriter::new();
+        io_bus.save_state(&mut w);
+        let state = w.finish();
+
+        let cartridge = alloc::rc::Rc::new(core::cell::RefCell::new(Cartridge::test_build()));
+        let mut other = IOBus::new(cartridge, vec![0; 0x80], Some(vec![0; 0x2000]), false, 0);
+        let error = other.load_state(&mut StateReader::new(&state)).unwrap_err();
+        assert!(matches!(&error, StateError::InComponent {component, error} if component == b"EEPR" && matches!(**error, StateError::Mismatch(_))));
+        assert!(error.to_string().starts_with("component EEPR: "));
+    }
+
+    #[test]
+    fn test_checksums() {
+        let mut w = StateWriter::new();
+        Counter {count: 1, step: 2}.save_state(&mut w);
+        Counter {count: 3, step: 4}.save_state(&mut w);
+        write_checksums(&mut w, 0);
+        let mut state = w.finish();
+        assert_eq!(verify_checksums(&state, 2), Ok(()));
+
+        // Corrupts the second counter's step
+        state[12 + 10 + 2] ^= 1;
+        assert_eq!(verify_checksums(&state, 2), Err(StateError::Checksum {component: *b"TEST"}));
+        assert_eq!(verify_checksums(&state[..state.len() - 1], 2), Err(StateError::UnexpectedEnd));
+    }
+}
@@ -0,0 +1,125 @@
+use alloc::{collections::BTreeMap, format, string::{String, ToString}, vec, vec::Vec};
+
+use crate::{bus::io_bus::IOBus, cartridge::rtc::Rtc};
+
+/// Where the files the emulator keeps between runs are stored, such as save media
+///
+/// Files are named as they are next to the ROM on disk, like `game.sram` or `wsc.ieeprom`,
+/// so that a front-end without a filesystem can map them to flash, a key-value store or anything else it has.
+pub trait Storage {
+    /// Returns the contents of a file, `None` if it does not exist or cannot be read
+    fn read(&self, name: &str) -> Option<Vec<u8>>;
+
+    /// Replaces the contents of a file, creating it if needed
+    fn write(&mut self, name: &str, contents: &[u8]) -> Result<(), String>;
+}
+
+/// Files on disk, names being paths relative to the working directory
+#[cfg(feature = "std")]
+pub struct FileStorage;
+
+#[cfg(feature = "std")]
+impl Storage for FileStorage {
+    fn read(&self, name: &str) -> Option<Vec<u8>> {
+        std::fs::read(name).ok()
+    }
+
+    fn write(&mut self, name: &str, contents: &[u8]) -> Result<(), String> {
+        std::fs::write(name, contents).map_err(|e| format!("Could not write {}: {}", name, e))
+    }
+}
+
+/// Files kept in memory, for front-ends that persist them their own way and for tests
+#[derive(Clone, PartialEq, Eq, Debug, Default)]
+pub struct MemoryStorage {
+    /// Contents of each file by name
+    pub files: BTreeMap<String, Vec<u8>>,
+}
+
+impl Storage for MemoryStorage {
+    fn read(&self, name: &str) -> Option<Vec<u8>> {
+        self.files.get(name).cloned()
+    }
+
+    fn write(&mut self, name: &str, contents: &[u8]) -> Result<(), String> {
+        self.files.insert(name.to_string(), contents.to_vec());
+        Ok(())
+    }
+}
+
+/// The rewritable memories of the console and its cartridge, along with the names of their files
+///
+/// - IEEPROM in either wsc.ieeprom or ws.ieeprom depending on color, shared by every game
+/// - Cart EEPROM in \[game\].eeprom
+/// - SRAM in \[game\].sram
+/// - The time set on the cartridge's real-time clock in \[game\].rtc, see [`Rtc::battery`](crate::cartridge::rtc::Rtc::battery)
+#[derive(Clone, PartialEq, Eq, Debug, Default)]
+pub struct SaveMedia {
+    /// Contents of the internal EEPROM, empty to have the SoC format a blank one
+    pub ieeprom: Vec<u8>,
+    /// Contents of the cartridge EEPROM, empty if it has none or it is blank
+    pub eeprom: Vec<u8>,
+    /// Contents of the cartridge SRAM
+    pub sram: Vec<u8>,
+    /// What the battery of the cartridge's real-time clock keeps, empty if it has none or it was never stored
+    pub rtc: Vec<u8>,
+}
+
+impl SaveMedia {
+    /// Returns the names of the IEEPROM, cart EEPROM, SRAM and RTC files of a game
+    pub fn names(game: &str, color: bool) -> [String; 4] {
+        let ieeprom = if color {"wsc.ieeprom"} else {"ws.ieeprom"};
+        [ieeprom.to_string(), format!("{}.eeprom", game), format!("{}.sram", game), format!("{}.rtc", game)]
+    }
+
+    /// Reads a game's save media, those without a file start blank with SRAM of the given size
+    pub fn load(storage: &dyn Storage, game: &str, color: bool, ram_size: usize) -> Self {
+        let [ieeprom, eeprom, sram, rtc] = Self::names(game, color);
+        Self {
+            ieeprom: storage.read(&ieeprom).unwrap_or_default(),
+            eeprom: storage.read(&eeprom).unwrap_or_default(),
+            sram: storage.read(&sram).unwrap_or_else(|| vec![0; ram_size]),
+            rtc: storage.read(&rtc).unwrap_or_default(),
+        }
+    }
+
+    /// Copies the save media out of a running console
+    pub fn from_io_bus(io_bus: &IOBus) -> Self {
+        Self {
+            ieeprom: io_bus.ieeprom.contents.clone(),
+            eeprom: io_bus.eeprom.as_ref().map(|eeprom| eeprom.contents.clone()).unwrap_or_default(),
+            sram: io_bus.cartridge.borrow().sram.clone(),
+            rtc: io_bus.cartridge.borrow().rtc.as_ref().map(Rtc::battery).unwrap_or_default(),
+        }
+    }
+
+    /// Writes a game's save media, the cartridge's are skipped if it does not have them
+    pub fn store(&self, storage: &mut dyn Storage, game: &str, color: bool) -> Result<(), String> {
+        let [ieeprom, eeprom, sram, rtc] = Self::names(game, color);
+        storage.write(&ieeprom, &self.ieeprom)?;
+        if !self.eeprom.is_empty() {storage.write(&eeprom, &self.eeprom)?}
+        if !self.sram.is_empty() {storage.write(&sram, &self.sram)?}
+        if !self.rtc.is_empty() {storage.write(&rtc, &self.rtc)?}
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+#[cfg_attr(coverage_nightly, coverage(off))]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_save_media() {
+        let mut storage = MemoryStorage::default();
+        let blank = SaveMedia::load(&storage, "game", true, 0x8000);
+        assert_eq!((blank.ieeprom.len(), blank.eeprom.len(), blank.sram.len()), (0, 0, 0x8000));
+
+        let saves = SaveMedia {ieeprom: vec![1; 0x800], eeprom: Vec::new(), sram: vec![2; 0x8000], rtc: Vec::new()};
+        saves.store(&mut storage, "game", true).unwrap();
+        assert_eq!(storage.files.keys().collect::<Vec<_>>(), ["game.sram", "wsc.ieeprom"]);
+        assert_eq!(SaveMedia::load(&storage, "game", true, 0x8000), saves);
+        // Mono consoles have their own internal EEPROM
+        assert!(SaveMedia::load(&storage, "game", false, 0x8000).ieeprom.is_empty());
+    }
+}
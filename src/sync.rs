@@ -0,0 +1,90 @@
+pub use alloc::sync::Arc;
+
+#[cfg(feature = "std")]
+pub use std::sync::{Mutex, MutexGuard};
+
+#[cfg(not(feature = "std"))]
+pub use spin::{Mutex, MutexGuard};
+
+/// Stand-in for the standard library's mutex on targets without it
+#[cfg(not(feature = "std"))]
+mod spin {
+    use core::{cell::UnsafeCell, convert::Infallible, hint, ops::{Deref, DerefMut}, sync::atomic::{AtomicBool, Ordering}};
+
+    /// A lock that spins until it is released
+    ///
+    /// The options and samples are only ever held for a few instructions, and hosts without the standard library usually run
+    /// the emulator and its audio on a single core, so spinning is cheaper than anything the core could wait on instead.
+    /// Locking cannot fail, it returns a result to be used the same way as the standard library's, which reports poisoning.
+    #[derive(Default)]
+    pub struct Mutex<T> {
+        /// Set while a guard exists
+        locked: AtomicBool,
+        /// The value behind the lock
+        value: UnsafeCell<T>,
+    }
+
+    // The value is only ever reached through a guard, of which there is at most one at a time
+    unsafe impl<T: Send> Send for Mutex<T> {}
+    unsafe impl<T: Send> Sync for Mutex<T> {}
+
+    impl<T> Mutex<T> {
+        /// Creates an unlocked mutex holding a value
+        pub const fn new(value: T) -> Self {
+            Self {locked: AtomicBool::new(false), value: UnsafeCell::new(value)}
+        }
+
+        /// Waits until the lock is free and takes it
+        pub fn lock(&self) -> Result<MutexGuard<'_, T>, Infallible> {
+            while self.locked.compare_exchange_weak(false, true, Ordering::Acquire, Ordering::Relaxed).is_err() {
+                hint::spin_loop();
+            }
+            Ok(MutexGuard {mutex: self})
+        }
+    }
+
+    /// Access to the value of a locked mutex, releasing the lock when dropped
+    pub struct MutexGuard<'a, T> {
+        /// The mutex being held
+        mutex: &'a Mutex<T>,
+    }
+
+    impl<T> Deref for MutexGuard<'_, T> {
+        type Target = T;
+
+        fn deref(&self) -> &T {
+            // The guard holds the lock
+            unsafe {&*self.mutex.value.get()}
+        }
+    }
+
+    impl<T> DerefMut for MutexGuard<'_, T> {
+        fn deref_mut(&mut self) -> &mut T {
+            // The guard holds the lock, and is borrowed mutably
+            unsafe {&mut *self.mutex.value.get()}
+        }
+    }
+
+    impl<T> Drop for MutexGuard<'_, T> {
+        fn drop(&mut self) {
+            self.mutex.locked.store(false, Ordering::Release);
+        }
+    }
+}
+
+#[cfg(test)]
+#[cfg_attr(coverage_nightly, coverage(off))]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_mutex() {
+        let shared = Arc::new(Mutex::new(1));
+        *shared.lock().unwrap() += 1;
+        // The guard above was dropped with its statement, so the lock is free again
+        let mut guard = shared.lock().unwrap();
+        *guard *= 3;
+        drop(guard);
+        assert_eq!(*Arc::clone(&shared).lock().unwrap(), 6);
+    }
+}
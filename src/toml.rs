@@ -0,0 +1,89 @@
+use alloc::{format, string::{String, ToString}};
+
+/// A value of the TOML subset
+#[derive(Clone, PartialEq, Eq, Debug)]
+pub enum Value {
+    /// A string between double quotes, escapes are not supported
+    String(String),
+    /// An integer in decimal or, prefixed with `0x`, hexadecimal, underscores being ignored
+    Integer(usize),
+    /// `true` or `false`
+    Boolean(bool),
+}
+
+impl Value {
+    /// Parses a value, dropping any comment after it
+    pub fn parse(raw: &str) -> Result<Self, String> {
+        let raw = raw.trim();
+        if let Some(rest) = raw.strip_prefix('"') {
+            let (string, rest) = rest.split_once('"').ok_or("Unterminated string")?;
+            let rest = rest.trim();
+            if !rest.is_empty() && !rest.starts_with('#') {return Err(format!("Unexpected {} after a string", rest))}
+            return Ok(Value::String(string.to_string()));
+        }
+        let raw = raw.split('#').next().unwrap().trim().replace('_', "");
+        match raw.as_str() {
+            "true" => Ok(Value::Boolean(true)),
+            "false" => Ok(Value::Boolean(false)),
+            raw => match raw.strip_prefix("0x") {
+                Some(hex) => usize::from_str_radix(hex, 16),
+                None => raw.parse(),
+            }.map(Value::Integer).map_err(|_| format!("Invalid value {}", raw)),
+        }
+    }
+}
+
+/// A line of a document that is not blank or a comment
+#[derive(Clone, PartialEq, Eq, Debug)]
+pub enum Line {
+    /// A `[name]` header, the keys after it up to the next header belong to the table
+    Table(String),
+    /// A `key = value` pair
+    Pair(String, Value),
+}
+
+/// Parses the lines of a document, each along with its number counting from 1
+///
+/// Only this flat subset of TOML is understood: bare table names, bare keys, strings, integers, booleans and comments.
+/// Errors are prefixed with the number of the line they are on.
+pub fn lines(text: &str) -> impl Iterator<Item = Result<(usize, Line), String>> + '_ {
+    text.lines().enumerate().filter_map(|(number, line)| {
+        let (number, line) = (number + 1, line.trim());
+        if line.is_empty() || line.starts_with('#') {return None}
+        let error = |e: String| format!("line {}: {}", number, e);
+        if let Some(table) = line.strip_prefix('[') {
+            return Some(match table.split_once(']') {
+                Some((name, rest)) if rest.trim().is_empty() || rest.trim().starts_with('#') => Ok((number, Line::Table(name.trim().to_string()))),
+                _ => Err(error(format!("Invalid table header {}", line))),
+            });
+        }
+        Some(line.split_once('=')
+            .ok_or_else(|| error(format!("Expected key = value, found {}", line)))
+            .and_then(|(key, value)| Ok((number, Line::Pair(key.trim().to_string(), Value::parse(value).map_err(error)?)))))
+    })
+}
+
+#[cfg(test)]
+#[cfg_attr(coverage_nightly, coverage(off))]
+mod test {
+    use alloc::vec::Vec;
+
+    use super::*;
+
+    #[test]
+    fn test_lines() {
+        let text = "# comment\n[game] # first\ntitle = \"Some # Game\" # trailing\n\nsize = 0x80_00\nrtc = true\n";
+        let parsed: Vec<_> = lines(text).collect::<Result<_, _>>().unwrap();
+        assert_eq!(parsed, [
+            (2, Line::Table("game".to_string())),
+            (3, Line::Pair("title".to_string(), Value::String("Some # Game".to_string()))),
+            (5, Line::Pair("size".to_string(), Value::Integer(0x8000))),
+            (6, Line::Pair("rtc".to_string(), Value::Boolean(true))),
+        ]);
+
+        assert!(lines("title = \"unterminated").next().unwrap().unwrap_err().contains("line 1"));
+        assert!(lines("\n\nsize = big").next().unwrap().unwrap_err().starts_with("line 3: Invalid value big"));
+        assert!(lines("[game] extra").next().unwrap().is_err());
+        assert!(lines("[game").next().unwrap().is_err());
+    }
+}
@@ -0,0 +1,257 @@
+use std::fmt::{self, Display};
+
+use crate::cpu::{trace::{self, Step, TraceReader}, v30mz::V30MZ};
+
+/// Most differing steps listed by a full comparison before the rest is cut
+pub const MAX_LISTED: usize = 32;
+
+/// Short names of the PSW bits a program can observe, fixed bits are left out
+pub const FLAG_NAMES: [(u16, &str); 9] = [
+    (0x0800, "V"), (0x0400, "DIR"), (0x0200, "IE"), (0x0100, "BRK"),
+    (0x0080, "S"), (0x0040, "Z"), (0x0010, "AC"), (0x0004, "P"), (0x0001, "CY"),
+];
+/// The bits of [`FLAG_NAMES`]
+const FLAG_MASK: u16 = 0x0FD5;
+
+/// An instruction of a CPU trace, along with the registers before it executed
+#[derive(Clone, PartialEq, Eq, Debug)]
+pub struct TraceStep {
+    /// Physical address of the instruction
+    pub address: u32,
+    /// First byte of the instruction
+    pub code: u8,
+    /// Mnemonic
+    pub name: String,
+    /// Registers in the order of [`V30MZ::REGISTER_NAMES`]
+    pub registers: [u16; 13],
+    /// Value of the PSW
+    pub psw: u16,
+}
+
+impl Display for TraceStep {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{:05X} {:02X} {}", self.address, self.code, self.name)
+    }
+}
+
+impl From<Step> for TraceStep {
+    fn from(step: Step) -> Self {
+        Self {address: step.address(), code: step.code, name: step.name().to_string(), registers: step.registers, psw: step.psw}
+    }
+}
+
+/// Reads a trace from a file, either printed by the CPU or recorded in the binary format of [`trace::TraceWriter`]
+pub fn read_trace(path: &str) -> Result<Vec<TraceStep>, String> {
+    let bytes = std::fs::read(path).map_err(|e| format!("Could not read {}: {}", path, e))?;
+    if trace::is_binary_trace(&bytes) {
+        let reader = TraceReader::new(&bytes[..]).map_err(|e| format!("{}: {}", path, e))?;
+        return reader.map(|step| step.map(TraceStep::from).map_err(|e| format!("{}: {}", path, e))).collect();
+    }
+    parse_trace(&String::from_utf8_lossy(&bytes))
+}
+
+/// Parses the trace the CPU prints with the trace option, a block of lines for each instruction
+///
+/// Lines that are not part of an instruction's block, such as exception notices, are skipped.
+pub fn parse_trace(text: &str) -> Result<Vec<TraceStep>, String> {
+    let mut steps = Vec::new();
+    let mut lines = text.lines().enumerate();
+    while let Some((number, line)) = lines.next() {
+        let words: Vec<&str> = line.split_whitespace().collect();
+        // Instruction lines are the only ones starting with a five digit address
+        let (Some(address), Some(code)) = (words.first().filter(|word| word.len() == 5), words.get(1)) else {continue};
+        let (Ok(address), Ok(code)) = (u32::from_str_radix(address, 16), u8::from_str_radix(code, 16)) else {continue};
+
+        let mut values = std::collections::HashMap::new();
+        for _ in 0..4 {
+            let (_, line) = lines.next().ok_or(format!("Line {}: truncated trace", number + 1))?;
+            for pair in line.split_whitespace().collect::<Vec<_>>().chunks(2) {
+                let [name, value] = pair else {return Err(format!("Line {}: invalid register line {}", number + 1, line))};
+                let value = u16::from_str_radix(value, 16).map_err(|_| format!("Line {}: invalid value {}", number + 1, value))?;
+                values.insert(name.trim_end_matches(':'), value);
+            }
+        }
+        let register = |name: &str| values.get(name).copied().ok_or(format!("Line {}: missing {}", number + 1, name));
+        let mut registers = [0; 13];
+        for (value, name) in registers.iter_mut().zip(V30MZ::REGISTER_NAMES) {*value = register(name)?}
+        steps.push(TraceStep {address, code, name: words[2..].join(" "), registers, psw: register("PSW")?});
+    }
+    Ok(steps)
+}
+
+/// Describes the flags that differ between two PSW values, such as `CY 0>1 Z 1>0`
+pub fn flag_changes(left: u16, right: u16) -> String {
+    FLAG_NAMES.iter().filter(|(bit, _)| (left ^ right) & bit != 0)
+        .map(|(bit, name)| format!("{} {}>{}", name, (left & bit != 0) as u8, (right & bit != 0) as u8))
+        .collect::<Vec<_>>().join(" ")
+}
+
+/// Where two traces of the same program first stop agreeing on the flags
+#[derive(Clone, PartialEq, Eq, Debug)]
+pub struct FlagDivergence {
+    /// Steps run at the same addresses with the same flags before the responsible instruction
+    pub identical: usize,
+    /// The instruction that set the flags differently, `None` if they already differed at the start
+    pub responsible: Option<TraceStep>,
+    /// Flags of the left trace after it
+    pub left: u16,
+    /// Flags of the right trace after it
+    pub right: u16,
+}
+
+impl Display for FlagDivergence {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match &self.responsible {
+            Some(step) => write!(f, "{} identical steps, then {} left {}", self.identical, step, flag_changes(self.left, self.right)),
+            None => write!(f, "The traces start with different flags {}", flag_changes(self.left, self.right)),
+        }
+    }
+}
+
+/// Finds the first step whose flags differ between two traces
+///
+/// Traces print the registers before each instruction, so the instruction responsible is the one before.
+/// Other registers are not compared, as a wrong flag usually shows up in them only later if at all, but the traces must still run the same instructions:
+/// if they go different ways before any flag differs that is returned as an error.
+pub fn first_flag_divergence(left: &[TraceStep], right: &[TraceStep]) -> Result<Option<FlagDivergence>, String> {
+    for (idx, (a, b)) in left.iter().zip(right).enumerate() {
+        if a.psw & FLAG_MASK != b.psw & FLAG_MASK {
+            let responsible = idx.checked_sub(1).map(|previous| left[previous].clone());
+            return Ok(Some(FlagDivergence {identical: idx.saturating_sub(1), responsible, left: a.psw, right: b.psw}));
+        }
+        if a.address != b.address {
+            return Err(format!("The traces run different instructions at step {}, {} and {}, with the same flags", idx, a, b));
+        }
+    }
+    Ok(None)
+}
+
+/// A run of steps of two traces that either all match or all differ
+#[derive(Clone, PartialEq, Eq, Debug)]
+pub enum Run {
+    /// Steps that are the same in both traces
+    Identical(usize),
+    /// A step that differs, with its index and both sides
+    Different(usize, TraceStep, TraceStep),
+}
+
+/// Compares two traces step by step, collapsing the steps they agree on into runs
+///
+/// Extra steps at the end of the longer trace are not compared.
+pub fn diff(left: &[TraceStep], right: &[TraceStep]) -> Vec<Run> {
+    let mut runs = Vec::new();
+    for (idx, (a, b)) in left.iter().zip(right).enumerate() {
+        match runs.last_mut() {
+            _ if a != b => runs.push(Run::Different(idx, a.clone(), b.clone())),
+            Some(Run::Identical(count)) => *count += 1,
+            _ => runs.push(Run::Identical(1)),
+        }
+    }
+    runs
+}
+
+impl Display for Run {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Run::Identical(count) => write!(f, "= {} identical steps", count),
+            Run::Different(idx, a, b) => {
+                write!(f, "! step {}: {}", idx, a)?;
+                if a.address != b.address || a.code != b.code {write!(f, " / {}", b)?}
+                for ((name, x), y) in V30MZ::REGISTER_NAMES.iter().zip(a.registers).zip(b.registers).filter(|((_, x), y)| x != y) {
+                    write!(f, " {} {:04X}/{:04X}", name, x, y)?;
+                }
+                if a.psw != b.psw {write!(f, " PSW {:04X}/{:04X} {}", a.psw, b.psw, flag_changes(a.psw, b.psw))?}
+                Ok(())
+            }
+        }
+    }
+}
+
+/// Entry point of the `tracediff` command
+///
+/// Usage: `tracediff <left> <right> [flags]`
+///
+/// Compares two traces printed by the CPU or recorded by `btrace`, such as a run before and after a change to an instruction.
+/// By default every differing step is listed with the registers that differ, identical steps being collapsed into a single line,
+/// while `flags` only prints the first instruction that set the flags differently.
+pub fn run(args: &[String]) -> Result<(), String> {
+    let [left, right, ..] = args else {return Err("Usage: tracediff <left> <right> [flags]".to_string())};
+    let (left, right) = (read_trace(left)?, read_trace(right)?);
+
+    if args.get(2).map(String::as_str) == Some("flags") {
+        match first_flag_divergence(&left, &right)? {
+            Some(divergence) => println!("{}", divergence),
+            None => println!("The flags never differ over {} steps", left.len().min(right.len())),
+        }
+        return Ok(());
+    }
+
+    let runs = diff(&left, &right);
+    let differing = runs.iter().filter(|run| matches!(run, Run::Different(..))).count();
+    let mut listed = 0;
+    for run in &runs {
+        if listed == MAX_LISTED {
+            println!("... {} more differing steps", differing - listed);
+            break;
+        }
+        if matches!(run, Run::Different(..)) {listed += 1}
+        println!("{}", run);
+    }
+    if left.len() != right.len() {println!("The traces have {} and {} steps", left.len(), right.len())}
+    Ok(())
+}
+
+#[cfg(test)]
+#[cfg_attr(coverage_nightly, coverage(off))]
+mod test {
+    use super::*;
+
+    /// Formats a step the way the CPU prints it
+    fn trace_block(address: u32, code: u8, name: &str, aw: u16, psw: u16) -> String {
+        format!(
+            "{:05X} {:02X} {}\nIY 0000 IX 0000 BP 0000 SP 2000\nBW 0000 DW 0000 CW 0000 AW {:04X}\nPC {:04X} PS F000 PSW: {:04X}\nDS0: 0000 DS1: 0000 SS 0000 PS F000\n\n",
+            address, code, name, aw, address & 0xFFFF, psw,
+        )
+    }
+
+    #[test]
+    fn test_parse_trace() {
+        let text = trace_block(0xF0000, 0x04, "ADD", 0x00FF, 0xF002) + "Exception raised: vector=00.\n" + &trace_block(0xF0002, 0x90, "NOP", 0x0100, 0xF057);
+        let steps = parse_trace(&text).unwrap();
+        assert_eq!(steps.len(), 2);
+        assert_eq!((steps[1].address, steps[1].code, steps[1].name.as_str(), steps[1].psw), (0xF0002, 0x90, "NOP", 0xF057));
+        assert_eq!((steps[1].registers[0], steps[1].registers[6], steps[1].registers[12]), (0x0100, 0xF000, 0x0002));
+        assert!(parse_trace(&trace_block(0xF0000, 0x04, "ADD", 0, 0)[..40]).is_err());
+    }
+
+    #[test]
+    fn test_first_flag_divergence() {
+        let step = |address: u32, psw: u16| TraceStep {address, code: 0x90, name: "NOP".to_string(), registers: [0; 13], psw};
+        let left = [step(0xF0000, 0xF002), step(0xF0001, 0xF002), step(0xF0002, 0xF003), step(0xF0003, 0xF003)];
+        let mut right = left.clone();
+        right[2].psw = 0xF042;
+        right[3].psw = 0xF042;
+
+        let divergence = first_flag_divergence(&left, &right).unwrap().unwrap();
+        assert_eq!((divergence.identical, divergence.responsible.as_ref().map(|step| step.address)), (1, Some(0xF0001)));
+        assert_eq!(divergence.to_string(), "1 identical steps, then F0001 90 NOP left Z 0>1 CY 1>0");
+        assert_eq!(first_flag_divergence(&left, &left), Ok(None));
+
+        right[1].address = 0xF0010;
+        assert!(first_flag_divergence(&left, &right).is_err());
+    }
+
+    #[test]
+    fn test_diff() {
+        let step = |aw: u16| TraceStep {address: 0xF0000, code: 0x40, name: "INC".to_string(), registers: [aw, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0], psw: 0xF002};
+        let left: Vec<_> = (0..10).map(step).collect();
+        let mut right = left.clone();
+        right[6].registers[0] = 7;
+
+        let runs = diff(&left, &right);
+        assert_eq!(runs.len(), 3);
+        assert_eq!(runs[0], Run::Identical(6));
+        assert_eq!(runs[1].to_string(), "! step 6: F0000 40 INC AW 0006/0007");
+        assert_eq!(runs[2], Run::Identical(3));
+    }
+}
@@ -0,0 +1,295 @@
+use std::{cell::RefCell, fmt::Debug, fs, panic, path::Path, rc::Rc};
+
+use crate::{bus::{io_bus::{IOBus, IOBusConnection}, mem_bus::{MemBus, MemBusConnection}}, cartridge::Cartridge, demo, options::{EmulatorOptions, SharedOptions}, regress::{self, Verdict}, soc::SoC, sound::{buffer::SampleBuffer, Sound}};
+
+/// Samples taken by the sound chip every second
+const SAMPLE_RATE: u32 = 24000;
+/// Parts of a second tones are played for, a tenth being long enough to tell frequencies apart by a single hertz
+const TONE_DIVIDER: u32 = 10;
+/// Hashes of the demo's frames 1 and 60
+const DEMO_HASHES: [u64; 2] = [0xD926_2EF4_01FB_7525, 0x549F_A6DD_16B3_ADA7];
+
+/// A check of one behaviour of a subsystem, returning what went wrong if it fails
+struct Check {
+    /// Subsystem the check is counted towards in the scorecard
+    subsystem: &'static str,
+    /// What is checked
+    name: &'static str,
+    /// Runs the check
+    run: fn() -> Result<(), String>,
+}
+
+/// Checks built into the emulator, in the order of the scorecard
+const CHECKS: &[Check] = &[
+    Check {subsystem: "CPU", name: "ADD sets carry and zero", run: cpu_add},
+    Check {subsystem: "CPU", name: "SUB sets overflow", run: cpu_sub},
+    Check {subsystem: "CPU", name: "MUL of words", run: cpu_mul},
+    Check {subsystem: "CPU", name: "DIV of bytes", run: cpu_div},
+    Check {subsystem: "CPU", name: "CALL and RET", run: cpu_call},
+    Check {subsystem: "CPU", name: "PUSH and POP", run: cpu_stack},
+    Check {subsystem: "CPU", name: "REP MOVBK", run: cpu_block},
+    Check {subsystem: "Sound", name: "500 Hz tone", run: || sound_frequency(1856, 500)},
+    Check {subsystem: "Sound", name: "1000 Hz tone", run: || sound_frequency(1952, 1000)},
+    Check {subsystem: "Sound", name: "2000 Hz tone", run: || sound_frequency(2000, 2000)},
+    Check {subsystem: "Display", name: "demo golden frames", run: display_demo},
+    Check {subsystem: "Timers", name: "HBLANK timer counts lines", run: timer_hblank_count},
+    Check {subsystem: "Timers", name: "HBLANK timer interrupt", run: timer_hblank_interrupt},
+    Check {subsystem: "Timers", name: "VBLANK interrupt", run: vblank_interrupt},
+];
+
+/// Returns an error naming the value if it is not the expected one
+fn expect<T: PartialEq + Debug>(what: &str, actual: T, expected: T) -> Result<(), String> {
+    match actual == expected {
+        true => Ok(()),
+        false => Err(format!("{} is {:X?}, expected {:X?}", what, actual, expected)),
+    }
+}
+
+/// Builds a test SoC with a program at the start of WRAM, where its CPU starts, and runs it until it halts
+fn run_program(code: &[u8]) -> SoC {
+    let mut soc = SoC::test_build();
+    for (addr, byte) in code.iter().enumerate() {soc.write_mem(addr as u32, *byte)}
+    for _ in 0..1000 {soc.tick();}
+    soc
+}
+
+/// Returns a register by its name in [`crate::cpu::v30mz::V30MZ::REGISTER_NAMES`]
+fn register(soc: &SoC, name: &str) -> u16 {
+    let index = crate::cpu::v30mz::V30MZ::REGISTER_NAMES.iter().position(|register| *register == name).unwrap();
+    soc.cpu.registers()[index]
+}
+
+fn cpu_add() -> Result<(), String> {
+    // MOV AL, 0xFF; ADD AL, 1; HLT
+    let soc = run_program(&[0xB0, 0xFF, 0x04, 0x01, 0xF4]);
+    expect("AL", register(&soc, "AW") & 0xFF, 0)?;
+    expect("CY and Z", soc.cpu.psw() & 0x41, 0x41)
+}
+
+fn cpu_sub() -> Result<(), String> {
+    // MOV AL, 0x80; SUB AL, 1; HLT
+    let soc = run_program(&[0xB0, 0x80, 0x2C, 0x01, 0xF4]);
+    expect("AL", register(&soc, "AW") & 0xFF, 0x7F)?;
+    expect("V and S", soc.cpu.psw() & 0x880, 0x800)
+}
+
+fn cpu_mul() -> Result<(), String> {
+    // MOV AW, 0x1234; MOV CW, 0x0100; MUL CW; HLT
+    let soc = run_program(&[0xB8, 0x34, 0x12, 0xB9, 0x00, 0x01, 0xF7, 0xE1, 0xF4]);
+    expect("DW:AW", (register(&soc, "DW"), register(&soc, "AW")), (0x0012, 0x3400))
+}
+
+fn cpu_div() -> Result<(), String> {
+    // MOV AW, 100; MOV CL, 7; DIV CL; HLT
+    let soc = run_program(&[0xB8, 0x64, 0x00, 0xB1, 0x07, 0xF6, 0xF1, 0xF4]);
+    expect("AW", register(&soc, "AW"), 0x020E)
+}
+
+fn cpu_call() -> Result<(), String> {
+    // MOV SP, 0x1000; CALL 8; HLT; NOP; MOV BL, 0x42; RET
+    let soc = run_program(&[0xBC, 0x00, 0x10, 0xE8, 0x02, 0x00, 0xF4, 0x90, 0xB3, 0x42, 0xC3]);
+    expect("BL", register(&soc, "BW") & 0xFF, 0x42)?;
+    expect("SP", register(&soc, "SP"), 0x1000)?;
+    expect("PC", register(&soc, "PC"), 0x0007)
+}
+
+fn cpu_stack() -> Result<(), String> {
+    // MOV SP, 0x1000; MOV AW, 0xBEEF; PUSH AW; POP DW; HLT
+    let soc = run_program(&[0xBC, 0x00, 0x10, 0xB8, 0xEF, 0xBE, 0x50, 0x5A, 0xF4]);
+    expect("DW", register(&soc, "DW"), 0xBEEF)?;
+    expect("SP", register(&soc, "SP"), 0x1000)
+}
+
+fn cpu_block() -> Result<(), String> {
+    let mut soc = SoC::test_build();
+    // MOV IX, 0x0100; MOV IY, 0x0200; MOV CW, 4; CLR1 DIR; REP MOVBK; HLT
+    let code = [0xBE, 0x00, 0x01, 0xBF, 0x00, 0x02, 0xB9, 0x04, 0x00, 0xFC, 0xF3, 0xA4, 0xF4];
+    for (addr, byte) in code.iter().enumerate() {soc.write_mem(addr as u32, *byte)}
+    for (addr, byte) in (0x0100..).zip([0x11, 0x22, 0x33, 0x44]) {soc.write_mem(addr, byte)}
+    for _ in 0..1000 {soc.tick();}
+    expect("copied block", (0x0200..0x0205).map(|addr| soc.read_mem(addr)).collect::<Vec<_>>(), vec![0x11, 0x22, 0x33, 0x44, 0x01])?;
+    expect("CW", register(&soc, "CW"), 0)
+}
+
+/// Plays a square wave on channel 1 for a tenth of a second and checks how many periods it has
+fn sound_frequency(period: u16, expected: u32) -> Result<(), String> {
+    let cartridge = Rc::new(RefCell::new(Cartridge::test_build()));
+    let io_bus = Rc::new(RefCell::new(IOBus::new(Rc::clone(&cartridge), Vec::new(), None, false, 0)));
+    let mem_bus = Rc::new(RefCell::new(MemBus::test_build(Rc::clone(&io_bus), cartridge)));
+    // Waveforms at 0x0100, channel 1's being 16 samples high then 16 low
+    mem_bus.borrow_mut().wram[0x100..0x108].fill(0xFF);
+    let mut sound = Sound::new(mem_bus, io_bus);
+    sound.write_io(0x8F, 0x04);
+    sound.write_io_16(0x80, period);
+    sound.write_io(0x88, 0xFF);
+    sound.write_io(0x90, 0x01);
+
+    let samples: Vec<u16> = (0..SAMPLE_RATE / TONE_DIVIDER).map(|_| {
+        for _ in 0..128 {sound.tick()}
+        sound.take_sample().0
+    }).collect();
+    let (low, high) = (*samples.iter().min().unwrap(), *samples.iter().max().unwrap());
+    // Counts rising edges with some hysteresis, so that the speaker filter's ripple is not counted
+    let (falling, rising) = (low + (high - low) / 4, high - (high - low) / 4);
+    let mut up = false;
+    let mut periods = 0u32;
+    for sample in samples {
+        if !up && sample >= rising {(up, periods) = (true, periods + 1)}
+        if up && sample <= falling {up = false}
+    }
+    let expected = expected / TONE_DIVIDER;
+    if periods.abs_diff(expected) <= 1 {Ok(())} else {Err(format!("played {} periods in a tenth of a second, expected {}", periods, expected))}
+}
+
+fn display_demo() -> Result<(), String> {
+    let options = SharedOptions::new(EmulatorOptions {mute: true, ..EmulatorOptions::new()});
+    let mut soc = demo::demo_soc(SampleBuffer::shared(), options);
+    let mut hashes = Vec::new();
+    for frame in 1..=60 {
+        soc.run_frame();
+        if frame == 1 || frame == 60 {hashes.push(regress::hash_frame(&soc.get_lcd().borrow()[..]))}
+    }
+    expect("frame hashes", hashes, DEMO_HASHES.to_vec())
+}
+
+fn timer_hblank_count() -> Result<(), String> {
+    let mut soc = SoC::test_build();
+    // MOV SP, 0x1000; HLT
+    for (addr, byte) in [0xBC, 0x00, 0x10, 0xF4].into_iter().enumerate() {soc.write_mem(addr as u32, byte)}
+    soc.write_io_16(0xA4, 200);
+    soc.write_io(0xA2, 0x01);
+    soc.run_frame();
+    expect("counter after a frame", soc.read_io_16(0xA8), (41, 0))
+}
+
+/// Runs a program that halts with interrupts enabled until a handler sets BL, for a frame
+fn interrupt_program(setup: impl Fn(&mut SoC)) -> SoC {
+    let mut soc = SoC::test_build();
+    // MOV SP, 0x1000; EI; HLT; BR -3
+    for (addr, byte) in [0xBC, 0x00, 0x10, 0xFB, 0xF4, 0xEB, 0xFD].into_iter().enumerate() {soc.write_mem(addr as u32, byte)}
+    // Every vector from 0x08 points to the handler at 0x0400: MOV BL, 0x55; HLT
+    for vector in 0x08..0x10 {
+        for (addr, byte) in (vector * 4..).zip([0x00, 0x04, 0x00, 0x00]) {soc.write_mem(addr, byte)}
+    }
+    for (addr, byte) in (0x0400..).zip([0xB3, 0x55, 0xF4]) {soc.write_mem(addr, byte)}
+    soc.write_io(0xB0, 0x08);
+    setup(&mut soc);
+    soc.run_frame();
+    soc
+}
+
+fn timer_hblank_interrupt() -> Result<(), String> {
+    let soc = interrupt_program(|soc| {
+        soc.write_io_16(0xA4, 10);
+        soc.write_io(0xA2, 0x03);
+        soc.write_io(0xB2, 0x80);
+    });
+    expect("last interrupt", soc.cpu.last_interrupt, Some(0x0F))?;
+    expect("BL", register(&soc, "BW") & 0xFF, 0x55)
+}
+
+fn vblank_interrupt() -> Result<(), String> {
+    let soc = interrupt_program(|soc| soc.write_io(0xB2, 0x40));
+    expect("last interrupt", soc.cpu.last_interrupt, Some(0x0E))?;
+    expect("BL", register(&soc, "BW") & 0xFF, 0x55)
+}
+
+/// Result of one check
+struct Outcome {
+    subsystem: String,
+    name: String,
+    result: Result<(), String>,
+}
+
+/// Runs the built-in checks, catching panics as failures
+fn run_checks() -> Vec<Outcome> {
+    CHECKS.iter().map(|check| {
+        let result = panic::catch_unwind(check.run).unwrap_or_else(|_| Err("panicked".to_string()));
+        Outcome {subsystem: check.subsystem.to_string(), name: check.name.to_string(), result}
+    }).collect()
+}
+
+/// Runs the ROMs of a regression directory against its baseline, each ROM being a golden frame check of the display
+fn run_regressions(dir: &str, frames: usize) -> Result<Vec<Outcome>, String> {
+    let baseline = fs::read_to_string(Path::new(dir).join(regress::BASELINE_FILE))
+        .map_err(|_| format!("{} has no baseline, create one with regress first", dir))?;
+    let baseline = regress::read_baseline(&baseline);
+    regress::list_roms(dir)?.iter().map(|game| {
+        let name = game.file_name().unwrap().to_string_lossy().to_string();
+        let result = match regress::compare(baseline.get(&name), &regress::run_listed_rom(game, frames, false)?) {
+            Verdict::Unchanged => Ok(()),
+            Verdict::Changed(frame) => Err(format!("differs from the baseline on frame {}", frame)),
+            verdict => Err(format!("{:?} in the baseline", verdict)),
+        };
+        Ok(Outcome {subsystem: "Display".to_string(), name, result})
+    }).collect()
+}
+
+/// Lays out the failures followed by the amount of checks passed per subsystem
+fn scorecard(outcomes: &[Outcome]) -> String {
+    let mut lines: Vec<String> = outcomes.iter()
+        .filter_map(|outcome| outcome.result.as_ref().err().map(|e| format!("FAILED  {}: {}: {}", outcome.subsystem, outcome.name, e)))
+        .collect();
+    let mut subsystems: Vec<&str> = Vec::new();
+    for outcome in outcomes {
+        if !subsystems.contains(&outcome.subsystem.as_str()) {subsystems.push(&outcome.subsystem)}
+    }
+    lines.push(format!("{:<10} {:>6} {:>6} {:>7}", "Subsystem", "Passed", "Total", "Score"));
+    let row = |name: &str, outcomes: Vec<&Outcome>| {
+        let passed = outcomes.iter().filter(|outcome| outcome.result.is_ok()).count();
+        format!("{:<10} {:>6} {:>6} {:>6.1}%", name, passed, outcomes.len(), 100.0 * passed as f64 / outcomes.len().max(1) as f64)
+    };
+    for subsystem in subsystems {
+        lines.push(row(subsystem, outcomes.iter().filter(|outcome| outcome.subsystem == subsystem).collect()));
+    }
+    lines.push(row("Total", outcomes.iter().collect()));
+    lines.join("\n")
+}
+
+/// Entry point of the `verify` command
+///
+/// Usage: `verify [directory] [frames]`
+///
+/// Runs the checks built into the emulator, and the ROMs of a regression directory against its baseline when one is given,
+/// then prints a scorecard of the checks passed per subsystem. Fails if any check did.
+pub fn run(args: &[String]) -> Result<(), String> {
+    // Failures are listed in the scorecard
+    let hook = panic::take_hook();
+    panic::set_hook(Box::new(|_| {}));
+    let mut outcomes = run_checks();
+    let regressions = args.first().map(|dir| {
+        let frames = args.get(1).and_then(|frames| frames.parse().ok()).unwrap_or(regress::DEFAULT_FRAMES);
+        run_regressions(dir, frames)
+    }).transpose();
+    panic::set_hook(hook);
+    outcomes.extend(regressions?.unwrap_or_default());
+
+    println!("{}", scorecard(&outcomes));
+    match outcomes.iter().filter(|outcome| outcome.result.is_err()).count() {
+        0 => Ok(()),
+        failed => Err(format!("{} of {} checks failed", failed, outcomes.len())),
+    }
+}
+
+#[cfg(test)]
+#[cfg_attr(coverage_nightly, coverage(off))]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_checks_pass() {
+        for outcome in run_checks() {
+            assert!(outcome.result.is_ok(), "{}: {}: {:?}", outcome.subsystem, outcome.name, outcome.result);
+        }
+    }
+
+    #[test]
+    fn test_scorecard() {
+        let outcome = |subsystem: &str, result| Outcome {subsystem: subsystem.to_string(), name: "check".to_string(), result};
+        let card = scorecard(&[outcome("CPU", Ok(())), outcome("CPU", Err("AL is 1".to_string())), outcome("Sound", Ok(()))]);
+        assert!(card.starts_with("FAILED  CPU: check: AL is 1\n"));
+        assert!(card.contains("CPU             1      2   50.0%"));
+        assert!(card.contains("Sound           1      1  100.0%"));
+        assert!(card.contains("Total           2      3   66.7%"));
+    }
+}
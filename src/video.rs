@@ -0,0 +1,120 @@
+use sdl2::{pixels::PixelFormatEnum, render::{Canvas, Texture, TextureCreator}, video::{Window, WindowContext}};
+
+use wonderswan::options::Choice;
+
+/// How frames are scaled up to the window
+///
+/// Nearest keeps pixels sharp but they end up of uneven sizes when the window is not an integer multiple of the screen, which shimmers as things scroll.
+/// Linear avoids that but blurs the whole frame. Sharp bilinear first enlarges the frame by the largest integer factor that fits with nearest filtering,
+/// then scales the rest of the way linearly, so that only the edges between pixels are blended.
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum Scaling {
+    /// Nearest neighbour, the default
+    Nearest,
+    /// Bilinear filtering of the frame itself
+    Linear,
+    /// Integer prescaling with nearest filtering followed by bilinear filtering
+    SharpBilinear,
+}
+
+impl Choice for Scaling {
+    const ALL: &'static [Self] = &[Scaling::Nearest, Scaling::Linear, Scaling::SharpBilinear];
+
+    fn name(&self) -> &'static str {
+        match self {
+            Scaling::Nearest => "nearest",
+            Scaling::Linear => "linear",
+            Scaling::SharpBilinear => "sharp",
+        }
+    }
+}
+
+/// Returns the largest integer factor a frame of `logical` size can be enlarged by and still fit in `output`, at least 1
+pub fn prescale_factor(output: (u32, u32), logical: (u32, u32)) -> u32 {
+    (output.0 / logical.0.max(1)).min(output.1 / logical.1.max(1)).max(1)
+}
+
+/// Creates a texture, filtered linearly when scaled if `linear` is set
+///
+/// SDL takes the filtering from a hint when the texture is created, so switching it means creating the texture again.
+fn create_texture<'a>(creator: &'a TextureCreator<WindowContext>, format: Option<PixelFormatEnum>, (width, height): (u32, u32), linear: bool) -> Result<Texture<'a>, String> {
+    sdl2::hint::set("SDL_RENDER_SCALE_QUALITY", if linear {"1"} else {"0"});
+    creator.create_texture_target(format, width, height).map_err(|e| e.to_string())
+}
+
+/// The textures frames go through on their way to the window
+pub struct Scaler<'a> {
+    /// How frames are scaled
+    scaling: Scaling,
+    /// Creates the textures, they cannot outlive it
+    creator: &'a TextureCreator<WindowContext>,
+    /// Size of the frames
+    size: (u32, u32),
+    /// The frame as the emulator drew it
+    frame: Texture<'a>,
+    /// The frame enlarged by an integer factor, only used by sharp bilinear, along with the factor
+    ///
+    /// Created again whenever resizing the window changes the factor.
+    prescaled: Option<(u32, Texture<'a>)>,
+}
+
+impl<'a> Scaler<'a> {
+    /// Creates the textures for frames of the given size, in 24-bit RGB
+    pub fn new(creator: &'a TextureCreator<WindowContext>, size: (u32, u32), scaling: Scaling) -> Result<Self, String> {
+        let frame = create_texture(creator, Some(PixelFormatEnum::RGB24), size, scaling == Scaling::Linear)?;
+        Ok(Self {scaling, creator, size, frame, prescaled: None})
+    }
+
+    /// Returns how frames are scaled
+    pub fn scaling(&self) -> Scaling {
+        self.scaling
+    }
+
+    /// Changes how frames are scaled
+    pub fn set_scaling(&mut self, scaling: Scaling) -> Result<(), String> {
+        *self = Self::new(self.creator, self.size, scaling)?;
+        Ok(())
+    }
+
+    /// Uploads a frame and returns the texture to copy to the canvas, at the canvas' logical size
+    pub fn texture(&mut self, canvas: &mut Canvas<Window>, frame: &[u8]) -> Result<&Texture<'a>, String> {
+        self.frame.update(None, frame, self.size.0 as usize * 3).map_err(|e| e.to_string())?;
+        if self.scaling != Scaling::SharpBilinear {return Ok(&self.frame)}
+
+        // The logical size and the window are rotated along with the screen, which leaves the factor the same as for the frame
+        let factor = prescale_factor(canvas.output_size()?, canvas.logical_size());
+        if self.prescaled.as_ref().map(|(prescaled, _)| *prescaled) != Some(factor) {
+            let texture = create_texture(self.creator, None, (self.size.0 * factor, self.size.1 * factor), true)?;
+            self.prescaled = Some((factor, texture));
+        }
+
+        let (_, prescaled) = self.prescaled.as_mut().unwrap();
+        let frame = &self.frame;
+        let mut result = Ok(());
+        canvas.with_texture_canvas(prescaled, |target| result = target.copy(frame, None, None)).map_err(|e| e.to_string())?;
+        result?;
+        Ok(prescaled)
+    }
+}
+
+#[cfg(test)]
+#[cfg_attr(coverage_nightly, coverage(off))]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_prescale_factor() {
+        assert_eq!(prescale_factor((1344, 864), (224, 144)), 6);
+        // Dragged to a size between two multiples, the factor is the one below
+        assert_eq!(prescale_factor((1000, 700), (224, 144)), 4);
+        assert_eq!(prescale_factor((1000, 300), (224, 144)), 2);
+        assert_eq!(prescale_factor((100, 100), (224, 144)), 1);
+    }
+
+    #[test]
+    fn test_scaling_names() {
+        assert_eq!(Scaling::from_name("sharp"), Some(Scaling::SharpBilinear));
+        assert_eq!(Scaling::SharpBilinear.next(), Scaling::Nearest);
+        assert_eq!(Scaling::from_name("bicubic"), None);
+    }
+}